@@ -0,0 +1,214 @@
+//! The bench module contains a benchmark mode which runs bare-metal Dhrystone and CoreMark images
+//! and reports the speed of the emulator, so a performance change can be measured the same way
+//! every time:
+//!
+//! ```text
+//! rvemu-for-book bench dhrystone.elf coremark.bin
+//! ```
+//!
+//! An image prints its report to the console and exits via the emulator control device. The
+//! report is checked the way each benchmark checks itself:
+//! - Dhrystone prints each final value followed by `should be:` and the expected value, which
+//!   must be equal unless the expected value is implementation-dependent.
+//! - CoreMark prints `Correct operation validated.` or `Errors detected`.
+//!
+//! The host-side MIPS is the number of executed instructions per second of host time, and the
+//! guest-reported score is Dhrystones per second or the CoreMark score, which depend on the timer
+//! frequency the image assumes.
+
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use crate::cpu::Cpu;
+use crate::emulator::Emulator;
+use crate::loader::Image;
+
+/// The default maximum number of instructions an image may execute.
+pub const DEFAULT_BENCH_STEPS: u64 = 20_000_000_000;
+/// The number of Dhrystones per second of the VAX 11/780, which is 1 DMIPS.
+const VAX_DHRYSTONES: f64 = 1757.0;
+
+/// A benchmark recognized by its report.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Benchmark {
+    /// Dhrystone with the score in Dhrystones per second.
+    Dhrystone(Option<f64>),
+    /// CoreMark with the score in iterations per second.
+    CoreMark(Option<f64>),
+    /// An image whose report isn't recognized, which only has to exit with 0.
+    Unknown,
+}
+
+impl fmt::Display for Benchmark {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Benchmark::Dhrystone(Some(score)) => write!(
+                f,
+                "Dhrystone {:.1} Dhrystones/s ({:.2} DMIPS)",
+                score,
+                score / VAX_DHRYSTONES
+            ),
+            Benchmark::CoreMark(Some(score)) => write!(f, "CoreMark {:.2}", score),
+            Benchmark::Dhrystone(None) => write!(f, "Dhrystone without a score"),
+            Benchmark::CoreMark(None) => write!(f, "CoreMark without a score"),
+            Benchmark::Unknown => write!(f, "no benchmark report"),
+        }
+    }
+}
+
+/// The result of a benchmark run.
+#[derive(Debug)]
+pub struct BenchResult {
+    pub benchmark: Benchmark,
+    /// The number of executed instructions.
+    pub instructions: u64,
+    /// The host time of the run in seconds.
+    pub seconds: f64,
+    /// The exit code requested by the image, or None if it didn't exit.
+    pub exit_code: Option<u64>,
+    /// Failures of the self-check.
+    pub errors: Vec<String>,
+}
+
+impl BenchResult {
+    /// Return the number of executed instructions per second of host time in millions.
+    pub fn mips(&self) -> f64 {
+        match self.seconds {
+            s if s > 0.0 => self.instructions as f64 / s / 1_000_000.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Return true if the image exited with 0 and its self-check passed.
+    pub fn passed(&self) -> bool {
+        self.exit_code == Some(0) && self.errors.is_empty()
+    }
+}
+
+impl fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}, {} instructions in {:.2} s, {:.1} MIPS: ",
+            self.benchmark,
+            self.instructions,
+            self.seconds,
+            self.mips()
+        )?;
+        match self.exit_code {
+            _ if self.passed() => write!(f, "ok"),
+            Some(0) => write!(f, "failed"),
+            Some(code) => write!(f, "failed with exit code {}", code),
+            None => write!(f, "failed without exiting"),
+        }?;
+        for error in self.errors.iter() {
+            write!(f, "\n  {}", error)?;
+        }
+        Ok(())
+    }
+}
+
+/// A serial backend which keeps transmitted bytes.
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run `image` until it exits, a fatal exception occurs or it executes `max_steps` instructions,
+/// and check its report on the console.
+pub fn run(image: &Image, max_steps: u64) -> io::Result<BenchResult> {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    image.load_into(&mut cpu)?;
+    let output = Arc::new(Mutex::new(Vec::new()));
+    cpu.bus
+        .add_serial_backend(Box::new(Capture(Arc::clone(&output))));
+
+    // Step through the emulator like the main loop, without tracing, so the speed is comparable.
+    let mut emu = Emulator::new(cpu);
+    let start = Instant::now();
+    let mut instructions = 0;
+    while instructions < max_steps && emu.step().is_some() {
+        instructions += 1;
+    }
+    let seconds = start.elapsed().as_secs_f64();
+
+    let output = String::from_utf8_lossy(&output.lock().unwrap()).to_string();
+    let (benchmark, errors) = check_report(&output);
+    Ok(BenchResult {
+        benchmark,
+        instructions,
+        seconds,
+        exit_code: emu.cpu.bus.emuctl.exit_code(),
+        errors,
+    })
+}
+
+/// Return the number after `label` and an optional colon in a line of `output`.
+fn find_number(output: &str, label: &str) -> Option<f64> {
+    output.lines().find_map(|line| {
+        let (_, rest) = line.split_once(label)?;
+        let rest = rest.trim_start().trim_start_matches(':');
+        rest.split_whitespace().next()?.parse().ok()
+    })
+}
+
+/// Recognize the benchmark printing `output` and return it with the failures of its self-check.
+pub fn check_report(output: &str) -> (Benchmark, Vec<String>) {
+    let mut errors = Vec::new();
+    if output.contains("CoreMark") {
+        let score =
+            find_number(output, "CoreMark 1.0 :").or_else(|| find_number(output, "Iterations/Sec"));
+        if output.contains("Errors detected") {
+            errors.push("CoreMark detected errors".to_string());
+        } else if !output.contains("Correct operation validated") {
+            errors.push("CoreMark didn't validate its operation".to_string());
+        }
+        return (Benchmark::CoreMark(score), errors);
+    }
+    if output.contains("Dhrystone") {
+        let score = find_number(output, "Dhrystones per Second:");
+        // "Execution starts, <n> runs through Dhrystone"
+        let runs = find_number(output, "Execution starts,");
+        // Compare each value with the `should be:` line after it.
+        let lines: Vec<&str> = output.lines().map(str::trim).collect();
+        for pair in lines.windows(2) {
+            let expected = match pair[1].strip_prefix("should be:") {
+                Some(expected) => expected.trim(),
+                None => continue,
+            };
+            if expected.contains("implementation-dependent") || expected.contains("depends") {
+                continue;
+            }
+            let expected = match (expected, runs) {
+                ("Number_Of_Runs + 10", Some(runs)) => format!("{}", runs as u64 + 10),
+                ("Number_Of_Runs + 10", None) => continue,
+                _ => expected.to_string(),
+            };
+            let (name, actual) = pair[0].split_once(':').unwrap_or(("", pair[0]));
+            if actual.trim() != expected {
+                errors.push(format!(
+                    "{} is {:?}, should be {:?}",
+                    name,
+                    actual.trim(),
+                    expected
+                ));
+            }
+        }
+        if score.is_none() {
+            errors.push("Dhrystone didn't report its score".to_string());
+        }
+        return (Benchmark::Dhrystone(score), errors);
+    }
+    (Benchmark::Unknown, errors)
+}
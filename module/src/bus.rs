@@ -1,12 +1,53 @@
 //! The bus module contains the system bus which can access the memroy or memory-mapped peripheral
 //! devices.
 
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+use std::ops::Range;
+
 use crate::clint::*;
+use crate::debugcon::*;
 use crate::dram::*;
+use crate::emuctl::*;
+use crate::entropy::*;
+use crate::filestore::*;
+use crate::goldfish_rtc::*;
+use crate::level::*;
+use crate::machine::*;
+use crate::pflash::*;
 use crate::plic::*;
+use crate::plugin::*;
+use crate::sifive_pwm::*;
+use crate::sifive_uart::*;
+use crate::snapshot::*;
 use crate::trap::*;
 use crate::uart::*;
 use crate::virtio::*;
+use crate::virtio_snd::*;
+use crate::virtio_vsock::*;
+
+/// The address which the emulator control device starts. It doesn't exist in real hardware and
+/// provides a "magic hypercall" interface for guests running on this emulator.
+pub const EMUCTL_BASE: u64 = 0x100_0000;
+/// The size of the emulator control device.
+pub const EMUCTL_SIZE: u64 = 0x1000;
+
+/// The address which the debug console starts. Writing a byte to this address prints it.
+pub const DEBUGCON_BASE: u64 = 0x100_1000;
+/// The size of the debug console.
+pub const DEBUGCON_SIZE: u64 = 0x1000;
+
+/// The address which the file export device starts. Like the emulator control device, it only
+/// exists in this emulator.
+pub const FILESTORE_BASE: u64 = 0x100_2000;
+/// The size of the file export device.
+pub const FILESTORE_SIZE: u64 = 0x1000;
+
+/// The address which the Goldfish real-time clock starts, same as QEMU virt machine.
+pub const GOLDFISH_RTC_BASE: u64 = 0x10_1000;
+/// The size of the Goldfish real-time clock.
+pub const GOLDFISH_RTC_SIZE: u64 = 0x1000;
 
 /// The address which the core-local interruptor (CLINT) starts. It contains the timer and
 /// generates per-hart software interrupts and timer
@@ -26,75 +67,1245 @@ pub const UART_BASE: u64 = 0x1000_0000;
 /// The size of UART.
 pub const UART_SIZE: u64 = 0x100;
 
-/// The address which virtio starts.
+/// The address which the SiFive UART starts, same as UART0 in HiFive boards.
+pub const SIFIVE_UART_BASE: u64 = 0x1001_0000;
+/// The size of the SiFive UART.
+pub const SIFIVE_UART_SIZE: u64 = 0x1000;
+
+/// The address which the PWM0 block starts, same as FU540.
+pub const SIFIVE_PWM0_BASE: u64 = 0x1002_0000;
+/// The address which the PWM1 block starts, same as FU540.
+pub const SIFIVE_PWM1_BASE: u64 = 0x1002_1000;
+/// The size of a PWM block.
+pub const SIFIVE_PWM_SIZE: u64 = 0x1000;
+
+/// The address which the first virtio-mmio slot starts. The slots are consecutive windows like
+/// the 8 virtio-mmio transports of QEMU virt machine.
 pub const VIRTIO_BASE: u64 = 0x1000_1000;
-/// The size of virtio.
+/// The size of a virtio-mmio slot.
 pub const VIRTIO_SIZE: u64 = 0x1000;
+/// The number of virtio-mmio slots.
+pub const VIRTIO_SLOTS: usize = 8;
+
+/// The address which the virtio socket device decodes, the second slot.
+pub const VIRTIO_VSOCK_BASE: u64 = 0x1000_2000;
+
+/// The address which the virtio sound device decodes, the third slot.
+pub const VIRTIO_SND_BASE: u64 = 0x1000_3000;
+
+/// The address which the parallel flash starts, same as pflash0 in QEMU virt machine.
+pub const PFLASH_BASE: u64 = 0x2000_0000;
+/// The size of the parallel flash (32 MiB).
+pub const PFLASH_SIZE: u64 = 0x200_0000;
 
 /// The address which dram starts, same as QEMU virt machine.
 pub const DRAM_BASE: u64 = 0x8000_0000;
 
+/// The maximum number of instructions between polls of console input if the entropy is seeded.
+const INPUT_INTERVAL: u64 = 20_000;
+
+/// The kind of a console device connected to stdin and stdout.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum ConsoleKind {
+    /// The 16550a UART at `UART_BASE`, used in the QEMU virt machine.
+    Ns16550,
+    /// The SiFive UART at `SIFIVE_UART_BASE`, used in HiFive boards.
+    Sifive,
+}
+
+/// The initial contents of the dram, which guests shouldn't rely on.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MemFill {
+    /// All zeros.
+    Zero,
+    /// The word 0xdeadbeef repeated, which is easy to spot in registers and dumps.
+    Pattern,
+    /// Random bytes from a seed.
+    Random(u64),
+}
+
+impl MemFill {
+    /// Parse `zero`, `pattern` or `random(<seed>)`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s {
+            "zero" => return Ok(MemFill::Zero),
+            "pattern" => return Ok(MemFill::Pattern),
+            _ => {}
+        }
+        s.strip_prefix("random(")
+            .and_then(|s| s.strip_suffix(')'))
+            .and_then(|seed| match seed.strip_prefix("0x") {
+                Some(hex) => u64::from_str_radix(hex, 16).ok(),
+                None => seed.parse().ok(),
+            })
+            .map(MemFill::Random)
+            .ok_or_else(|| format!("expected zero, pattern or random(<seed>): '{}'", s))
+    }
+}
+
+/// A virtio device which can be placed in a virtio-mmio slot.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum VirtioDevice {
+    /// The block device.
+    Blk,
+    /// The socket device.
+    Vsock,
+    /// The sound device.
+    Snd,
+}
+
+impl VirtioDevice {
+    fn target(self) -> Target {
+        match self {
+            VirtioDevice::Blk => Target::Virtio,
+            VirtioDevice::Vsock => Target::Vsock,
+            VirtioDevice::Snd => Target::Snd,
+        }
+    }
+
+    /// Return the address which the device decodes at the start of its slot.
+    fn base(self) -> u64 {
+        match self {
+            VirtioDevice::Blk => VIRTIO_BASE,
+            VirtioDevice::Vsock => VIRTIO_VSOCK_BASE,
+            VirtioDevice::Snd => VIRTIO_SND_BASE,
+        }
+    }
+}
+
+/// The devices in the virtio-mmio slots by default: the block device, the socket device and the
+/// sound device.
+const DEFAULT_VIRTIO_SLOTS: [Option<VirtioDevice>; VIRTIO_SLOTS] = [
+    Some(VirtioDevice::Blk),
+    Some(VirtioDevice::Vsock),
+    Some(VirtioDevice::Snd),
+    None,
+    None,
+    None,
+    None,
+    None,
+];
+
+/// The shift of the page size (4 KiB) in the page table of the bus.
+const BUS_PAGE_SHIFT: u64 = 12;
+/// The page table of the bus covers at least the devices and the dram of the default layout.
+const BUS_PAGE_MIN: u64 = DRAM_BASE + DRAM_SIZE;
+/// Addresses from it aren't covered by the page table and are looked up in the regions.
+const BUS_PAGE_LIMIT: u64 = 1 << 32;
+/// A page which no region is mapped at.
+const UNMAPPED: u16 = u16::MAX;
+
+/// A device which a region of the bus is mapped to.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum Target {
+    Emuctl,
+    Debugcon,
+    Rtc,
+    Clint,
+    Plic,
+    Uart,
+    SifiveUart,
+    SifivePwm0,
+    SifivePwm1,
+    Virtio,
+    Vsock,
+    Snd,
+    Pflash,
+    Filestore,
+    /// A device loaded from a plugin, an index to `Bus::plugins`.
+    Plugin(usize),
+    Dram,
+}
+
+impl Target {
+    /// Return the name of the device used by `--trace-mmio`.
+    fn name(self) -> &'static str {
+        match self {
+            Target::Emuctl => "emuctl",
+            Target::Debugcon => "debugcon",
+            Target::Rtc => "rtc",
+            Target::Clint => "clint",
+            Target::Plic => "plic",
+            Target::Uart => "uart",
+            Target::SifiveUart => "sifive-uart",
+            Target::SifivePwm0 => "pwm0",
+            Target::SifivePwm1 => "pwm1",
+            Target::Virtio => "virtio",
+            Target::Vsock => "vsock",
+            Target::Snd => "snd",
+            Target::Pflash => "pflash",
+            Target::Filestore => "filestore",
+            Target::Plugin(_) => "plugin",
+            Target::Dram => "dram",
+        }
+    }
+}
+
+/// A load or a store to a device recorded by `--trace-mmio`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct MmioAccess {
+    pub device: &'static str,
+    /// The offset from the start of the device.
+    pub offset: u64,
+    /// The name of the register at `offset`, if the device names it.
+    pub register: Option<&'static str>,
+    pub store: bool,
+    pub size: u64,
+    /// The value loaded or stored, or None if the access faulted.
+    pub value: Option<u64>,
+}
+
+impl fmt::Display for MmioAccess {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let kind = match self.store {
+            true => "store",
+            false => "load",
+        };
+        match self.register {
+            Some(register) => write!(f, "{}.{} {}{}", self.device, register, kind, self.size)?,
+            None => write!(
+                f,
+                "{}+{:#x} {}{}",
+                self.device, self.offset, kind, self.size
+            )?,
+        }
+        match self.value {
+            Some(value) => write!(f, " {:#x}", value),
+            None => write!(f, " fault"),
+        }
+    }
+}
+
+/// A physical address range mapped to a device.
+#[derive(Debug)]
+struct Region {
+    range: Range<u64>,
+    /// The address which devices see at the start of `range`. Devices decode addresses of the
+    /// default layout, so a region moved by a machine description passes them rebased.
+    base: u64,
+    target: Target,
+}
+
+/// The width of an access to a device.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Width {
+    Byte,
+    HalfWord,
+    Word,
+    DoubleWord,
+    /// 128 bits, for 128-bit accesses such as amocas.q of Zacas in the future. No device supports
+    /// it yet, since a value doesn't fit in u64.
+    QuadWord,
+}
+
+impl Width {
+    /// Return the width of an access of `size` bits, or None if no access is that wide.
+    pub fn from_bits(size: u64) -> Option<Self> {
+        match size {
+            8 => Some(Self::Byte),
+            16 => Some(Self::HalfWord),
+            32 => Some(Self::Word),
+            64 => Some(Self::DoubleWord),
+            128 => Some(Self::QuadWord),
+            _ => None,
+        }
+    }
+
+    /// Return the number of bits.
+    pub fn bits(self) -> u64 {
+        match self {
+            Self::Byte => 8,
+            Self::HalfWord => 16,
+            Self::Word => 32,
+            Self::DoubleWord => 64,
+            Self::QuadWord => 128,
+        }
+    }
+
+    /// Return the number of bytes.
+    pub fn bytes(self) -> u64 {
+        self.bits() / 8
+    }
+
+    /// Return the mask of the bits of a value of the width in u64, all of them for `QuadWord`.
+    pub fn mask(self) -> u64 {
+        u64::MAX >> 64u64.saturating_sub(self.bits())
+    }
+}
+
+/// The operation of an AMO with the value of rs2.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Amo {
+    Swap(u64),
+    Add(u64),
+    Xor(u64),
+    And(u64),
+    Or(u64),
+    Min(u64),
+    Max(u64),
+    Minu(u64),
+    Maxu(u64),
+}
+
+impl Amo {
+    /// Return the value which the AMO writes over `old` of `width`. Min and max compare the
+    /// values of the width.
+    pub fn apply(self, old: u64, width: Width) -> u64 {
+        let shift = 64u64.saturating_sub(width.bits());
+        let sext = |v: u64| ((v << shift) as i64 >> shift) as u64;
+        let zext = |v: u64| (v << shift) >> shift;
+        let old = sext(old);
+        match self {
+            Amo::Swap(src) => src,
+            Amo::Add(src) => old.wrapping_add(src),
+            Amo::Xor(src) => old ^ src,
+            Amo::And(src) => old & src,
+            Amo::Or(src) => old | src,
+            Amo::Min(src) => (old as i64).min(sext(src) as i64) as u64,
+            Amo::Max(src) => (old as i64).max(sext(src) as i64) as u64,
+            Amo::Minu(src) => zext(old).min(zext(src)),
+            Amo::Maxu(src) => zext(old).max(zext(src)),
+        }
+    }
+}
+
 pub trait Device {
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception>;
-    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception>;
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception>;
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception>;
+
+    /// Atomically read, modify and write the register at `addr`, and return the old value. Nothing
+    /// else reaches the device between the load and the store.
+    fn amo(&mut self, addr: u64, width: Width, op: Amo) -> Result<u64, Exception> {
+        let old = self.load(addr, width)?;
+        self.store(addr, width, op.apply(old, width))?;
+        Ok(old)
+    }
+
+    /// Return the name of the register at `addr` for `--trace-mmio`. A register may be named
+    /// differently by a load and a store.
+    fn register_name(&self, _addr: u64, _store: bool) -> Option<&'static str> {
+        None
+    }
+
+    /// Return the registers to their values at power-on for a machine-wide reset. Connections to
+    /// the host, e.g. a disk image, a console or a socket, are kept.
+    fn reset(&mut self) {}
+}
+
+/// A handle which devices use to access the dram directly (DMA). It's given to a device at each
+/// call, so the device doesn't reach the dram through the bus which owns it.
+pub struct Dma<'a> {
+    dram: &'a mut Dram,
+    /// The physical address range of the dram.
+    range: Range<u64>,
+}
+
+impl Dma<'_> {
+    /// Load `size` bits from a physical address in the dram.
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        match (self.dram_addr(addr, size), Width::from_bits(size)) {
+            (Some(addr), Some(width)) => self.dram.load(addr, width),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    /// Store `size` bits to a physical address in the dram.
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        match (self.dram_addr(addr, size), Width::from_bits(size)) {
+            (Some(addr), Some(width)) => self.dram.store(addr, width, value),
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    /// Return the address which the dram decodes for an access, or None if it's out of the dram.
+    fn dram_addr(&self, addr: u64, size: u64) -> Option<u64> {
+        let end = addr.checked_add(size / 8)?;
+        (self.range.start <= addr && end <= self.range.end)
+            .then(|| addr - self.range.start + DRAM_BASE)
+    }
+}
+
+/// A device which accesses the dram directly (DMA), e.g. a virtio device.
+pub trait DmaDevice {
+    /// Process the requests of the driver. It's called every instruction.
+    fn tick(&mut self, dma: &mut Dma);
+
+    /// Return true if an interrupt should be raised, and clear it.
+    fn is_interrupting(&mut self) -> bool;
+}
+
+/// Return an error for a snapshot taken with a different set of optional devices.
+fn mismatch(device: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("the snapshot and the machine disagree on the {}", device),
+    )
 }
 
 /// The system bus.
 pub struct Bus {
+    pub emuctl: Emuctl,
+    debugcon: Debugcon,
+    pub rtc: GoldfishRtc,
     clint: Clint,
     plic: Plic,
     pub uart: Uart,
+    pub sifive_uart: SifiveUart,
+    pub sifive_pwm0: SifivePwm,
+    pub sifive_pwm1: SifivePwm,
+    console: ConsoleKind,
     pub virtio: Virtio,
+    pub vsock: VirtioVsock,
+    pub snd: VirtioSnd,
+    /// The virtio devices in the virtio-mmio slots.
+    virtio_slots: [Option<VirtioDevice>; VIRTIO_SLOTS],
+    /// The parallel flash, if it's backed by a file.
+    pub pflash: Option<Pflash>,
+    /// The file export device, if a host directory is given.
+    filestore: Option<Filestore>,
     dram: Dram,
+    /// The board layout, or None for the default layout of the constants above.
+    machine: Option<Machine>,
+    /// The step of the book up to which devices are mapped.
+    level: Level,
+    /// Read-only regions such as a boot ROM or a device tree.
+    rom: Vec<Range<u64>>,
+    /// Devices loaded from plugins.
+    plugins: Vec<PluginDevice>,
+    /// IRQs raised by plugins and not delivered yet.
+    plugin_irqs: Vec<u64>,
+    /// Regions of devices in priority order. An address in multiple regions is mapped to the
+    /// first one.
+    regions: Vec<Region>,
+    /// Indexes to `regions` for each page, of the region covering the whole page.
+    pages: Vec<u16>,
+    /// The source of nondeterminism for devices.
+    pub entropy: Entropy,
+    /// The number of instructions until console input is polled, if the entropy is seeded.
+    input_countdown: u64,
+    /// The devices whose loads and stores are recorded.
+    mmio_trace: Vec<Target>,
+    /// The recorded accesses which haven't been taken yet.
+    mmio_log: Vec<MmioAccess>,
 }
 
 impl Bus {
     /// Create a new system bus object.
     pub fn new(binary: Vec<u8>, disk_image: Vec<u8>) -> Bus {
-        Self {
+        let mut bus = Self {
+            emuctl: Emuctl::new(),
+            debugcon: Debugcon::new(),
+            rtc: GoldfishRtc::new(),
             clint: Clint::new(),
             plic: Plic::new(),
             uart: Uart::new(),
+            sifive_uart: SifiveUart::new(),
+            sifive_pwm0: SifivePwm::new(SIFIVE_PWM0_IRQ),
+            sifive_pwm1: SifivePwm::new(SIFIVE_PWM1_IRQ),
+            console: ConsoleKind::Ns16550,
             virtio: Virtio::new(disk_image),
+            vsock: VirtioVsock::new(),
+            snd: VirtioSnd::new(),
+            virtio_slots: DEFAULT_VIRTIO_SLOTS,
+            pflash: None,
+            filestore: None,
             dram: Dram::new(binary),
+            machine: None,
+            level: Level::FULL,
+            rom: Vec::new(),
+            plugins: Vec::new(),
+            plugin_irqs: Vec::new(),
+            regions: Vec::new(),
+            pages: Vec::new(),
+            entropy: Entropy::from_host(),
+            input_countdown: 0,
+            mmio_trace: Vec::new(),
+            mmio_log: Vec::new(),
+        };
+        bus.map_devices();
+        bus
+    }
+
+    /// Select a console device. Only the selected one is mapped on the bus.
+    pub fn set_console(&mut self, console: ConsoleKind) {
+        self.console = console;
+        self.map_devices();
+    }
+
+    /// Map only the devices which the steps of the book up to `level` introduce.
+    pub fn set_level(&mut self, level: Level) {
+        self.level = level;
+        self.map_devices();
+    }
+
+    /// Place the memory and the devices at the addresses of a board layout. Devices which the
+    /// machine doesn't declare aren't mapped, except the emulator-specific ones, the flash and
+    /// plugins. The console is the first UART the machine declares.
+    pub fn set_machine(&mut self, machine: &Machine) {
+        self.dram.resize(machine.memory.end - machine.memory.start);
+        if machine.device(MachineDevice::Uart).is_none()
+            && machine.device(MachineDevice::SifiveUart).is_some()
+        {
+            self.console = ConsoleKind::Sifive;
         }
+        self.machine = Some(machine.clone());
+        self.map_devices();
     }
 
-    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.load(addr, size);
+    /// Return the physical address range backed by the dram.
+    pub fn dram_range(&self) -> Range<u64> {
+        match &self.machine {
+            Some(machine) => machine.memory.clone(),
+            None => DRAM_BASE..DRAM_BASE + DRAM_SIZE,
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.load(addr, size);
+    }
+
+    /// Return the contents of the dram, which starts at the start of `dram_range()`.
+    pub fn dram_bytes(&self) -> &[u8] {
+        &self.dram.dram
+    }
+
+    /// Return the regions of the devices a machine places on the bus. They are truncated to the
+    /// size of each device.
+    fn machine_regions(&self, machine: &Machine) -> Vec<Region> {
+        machine
+            .devices
+            .iter()
+            .filter_map(|(device, range)| {
+                let (base, size, target) = match device {
+                    MachineDevice::Rtc => (GOLDFISH_RTC_BASE, GOLDFISH_RTC_SIZE, Target::Rtc),
+                    MachineDevice::Clint => (CLINT_BASE, CLINT_SIZE, Target::Clint),
+                    MachineDevice::Plic => (PLIC_BASE, PLIC_SIZE, Target::Plic),
+                    MachineDevice::Uart if self.console == ConsoleKind::Ns16550 => {
+                        (UART_BASE, UART_SIZE, Target::Uart)
+                    }
+                    MachineDevice::SifiveUart if self.console == ConsoleKind::Sifive => {
+                        (SIFIVE_UART_BASE, SIFIVE_UART_SIZE, Target::SifiveUart)
+                    }
+                    MachineDevice::Uart | MachineDevice::SifiveUart => return None,
+                    MachineDevice::SifivePwm0 => {
+                        (SIFIVE_PWM0_BASE, SIFIVE_PWM_SIZE, Target::SifivePwm0)
+                    }
+                    MachineDevice::SifivePwm1 => {
+                        (SIFIVE_PWM1_BASE, SIFIVE_PWM_SIZE, Target::SifivePwm1)
+                    }
+                };
+                let end = range.end.min(range.start.saturating_add(size));
+                (range.start < end).then_some(Region {
+                    range: range.start..end,
+                    base,
+                    target,
+                })
+            })
+            .collect()
+    }
+
+    /// Return the address ranges of the virtio-mmio slots, which a machine declares in the
+    /// order of addresses.
+    fn virtio_windows(&self) -> Vec<Range<u64>> {
+        match &self.machine {
+            Some(machine) => machine.virtio.clone(),
+            None => (0..VIRTIO_SLOTS as u64)
+                .map(|slot| {
+                    let base = VIRTIO_BASE + slot * VIRTIO_SIZE;
+                    base..base + VIRTIO_SIZE
+                })
+                .collect(),
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.load(addr, size);
+    }
+
+    /// Return the regions of the virtio devices in the slots. They are truncated to the size of a
+    /// slot.
+    fn virtio_regions(&self) -> Vec<Region> {
+        self.virtio_windows()
+            .into_iter()
+            .zip(self.virtio_slots.iter())
+            .filter_map(|(window, device)| {
+                let device = (*device)?;
+                let end = window.end.min(window.start.saturating_add(VIRTIO_SIZE));
+                (window.start < end).then_some(Region {
+                    range: window.start..end,
+                    base: device.base(),
+                    target: device.target(),
+                })
+            })
+            .collect()
+    }
+
+    /// Place virtio devices in the slots by a comma-separated list of names, e.g. "snd,,virtio"
+    /// puts the sound device in the first slot and the block device in the third one. Devices
+    /// which aren't listed aren't mapped. Return an error for an unknown or a duplicated name. It
+    /// must be called after the console is selected, which may take an IRQ of a slot.
+    pub fn set_virtio_slots(&mut self, devices: &str) -> Result<(), String> {
+        const DEVICES: [VirtioDevice; 3] =
+            [VirtioDevice::Blk, VirtioDevice::Vsock, VirtioDevice::Snd];
+        let mut slots = [None; VIRTIO_SLOTS];
+        for (slot, name) in devices.split(',').enumerate() {
+            if name.is_empty() {
+                continue;
+            }
+            let device = DEVICES
+                .iter()
+                .copied()
+                .find(|device| device.target().name() == name)
+                .ok_or_else(|| format!("unknown virtio device `{}`", name))?;
+            if slots.contains(&Some(device)) {
+                return Err(format!("virtio device `{}` is in multiple slots", name));
+            }
+            let irq = VIRTIO_IRQ + slot as u64;
+            if irq == SIFIVE_UART_IRQ && self.console == ConsoleKind::Sifive {
+                return Err(format!(
+                    "virtio slot {} shares IRQ {} with the console",
+                    slot, irq
+                ));
+            }
+            match slots.get_mut(slot) {
+                Some(entry) => *entry = Some(device),
+                None => return Err(format!("only {} virtio slots exist", VIRTIO_SLOTS)),
+            }
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.load(addr, size);
+        self.virtio_slots = slots;
+        self.map_devices();
+        Ok(())
+    }
+
+    /// Return the IRQ of a virtio device, `VIRTIO_IRQ` plus its slot, or None if it isn't in a
+    /// slot.
+    pub fn virtio_irq(&self, device: VirtioDevice) -> Option<u64> {
+        self.virtio_slots
+            .iter()
+            .position(|d| *d == Some(device))
+            .map(|slot| VIRTIO_IRQ + slot as u64)
+    }
+
+    /// Build the regions of devices and the page table to look them up.
+    fn map_devices(&mut self) {
+        let mut regions = vec![
+            (EMUCTL_BASE, EMUCTL_SIZE, Target::Emuctl),
+            (DEBUGCON_BASE, DEBUGCON_SIZE, Target::Debugcon),
+        ];
+        if self.machine.is_none() {
+            let console = match self.console {
+                ConsoleKind::Ns16550 => (UART_BASE, UART_SIZE, Target::Uart),
+                ConsoleKind::Sifive => (SIFIVE_UART_BASE, SIFIVE_UART_SIZE, Target::SifiveUart),
+            };
+            regions.extend([
+                (GOLDFISH_RTC_BASE, GOLDFISH_RTC_SIZE, Target::Rtc),
+                (CLINT_BASE, CLINT_SIZE, Target::Clint),
+                (PLIC_BASE, PLIC_SIZE, Target::Plic),
+                console,
+                (SIFIVE_PWM0_BASE, SIFIVE_PWM_SIZE, Target::SifivePwm0),
+                (SIFIVE_PWM1_BASE, SIFIVE_PWM_SIZE, Target::SifivePwm1),
+            ]);
+        }
+        let mut regions = regions
+            .into_iter()
+            .map(|(base, size, target)| Region {
+                range: base..base + size,
+                base,
+                target,
+            })
+            .collect::<Vec<_>>();
+        if let Some(machine) = &self.machine {
+            regions.extend(self.machine_regions(machine));
         }
-        if DRAM_BASE <= addr {
-            return self.dram.load(addr, size);
+        regions.extend(self.virtio_regions());
+        if self.pflash.is_some() {
+            regions.push(Region {
+                range: PFLASH_BASE..PFLASH_BASE + PFLASH_SIZE,
+                base: PFLASH_BASE,
+                target: Target::Pflash,
+            });
         }
-        Err(Exception::LoadAccessFault)
+        if self.filestore.is_some() {
+            regions.push(Region {
+                range: FILESTORE_BASE..FILESTORE_BASE + FILESTORE_SIZE,
+                base: FILESTORE_BASE,
+                target: Target::Filestore,
+            });
+        }
+        for (i, plugin) in self.plugins.iter().enumerate() {
+            let range = plugin.range();
+            regions.push(Region {
+                base: range.start,
+                range,
+                target: Target::Plugin(i),
+            });
+        }
+        let level = self.level;
+        regions.retain(|region| match region.target {
+            Target::Clint | Target::Plic => level.enables(Feature::PlicClint),
+            // Either UART can be the console.
+            Target::Uart | Target::SifiveUart => level.enables(Feature::Uart),
+            Target::Virtio => level.enables(Feature::Virtio),
+            Target::Debugcon
+            | Target::Rtc
+            | Target::SifivePwm0
+            | Target::SifivePwm1
+            | Target::Vsock
+            | Target::Snd => level.enables(Feature::Extensions),
+            // The emulator control device, the flash, the file export device and plugins are asked
+            // for explicitly.
+            Target::Emuctl
+            | Target::Pflash
+            | Target::Filestore
+            | Target::Plugin(_)
+            | Target::Dram => true,
+        });
+        regions.push(Region {
+            range: self.dram_range(),
+            base: DRAM_BASE,
+            target: Target::Dram,
+        });
+
+        // Fill pages with lower priority regions first, so higher priority ones win. A page
+        // which the winner doesn't cover entirely is left to the slow path.
+        let end = regions
+            .iter()
+            .map(|region| region.range.end)
+            .filter(|end| *end != u64::MAX)
+            .fold(BUS_PAGE_MIN, u64::max)
+            .min(BUS_PAGE_LIMIT);
+        let pages = (end + (1 << BUS_PAGE_SHIFT) - 1) >> BUS_PAGE_SHIFT;
+        self.pages.clear();
+        self.pages.resize(pages as usize, UNMAPPED);
+        for (index, region) in regions.iter().enumerate().rev() {
+            let first = region.range.start >> BUS_PAGE_SHIFT;
+            let last = (region.range.end - 1) >> BUS_PAGE_SHIFT;
+            for page in first..=last.min(pages - 1) {
+                let start = page << BUS_PAGE_SHIFT;
+                let whole = region.range.start <= start
+                    && start + (1 << BUS_PAGE_SHIFT) <= region.range.end;
+                self.pages[page as usize] = if whole { index as u16 } else { UNMAPPED };
+            }
+        }
+        self.regions = regions;
     }
 
-    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        if CLINT_BASE <= addr && addr < CLINT_BASE + CLINT_SIZE {
-            return self.clint.store(addr, size, value);
+    /// Seed the entropy with `seed` and deliver console input at instructions drawn from it
+    /// instead of when the host receives it. It must be called before a guest runs.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.entropy = Entropy::new(seed);
+        self.uart.set_deterministic();
+        self.sifive_uart.set_deterministic();
+        self.rtc.set_deterministic();
+        self.clint.set_deterministic();
+        self.input_countdown = self.entropy.below(INPUT_INTERVAL);
+    }
+
+    /// Advance the console input by one instruction if the entropy is seeded.
+    pub fn tick_input(&mut self) {
+        if !self.entropy.is_deterministic() {
+            return;
+        }
+        if self.input_countdown > 0 {
+            self.input_countdown -= 1;
+            return;
+        }
+        self.uart.poll_input();
+        self.sifive_uart.poll_input();
+        self.input_countdown = self.entropy.below(INPUT_INTERVAL);
+    }
+
+    /// Register a read-only region from `start` to `end` (exclusive). Stores to the region raise
+    /// a StoreAMOAccessFault exception, so guests that scribble over firmware or a device tree
+    /// fail loudly.
+    pub fn add_rom(&mut self, start: u64, end: u64) {
+        self.rom.push(start..end);
+    }
+
+    /// Return true if any of `bytes` bytes from `addr` is in a read-only region.
+    fn is_rom(&self, addr: u64, bytes: u64) -> bool {
+        let end = addr.saturating_add(bytes);
+        self.rom
+            .iter()
+            .any(|range| range.start < end && addr < range.end)
+    }
+
+    /// Map a file export device at `FILESTORE_BASE`.
+    pub fn set_filestore(&mut self, filestore: Filestore) {
+        self.filestore = Some(filestore);
+        self.map_devices();
+    }
+
+    /// Map a parallel flash at `PFLASH_BASE`.
+    pub fn set_pflash(&mut self, pflash: Pflash) {
+        self.pflash = Some(pflash);
+        self.map_devices();
+    }
+
+    /// Map a device loaded from a plugin. Built-in devices take priority over it, but it takes
+    /// priority over the dram.
+    pub fn add_plugin(&mut self, device: PluginDevice) {
+        self.plugins.push(device);
+        self.map_devices();
+    }
+
+    /// Advance the plugin devices by one instruction and record IRQs they raise.
+    pub fn tick_plugins(&mut self) {
+        for plugin in self.plugins.iter_mut() {
+            if let Some(irq) = plugin.tick() {
+                if !self.plugin_irqs.contains(&irq) {
+                    self.plugin_irqs.push(irq);
+                }
+            }
+        }
+    }
+
+    /// Make `irq` pending in the PLIC.
+    pub fn raise_irq(&mut self, irq: u64) {
+        self.plic.raise(irq);
+    }
+
+    /// Return true if the PLIC asserts the external interrupt, i.e. an enabled IRQ is pending.
+    pub fn is_external_interrupting(&self) -> bool {
+        self.plic.is_interrupting()
+    }
+
+    /// Record loads and stores to the devices in a comma-separated list of names, e.g.
+    /// "uart,virtio". Return the unknown name if any.
+    pub fn set_mmio_trace(&mut self, devices: &str) -> Result<(), String> {
+        const TRACEABLE: [Target; 14] = [
+            Target::Emuctl,
+            Target::Debugcon,
+            Target::Rtc,
+            Target::Clint,
+            Target::Plic,
+            Target::Uart,
+            Target::SifiveUart,
+            Target::SifivePwm0,
+            Target::SifivePwm1,
+            Target::Virtio,
+            Target::Vsock,
+            Target::Snd,
+            Target::Pflash,
+            Target::Filestore,
+        ];
+        self.mmio_trace.clear();
+        for name in devices.split(',') {
+            if name == "plugin" {
+                self.mmio_trace
+                    .extend((0..self.plugins.len()).map(Target::Plugin));
+                continue;
+            }
+            match TRACEABLE.iter().find(|target| target.name() == name) {
+                Some(&target) => self.mmio_trace.push(target),
+                None => return Err(format!("unknown device `{}`", name)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the loads and stores recorded since the last call.
+    pub fn take_mmio_log(&mut self) -> Vec<MmioAccess> {
+        std::mem::take(&mut self.mmio_log)
+    }
+
+    /// Record an access to `addr`, an address which the device of `region` sees.
+    fn trace_mmio(&mut self, index: usize, addr: u64, store: bool, size: u64, value: Option<u64>) {
+        let region = &self.regions[index];
+        if !self.mmio_trace.contains(&region.target) {
+            return;
+        }
+        let target = region.target;
+        let offset = addr - region.base;
+        let register = match target {
+            Target::Dram => self.dram.register_name(addr, store),
+            Target::Emuctl => self.emuctl.register_name(addr, store),
+            Target::Debugcon => self.debugcon.register_name(addr, store),
+            Target::Rtc => self.rtc.register_name(addr, store),
+            Target::Clint => self.clint.register_name(addr, store),
+            Target::Plic => self.plic.register_name(addr, store),
+            Target::Uart => self.uart.register_name(addr, store),
+            Target::SifiveUart => self.sifive_uart.register_name(addr, store),
+            Target::SifivePwm0 => self.sifive_pwm0.register_name(addr, store),
+            Target::SifivePwm1 => self.sifive_pwm1.register_name(addr, store),
+            Target::Virtio => self.virtio.register_name(addr, store),
+            Target::Vsock => self.vsock.register_name(addr, store),
+            Target::Snd => self.snd.register_name(addr, store),
+            Target::Pflash => self
+                .pflash
+                .as_ref()
+                .and_then(|pflash| pflash.register_name(addr, store)),
+            Target::Filestore => self
+                .filestore
+                .as_ref()
+                .and_then(|filestore| filestore.register_name(addr, store)),
+            Target::Plugin(i) => self.plugins[i].register_name(addr, store),
+        };
+        self.mmio_log.push(MmioAccess {
+            device: target.name(),
+            offset,
+            register,
+            store,
+            size,
+            value,
+        });
+    }
+
+    /// Return the state of the PLIC for the monitor.
+    pub fn plic_info(&self) -> String {
+        self.plic.info()
+    }
+
+    /// Return mtime of the CLINT, which the time CSR reads.
+    pub fn mtime(&self) -> u64 {
+        self.clint.mtime()
+    }
+
+    /// Advance mtime of the CLINT by one instruction.
+    pub fn tick_timer(&mut self) {
+        self.clint.tick();
+    }
+
+    /// Return true if the CLINT raises the machine timer interrupt.
+    pub fn is_timer_interrupting(&self) -> bool {
+        self.clint.is_interrupting()
+    }
+
+    /// Return how far the guest clock lags the host clock in nanoseconds.
+    pub fn clock_drift(&self) -> i64 {
+        self.clint.drift()
+    }
+
+    /// Return the timer registers of the CLINT for the monitor.
+    pub fn clint_info(&self) -> String {
+        self.clint.info()
+    }
+
+    /// Return a handle to access the dram directly.
+    pub fn dma(&mut self) -> Dma<'_> {
+        let range = self.dram_range();
+        Dma {
+            dram: &mut self.dram,
+            range,
         }
-        if PLIC_BASE <= addr && addr < PLIC_BASE + PLIC_SIZE {
-            return self.plic.store(addr, size, value);
+    }
+
+    /// Return the device in a virtio-mmio slot and a handle to the dram for it.
+    fn virtio_dma(&mut self, device: VirtioDevice) -> (&mut dyn DmaDevice, Dma<'_>) {
+        let dma = Dma {
+            range: self.dram_range(),
+            dram: &mut self.dram,
+        };
+        let device: &mut dyn DmaDevice = match device {
+            VirtioDevice::Blk => &mut self.virtio,
+            VirtioDevice::Vsock => &mut self.vsock,
+            VirtioDevice::Snd => &mut self.snd,
+        };
+        (device, dma)
+    }
+
+    /// Advance the virtio devices in the slots.
+    pub fn tick_virtio(&mut self) {
+        let slots = self.virtio_slots;
+        for &device in slots.iter().flatten() {
+            let (device, mut dma) = self.virtio_dma(device);
+            device.tick(&mut dma);
+        }
+    }
+
+    /// Return the IRQ of a virtio device which raises an interrupt, the first slot first.
+    pub fn virtio_interrupt(&mut self) -> Option<u64> {
+        let slots = self.virtio_slots;
+        for &device in slots.iter().flatten() {
+            if self.virtio_dma(device).0.is_interrupting() {
+                return self.virtio_irq(device);
+            }
+        }
+        None
+    }
+
+    /// Return an IRQ raised by a plugin device.
+    pub fn plugin_interrupt(&mut self) -> Option<u64> {
+        self.plugin_irqs.pop()
+    }
+
+    /// Return the number of bytes transferred by the console and disk devices.
+    pub fn io_bytes(&self) -> u64 {
+        self.uart.io_bytes() + self.sifive_uart.io_bytes() + self.virtio.io_bytes()
+    }
+
+    /// Save the state of devices for a snapshot. It fails if a plugin device is mapped or a vsock
+    /// connection is open, because their state lives outside the emulator.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        if !self.plugins.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't snapshot plugin devices",
+            ));
+        }
+        self.emuctl.save(out)?;
+        self.rtc.save(out)?;
+        self.clint.save(out)?;
+        self.plic.save(out)?;
+        self.uart.save(out)?;
+        self.sifive_uart.save(out)?;
+        self.sifive_pwm0.save(out)?;
+        self.sifive_pwm1.save(out)?;
+        self.virtio.save(out)?;
+        self.vsock.save(out)?;
+        self.snd.save(out)?;
+        write_u64(out, self.pflash.is_some() as u64)?;
+        if let Some(pflash) = &self.pflash {
+            pflash.save(out)?;
+        }
+        write_u64(out, self.filestore.is_some() as u64)?;
+        if let Some(filestore) = &self.filestore {
+            filestore.save(out)?;
+        }
+        self.dram.save(out)
+    }
+
+    /// Restore the state of devices from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.emuctl.restore(input)?;
+        self.rtc.restore(input)?;
+        self.clint.restore(input)?;
+        self.plic.restore(input)?;
+        self.uart.restore(input)?;
+        self.sifive_uart.restore(input)?;
+        self.sifive_pwm0.restore(input)?;
+        self.sifive_pwm1.restore(input)?;
+        self.virtio.restore(input)?;
+        self.vsock.restore(input)?;
+        self.snd.restore(input)?;
+        if (read_u64(input)? != 0) != self.pflash.is_some() {
+            return Err(mismatch("flash"));
+        }
+        if let Some(pflash) = &mut self.pflash {
+            pflash.restore(input)?;
+        }
+        if (read_u64(input)? != 0) != self.filestore.is_some() {
+            return Err(mismatch("file export device"));
+        }
+        if let Some(filestore) = &mut self.filestore {
+            filestore.restore(input)?;
+        }
+        self.dram.restore(input)
+    }
+
+    /// Clear the dram and copy `binary` to its start for another run. Devices connected to the
+    /// host, i.e. the consoles, the virtio devices, the flash and plugins, keep their state and
+    /// the others are reset.
+    pub fn reset(&mut self, binary: &[u8]) {
+        self.dram.reset(binary);
+        self.emuctl.reset();
+        self.clint.reset();
+        self.plic.reset();
+        self.sifive_pwm0.reset();
+        self.sifive_pwm1.reset();
+        self.plugin_irqs.clear();
+    }
+
+    /// Reset every device and restore the images loaded to the dram for a machine-wide reset,
+    /// e.g. when a guest reboots. Devices keep their connections to the host, e.g. the disk
+    /// image, the consoles and the flash file. Plugins aren't told about a reset.
+    pub fn reset_devices(&mut self) {
+        self.dram.restore_images();
+        self.emuctl.reset();
+        self.debugcon.reset();
+        self.rtc.reset();
+        self.clint.reset();
+        self.plic.reset();
+        self.uart.reset();
+        self.sifive_uart.reset();
+        self.sifive_pwm0.reset();
+        self.sifive_pwm1.reset();
+        self.virtio.reset();
+        self.vsock.reset();
+        self.snd.reset();
+        if let Some(pflash) = self.pflash.as_mut() {
+            pflash.reset();
+        }
+        if let Some(filestore) = self.filestore.as_mut() {
+            filestore.reset();
+        }
+        for plugin in self.plugins.iter_mut() {
+            plugin.reset();
+        }
+        self.plugin_irqs.clear();
+    }
+
+    /// Fill the dram by `fill` except the images written so far, also at each reset.
+    pub fn set_memfill(&mut self, fill: MemFill) {
+        self.dram.set_fill(fill);
+    }
+
+    /// Copy an image such as a kernel to the dram starting at `addr`.
+    pub fn write_image(&mut self, addr: u64, image: &[u8]) {
+        self.dram
+            .write_image(addr - self.dram_range().start + DRAM_BASE, image);
+    }
+
+    /// Disconnect the selected console from stdin and stdout. Input is read from `input` instead,
+    /// and output is only copied to serial backends.
+    pub fn detach_console(&mut self, input: Box<dyn Read + Send>) {
+        match self.console {
+            ConsoleKind::Ns16550 => self.uart.detach(input),
+            ConsoleKind::Sifive => self.sifive_uart.detach(input),
+        }
+    }
+
+    /// Add an output which bytes transmitted by the selected console are copied to.
+    pub fn add_serial_backend(&mut self, backend: Box<dyn Write + Send>) {
+        match self.console {
+            ConsoleKind::Ns16550 => self.uart.add_backend(backend),
+            ConsoleKind::Sifive => self.sifive_uart.add_backend(backend),
         }
-        if UART_BASE <= addr && addr < UART_BASE + UART_SIZE {
-            return self.uart.store(addr, size, value);
+    }
+
+    /// Return the index of the region containing `addr`. The page table finds it at once unless
+    /// the page is shared by regions or partly unmapped.
+    fn region(&self, addr: u64) -> Option<usize> {
+        match self.pages.get((addr >> BUS_PAGE_SHIFT) as usize) {
+            Some(&index) if index != UNMAPPED => return Some(index as usize),
+            _ => {}
         }
-        if VIRTIO_BASE <= addr && addr < VIRTIO_BASE + VIRTIO_SIZE {
-            return self.virtio.store(addr, size, value);
+        self.regions
+            .iter()
+            .position(|region| region.range.contains(&addr))
+    }
+
+    pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
+        let (index, width) = match (self.region(addr), Width::from_bits(size)) {
+            (Some(index), Some(width)) => (index, width),
+            _ => return Err(Exception::LoadAccessFault(addr)),
+        };
+        let region = &self.regions[index];
+        let addr = addr - region.range.start + region.base;
+        let result = match region.target {
+            Target::Dram => self.dram.load(addr, width),
+            Target::Emuctl => self.emuctl.load(addr, width),
+            Target::Debugcon => self.debugcon.load(addr, width),
+            Target::Rtc => self.rtc.load(addr, width),
+            Target::Clint => self.clint.load(addr, width),
+            Target::Plic => self.plic.load(addr, width),
+            Target::Uart => self.uart.load(addr, width),
+            Target::SifiveUart => self.sifive_uart.load(addr, width),
+            Target::SifivePwm0 => self.sifive_pwm0.load(addr, width),
+            Target::SifivePwm1 => self.sifive_pwm1.load(addr, width),
+            Target::Virtio => self.virtio.load(addr, width),
+            Target::Vsock => self.vsock.load(addr, width),
+            Target::Snd => self.snd.load(addr, width),
+            Target::Pflash => match self.pflash.as_mut() {
+                Some(pflash) => pflash.load(addr, width),
+                None => Err(Exception::LoadAccessFault(addr)),
+            },
+            Target::Filestore => match self.filestore.as_ref() {
+                Some(filestore) => filestore.load(addr, width),
+                None => Err(Exception::LoadAccessFault(addr)),
+            },
+            Target::Plugin(i) => self.plugins[i].load(addr, width),
+        };
+        if !self.mmio_trace.is_empty() {
+            self.trace_mmio(index, addr, false, size, result.as_ref().ok().copied());
+        }
+        result
+    }
+
+    pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
+        if self.is_rom(addr, size / 8) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        let (index, width) = match (self.region(addr), Width::from_bits(size)) {
+            (Some(index), Some(width)) => (index, width),
+            _ => return Err(Exception::StoreAMOAccessFault(addr)),
+        };
+        let region = &self.regions[index];
+        let addr = addr - region.range.start + region.base;
+        let result = match region.target {
+            Target::Dram => self.dram.store(addr, width, value),
+            Target::Emuctl => self.emuctl.store(addr, width, value),
+            Target::Debugcon => self.debugcon.store(addr, width, value),
+            Target::Rtc => self.rtc.store(addr, width, value),
+            Target::Clint => self.clint.store(addr, width, value),
+            Target::Plic => self.plic.store(addr, width, value),
+            Target::Uart => self.uart.store(addr, width, value),
+            Target::SifiveUart => self.sifive_uart.store(addr, width, value),
+            Target::SifivePwm0 => self.sifive_pwm0.store(addr, width, value),
+            Target::SifivePwm1 => self.sifive_pwm1.store(addr, width, value),
+            Target::Virtio => self.virtio.store(addr, width, value),
+            Target::Vsock => self.vsock.store(addr, width, value),
+            Target::Snd => self.snd.store(addr, width, value),
+            Target::Pflash => match self.pflash.as_mut() {
+                Some(pflash) => pflash.store(addr, width, value),
+                None => Err(Exception::StoreAMOAccessFault(addr)),
+            },
+            // A command reads the dram directly.
+            Target::Filestore => {
+                let mut dma = Dma {
+                    range: self.dram_range(),
+                    dram: &mut self.dram,
+                };
+                match self.filestore.as_mut() {
+                    Some(filestore) => filestore.store(addr, width, value, &mut dma),
+                    None => Err(Exception::StoreAMOAccessFault(addr)),
+                }
+            }
+            Target::Plugin(i) => self.plugins[i].store(addr, width, value),
+        };
+        if !self.mmio_trace.is_empty() {
+            let traced = result.as_ref().ok().map(|_| value & width.mask());
+            self.trace_mmio(index, addr, true, size, traced);
+        }
+        result
+    }
+
+    /// Atomically read, modify and write a value for an AMO, and return the old value. The
+    /// device sees a load and a store with nothing in between, unlike separate accesses.
+    pub fn amo(&mut self, addr: u64, width: Width, op: Amo) -> Result<u64, Exception> {
+        if self.is_rom(addr, width.bytes()) {
+            return Err(Exception::StoreAMOAccessFault(addr));
         }
-        if DRAM_BASE <= addr {
-            return self.dram.store(addr, size, value);
+        let index = match self.region(addr) {
+            Some(index) => index,
+            None => return Err(Exception::StoreAMOAccessFault(addr)),
+        };
+        let region = &self.regions[index];
+        let addr = addr - region.range.start + region.base;
+        let result = match region.target {
+            Target::Dram => self.dram.amo(addr, width, op),
+            Target::Emuctl => self.emuctl.amo(addr, width, op),
+            Target::Debugcon => self.debugcon.amo(addr, width, op),
+            Target::Rtc => self.rtc.amo(addr, width, op),
+            Target::Clint => self.clint.amo(addr, width, op),
+            Target::Plic => self.plic.amo(addr, width, op),
+            Target::Uart => self.uart.amo(addr, width, op),
+            Target::SifiveUart => self.sifive_uart.amo(addr, width, op),
+            Target::SifivePwm0 => self.sifive_pwm0.amo(addr, width, op),
+            Target::SifivePwm1 => self.sifive_pwm1.amo(addr, width, op),
+            Target::Virtio => self.virtio.amo(addr, width, op),
+            Target::Vsock => self.vsock.amo(addr, width, op),
+            Target::Snd => self.snd.amo(addr, width, op),
+            Target::Pflash => match self.pflash.as_mut() {
+                Some(pflash) => pflash.amo(addr, width, op),
+                None => Err(Exception::StoreAMOAccessFault(addr)),
+            },
+            // A command reads the dram directly.
+            Target::Filestore => {
+                let mut dma = Dma {
+                    range: self.dram_range(),
+                    dram: &mut self.dram,
+                };
+                match self.filestore.as_mut() {
+                    Some(filestore) => filestore.load(addr, width).and_then(|old| {
+                        filestore.store(addr, width, op.apply(old, width), &mut dma)?;
+                        Ok(old)
+                    }),
+                    None => Err(Exception::StoreAMOAccessFault(addr)),
+                }
+            }
+            Target::Plugin(i) => self.plugins[i].amo(addr, width, op),
+        };
+        // The old value is traced as a load, and the new one as a store.
+        if !self.mmio_trace.is_empty() {
+            if let Ok(old) = result {
+                let (size, mask) = (width.bits(), width.mask());
+                self.trace_mmio(index, addr, false, size, Some(old & mask));
+                self.trace_mmio(index, addr, true, size, Some(op.apply(old, width) & mask));
+            }
         }
-        Err(Exception::StoreAMOAccessFault)
+        result
     }
 }
@@ -0,0 +1,72 @@
+//! The checkpoint module contains snapshots taken while a guest keeps running. Sending SIGUSR1 to
+//! the emulator or the QMP command `checkpoint` requests one, and the emulator writes it at the
+//! next instruction boundary to `<prefix>-<n>.snap`, numbered from 1:
+//!
+//! ```text
+//! kill -USR1 $(pidof rvemu-for-book)
+//! ```
+
+use std::io;
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu::*;
+use crate::snapshot;
+
+/// The default prefix of checkpoint files.
+pub const DEFAULT_CHECKPOINT_PREFIX: &str = "rvemu-checkpoint";
+
+#[cfg(target_os = "linux")]
+const SIGUSR1: c_int = 10;
+#[cfg(not(target_os = "linux"))]
+const SIGUSR1: c_int = 30;
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+/// True if a checkpoint has been requested and not written yet.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_signum: c_int) {
+    // Only async-signal-safe operations are allowed here.
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Request a checkpoint. It's written when the emulator polls next.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Writer of checkpoints.
+pub struct Checkpoint {
+    prefix: String,
+    /// The number of checkpoints written.
+    count: u64,
+}
+
+impl Checkpoint {
+    /// Handle SIGUSR1 by requesting a checkpoint, written to files starting with `prefix`.
+    pub fn install(prefix: &str) -> Self {
+        // SAFETY: the handler only stores to an atomic variable.
+        unsafe {
+            signal(SIGUSR1, on_signal);
+        }
+        Self {
+            prefix: prefix.to_string(),
+            count: 0,
+        }
+    }
+
+    /// Write a checkpoint if one has been requested. Return the path of the written file.
+    pub fn poll(&mut self, cpu: &Cpu) -> io::Result<Option<String>> {
+        if !REQUESTED.load(Ordering::Relaxed) {
+            return Ok(None);
+        }
+        REQUESTED.store(false, Ordering::Relaxed);
+        self.count += 1;
+        let path = format!("{}-{}.snap", self.prefix, self.count);
+        snapshot::save(cpu, &path)?;
+        Ok(Some(path))
+    }
+}
@@ -0,0 +1,98 @@
+//! The chrome_trace module contains an exporter of guest execution in the Trace Event format, which
+//! can be opened by chrome://tracing or Perfetto UI. Each function call becomes a span derived from
+//! a call (jal/jalr linking to ra) and a return (jalr x0, 0(ra)), and traps and privilege mode
+//! changes become instant events. A timestamp is the number of executed instructions.
+//!
+//! The format spec:
+//! https://docs.google.com/document/d/1CvAClvFfyA5R-PhYUmn5OOQtYMH4h6I0nSsKchNAySU
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::cpu::*;
+use crate::trace_file::TraceFile;
+
+/// An exporter writing trace events to a file.
+pub struct ChromeTrace {
+    out: TraceFile,
+    /// The timestamp of the next event, i.e. the number of executed instructions.
+    ts: u64,
+    /// The privilege mode when the last event was recorded.
+    mode: Mode,
+    /// True if no event has been written yet.
+    first: bool,
+}
+
+impl ChromeTrace {
+    /// Create a new trace file at `path`.
+    pub fn create(path: &str, cpu: &Cpu) -> io::Result<Self> {
+        let mut out = TraceFile::create(path)?;
+        out.write_all(b"[\n")?;
+        Ok(Self {
+            out,
+            ts: 0,
+            mode: cpu.mode,
+            first: true,
+        })
+    }
+
+    fn event(&mut self, name: &str, category: &str, phase: &str) -> io::Result<()> {
+        if !self.first {
+            self.out.write_all(b",\n")?;
+        }
+        self.first = false;
+        let scope = if phase == "i" { r#","s":"g""# } else { "" };
+        write!(
+            self.out,
+            r#"{{"name":"{}","cat":"{}","ph":"{}","ts":{},"pid":0,"tid":0{}}}"#,
+            name, category, phase, self.ts, scope
+        )
+    }
+
+    /// Record a privilege mode change if the mode differs from the last recorded one.
+    fn sync_mode(&mut self, cpu: &Cpu) -> io::Result<()> {
+        if self.mode == cpu.mode {
+            return Ok(());
+        }
+        let name = format!("{:?} -> {:?}", self.mode, cpu.mode);
+        self.mode = cpu.mode;
+        self.event(&name, "mode", "i")
+    }
+
+    /// Record an instruction `inst` at `pc` which has been executed. `cpu` is the state after the
+    /// execution.
+    pub fn instruction(&mut self, cpu: &Cpu, pc: u64, inst: u64) -> io::Result<()> {
+        let opcode = inst & 0x7f;
+        let rd = (inst >> 7) & 0x1f;
+        let rs1 = (inst >> 15) & 0x1f;
+        match opcode {
+            // jal or jalr linking to ra (x1) or t0 (x5) is a call.
+            0x6f | 0x67 if rd == 1 || rd == 5 => {
+                let name = format!("{:#x}", cpu.pc);
+                self.event(&name, "call", "B")?;
+            }
+            // jalr x0, 0(ra) is a return.
+            0x67 if rd == 0 && rs1 == 1 => {
+                let name = format!("{:#x}", pc);
+                self.event(&name, "call", "E")?;
+            }
+            _ => {}
+        }
+        self.sync_mode(cpu)?;
+        self.ts += 1;
+        Ok(())
+    }
+
+    /// Record a trap such as an exception or an interrupt. `cpu` is the state after taking the
+    /// trap.
+    pub fn trap(&mut self, cpu: &Cpu, name: &str) -> io::Result<()> {
+        self.event(name, "trap", "i")?;
+        self.sync_mode(cpu)
+    }
+
+    /// Close the JSON array and finish the file.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.out.write_all(b"\n]\n")?;
+        self.out.finish()
+    }
+}
@@ -1,8 +1,18 @@
 //! The clint module contains the core-local interruptor (CLINT). The CLINT
 //! block holds memory-mapped control and status registers associated with
 //! software and timer interrupts. It generates per-hart software interrupts and timer.
+//!
+//! mtime follows the host clock, or advances by instructions if the machine is deterministic. A
+//! host stall, e.g. a debugger halting the hart, is counted only up to `MAX_CATCH_UP_NANOS`, so
+//! the guest doesn't see the time jump. The guest clock lags the host clock by the time which
+//! isn't counted, and `Clint::drift` reports it.
+
+use std::io;
+use std::io::prelude::*;
+use std::time::Instant;
 
 use crate::bus::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
 /// The address of a mtimecmp register starts. A mtimecmp is a dram mapped machine mode timer
@@ -12,35 +22,161 @@ pub const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
 /// constant frequency.
 pub const CLINT_MTIME: u64 = CLINT_BASE + 0xbff8;
 
+/// The frequency of mtime (10 MHz), same as the timebase of QEMU virt machine.
+pub const MTIME_FREQUENCY: u64 = 10_000_000;
+const NANOS_PER_TICK: u64 = 1_000_000_000 / MTIME_FREQUENCY;
+/// The time of an instruction when the clock advances by instructions (100 MIPS).
+const NANOS_PER_INSTRUCTION: u64 = 10;
+/// The number of instructions between reads of the host clock.
+const HOST_CLOCK_INTERVAL: u64 = 1024;
+/// The maximum host time counted at a read of the host clock.
+const MAX_CATCH_UP_NANOS: u64 = 10_000_000;
+
 /// The core-local interruptor (CLINT).
 pub struct Clint {
     mtime: u64,
     mtimecmp: u64,
+    /// True if the clock advances by instructions instead of the host clock.
+    deterministic: bool,
+    /// The number of instructions since the host clock was read.
+    instructions: u64,
+    /// The guest time in nanoseconds which isn't counted in mtime yet.
+    remainder: u64,
+    /// The guest time in nanoseconds since the clock started.
+    guest_nanos: u64,
+    /// The host instant when the clock started.
+    started: Instant,
+    /// The host instant when the host clock was read last.
+    last: Instant,
 }
 
 impl Device for Clint {
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match size {
-            64 => Ok(self.load64(addr)),
-            _ => Err(Exception::LoadAccessFault),
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::DoubleWord => Ok(self.load64(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
-    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match size {
-            64 => Ok(self.store64(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::DoubleWord => {
+                self.store64(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
+
+    fn register_name(&self, addr: u64, _store: bool) -> Option<&'static str> {
+        match addr {
+            CLINT_MTIMECMP => Some("mtimecmp"),
+            CLINT_MTIME => Some("mtime"),
+            _ => None,
+        }
+    }
+
+    /// The clock keeps advancing by instructions if it did.
+    fn reset(&mut self) {
+        *self = Self {
+            deterministic: self.deterministic,
+            ..Self::new()
+        };
+    }
 }
 
 impl Clint {
     /// Create a new `Clint` object.
     pub fn new() -> Self {
+        let now = Instant::now();
         Self {
             mtime: 0,
-            mtimecmp: 0,
+            // No timer interrupt is pending until a guest sets mtimecmp.
+            mtimecmp: u64::MAX,
+            deterministic: false,
+            instructions: 0,
+            remainder: 0,
+            guest_nanos: 0,
+            started: now,
+            last: now,
+        }
+    }
+
+    /// Advance the clock by instructions instead of the host clock.
+    pub fn set_deterministic(&mut self) {
+        self.deterministic = true;
+    }
+
+    /// Return the value of mtime.
+    pub fn mtime(&self) -> u64 {
+        self.mtime
+    }
+
+    /// Advance mtime by one instruction, reading the host clock every `HOST_CLOCK_INTERVAL`
+    /// instructions unless the clock is deterministic.
+    pub fn tick(&mut self) {
+        if self.deterministic {
+            self.advance(NANOS_PER_INSTRUCTION);
+            return;
+        }
+        self.instructions += 1;
+        if self.instructions < HOST_CLOCK_INTERVAL {
+            return;
         }
+        self.instructions = 0;
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_nanos() as u64;
+        self.last = now;
+        self.advance(elapsed.min(MAX_CATCH_UP_NANOS));
+    }
+
+    fn advance(&mut self, nanos: u64) {
+        self.guest_nanos += nanos;
+        let nanos = self.remainder + nanos;
+        self.mtime = self.mtime.wrapping_add(nanos / NANOS_PER_TICK);
+        self.remainder = nanos % NANOS_PER_TICK;
+    }
+
+    /// Return true if the timer interrupt is pending, i.e., mtime has reached mtimecmp.
+    pub fn is_interrupting(&self) -> bool {
+        self.mtime >= self.mtimecmp
+    }
+
+    /// Return how far the guest clock lags the host clock in nanoseconds since the clock started.
+    /// It's negative if the guest clock runs ahead, which happens if the clock is deterministic
+    /// and the emulator runs faster than 100 MIPS.
+    pub fn drift(&self) -> i64 {
+        self.started.elapsed().as_nanos() as i64 - self.guest_nanos as i64
+    }
+
+    /// Return the timer registers for the monitor.
+    pub fn info(&self) -> String {
+        let state = match self.mtime >= self.mtimecmp {
+            true => "expired",
+            false => "armed",
+        };
+        format!(
+            "mtime     {:#018x}\nmtimecmp  {:#018x} ({})",
+            self.mtime, self.mtimecmp, state
+        )
+    }
+
+    /// Save the registers for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u64(out, self.mtime)?;
+        write_u64(out, self.mtimecmp)
+    }
+
+    /// Restore the registers from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.mtime = read_u64(input)?;
+        self.mtimecmp = read_u64(input)?;
+        // The drift is measured from the restore.
+        self.remainder = 0;
+        self.guest_nanos = 0;
+        self.started = Instant::now();
+        self.last = self.started;
+        Ok(())
     }
 
     fn load64(&self, addr: u64) -> u64 {
@@ -0,0 +1,187 @@
+//! The compare module contains a mode which runs the same guest several times and compares the
+//! architectural state at checkpoints, to catch nondeterminism which device threads and channels
+//! bring into a run that should be reproducible:
+//!
+//! ```text
+//! rvemu-for-book --seed 1 --compare-runs 3 xv6-kernel.bin xv6-fs.img < input.txt
+//! ```
+//!
+//! Each run builds a new CPU with the same options, seed and console input, and executes up to a
+//! number of instructions. The pc, the privilege mode, the registers, the CSRs, a hash of the dram
+//! and the number of bytes devices transferred are recorded every `interval` instructions and
+//! when the run stops, and compared with the first run.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::io;
+
+use crate::cpu::*;
+use crate::disasm::csr_name;
+use crate::emulator::Emulator;
+
+/// The default maximum number of instructions of a run.
+pub const DEFAULT_COMPARE_STEPS: u64 = 10_000_000;
+/// The default number of instructions between checkpoints.
+pub const DEFAULT_COMPARE_INTERVAL: u64 = 1_000_000;
+
+/// Return a hash of `bytes`, whose length is a multiple of 8, mixing a 64-bit word at a time.
+fn hash(bytes: &[u8]) -> u64 {
+    bytes
+        .chunks_exact(8)
+        .fold(0xcbf2_9ce4_8422_2325, |hash, word| {
+            let word = u64::from_le_bytes(word.try_into().unwrap());
+            (hash ^ word)
+                .wrapping_mul(0x0000_0100_0000_01b3)
+                .rotate_left(31)
+        })
+}
+
+/// The architectural state at a checkpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct State {
+    /// The number of instructions executed.
+    step: u64,
+    pc: u64,
+    mode: Mode,
+    regs: Vec<u64>,
+    csrs: Vec<u64>,
+    /// A hash of the dram.
+    dram: u64,
+    /// The number of bytes devices transferred.
+    io_bytes: u64,
+}
+
+impl State {
+    fn record(cpu: &Cpu, step: u64) -> Self {
+        Self {
+            step,
+            pc: cpu.pc,
+            mode: cpu.mode,
+            regs: cpu.regs.to_vec(),
+            csrs: cpu.csrs.to_vec(),
+            dram: hash(cpu.bus.dram_bytes()),
+            io_bytes: cpu.bus.io_bytes(),
+        }
+    }
+
+    /// Return the differences from `expected`.
+    fn diff(&self, expected: &State) -> Vec<String> {
+        let mut differences = Vec::new();
+        if self.step != expected.step {
+            differences.push(format!(
+                "stopped at step {}, expected {}",
+                self.step, expected.step
+            ));
+        }
+        if self.pc != expected.pc {
+            differences.push(format!("pc {:#x}, expected {:#x}", self.pc, expected.pc));
+        }
+        if self.mode != expected.mode {
+            differences.push(format!(
+                "mode {:?}, expected {:?}",
+                self.mode, expected.mode
+            ));
+        }
+        for (i, (value, expected)) in self.regs.iter().zip(expected.regs.iter()).enumerate() {
+            if value != expected {
+                differences.push(format!("x{} {:#x}, expected {:#x}", i, value, expected));
+            }
+        }
+        for (i, (value, expected)) in self.csrs.iter().zip(expected.csrs.iter()).enumerate() {
+            if value != expected {
+                differences.push(format!(
+                    "{} {:#x}, expected {:#x}",
+                    csr_name(i as u64),
+                    value,
+                    expected
+                ));
+            }
+        }
+        if self.dram != expected.dram {
+            differences.push("the dram differs".to_string());
+        }
+        if self.io_bytes != expected.io_bytes {
+            differences.push(format!(
+                "devices transferred {} bytes, expected {}",
+                self.io_bytes, expected.io_bytes
+            ));
+        }
+        differences
+    }
+}
+
+/// A run whose state differs from the first run.
+#[derive(Debug)]
+pub struct Mismatch {
+    /// The index of the run, from 1 for the second run.
+    pub run: u64,
+    /// The number of instructions executed at the first differing checkpoint.
+    pub step: u64,
+    pub differences: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "run {} differs from run 0 at step {}:",
+            self.run, self.step
+        )?;
+        for difference in &self.differences {
+            write!(f, "\n  {}", difference)?;
+        }
+        Ok(())
+    }
+}
+
+/// Execute `cpu` until it exits, a fatal exception occurs or it executes `max_steps`
+/// instructions, and return the states every `interval` instructions and at the end.
+fn record(cpu: Cpu, max_steps: u64, interval: u64) -> Vec<State> {
+    let mut states = Vec::new();
+    let mut step = 0;
+    let mut emu = Emulator::new(cpu);
+    while step < max_steps && emu.step().is_some() {
+        step += 1;
+        if step.is_multiple_of(interval) {
+            states.push(State::record(&emu.cpu, step));
+        }
+    }
+    if !step.is_multiple_of(interval) || step == 0 {
+        states.push(State::record(&emu.cpu, step));
+    }
+    states
+}
+
+/// Run a CPU built by `build` `runs` times, and return the runs whose states differ from the
+/// first one at a checkpoint.
+pub fn run<F>(mut build: F, runs: u64, max_steps: u64, interval: u64) -> io::Result<Vec<Mismatch>>
+where
+    F: FnMut() -> io::Result<Cpu>,
+{
+    let expected = record(build()?, max_steps, interval);
+    let mut mismatches = Vec::new();
+    for run in 1..runs {
+        let states = record(build()?, max_steps, interval);
+        let first_difference = states
+            .iter()
+            .zip(expected.iter())
+            .map(|(state, expected)| (state.step, state.diff(expected)))
+            .find(|(_, differences)| !differences.is_empty());
+        // A run which stops earlier or later differs at its last state.
+        let (last, expected_last) = (states.last(), expected.last());
+        let first_difference = first_difference.or_else(|| match (last, expected_last) {
+            (Some(last), Some(expected_last)) if last.step != expected_last.step => {
+                Some((last.step, last.diff(expected_last)))
+            }
+            _ => None,
+        });
+        if let Some((step, differences)) = first_difference {
+            mismatches.push(Mismatch {
+                run,
+                step,
+                differences,
+            });
+        }
+    }
+    Ok(mismatches)
+}
@@ -0,0 +1,169 @@
+//! The coredump module contains a writer of ELF core files for post-mortem debugging. When a
+//! fatal exception stops the emulator and `--core <file>` is given, the integer registers and the
+//! dram are written as a core file which GDB opens against the guest ELF:
+//!
+//! ```text
+//! $ riscv64-unknown-elf-gdb kernel core
+//! (gdb) bt
+//! ```
+//!
+//! The registers are in an NT_PRSTATUS note in the layout of `struct elf_prstatus` on Linux
+//! riscv64, with the pc of the faulting instruction, and the dram is a PT_LOAD segment at its
+//! physical address. A kernel whose virtual addresses differ from physical ones, e.g. a Linux
+//! kernel, only shows the memory of identity-mapped addresses. CSRs aren't included.
+//!
+//! See the spec: https://refspecs.linuxfoundation.org/elf/gabi4+/ch5.pheader.html
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+
+use crate::cpu::*;
+use crate::trap::*;
+
+/// The size of the ELF header.
+const EHDR_SIZE: u64 = 64;
+/// The size of a program header.
+const PHDR_SIZE: u64 = 56;
+/// The file type of a core file.
+const ET_CORE: u16 = 4;
+/// The machine type of RISC-V.
+const EM_RISCV: u16 = 243;
+/// Program header types.
+const PT_LOAD: u32 = 1;
+const PT_NOTE: u32 = 4;
+/// Segment permissions, readable, writable and executable.
+const PF_RWX: u32 = 7;
+/// The alignment of a PT_LOAD segment in the file.
+const PAGE_SIZE: u64 = 0x1000;
+
+/// The note type of the process status, which holds the registers.
+const NT_PRSTATUS: u32 = 1;
+/// The size of `struct elf_prstatus` on riscv64.
+const PRSTATUS_SIZE: usize = 376;
+/// The offset of the signal number in `struct elf_prstatus`, `pr_info.si_signo`.
+const PRSTATUS_SIGNO: usize = 0;
+/// The offset of the current signal, `pr_cursig`.
+const PRSTATUS_CURSIG: usize = 12;
+/// The offset of the process ID, `pr_pid`.
+const PRSTATUS_PID: usize = 32;
+/// The offset of the registers, `pr_reg`, which are the pc and x1 to x31.
+const PRSTATUS_REG: usize = 112;
+
+/// Signal numbers which GDB reports as the reason the program stopped.
+const SIGILL: u32 = 4;
+const SIGTRAP: u32 = 5;
+const SIGBUS: u32 = 7;
+const SIGSEGV: u32 = 11;
+
+/// Return the signal which a Unix-like kernel would send for an exception.
+fn signal(exception: &Exception) -> u32 {
+    match exception {
+        Exception::IllegalInstruction => SIGILL,
+        Exception::InstructionAddressMisaligned(_)
+        | Exception::LoadAddressMisaligned(_)
+        | Exception::StoreAMOAddressMisaligned(_) => SIGBUS,
+        Exception::InstructionAccessFault(_)
+        | Exception::LoadAccessFault(_)
+        | Exception::StoreAMOAccessFault(_)
+        | Exception::InstructionPageFault(_)
+        | Exception::LoadPageFault(_)
+        | Exception::StoreAMOPageFault(_) => SIGSEGV,
+        Exception::Breakpoint
+        | Exception::EnvironmentCallFromUMode
+        | Exception::EnvironmentCallFromSMode
+        | Exception::EnvironmentCallFromMMode => SIGTRAP,
+    }
+}
+
+/// Return the descriptor of the NT_PRSTATUS note.
+fn prstatus(cpu: &Cpu, pc: u64, exception: &Exception) -> Vec<u8> {
+    let mut desc = vec![0; PRSTATUS_SIZE];
+    let signo = signal(exception);
+    desc[PRSTATUS_SIGNO..PRSTATUS_SIGNO + 4].copy_from_slice(&signo.to_le_bytes());
+    desc[PRSTATUS_CURSIG..PRSTATUS_CURSIG + 2].copy_from_slice(&(signo as u16).to_le_bytes());
+    desc[PRSTATUS_PID..PRSTATUS_PID + 4].copy_from_slice(&1u32.to_le_bytes());
+    // x0 is always zero, so its slot holds the pc.
+    let regs = std::iter::once(pc).chain(cpu.regs[1..].iter().copied());
+    for (i, value) in regs.enumerate() {
+        let offset = PRSTATUS_REG + i * 8;
+        desc[offset..offset + 8].copy_from_slice(&value.to_le_bytes());
+    }
+    desc
+}
+
+/// Return a note with the name `CORE`, padded to 4 bytes.
+fn note(kind: u32, desc: &[u8]) -> Vec<u8> {
+    let name = b"CORE\0\0\0\0";
+    let mut out = Vec::new();
+    out.extend_from_slice(&5u32.to_le_bytes());
+    out.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(name);
+    out.extend_from_slice(desc);
+    out.resize((out.len() + 3) & !3, 0);
+    out
+}
+
+/// Append a program header.
+fn phdr(out: &mut Vec<u8>, kind: u32, flags: u32, offset: u64, addr: u64, size: u64, align: u64) {
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&flags.to_le_bytes());
+    out.extend_from_slice(&offset.to_le_bytes());
+    // p_vaddr and p_paddr.
+    out.extend_from_slice(&addr.to_le_bytes());
+    out.extend_from_slice(&addr.to_le_bytes());
+    // p_filesz and p_memsz.
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&size.to_le_bytes());
+    out.extend_from_slice(&align.to_le_bytes());
+}
+
+/// Return a core file of `cpu` stopped by `exception` at the instruction at `pc`. It must be
+/// called before the registers are changed by the next instruction.
+pub fn core_image(cpu: &Cpu, pc: u64, exception: &Exception) -> Vec<u8> {
+    let notes = note(NT_PRSTATUS, &prstatus(cpu, pc, exception));
+    let dram = cpu.bus.dram_bytes();
+    let notes_offset = EHDR_SIZE + 2 * PHDR_SIZE;
+    let dram_offset = (notes_offset + notes.len() as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+
+    let mut out = Vec::with_capacity(dram_offset as usize + dram.len());
+    // e_ident: ELFCLASS64, ELFDATA2LSB, EV_CURRENT and ELFOSABI_NONE.
+    out.extend_from_slice(b"\x7fELF\x02\x01\x01\x00");
+    out.resize(16, 0);
+    out.extend_from_slice(&ET_CORE.to_le_bytes());
+    out.extend_from_slice(&EM_RISCV.to_le_bytes());
+    // e_version, e_entry, e_phoff, e_shoff and e_flags.
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&EHDR_SIZE.to_le_bytes());
+    out.extend_from_slice(&0u64.to_le_bytes());
+    out.extend_from_slice(&0u32.to_le_bytes());
+    // e_ehsize, e_phentsize, e_phnum, e_shentsize, e_shnum and e_shstrndx.
+    for half in [EHDR_SIZE as u16, PHDR_SIZE as u16, 2, 0, 0, 0].iter() {
+        out.extend_from_slice(&half.to_le_bytes());
+    }
+
+    let notes_size = notes.len() as u64;
+    phdr(&mut out, PT_NOTE, 0, notes_offset, 0, notes_size, 4);
+    let dram_size = dram.len() as u64;
+    let dram_base = cpu.bus.dram_range().start;
+    phdr(
+        &mut out,
+        PT_LOAD,
+        PF_RWX,
+        dram_offset,
+        dram_base,
+        dram_size,
+        PAGE_SIZE,
+    );
+    out.extend_from_slice(&notes);
+    out.resize(dram_offset as usize, 0);
+    out.extend_from_slice(dram);
+    out
+}
+
+/// Write a core file of `cpu` stopped by `exception` at the instruction at `pc` to `path`.
+pub fn write_core(path: &str, cpu: &Cpu, pc: u64, exception: &Exception) -> io::Result<()> {
+    File::create(path)?.write_all(&core_image(cpu, pc, exception))
+}
@@ -1,17 +1,75 @@
 //! The cpu module contains `Cpu` and implementarion for it.
 
 use crate::bus::*;
+use std::ops::Range;
+
 use crate::dram::*;
+use crate::extensions::Extensions;
+use crate::goldfish_rtc::*;
 use crate::isa::*;
-use crate::mmu::{translate, AccessType, PAGE_SIZE};
-use crate::plic::*;
+use crate::level::*;
+use crate::machine::Machine;
+use crate::mmu::{paging_levels, translate, AccessType, PAGE_SIZE};
+use crate::pmp;
+use crate::sifive_uart::*;
+use crate::timeline::*;
 use crate::trap::*;
 use crate::uart::*;
-use crate::virtio::*;
+
+// User-level CSRs.
+/// Floating-point accrued exceptions, an alias of fcsr[4:0].
+pub const FFLAGS: usize = 0x001;
+/// Floating-point dynamic rounding mode, an alias of fcsr[7:5].
+pub const FRM: usize = 0x002;
+/// Floating-point control and status register.
+pub const FCSR: usize = 0x003;
+/// Cycle counter, a read-only shadow of mcycle.
+pub const CYCLE: usize = 0xc00;
+/// Timer, a read-only shadow of mtime of the CLINT.
+pub const TIME: usize = 0xc01;
+/// Instructions-retired counter, a read-only shadow of minstret.
+pub const INSTRET: usize = 0xc02;
+
+// FFLAGS fields.
+/// Inexact.
+pub const FFLAGS_NX: u64 = 1 << 0;
+/// Underflow.
+pub const FFLAGS_UF: u64 = 1 << 1;
+/// Overflow.
+pub const FFLAGS_OF: u64 = 1 << 2;
+/// Divide by zero.
+pub const FFLAGS_DZ: u64 = 1 << 3;
+/// Invalid operation.
+pub const FFLAGS_NV: u64 = 1 << 4;
+const FFLAGS_MASK: u64 = 0x1f;
+
+// Rounding modes in the rm field of instructions and in frm.
+/// Round to nearest, ties to even.
+pub const RM_RNE: u64 = 0b000;
+/// Round towards zero.
+pub const RM_RTZ: u64 = 0b001;
+/// Round down (towards negative infinity).
+pub const RM_RDN: u64 = 0b010;
+/// Round up (towards positive infinity).
+pub const RM_RUP: u64 = 0b011;
+/// Round to nearest, ties to max magnitude.
+pub const RM_RMM: u64 = 0b100;
+/// The rm field which selects the rounding mode in frm. It's reserved in frm.
+pub const RM_DYN: u64 = 0b111;
 
 // Machine-level CSRs.
+/// Vendor ID. 0 means a non-commercial implementation.
+pub const MVENDORID: usize = 0xf11;
+/// Architecture ID. 0 means the field isn't implemented.
+pub const MARCHID: usize = 0xf12;
+/// Implementation ID, which is the version of the emulator.
+pub const MIMPID: usize = 0xf13;
+/// Hart ID. The emulator has only hart 0.
+pub const MHARTID: usize = 0xf14;
 /// Machine status register.
 pub const MSTATUS: usize = 0x300;
+/// Machine ISA register. It's read-only and writes are ignored, which WARL allows.
+pub const MISA: usize = 0x301;
 /// Machine exception delefation register.
 pub const MEDELEG: usize = 0x302;
 /// Machine interrupt delefation register.
@@ -28,6 +86,84 @@ pub const MCAUSE: usize = 0x342;
 pub const MTVAL: usize = 0x343;
 /// Machine interrupt pending.
 pub const MIP: usize = 0x344;
+/// Machine counter-enable register. A clear bit makes the counter inaccessible to S-mode and
+/// U-mode.
+pub const MCOUNTEREN: usize = 0x306;
+/// Machine environment configuration register.
+pub const MENVCFG: usize = 0x30a;
+/// Machine counter-inhibit register. The CY bit stops mcycle and the IR bit stops minstret.
+pub const MCOUNTINHIBIT: usize = 0x320;
+/// Machine cycle counter. Every instruction takes one cycle.
+pub const MCYCLE: usize = 0xb00;
+/// Machine instructions-retired counter.
+pub const MINSTRET: usize = 0xb02;
+/// Machine state enable registers (Smstateen). Only mstateen0 has defined bits.
+pub const MSTATEEN0: usize = 0x30c;
+pub const MSTATEEN3: usize = 0x30f;
+
+// MSTATEEN0 fields. A bit set to 0 makes the state inaccessible to S-mode and U-mode.
+/// Context CSRs such as scontext.
+pub const MSTATEEN0_CONTEXT: u64 = 1 << 57;
+/// IMSIC state such as stopei.
+pub const MSTATEEN0_IMSIC: u64 = 1 << 58;
+/// AIA state such as stopi, except the IMSIC and the indirect CSR access.
+pub const MSTATEEN0_AIA: u64 = 1 << 59;
+/// Indirect CSR access, siselect and sireg.
+pub const MSTATEEN0_CSRIND: u64 = 1 << 60;
+/// senvcfg.
+pub const MSTATEEN0_ENVCFG: u64 = 1 << 62;
+/// sstateen0.
+pub const MSTATEEN0_SE0: u64 = 1 << 63;
+/// Writable bits of mstateen0.
+const MSTATEEN0_MASK: u64 = MSTATEEN0_CONTEXT
+    | MSTATEEN0_IMSIC
+    | MSTATEEN0_AIA
+    | MSTATEEN0_CSRIND
+    | MSTATEEN0_ENVCFG
+    | MSTATEEN0_SE0;
+
+// MCOUNTINHIBIT fields.
+pub const MCOUNTINHIBIT_CY: u64 = 1 << 0;
+pub const MCOUNTINHIBIT_IR: u64 = 1 << 2;
+
+// MCOUNTEREN and SCOUNTEREN fields, the bit of each counter from cycle. The hpmcounters aren't
+// implemented, so their bits are read-only zero.
+pub const COUNTEREN_CY: u64 = 1 << 0;
+pub const COUNTEREN_TM: u64 = 1 << 1;
+pub const COUNTEREN_IR: u64 = 1 << 2;
+const COUNTEREN_MASK: u64 = COUNTEREN_CY | COUNTEREN_TM | COUNTEREN_IR;
+
+// MSTATUS fields which change the permissions of virtual memory accesses.
+/// Modify PRiVilege: loads and stores in M-mode are translated and checked as in the mode of MPP.
+pub const MSTATUS_MPRV: u64 = 1 << 17;
+/// Permit Supervisor User Memory access: S-mode can load and store to pages with the U bit.
+pub const MSTATUS_SUM: u64 = 1 << 18;
+/// Make eXecutable Readable: loads can read pages which are executable but not readable.
+pub const MSTATUS_MXR: u64 = 1 << 19;
+
+// MSTATUS fields which trap instructions of S-mode for M-mode to virtualize them.
+/// Trap Virtual Memory: satp accesses and sfence.vma are illegal in S-mode.
+pub const MSTATUS_TVM: u64 = 1 << 20;
+/// Timeout Wait: wfi is illegal in S-mode if it doesn't complete within `WFI_TIMEOUT`.
+pub const MSTATUS_TW: u64 = 1 << 21;
+/// Trap SRET: sret is illegal in S-mode.
+pub const MSTATUS_TSR: u64 = 1 << 22;
+
+// MSTATUS fields of the floating-point state.
+/// The status of the floating-point state: Off (0), Initial (1), Clean (2) or Dirty (3). The
+/// floating-point CSRs are illegal while it's Off.
+pub const MSTATUS_FS: u64 = 0b11 << 13;
+pub const MSTATUS_FS_INITIAL: u64 = 0b01 << 13;
+/// State Dirty: a read-only summary which is set if FS is Dirty.
+pub const MSTATUS_SD: u64 = 1 << 63;
+
+/// The number of SCs in a row failing at the same pc before they are reported as a livelock of
+/// an LR/SC loop.
+pub const SC_LIVELOCK_FAILURES: u64 = 1000;
+
+/// The number of steps for which wfi can stall in S-mode before it raises an illegal instruction
+/// exception if mstatus.TW is set.
+pub const WFI_TIMEOUT: u64 = 10_000;
 
 // MIP fields.
 pub const MIP_SSIP: u64 = 1 << 1;
@@ -36,6 +172,11 @@ pub const MIP_STIP: u64 = 1 << 5;
 pub const MIP_MTIP: u64 = 1 << 7;
 pub const MIP_SEIP: u64 = 1 << 9;
 pub const MIP_MEIP: u64 = 1 << 11;
+/// Interrupts of S-mode, which sip and sie show if they are delegated.
+const SUPERVISOR_INTERRUPTS: u64 = MIP_SSIP | MIP_STIP | MIP_SEIP;
+
+/// Fields of mstatus which sstatus shows: SIE, SPIE, UBE, SPP, VS, FS, XS, SUM, MXR, UXL and SD.
+const SSTATUS_MASK: u64 = 0x8000_0003_000d_e762;
 
 // Supervisor-level CSRs.
 /// Supervisor status register.
@@ -54,6 +195,32 @@ pub const STVAL: usize = 0x143;
 pub const SIP: usize = 0x144;
 /// Supervisor address translation and protection.
 pub const SATP: usize = 0x180;
+/// Supervisor counter-enable register. A clear bit makes the counter inaccessible to U-mode.
+pub const SCOUNTEREN: usize = 0x106;
+/// Supervisor environment configuration register.
+pub const SENVCFG: usize = 0x10a;
+/// Supervisor state enable registers (Smstateen). Only sstateen0 has defined bits.
+pub const SSTATEEN0: usize = 0x10c;
+pub const SSTATEEN3: usize = 0x10f;
+/// Supervisor indirect register select (Sscsrind).
+pub const SISELECT: usize = 0x150;
+/// Supervisor indirect register alias (Sscsrind).
+pub const SIREG: usize = 0x151;
+/// Supervisor top external interrupt (AIA IMSIC).
+pub const STOPEI: usize = 0x15c;
+/// Supervisor context register (Sdtrig).
+pub const SCONTEXT: usize = 0x5a8;
+/// Supervisor top interrupt (AIA).
+pub const STOPI: usize = 0xdb0;
+
+/// Return the implementation ID in mimpid, which is the version of the emulator as
+/// 0xMMmmpp. The emulator control device reports the same value as its version.
+pub fn implementation_id() -> u64 {
+    let part = |s: &str| s.parse::<u64>().unwrap_or(0) & 0xff;
+    (part(env!("CARGO_PKG_VERSION_MAJOR")) << 16)
+        | (part(env!("CARGO_PKG_VERSION_MINOR")) << 8)
+        | part(env!("CARGO_PKG_VERSION_PATCH"))
+}
 
 /// The privileged mode.
 #[derive(Debug, PartialEq, PartialOrd, Eq, Copy, Clone)]
@@ -77,10 +244,58 @@ pub struct Cpu {
     /// Control and status registers. RISC-V ISA sets aside a 12-bit encoding space (csr[11:0]) for
     /// up to 4096 CSRs.
     pub csrs: [u64; 4096],
-    /// SV39 paging flag.
+    /// SV39, SV48 or SV57 paging flag.
     pub enable_paging: bool,
     /// physical page number (PPN) × PAGE_SIZE (4096).
     pub page_table: u64,
+    /// The number of levels of the page table, 3 for SV39, 4 for SV48 and 5 for SV57.
+    pub page_levels: usize,
+    /// The number of page-table walks performed by the virtual address translation.
+    pub page_walks: u64,
+    /// Physical ranges which S-mode and U-mode can't access.
+    pub protected: Vec<Range<u64>>,
+    /// The policy deciding which exceptions stop the emulator.
+    pub fatal_policy: FatalPolicy,
+    /// The last register write by an instruction.
+    last_write: Option<(usize, u64)>,
+    /// The address of the trap handler entered by the last trap, until it's left.
+    pub(crate) trap_entry: Option<u64>,
+    /// The pc and the cause of the last exception except breakpoints and environment calls.
+    pub(crate) last_exception: Option<(u64, u64)>,
+    /// The number of times the last exception has been raised without the instruction completing.
+    pub(crate) exception_repeats: u64,
+    /// A trap loop which has been detected.
+    pub trap_loop: Option<TrapLoop>,
+    /// The ISA extensions which are executed and reported by misa.
+    pub extensions: Extensions,
+    /// The step of the book up to which subsystems are enabled.
+    level: Level,
+    /// The last privilege-mode transitions, if enabled.
+    pub timeline: Timeline,
+    /// The physical range reserved by the last LR, until a store to it or an SC.
+    reservation: Option<Range<u64>>,
+    /// Check the natural alignment of AMOs, LRs and SCs, and that an SC pairs with the last LR,
+    /// which catches bugs in guest lock code.
+    pub strict_atomics: bool,
+    /// The virtual address and the size of the last LR, until an SC.
+    lr_access: Option<(u64, u64)>,
+    /// An SC which doesn't pair with the last LR, reported if `strict_atomics` is enabled.
+    pub unpaired_sc: Option<String>,
+    /// The number of failed SCs.
+    pub sc_failures: u64,
+    /// The pc of the last failed SC and the number of SCs in a row which have failed there.
+    sc_streak: (u64, u64),
+    /// An LR/SC loop whose SC has failed `SC_LIVELOCK_FAILURES` times in a row.
+    pub sc_livelock: Option<String>,
+    /// The counters written by the executing instruction in the bits of mcountinhibit. A written
+    /// counter isn't incremented by the instruction.
+    counters_written: u64,
+    /// True if the last instruction was a wfi which stalled because no interrupt was pending.
+    waiting: bool,
+    /// The number of consecutive steps for which wfi has stalled.
+    wfi_steps: u64,
+    /// The pc and the registers which a machine-wide reset starts the hart with.
+    boot: (u64, [u64; 32]),
 }
 
 impl Cpu {
@@ -99,9 +314,90 @@ impl Cpu {
             csrs: [0; 4096],
             enable_paging: false,
             page_table: 0,
+            page_levels: 3,
+            page_walks: 0,
+            protected: Vec::new(),
+            fatal_policy: FatalPolicy::Stuck,
+            last_write: None,
+            trap_entry: None,
+            last_exception: None,
+            exception_repeats: 0,
+            trap_loop: None,
+            extensions: Extensions::new(),
+            level: Level::FULL,
+            timeline: Timeline::default(),
+            reservation: None,
+            strict_atomics: false,
+            lr_access: None,
+            unpaired_sc: None,
+            sc_failures: 0,
+            sc_streak: (0, 0),
+            sc_livelock: None,
+            counters_written: 0,
+            waiting: false,
+            wfi_steps: 0,
+            boot: (DRAM_BASE, regs),
         }
     }
 
+    /// Reinitialize the CPU to run `binary` from the start of the dram, as if it was created by
+    /// `Cpu::new`, but reuse the dram buffer. Only the pages written by the last run are cleared,
+    /// so test runners and fuzzers can execute many small programs cheaply. The configuration,
+    /// i.e. protected ranges, the fatal policy, the level, the extensions, the atomic checks and
+    /// devices connected to the host, is kept.
+    pub fn reset_with(&mut self, binary: &[u8]) {
+        self.bus.reset(binary);
+        let dram = self.bus.dram_range();
+        self.regs = [0; 32];
+        self.regs[2] = dram.end;
+        self.pc = dram.start;
+        self.save_boot_state();
+        self.reset_hart();
+    }
+
+    /// Remember the current pc and registers, e.g. the entry point of a kernel and the arguments
+    /// which firmware passes, as the state which a machine-wide reset starts the hart with.
+    pub fn save_boot_state(&mut self) {
+        self.boot = (self.pc, self.regs);
+    }
+
+    /// Reset the machine in place, e.g. when a guest reboots. Every device and the CSRs return to
+    /// their state at power-on, the dram to the images loaded at boot, and the pc and the
+    /// registers to the state saved by `save_boot_state`. Devices keep their connections to the
+    /// host, so the process doesn't restart.
+    pub fn reset(&mut self) {
+        self.bus.reset_devices();
+        let (pc, regs) = self.boot;
+        self.pc = pc;
+        self.regs = regs;
+        self.reset_hart();
+    }
+
+    /// Reset the privilege mode, the CSRs, the paging and the execution state of the hart.
+    fn reset_hart(&mut self) {
+        self.mode = Mode::Machine;
+        self.csrs = [0; 4096];
+        self.enable_paging = false;
+        self.page_table = 0;
+        self.page_levels = 3;
+        self.page_walks = 0;
+        self.last_write = None;
+        self.trap_entry = None;
+        self.last_exception = None;
+        self.exception_repeats = 0;
+        self.trap_loop = None;
+        self.timeline.clear();
+        self.reservation = None;
+        self.lr_access = None;
+        self.unpaired_sc = None;
+        self.sc_failures = 0;
+        self.sc_streak = (0, 0);
+        self.sc_livelock = None;
+        self.counters_written = 0;
+        self.waiting = false;
+        self.wfi_steps = 0;
+    }
+
     /// Print values in all registers (x0-x31).
     pub fn dump_registers(&self) {
         let mut output = String::from("");
@@ -112,23 +408,20 @@ impl Cpu {
         ];
         for i in (0..32).step_by(4) {
             output = format!(
-                "{}\n{}",
+                "{}\nx{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
                 output,
-                format!(
-                    "x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x} x{:02}({})={:>#18x}",
-                    i,
-                    abi[i],
-                    self.regs[i],
-                    i + 1,
-                    abi[i + 1],
-                    self.regs[i + 1],
-                    i + 2,
-                    abi[i + 2],
-                    self.regs[i + 2],
-                    i + 3,
-                    abi[i + 3],
-                    self.regs[i + 3],
-                )
+                i,
+                abi[i],
+                self.regs[i],
+                i + 1,
+                abi[i + 1],
+                self.regs[i + 1],
+                i + 2,
+                abi[i + 2],
+                self.regs[i + 2],
+                i + 3,
+                abi[i + 3],
+                self.regs[i + 3],
             );
         }
         println!("{}", output);
@@ -137,104 +430,123 @@ impl Cpu {
     /// Print values in some csrs.
     pub fn dump_csrs(&self) {
         let output = format!(
-            "{}\n{}",
-            format!(
-                "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}",
-                self.load_csr(MSTATUS),
-                self.load_csr(MTVEC),
-                self.load_csr(MEPC),
-                self.load_csr(MCAUSE),
-            ),
-            format!(
-                "sstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
-                self.load_csr(SSTATUS),
-                self.load_csr(STVEC),
-                self.load_csr(SEPC),
-                self.load_csr(SCAUSE),
-            ),
+            "mstatus={:>#18x} mtvec={:>#18x} mepc={:>#18x} mcause={:>#18x}\n\
+             sstatus={:>#18x} stvec={:>#18x} sepc={:>#18x} scause={:>#18x}",
+            self.load_csr(MSTATUS),
+            self.load_csr(MTVEC),
+            self.load_csr(MEPC),
+            self.load_csr(MCAUSE),
+            self.load_csr(SSTATUS),
+            self.load_csr(STVEC),
+            self.load_csr(SEPC),
+            self.load_csr(SCAUSE),
         );
         println!("{}", output);
     }
 
     pub fn check_pending_interrupt(&mut self) -> Option<Interrupt> {
-        // 3.1.6.1 Privilege and Global Interrupt-Enable Stack in mstatus register
-        // "When a hart is executing in privilege mode x, interrupts are globally enabled when x
-        // IE=1 and globally disabled when x IE=0."
-        match self.mode {
-            Mode::Machine => {
-                // Check if the MIE bit is enabled.
-                if (self.load_csr(MSTATUS) >> 3) & 1 == 0 {
-                    return None;
-                }
-            }
-            Mode::Supervisor => {
-                // Check if the SIE bit is enabled.
-                if (self.load_csr(SSTATUS) >> 1) & 1 == 0 {
-                    return None;
-                }
-            }
-            _ => {}
+        // Advance the timer, the PWM counters, the RTC, plugin devices, console input and disk
+        // requests even while interrupts are disabled.
+        self.bus.tick_timer();
+        self.bus.rtc.tick();
+        self.bus.sifive_pwm0.tick();
+        self.bus.sifive_pwm1.tick();
+        self.bus.tick_plugins();
+        self.bus.tick_input();
+        self.bus.tick_virtio();
+
+        if !self.level.enables(Feature::Interrupts) {
+            return None;
         }
 
-        // Check external interrupt for uart and virtio.
+        // Check external interrupt for uart, rtc, pwm, plugins, virtio, vsock and sound. One
+        // device is polled at a time, and the PLIC holds its IRQ until it's claimed.
         let irq;
         if self.bus.uart.is_interrupting() {
             irq = UART_IRQ;
-        } else if self.bus.virtio.is_interrupting() {
-            // Access disk by direct dram access (DMA). An interrupt is raised after a disk
-            // access is done.
-            Virtio::disk_access(self);
-            irq = VIRTIO_IRQ;
+        } else if self.bus.sifive_uart.is_interrupting() {
+            irq = SIFIVE_UART_IRQ;
+        } else if self.bus.rtc.is_interrupting() {
+            irq = GOLDFISH_RTC_IRQ;
+        } else if let Some(pwm_irq) = self.bus.sifive_pwm0.is_interrupting() {
+            irq = pwm_irq;
+        } else if let Some(pwm_irq) = self.bus.sifive_pwm1.is_interrupting() {
+            irq = pwm_irq;
+        } else if let Some(plugin_irq) = self.bus.plugin_interrupt() {
+            irq = plugin_irq;
+        } else if let Some(virtio_irq) = self.bus.virtio_interrupt() {
+            // Virtio devices access the dram directly (DMA) in `Bus::tick_virtio`. An
+            // interrupt is raised after they use queues unless the driver suppresses it.
+            irq = virtio_irq;
         } else {
             irq = 0;
         }
-
         if irq != 0 {
-            self.bus
-                .store(PLIC_SCLAIM, 32, irq)
-                .expect("failed to write an IRQ to the PLIC_SCLAIM");
-            self.store_csr(MIP, self.load_csr(MIP) | MIP_SEIP);
+            self.bus.raise_irq(irq);
         }
 
-        // "An interrupt i will be taken if bit i is set in both mip and mie, and if interrupts are globally enabled.
-        // By default, M-mode interrupts are globally enabled if the hart’s current privilege mode is less than
-        // M, or if the current privilege mode is M and the MIE bit in the mstatus register is set. If bit i
-        // in mideleg is set, however, interrupts are considered to be globally enabled if the hart’s current
-        // privilege mode equals the delegated privilege mode (S or U) and that mode’s interrupt enable bit
-        // (SIE or UIE in mstatus) is set, or if the current privilege mode is less than the delegated privilege
-        // mode."
+        // The external interrupt is level-sensitive: the PLIC output ORed into SEIP isn't cleared
+        // by taking the trap, but by claiming the IRQ.
+        let mip = self.load_csr(MIP);
 
-        let pending = self.load_csr(MIE) & self.load_csr(MIP);
+        // 3.1.9 Machine Interrupt Registers (mip and mie)
+        // "An interrupt i will trap to M-mode (causing the privilege mode to change to M-mode) if
+        // all of the following are true: (a) either the current privilege mode is M and the MIE
+        // bit in the mstatus register is set, or the current privilege mode has less privilege
+        // than M-mode; (b) bit i is set in both mip and mie; and (c) if register mideleg exists,
+        // bit i is not set in mideleg."
+        // 4.1.3 Supervisor Interrupt Registers (sip and sie)
+        // "An interrupt i will trap to S-mode if both of the following are true: (a) either the
+        // current privilege mode is S and the SIE bit in the sstatus register is set, or the
+        // current privilege mode has less privilege than S-mode; and (b) bit i is set in both
+        // sip and sie."
+        let pending = self.load_csr(MIE) & mip;
+        let mideleg = self.load_csr(MIDELEG);
+        let machine_enabled = match self.mode {
+            Mode::Machine => (self.load_csr(MSTATUS) >> 3) & 1 == 1,
+            _ => true,
+        };
+        let supervisor_enabled = match self.mode {
+            Mode::Machine => false,
+            Mode::Supervisor => (self.load_csr(SSTATUS) >> 1) & 1 == 1,
+            Mode::User => true,
+        };
+        let to_machine = if machine_enabled {
+            pending & !mideleg
+        } else {
+            0
+        };
+        let to_supervisor = if supervisor_enabled {
+            pending & mideleg
+        } else {
+            0
+        };
 
-        if (pending & MIP_MEIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_MEIP);
-            return Some(Interrupt::MachineExternalInterrupt);
-        }
-        if (pending & MIP_MSIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_MSIP);
-            return Some(Interrupt::MachineSoftwareInterrupt);
-        }
-        if (pending & MIP_MTIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_MTIP);
-            return Some(Interrupt::MachineTimerInterrupt);
-        }
-        if (pending & MIP_SEIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_SEIP);
-            return Some(Interrupt::SupervisorExternalInterrupt);
-        }
-        if (pending & MIP_SSIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_SSIP);
-            return Some(Interrupt::SupervisorSoftwareInterrupt);
-        }
-        if (pending & MIP_STIP) != 0 {
-            self.store_csr(MIP, self.load_csr(MIP) & !MIP_STIP);
-            return Some(Interrupt::SupervisorTimerInterrupt);
-        }
-        None
+        // "Interrupts to M-mode take priority over any interrupts to lower privilege modes."
+        // "Multiple simultaneous interrupts destined for M-mode are handled in the following
+        // decreasing priority order: MEI, MSI, MTI, SEI, SSI, STI." S-mode uses the same order.
+        // Pending bits aren't cleared here: sources and software clear them.
+        let selected = if to_machine != 0 {
+            to_machine
+        } else {
+            to_supervisor
+        };
+        // Arrays are iterated by reference in the 2018 edition unless into_iter is called
+        // explicitly.
+        IntoIterator::into_iter([
+            (MIP_MEIP, Interrupt::MachineExternalInterrupt),
+            (MIP_MSIP, Interrupt::MachineSoftwareInterrupt),
+            (MIP_MTIP, Interrupt::MachineTimerInterrupt),
+            (MIP_SEIP, Interrupt::SupervisorExternalInterrupt),
+            (MIP_SSIP, Interrupt::SupervisorSoftwareInterrupt),
+            (MIP_STIP, Interrupt::SupervisorTimerInterrupt),
+        ])
+        .find(|(bit, _)| selected & bit != 0)
+        .map(|(_, interrupt)| interrupt)
     }
 
     /// Update the physical page number (PPN) and the addressing mode.
-    fn update_paging(&mut self, csr_addr: usize) {
+    pub(crate) fn update_paging(&mut self, csr_addr: usize) {
         if csr_addr != SATP {
             return;
         }
@@ -243,21 +555,58 @@ impl Cpu {
         // supervisor physical address divided by 4 KiB.
         self.page_table = (self.load_csr(SATP) & ((1 << 44) - 1)) * PAGE_SIZE;
 
-        // Read the MODE field, which selects the current address-translation scheme.
-        let mode = self.load_csr(SATP) >> 60;
-
-        // Enable the SV39 paging if the value of the mode field is 8.
-        if mode == 8 {
-            self.enable_paging = true;
-        } else {
-            self.enable_paging = false;
+        // Read the MODE field, which selects the current address-translation scheme. Enable the
+        // SV39 paging if the value of the mode field is 8, the SV48 paging if it's 9, or the SV57
+        // paging if it's 10.
+        match paging_levels(self.load_csr(SATP)) {
+            Some(levels) if self.level.enables(Feature::Paging) => {
+                self.enable_paging = true;
+                self.page_levels = levels;
+            }
+            _ => self.enable_paging = false,
         }
     }
 
     /// Load a value from a CSR.
     pub fn load_csr(&self, addr: usize) -> u64 {
         match addr {
-            SIE => self.csrs[MIE] & self.csrs[MIDELEG],
+            MSTATUS => match self.csrs[MSTATUS] & MSTATUS_FS == MSTATUS_FS {
+                true => self.csrs[MSTATUS] | MSTATUS_SD,
+                false => self.csrs[MSTATUS] & !MSTATUS_SD,
+            },
+            // sstatus, sip and sie are views of mstatus, mip and mie restricted to S-mode.
+            SSTATUS => self.load_csr(MSTATUS) & SSTATUS_MASK,
+            SIP => self.load_csr(MIP) & self.csrs[MIDELEG] & SUPERVISOR_INTERRUPTS,
+            // 3.1.9 Machine Interrupt Registers (mip and mie)
+            // "MEIP is read-only in mip, and is set and cleared by a platform-specific interrupt
+            // controller." The PLIC has no M-mode context, so MEIP is always zero. MTIP is likewise
+            // the output of the CLINT comparing mtime with mtimecmp.
+            // "When mip is read with a CSR instruction, the value of the SEIP bit returned in the
+            // rd destination register is the logical-OR of the software-writable bit and the
+            // interrupt signal from the interrupt controller."
+            MIP => {
+                let mut mip = self.csrs[MIP];
+                if self.bus.is_external_interrupting() {
+                    mip |= MIP_SEIP;
+                }
+                if self.bus.is_timer_interrupting() {
+                    mip |= MIP_MTIP;
+                }
+                mip
+            }
+            SIE => self.csrs[MIE] & self.csrs[MIDELEG] & SUPERVISOR_INTERRUPTS,
+            // Only the low 32 bits of sstateen0 can be defined, and a bit is read-only zero if the
+            // same bit in mstateen0 is zero.
+            SSTATEEN0 => self.csrs[SSTATEEN0] & self.csrs[MSTATEEN0] & 0xffff_ffff,
+            // fflags and frm are fields of fcsr, which is stored in `csrs[FCSR]`.
+            FFLAGS => self.csrs[FCSR] & FFLAGS_MASK,
+            FRM => (self.csrs[FCSR] >> 5) & 0b111,
+            MISA => self.extensions.misa(),
+            MVENDORID | MARCHID | MHARTID => 0,
+            MIMPID => implementation_id(),
+            CYCLE => self.csrs[MCYCLE],
+            TIME => self.bus.mtime(),
+            INSTRET => self.csrs[MINSTRET],
             _ => self.csrs[addr],
         }
     }
@@ -265,37 +614,323 @@ impl Cpu {
     /// Store a value to a CSR.
     pub fn store_csr(&mut self, addr: usize, value: u64) {
         match addr {
+            SSTATUS => {
+                self.csrs[MSTATUS] = (self.csrs[MSTATUS] & !SSTATUS_MASK) | (value & SSTATUS_MASK);
+            }
+            // MEIP follows the PLIC and MTIP follows the CLINT, so they aren't stored. SEIP is
+            // stored as the software-writable bit.
+            MIP => self.csrs[MIP] = value & !(MIP_MEIP | MIP_MTIP),
+            // Only SSIP is writable in sip. STIP and SEIP are set by the timer and the PLIC.
+            SIP => {
+                let mask = self.csrs[MIDELEG] & MIP_SSIP;
+                self.csrs[MIP] = (self.csrs[MIP] & !mask) | (value & mask);
+            }
             SIE => {
-                self.csrs[MIE] =
-                    (self.csrs[MIE] & !self.csrs[MIDELEG]) | (value & self.csrs[MIDELEG]);
+                let mask = self.csrs[MIDELEG] & SUPERVISOR_INTERRUPTS;
+                self.csrs[MIE] = (self.csrs[MIE] & !mask) | (value & mask);
+            }
+            // "mideleg holds trap delegation bits for individual interrupts", and only interrupts of
+            // S-mode can be delegated. M-mode interrupts always trap to M-mode.
+            MIDELEG => self.csrs[addr] = value & SUPERVISOR_INTERRUPTS,
+            MSTATEEN0 => self.csrs[addr] = value & MSTATEEN0_MASK,
+            MCOUNTEREN | SCOUNTEREN => self.csrs[addr] = value & COUNTEREN_MASK,
+            // Writes to the floating-point CSRs make the floating-point state Dirty.
+            FFLAGS => {
+                self.csrs[FCSR] = (self.csrs[FCSR] & !FFLAGS_MASK) | (value & FFLAGS_MASK);
+                self.csrs[MSTATUS] |= MSTATUS_FS;
             }
+            FRM => {
+                self.csrs[FCSR] = (self.csrs[FCSR] & FFLAGS_MASK) | ((value & 0b111) << 5);
+                self.csrs[MSTATUS] |= MSTATUS_FS;
+            }
+            FCSR => {
+                self.csrs[FCSR] = value & 0xff;
+                self.csrs[MSTATUS] |= MSTATUS_FS;
+            }
+            MISA | MVENDORID..=MHARTID | CYCLE | TIME | INSTRET => {}
+            MCYCLE => {
+                self.csrs[addr] = value;
+                self.counters_written |= MCOUNTINHIBIT_CY;
+            }
+            MINSTRET => {
+                self.csrs[addr] = value;
+                self.counters_written |= MCOUNTINHIBIT_IR;
+            }
+            // mstateen1-3 and sstateen1-3 have no defined bits.
+            0x30d..=MSTATEEN3 | 0x10d..=SSTATEEN3 => {}
             _ => self.csrs[addr] = value,
         }
     }
 
+    /// Return the rounding mode of a floating-point instruction with the rm field `rm`. The
+    /// dynamic mode is read from frm, and a reserved mode raises an illegal instruction
+    /// exception.
+    pub fn rounding_mode(&self, rm: u64) -> Result<u64, Exception> {
+        let mode = match rm {
+            RM_DYN => self.load_csr(FRM),
+            _ => rm,
+        };
+        match mode {
+            RM_RNE..=RM_RMM => Ok(mode),
+            _ => Err(Exception::IllegalInstruction),
+        }
+    }
+
+    /// Accumulate the exception flags raised by a floating-point instruction in fflags. They're
+    /// sticky until software clears them.
+    pub fn accrue_fflags(&mut self, flags: u64) {
+        self.csrs[FCSR] |= flags & FFLAGS_MASK;
+        self.csrs[MSTATUS] |= MSTATUS_FS;
+    }
+
+    /// Check if the floating-point CSRs can be accessed. They exist only if F is in misa, and an
+    /// access raises an illegal instruction exception while mstatus.FS is Off.
+    fn check_fs(&self, addr: usize) -> Result<(), Exception> {
+        match addr {
+            FFLAGS | FRM | FCSR if !self.extensions.f || self.csrs[MSTATUS] & MSTATUS_FS == 0 => {
+                Err(Exception::IllegalInstruction)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Check if the current privilege mode can access the state guarded by mstateen0. Accessing
+    /// guarded state whose bit is zero from S-mode or U-mode raises an illegal instruction
+    /// exception.
+    fn check_stateen(&self, addr: usize) -> Result<(), Exception> {
+        let bit = match addr {
+            SENVCFG => MSTATEEN0_ENVCFG,
+            SSTATEEN0..=SSTATEEN3 => MSTATEEN0_SE0,
+            SISELECT | SIREG => MSTATEEN0_CSRIND,
+            STOPI => MSTATEEN0_AIA,
+            STOPEI => MSTATEEN0_IMSIC,
+            SCONTEXT => MSTATEEN0_CONTEXT,
+            _ => return Ok(()),
+        };
+        if self.mode != Mode::Machine && self.csrs[MSTATEEN0] & bit == 0 {
+            return Err(Exception::IllegalInstruction);
+        }
+        Ok(())
+    }
+
+    /// Check if the current privilege mode can read a counter. A read from S-mode or U-mode raises
+    /// an illegal instruction exception if the counter's bit in mcounteren is clear, and a read
+    /// from U-mode also if the bit in scounteren is clear.
+    fn check_counteren(&self, addr: usize) -> Result<(), Exception> {
+        let bit = match addr {
+            CYCLE => COUNTEREN_CY,
+            TIME => COUNTEREN_TM,
+            INSTRET => COUNTEREN_IR,
+            _ => return Ok(()),
+        };
+        let enabled = match self.mode {
+            Mode::Machine => true,
+            Mode::Supervisor => self.csrs[MCOUNTEREN] & bit != 0,
+            Mode::User => self.csrs[MCOUNTEREN] & self.csrs[SCOUNTEREN] & bit != 0,
+        };
+        match enabled {
+            true => Ok(()),
+            false => Err(Exception::IllegalInstruction),
+        }
+    }
+
+    /// Check if the current privilege mode can access a CSR in the way a CSR instruction does.
+    /// csr[9:8] is the lowest privilege mode which can access it, and csr[11:10] is 0b11 if it's
+    /// read-only. An access from a lower mode or a write to a read-only CSR raises an illegal
+    /// instruction exception. csrrw and csrrwi always write, and the others write unless rs1 or
+    /// the immediate is zero.
+    fn check_csr_access(&self, addr: usize, funct3: u64, rs1: usize) -> Result<(), Exception> {
+        let privilege = (addr >> 8) & 0b11;
+        let read_only = (addr >> 10) & 0b11 == 0b11;
+        let writes = funct3 & 0b11 == 0b01 || rs1 != 0;
+        if (self.mode as usize) < privilege || (read_only && writes) {
+            return Err(Exception::IllegalInstruction);
+        }
+        // "When TVM=1, attempts to read or write the satp CSR ... while executing in S-mode will
+        // raise an illegal instruction exception."
+        if addr == SATP && self.mode == Mode::Supervisor && self.trapped_by(MSTATUS_TVM) {
+            return Err(Exception::IllegalInstruction);
+        }
+        Ok(())
+    }
+
+    /// Return the value of a CSR which csrrs and csrrc modify, from the value `read` by them.
+    /// "Only the software-writable SEIP bit participates in the read-modify-write sequence of a
+    /// CSRRS or CSRRC instruction," so the PLIC output isn't latched into it.
+    fn csr_to_modify(&self, addr: usize, read: u64) -> u64 {
+        match addr {
+            MIP => (read & !MIP_SEIP) | (self.csrs[MIP] & MIP_SEIP),
+            _ => read,
+        }
+    }
+
+    /// Return true if `field` of mstatus is set to trap an instruction of S-mode.
+    fn trapped_by(&self, field: u64) -> bool {
+        self.csrs[MSTATUS] & field != 0
+    }
+
+    /// Use the memory and the devices of a board layout. Execution starts at the start of its
+    /// dram and the stack pointer is at the end.
+    pub fn set_machine(&mut self, machine: &Machine) {
+        self.bus.set_machine(machine);
+        self.pc = machine.memory.start;
+        self.regs[2] = machine.memory.end;
+    }
+
+    /// Enable only the subsystems which the steps of the book up to `level` introduce.
+    pub fn set_level(&mut self, level: Level) {
+        self.level = level;
+        self.bus.set_level(level);
+    }
+
+    /// Return the step of the book up to which subsystems are enabled.
+    pub fn level(&self) -> Level {
+        self.level
+    }
+
+    /// Protect a physical range from `start` to `end` (exclusive) against accesses from S-mode
+    /// and U-mode, e.g. where the M-mode firmware lives.
+    pub fn protect(&mut self, start: u64, end: u64) {
+        self.protected.push(start..end);
+    }
+
     /// Load a value from a dram.
     pub fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
         let p_addr = translate(self, addr, AccessType::Load)?;
-        self.bus.load(p_addr, size)
+        pmp::check(self, p_addr, size, AccessType::Load).map_err(|e| e.at(addr))?;
+        self.bus.load(p_addr, size).map_err(|e| e.at(addr))
     }
 
     /// Store a value to a dram.
     pub fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
         let p_addr = translate(self, addr, AccessType::Store)?;
-        self.bus.store(p_addr, size, value)
+        pmp::check(self, p_addr, size, AccessType::Store).map_err(|e| e.at(addr))?;
+        self.bus
+            .store(p_addr, size, value)
+            .map_err(|e| e.at(addr))?;
+        self.invalidate_reservation(p_addr, size);
+        Ok(())
     }
 
-    /// Get an instruction from the dram.
+    /// Atomically read, modify and write a value of `width` in a dram or a device for an AMO,
+    /// and return the old value.
+    pub fn amo(&mut self, addr: u64, width: Width, op: Amo) -> Result<u64, Exception> {
+        // "AMOs never raise load page-fault exceptions", so an AMO is translated as a store.
+        let p_addr = translate(self, addr, AccessType::Store)?;
+        pmp::check(self, p_addr, width.bits(), AccessType::Store).map_err(|e| e.at(addr))?;
+        let old = self.bus.amo(p_addr, width, op).map_err(|e| e.at(addr))?;
+        self.invalidate_reservation(p_addr, width.bits());
+        Ok(old)
+    }
+
+    /// Invalidate the reservation if a store of `size` bits at `p_addr` overlaps it.
+    fn invalidate_reservation(&mut self, p_addr: u64, size: u64) {
+        // "An SC may succeed only if no store from another hart to the reservation set can be
+        // observed to have occurred between the LR and the SC". A store from this hart to the
+        // reservation set also invalidates it, so a lock taken in between is noticed.
+        if let Some(reservation) = &self.reservation {
+            if p_addr < reservation.end && reservation.start < p_addr + size / 8 {
+                self.reservation = None;
+            }
+        }
+    }
+
+    /// Get an instruction from the dram. A page fault or an access fault is returned as an
+    /// exception for instruction fetch, which a caller takes as a trap at the program counter.
     pub fn fetch(&mut self) -> Result<u64, Exception> {
+        if self.trap_entry != Some(self.pc) {
+            self.trap_entry = None;
+        }
         let p_pc = translate(self, self.pc, AccessType::Instruction)?;
+        pmp::check(self, p_pc, 32, AccessType::Instruction).map_err(|e| e.at(self.pc))?;
         match self.bus.load(p_pc, 32) {
             Ok(inst) => Ok(inst),
-            Err(_e) => Err(Exception::InstructionAccessFault),
+            Err(_e) => Err(Exception::InstructionAccessFault(self.pc)),
         }
     }
 
+    /// Read an integer register.
+    pub fn read_reg(&self, reg: usize) -> u64 {
+        self.regs[reg]
+    }
+
+    /// Write an integer register. Register x0 is hardwired with all bits equal to 0, so a write to
+    /// it is ignored. The last write is recorded for `take_last_write`.
+    pub fn write_reg(&mut self, rd: usize, value: u64) {
+        if rd == 0 {
+            return;
+        }
+        self.regs[rd] = value;
+        self.last_write = Some((rd, value));
+    }
+
+    /// Return the register and the value written by `write_reg` since the last call, if any.
+    pub fn take_last_write(&mut self) -> Option<(usize, u64)> {
+        self.last_write.take()
+    }
+
     /// Execute an instruction after decoding. Return true if an error happens, otherwise false.
     pub fn execute(&mut self, inst: u64) -> Result<(), Exception> {
+        // The program counter has already moved on.
+        let pc = self.pc.wrapping_sub(4);
+        if !self.level.allows(inst) || !self.extensions.allows(inst) {
+            return Err(Exception::IllegalInstruction);
+        }
+        self.counters_written = 0;
+        self.waiting = false;
+        let result = self.execute_inst(inst);
+        // The instruction which raised the last exception has completed, so it's not a trap loop.
+        if result.is_ok() && self.last_exception.map(|(p, _)| p) == Some(pc) {
+            self.exception_repeats = 0;
+        }
+        // A stalled wfi executes again at the next step, so it doesn't retire.
+        self.count(result.is_ok() && !self.waiting);
+        if !self.waiting {
+            self.wfi_steps = 0;
+        }
+        result
+    }
+
+    /// Count a failed SC and detect a livelock, where the SC at the same pc keeps failing. On a
+    /// single hart, only the guest invalidates its reservation, by a store to the reservation set
+    /// or another LR or SC between the LR and the SC, so such a loop never makes progress.
+    fn count_sc(&mut self, succeeded: bool) {
+        if succeeded {
+            self.sc_streak = (0, 0);
+            return;
+        }
+        self.sc_failures += 1;
+        let pc = self.pc.wrapping_sub(4);
+        self.sc_streak = match self.sc_streak {
+            (last, count) if last == pc => (pc, count + 1),
+            _ => (pc, 1),
+        };
+        if self.sc_streak.1 == SC_LIVELOCK_FAILURES {
+            self.sc_livelock = Some(format!(
+                "atomics: sc at {:#x} failed {} times in a row ({} failed scs in total)",
+                pc, SC_LIVELOCK_FAILURES, self.sc_failures
+            ));
+        }
+    }
+
+    /// Return true if the hart is stalled by wfi until an interrupt is pending.
+    pub fn is_waiting(&self) -> bool {
+        self.waiting
+    }
+
+    /// Count a cycle and, if the instruction has completed, a retired instruction, unless
+    /// mcountinhibit stops the counter or the instruction has written it.
+    fn count(&mut self, retired: bool) {
+        let inhibit = self.csrs[MCOUNTINHIBIT] | self.counters_written;
+        if inhibit & MCOUNTINHIBIT_CY == 0 {
+            self.csrs[MCYCLE] = self.csrs[MCYCLE].wrapping_add(1);
+        }
+        if retired && inhibit & MCOUNTINHIBIT_IR == 0 {
+            self.csrs[MINSTRET] = self.csrs[MINSTRET].wrapping_add(1);
+        }
+    }
+
+    fn execute_inst(&mut self, inst: u64) -> Result<(), Exception> {
         let opcode = inst & 0x7f;
         let rd = ((inst >> 7) & 0x1f) as usize;
         let rs1 = ((inst >> 15) & 0x1f) as usize;
@@ -303,49 +938,46 @@ impl Cpu {
         let funct3 = (inst >> 12) & 0x7;
         let funct7 = (inst >> 25) & 0x7f;
 
-        // Emulate that register x0 is hardwired with all bits equal to 0.
-        self.regs[0] = 0;
-
         match opcode {
             0x03 => {
                 // imm[11:0] = inst[31:20]
                 let imm = ((inst as i32 as i64) >> 20) as u64;
-                let addr = self.regs[rs1].wrapping_add(imm);
+                let addr = self.read_reg(rs1).wrapping_add(imm);
                 match funct3 {
                     0x0 => {
                         // lb
                         let val = self.load(addr, 8)?;
-                        self.regs[rd] = val as i8 as i64 as u64;
+                        self.write_reg(rd, val as i8 as i64 as u64);
                     }
                     0x1 => {
                         // lh
                         let val = self.load(addr, 16)?;
-                        self.regs[rd] = val as i16 as i64 as u64;
+                        self.write_reg(rd, val as i16 as i64 as u64);
                     }
                     0x2 => {
                         // lw
                         let val = self.load(addr, 32)?;
-                        self.regs[rd] = val as i32 as i64 as u64;
+                        self.write_reg(rd, val as i32 as i64 as u64);
                     }
                     0x3 => {
                         // ld
                         let val = self.load(addr, 64)?;
-                        self.regs[rd] = val;
+                        self.write_reg(rd, val);
                     }
                     0x4 => {
                         // lbu
                         let val = self.load(addr, 8)?;
-                        self.regs[rd] = val;
+                        self.write_reg(rd, val);
                     }
                     0x5 => {
                         // lhu
                         let val = self.load(addr, 16)?;
-                        self.regs[rd] = val;
+                        self.write_reg(rd, val);
                     }
                     0x6 => {
                         // lwu
                         let val = self.load(addr, 32)?;
-                        self.regs[rd] = val;
+                        self.write_reg(rd, val);
                     }
                     _ => {
                         println!("not implemented: opcode {:#x} funct3 {:#x}", opcode, funct3);
@@ -358,6 +990,13 @@ impl Cpu {
                 // instruction sequentially on a single thread.
                 match funct3 {
                     0x0 => {} // fence
+                    0x1 => {
+                        // fence.i (Zifencei): "explicit synchronization between writes to
+                        // instruction memory and instruction fetches on the same hart". Every
+                        // instruction is fetched from the dram and decoded again, so there is no
+                        // decoded instruction nor icache to flush, and stores before it are
+                        // already visible to fetches after it.
+                    }
                     _ => {
                         println!("not implemented: opcode {:#x} funct3 {:#x}", opcode, funct3);
                         return Err(Exception::IllegalInstruction);
@@ -372,48 +1011,124 @@ impl Cpu {
                 match funct3 {
                     0x0 => {
                         // addi
-                        self.regs[rd] = self.regs[rs1].wrapping_add(imm);
+                        self.write_reg(rd, self.read_reg(rs1).wrapping_add(imm));
                     }
-                    0x1 => {
+                    0x1 => match (imm & 0xfff, funct7 >> 1) {
                         // slli
-                        self.regs[rd] = self.regs[rs1] << shamt;
-                    }
+                        (_, 0x00) => self.write_reg(rd, self.read_reg(rs1) << shamt),
+                        // sha256sum0, sha256sum1, sha256sig0, sha256sig1 (Zknh)
+                        (0x100..=0x103, _) => {
+                            self.write_reg(rd, zk::sha256(self.read_reg(rs1), imm & 0xfff))
+                        }
+                        // sha512sum0, sha512sum1, sha512sig0, sha512sig1 (Zknh)
+                        (0x104..=0x107, _) => {
+                            self.write_reg(rd, zk::sha512(self.read_reg(rs1), imm & 0xfff))
+                        }
+                        // sm3p0 (Zksh)
+                        (0x108, _) => self.write_reg(rd, zk::sm3p0(self.read_reg(rs1))),
+                        // sm3p1 (Zksh)
+                        (0x109, _) => self.write_reg(rd, zk::sm3p1(self.read_reg(rs1))),
+                        // aes64im (Zknd)
+                        (0x300, _) => self.write_reg(rd, zk::aes64im(self.read_reg(rs1))),
+                        // aes64ks1i (Zkne, Zknd)
+                        (0x310..=0x31f, _) => {
+                            self.write_reg(
+                                rd,
+                                zk::aes64ks1i(self.read_reg(rs1), imm & 0xf)
+                                    .ok_or(Exception::IllegalInstruction)?,
+                            );
+                        }
+                        // clz (Zbb)
+                        (0x600, _) => self.write_reg(rd, self.read_reg(rs1).leading_zeros() as u64),
+                        // ctz (Zbb)
+                        (0x601, _) => {
+                            self.write_reg(rd, self.read_reg(rs1).trailing_zeros() as u64)
+                        }
+                        // cpop (Zbb)
+                        (0x602, _) => self.write_reg(rd, self.read_reg(rs1).count_ones() as u64),
+                        // sext.b (Zbb)
+                        (0x604, _) => self.write_reg(rd, self.read_reg(rs1) as i8 as i64 as u64),
+                        // sext.h (Zbb)
+                        (0x605, _) => self.write_reg(rd, self.read_reg(rs1) as i16 as i64 as u64),
+                        // bseti (Zbs)
+                        (_, 0x0a) => self.write_reg(rd, self.read_reg(rs1) | (1 << shamt)),
+                        // bclri (Zbs)
+                        (_, 0x12) => self.write_reg(rd, self.read_reg(rs1) & !(1 << shamt)),
+                        // binvi (Zbs)
+                        (_, 0x1a) => self.write_reg(rd, self.read_reg(rs1) ^ (1 << shamt)),
+                        _ => {
+                            println!(
+                                "not implemented: opcode {:#x} funct3 {:#x} funct7 {:#x}",
+                                opcode, funct3, funct7
+                            );
+                            return Err(Exception::IllegalInstruction);
+                        }
+                    },
                     0x2 => {
                         // slti
-                        self.regs[rd] = if (self.regs[rs1] as i64) < (imm as i64) {
-                            1
-                        } else {
-                            0
-                        };
+                        self.write_reg(
+                            rd,
+                            if (self.read_reg(rs1) as i64) < (imm as i64) {
+                                1
+                            } else {
+                                0
+                            },
+                        );
                     }
                     0x3 => {
                         // sltiu
-                        self.regs[rd] = if self.regs[rs1] < imm { 1 } else { 0 };
+                        self.write_reg(rd, if self.read_reg(rs1) < imm { 1 } else { 0 });
                     }
                     0x4 => {
                         // xori
-                        self.regs[rd] = self.regs[rs1] ^ imm;
+                        self.write_reg(rd, self.read_reg(rs1) ^ imm);
                     }
                     0x5 => {
-                        match funct7 >> 1 {
+                        match (imm & 0xfff, funct7 >> 1) {
                             // srli
-                            0x00 => self.regs[rd] = self.regs[rs1].wrapping_shr(shamt),
+                            (_, 0x00) => self.write_reg(rd, self.read_reg(rs1).wrapping_shr(shamt)),
                             // srai
-                            0x10 => {
-                                self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64
+                            (_, 0x10) => self.write_reg(
+                                rd,
+                                (self.read_reg(rs1) as i64).wrapping_shr(shamt) as u64,
+                            ),
+                            // rori (Zbb, Zbkb)
+                            (_, 0x18) => self.write_reg(rd, self.read_reg(rs1).rotate_right(shamt)),
+                            // brev8 (Zbkb)
+                            (0x687, _) => self.write_reg(rd, zk::brev8(self.read_reg(rs1))),
+                            // rev8 (Zbb, Zbkb)
+                            (0x6b8, _) => self.write_reg(rd, self.read_reg(rs1).swap_bytes()),
+                            // orc.b (Zbb)
+                            (0x287, _) => {
+                                let bytes = self.read_reg(rs1).to_le_bytes().map(|b| match b {
+                                    0 => 0,
+                                    _ => 0xff,
+                                });
+                                self.write_reg(rd, u64::from_le_bytes(bytes));
+                            }
+                            // bexti (Zbs)
+                            (_, 0x12) => self.write_reg(rd, (self.read_reg(rs1) >> shamt) & 1),
+                            _ => {
+                                println!(
+                                    "not implemented: opcode {:#x} funct3 {:#x} funct7 {:#x}",
+                                    opcode, funct3, funct7
+                                );
+                                return Err(Exception::IllegalInstruction);
                             }
-                            _ => {}
                         }
                     }
-                    0x6 => self.regs[rd] = self.regs[rs1] | imm, // ori
-                    0x7 => self.regs[rd] = self.regs[rs1] & imm, // andi
-                    _ => {}
+                    0x6 => self.write_reg(rd, self.read_reg(rs1) | imm), // ori
+                    0x7 => self.write_reg(rd, self.read_reg(rs1) & imm), // andi
+                    _ => {
+                        println!("not implemented: opcode {:#x} funct3 {:#x}", opcode, funct3);
+                        return Err(Exception::IllegalInstruction);
+                    }
                 }
             }
             0x17 => {
                 // auipc
                 let imm = (inst & 0xfffff000) as i32 as i64 as u64;
-                self.regs[rd] = self.pc.wrapping_add(imm).wrapping_sub(4);
+                self.write_reg(rd, self.pc.wrapping_add(imm).wrapping_sub(4));
             }
             0x1b => {
                 let imm = ((inst as i32 as i64) >> 20) as u64;
@@ -422,23 +1137,69 @@ impl Cpu {
                 match funct3 {
                     0x0 => {
                         // addiw
-                        self.regs[rd] = self.regs[rs1].wrapping_add(imm) as i32 as i64 as u64;
-                    }
-                    0x1 => {
-                        // slliw
-                        self.regs[rd] = self.regs[rs1].wrapping_shl(shamt) as i32 as i64 as u64;
+                        self.write_reg(
+                            rd,
+                            self.read_reg(rs1).wrapping_add(imm) as i32 as i64 as u64,
+                        );
                     }
+                    0x1 => match (funct7, rs2) {
+                        (0x00, _) => {
+                            // slliw
+                            self.write_reg(
+                                rd,
+                                self.read_reg(rs1).wrapping_shl(shamt) as i32 as i64 as u64,
+                            );
+                        }
+                        (0x04 | 0x05, _) => {
+                            // slli.uw (Zba)
+                            // The shift amount is 6 bits like slli.
+                            let shamt = (imm & 0x3f) as u32;
+                            self.write_reg(rd, (self.read_reg(rs1) & 0xffffffff) << shamt);
+                        }
+                        (0x30, 0x0) => {
+                            // clzw (Zbb)
+                            self.write_reg(rd, (self.read_reg(rs1) as u32).leading_zeros() as u64);
+                        }
+                        (0x30, 0x1) => {
+                            // ctzw (Zbb)
+                            self.write_reg(rd, (self.read_reg(rs1) as u32).trailing_zeros() as u64);
+                        }
+                        (0x30, 0x2) => {
+                            // cpopw (Zbb)
+                            self.write_reg(rd, (self.read_reg(rs1) as u32).count_ones() as u64);
+                        }
+                        _ => {
+                            println!(
+                                "not implemented: opcode {:#x} funct3 {:#x} funct7 {:#x}",
+                                opcode, funct3, funct7
+                            );
+                            return Err(Exception::IllegalInstruction);
+                        }
+                    },
                     0x5 => {
                         match funct7 {
                             0x00 => {
                                 // srliw
-                                self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32
-                                    as i64 as u64;
+                                self.write_reg(
+                                    rd,
+                                    (self.read_reg(rs1) as u32).wrapping_shr(shamt) as i32 as i64
+                                        as u64,
+                                );
                             }
                             0x20 => {
                                 // sraiw
-                                self.regs[rd] =
-                                    (self.regs[rs1] as i32).wrapping_shr(shamt) as i64 as u64;
+                                self.write_reg(
+                                    rd,
+                                    (self.read_reg(rs1) as i32).wrapping_shr(shamt) as i64 as u64,
+                                );
+                            }
+                            0x30 => {
+                                // roriw (Zbb, Zbkb)
+                                self.write_reg(
+                                    rd,
+                                    (self.read_reg(rs1) as u32).rotate_right(shamt) as i32 as i64
+                                        as u64,
+                                );
                             }
                             _ => {
                                 println!(
@@ -458,12 +1219,12 @@ impl Cpu {
             0x23 => {
                 // imm[11:5|4:0] = inst[31:25|11:7]
                 let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
-                let addr = self.regs[rs1].wrapping_add(imm);
+                let addr = self.read_reg(rs1).wrapping_add(imm);
                 match funct3 {
-                    0x0 => self.store(addr, 8, self.regs[rs2])?,  // sb
-                    0x1 => self.store(addr, 16, self.regs[rs2])?, // sh
-                    0x2 => self.store(addr, 32, self.regs[rs2])?, // sw
-                    0x3 => self.store(addr, 64, self.regs[rs2])?, // sd
+                    0x0 => self.store(addr, 8, self.read_reg(rs2))?, // sb
+                    0x1 => self.store(addr, 16, self.read_reg(rs2))?, // sh
+                    0x2 => self.store(addr, 32, self.read_reg(rs2))?, // sw
+                    0x3 => self.store(addr, 64, self.read_reg(rs2))?, // sd
                     _ => {}
                 }
             }
@@ -473,29 +1234,119 @@ impl Cpu {
                 let _aq = (funct7 & 0b0000010) >> 1; // acquire access
                 let _rl = funct7 & 0b0000001; // release access
                 match (funct3, funct5) {
-                    (0x2, 0x00) => {
-                        // amoadd.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
+                    (0x2, 0x02) | (0x3, 0x02) => {
+                        // lr.w and lr.d: "LR.W loads a word from the address in rs1, places the
+                        // sign-extended value in rd, and registers a reservation set".
+                        let size = if funct3 == 0x2 { 32 } else { 64 };
+                        let addr = self.read_reg(rs1);
+                        if self.strict_atomics && !addr.is_multiple_of(size / 8) {
+                            return Err(Exception::LoadAddressMisaligned(addr));
+                        }
+                        let p_addr = translate(self, addr, AccessType::Load)?;
+                        pmp::check(self, p_addr, size, AccessType::Load).map_err(|e| e.at(addr))?;
+                        let value = self.bus.load(p_addr, size).map_err(|e| e.at(addr))?;
+                        let t = match size {
+                            32 => value as i32 as i64 as u64,
+                            _ => value,
+                        };
+                        self.reservation = Some(p_addr..p_addr + size / 8);
+                        self.lr_access = Some((addr, size));
+                        self.write_reg(rd, t);
                     }
-                    (0x3, 0x00) => {
-                        // amoadd.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, t.wrapping_add(self.regs[rs2]))?;
-                        self.regs[rd] = t;
-                    }
-                    (0x2, 0x01) => {
-                        // amoswap.w
-                        let t = self.load(self.regs[rs1], 32)?;
-                        self.store(self.regs[rs1], 32, self.regs[rs2])?;
-                        self.regs[rd] = t;
-                    }
-                    (0x3, 0x01) => {
-                        // amoswap.d
-                        let t = self.load(self.regs[rs1], 64)?;
-                        self.store(self.regs[rs1], 64, self.regs[rs2])?;
-                        self.regs[rd] = t;
+                    (0x2, 0x03) | (0x3, 0x03) => {
+                        // sc.w and sc.d: "SC.W conditionally writes a word in rs2 to the address
+                        // in rs1: the SC.W succeeds only if the reservation is still valid and the
+                        // reservation set contains the bytes being written. If the SC.W succeeds,
+                        // the instruction writes the word in rs2 to memory, and it writes zero to
+                        // rd. If the SC.W fails, the instruction does not write to memory, and it
+                        // writes a nonzero value to rd. Regardless of success or failure,
+                        // executing an SC.W instruction invalidates any reservation held by this
+                        // hart."
+                        let size = if funct3 == 0x2 { 32 } else { 64 };
+                        let addr = self.read_reg(rs1);
+                        if self.strict_atomics && !addr.is_multiple_of(size / 8) {
+                            return Err(Exception::StoreAMOAddressMisaligned(addr));
+                        }
+                        let p_addr = translate(self, addr, AccessType::Store)?;
+                        let mut reserved = match self.reservation.take() {
+                            Some(reservation) => {
+                                reservation.start <= p_addr && p_addr + size / 8 <= reservation.end
+                            }
+                            None => false,
+                        };
+                        // "Software should not use LR/SC pairs with different addresses or sizes"
+                        // even if the reservation set contains the bytes, so such an SC fails.
+                        let lr_access = self.lr_access.take();
+                        if self.strict_atomics && lr_access != Some((addr, size)) {
+                            let pc = self.pc.wrapping_sub(4);
+                            let width = |size| if size == 32 { 'w' } else { 'd' };
+                            self.unpaired_sc = Some(match lr_access {
+                                Some((lr_addr, lr_size)) => format!(
+                                    "atomics: sc.{} to {:#x} at {:#x} doesn't pair with lr.{} to {:#x}",
+                                    width(size),
+                                    addr,
+                                    pc,
+                                    width(lr_size),
+                                    lr_addr
+                                ),
+                                None => format!(
+                                    "atomics: sc.{} to {:#x} at {:#x} without lr",
+                                    width(size),
+                                    addr,
+                                    pc
+                                ),
+                            });
+                            reserved = false;
+                        }
+                        if reserved {
+                            pmp::check(self, p_addr, size, AccessType::Store)
+                                .map_err(|e| e.at(addr))?;
+                            self.bus
+                                .store(p_addr, size, self.read_reg(rs2))
+                                .map_err(|e| e.at(addr))?;
+                            self.invalidate_reservation(p_addr, size);
+                        }
+                        self.count_sc(reserved);
+                        self.write_reg(rd, !reserved as u64);
+                    }
+                    (0x0..=0x3, _) => {
+                        // amoswap, amoadd, amoxor, amoand, amoor, amomin, amomax, amominu and
+                        // amomaxu for words (.w) and doublewords (.d), and for bytes (.b) and
+                        // halfwords (.h) by Zabha. "For RV64, 32-bit AMOs always sign-extend the
+                        // value placed in rd", and min and max compare the values of the width.
+                        let width = match funct3 {
+                            0x0 => Width::Byte,
+                            0x1 => Width::HalfWord,
+                            0x2 => Width::Word,
+                            _ => Width::DoubleWord,
+                        };
+                        let shift = 64 - width.bits();
+                        let sext = |v: u64| ((v << shift) as i64 >> shift) as u64;
+                        if self.strict_atomics && !self.read_reg(rs1).is_multiple_of(width.bytes())
+                        {
+                            return Err(Exception::StoreAMOAddressMisaligned(self.read_reg(rs1)));
+                        }
+                        let src = self.read_reg(rs2);
+                        let op = match funct5 {
+                            0x00 => Amo::Add(src),
+                            0x01 => Amo::Swap(src),
+                            0x04 => Amo::Xor(src),
+                            0x08 => Amo::Or(src),
+                            0x0c => Amo::And(src),
+                            0x10 => Amo::Min(src),
+                            0x14 => Amo::Max(src),
+                            0x18 => Amo::Minu(src),
+                            0x1c => Amo::Maxu(src),
+                            _ => {
+                                println!(
+                                    "not implemented: opcode {:#x} funct3 {:#x} funct7 {:#x}",
+                                    opcode, funct3, funct7
+                                );
+                                return Err(Exception::IllegalInstruction);
+                            }
+                        };
+                        let t = self.amo(self.read_reg(rs1), width, op)?;
+                        self.write_reg(rd, sext(t));
                     }
                     _ => {
                         println!(
@@ -510,59 +1361,195 @@ impl Cpu {
                 // "SLL, SRL, and SRA perform logical left, logical right, and arithmetic right
                 // shifts on the value in register rs1 by the shift amount held in register rs2.
                 // In RV64I, only the low 6 bits of rs2 are considered for the shift amount."
-                let shamt = ((self.regs[rs2] & 0x3f) as u64) as u32;
+                let shamt = (self.read_reg(rs2) & 0x3f) as u32;
                 match (funct3, funct7) {
                     (0x0, 0x00) => {
                         // add
-                        self.regs[rd] = self.regs[rs1].wrapping_add(self.regs[rs2]);
+                        self.write_reg(rd, self.read_reg(rs1).wrapping_add(self.read_reg(rs2)));
                     }
                     (0x0, 0x01) => {
                         // mul
-                        self.regs[rd] = self.regs[rs1].wrapping_mul(self.regs[rs2]);
+                        self.write_reg(rd, self.read_reg(rs1).wrapping_mul(self.read_reg(rs2)));
                     }
                     (0x0, 0x20) => {
                         // sub
-                        self.regs[rd] = self.regs[rs1].wrapping_sub(self.regs[rs2]);
+                        self.write_reg(rd, self.read_reg(rs1).wrapping_sub(self.read_reg(rs2)));
                     }
                     (0x1, 0x00) => {
                         // sll
-                        self.regs[rd] = self.regs[rs1].wrapping_shl(shamt);
+                        self.write_reg(rd, self.read_reg(rs1).wrapping_shl(shamt));
                     }
                     (0x2, 0x00) => {
                         // slt
-                        self.regs[rd] = if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
-                            1
-                        } else {
-                            0
-                        };
+                        self.write_reg(
+                            rd,
+                            if (self.read_reg(rs1) as i64) < (self.read_reg(rs2) as i64) {
+                                1
+                            } else {
+                                0
+                            },
+                        );
                     }
                     (0x3, 0x00) => {
                         // sltu
-                        self.regs[rd] = if self.regs[rs1] < self.regs[rs2] {
-                            1
-                        } else {
-                            0
-                        };
+                        self.write_reg(
+                            rd,
+                            if self.read_reg(rs1) < self.read_reg(rs2) {
+                                1
+                            } else {
+                                0
+                            },
+                        );
                     }
                     (0x4, 0x00) => {
                         // xor
-                        self.regs[rd] = self.regs[rs1] ^ self.regs[rs2];
+                        self.write_reg(rd, self.read_reg(rs1) ^ self.read_reg(rs2));
                     }
                     (0x5, 0x00) => {
                         // srl
-                        self.regs[rd] = self.regs[rs1].wrapping_shr(shamt);
+                        self.write_reg(rd, self.read_reg(rs1).wrapping_shr(shamt));
                     }
                     (0x5, 0x20) => {
                         // sra
-                        self.regs[rd] = (self.regs[rs1] as i64).wrapping_shr(shamt) as u64;
+                        self.write_reg(rd, (self.read_reg(rs1) as i64).wrapping_shr(shamt) as u64);
                     }
                     (0x6, 0x00) => {
                         // or
-                        self.regs[rd] = self.regs[rs1] | self.regs[rs2];
+                        self.write_reg(rd, self.read_reg(rs1) | self.read_reg(rs2));
                     }
                     (0x7, 0x00) => {
                         // and
-                        self.regs[rd] = self.regs[rs1] & self.regs[rs2];
+                        self.write_reg(rd, self.read_reg(rs1) & self.read_reg(rs2));
+                    }
+                    (0x0, 0x19) => {
+                        // aes64es (Zkne)
+                        self.write_reg(rd, zk::aes64es(self.read_reg(rs1), self.read_reg(rs2)));
+                    }
+                    (0x0, 0x1b) => {
+                        // aes64esm (Zkne)
+                        self.write_reg(rd, zk::aes64esm(self.read_reg(rs1), self.read_reg(rs2)));
+                    }
+                    (0x0, 0x1d) => {
+                        // aes64ds (Zknd)
+                        self.write_reg(rd, zk::aes64ds(self.read_reg(rs1), self.read_reg(rs2)));
+                    }
+                    (0x0, 0x1f) => {
+                        // aes64dsm (Zknd)
+                        self.write_reg(rd, zk::aes64dsm(self.read_reg(rs1), self.read_reg(rs2)));
+                    }
+                    (0x0, 0x3f) => {
+                        // aes64ks2 (Zkne, Zknd)
+                        self.write_reg(rd, zk::aes64ks2(self.read_reg(rs1), self.read_reg(rs2)));
+                    }
+                    (0x0, _) if funct7 & 0x1f == 0x18 => {
+                        // sm4ed (Zksed)
+                        // The byte select is encoded in the upper 2 bits of funct7.
+                        self.write_reg(
+                            rd,
+                            zk::sm4(self.read_reg(rs1), self.read_reg(rs2), funct7 >> 5, false),
+                        );
+                    }
+                    (0x0, _) if funct7 & 0x1f == 0x1a => {
+                        // sm4ks (Zksed)
+                        self.write_reg(
+                            rd,
+                            zk::sm4(self.read_reg(rs1), self.read_reg(rs2), funct7 >> 5, true),
+                        );
+                    }
+                    (0x1, 0x05) => {
+                        // clmul (Zbkc)
+                        self.write_reg(
+                            rd,
+                            zk::clmul(self.read_reg(rs1), self.read_reg(rs2), false),
+                        );
+                    }
+                    (0x3, 0x05) => {
+                        // clmulh (Zbkc)
+                        self.write_reg(rd, zk::clmul(self.read_reg(rs1), self.read_reg(rs2), true));
+                    }
+                    (0x2, 0x14) => {
+                        // xperm4 (Zbkx)
+                        self.write_reg(rd, zk::xperm(self.read_reg(rs1), self.read_reg(rs2), 4));
+                    }
+                    (0x4, 0x14) => {
+                        // xperm8 (Zbkx)
+                        self.write_reg(rd, zk::xperm(self.read_reg(rs1), self.read_reg(rs2), 8));
+                    }
+                    (0x1, 0x30) => {
+                        // rol (Zbb, Zbkb)
+                        self.write_reg(rd, self.read_reg(rs1).rotate_left(shamt));
+                    }
+                    (0x5, 0x30) => {
+                        // ror (Zbb, Zbkb)
+                        self.write_reg(rd, self.read_reg(rs1).rotate_right(shamt));
+                    }
+                    (0x4, 0x20) => {
+                        // xnor (Zbb, Zbkb)
+                        self.write_reg(rd, !(self.read_reg(rs1) ^ self.read_reg(rs2)));
+                    }
+                    (0x6, 0x20) => {
+                        // orn (Zbb, Zbkb)
+                        self.write_reg(rd, self.read_reg(rs1) | !self.read_reg(rs2));
+                    }
+                    (0x7, 0x20) => {
+                        // andn (Zbb, Zbkb)
+                        self.write_reg(rd, self.read_reg(rs1) & !self.read_reg(rs2));
+                    }
+                    (0x4, 0x04) => {
+                        // pack (Zbkb)
+                        self.write_reg(
+                            rd,
+                            (self.read_reg(rs2) << 32) | (self.read_reg(rs1) & 0xffffffff),
+                        );
+                    }
+                    (0x7, 0x04) => {
+                        // packh (Zbkb)
+                        self.write_reg(
+                            rd,
+                            ((self.read_reg(rs2) & 0xff) << 8) | (self.read_reg(rs1) & 0xff),
+                        );
+                    }
+                    (0x2 | 0x4 | 0x6, 0x10) => {
+                        // sh1add, sh2add and sh3add (Zba)
+                        let shift = funct3 >> 1;
+                        self.write_reg(
+                            rd,
+                            self.read_reg(rs2).wrapping_add(self.read_reg(rs1) << shift),
+                        );
+                    }
+                    (0x4, 0x05) => {
+                        // min (Zbb)
+                        let (a, b) = (self.read_reg(rs1) as i64, self.read_reg(rs2) as i64);
+                        self.write_reg(rd, a.min(b) as u64);
+                    }
+                    (0x5, 0x05) => {
+                        // minu (Zbb)
+                        self.write_reg(rd, self.read_reg(rs1).min(self.read_reg(rs2)));
+                    }
+                    (0x6, 0x05) => {
+                        // max (Zbb)
+                        let (a, b) = (self.read_reg(rs1) as i64, self.read_reg(rs2) as i64);
+                        self.write_reg(rd, a.max(b) as u64);
+                    }
+                    (0x7, 0x05) => {
+                        // maxu (Zbb)
+                        self.write_reg(rd, self.read_reg(rs1).max(self.read_reg(rs2)));
+                    }
+                    (0x1, 0x14) => {
+                        // bset (Zbs)
+                        self.write_reg(rd, self.read_reg(rs1) | (1 << shamt));
+                    }
+                    (0x1, 0x24) => {
+                        // bclr (Zbs)
+                        self.write_reg(rd, self.read_reg(rs1) & !(1 << shamt));
+                    }
+                    (0x1, 0x34) => {
+                        // binv (Zbs)
+                        self.write_reg(rd, self.read_reg(rs1) ^ (1 << shamt));
+                    }
+                    (0x5, 0x24) => {
+                        // bext (Zbs)
+                        self.write_reg(rd, (self.read_reg(rs1) >> shamt) & 1);
                     }
                     _ => {
                         println!(
@@ -575,58 +1562,111 @@ impl Cpu {
             }
             0x37 => {
                 // lui
-                self.regs[rd] = (inst & 0xfffff000) as i32 as i64 as u64;
+                self.write_reg(rd, (inst & 0xfffff000) as i32 as i64 as u64);
             }
             0x3b => {
                 // "The shift amount is given by rs2[4:0]."
-                let shamt = (self.regs[rs2] & 0x1f) as u32;
+                let shamt = (self.read_reg(rs2) & 0x1f) as u32;
                 match (funct3, funct7) {
                     (0x0, 0x00) => {
                         // addw
-                        self.regs[rd] =
-                            self.regs[rs1].wrapping_add(self.regs[rs2]) as i32 as i64 as u64;
+                        self.write_reg(
+                            rd,
+                            self.read_reg(rs1).wrapping_add(self.read_reg(rs2)) as i32 as i64
+                                as u64,
+                        );
                     }
                     (0x0, 0x20) => {
                         // subw
-                        self.regs[rd] =
-                            ((self.regs[rs1].wrapping_sub(self.regs[rs2])) as i32) as u64;
+                        self.write_reg(
+                            rd,
+                            ((self.read_reg(rs1).wrapping_sub(self.read_reg(rs2))) as i32) as u64,
+                        );
                     }
                     (0x1, 0x00) => {
                         // sllw
-                        self.regs[rd] = (self.regs[rs1] as u32).wrapping_shl(shamt) as i32 as u64;
+                        self.write_reg(
+                            rd,
+                            (self.read_reg(rs1) as u32).wrapping_shl(shamt) as i32 as u64,
+                        );
                     }
                     (0x5, 0x00) => {
                         // srlw
-                        self.regs[rd] = (self.regs[rs1] as u32).wrapping_shr(shamt) as i32 as u64;
+                        self.write_reg(
+                            rd,
+                            (self.read_reg(rs1) as u32).wrapping_shr(shamt) as i32 as u64,
+                        );
                     }
                     (0x5, 0x01) => {
                         // divu
-                        self.regs[rd] = match self.regs[rs2] {
-                            0 => {
-                                // TODO: Set DZ (Divide by Zero) in the FCSR csr flag to 1.
-                                0xffffffff_ffffffff
-                            }
-                            _ => {
-                                let dividend = self.regs[rs1];
-                                let divisor = self.regs[rs2];
-                                dividend.wrapping_div(divisor)
-                            }
-                        };
+                        self.write_reg(
+                            rd,
+                            match self.read_reg(rs2) {
+                                // Integer division by zero doesn't raise an exception nor set
+                                // the DZ flag, which is only for floating-point division.
+                                0 => 0xffffffff_ffffffff,
+                                _ => {
+                                    let dividend = self.read_reg(rs1);
+                                    let divisor = self.read_reg(rs2);
+                                    dividend.wrapping_div(divisor)
+                                }
+                            },
+                        );
                     }
                     (0x5, 0x20) => {
                         // sraw
-                        self.regs[rd] = ((self.regs[rs1] as i32) >> (shamt as i32)) as u64;
+                        self.write_reg(rd, ((self.read_reg(rs1) as i32) >> (shamt as i32)) as u64);
+                    }
+                    (0x1, 0x30) => {
+                        // rolw (Zbb, Zbkb)
+                        self.write_reg(
+                            rd,
+                            (self.read_reg(rs1) as u32).rotate_left(shamt) as i32 as u64,
+                        );
+                    }
+                    (0x5, 0x30) => {
+                        // rorw (Zbb, Zbkb)
+                        self.write_reg(
+                            rd,
+                            (self.read_reg(rs1) as u32).rotate_right(shamt) as i32 as u64,
+                        );
+                    }
+                    (0x0, 0x04) => {
+                        // add.uw (Zba)
+                        self.write_reg(
+                            rd,
+                            self.read_reg(rs2)
+                                .wrapping_add(self.read_reg(rs1) & 0xffffffff),
+                        );
+                    }
+                    (0x2 | 0x4 | 0x6, 0x10) => {
+                        // sh1add.uw, sh2add.uw and sh3add.uw (Zba)
+                        let shift = funct3 >> 1;
+                        self.write_reg(
+                            rd,
+                            self.read_reg(rs2)
+                                .wrapping_add((self.read_reg(rs1) & 0xffffffff) << shift),
+                        );
+                    }
+                    (0x4, 0x04) => {
+                        // packw (Zbkb), zext.h (Zbb) if rs2 is x0
+                        let lo = self.read_reg(rs1) & 0xffff;
+                        let hi = self.read_reg(rs2) & 0xffff;
+                        self.write_reg(rd, ((hi << 16) | lo) as u32 as i32 as u64);
                     }
                     (0x7, 0x01) => {
                         // remuw
-                        self.regs[rd] = match self.regs[rs2] {
-                            0 => self.regs[rs1],
-                            _ => {
-                                let dividend = self.regs[rs1] as u32;
-                                let divisor = self.regs[rs2] as u32;
-                                dividend.wrapping_rem(divisor) as i32 as u64
-                            }
-                        };
+                        self.write_reg(
+                            rd,
+                            match self.read_reg(rs2) {
+                                0 => self.read_reg(rs1),
+                                _ => {
+                                    let dividend = self.read_reg(rs1) as u32;
+                                    let divisor = self.read_reg(rs2) as u32;
+                                    dividend.wrapping_rem(divisor) as i32 as u64
+                                }
+                            },
+                        );
                     }
                     _ => {
                         println!(
@@ -647,37 +1687,37 @@ impl Cpu {
                 match funct3 {
                     0x0 => {
                         // beq
-                        if self.regs[rs1] == self.regs[rs2] {
+                        if self.read_reg(rs1) == self.read_reg(rs2) {
                             self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
                         }
                     }
                     0x1 => {
                         // bne
-                        if self.regs[rs1] != self.regs[rs2] {
+                        if self.read_reg(rs1) != self.read_reg(rs2) {
                             self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
                         }
                     }
                     0x4 => {
                         // blt
-                        if (self.regs[rs1] as i64) < (self.regs[rs2] as i64) {
+                        if (self.read_reg(rs1) as i64) < (self.read_reg(rs2) as i64) {
                             self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
                         }
                     }
                     0x5 => {
                         // bge
-                        if (self.regs[rs1] as i64) >= (self.regs[rs2] as i64) {
+                        if (self.read_reg(rs1) as i64) >= (self.read_reg(rs2) as i64) {
                             self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
                         }
                     }
                     0x6 => {
                         // bltu
-                        if self.regs[rs1] < self.regs[rs2] {
+                        if self.read_reg(rs1) < self.read_reg(rs2) {
                             self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
                         }
                     }
                     0x7 => {
                         // bgeu
-                        if self.regs[rs1] >= self.regs[rs2] {
+                        if self.read_reg(rs1) >= self.read_reg(rs2) {
                             self.pc = self.pc.wrapping_add(imm).wrapping_sub(4);
                         }
                     }
@@ -693,13 +1733,13 @@ impl Cpu {
                 let t = self.pc;
 
                 let imm = ((((inst & 0xfff00000) as i32) as i64) >> 20) as u64;
-                self.pc = (self.regs[rs1].wrapping_add(imm)) & !1;
+                self.pc = (self.read_reg(rs1).wrapping_add(imm)) & !1;
 
-                self.regs[rd] = t;
+                self.write_reg(rd, t);
             }
             0x6f => {
                 // jal
-                self.regs[rd] = self.pc;
+                self.write_reg(rd, self.pc);
 
                 // imm[20|10:1|11|19:12] = inst[31|30:21|20|19:12]
                 let imm = (((inst & 0x80000000) as i32 as i64 >> 11) as u64) // imm[20]
@@ -711,6 +1751,12 @@ impl Cpu {
             }
             0x73 => {
                 let csr_addr = ((inst & 0xfff00000) >> 20) as usize;
+                if funct3 != 0x0 {
+                    self.check_csr_access(csr_addr, funct3, rs1)?;
+                    self.check_stateen(csr_addr)?;
+                    self.check_counteren(csr_addr)?;
+                    self.check_fs(csr_addr)?;
+                }
                 match funct3 {
                     0x0 => {
                         match (rs2, funct7) {
@@ -745,6 +1791,14 @@ impl Cpu {
                                 // - Sets CSRs[sstatus].SIE to CSRs[sstatus].SPIE.
                                 // - Sets CSRs[sstatus].SPIE to 1.
                                 // - Sets CSRs[sstatus].SPP to 0.
+                                // "When TSR=1, attempts to execute SRET while executing in S-mode
+                                // will raise an illegal instruction exception."
+                                if self.mode == Mode::User
+                                    || (self.mode == Mode::Supervisor
+                                        && self.trapped_by(MSTATUS_TSR))
+                                {
+                                    return Err(Exception::IllegalInstruction);
+                                }
                                 self.pc = self.load_csr(SEPC);
                                 // When the SRET instruction is executed to return from the trap
                                 // handler, the privilege level is set to user mode if the SPP
@@ -766,6 +1820,14 @@ impl Cpu {
                                 );
                                 self.store_csr(SSTATUS, self.load_csr(SSTATUS) | (1 << 5));
                                 self.store_csr(SSTATUS, self.load_csr(SSTATUS) & !(1 << 8));
+                                // "An MRET or SRET instruction that changes the privilege mode to
+                                // a mode less privileged than M also sets MPRV=0."
+                                self.store_csr(MSTATUS, self.load_csr(MSTATUS) & !MSTATUS_MPRV);
+                                self.timeline.record(TimelineEvent::Return {
+                                    from: Mode::Supervisor,
+                                    to: self.mode,
+                                    pc: self.pc,
+                                });
                             }
                             (0x2, 0x18) => {
                                 // mret
@@ -779,7 +1841,7 @@ impl Cpu {
                                 self.pc = self.load_csr(MEPC);
                                 // MPP is two bits wide at [11..12] of the MSTATUS csr.
                                 self.mode = match (self.load_csr(MSTATUS) >> 11) & 0b11 {
-                                    2 => Mode::Machine,
+                                    3 => Mode::Machine,
                                     1 => Mode::Supervisor,
                                     _ => Mode::User,
                                 };
@@ -795,10 +1857,53 @@ impl Cpu {
                                 );
                                 self.store_csr(MSTATUS, self.load_csr(MSTATUS) | (1 << 7));
                                 self.store_csr(MSTATUS, self.load_csr(MSTATUS) & !(0b11 << 11));
+                                if self.mode != Mode::Machine {
+                                    self.store_csr(MSTATUS, self.load_csr(MSTATUS) & !MSTATUS_MPRV);
+                                }
+                                self.timeline.record(TimelineEvent::Return {
+                                    from: Mode::Machine,
+                                    to: self.mode,
+                                    pc: self.pc,
+                                });
+                            }
+                            (0x5, 0x8) => {
+                                // wfi
+                                // "The Wait for Interrupt instruction (WFI) provides a hint to
+                                // the implementation that the current hart can be stalled until
+                                // an interrupt might need servicing." The hart resumes when an
+                                // interrupt is pending in both mip and mie, even if interrupts
+                                // are globally disabled, and takes the trap only if they are
+                                // enabled. It's stalled by executing wfi again at the next step,
+                                // while devices keep running in check_pending_interrupt().
+                                // "When S-mode is implemented, then executing WFI in U-mode
+                                // causes an illegal instruction exception"
+                                if self.mode == Mode::User {
+                                    return Err(Exception::IllegalInstruction);
+                                }
+                                if self.load_csr(MIP) & self.csrs[MIE] == 0 {
+                                    // "When TW=1, then if WFI is executed in any less-privileged
+                                    // mode, and it does not complete within an
+                                    // implementation-specific, bounded time limit, the WFI
+                                    // instruction causes an illegal instruction exception."
+                                    if self.mode == Mode::Supervisor
+                                        && self.trapped_by(MSTATUS_TW)
+                                        && self.wfi_steps >= WFI_TIMEOUT
+                                    {
+                                        return Err(Exception::IllegalInstruction);
+                                    }
+                                    self.pc = self.pc.wrapping_sub(4);
+                                    self.waiting = true;
+                                    self.wfi_steps += 1;
+                                }
                             }
                             (_, 0x9) => {
                                 // sfence.vma
-                                // Do nothing.
+                                // "When TVM=1, attempts to ... execute an SFENCE.VMA ... while
+                                // executing in S-mode will raise an illegal instruction
+                                // exception." Otherwise, do nothing.
+                                if self.mode == Mode::Supervisor && self.trapped_by(MSTATUS_TVM) {
+                                    return Err(Exception::IllegalInstruction);
+                                }
                             }
                             _ => {
                                 println!(
@@ -812,31 +1917,37 @@ impl Cpu {
                     0x1 => {
                         // csrrw
                         let t = self.load_csr(csr_addr);
-                        self.store_csr(csr_addr, self.regs[rs1]);
-                        self.regs[rd] = t;
+                        self.store_csr(csr_addr, self.read_reg(rs1));
+                        self.write_reg(rd, t);
 
                         self.update_paging(csr_addr);
                     }
                     0x2 => {
                         // csrrs
                         let t = self.load_csr(csr_addr);
-                        self.store_csr(csr_addr, t | self.regs[rs1]);
-                        self.regs[rd] = t;
+                        self.store_csr(
+                            csr_addr,
+                            self.csr_to_modify(csr_addr, t) | self.read_reg(rs1),
+                        );
+                        self.write_reg(rd, t);
 
                         self.update_paging(csr_addr);
                     }
                     0x3 => {
                         // csrrc
                         let t = self.load_csr(csr_addr);
-                        self.store_csr(csr_addr, t & (!self.regs[rs1]));
-                        self.regs[rd] = t;
+                        self.store_csr(
+                            csr_addr,
+                            self.csr_to_modify(csr_addr, t) & !self.read_reg(rs1),
+                        );
+                        self.write_reg(rd, t);
 
                         self.update_paging(csr_addr);
                     }
                     0x5 => {
                         // csrrwi
                         let zimm = rs1 as u64;
-                        self.regs[rd] = self.load_csr(csr_addr);
+                        self.write_reg(rd, self.load_csr(csr_addr));
                         self.store_csr(csr_addr, zimm);
 
                         self.update_paging(csr_addr);
@@ -845,8 +1956,8 @@ impl Cpu {
                         // csrrsi
                         let zimm = rs1 as u64;
                         let t = self.load_csr(csr_addr);
-                        self.store_csr(csr_addr, t | zimm);
-                        self.regs[rd] = t;
+                        self.store_csr(csr_addr, self.csr_to_modify(csr_addr, t) | zimm);
+                        self.write_reg(rd, t);
 
                         self.update_paging(csr_addr);
                     }
@@ -854,8 +1965,8 @@ impl Cpu {
                         // csrrci
                         let zimm = rs1 as u64;
                         let t = self.load_csr(csr_addr);
-                        self.store_csr(csr_addr, t & (!zimm));
-                        self.regs[rd] = t;
+                        self.store_csr(csr_addr, self.csr_to_modify(csr_addr, t) & !zimm);
+                        self.write_reg(rd, t);
 
                         self.update_paging(csr_addr);
                     }
@@ -870,6 +1981,6 @@ impl Cpu {
                 return Err(Exception::IllegalInstruction);
             }
         }
-        return Ok(());
+        Ok(())
     }
 }
@@ -0,0 +1,336 @@
+//! The debug_module module contains the Debug Module (DM) of the RISC-V External Debug Support
+//! 0.13. A debugger accesses its registers through the Debug Module Interface (DMI) to halt and
+//! resume the hart, to read and write registers and memory with abstract commands, and to execute
+//! instructions in the program buffer.
+//! See the spec: https://github.com/riscv/riscv-debug-spec/releases/download/task_group_vote/riscv-debug-draft-014549f.pdf
+
+use crate::cpu::*;
+use crate::mmu::{debug_load, debug_store};
+
+/// Debug control and status register.
+pub const DCSR: usize = 0x7b0;
+/// Debug program counter.
+pub const DPC: usize = 0x7b1;
+
+// DCSR fields.
+const DCSR_XDEBUGVER: u64 = 4 << 28;
+const DCSR_EBREAKM: u64 = 1 << 15;
+const DCSR_EBREAKS: u64 = 1 << 13;
+const DCSR_EBREAKU: u64 = 1 << 12;
+const DCSR_CAUSE_SHIFT: u64 = 6;
+const DCSR_STEP: u64 = 1 << 2;
+const DCSR_PRV: u64 = 0b11;
+
+// The causes of entering debug mode.
+const CAUSE_EBREAK: u64 = 1;
+const CAUSE_HALTREQ: u64 = 3;
+const CAUSE_STEP: u64 = 4;
+
+// Debug Module registers.
+const DM_DATA0: u32 = 0x04;
+const DM_DMCONTROL: u32 = 0x10;
+const DM_DMSTATUS: u32 = 0x11;
+const DM_HARTINFO: u32 = 0x12;
+const DM_ABSTRACTCS: u32 = 0x16;
+const DM_COMMAND: u32 = 0x17;
+const DM_PROGBUF0: u32 = 0x20;
+const DM_SBCS: u32 = 0x38;
+const DM_HALTSUM0: u32 = 0x40;
+
+/// The number of data registers.
+const DATA_COUNT: usize = 4;
+/// The number of program buffer words. An ebreak is implied after the last word.
+const PROGBUF_SIZE: usize = 2;
+
+// Abstract command errors.
+const CMDERR_NOT_SUPPORTED: u32 = 2;
+const CMDERR_EXCEPTION: u32 = 3;
+const CMDERR_HALT_RESUME: u32 = 4;
+const CMDERR_BUS: u32 = 5;
+
+/// The encoding of the ebreak instruction.
+const EBREAK: u32 = 0x00100073;
+
+/// The Debug Module for a single hart.
+pub struct DebugModule {
+    dmactive: bool,
+    ndmreset: bool,
+    hartsel: u32,
+    /// True if the hart is in debug mode.
+    halted: bool,
+    resumeack: bool,
+    /// True if the hart was resumed with dcsr.step and halts after the next instruction.
+    stepping: bool,
+    cmderr: u32,
+    data: [u32; DATA_COUNT],
+    progbuf: [u32; PROGBUF_SIZE],
+}
+
+impl DebugModule {
+    /// Create a new `DebugModule` object.
+    pub fn new() -> Self {
+        Self {
+            dmactive: false,
+            ndmreset: false,
+            hartsel: 0,
+            halted: false,
+            resumeack: false,
+            stepping: false,
+            cmderr: 0,
+            data: [0; DATA_COUNT],
+            progbuf: [0; PROGBUF_SIZE],
+        }
+    }
+
+    /// Return true if the hart is in debug mode.
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// Halt the hart after a single step. It's called before each instruction.
+    pub fn check_step(&mut self, cpu: &mut Cpu) {
+        if self.stepping {
+            self.stepping = false;
+            self.halt(cpu, CAUSE_STEP);
+        }
+    }
+
+    /// Enter debug mode on an ebreak if dcsr enables it for the current privilege mode. Return
+    /// true if the hart has halted and the exception must not be taken.
+    pub fn ebreak(&mut self, cpu: &mut Cpu) -> bool {
+        let dcsr = cpu.load_csr(DCSR);
+        let enabled = match cpu.mode {
+            Mode::Machine => dcsr & DCSR_EBREAKM,
+            Mode::Supervisor => dcsr & DCSR_EBREAKS,
+            Mode::User => dcsr & DCSR_EBREAKU,
+        };
+        if enabled == 0 {
+            return false;
+        }
+        // The program counter has already been incremented.
+        cpu.pc = cpu.pc.wrapping_sub(4);
+        self.halt(cpu, CAUSE_EBREAK);
+        true
+    }
+
+    fn halt(&mut self, cpu: &mut Cpu, cause: u64) {
+        if self.halted {
+            return;
+        }
+        self.halted = true;
+        cpu.store_csr(DPC, cpu.pc);
+        let dcsr = cpu.load_csr(DCSR) & (DCSR_EBREAKM | DCSR_EBREAKS | DCSR_EBREAKU | DCSR_STEP);
+        cpu.store_csr(
+            DCSR,
+            DCSR_XDEBUGVER | dcsr | (cause << DCSR_CAUSE_SHIFT) | cpu.mode as u64,
+        );
+    }
+
+    fn resume(&mut self, cpu: &mut Cpu) {
+        if !self.halted {
+            return;
+        }
+        self.halted = false;
+        self.resumeack = true;
+        let dcsr = cpu.load_csr(DCSR);
+        cpu.pc = cpu.load_csr(DPC);
+        cpu.mode = match dcsr & DCSR_PRV {
+            0b00 => Mode::User,
+            0b01 => Mode::Supervisor,
+            _ => Mode::Machine,
+        };
+        self.stepping = dcsr & DCSR_STEP != 0;
+    }
+
+    /// Read a Debug Module register.
+    pub fn read(&self, addr: u32) -> u32 {
+        match addr {
+            DM_DATA0..=0x07 => self.data[(addr - DM_DATA0) as usize],
+            DM_DMCONTROL => {
+                (self.hartsel << 16) | ((self.ndmreset as u32) << 1) | self.dmactive as u32
+            }
+            DM_DMSTATUS => self.dmstatus(),
+            DM_HARTINFO => 0,
+            DM_ABSTRACTCS => ((PROGBUF_SIZE as u32) << 24) | (self.cmderr << 8) | DATA_COUNT as u32,
+            DM_PROGBUF0..=0x21 => self.progbuf[(addr - DM_PROGBUF0) as usize],
+            // No system bus access.
+            DM_SBCS => 0,
+            DM_HALTSUM0 => (self.halted && self.hartsel == 0) as u32,
+            _ => 0,
+        }
+    }
+
+    /// Write a Debug Module register.
+    pub fn write(&mut self, cpu: &mut Cpu, addr: u32, value: u32) {
+        if addr != DM_DMCONTROL && !self.dmactive {
+            return;
+        }
+        match addr {
+            DM_DATA0..=0x07 => self.data[(addr - DM_DATA0) as usize] = value,
+            DM_DMCONTROL => self.write_dmcontrol(cpu, value),
+            DM_ABSTRACTCS => self.cmderr &= !((value >> 8) & 0b111),
+            // A command is ignored while an error is set.
+            DM_COMMAND if self.cmderr == 0 => self.execute_command(cpu, value),
+            DM_PROGBUF0..=0x21 => self.progbuf[(addr - DM_PROGBUF0) as usize] = value,
+            _ => {}
+        }
+    }
+
+    fn dmstatus(&self) -> u32 {
+        // version = 2 (0.13), authenticated, impebreak.
+        let mut status = 2 | (1 << 7) | (1 << 22);
+        if self.hartsel != 0 {
+            // anynonexistent and allnonexistent.
+            return status | (0b11 << 14);
+        }
+        status |= if self.halted { 0b11 << 8 } else { 0b11 << 10 };
+        if self.resumeack {
+            status |= 0b11 << 16;
+        }
+        status
+    }
+
+    fn write_dmcontrol(&mut self, cpu: &mut Cpu, value: u32) {
+        self.dmactive = value & 1 != 0;
+        if !self.dmactive {
+            // Reset the Debug Module itself. It doesn't affect the hart.
+            let halted = self.halted;
+            *self = Self::new();
+            self.halted = halted;
+            return;
+        }
+        self.ndmreset = (value >> 1) & 1 != 0;
+        self.hartsel = (value >> 16) & 0x3ff;
+        if self.hartsel != 0 {
+            return;
+        }
+        if (value >> 31) & 1 != 0 {
+            // haltreq
+            self.halt(cpu, CAUSE_HALTREQ);
+        } else if (value >> 30) & 1 != 0 {
+            // resumereq
+            self.resumeack = false;
+            self.resume(cpu);
+        }
+    }
+
+    fn execute_command(&mut self, cpu: &mut Cpu, command: u32) {
+        let result = match command >> 24 {
+            0 => self.access_register(cpu, command),
+            2 => self.access_memory(cpu, command),
+            _ => Err(CMDERR_NOT_SUPPORTED),
+        };
+        if let Err(cmderr) = result {
+            self.cmderr = cmderr;
+        }
+    }
+
+    /// Return an argument of 32 or 64 bits from the data registers.
+    fn arg(&self, index: usize, size: u32) -> u64 {
+        match size {
+            64 => (self.data[index * 2] as u64) | ((self.data[index * 2 + 1] as u64) << 32),
+            _ => self.data[index * 2] as u64,
+        }
+    }
+
+    fn set_arg(&mut self, index: usize, size: u32, value: u64) {
+        self.data[index * 2] = value as u32;
+        if size == 64 {
+            self.data[index * 2 + 1] = (value >> 32) as u32;
+        }
+    }
+
+    /// The access register command. Register numbers 0x0000-0x0fff are CSRs and 0x1000-0x101f
+    /// are GPRs.
+    fn access_register(&mut self, cpu: &mut Cpu, command: u32) -> Result<(), u32> {
+        if !self.halted {
+            return Err(CMDERR_HALT_RESUME);
+        }
+        let size = match (command >> 20) & 0b111 {
+            2 => 32,
+            3 => 64,
+            _ => return Err(CMDERR_NOT_SUPPORTED),
+        };
+        let regno = (command & 0xffff) as usize;
+        let transfer = (command >> 17) & 1 != 0;
+        let write = (command >> 16) & 1 != 0;
+        let postexec = (command >> 18) & 1 != 0;
+
+        if transfer {
+            match (regno, write) {
+                (0x0000..=0x0fff, false) => self.set_arg(0, size, cpu.load_csr(regno)),
+                (0x0000..=0x0fff, true) => {
+                    cpu.store_csr(regno, self.arg(0, size));
+                    cpu.update_paging(regno);
+                }
+                (0x1000..=0x101f, false) => self.set_arg(0, size, cpu.regs[regno - 0x1000]),
+                (0x1000..=0x101f, true) => {
+                    if regno != 0x1000 {
+                        cpu.regs[regno - 0x1000] = self.arg(0, size);
+                    }
+                }
+                _ => return Err(CMDERR_EXCEPTION),
+            }
+        }
+        if postexec {
+            self.execute_progbuf(cpu)?;
+        }
+        Ok(())
+    }
+
+    /// The access memory command. arg0 holds data and arg1 holds an address. A virtual address
+    /// is translated with the current satp without side effects on the guest.
+    fn access_memory(&mut self, cpu: &mut Cpu, command: u32) -> Result<(), u32> {
+        let size = match (command >> 20) & 0b111 {
+            aamsize @ 0..=3 => 8 << aamsize,
+            _ => return Err(CMDERR_NOT_SUPPORTED),
+        };
+        let virtual_address = (command >> 23) & 1 != 0;
+        let postincrement = (command >> 19) & 1 != 0;
+        let write = (command >> 16) & 1 != 0;
+        let addr = self.arg(1, 64);
+        if !virtual_address && addr.wrapping_add(size as u64 / 8) > cpu.bus.dram_range().end {
+            return Err(CMDERR_BUS);
+        }
+
+        if write {
+            let value = self.arg(0, size);
+            let result = if virtual_address {
+                debug_store(cpu, addr, size as u64, value, None)
+            } else {
+                cpu.bus.store(addr, size as u64, value).ok()
+            };
+            result.ok_or(CMDERR_BUS)?;
+        } else {
+            let result = if virtual_address {
+                debug_load(cpu, addr, size as u64, None)
+            } else {
+                cpu.bus.load(addr, size as u64).ok()
+            };
+            let value = result.ok_or(CMDERR_BUS)?;
+            self.set_arg(0, size.max(32), value);
+        }
+        if postincrement {
+            self.set_arg(1, 64, addr.wrapping_add(size as u64 / 8));
+        }
+        Ok(())
+    }
+
+    /// Execute the program buffer until an ebreak. The program counter of the hart is kept.
+    fn execute_progbuf(&mut self, cpu: &mut Cpu) -> Result<(), u32> {
+        let pc = cpu.pc;
+        let mut result = Ok(());
+        for inst in self.progbuf {
+            if inst == EBREAK {
+                break;
+            }
+            if let Err(exception) = cpu.execute(inst as u64) {
+                println!("exception in the program buffer: {:?}", exception);
+                result = Err(CMDERR_EXCEPTION);
+                break;
+            }
+        }
+        cpu.pc = pc;
+        result
+    }
+}
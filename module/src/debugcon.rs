@@ -0,0 +1,54 @@
+//! The debugcon module contains a debug console, the simplest output device in the system. It has
+//! a single write-only byte register at `DEBUGCON_BASE` and prints whatever is written to it to
+//! stdout. Bare-metal programs can print without writing a UART driver first:
+//!
+//! ```text
+//!     li t0, 0x1001000  # DEBUGCON_BASE
+//!     li t1, 'A'
+//!     sb t1, 0(t0)
+//! ```
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::bus::*;
+use crate::trap::*;
+
+/// Output register, write-only. Reading from it returns 0.
+pub const DEBUGCON_OUT: u64 = DEBUGCON_BASE;
+
+/// The debug console.
+pub struct Debugcon {}
+
+impl Device for Debugcon {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::QuadWord => Err(Exception::LoadAccessFault(addr)),
+            _ => Ok(0),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::QuadWord => Err(Exception::StoreAMOAccessFault(addr)),
+            _ => {
+                self.store8(addr, value);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Debugcon {
+    /// Create a new `Debugcon` object.
+    pub fn new() -> Self {
+        Self {}
+    }
+
+    fn store8(&mut self, addr: u64, value: u64) {
+        if addr == DEBUGCON_OUT {
+            print!("{}", value as u8 as char);
+            io::stdout().flush().expect("failed to flush stdout");
+        }
+    }
+}
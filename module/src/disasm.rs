@@ -0,0 +1,356 @@
+//! The disasm module contains a disassembler for RV64I with the M and A extensions, Zicsr, the
+//! scalar cryptography extensions, and the privileged instructions. It returns assembly in the
+//! same syntax as GNU objdump with ABI register names.
+
+/// ABI names of integer registers.
+pub const REG_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+/// Return the name of a CSR if it's known, otherwise its number.
+pub fn csr_name(csr: u64) -> String {
+    let name = match csr {
+        0x001 => "fflags",
+        0x002 => "frm",
+        0x003 => "fcsr",
+        0xc00 => "cycle",
+        0xc01 => "time",
+        0xc02 => "instret",
+        0x100 => "sstatus",
+        0x104 => "sie",
+        0x105 => "stvec",
+        0x106 => "scounteren",
+        0x10a => "senvcfg",
+        0x10c => "sstateen0",
+        0x140 => "sscratch",
+        0x141 => "sepc",
+        0x142 => "scause",
+        0x143 => "stval",
+        0x144 => "sip",
+        0x180 => "satp",
+        0x300 => "mstatus",
+        0x301 => "misa",
+        0x302 => "medeleg",
+        0x303 => "mideleg",
+        0x304 => "mie",
+        0x305 => "mtvec",
+        0x306 => "mcounteren",
+        0x30a => "menvcfg",
+        0x30c => "mstateen0",
+        0x340 => "mscratch",
+        0x341 => "mepc",
+        0x342 => "mcause",
+        0x343 => "mtval",
+        0x344 => "mip",
+        0x3a0 => "pmpcfg0",
+        0x3b0 => "pmpaddr0",
+        0xb00 => "mcycle",
+        0xb02 => "minstret",
+        0xf11 => "mvendorid",
+        0xf12 => "marchid",
+        0xf13 => "mimpid",
+        0xf14 => "mhartid",
+        _ => return format!("{:#x}", csr),
+    };
+    name.to_string()
+}
+
+/// Disassemble a 32-bit instruction. `pc` is the address of the instruction and is used to show
+/// the targets of branches and jumps.
+pub fn disassemble(pc: u64, inst: u64) -> String {
+    let opcode = inst & 0x7f;
+    let rd = REG_NAMES[((inst >> 7) & 0x1f) as usize];
+    let rs1 = REG_NAMES[((inst >> 15) & 0x1f) as usize];
+    let rs2 = REG_NAMES[((inst >> 20) & 0x1f) as usize];
+    let funct3 = (inst >> 12) & 0x7;
+    let funct7 = (inst >> 25) & 0x7f;
+    let i_imm = (inst as i32 as i64) >> 20;
+    let s_imm = (((inst & 0xfe000000) as i32 as i64) >> 20) | ((inst >> 7) & 0x1f) as i64;
+    let b_imm = (((inst & 0x80000000) as i32 as i64) >> 19)
+        | ((inst & 0x80) << 4) as i64
+        | ((inst >> 20) & 0x7e0) as i64
+        | ((inst >> 7) & 0x1e) as i64;
+    let u_imm = (inst & 0xfffff000) as i32 as i64;
+    let j_imm = (((inst & 0x80000000) as i32 as i64) >> 11)
+        | (inst & 0xff000) as i64
+        | ((inst >> 9) & 0x800) as i64
+        | ((inst >> 20) & 0x7fe) as i64;
+
+    let unknown = || format!("unknown {:#010x}", inst);
+
+    match opcode {
+        0x03 => {
+            let name = match funct3 {
+                0x0 => "lb",
+                0x1 => "lh",
+                0x2 => "lw",
+                0x3 => "ld",
+                0x4 => "lbu",
+                0x5 => "lhu",
+                0x6 => "lwu",
+                _ => return unknown(),
+            };
+            format!("{} {},{}({})", name, rd, i_imm, rs1)
+        }
+        0x0f => match funct3 {
+            0x0 => "fence".to_string(),
+            0x1 => "fence.i".to_string(),
+            _ => unknown(),
+        },
+        0x13 => {
+            let shamt = (inst >> 20) & 0x3f;
+            match funct3 {
+                0x0 if inst == 0x13 => "nop".to_string(),
+                0x0 if i_imm == 0 => format!("mv {},{}", rd, rs1),
+                0x0 => format!("addi {},{},{}", rd, rs1, i_imm),
+                0x1 => match (i_imm & 0xfff, funct7 >> 1) {
+                    (_, 0x00) => format!("slli {},{},{:#x}", rd, rs1, shamt),
+                    (0x310..=0x31f, _) => format!("aes64ks1i {},{},{:#x}", rd, rs1, i_imm & 0xf),
+                    (_, 0x0a) => format!("bseti {},{},{:#x}", rd, rs1, shamt),
+                    (_, 0x12) => format!("bclri {},{},{:#x}", rd, rs1, shamt),
+                    (_, 0x1a) => format!("binvi {},{},{:#x}", rd, rs1, shamt),
+                    (funct, _) => {
+                        let name = match funct {
+                            0x100 => "sha256sum0",
+                            0x101 => "sha256sum1",
+                            0x102 => "sha256sig0",
+                            0x103 => "sha256sig1",
+                            0x104 => "sha512sum0",
+                            0x105 => "sha512sum1",
+                            0x106 => "sha512sig0",
+                            0x107 => "sha512sig1",
+                            0x108 => "sm3p0",
+                            0x109 => "sm3p1",
+                            0x300 => "aes64im",
+                            0x600 => "clz",
+                            0x601 => "ctz",
+                            0x602 => "cpop",
+                            0x604 => "sext.b",
+                            0x605 => "sext.h",
+                            _ => return unknown(),
+                        };
+                        format!("{} {},{}", name, rd, rs1)
+                    }
+                },
+                0x2 => format!("slti {},{},{}", rd, rs1, i_imm),
+                0x3 => format!("sltiu {},{},{}", rd, rs1, i_imm),
+                0x4 => format!("xori {},{},{}", rd, rs1, i_imm),
+                0x5 => match (i_imm & 0xfff, funct7 >> 1) {
+                    (_, 0x00) => format!("srli {},{},{:#x}", rd, rs1, shamt),
+                    (_, 0x10) => format!("srai {},{},{:#x}", rd, rs1, shamt),
+                    (_, 0x18) => format!("rori {},{},{:#x}", rd, rs1, shamt),
+                    (0x687, _) => format!("brev8 {},{}", rd, rs1),
+                    (0x6b8, _) => format!("rev8 {},{}", rd, rs1),
+                    (0x287, _) => format!("orc.b {},{}", rd, rs1),
+                    (_, 0x12) => format!("bexti {},{},{:#x}", rd, rs1, shamt),
+                    _ => unknown(),
+                },
+                0x6 => format!("ori {},{},{}", rd, rs1, i_imm),
+                _ => format!("andi {},{},{}", rd, rs1, i_imm),
+            }
+        }
+        0x17 => format!("auipc {},{:#x}", rd, (u_imm as u64 >> 12) & 0xfffff),
+        0x1b => {
+            let shamt = (inst >> 20) & 0x1f;
+            match (funct3, funct7) {
+                (0x0, _) => format!("addiw {},{},{}", rd, rs1, i_imm),
+                (0x1, 0x00) => format!("slliw {},{},{:#x}", rd, rs1, shamt),
+                (0x1, 0x04 | 0x05) => format!("slli.uw {},{},{:#x}", rd, rs1, (inst >> 20) & 0x3f),
+                (0x1, 0x30) => {
+                    let name = match (inst >> 20) & 0x1f {
+                        0x0 => "clzw",
+                        0x1 => "ctzw",
+                        0x2 => "cpopw",
+                        _ => return unknown(),
+                    };
+                    format!("{} {},{}", name, rd, rs1)
+                }
+                (0x5, 0x00) => format!("srliw {},{},{:#x}", rd, rs1, shamt),
+                (0x5, 0x20) => format!("sraiw {},{},{:#x}", rd, rs1, shamt),
+                (0x5, 0x30) => format!("roriw {},{},{:#x}", rd, rs1, shamt),
+                _ => unknown(),
+            }
+        }
+        0x23 => {
+            let name = match funct3 {
+                0x0 => "sb",
+                0x1 => "sh",
+                0x2 => "sw",
+                0x3 => "sd",
+                _ => return unknown(),
+            };
+            format!("{} {},{}({})", name, rs2, s_imm, rs1)
+        }
+        0x2f => {
+            let width = match funct3 {
+                0x0 => "b",
+                0x1 => "h",
+                0x2 => "w",
+                0x3 => "d",
+                _ => return unknown(),
+            };
+            let ordering = match funct7 & 0b11 {
+                0b10 => ".aq",
+                0b01 => ".rl",
+                0b11 => ".aqrl",
+                _ => "",
+            };
+            let name = match funct7 >> 2 {
+                0x00 => "amoadd",
+                0x01 => "amoswap",
+                0x02 => return format!("lr.{}{} {},({})", width, ordering, rd, rs1),
+                0x03 => "sc",
+                0x04 => "amoxor",
+                0x08 => "amoor",
+                0x0c => "amoand",
+                0x10 => "amomin",
+                0x14 => "amomax",
+                0x18 => "amominu",
+                0x1c => "amomaxu",
+                _ => return unknown(),
+            };
+            format!("{}.{}{} {},{},({})", name, width, ordering, rd, rs2, rs1)
+        }
+        0x33 => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "add",
+                (0x0, 0x20) => "sub",
+                (0x1, 0x00) => "sll",
+                (0x2, 0x00) => "slt",
+                (0x3, 0x00) => "sltu",
+                (0x4, 0x00) => "xor",
+                (0x5, 0x00) => "srl",
+                (0x5, 0x20) => "sra",
+                (0x6, 0x00) => "or",
+                (0x7, 0x00) => "and",
+                (0x0, 0x01) => "mul",
+                (0x1, 0x01) => "mulh",
+                (0x2, 0x01) => "mulhsu",
+                (0x3, 0x01) => "mulhu",
+                (0x4, 0x01) => "div",
+                (0x5, 0x01) => "divu",
+                (0x6, 0x01) => "rem",
+                (0x7, 0x01) => "remu",
+                (0x0, 0x19) => "aes64es",
+                (0x0, 0x1b) => "aes64esm",
+                (0x0, 0x1d) => "aes64ds",
+                (0x0, 0x1f) => "aes64dsm",
+                (0x0, 0x3f) => "aes64ks2",
+                (0x0, _) if funct7 & 0x1f == 0x18 || funct7 & 0x1f == 0x1a => {
+                    let name = if funct7 & 0x1f == 0x18 {
+                        "sm4ed"
+                    } else {
+                        "sm4ks"
+                    };
+                    return format!("{} {},{},{},{}", name, rd, rs1, rs2, funct7 >> 5);
+                }
+                (0x1, 0x05) => "clmul",
+                (0x3, 0x05) => "clmulh",
+                (0x2, 0x14) => "xperm4",
+                (0x4, 0x14) => "xperm8",
+                (0x1, 0x30) => "rol",
+                (0x5, 0x30) => "ror",
+                (0x4, 0x20) => "xnor",
+                (0x6, 0x20) => "orn",
+                (0x7, 0x20) => "andn",
+                (0x4, 0x04) => "pack",
+                (0x7, 0x04) => "packh",
+                (0x2, 0x10) => "sh1add",
+                (0x4, 0x10) => "sh2add",
+                (0x6, 0x10) => "sh3add",
+                (0x4, 0x05) => "min",
+                (0x5, 0x05) => "minu",
+                (0x6, 0x05) => "max",
+                (0x7, 0x05) => "maxu",
+                (0x1, 0x14) => "bset",
+                (0x1, 0x24) => "bclr",
+                (0x1, 0x34) => "binv",
+                (0x5, 0x24) => "bext",
+                _ => return unknown(),
+            };
+            format!("{} {},{},{}", name, rd, rs1, rs2)
+        }
+        0x37 => format!("lui {},{:#x}", rd, (u_imm as u64 >> 12) & 0xfffff),
+        0x3b => {
+            let name = match (funct3, funct7) {
+                (0x0, 0x00) => "addw",
+                (0x0, 0x20) => "subw",
+                (0x1, 0x00) => "sllw",
+                (0x5, 0x00) => "srlw",
+                (0x5, 0x20) => "sraw",
+                (0x0, 0x01) => "mulw",
+                (0x4, 0x01) => "divw",
+                (0x5, 0x01) => "divuw",
+                (0x6, 0x01) => "remw",
+                (0x7, 0x01) => "remuw",
+                (0x1, 0x30) => "rolw",
+                (0x5, 0x30) => "rorw",
+                (0x4, 0x04) if rs2 == "zero" => return format!("zext.h {},{}", rd, rs1),
+                (0x4, 0x04) => "packw",
+                (0x0, 0x04) => "add.uw",
+                (0x2, 0x10) => "sh1add.uw",
+                (0x4, 0x10) => "sh2add.uw",
+                (0x6, 0x10) => "sh3add.uw",
+                _ => return unknown(),
+            };
+            format!("{} {},{},{}", name, rd, rs1, rs2)
+        }
+        0x63 => {
+            let name = match funct3 {
+                0x0 => "beq",
+                0x1 => "bne",
+                0x4 => "blt",
+                0x5 => "bge",
+                0x6 => "bltu",
+                0x7 => "bgeu",
+                _ => return unknown(),
+            };
+            format!(
+                "{} {},{},{:#x}",
+                name,
+                rs1,
+                rs2,
+                pc.wrapping_add(b_imm as u64)
+            )
+        }
+        0x67 if funct3 == 0 => {
+            if inst == 0x00008067 {
+                "ret".to_string()
+            } else {
+                format!("jalr {},{}({})", rd, i_imm, rs1)
+            }
+        }
+        0x6f => {
+            let target = pc.wrapping_add(j_imm as u64);
+            match (inst >> 7) & 0x1f {
+                0 => format!("j {:#x}", target),
+                _ => format!("jal {},{:#x}", rd, target),
+            }
+        }
+        0x73 => {
+            let csr = csr_name((inst >> 20) & 0xfff);
+            let zimm = (inst >> 15) & 0x1f;
+            match funct3 {
+                0x0 => match inst {
+                    0x00000073 => "ecall".to_string(),
+                    0x00100073 => "ebreak".to_string(),
+                    0x10200073 => "sret".to_string(),
+                    0x30200073 => "mret".to_string(),
+                    0x10500073 => "wfi".to_string(),
+                    _ if funct7 == 0x09 => format!("sfence.vma {},{}", rs1, rs2),
+                    _ => unknown(),
+                },
+                0x1 => format!("csrrw {},{},{}", rd, csr, rs1),
+                0x2 => format!("csrrs {},{},{}", rd, csr, rs1),
+                0x3 => format!("csrrc {},{},{}", rd, csr, rs1),
+                0x5 => format!("csrrwi {},{},{}", rd, csr, zimm),
+                0x6 => format!("csrrsi {},{},{}", rd, csr, zimm),
+                0x7 => format!("csrrci {},{},{}", rd, csr, zimm),
+                _ => unknown(),
+            }
+        }
+        _ => unknown(),
+    }
+}
@@ -1,35 +1,71 @@
 //! The dram module contains a dram structure and implementation for dram access.
 
+use std::convert::TryInto;
+use std::io;
+use std::io::prelude::*;
+
 use crate::bus::*;
+use crate::entropy::Entropy;
+use crate::snapshot::*;
 use crate::trap::*;
 
 /// Default dram size (128MiB).
 pub const DRAM_SIZE: u64 = 1024 * 1024 * 128;
 
+/// The shift of the size of a page (4 KiB) whose modification is tracked.
+const DIRTY_PAGE_SHIFT: usize = 12;
+/// The word repeated by `MemFill::Pattern`.
+const FILL_PATTERN: u32 = 0xdeadbeef;
+
 /// The dynamic random access dram (DRAM).
 #[derive(Debug)]
 pub struct Dram {
     pub dram: Vec<u8>,
+    /// A bitmap of pages written since the last reset, so a reset only clears them.
+    dirty: Vec<u64>,
+    /// The initial contents of bytes which no image is written to.
+    fill: MemFill,
+    /// The images written since the last reset and their indexes, to restore them at a
+    /// machine-wide reset.
+    images: Vec<(usize, Vec<u8>)>,
 }
 
 impl Device for Dram {
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match size {
-            8 => Ok(self.load8(addr)),
-            16 => Ok(self.load16(addr)),
-            32 => Ok(self.load32(addr)),
-            64 => Ok(self.load64(addr)),
-            _ => Err(Exception::LoadAccessFault),
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        if !self.contains(addr, width.bytes()) {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        match width {
+            Width::Byte => Ok(self.load8(addr)),
+            Width::HalfWord => Ok(self.load16(addr)),
+            Width::Word => Ok(self.load32(addr)),
+            Width::DoubleWord => Ok(self.load64(addr)),
+            Width::QuadWord => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
-    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match size {
-            8 => Ok(self.store8(addr, value)),
-            16 => Ok(self.store16(addr, value)),
-            32 => Ok(self.store32(addr, value)),
-            64 => Ok(self.store64(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        if !self.contains(addr, width.bytes()) {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        match width {
+            Width::Byte => {
+                self.store8(addr, value);
+                Ok(())
+            }
+            Width::HalfWord => {
+                self.store16(addr, value);
+                Ok(())
+            }
+            Width::Word => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            Width::DoubleWord => {
+                self.store64(addr, value);
+                Ok(())
+            }
+            Width::QuadWord => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
 }
@@ -37,10 +73,146 @@ impl Device for Dram {
 impl Dram {
     /// Create a new `Dram` object with default dram size.
     pub fn new(binary: Vec<u8>) -> Dram {
-        let mut dram = vec![0; DRAM_SIZE as usize];
-        dram.splice(..binary.len(), binary.iter().cloned());
+        let mut dram = Self {
+            dram: vec![0; DRAM_SIZE as usize],
+            dirty: vec![0; Self::dirty_words(DRAM_SIZE as usize)],
+            fill: MemFill::Zero,
+            images: Vec::new(),
+        };
+        dram.write_image(DRAM_BASE, &binary);
+        dram
+    }
+
+    /// Return the number of words of the bitmap of dirty pages for `size` bytes.
+    fn dirty_words(size: usize) -> usize {
+        ((size >> DIRTY_PAGE_SHIFT) + 64) / 64
+    }
+
+    /// Mark the pages from `index` to `index + len` (exclusive) as written.
+    fn mark_dirty(&mut self, index: usize, len: usize) {
+        if len == 0 {
+            return;
+        }
+        for page in (index >> DIRTY_PAGE_SHIFT)..=((index + len - 1) >> DIRTY_PAGE_SHIFT) {
+            self.dirty[page / 64] |= 1 << (page % 64);
+        }
+    }
+
+    /// Return true if `bytes` bytes from `addr` are inside the dram.
+    fn contains(&self, addr: u64, bytes: u64) -> bool {
+        addr.checked_sub(DRAM_BASE)
+            .and_then(|index| index.checked_add(bytes))
+            .is_some_and(|end| end <= self.dram.len() as u64)
+    }
+
+    /// Change the size of the dram, keeping its contents up to the new size.
+    pub fn resize(&mut self, size: u64) {
+        self.dram.resize(size as usize, 0);
+        self.dirty = vec![u64::MAX; Self::dirty_words(size as usize)];
+    }
+
+    /// Clear the dram and copy `binary` to its start. Only pages written since the last reset
+    /// are cleared, so the buffer isn't reallocated nor entirely zeroed.
+    pub fn reset(&mut self, binary: &[u8]) {
+        self.clear_dirty_pages();
+        self.images.clear();
+        self.write_image(DRAM_BASE, binary);
+    }
+
+    /// Restore the contents at boot for a machine-wide reset: pages written since the last reset
+    /// are cleared, and the images are written back.
+    pub fn restore_images(&mut self) {
+        self.clear_dirty_pages();
+        for (index, image) in std::mem::take(&mut self.images) {
+            self.write_image(index as u64 + DRAM_BASE, &image);
+        }
+    }
 
-        Self { dram }
+    /// Write the initial contents to the pages written since the last reset.
+    fn clear_dirty_pages(&mut self) {
+        for word in 0..self.dirty.len() {
+            let mut bits = std::mem::take(&mut self.dirty[word]);
+            while bits != 0 {
+                let page = word * 64 + bits.trailing_zeros() as usize;
+                bits &= bits - 1;
+                self.fill_page(page);
+            }
+        }
+    }
+
+    /// Fill the dram by `fill` except the images written so far. The contents of a page only
+    /// depend on its index, so a reset fills it the same way.
+    pub fn set_fill(&mut self, fill: MemFill) {
+        self.fill = fill;
+        let images = std::mem::take(&mut self.images);
+        let pages = self.dram.len().div_ceil(1 << DIRTY_PAGE_SHIFT);
+        for page in 0..pages {
+            self.fill_page(page);
+        }
+        for (start, bytes) in images.iter() {
+            self.dram[*start..*start + bytes.len()].copy_from_slice(bytes);
+        }
+        self.images = images;
+    }
+
+    /// Write the initial contents to a page.
+    fn fill_page(&mut self, page: usize) {
+        let page_size = 1 << DIRTY_PAGE_SHIFT;
+        let start = (page * page_size).min(self.dram.len());
+        let end = (start + page_size).min(self.dram.len());
+        let bytes = &mut self.dram[start..end];
+        match self.fill {
+            MemFill::Zero => bytes.fill(0),
+            MemFill::Pattern => {
+                for (chunk, word) in bytes
+                    .chunks_mut(4)
+                    .zip(std::iter::repeat(FILL_PATTERN.to_le_bytes()))
+                {
+                    chunk.copy_from_slice(&word[..chunk.len()]);
+                }
+            }
+            MemFill::Random(seed) => {
+                Entropy::new(seed ^ (page as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+                    .fill_bytes(bytes)
+            }
+        }
+    }
+
+    /// Save the whole dram for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_bytes(out, &self.dram)
+    }
+
+    /// Restore the whole dram from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.dram = read_bytes(input)?;
+        self.dirty = vec![u64::MAX; Self::dirty_words(self.dram.len())];
+        Ok(())
+    }
+
+    /// Copy `image` to the dram starting at `addr`.
+    pub fn write_image(&mut self, addr: u64, image: &[u8]) {
+        let index = (addr - DRAM_BASE) as usize;
+        self.dram[index..index + image.len()].copy_from_slice(image);
+        self.mark_dirty(index, image.len());
+        self.images.push((index, image.to_vec()));
+    }
+
+    /// Return `N` bytes of the dram starting at `addr`, with a single bounds check.
+    fn bytes<const N: usize>(&self, addr: u64) -> [u8; N] {
+        let index = (addr - DRAM_BASE) as usize;
+        self.dram[index..index + N]
+            .try_into()
+            .expect("a slice has N bytes")
+    }
+
+    /// Return `N` bytes of the dram starting at `addr` to write to them.
+    fn bytes_mut<const N: usize>(&mut self, addr: u64) -> &mut [u8; N] {
+        let index = (addr - DRAM_BASE) as usize;
+        self.mark_dirty(index, N);
+        (&mut self.dram[index..index + N])
+            .try_into()
+            .expect("a slice has N bytes")
     }
 
     /// Load a byte from the little-endian dram.
@@ -51,64 +223,38 @@ impl Dram {
 
     /// Load 2 bytes from the little-endian dram.
     fn load16(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
-        return (self.dram[index] as u64) | ((self.dram[index + 1] as u64) << 8);
+        u16::from_le_bytes(self.bytes(addr)) as u64
     }
 
     /// Load 4 bytes from the little-endian dram.
     fn load32(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
-        return (self.dram[index] as u64)
-            | ((self.dram[index + 1] as u64) << 8)
-            | ((self.dram[index + 2] as u64) << 16)
-            | ((self.dram[index + 3] as u64) << 24);
+        u32::from_le_bytes(self.bytes(addr)) as u64
     }
 
     /// Load 8 bytes from the little-endian dram.
     fn load64(&self, addr: u64) -> u64 {
-        let index = (addr - DRAM_BASE) as usize;
-        return (self.dram[index] as u64)
-            | ((self.dram[index + 1] as u64) << 8)
-            | ((self.dram[index + 2] as u64) << 16)
-            | ((self.dram[index + 3] as u64) << 24)
-            | ((self.dram[index + 4] as u64) << 32)
-            | ((self.dram[index + 5] as u64) << 40)
-            | ((self.dram[index + 6] as u64) << 48)
-            | ((self.dram[index + 7] as u64) << 56);
+        u64::from_le_bytes(self.bytes(addr))
     }
 
     /// Store a byte to the little-endian dram.
     fn store8(&mut self, addr: u64, value: u64) {
         let index = (addr - DRAM_BASE) as usize;
+        self.mark_dirty(index, 1);
         self.dram[index] = value as u8
     }
 
     /// Store 2 bytes to the little-endian dram.
     fn store16(&mut self, addr: u64, value: u64) {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (value & 0xff) as u8;
-        self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
+        *self.bytes_mut(addr) = (value as u16).to_le_bytes();
     }
 
     /// Store 4 bytes to the little-endian dram.
     fn store32(&mut self, addr: u64, value: u64) {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (value & 0xff) as u8;
-        self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
-        self.dram[index + 2] = ((value >> 16) & 0xff) as u8;
-        self.dram[index + 3] = ((value >> 24) & 0xff) as u8;
+        *self.bytes_mut(addr) = (value as u32).to_le_bytes();
     }
 
     /// Store 8 bytes to the little-endian dram.
     fn store64(&mut self, addr: u64, value: u64) {
-        let index = (addr - DRAM_BASE) as usize;
-        self.dram[index] = (value & 0xff) as u8;
-        self.dram[index + 1] = ((value >> 8) & 0xff) as u8;
-        self.dram[index + 2] = ((value >> 16) & 0xff) as u8;
-        self.dram[index + 3] = ((value >> 24) & 0xff) as u8;
-        self.dram[index + 4] = ((value >> 32) & 0xff) as u8;
-        self.dram[index + 5] = ((value >> 40) & 0xff) as u8;
-        self.dram[index + 6] = ((value >> 48) & 0xff) as u8;
-        self.dram[index + 7] = ((value >> 56) & 0xff) as u8;
+        *self.bytes_mut(addr) = value.to_le_bytes();
     }
 }
@@ -0,0 +1,203 @@
+//! The elf module contains a reader of ELF files. The loader reads program headers with it, and
+//! the symbol table lets the debugger resolve names like `kernelvec` to addresses and show
+//! addresses as `<name+offset>`. Only 64-bit little-endian files are supported.
+//! See the spec: https://refspecs.linuxfoundation.org/elf/gabi4+/ch4.symtab.html
+
+use std::fs;
+use std::io;
+
+/// The program header type of a loadable segment.
+pub(crate) const PT_LOAD: u32 = 1;
+/// The section type of a symbol table.
+const SHT_SYMTAB: u32 = 2;
+/// The size of a symbol table entry.
+const SYM_SIZE: usize = 24;
+/// Symbol types for functions and data objects.
+const STT_OBJECT: u8 = 1;
+const STT_FUNC: u8 = 2;
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// A program header, which describes a segment.
+pub(crate) struct ProgramHeader {
+    pub kind: u32,
+    /// The offset of the segment in the file.
+    pub offset: u64,
+    pub paddr: u64,
+    /// The number of bytes in the file. The rest of the segment up to `memsz` is zero-filled.
+    pub filesz: u64,
+    pub memsz: u64,
+}
+
+/// An ELF file whose fields are read at offsets.
+pub(crate) struct ElfFile<'a> {
+    image: &'a [u8],
+}
+
+impl<'a> ElfFile<'a> {
+    /// Check the identification of an ELF image.
+    pub(crate) fn new(image: &'a [u8]) -> io::Result<Self> {
+        // ELFCLASS64 and ELFDATA2LSB.
+        if image.len() < 64 || &image[..4] != b"\x7fELF" || image[4] != 2 || image[5] != 1 {
+            return Err(invalid("not a 64-bit little-endian ELF file"));
+        }
+        Ok(Self { image })
+    }
+
+    /// Return `len` bytes at `offset`.
+    pub(crate) fn bytes(&self, offset: usize, len: usize) -> io::Result<&'a [u8]> {
+        self.image
+            .get(offset..offset.saturating_add(len))
+            .ok_or_else(|| invalid("truncated ELF file"))
+    }
+
+    fn u16_at(&self, offset: usize) -> io::Result<u64> {
+        let b = self.bytes(offset, 2)?;
+        Ok(u16::from_le_bytes([b[0], b[1]]) as u64)
+    }
+
+    fn u32_at(&self, offset: usize) -> io::Result<u64> {
+        let b = self.bytes(offset, 4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]) as u64)
+    }
+
+    fn u64_at(&self, offset: usize) -> io::Result<u64> {
+        let mut array = [0; 8];
+        array.copy_from_slice(self.bytes(offset, 8)?);
+        Ok(u64::from_le_bytes(array))
+    }
+
+    /// Return the entry point.
+    pub(crate) fn entry(&self) -> io::Result<u64> {
+        self.u64_at(0x18)
+    }
+
+    /// Return the program headers.
+    pub(crate) fn program_headers(&self) -> io::Result<Vec<ProgramHeader>> {
+        let phoff = self.u64_at(0x20)? as usize;
+        let phentsize = self.u16_at(0x36)? as usize;
+        let phnum = self.u16_at(0x38)? as usize;
+        (0..phnum)
+            .map(|i| {
+                let ph = phoff.saturating_add(i * phentsize);
+                Ok(ProgramHeader {
+                    kind: self.u32_at(ph)? as u32,
+                    offset: self.u64_at(ph.saturating_add(0x08))?,
+                    paddr: self.u64_at(ph.saturating_add(0x18))?,
+                    filesz: self.u64_at(ph.saturating_add(0x20))?,
+                    memsz: self.u64_at(ph.saturating_add(0x28))?,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A symbol, a name with an address and a size.
+#[derive(Debug, Clone)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u64,
+    pub size: u64,
+}
+
+/// Symbols sorted by addresses.
+#[derive(Debug, Default)]
+pub struct SymbolTable {
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Create a new `SymbolTable` object from symbols in any order.
+    pub fn new(mut symbols: Vec<Symbol>) -> Self {
+        symbols.sort_by_key(|symbol| symbol.addr);
+        Self { symbols }
+    }
+
+    /// Read the symbol table of an ELF file at `path`.
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(&fs::read(path)?)
+    }
+
+    /// Parse the symbol table of an ELF image.
+    pub fn parse(image: &[u8]) -> io::Result<Self> {
+        let elf = ElfFile::new(image)?;
+        let bytes = |offset, len| elf.bytes(offset, len);
+        let u16_at = |offset| elf.u16_at(offset);
+        let u32_at = |offset| elf.u32_at(offset);
+        let u64_at = |offset| elf.u64_at(offset);
+
+        let shoff = u64_at(0x28)? as usize;
+        let shentsize = u16_at(0x3a)? as usize;
+        let shnum = u16_at(0x3c)? as usize;
+        let mut symbols = Vec::new();
+        for i in 0..shnum {
+            let sh = shoff + i * shentsize;
+            if u32_at(sh + 4)? as u32 != SHT_SYMTAB {
+                continue;
+            }
+            let offset = u64_at(sh + 0x18)? as usize;
+            let size = u64_at(sh + 0x20)? as usize;
+            // sh_link is the index of the string table.
+            let strtab = shoff + u32_at(sh + 0x28)? as usize * shentsize;
+            let str_offset = u64_at(strtab + 0x18)? as usize;
+            let str_size = u64_at(strtab + 0x20)? as usize;
+            let strings = bytes(str_offset, str_size)?;
+
+            for entry in (offset..offset + size).step_by(SYM_SIZE) {
+                let name = u32_at(entry)? as usize;
+                let kind = bytes(entry + 4, 1)?[0] & 0xf;
+                let addr = u64_at(entry + 8)?;
+                if (kind != STT_FUNC && kind != STT_OBJECT && kind != 0) || addr == 0 {
+                    continue;
+                }
+                let name = match strings.get(name..) {
+                    Some(rest) => rest.split(|b| *b == 0).next().unwrap_or(&[]),
+                    None => continue,
+                };
+                // Skip unnamed symbols and local labels such as `.L0`.
+                if name.is_empty() || name.starts_with(b".L") {
+                    continue;
+                }
+                symbols.push(Symbol {
+                    name: String::from_utf8_lossy(name).to_string(),
+                    addr,
+                    size: u64_at(entry + 16)?,
+                });
+            }
+        }
+        symbols.sort_by_key(|symbol| symbol.addr);
+        Ok(Self { symbols })
+    }
+
+    /// Return the number of symbols.
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    /// Return true if there are no symbols.
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Return the address of a symbol named `name`.
+    pub fn lookup(&self, name: &str) -> Option<u64> {
+        self.symbols
+            .iter()
+            .find(|symbol| symbol.name == name)
+            .map(|symbol| symbol.addr)
+    }
+
+    /// Return the symbol containing `addr` and the offset from its start. A symbol without a
+    /// size contains addresses up to the next symbol.
+    pub fn symbolize(&self, addr: u64) -> Option<(&str, u64)> {
+        let index = self.symbols.partition_point(|symbol| symbol.addr <= addr);
+        let symbol = &self.symbols[index.checked_sub(1)?];
+        let offset = addr - symbol.addr;
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+        Some((&symbol.name, offset))
+    }
+}
@@ -0,0 +1,159 @@
+//! The emuctl module contains an emulator control device. It's a "magic hypercall" interface
+//! which only exists in this emulator: a guest can query the emulator version and features, print
+//! debug strings, start and stop an instruction trace, exit with a code, and reboot the machine.
+//! It greatly simplifies writing test programs that target this emulator.
+//!
+//! Every register can be loaded and stored with 1, 2, 4 or 8 bytes at its address. A narrower
+//! load returns the low bits of the register, and a narrower store writes the value zero-extended.
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::bus::*;
+use crate::cpu::implementation_id;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The version of the emulator, read-only. It's encoded as `major << 16 | minor << 8 | patch`.
+pub const EMUCTL_VERSION: u64 = EMUCTL_BASE;
+/// The features supported by the emulator control device, read-only. See `EMUCTL_FEATURE_*`.
+pub const EMUCTL_FEATURES: u64 = EMUCTL_BASE + 0x08;
+/// Debug output, write-only. The low byte of a written value is appended to a line buffer, which
+/// is printed to stderr when a newline is written.
+pub const EMUCTL_PUTCHAR: u64 = EMUCTL_BASE + 0x10;
+/// Instruction trace control, read and write. Writing 1 starts tracing and writing 0 stops it.
+pub const EMUCTL_TRACE: u64 = EMUCTL_BASE + 0x18;
+/// Exit request, write-only. Writing a value stops the emulator with the value as an exit code.
+pub const EMUCTL_EXIT: u64 = EMUCTL_BASE + 0x20;
+/// Reset request, write-only. Writing any value resets the machine in place after the store.
+pub const EMUCTL_RESET: u64 = EMUCTL_BASE + 0x28;
+
+/// `EMUCTL_PUTCHAR` is available.
+pub const EMUCTL_FEATURE_PUTCHAR: u64 = 1 << 0;
+/// `EMUCTL_TRACE` is available.
+pub const EMUCTL_FEATURE_TRACE: u64 = 1 << 1;
+/// `EMUCTL_EXIT` is available.
+pub const EMUCTL_FEATURE_EXIT: u64 = 1 << 2;
+/// `EMUCTL_RESET` is available.
+pub const EMUCTL_FEATURE_RESET: u64 = 1 << 3;
+
+/// The emulator control device.
+pub struct Emuctl {
+    /// Characters written to `EMUCTL_PUTCHAR` which haven't been printed yet.
+    line: Vec<u8>,
+    /// True if the instruction trace is enabled.
+    trace: bool,
+    /// The exit code requested by a guest.
+    exit_code: Option<u64>,
+    /// True if a guest requested a machine-wide reset.
+    reset_requested: bool,
+}
+
+impl Device for Emuctl {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::QuadWord => Err(Exception::LoadAccessFault(addr)),
+            _ => Ok(self.load64(addr) & width.mask()),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::QuadWord => Err(Exception::StoreAMOAccessFault(addr)),
+            _ => {
+                self.store64(addr, value & width.mask());
+                Ok(())
+            }
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
+}
+
+impl Emuctl {
+    /// Create a new `Emuctl` object.
+    pub fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            trace: false,
+            exit_code: None,
+            reset_requested: false,
+        }
+    }
+
+    /// Save the state for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_bytes(out, &self.line)?;
+        write_u64(out, self.trace as u64)?;
+        write_bytes(
+            out,
+            &self.exit_code.map(u64::to_le_bytes).unwrap_or_default(),
+        )?;
+        write_u64(out, self.reset_requested as u64)
+    }
+
+    /// Restore the state from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.line = read_bytes(input)?;
+        self.trace = read_u64(input)? != 0;
+        let code = read_bytes(input)?;
+        self.exit_code = match code.len() {
+            8 => Some(code.iter().rev().fold(0, |acc, b| (acc << 8) | *b as u64)),
+            _ => None,
+        };
+        self.reset_requested = read_u64(input)? != 0;
+        Ok(())
+    }
+
+    /// Return true if a guest enabled the instruction trace.
+    pub fn is_tracing(&self) -> bool {
+        self.trace
+    }
+
+    /// Return an exit code if a guest requested to stop the emulator.
+    pub fn exit_code(&self) -> Option<u64> {
+        self.exit_code
+    }
+
+    /// Return true if a guest requested a machine-wide reset. The request is cleared by the
+    /// reset.
+    pub fn is_reset_requested(&self) -> bool {
+        self.reset_requested
+    }
+
+    fn load64(&self, addr: u64) -> u64 {
+        match addr {
+            EMUCTL_VERSION => implementation_id(),
+            EMUCTL_FEATURES => {
+                EMUCTL_FEATURE_PUTCHAR
+                    | EMUCTL_FEATURE_TRACE
+                    | EMUCTL_FEATURE_EXIT
+                    | EMUCTL_FEATURE_RESET
+            }
+            EMUCTL_TRACE => self.trace as u64,
+            _ => 0,
+        }
+    }
+
+    fn store64(&mut self, addr: u64, value: u64) {
+        match addr {
+            EMUCTL_PUTCHAR => self.putchar(value as u8),
+            EMUCTL_TRACE => self.trace = value & 1 == 1,
+            EMUCTL_EXIT => self.exit_code = Some(value),
+            EMUCTL_RESET => self.reset_requested = true,
+            _ => {}
+        }
+    }
+
+    fn putchar(&mut self, c: u8) {
+        if c != b'\n' {
+            self.line.push(c);
+            return;
+        }
+        eprintln!("[guest] {}", String::from_utf8_lossy(&self.line));
+        io::stderr().flush().expect("failed to flush stderr");
+        self.line.clear();
+    }
+}
@@ -0,0 +1,264 @@
+//! The emulator module contains `Emulator`, which drives a CPU one instruction at a time for
+//! host-side analysis. `Emulator::iter` yields every executed instruction, so analyses can be
+//! written as iterator pipelines:
+//!
+//! ```ignore
+//! let mut emu = Emulator::new(Cpu::new(kernel, disk_image));
+//! let stores = emu.iter().take(1_000_000).filter(|i| i.raw & 0x7f == 0x23).count();
+//! ```
+//!
+//! Pre-execute hooks run before each instruction. They can modify the CPU state and alter the
+//! control flow, e.g. to inject faults or interpose system calls:
+//!
+//! ```ignore
+//! emu.add_pre_execute_hook(|cpu, _pc, inst| match inst {
+//!     // Make every ecall return -1 without entering the kernel.
+//!     0x00000073 => {
+//!         cpu.write_reg(10, u64::MAX);
+//!         HookAction::Skip
+//!     }
+//!     _ => HookAction::Continue,
+//! });
+//! ```
+//!
+//! `Emulator::step_with` calls an `Observer` at each phase of a step, for tools which look at the
+//! CPU around each instruction such as tracers. The main loop, the debuggers and the checkers all
+//! step through it, so they agree on how traps, exit and reset requests are handled.
+
+use crate::cpu::*;
+use crate::disasm;
+use crate::trap::*;
+
+/// A register written by an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Writeback {
+    pub rd: usize,
+    pub value: u64,
+}
+
+/// An instruction which has been executed.
+#[derive(Debug)]
+pub struct ExecutedInstruction {
+    /// The address of the instruction.
+    pub pc: u64,
+    /// The raw encoding, or the one injected by a hook. It's 0 if the fetch failed.
+    pub raw: u64,
+    /// The destination register and its new value, or None if the instruction doesn't write a
+    /// register, raised an exception or was skipped by a hook.
+    pub writeback: Option<Writeback>,
+    /// The exception raised by the instruction, which has been taken.
+    pub exception: Option<Exception>,
+    /// The interrupt taken after the instruction.
+    pub interrupt: Option<Interrupt>,
+}
+
+impl ExecutedInstruction {
+    /// Return the disassembly.
+    pub fn decoded(&self) -> String {
+        disasm::disassemble(self.pc, self.raw)
+    }
+}
+
+/// What to do with an instruction after a pre-execute hook.
+#[derive(Debug)]
+pub enum HookAction {
+    /// Execute the instruction, or pass it to the next hook.
+    Continue,
+    /// Don't execute the instruction and continue at the next one, or at the program counter the
+    /// hook has set.
+    Skip,
+    /// Execute another instruction instead. The next hooks see the new one.
+    Replace(u64),
+    /// Raise an exception instead of executing the instruction.
+    Raise(Exception),
+}
+
+/// A hook called with the CPU, the program counter and the instruction before execution.
+pub type PreExecuteHook = Box<dyn FnMut(&mut Cpu, u64, u64) -> HookAction>;
+
+/// Callbacks from `Emulator::step_with`. All of them do nothing by default.
+pub trait Observer {
+    /// Called before the instruction at `pc` is executed. `inst` is None if the fetch failed.
+    fn before_execute(&mut self, _cpu: &mut Cpu, _pc: u64, _inst: Option<u64>) {}
+
+    /// Called after `inst` at `pc` is executed without an exception.
+    fn executed(&mut self, _cpu: &mut Cpu, _pc: u64, _inst: u64) {}
+
+    /// Called when the instruction at `pc` raised `exception`, before the trap is taken. Return
+    /// true to consume the exception, e.g. to enter debug mode, and end the step without taking
+    /// the trap or an interrupt.
+    fn exception_raised(
+        &mut self,
+        _cpu: &mut Cpu,
+        _pc: u64,
+        _inst: Option<u64>,
+        _exception: &Exception,
+    ) -> bool {
+        false
+    }
+
+    /// Called after the trap of `exception` is taken.
+    fn exception_taken(&mut self, _cpu: &mut Cpu, _exception: &Exception) {}
+
+    /// Called after the trap of `interrupt` is taken.
+    fn interrupt_taken(&mut self, _cpu: &mut Cpu, _interrupt: &Interrupt) {}
+}
+
+impl Observer for () {}
+
+/// An emulator driving a CPU.
+pub struct Emulator {
+    pub cpu: Cpu,
+    /// True after a fatal exception or an exit request via the emulator control device.
+    stopped: bool,
+    pre_execute_hooks: Vec<PreExecuteHook>,
+}
+
+impl Emulator {
+    /// Create a new `Emulator` object running `cpu`.
+    pub fn new(cpu: Cpu) -> Self {
+        Self {
+            cpu,
+            stopped: false,
+            pre_execute_hooks: Vec::new(),
+        }
+    }
+
+    /// Reset the CPU to run `binary` and resume stepping. The hooks are kept.
+    pub fn reset_with(&mut self, binary: &[u8]) {
+        self.cpu.reset_with(binary);
+        self.stopped = false;
+    }
+
+    /// Add a hook called before each instruction. Hooks are called in the order they are added
+    /// until one returns other than `HookAction::Continue`.
+    pub fn add_pre_execute_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(&mut Cpu, u64, u64) -> HookAction + 'static,
+    {
+        self.pre_execute_hooks.push(Box::new(hook));
+    }
+
+    /// Run the pre-execute hooks and execute the instruction at `pc`. Return the instruction
+    /// executed and its result, or None if it's skipped.
+    fn execute(&mut self, pc: u64, mut inst: u64) -> (u64, Option<Result<(), Exception>>) {
+        for hook in self.pre_execute_hooks.iter_mut() {
+            match hook(&mut self.cpu, pc, inst) {
+                HookAction::Continue => {}
+                HookAction::Replace(new_inst) => inst = new_inst,
+                HookAction::Skip => {
+                    if self.cpu.pc == pc {
+                        self.cpu.pc = pc.wrapping_add(4);
+                    }
+                    return (inst, None);
+                }
+                HookAction::Raise(exception) => {
+                    self.cpu.pc = pc.wrapping_add(4);
+                    return (inst, Some(Err(exception)));
+                }
+            }
+        }
+        self.cpu.pc = pc.wrapping_add(4);
+        // Forget a register written by the hooks.
+        self.cpu.take_last_write();
+        (inst, Some(self.cpu.execute(inst)))
+    }
+
+    /// Return true if the emulator has stopped. No more instructions are executed.
+    pub fn is_stopped(&self) -> bool {
+        self.stopped
+    }
+
+    /// Execute one instruction and take a pending interrupt after it. Return None if the
+    /// emulator has stopped.
+    pub fn step(&mut self) -> Option<ExecutedInstruction> {
+        self.step_with(&mut ())
+    }
+
+    /// Execute one instruction like `step`, calling `observer` at each phase of it.
+    pub fn step_with(&mut self, observer: &mut dyn Observer) -> Option<ExecutedInstruction> {
+        if self.stopped {
+            return None;
+        }
+        let pc = self.cpu.pc;
+        let fetched = self.cpu.fetch();
+        let is_fetched = fetched.is_ok();
+        observer.before_execute(&mut self.cpu, pc, fetched.as_ref().ok().copied());
+        // If fetch() fails, the exception is taken without running the hooks.
+        let (raw, result) = match fetched {
+            Ok(inst) => self.execute(pc, inst),
+            Err(exception) => {
+                self.cpu.pc = pc.wrapping_add(4);
+                (0, Some(Err(exception)))
+            }
+        };
+
+        let cpu = &mut self.cpu;
+        let (writeback, exception) = match result {
+            None => (None, None),
+            Some(Ok(_)) => {
+                observer.executed(cpu, pc, raw);
+                let writeback = cpu
+                    .take_last_write()
+                    .map(|(rd, value)| Writeback { rd, value });
+                (writeback, None)
+            }
+            Some(Err(exception)) => {
+                let inst = if is_fetched { Some(raw) } else { None };
+                if observer.exception_raised(cpu, pc, inst, &exception) {
+                    return Some(ExecutedInstruction {
+                        pc,
+                        raw,
+                        writeback: None,
+                        exception: None,
+                        interrupt: None,
+                    });
+                }
+                exception.take_trap(cpu);
+                observer.exception_taken(cpu, &exception);
+                if exception.is_fatal(cpu) {
+                    self.stopped = true;
+                }
+                (None, Some(exception))
+            }
+        };
+
+        let interrupt = cpu.check_pending_interrupt();
+        if let Some(interrupt) = &interrupt {
+            interrupt.take_trap(cpu);
+            observer.interrupt_taken(cpu, interrupt);
+        }
+        if cpu.bus.emuctl.exit_code().is_some() {
+            self.stopped = true;
+        }
+        if cpu.bus.emuctl.is_reset_requested() {
+            cpu.reset();
+        }
+
+        Some(ExecutedInstruction {
+            pc,
+            raw,
+            writeback,
+            exception,
+            interrupt,
+        })
+    }
+
+    /// Return an iterator executing an instruction per item until the emulator stops.
+    pub fn iter(&mut self) -> Iter<'_> {
+        Iter { emulator: self }
+    }
+}
+
+/// An iterator over executed instructions, created by `Emulator::iter`.
+pub struct Iter<'a> {
+    emulator: &'a mut Emulator,
+}
+
+impl Iterator for Iter<'_> {
+    type Item = ExecutedInstruction;
+
+    fn next(&mut self) -> Option<ExecutedInstruction> {
+        self.emulator.step()
+    }
+}
@@ -0,0 +1,68 @@
+//! The entropy module contains the source of nondeterminism which devices use. Without a seed, it
+//! is seeded from the host and device threads deliver input whenever it arrives. With a seed, e.g.
+//! `--seed 42`, device threads are replaced by delivery at instruction boundaries drawn from the
+//! generator, so two runs with the same seed and the same input are bit-identical. Connections
+//! to host sockets, e.g. vsock, QMP and JTAG, are inputs themselves and aren't covered.
+//!
+//! The generator is SplitMix64.
+//! See: https://prng.di.unimi.it/splitmix64.c
+
+use std::process;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A pseudo-random number generator.
+#[derive(Debug, Clone)]
+pub struct Entropy {
+    state: u64,
+    /// True if the generator was seeded by a user.
+    deterministic: bool,
+}
+
+impl Entropy {
+    /// Create a new `Entropy` object seeded by `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self {
+            state: seed,
+            deterministic: true,
+        }
+    }
+
+    /// Create a new `Entropy` object seeded from the host clock and the process id.
+    pub fn from_host() -> Self {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0);
+        Self {
+            state: nanos ^ ((process::id() as u64) << 32),
+            deterministic: false,
+        }
+    }
+
+    /// Return true if the generator was seeded by a user.
+    pub fn is_deterministic(&self) -> bool {
+        self.deterministic
+    }
+
+    /// Return the next random number.
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Return a random number less than `n`, which must not be 0.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n
+    }
+
+    /// Fill `buf` with random bytes.
+    pub fn fill_bytes(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,392 @@
+//! The explain module contains the tutorial mode enabled by `--explain`. For each executed
+//! instruction, it prints the decoded fields, what the instruction means and the registers it
+//! reads and writes before and after the execution:
+//!
+//! ```text
+//! 0x80000000: addi a0,zero,42
+//!     fields:  opcode=0x13 (OP-IMM, I-type) funct3=0x0 rd=a0 (x10) rs1=zero (x0) imm=42
+//!     meaning: a0 = zero + 42
+//!     before:  zero=0x0 a0=0x0
+//!     after:   a0=0x2a
+//! ```
+
+use crate::cpu::*;
+use crate::disasm::*;
+use crate::trap::*;
+
+/// The encoding formats of the base instructions.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Format {
+    R,
+    I,
+    S,
+    B,
+    U,
+    J,
+}
+
+/// Return the name and the format of the major opcode.
+fn opcode_info(opcode: u64) -> Option<(&'static str, Format)> {
+    let info = match opcode {
+        0x03 => ("LOAD", Format::I),
+        0x0f => ("MISC-MEM", Format::I),
+        0x13 => ("OP-IMM", Format::I),
+        0x17 => ("AUIPC", Format::U),
+        0x1b => ("OP-IMM-32", Format::I),
+        0x23 => ("STORE", Format::S),
+        0x2f => ("AMO", Format::R),
+        0x33 => ("OP", Format::R),
+        0x37 => ("LUI", Format::U),
+        0x3b => ("OP-32", Format::R),
+        0x63 => ("BRANCH", Format::B),
+        0x67 => ("JALR", Format::I),
+        0x6f => ("JAL", Format::J),
+        0x73 => ("SYSTEM", Format::I),
+        _ => return None,
+    };
+    Some(info)
+}
+
+/// Return the sign-extended immediate of `inst` in `format`.
+fn immediate(format: Format, inst: u64) -> i64 {
+    match format {
+        Format::R => 0,
+        Format::I => (inst as i32 as i64) >> 20,
+        Format::S => (((inst & 0xfe000000) as i32 as i64) >> 20) | ((inst >> 7) & 0x1f) as i64,
+        Format::B => {
+            (((inst & 0x80000000) as i32 as i64) >> 19)
+                | ((inst & 0x80) << 4) as i64
+                | ((inst >> 20) & 0x7e0) as i64
+                | ((inst >> 7) & 0x1e) as i64
+        }
+        Format::U => (inst & 0xfffff000) as i32 as i64,
+        Format::J => {
+            (((inst & 0x80000000) as i32 as i64) >> 11)
+                | (inst & 0xff000) as i64
+                | ((inst >> 9) & 0x800) as i64
+                | ((inst >> 20) & 0x7fe) as i64
+        }
+    }
+}
+
+/// Return true if `inst` is a CSR instruction.
+fn is_csr(inst: u64) -> bool {
+    inst & 0x7f == 0x73 && (inst >> 12) & 0x7 != 0
+}
+
+/// Return a register written as its ABI name and its number.
+fn reg(index: u64) -> String {
+    format!("{} (x{})", REG_NAMES[index as usize], index)
+}
+
+/// Return the decoded fields of `inst`.
+pub fn fields(inst: u64) -> String {
+    let opcode = inst & 0x7f;
+    let (name, format) = match opcode_info(opcode) {
+        Some(info) => info,
+        None => return format!("opcode={:#04x} (unknown)", opcode),
+    };
+    let rd = (inst >> 7) & 0x1f;
+    let rs1 = (inst >> 15) & 0x1f;
+    let rs2 = (inst >> 20) & 0x1f;
+    let funct3 = (inst >> 12) & 0x7;
+    let funct7 = (inst >> 25) & 0x7f;
+    let imm = immediate(format, inst);
+
+    let mut s = format!("opcode={:#04x} ({}, {:?}-type)", opcode, name, format);
+    match format {
+        Format::R => {
+            s += &format!(
+                " funct3={:#x} funct7={:#04x} rd={} rs1={} rs2={}",
+                funct3,
+                funct7,
+                reg(rd),
+                reg(rs1),
+                reg(rs2)
+            )
+        }
+        Format::I if is_csr(inst) => {
+            let source = match funct3 {
+                0x5..=0x7 => format!("uimm={}", rs1),
+                _ => format!("rs1={}", reg(rs1)),
+            };
+            s += &format!(
+                " funct3={:#x} rd={} {} csr={}",
+                funct3,
+                reg(rd),
+                source,
+                csr_name((inst >> 20) & 0xfff)
+            );
+        }
+        Format::I => {
+            s += &format!(
+                " funct3={:#x} rd={} rs1={} imm={}",
+                funct3,
+                reg(rd),
+                reg(rs1),
+                imm
+            )
+        }
+        Format::S | Format::B => {
+            s += &format!(
+                " funct3={:#x} rs1={} rs2={} imm={}",
+                funct3,
+                reg(rs1),
+                reg(rs2),
+                imm
+            )
+        }
+        Format::U | Format::J => s += &format!(" rd={} imm={:#x}", reg(rd), imm),
+    }
+    s
+}
+
+/// Return the meaning of `inst` at `pc` with the operands filled in.
+pub fn describe(pc: u64, inst: u64) -> String {
+    let assembly = disassemble(pc, inst);
+    let mnemonic = assembly.split_whitespace().next().unwrap_or("");
+    // The ordering of atomic instructions doesn't change what they compute.
+    let mnemonic = mnemonic
+        .trim_end_matches(".aqrl")
+        .trim_end_matches(".aq")
+        .trim_end_matches(".rl");
+    // The suffix of atomic instructions is the width of the accessed data.
+    let base = match mnemonic {
+        "sfence.vma" | "fence.i" => mnemonic,
+        _ => mnemonic.split('.').next().unwrap_or(mnemonic),
+    };
+    let width = match (inst >> 12) & 0x3 {
+        0x0 => "byte",
+        0x1 => "halfword",
+        0x2 => "word",
+        _ => "doubleword",
+    };
+
+    let template = match base {
+        "nop" => "do nothing",
+        "mv" => "{rd} = {rs1}",
+        "add" => "{rd} = {rs1} + {rs2}",
+        "sub" => "{rd} = {rs1} - {rs2}",
+        "sll" => "{rd} = {rs1} << {rs2}",
+        "slt" => "{rd} = 1 if {rs1} < {rs2} as signed numbers, otherwise 0",
+        "sltu" => "{rd} = 1 if {rs1} < {rs2} as unsigned numbers, otherwise 0",
+        "xor" => "{rd} = {rs1} ^ {rs2}",
+        "srl" => "{rd} = {rs1} >> {rs2}, filling with zeros",
+        "sra" => "{rd} = {rs1} >> {rs2}, filling with the sign bit",
+        "or" => "{rd} = {rs1} | {rs2}",
+        "and" => "{rd} = {rs1} & {rs2}",
+        "addi" => "{rd} = {rs1} + {imm}",
+        "slti" => "{rd} = 1 if {rs1} < {imm} as signed numbers, otherwise 0",
+        "sltiu" => "{rd} = 1 if {rs1} < {imm} as unsigned numbers, otherwise 0",
+        "xori" => "{rd} = {rs1} ^ {imm}",
+        "ori" => "{rd} = {rs1} | {imm}",
+        "andi" => "{rd} = {rs1} & {imm}",
+        "slli" => "{rd} = {rs1} << {shamt}",
+        "srli" => "{rd} = {rs1} >> {shamt}, filling with zeros",
+        "srai" => "{rd} = {rs1} >> {shamt}, filling with the sign bit",
+        "addw" => "{rd} = sign-extended low 32 bits of {rs1} + {rs2}",
+        "subw" => "{rd} = sign-extended low 32 bits of {rs1} - {rs2}",
+        "sllw" => "{rd} = sign-extended low 32 bits of {rs1} << {rs2}",
+        "srlw" => "{rd} = sign-extended low 32 bits of {rs1} >> {rs2}, filling with zeros",
+        "sraw" => "{rd} = sign-extended low 32 bits of {rs1} >> {rs2}, filling with the sign bit",
+        "addiw" => "{rd} = sign-extended low 32 bits of {rs1} + {imm}",
+        "slliw" => "{rd} = sign-extended low 32 bits of {rs1} << {shamt}",
+        "srliw" => "{rd} = sign-extended low 32 bits of {rs1} >> {shamt}, filling with zeros",
+        "sraiw" => {
+            "{rd} = sign-extended low 32 bits of {rs1} >> {shamt}, filling with the sign bit"
+        }
+        "lui" => "{rd} = {upper}",
+        "auipc" => "{rd} = pc + {upper} = {target}",
+        "lb" | "lh" | "lw" | "ld" => "{rd} = sign-extended {width} at address {rs1} + {imm}",
+        "lbu" | "lhu" | "lwu" => "{rd} = zero-extended {width} at address {rs1} + {imm}",
+        "sb" | "sh" | "sw" | "sd" => "store the low {width} of {rs2} at address {rs1} + {imm}",
+        "beq" => "jump to {target} if {rs1} == {rs2}",
+        "bne" => "jump to {target} if {rs1} != {rs2}",
+        "blt" => "jump to {target} if {rs1} < {rs2} as signed numbers",
+        "bge" => "jump to {target} if {rs1} >= {rs2} as signed numbers",
+        "bltu" => "jump to {target} if {rs1} < {rs2} as unsigned numbers",
+        "bgeu" => "jump to {target} if {rs1} >= {rs2} as unsigned numbers",
+        "j" => "jump to {target}",
+        "jal" => "{rd} = pc + 4, then jump to {target}",
+        "ret" => "return to the address in ra",
+        "jalr" => "{rd} = pc + 4, then jump to ({rs1} + {imm}) with the lowest bit cleared",
+        "mul" => "{rd} = low 64 bits of {rs1} * {rs2}",
+        "mulh" => "{rd} = high 64 bits of {rs1} * {rs2} as signed numbers",
+        "mulhsu" => "{rd} = high 64 bits of signed {rs1} * unsigned {rs2}",
+        "mulhu" => "{rd} = high 64 bits of {rs1} * {rs2} as unsigned numbers",
+        "div" => "{rd} = {rs1} / {rs2} as signed numbers, rounded toward zero",
+        "divu" => "{rd} = {rs1} / {rs2} as unsigned numbers",
+        "rem" => "{rd} = {rs1} % {rs2} as signed numbers",
+        "remu" => "{rd} = {rs1} % {rs2} as unsigned numbers",
+        "mulw" => "{rd} = sign-extended low 32 bits of {rs1} * {rs2}",
+        "divw" => "{rd} = low 32 bits of {rs1} / {rs2} as signed numbers, sign-extended",
+        "divuw" => "{rd} = low 32 bits of {rs1} / {rs2} as unsigned numbers, sign-extended",
+        "remw" => "{rd} = low 32 bits of {rs1} % {rs2} as signed numbers, sign-extended",
+        "remuw" => "{rd} = low 32 bits of {rs1} % {rs2} as unsigned numbers, sign-extended",
+        "lr" => "{rd} = {width} at address {rs1}, and reserve the address",
+        "sc" => {
+            "store {rs2} as a {width} at address {rs1} if it's still reserved; {rd} = 0 if stored, \
+             otherwise 1"
+        }
+        "amoswap" => "atomically {rd} = {width} at address {rs1}, and store {rs2} there",
+        "amoadd" => "atomically {rd} = {width} at address {rs1}, and store {rd} + {rs2} there",
+        "amoxor" => "atomically {rd} = {width} at address {rs1}, and store {rd} ^ {rs2} there",
+        "amoor" => "atomically {rd} = {width} at address {rs1}, and store {rd} | {rs2} there",
+        "amoand" => "atomically {rd} = {width} at address {rs1}, and store {rd} & {rs2} there",
+        "amomin" | "amominu" => {
+            "atomically {rd} = {width} at address {rs1}, and store the minimum of {rd} and {rs2} \
+             there"
+        }
+        "amomax" | "amomaxu" => {
+            "atomically {rd} = {width} at address {rs1}, and store the maximum of {rd} and {rs2} \
+             there"
+        }
+        "csrrw" => "{rd} = {csr}, then {csr} = {rs1}",
+        "csrrs" => "{rd} = {csr}, then set the bits of {csr} which are set in {rs1}",
+        "csrrc" => "{rd} = {csr}, then clear the bits of {csr} which are set in {rs1}",
+        "csrrwi" => "{rd} = {csr}, then {csr} = {uimm}",
+        "csrrsi" => "{rd} = {csr}, then set the bits of {csr} which are set in {uimm}",
+        "csrrci" => "{rd} = {csr}, then clear the bits of {csr} which are set in {uimm}",
+        "ecall" => "raise an environment call exception to ask a more privileged mode for help",
+        "ebreak" => "raise a breakpoint exception",
+        "mret" => "return from an M-mode trap handler to mepc, restoring the mode from mstatus.MPP",
+        "sret" => "return from an S-mode trap handler to sepc, restoring the mode from sstatus.SPP",
+        "wfi" => "wait for an interrupt",
+        "sfence.vma" => "flush cached address translations",
+        "fence" => "order memory accesses before and after it",
+        "fence.i" => "synchronize the instruction fetches with stores before it",
+        "unknown" => "not an instruction the emulator knows",
+        _ => "an instruction of the scalar cryptography or bit-manipulation extensions",
+    };
+    let (_, format) = opcode_info(inst & 0x7f).unwrap_or(("", Format::R));
+    let imm = immediate(format, inst);
+    template
+        .replace("{rd}", REG_NAMES[((inst >> 7) & 0x1f) as usize])
+        .replace("{rs1}", REG_NAMES[((inst >> 15) & 0x1f) as usize])
+        .replace("{rs2}", REG_NAMES[((inst >> 20) & 0x1f) as usize])
+        .replace("{imm}", &imm.to_string())
+        .replace("{upper}", &format!("{:#x}", imm as u64))
+        .replace("{shamt}", &((inst >> 20) & 0x3f).to_string())
+        .replace("{uimm}", &((inst >> 15) & 0x1f).to_string())
+        .replace("{csr}", &csr_name((inst >> 20) & 0xfff))
+        .replace("{width}", width)
+        .replace("{target}", &format!("{:#x}", pc.wrapping_add(imm as u64)))
+}
+
+/// Return the registers which `inst` reads.
+pub(crate) fn sources(inst: u64) -> Vec<u64> {
+    let rs1 = (inst >> 15) & 0x1f;
+    let rs2 = (inst >> 20) & 0x1f;
+    match opcode_info(inst & 0x7f) {
+        // lr doesn't read rs2.
+        Some((_, Format::R)) if inst & 0x7f == 0x2f && (inst >> 27) == 0x02 => vec![rs1],
+        Some((_, Format::R)) | Some((_, Format::S)) | Some((_, Format::B)) => vec![rs1, rs2],
+        Some(("SYSTEM", _)) => match (inst >> 12) & 0x7 {
+            0x1..=0x3 => vec![rs1],
+            _ => Vec::new(),
+        },
+        Some(("MISC-MEM", _)) => Vec::new(),
+        Some((_, Format::I)) => vec![rs1],
+        _ => Vec::new(),
+    }
+}
+
+/// The explainer of executed instructions, which remembers the state before each instruction.
+pub struct Explainer {
+    regs: [u64; 32],
+    /// The value of the CSR which the instruction accesses, if any.
+    csr: Option<(u64, u64)>,
+}
+
+impl Default for Explainer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Explainer {
+    /// Create a new `Explainer` object.
+    pub fn new() -> Self {
+        Self {
+            regs: [0; 32],
+            csr: None,
+        }
+    }
+
+    /// Remember the registers before `inst` executes.
+    pub fn before(&mut self, cpu: &Cpu, inst: u64) {
+        self.regs = cpu.regs;
+        self.csr = match is_csr(inst) {
+            true => {
+                let csr = (inst >> 20) & 0xfff;
+                Some((csr, cpu.load_csr(csr as usize)))
+            }
+            false => None,
+        };
+    }
+
+    /// Return the explanation of `inst` at `pc`, which has just executed and raised `exception`
+    /// if it's not None. `inst` is None if fetching the instruction failed.
+    pub fn explain(
+        &self,
+        cpu: &Cpu,
+        pc: u64,
+        inst: Option<u64>,
+        exception: Option<&Exception>,
+    ) -> String {
+        let inst = match inst {
+            Some(inst) => inst,
+            None => {
+                let cause = exception.map_or(String::new(), |e| format!("{:?}", e));
+                return format!("{:#x}: fetching the instruction raised {}", pc, cause);
+            }
+        };
+
+        let mut before = Vec::new();
+        let mut after = Vec::new();
+        let mut read = sources(inst);
+        read.dedup();
+        let rd = (inst >> 7) & 0x1f;
+        // Show the old value of rd too if it's overwritten.
+        if cpu.regs[rd as usize] != self.regs[rd as usize] && !read.contains(&rd) {
+            read.push(rd);
+        }
+        for r in read {
+            before.push(format!(
+                "{}={:#x}",
+                REG_NAMES[r as usize], self.regs[r as usize]
+            ));
+        }
+        for (i, (old, new)) in self.regs.iter().zip(cpu.regs.iter()).enumerate() {
+            if old != new {
+                after.push(format!("{}={:#x}", REG_NAMES[i], new));
+            }
+        }
+        if let Some((csr, old)) = self.csr {
+            before.push(format!("{}={:#x}", csr_name(csr), old));
+            let new = cpu.load_csr(csr as usize);
+            if new != old {
+                after.push(format!("{}={:#x}", csr_name(csr), new));
+            }
+        }
+        match exception {
+            Some(exception) => after.push(format!("raised {:?}", exception)),
+            None if cpu.pc != pc.wrapping_add(4) => after.push(format!("pc={:#x}", cpu.pc)),
+            None => {}
+        }
+
+        let join = |values: Vec<String>| match values.is_empty() {
+            true => "(nothing)".to_string(),
+            false => values.join(" "),
+        };
+        format!(
+            "{:#x}: {}\n    fields:  {}\n    meaning: {}\n    before:  {}\n    after:   {}",
+            pc,
+            disassemble(pc, inst),
+            fields(inst),
+            describe(pc, inst),
+            join(before),
+            join(after)
+        )
+    }
+}
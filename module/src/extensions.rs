@@ -0,0 +1,300 @@
+//! The extensions module contains the set of ISA extensions which the CPU implements. The misa CSR
+//! is built from it, so kernels probing misa at boot see what the decoder executes. The set can be
+//! narrowed by an ISA string such as `rv64ima_zicsr_zifencei`, and an instruction of a disabled
+//! extension raises an illegal instruction exception, which shows how a guest runs on a smaller
+//! core.
+//!
+//! The misa spec:
+//! https://github.com/riscv/riscv-isa-manual/blob/main/src/machine.adoc#machine-isa-misa-register
+//! The naming of ISA strings:
+//! https://github.com/riscv/riscv-isa-manual/blob/main/src/naming.adoc
+
+use std::fmt;
+
+/// MXL in misa[63:62] for XLEN 64.
+const MISA_MXL_64: u64 = 2 << 62;
+
+/// Return the bit of a single-letter extension in misa.
+const fn misa_bit(letter: u8) -> u64 {
+    1 << (letter - b'A')
+}
+
+/// The ISA extensions which the CPU implements.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Extensions {
+    /// Integer multiplication and division.
+    pub m: bool,
+    /// Atomic instructions.
+    pub a: bool,
+    /// Single-precision floating point. Only its CSRs, fflags, frm and fcsr, are implemented, so
+    /// it's enabled only by an ISA string naming it.
+    pub f: bool,
+    /// Supervisor mode.
+    pub s: bool,
+    /// User mode.
+    pub u: bool,
+    /// CSR instructions.
+    pub zicsr: bool,
+    /// fence.i.
+    pub zifencei: bool,
+    /// Address generation.
+    pub zba: bool,
+    /// Basic bit manipulation.
+    pub zbb: bool,
+    /// Single-bit instructions.
+    pub zbs: bool,
+    /// Bit manipulation for cryptography.
+    pub zbkb: bool,
+    /// Carry-less multiplication for cryptography.
+    pub zbkc: bool,
+    /// Crossbar permutations.
+    pub zbkx: bool,
+    /// AES encryption.
+    pub zkne: bool,
+    /// AES decryption.
+    pub zknd: bool,
+    /// SHA-2 hashes.
+    pub zknh: bool,
+    /// SM4 block cipher.
+    pub zksed: bool,
+    /// SM3 hash.
+    pub zksh: bool,
+}
+
+impl Default for Extensions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Extensions {
+    /// Create a new `Extensions` object with all extensions which the emulator implements.
+    pub fn new() -> Self {
+        Self {
+            m: true,
+            a: true,
+            f: false,
+            s: true,
+            u: true,
+            zicsr: true,
+            zifencei: true,
+            zba: true,
+            zbb: true,
+            zbs: true,
+            zbkb: true,
+            zbkc: true,
+            zbkx: true,
+            zkne: true,
+            zknd: true,
+            zknh: true,
+            zksed: true,
+            zksh: true,
+        }
+    }
+
+    /// Parse an ISA string such as `rv64imab_zicsr_zifencei`, case-insensitively. The base must
+    /// be RV64I, and S-mode and U-mode, which ISA strings don't name, are kept. `b`, `zkn` and `zks`
+    /// stand for the extensions they consist of. An extension which the emulator doesn't implement
+    /// is an error.
+    pub fn parse(isa: &str) -> Result<Self, String> {
+        let isa = isa.to_ascii_lowercase();
+        let rest = match isa.strip_prefix("rv64") {
+            Some(rest) => rest,
+            None => return Err(format!("the ISA string {} isn't for RV64", isa)),
+        };
+        let mut parts = rest.split('_');
+        let letters = parts.next().unwrap_or("");
+        let mut letters = letters.chars();
+        if letters.next() != Some('i') {
+            return Err(format!("the base of {} isn't I", isa));
+        }
+
+        let mut extensions = Self::none();
+        for letter in letters {
+            match letter {
+                'm' => extensions.m = true,
+                'a' => extensions.a = true,
+                'f' => extensions.f = true,
+                'b' => extensions.enable("b")?,
+                _ => return Err(format!("the extension {} isn't implemented", letter)),
+            }
+        }
+        for name in parts {
+            extensions.enable(name)?;
+        }
+        Ok(extensions)
+    }
+
+    /// Return the extensions of RV64I with S-mode and U-mode.
+    fn none() -> Self {
+        Self {
+            m: false,
+            a: false,
+            f: false,
+            s: true,
+            u: true,
+            zicsr: false,
+            zifencei: false,
+            zba: false,
+            zbb: false,
+            zbs: false,
+            zbkb: false,
+            zbkc: false,
+            zbkx: false,
+            zkne: false,
+            zknd: false,
+            zknh: false,
+            zksed: false,
+            zksh: false,
+        }
+    }
+
+    /// Enable a multi-letter extension or a shorthand for a group of extensions.
+    fn enable(&mut self, name: &str) -> Result<(), String> {
+        match name {
+            "zicsr" => self.zicsr = true,
+            "zifencei" => self.zifencei = true,
+            "zba" => self.zba = true,
+            "zbb" => self.zbb = true,
+            "zbs" => self.zbs = true,
+            "zbkb" => self.zbkb = true,
+            "zbkc" => self.zbkc = true,
+            "zbkx" => self.zbkx = true,
+            "zkne" => self.zkne = true,
+            "zknd" => self.zknd = true,
+            "zknh" => self.zknh = true,
+            "zksed" => self.zksed = true,
+            "zksh" => self.zksh = true,
+            "b" => ["zba", "zbb", "zbs"]
+                .iter()
+                .try_for_each(|name| self.enable(name))?,
+            "zkn" => ["zbkb", "zbkc", "zbkx", "zkne", "zknd", "zknh"]
+                .iter()
+                .try_for_each(|name| self.enable(name))?,
+            "zks" => ["zbkb", "zbkc", "zbkx", "zksed", "zksh"]
+                .iter()
+                .try_for_each(|name| self.enable(name))?,
+            _ => return Err(format!("the extension {} isn't implemented", name)),
+        }
+        Ok(())
+    }
+
+    /// Return the value of misa. The base ISA I is always implemented, and B is reported if Zba,
+    /// Zbb and Zbs are all enabled.
+    pub fn misa(&self) -> u64 {
+        let letters = [
+            (true, b'I'),
+            (self.m, b'M'),
+            (self.a, b'A'),
+            (self.f, b'F'),
+            (self.zba && self.zbb && self.zbs, b'B'),
+            (self.s, b'S'),
+            (self.u, b'U'),
+        ];
+        letters
+            .iter()
+            .filter(|(enabled, _)| *enabled)
+            .fold(MISA_MXL_64, |misa, (_, letter)| misa | misa_bit(*letter))
+    }
+
+    /// Return true if the extension of `inst` is enabled. Instructions of RV64I, privileged
+    /// instructions and instructions which don't exist at all are left to the decoder.
+    pub fn allows(&self, inst: u64) -> bool {
+        let opcode = inst & 0x7f;
+        let rs2 = (inst >> 20) & 0x1f;
+        let funct3 = (inst >> 12) & 0x7;
+        let funct7 = (inst >> 25) & 0x7f;
+        // The upper bits of a shift amount or funct6 of shift-immediate instructions.
+        let funct6 = (inst >> 26) & 0x3f;
+        // The immediate of instructions whose rs2 field is a part of the opcode.
+        let imm = (inst >> 20) & 0xfff;
+        match opcode {
+            0x0f if funct3 == 0x1 => self.zifencei,
+            0x2f => self.a,
+            0x73 if funct3 != 0x0 => self.zicsr,
+            0x13 if funct3 == 0x1 => match (imm, funct6) {
+                (_, 0x00) => true,
+                (0x100..=0x107, _) => self.zknh,
+                (0x108 | 0x109, _) => self.zksh,
+                (0x300, _) => self.zknd,
+                (0x310..=0x31f, _) => self.zkne || self.zknd,
+                (0x600..=0x605, _) => self.zbb,
+                (_, 0x0a | 0x12 | 0x1a) => self.zbs,
+                _ => true,
+            },
+            0x13 if funct3 == 0x5 => match (imm, funct6) {
+                (_, 0x00 | 0x10) => true,
+                (0x287, _) => self.zbb,
+                (0x687, _) => self.zbkb,
+                (0x6b8, _) | (_, 0x18) => self.zbb || self.zbkb,
+                (_, 0x12) => self.zbs,
+                _ => true,
+            },
+            0x1b => match (funct3, funct7) {
+                (0x1, 0x04 | 0x05) => self.zba,
+                (0x1, 0x30) => self.zbb,
+                (0x5, 0x30) => self.zbb || self.zbkb,
+                _ => true,
+            },
+            0x33 => match (funct3, funct7) {
+                (_, 0x01) => self.m,
+                (0x0, 0x19 | 0x1b | 0x3f) => self.zkne,
+                (0x0, 0x1d | 0x1f) => self.zknd,
+                (0x0, _) if funct7 & 0x1f == 0x18 || funct7 & 0x1f == 0x1a => self.zksed,
+                (0x1 | 0x3, 0x05) => self.zbkc,
+                (0x2 | 0x4, 0x14) => self.zbkx,
+                (0x1 | 0x5, 0x30) | (0x4 | 0x6 | 0x7, 0x20) => self.zbb || self.zbkb,
+                (0x4 | 0x7, 0x04) => self.zbkb,
+                (0x2 | 0x4 | 0x6, 0x10) => self.zba,
+                (0x4..=0x7, 0x05) => self.zbb,
+                (0x1, 0x14 | 0x24 | 0x34) | (0x5, 0x24) => self.zbs,
+                _ => true,
+            },
+            0x3b => match (funct3, funct7) {
+                (_, 0x01) => self.m,
+                (0x1 | 0x5, 0x30) => self.zbb || self.zbkb,
+                (0x0, 0x04) | (0x2 | 0x4 | 0x6, 0x10) => self.zba,
+                // zext.h is packw with rs2 = x0.
+                (0x4, 0x04) if rs2 == 0 => self.zbb || self.zbkb,
+                (0x4, 0x04) => self.zbkb,
+                _ => true,
+            },
+            _ => true,
+        }
+    }
+}
+
+impl fmt::Display for Extensions {
+    /// Write the ISA string in the canonical order.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "rv64i")?;
+        if self.m {
+            write!(f, "m")?;
+        }
+        if self.a {
+            write!(f, "a")?;
+        }
+        if self.f {
+            write!(f, "f")?;
+        }
+        let names = [
+            (self.zicsr, "zicsr"),
+            (self.zifencei, "zifencei"),
+            (self.zba, "zba"),
+            (self.zbb, "zbb"),
+            (self.zbs, "zbs"),
+            (self.zbkb, "zbkb"),
+            (self.zbkc, "zbkc"),
+            (self.zbkx, "zbkx"),
+            (self.zkne, "zkne"),
+            (self.zknd, "zknd"),
+            (self.zknh, "zknh"),
+            (self.zksed, "zksed"),
+            (self.zksh, "zksh"),
+        ];
+        for (_, name) in names.iter().filter(|(enabled, _)| *enabled) {
+            write!(f, "_{}", name)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,232 @@
+//! The filestore module contains a device which exports guest memory to files on the host, so a
+//! bare-metal test can hand its results or generated data to the host without a block device or
+//! a console protocol. It only exists in this emulator and is mapped at `FILESTORE_BASE` if a host
+//! directory is given by `--filestore <dir>`:
+//!
+//! ```text
+//!     li t0, 0x1002000  # FILESTORE_BASE
+//!     la t1, result
+//!     sd t1, 0x00(t0)   # FILESTORE_ADDR
+//!     li t1, 4096
+//!     sd t1, 0x08(t0)   # FILESTORE_LEN
+//!     la t1, name       # "result.bin"
+//!     sd t1, 0x10(t0)   # FILESTORE_NAME
+//!     li t1, 1
+//!     sd t1, 0x18(t0)   # FILESTORE_CMD, write
+//!     ld t1, 0x20(t0)   # FILESTORE_STATUS, 0 if it succeeded
+//! ```
+//!
+//! A command completes before the store to `FILESTORE_CMD` does. A file name is a NUL-terminated
+//! string in the dram, and it must name a file directly in the host directory.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::path::PathBuf;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The physical address of the memory to export, read and write.
+pub const FILESTORE_ADDR: u64 = FILESTORE_BASE;
+/// The number of bytes to export, read and write.
+pub const FILESTORE_LEN: u64 = FILESTORE_BASE + 0x08;
+/// The physical address of the NUL-terminated file name, read and write.
+pub const FILESTORE_NAME: u64 = FILESTORE_BASE + 0x10;
+/// Command, write-only. See `FILESTORE_CMD_*`.
+pub const FILESTORE_CMD: u64 = FILESTORE_BASE + 0x18;
+/// The result of the last command, read-only. See `FILESTORE_STATUS_*`.
+pub const FILESTORE_STATUS: u64 = FILESTORE_BASE + 0x20;
+
+/// Write the memory to the file, replacing its contents.
+pub const FILESTORE_CMD_WRITE: u64 = 1;
+/// Append the memory to the file.
+pub const FILESTORE_CMD_APPEND: u64 = 2;
+
+/// The command succeeded.
+pub const FILESTORE_STATUS_OK: u64 = 0;
+/// The command is unknown.
+pub const FILESTORE_STATUS_BAD_COMMAND: u64 = 1;
+/// The file name isn't a name of a file in the host directory, or isn't terminated.
+pub const FILESTORE_STATUS_BAD_NAME: u64 = 2;
+/// The memory or the file name is out of the dram.
+pub const FILESTORE_STATUS_BAD_ADDRESS: u64 = 3;
+/// The host failed to write the file.
+pub const FILESTORE_STATUS_IO_ERROR: u64 = 4;
+
+/// The maximum length of a file name.
+const NAME_MAX: u64 = 255;
+
+/// The file export device.
+pub struct Filestore {
+    /// The host directory which files are written to.
+    dir: PathBuf,
+    addr: u64,
+    len: u64,
+    name: u64,
+    status: u64,
+}
+
+impl Filestore {
+    /// Create a new `Filestore` object writing files to `dir`, which must be a directory.
+    pub fn new(dir: &str) -> io::Result<Self> {
+        if !fs::metadata(dir)?.is_dir() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} isn't a directory", dir),
+            ));
+        }
+        Ok(Self {
+            dir: PathBuf::from(dir),
+            addr: 0,
+            len: 0,
+            name: 0,
+            status: FILESTORE_STATUS_OK,
+        })
+    }
+
+    pub fn load(&self, addr: u64, width: Width) -> Result<u64, Exception> {
+        if width != Width::DoubleWord {
+            return Err(Exception::LoadAccessFault(addr));
+        }
+        Ok(match addr {
+            FILESTORE_ADDR => self.addr,
+            FILESTORE_LEN => self.len,
+            FILESTORE_NAME => self.name,
+            FILESTORE_STATUS => self.status,
+            _ => 0,
+        })
+    }
+
+    /// Store to a register. A command reads the dram through `dma`.
+    pub fn store(
+        &mut self,
+        addr: u64,
+        width: Width,
+        value: u64,
+        dma: &mut Dma,
+    ) -> Result<(), Exception> {
+        if width != Width::DoubleWord {
+            return Err(Exception::StoreAMOAccessFault(addr));
+        }
+        match addr {
+            FILESTORE_ADDR => self.addr = value,
+            FILESTORE_LEN => self.len = value,
+            FILESTORE_NAME => self.name = value,
+            FILESTORE_CMD => {
+                self.status = match self.execute(value, dma) {
+                    Ok(()) => FILESTORE_STATUS_OK,
+                    Err(status) => status,
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Clear the registers for a machine-wide reset.
+    pub fn reset(&mut self) {
+        self.addr = 0;
+        self.len = 0;
+        self.name = 0;
+        self.status = FILESTORE_STATUS_OK;
+    }
+
+    /// Save the registers for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        for reg in [self.addr, self.len, self.name, self.status] {
+            write_u64(out, reg)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the registers from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.addr = read_u64(input)?;
+        self.len = read_u64(input)?;
+        self.name = read_u64(input)?;
+        self.status = read_u64(input)?;
+        Ok(())
+    }
+
+    pub fn register_name(&self, addr: u64, _store: bool) -> Option<&'static str> {
+        let name = match addr {
+            FILESTORE_ADDR => "ADDR",
+            FILESTORE_LEN => "LEN",
+            FILESTORE_NAME => "NAME",
+            FILESTORE_CMD => "CMD",
+            FILESTORE_STATUS => "STATUS",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    /// Execute a command and return the status if it fails.
+    fn execute(&mut self, command: u64, dma: &mut Dma) -> Result<(), u64> {
+        let append = match command {
+            FILESTORE_CMD_WRITE => false,
+            FILESTORE_CMD_APPEND => true,
+            _ => return Err(FILESTORE_STATUS_BAD_COMMAND),
+        };
+        let name = self.read_name(dma)?;
+        let data = (0..self.len)
+            .map(|i| {
+                let addr = self
+                    .addr
+                    .checked_add(i)
+                    .ok_or(FILESTORE_STATUS_BAD_ADDRESS)?;
+                dma.load(addr, 8)
+                    .map(|byte| byte as u8)
+                    .map_err(|_| FILESTORE_STATUS_BAD_ADDRESS)
+            })
+            .collect::<Result<Vec<u8>, u64>>()?;
+
+        let path = self.dir.join(&name);
+        let written = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(append)
+            .truncate(!append)
+            .open(&path)
+            .and_then(|mut file| file.write_all(&data));
+        match written {
+            Ok(()) => {
+                eprintln!(
+                    "filestore: wrote {} bytes to {}",
+                    data.len(),
+                    path.display()
+                );
+                Ok(())
+            }
+            Err(e) => {
+                eprintln!("filestore: failed to write {}: {}", path.display(), e);
+                Err(FILESTORE_STATUS_IO_ERROR)
+            }
+        }
+    }
+
+    /// Read the file name, which must name a file directly in the host directory.
+    fn read_name(&self, dma: &mut Dma) -> Result<String, u64> {
+        let mut name = Vec::new();
+        for i in 0..=NAME_MAX {
+            let addr = self
+                .name
+                .checked_add(i)
+                .ok_or(FILESTORE_STATUS_BAD_ADDRESS)?;
+            match dma.load(addr, 8) {
+                Ok(0) => break,
+                Ok(byte) => name.push(byte as u8),
+                Err(_) => return Err(FILESTORE_STATUS_BAD_ADDRESS),
+            }
+            if i == NAME_MAX {
+                return Err(FILESTORE_STATUS_BAD_NAME);
+            }
+        }
+        let name = String::from_utf8(name).map_err(|_| FILESTORE_STATUS_BAD_NAME)?;
+        if name.is_empty() || name == "." || name == ".." || name.contains(['/', '\\']) {
+            return Err(FILESTORE_STATUS_BAD_NAME);
+        }
+        Ok(name)
+    }
+}
@@ -0,0 +1,337 @@
+//! The goldfish_rtc module contains the Goldfish real-time clock, which the QEMU virt machine
+//! provides for the wall-clock time. It counts nanoseconds since the Unix epoch and raises an
+//! interrupt at an alarm time.
+//!
+//! The clock starts at the host time by default. `--rtc base=<time>` starts it at a fixed time and
+//! `--rtc offset=<seconds>` shifts the host time. If the entropy is seeded, the clock advances
+//! by a nominal time per instruction instead of the host clock, so timestamps are reproducible,
+//! and it starts at the Unix epoch unless a base is given. The `time` CSR and the CLINT count
+//! from boot and aren't affected.
+//! See: https://android.googlesource.com/platform/external/qemu/+/master/docs/GOLDFISH-VIRTUAL-HARDWARE.TXT
+
+use std::convert::TryFrom;
+use std::io;
+use std::io::prelude::*;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The interrupt request of the RTC, same as QEMU virt machine.
+pub const GOLDFISH_RTC_IRQ: u64 = 11;
+
+/// The low 32 bits of the time. Reading it latches the high 32 bits.
+pub const GOLDFISH_RTC_TIME_LOW: u64 = GOLDFISH_RTC_BASE;
+/// The high 32 bits of the time latched by the last read of the low bits.
+pub const GOLDFISH_RTC_TIME_HIGH: u64 = GOLDFISH_RTC_BASE + 0x04;
+/// The low 32 bits of the alarm time. Writing it arms the alarm.
+pub const GOLDFISH_RTC_ALARM_LOW: u64 = GOLDFISH_RTC_BASE + 0x08;
+/// The high 32 bits of the alarm time.
+pub const GOLDFISH_RTC_ALARM_HIGH: u64 = GOLDFISH_RTC_BASE + 0x0c;
+/// 1 if the alarm raises an interrupt.
+pub const GOLDFISH_RTC_IRQ_ENABLED: u64 = GOLDFISH_RTC_BASE + 0x10;
+/// Writing it disarms the alarm.
+pub const GOLDFISH_RTC_CLEAR_ALARM: u64 = GOLDFISH_RTC_BASE + 0x14;
+/// 1 if the alarm is armed.
+pub const GOLDFISH_RTC_ALARM_STATUS: u64 = GOLDFISH_RTC_BASE + 0x18;
+/// Writing it acknowledges the interrupt.
+pub const GOLDFISH_RTC_CLEAR_INTERRUPT: u64 = GOLDFISH_RTC_BASE + 0x1c;
+
+/// The time of an instruction when the clock advances by instructions (100 MIPS).
+const NANOS_PER_INSTRUCTION: u64 = 10;
+const NANOS_PER_SECOND: u64 = 1_000_000_000;
+
+/// Where the clock starts.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RtcSetting {
+    /// The host time.
+    Host,
+    /// A fixed time in nanoseconds since the Unix epoch.
+    Base(u64),
+    /// The host time shifted by nanoseconds.
+    Offset(i64),
+}
+
+impl RtcSetting {
+    /// Parse `base=<time>` or `offset=<seconds>`. A time is `YYYY-MM-DD[THH:MM:SS][Z]` in UTC or
+    /// seconds since the Unix epoch. An offset may end with `s`, `m`, `h` or `d`.
+    pub fn parse(s: &str) -> Result<Self, String> {
+        match s.split_once('=') {
+            Some(("base", time)) => parse_time(time)
+                .and_then(|secs| secs.checked_mul(NANOS_PER_SECOND))
+                .map(RtcSetting::Base)
+                .ok_or_else(|| format!("invalid RTC base '{}'", time)),
+            Some(("offset", offset)) => parse_offset(offset)
+                .and_then(|secs| secs.checked_mul(NANOS_PER_SECOND as i64))
+                .map(RtcSetting::Offset)
+                .ok_or_else(|| format!("invalid RTC offset '{}'", offset)),
+            _ => Err(format!("expected base=<time> or offset=<seconds>: '{}'", s)),
+        }
+    }
+}
+
+/// Return the number of days from the Unix epoch to a date in the proleptic Gregorian calendar.
+/// See: https://howardhinnant.github.io/date_algorithms.html#days_from_civil
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+/// Parse a time in seconds since the Unix epoch.
+fn parse_time(s: &str) -> Option<u64> {
+    if let Ok(secs) = s.parse::<u64>() {
+        return Some(secs);
+    }
+    let s = s.strip_suffix('Z').unwrap_or(s);
+    let (date, time) = s.split_once('T').unwrap_or((s, "00:00:00"));
+    let date: Vec<i64> = date
+        .split('-')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    let time: Vec<i64> = time
+        .split(':')
+        .map(|n| n.parse().ok())
+        .collect::<Option<_>>()?;
+    match (date.as_slice(), time.as_slice()) {
+        (&[year, month, day], &[hour, minute, second])
+            if (1..=12).contains(&month)
+                && (1..=31).contains(&day)
+                && (0..24).contains(&hour)
+                && (0..60).contains(&minute)
+                && (0..=60).contains(&second) =>
+        {
+            let days = days_from_civil(year, month, day);
+            let secs = days * 86_400 + hour * 3_600 + minute * 60 + second;
+            u64::try_from(secs).ok()
+        }
+        _ => None,
+    }
+}
+
+/// Parse a signed number of seconds with an optional unit.
+fn parse_offset(s: &str) -> Option<i64> {
+    let (number, unit) = match s.char_indices().last()? {
+        (i, 's') => (&s[..i], 1),
+        (i, 'm') => (&s[..i], 60),
+        (i, 'h') => (&s[..i], 3_600),
+        (i, 'd') => (&s[..i], 86_400),
+        _ => (s, 1),
+    };
+    number.parse::<i64>().ok()?.checked_mul(unit)
+}
+
+/// Return the host time in nanoseconds since the Unix epoch.
+fn host_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0)
+}
+
+/// The Goldfish real-time clock.
+pub struct GoldfishRtc {
+    setting: RtcSetting,
+    /// True if the clock advances by instructions instead of the host clock.
+    deterministic: bool,
+    /// The time in nanoseconds since the Unix epoch when the clock was set.
+    start: u64,
+    /// The host instant when the clock was set.
+    started: Instant,
+    /// The number of instructions since the clock was set.
+    instructions: u64,
+    time_high: u32,
+    alarm: u64,
+    alarm_armed: bool,
+    irq_enabled: bool,
+    interrupting: bool,
+}
+
+impl Device for GoldfishRtc {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::Word => Ok(self.load32(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::Word => {
+                self.store32(addr, value as u32);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    fn register_name(&self, addr: u64, _store: bool) -> Option<&'static str> {
+        let name = match addr {
+            GOLDFISH_RTC_TIME_LOW => "TIME_LOW",
+            GOLDFISH_RTC_TIME_HIGH => "TIME_HIGH",
+            GOLDFISH_RTC_ALARM_LOW => "ALARM_LOW",
+            GOLDFISH_RTC_ALARM_HIGH => "ALARM_HIGH",
+            GOLDFISH_RTC_IRQ_ENABLED => "IRQ_ENABLED",
+            GOLDFISH_RTC_CLEAR_ALARM => "CLEAR_ALARM",
+            GOLDFISH_RTC_ALARM_STATUS => "ALARM_STATUS",
+            GOLDFISH_RTC_CLEAR_INTERRUPT => "CLEAR_INTERRUPT",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    /// The clock keeps running like a battery-backed one, and the alarm is cleared.
+    fn reset(&mut self) {
+        self.time_high = 0;
+        self.alarm = 0;
+        self.alarm_armed = false;
+        self.irq_enabled = false;
+        self.interrupting = false;
+    }
+}
+
+impl Default for GoldfishRtc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl GoldfishRtc {
+    /// Create a new `GoldfishRtc` object starting at the host time.
+    pub fn new() -> Self {
+        Self {
+            setting: RtcSetting::Host,
+            deterministic: false,
+            start: host_nanos(),
+            started: Instant::now(),
+            instructions: 0,
+            time_high: 0,
+            alarm: 0,
+            alarm_armed: false,
+            irq_enabled: false,
+            interrupting: false,
+        }
+    }
+
+    /// Select where the clock starts.
+    pub fn set_clock(&mut self, setting: RtcSetting) {
+        self.setting = setting;
+        self.set_time(self.initial_time());
+    }
+
+    /// Advance the clock by instructions instead of the host clock.
+    pub fn set_deterministic(&mut self) {
+        self.deterministic = true;
+        self.set_time(self.initial_time());
+    }
+
+    fn initial_time(&self) -> u64 {
+        match self.setting {
+            RtcSetting::Base(nanos) => nanos,
+            RtcSetting::Offset(offset) => host_nanos().saturating_add_signed(offset),
+            RtcSetting::Host if self.deterministic => 0,
+            RtcSetting::Host => host_nanos(),
+        }
+    }
+
+    fn set_time(&mut self, nanos: u64) {
+        self.start = nanos;
+        self.started = Instant::now();
+        self.instructions = 0;
+    }
+
+    /// Save the time and the alarm for a snapshot. The clock continues from the saved time when
+    /// it's restored.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u64(out, self.now())?;
+        write_u64(out, self.time_high as u64)?;
+        write_u64(out, self.alarm)?;
+        for flag in [self.alarm_armed, self.irq_enabled, self.interrupting] {
+            write_u64(out, flag as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the time and the alarm from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.set_time(read_u64(input)?);
+        self.time_high = read_u64(input)? as u32;
+        self.alarm = read_u64(input)?;
+        self.alarm_armed = read_u64(input)? != 0;
+        self.irq_enabled = read_u64(input)? != 0;
+        self.interrupting = read_u64(input)? != 0;
+        Ok(())
+    }
+
+    /// Return the current time in nanoseconds since the Unix epoch.
+    pub fn now(&self) -> u64 {
+        let elapsed = if self.deterministic {
+            self.instructions * NANOS_PER_INSTRUCTION
+        } else {
+            self.started.elapsed().as_nanos() as u64
+        };
+        self.start.wrapping_add(elapsed)
+    }
+
+    /// Advance the clock by one instruction and fire the alarm if its time has come.
+    pub fn tick(&mut self) {
+        self.instructions += 1;
+        if self.alarm_armed && self.now() >= self.alarm {
+            self.alarm_armed = false;
+            if self.irq_enabled {
+                self.interrupting = true;
+            }
+        }
+    }
+
+    /// Return true if the alarm fired and the interrupt hasn't been taken yet.
+    pub fn is_interrupting(&mut self) -> bool {
+        std::mem::replace(&mut self.interrupting, false)
+    }
+
+    fn load32(&mut self, addr: u64) -> u64 {
+        let value = match addr {
+            GOLDFISH_RTC_TIME_LOW => {
+                let now = self.now();
+                self.time_high = (now >> 32) as u32;
+                now as u32
+            }
+            GOLDFISH_RTC_TIME_HIGH => self.time_high,
+            GOLDFISH_RTC_ALARM_LOW => self.alarm as u32,
+            GOLDFISH_RTC_ALARM_HIGH => (self.alarm >> 32) as u32,
+            GOLDFISH_RTC_IRQ_ENABLED => self.irq_enabled as u32,
+            GOLDFISH_RTC_ALARM_STATUS => self.alarm_armed as u32,
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn store32(&mut self, addr: u64, value: u32) {
+        match addr {
+            // Guests set the time by writing the high bits and then the low bits.
+            GOLDFISH_RTC_TIME_LOW => {
+                let high = self.now() & !0xffff_ffff;
+                self.set_time(high | value as u64);
+            }
+            GOLDFISH_RTC_TIME_HIGH => {
+                let low = self.now() & 0xffff_ffff;
+                self.set_time(((value as u64) << 32) | low);
+            }
+            GOLDFISH_RTC_ALARM_LOW => {
+                self.alarm = (self.alarm & !0xffff_ffff) | value as u64;
+                self.alarm_armed = true;
+            }
+            GOLDFISH_RTC_ALARM_HIGH => {
+                self.alarm = ((value as u64) << 32) | (self.alarm & 0xffff_ffff);
+            }
+            GOLDFISH_RTC_IRQ_ENABLED => self.irq_enabled = value & 1 == 1,
+            GOLDFISH_RTC_CLEAR_ALARM => self.alarm_armed = false,
+            GOLDFISH_RTC_CLEAR_INTERRUPT => self.interrupting = false,
+            _ => {}
+        }
+    }
+}
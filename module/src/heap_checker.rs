@@ -0,0 +1,315 @@
+//! The heap_checker module contains a checker of guest heap misuse in the spirit of
+//! AddressSanitizer, without recompiling the guest. `--heap-check` hooks the allocator functions
+//! of an ELF kernel or of `--symbols`, i.e. `malloc` and `free` of newlib or a libc, their
+//! reentrant variants `_malloc_r` and `_free_r`, and xv6's `kalloc` and `kfree`, and reports:
+//! - A load or a store to a freed block (use after free).
+//! - A load or a store just before or after a live block (buffer overflow), within `REDZONE`
+//!   bytes and not in another block.
+//! - A free of a freed block (double free) or of a pointer which was never allocated.
+//!
+//! A call is seen at the entry of a function and its return at the return address with the same
+//! stack pointer. Calls made while another allocator call is running, e.g. `_malloc_r` from
+//! `malloc`, are part of it, and accesses by the allocator itself aren't checked. Accesses are
+//! checked by virtual address in the privilege mode which calls the allocator, and only from the
+//! lowest address which has been allocated or freed up to the redzone after the highest one. A free of an unknown page to `kfree` is
+//! how xv6 fills its free list at boot, so it's recorded as a freed block and not reported.
+
+use std::collections::{BTreeMap, HashSet};
+use std::ops::Range;
+
+use crate::cpu::*;
+use crate::elf::SymbolTable;
+
+/// The number of bytes before and after a live block where an access is an overflow.
+pub const REDZONE: u64 = 16;
+/// The size of a page which xv6's `kalloc` returns.
+const XV6_PAGE_SIZE: u64 = 4096;
+
+/// The size of an allocation.
+#[derive(Debug, Clone, Copy)]
+enum AllocSize {
+    /// In an argument register, a0 + n.
+    Arg(usize),
+    /// Always the same.
+    Fixed(u64),
+}
+
+/// A hooked allocator function.
+#[derive(Debug, Clone, Copy)]
+enum Hook {
+    Alloc(AllocSize),
+    /// A free function with the pointer in a0 + n, and the size of the blocks if it's fixed.
+    Free(usize, Option<u64>),
+}
+
+/// The allocator functions which are hooked if the guest has them.
+const HOOKS: [(&str, Hook); 6] = [
+    ("malloc", Hook::Alloc(AllocSize::Arg(0))),
+    ("_malloc_r", Hook::Alloc(AllocSize::Arg(1))),
+    ("kalloc", Hook::Alloc(AllocSize::Fixed(XV6_PAGE_SIZE))),
+    ("free", Hook::Free(0, None)),
+    ("_free_r", Hook::Free(1, None)),
+    ("kfree", Hook::Free(0, Some(XV6_PAGE_SIZE))),
+];
+
+/// An allocated or freed block.
+#[derive(Debug, Clone, Copy)]
+struct Block {
+    size: u64,
+    /// The return address of the call which allocated or freed it.
+    caller: u64,
+}
+
+/// An allocator call which hasn't returned yet.
+#[derive(Debug)]
+struct Call {
+    /// The size of the allocation, or None for a free.
+    size: Option<u64>,
+    ra: u64,
+    sp: u64,
+}
+
+/// A checker of guest heap misuse.
+pub struct HeapChecker {
+    symbols: SymbolTable,
+    /// The addresses of the hooked functions.
+    entries: Vec<(u64, &'static str, Hook)>,
+    /// The allocator call being executed.
+    call: Option<Call>,
+    /// The privilege mode which calls the allocator.
+    mode: Option<Mode>,
+    /// Live blocks by their start addresses.
+    live: BTreeMap<u64, Block>,
+    /// Freed blocks by their start addresses, until they are allocated again.
+    freed: BTreeMap<u64, Block>,
+    /// The range of the addresses which have been allocated or freed.
+    heap: Option<Range<u64>>,
+    /// The pcs of the accesses which have been reported, so a loop reports once.
+    reported: HashSet<u64>,
+}
+
+impl HeapChecker {
+    /// Create a new `HeapChecker` object hooking the allocator functions in `symbols`.
+    pub fn new(symbols: SymbolTable) -> Self {
+        let entries = HOOKS
+            .iter()
+            .filter_map(|&(name, hook)| symbols.lookup(name).map(|addr| (addr, name, hook)))
+            .collect();
+        Self {
+            symbols,
+            entries,
+            call: None,
+            mode: None,
+            live: BTreeMap::new(),
+            freed: BTreeMap::new(),
+            heap: None,
+            reported: HashSet::new(),
+        }
+    }
+
+    /// Return the names of the hooked functions.
+    pub fn hooked(&self) -> Vec<&'static str> {
+        self.entries.iter().map(|(_, name, _)| *name).collect()
+    }
+
+    /// Return the number of live blocks and their total size.
+    pub fn live(&self) -> (usize, u64) {
+        (
+            self.live.len(),
+            self.live.values().map(|block| block.size).sum(),
+        )
+    }
+
+    /// Track allocator calls and returns. It must be called before the instruction at `cpu.pc`
+    /// is executed, and returns a report if a free is invalid.
+    pub fn check_call(&mut self, cpu: &Cpu) -> Option<String> {
+        if let Some(call) = &self.call {
+            if cpu.pc != call.ra || cpu.regs[2] != call.sp {
+                return None;
+            }
+            let call = self.call.take()?;
+            if let Some(size) = call.size {
+                // Ignore a failed allocation.
+                if cpu.regs[10] != 0 {
+                    self.allocate(cpu.regs[10], size, call.ra);
+                }
+            }
+            return None;
+        }
+
+        let &(_, name, hook) = self.entries.iter().find(|(addr, _, _)| *addr == cpu.pc)?;
+        self.mode = Some(cpu.mode);
+        let ra = cpu.regs[1];
+        let (size, report) = match hook {
+            Hook::Alloc(AllocSize::Arg(n)) => (Some(cpu.regs[10 + n]), None),
+            Hook::Alloc(AllocSize::Fixed(size)) => (Some(size), None),
+            Hook::Free(n, size) => (None, self.free(name, cpu.regs[10 + n], size, ra)),
+        };
+        self.call = Some(Call {
+            size,
+            ra,
+            sp: cpu.regs[2],
+        });
+        report
+    }
+
+    /// Check a load or a store of `inst` at `pc`. It must be called before `inst` is executed.
+    pub fn check_access(&mut self, cpu: &Cpu, pc: u64, inst: u64) -> Option<String> {
+        if self.call.is_some() || self.mode != Some(cpu.mode) || self.reported.contains(&pc) {
+            return None;
+        }
+        let (addr, size, kind) = access(cpu, inst)?;
+        let end = addr.wrapping_add(size);
+        let heap = self.heap.as_ref()?;
+        // Below the heap is usually static data, e.g. the bss of a kernel.
+        if addr < heap.start || addr >= heap.end + REDZONE {
+            return None;
+        }
+        if containing(&self.live, addr, end).is_some() {
+            return None;
+        }
+
+        let report = if let Some((start, block)) = containing(&self.freed, addr, end) {
+            format!(
+                "heap: use after free: {} of {} bytes at {:#x} by {}, {:#x} bytes into a block \
+                 of {:#x} bytes freed by {}",
+                kind,
+                size,
+                addr,
+                self.describe(pc),
+                addr - start,
+                block.size,
+                self.describe(block.caller)
+            )
+        } else if let Some((start, block)) = self.adjacent(addr, end) {
+            let position = match addr < start {
+                true => format!("{} bytes before", start - addr),
+                false => format!("{} bytes after", addr - (start + block.size)),
+            };
+            format!(
+                "heap: buffer overflow: {} of {} bytes at {:#x} by {}, {} a block of {:#x} bytes \
+                 at {:#x} allocated by {}",
+                kind,
+                size,
+                addr,
+                self.describe(pc),
+                position,
+                block.size,
+                start,
+                self.describe(block.caller)
+            )
+        } else {
+            return None;
+        };
+        self.reported.insert(pc);
+        Some(report)
+    }
+
+    /// Record a block returned by an allocator.
+    fn allocate(&mut self, addr: u64, size: u64, caller: u64) {
+        let end = addr.wrapping_add(size.max(1));
+        let reused: Vec<u64> = self
+            .freed
+            .range(..end)
+            .filter(|(start, block)| **start + block.size > addr)
+            .map(|(start, _)| *start)
+            .collect();
+        for start in reused {
+            self.freed.remove(&start);
+        }
+        self.live.insert(addr, Block { size, caller });
+        self.extend_heap(addr, end);
+    }
+
+    /// Record a free and return a report if it's invalid.
+    fn free(&mut self, name: &str, addr: u64, size: Option<u64>, caller: u64) -> Option<String> {
+        if addr == 0 {
+            return None;
+        }
+        if let Some(block) = self.live.remove(&addr) {
+            self.freed.insert(addr, Block { caller, ..block });
+            return None;
+        }
+        if let Some(block) = self.freed.get(&addr) {
+            return Some(format!(
+                "heap: double free: {}({:#x}) by {}, freed by {}",
+                name,
+                addr,
+                self.describe(caller),
+                self.describe(block.caller)
+            ));
+        }
+        match size {
+            Some(size) => {
+                self.freed.insert(addr, Block { size, caller });
+                self.extend_heap(addr, addr.wrapping_add(size));
+                None
+            }
+            None => Some(format!(
+                "heap: invalid free: {}({:#x}) by {} of a pointer which isn't allocated",
+                name,
+                addr,
+                self.describe(caller)
+            )),
+        }
+    }
+
+    fn extend_heap(&mut self, start: u64, end: u64) {
+        self.heap = Some(match self.heap.take() {
+            Some(heap) => heap.start.min(start)..heap.end.max(end),
+            None => start..end,
+        });
+    }
+
+    /// Return a live block whose redzone an access overlaps.
+    fn adjacent(&self, addr: u64, end: u64) -> Option<(u64, Block)> {
+        let before = self
+            .live
+            .range(..addr)
+            .next_back()
+            .filter(|(start, block)| addr < **start + block.size + REDZONE);
+        let after = self
+            .live
+            .range(addr..)
+            .next()
+            .filter(|(start, _)| end + REDZONE > **start);
+        before.or(after).map(|(start, block)| (*start, *block))
+    }
+
+    /// Return an address with its symbol.
+    fn describe(&self, addr: u64) -> String {
+        match self.symbols.symbolize(addr) {
+            Some((symbol, offset)) => format!("{:#x} <{}+{:#x}>", addr, symbol, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
+/// Return a block which overlaps the bytes from `addr` to `end`.
+fn containing(blocks: &BTreeMap<u64, Block>, addr: u64, end: u64) -> Option<(u64, Block)> {
+    blocks
+        .range(..end)
+        .next_back()
+        .filter(|(start, block)| **start + block.size.max(1) > addr)
+        .map(|(start, block)| (*start, *block))
+}
+
+/// Return the virtual address, the size in bytes and the kind of the memory access of `inst`.
+fn access(cpu: &Cpu, inst: u64) -> Option<(u64, u64, &'static str)> {
+    let opcode = inst & 0x7f;
+    let funct3 = (inst >> 12) & 0x7;
+    let rs1 = cpu.regs[((inst >> 15) & 0x1f) as usize];
+    let size = 1 << (funct3 & 0x3);
+    match opcode {
+        0x03 => {
+            let imm = ((inst as i32 as i64) >> 20) as u64;
+            Some((rs1.wrapping_add(imm), size, "load"))
+        }
+        0x23 => {
+            let imm = (((inst & 0xfe000000) as i32 as i64 >> 20) as u64) | ((inst >> 7) & 0x1f);
+            Some((rs1.wrapping_add(imm), size, "store"))
+        }
+        0x2f => Some((rs1, size, "atomic access")),
+        _ => None,
+    }
+}
@@ -0,0 +1,76 @@
+//! The history module contains a circular buffer of the last executed instructions. It's cheap
+//! enough to be always on: an instruction is recorded as its pc and encoding, and disassembled
+//! only when the history is printed, on a fatal exception or in a state dump.
+
+use std::fmt;
+
+use crate::disasm::disassemble;
+
+/// The default number of the last executed instructions kept.
+pub const DEFAULT_HISTORY_LENGTH: usize = 32;
+
+/// The last executed instructions.
+pub struct History {
+    /// The pcs and the instructions in a circular buffer. The instruction is None if the fetch
+    /// failed.
+    entries: Vec<(u64, Option<u32>)>,
+    /// The index of the next entry in `entries`.
+    next: usize,
+    /// The number of recorded instructions.
+    recorded: u64,
+}
+
+impl History {
+    /// Create a new `History` object keeping the last `length` instructions.
+    pub fn new(length: usize) -> Self {
+        Self {
+            entries: vec![(0, None); length],
+            next: 0,
+            recorded: 0,
+        }
+    }
+
+    /// Record an instruction executed at `pc`, or a failed fetch if `inst` is None.
+    pub fn record(&mut self, pc: u64, inst: Option<u32>) {
+        self.recorded += 1;
+        if self.entries.is_empty() {
+            return;
+        }
+        self.entries[self.next] = (pc, inst);
+        self.next = (self.next + 1) % self.entries.len();
+    }
+
+    /// Return the number of recorded instructions.
+    pub fn recorded(&self) -> u64 {
+        self.recorded
+    }
+
+    /// Return the kept instructions from the oldest.
+    pub fn entries(&self) -> Vec<(u64, Option<u32>)> {
+        let length = self.entries.len();
+        let count = (self.recorded as usize).min(length);
+        (0..count)
+            .map(|i| self.entries[(self.next + length - count + i) % length])
+            .collect()
+    }
+}
+
+impl fmt::Display for History {
+    /// Write the kept instructions from the oldest, one per line.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "last {} instructions:", self.entries().len())?;
+        for (pc, inst) in self.entries() {
+            match inst {
+                Some(inst) => writeln!(
+                    f,
+                    "  {:#018x}: {:08x}  {}",
+                    pc,
+                    inst,
+                    disassemble(pc, inst as u64)
+                )?,
+                None => writeln!(f, "  {:#018x}: <fetch failed>", pc)?,
+            }
+        }
+        Ok(())
+    }
+}
@@ -1 +1,2 @@
 pub mod rv64i;
+pub mod zk;
@@ -1,6 +1,7 @@
 use crate::cpu::Cpu;
 use crate::trap::Exception;
 
+#[allow(dead_code)]
 pub fn execute(_cpu: &mut Cpu, _inst: u64) -> Result<(), Exception> {
     /*
     let opcode = inst & 0x7f;
@@ -0,0 +1,300 @@
+//! The jtag module contains a JTAG Debug Transport Module (DTM) reachable over the remote_bitbang
+//! protocol of OpenOCD. OpenOCD drives the TAP signals over TCP and reaches the Debug Module
+//! through the DMI register, exactly as it would over a JTAG adapter:
+//!
+//! ```text
+//! adapter driver remote_bitbang
+//! remote_bitbang host localhost
+//! remote_bitbang port 9824
+//! jtag newtap riscv cpu -irlen 5 -expected-id 0xdeadbeef
+//! target create riscv.cpu riscv -chain-position riscv.cpu
+//! ```
+
+use std::io;
+use std::io::prelude::*;
+use std::net::{TcpListener, TcpStream};
+
+use crate::cpu::*;
+use crate::debug_module::DebugModule;
+
+/// The number of instructions between polls of the socket while the hart is running.
+const JTAG_POLL_INTERVAL: u64 = 1024;
+
+/// The IDCODE of the TAP, same as Spike.
+const IDCODE: u64 = 0xdeadbeef;
+
+// Instruction register values.
+const IR_IDCODE: u64 = 0x01;
+const IR_DTMCS: u64 = 0x10;
+const IR_DMI: u64 = 0x11;
+/// The length of the instruction register.
+const IR_LEN: u32 = 5;
+
+/// The number of address bits of the DMI.
+const DMI_ABITS: u64 = 7;
+/// dtmcs: version 1 (0.13), abits, and idle cycles 1.
+const DTMCS: u64 = 1 | (DMI_ABITS << 4) | (1 << 12);
+
+/// The states of the TAP controller.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDrScan,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIrScan,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl TapState {
+    /// Return the next state for the TMS value at a rising edge of TCK.
+    fn next(self, tms: bool) -> TapState {
+        use TapState::*;
+        match (self, tms) {
+            (TestLogicReset, true) => TestLogicReset,
+            (TestLogicReset, false) => RunTestIdle,
+            (RunTestIdle, true) => SelectDrScan,
+            (RunTestIdle, false) => RunTestIdle,
+            (SelectDrScan, true) => SelectIrScan,
+            (SelectDrScan, false) => CaptureDr,
+            (CaptureDr, true) => Exit1Dr,
+            (CaptureDr, false) => ShiftDr,
+            (ShiftDr, true) => Exit1Dr,
+            (ShiftDr, false) => ShiftDr,
+            (Exit1Dr, true) => UpdateDr,
+            (Exit1Dr, false) => PauseDr,
+            (PauseDr, true) => Exit2Dr,
+            (PauseDr, false) => PauseDr,
+            (Exit2Dr, true) => UpdateDr,
+            (Exit2Dr, false) => ShiftDr,
+            (UpdateDr, true) => SelectDrScan,
+            (UpdateDr, false) => RunTestIdle,
+            (SelectIrScan, true) => TestLogicReset,
+            (SelectIrScan, false) => CaptureIr,
+            (CaptureIr, true) => Exit1Ir,
+            (CaptureIr, false) => ShiftIr,
+            (ShiftIr, true) => Exit1Ir,
+            (ShiftIr, false) => ShiftIr,
+            (Exit1Ir, true) => UpdateIr,
+            (Exit1Ir, false) => PauseIr,
+            (PauseIr, true) => Exit2Ir,
+            (PauseIr, false) => PauseIr,
+            (Exit2Ir, true) => UpdateIr,
+            (Exit2Ir, false) => ShiftIr,
+            (UpdateIr, true) => SelectDrScan,
+            (UpdateIr, false) => RunTestIdle,
+        }
+    }
+}
+
+/// The TAP controller and the Debug Transport Module.
+struct Tap {
+    state: TapState,
+    tck: bool,
+    ir: u64,
+    ir_shift: u64,
+    dr: u64,
+    dr_len: u32,
+    /// The address and the data of the last DMI access, returned by the next capture.
+    dmi_addr: u64,
+    dmi_data: u64,
+}
+
+impl Tap {
+    fn new() -> Self {
+        Self {
+            state: TapState::TestLogicReset,
+            tck: false,
+            ir: IR_IDCODE,
+            ir_shift: 0,
+            dr: 0,
+            dr_len: 1,
+            dmi_addr: 0,
+            dmi_data: 0,
+        }
+    }
+
+    fn reset(&mut self) {
+        self.state = TapState::TestLogicReset;
+        self.ir = IR_IDCODE;
+    }
+
+    /// Return the value of TDO.
+    fn tdo(&self) -> bool {
+        match self.state {
+            TapState::ShiftDr => self.dr & 1 == 1,
+            TapState::ShiftIr => self.ir_shift & 1 == 1,
+            _ => false,
+        }
+    }
+
+    /// Drive the signals. The TAP acts on a rising edge of TCK.
+    fn set(&mut self, tck: bool, tms: bool, tdi: bool, dm: &mut DebugModule, cpu: &mut Cpu) {
+        let rising = !self.tck && tck;
+        self.tck = tck;
+        if !rising {
+            return;
+        }
+
+        match self.state {
+            TapState::CaptureDr => self.capture_dr(),
+            TapState::ShiftDr => {
+                self.dr = (self.dr >> 1) | ((tdi as u64) << (self.dr_len - 1));
+            }
+            TapState::CaptureIr => self.ir_shift = 0b00001,
+            TapState::ShiftIr => {
+                self.ir_shift = (self.ir_shift >> 1) | ((tdi as u64) << (IR_LEN - 1));
+            }
+            _ => {}
+        }
+
+        self.state = self.state.next(tms);
+        match self.state {
+            TapState::TestLogicReset => self.ir = IR_IDCODE,
+            TapState::UpdateIr => self.ir = self.ir_shift,
+            TapState::UpdateDr => self.update_dr(dm, cpu),
+            _ => {}
+        }
+    }
+
+    fn capture_dr(&mut self) {
+        let (dr, len) = match self.ir {
+            IR_IDCODE => (IDCODE, 32),
+            IR_DTMCS => (DTMCS, 32),
+            // op (2 bits) is always 0 (success) because accesses complete immediately.
+            IR_DMI => (
+                (self.dmi_addr << 34) | (self.dmi_data << 2),
+                34 + DMI_ABITS as u32,
+            ),
+            // BYPASS and unknown instructions.
+            _ => (0, 1),
+        };
+        self.dr = dr;
+        self.dr_len = len;
+    }
+
+    fn update_dr(&mut self, dm: &mut DebugModule, cpu: &mut Cpu) {
+        if self.ir != IR_DMI {
+            return;
+        }
+        let op = self.dr & 0b11;
+        let data = ((self.dr >> 2) & 0xffff_ffff) as u32;
+        let addr = ((self.dr >> 34) & ((1 << DMI_ABITS) - 1)) as u32;
+        match op {
+            1 => self.dmi_data = dm.read(addr) as u64,
+            2 => dm.write(cpu, addr, data),
+            _ => return,
+        }
+        self.dmi_addr = addr as u64;
+    }
+}
+
+/// A remote_bitbang server connecting OpenOCD to the Debug Module.
+pub struct RemoteBitbang {
+    listener: TcpListener,
+    stream: Option<TcpStream>,
+    tap: Tap,
+    dm: DebugModule,
+    /// The number of polls since the last check of the socket.
+    ticks: u64,
+}
+
+impl RemoteBitbang {
+    /// Listen on a TCP address such as `127.0.0.1:9824`.
+    pub fn bind(addr: &str) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        Ok(Self {
+            listener,
+            stream: None,
+            tap: Tap::new(),
+            dm: DebugModule::new(),
+            ticks: 0,
+        })
+    }
+
+    /// Enter debug mode on an ebreak if the debugger enabled it in dcsr. Return true if the
+    /// exception must not be taken.
+    pub fn ebreak(&mut self, cpu: &mut Cpu) -> bool {
+        self.dm.ebreak(cpu)
+    }
+
+    /// Handle requests from OpenOCD. It's called once per instruction and checks the socket every
+    /// `JTAG_POLL_INTERVAL` instructions. While the hart is halted, it blocks until the debugger
+    /// resumes it.
+    pub fn poll(&mut self, cpu: &mut Cpu) -> io::Result<()> {
+        self.dm.check_step(cpu);
+        if !self.dm.is_halted() {
+            self.ticks += 1;
+            if self.ticks < JTAG_POLL_INTERVAL {
+                return Ok(());
+            }
+        }
+        self.ticks = 0;
+
+        loop {
+            let halted = self.dm.is_halted();
+            if self.stream.is_none() {
+                self.listener.set_nonblocking(!halted)?;
+                match self.listener.accept() {
+                    Ok((stream, _)) => {
+                        stream.set_nodelay(true)?;
+                        self.stream = Some(stream);
+                    }
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                    Err(e) => return Err(e),
+                }
+            }
+
+            let mut stream = self.stream.take().expect("failed to get a stream");
+            stream.set_nonblocking(!halted)?;
+            let mut buf = [0; 4096];
+            let len = match stream.read(&mut buf) {
+                // The debugger has disconnected.
+                Ok(0) => continue,
+                Ok(len) => len,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    self.stream = Some(stream);
+                    return Ok(());
+                }
+                Err(_) => continue,
+            };
+
+            let mut reply = Vec::new();
+            let mut quit = false;
+            for c in &buf[..len] {
+                match c {
+                    b'0'..=b'7' => {
+                        let bits = c - b'0';
+                        self.tap.set(
+                            bits & 0b100 != 0,
+                            bits & 0b010 != 0,
+                            bits & 0b001 != 0,
+                            &mut self.dm,
+                            cpu,
+                        );
+                    }
+                    b'R' => reply.push(if self.tap.tdo() { b'1' } else { b'0' }),
+                    // Reset with TRST asserted.
+                    b't' | b'u' => self.tap.reset(),
+                    b'Q' => quit = true,
+                    // Blink, reset without TRST, and unknown commands.
+                    _ => {}
+                }
+            }
+            // Put the stream back only if it's still alive.
+            if stream.set_nonblocking(false).is_ok() && stream.write_all(&reply).is_ok() && !quit {
+                self.stream = Some(stream);
+            }
+        }
+    }
+}
@@ -0,0 +1,120 @@
+//! The level module contains the steps of the book, "Writing a RISC-V Emulator from Scratch in
+//! 10 Steps". `--level <n>` enables only the subsystems which the steps up to n introduce, so
+//! readers can reproduce the behavior of each step with one binary. The subsystems follow the
+//! step directories of this repository:
+//! - Step 1: add and addi
+//! - Step 2: RV64I and multiplication
+//! - Step 3: CSR instructions
+//! - Step 4: mret, sret, fences and atomic instructions
+//! - Step 5: exceptions, which stop the emulator before
+//! - Step 6: the PLIC and the CLINT
+//! - Step 7: the UART
+//! - Step 8: interrupts
+//! - Step 9: the virtio block device
+//! - Step 10: virtual memory
+//!
+//! Anything the book doesn't describe, e.g. the crypto instructions and devices other than the
+//! above, is disabled at every level. The emulator control device stays mapped so programs can
+//! still exit.
+
+use std::fmt;
+
+/// A subsystem and the step which introduces it.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Feature {
+    Adder = 1,
+    Rv64i = 2,
+    Csr = 3,
+    Privileged = 4,
+    Exceptions = 5,
+    PlicClint = 6,
+    Uart = 7,
+    Interrupts = 8,
+    Virtio = 9,
+    Paging = 10,
+    /// Everything beyond the book.
+    Extensions = 11,
+}
+
+/// The last step of the book.
+pub const MAX_LEVEL: u8 = 10;
+
+/// The step up to which subsystems are enabled.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub struct Level(u8);
+
+impl Level {
+    /// All subsystems including those beyond the book, which is the default.
+    pub const FULL: Level = Level(Feature::Extensions as u8);
+
+    /// Create a level of the step `step`, from 1 to `MAX_LEVEL`.
+    pub fn new(step: u8) -> Option<Self> {
+        match step {
+            1..=MAX_LEVEL => Some(Level(step)),
+            _ => None,
+        }
+    }
+
+    /// Return true if `feature` is enabled.
+    pub fn enables(&self, feature: Feature) -> bool {
+        self.0 >= feature as u8
+    }
+
+    /// Return true if `inst` belongs to an enabled subsystem. Instructions which don't exist at
+    /// all are left to the decoder.
+    pub fn allows(&self, inst: u64) -> bool {
+        if *self == Level::FULL {
+            return true;
+        }
+        self.enables(instruction_feature(inst))
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Level::FULL => write!(f, "full"),
+            Level(step) => write!(f, "{}", step),
+        }
+    }
+}
+
+/// Return the subsystem which introduces `inst`.
+fn instruction_feature(inst: u64) -> Feature {
+    let opcode = inst & 0x7f;
+    let funct3 = (inst >> 12) & 0x7;
+    let funct7 = (inst >> 25) & 0x7f;
+    // The upper bits of a shift amount or funct6 of shift-immediate instructions.
+    let funct6 = (inst >> 26) & 0x3f;
+    match opcode {
+        // addi
+        0x13 if funct3 == 0x0 => Feature::Adder,
+        // add
+        0x33 if funct3 == 0x0 && funct7 == 0x00 => Feature::Adder,
+        0x13 => match (funct3, funct6) {
+            // slli, srli and srai. Other shifts by immediates are bit-manipulation or crypto.
+            (0x1, 0x00) | (0x5, 0x00) | (0x5, 0x10) => Feature::Rv64i,
+            (0x1, _) | (0x5, _) => Feature::Extensions,
+            _ => Feature::Rv64i,
+        },
+        0x1b => match (funct3, funct7) {
+            (0x0, _) | (0x1, 0x00) | (0x5, 0x00) | (0x5, 0x20) => Feature::Rv64i,
+            _ => Feature::Extensions,
+        },
+        0x33 => match (funct3, funct7) {
+            (_, 0x00) | (_, 0x01) | (0x0, 0x20) | (0x5, 0x20) => Feature::Rv64i,
+            _ => Feature::Extensions,
+        },
+        0x3b => match (funct3, funct7) {
+            (0x0, 0x00) | (0x1, 0x00) | (0x5, 0x00) | (0x0, 0x20) | (0x5, 0x20) => Feature::Rv64i,
+            (0x0, 0x01) | (0x4..=0x7, 0x01) => Feature::Rv64i,
+            _ => Feature::Extensions,
+        },
+        0x03 | 0x17 | 0x23 | 0x37 | 0x63 | 0x67 | 0x6f => Feature::Rv64i,
+        // csrrw, csrrs, csrrc and their immediate forms.
+        0x73 if funct3 != 0x0 => Feature::Csr,
+        // ecall, ebreak, mret, sret, wfi and sfence.vma, fences and atomic instructions.
+        0x73 | 0x0f | 0x2f => Feature::Privileged,
+        _ => Feature::Extensions,
+    }
+}
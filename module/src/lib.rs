@@ -1,10 +1,55 @@
-mod bus;
+pub mod bench;
+pub mod bus;
+pub mod checkpoint;
+pub mod chrome_trace;
 mod clint;
+pub mod compare;
+pub mod coredump;
 pub mod cpu;
+mod debug_module;
+mod debugcon;
+pub mod disasm;
 mod dram;
+pub mod elf;
+mod emuctl;
+pub mod emulator;
+pub mod entropy;
+pub mod explain;
+pub mod extensions;
+pub mod filestore;
+pub mod goldfish_rtc;
+pub mod heap_checker;
+pub mod history;
 mod isa;
+mod json;
+pub mod jtag;
+pub mod level;
+pub mod loader;
+pub mod machine;
 mod mmu;
+pub mod mux;
+pub mod ntrace;
+pub mod panic_detector;
+pub mod pflash;
+pub mod pipeline;
 mod plic;
+pub mod plugin;
+mod pmp;
+pub mod qmp;
+pub mod script;
+mod sifive_pwm;
+mod sifive_uart;
+pub mod snapshot;
+pub mod state_dump;
+pub mod stats;
+pub mod strace;
+pub mod timeline;
+pub mod torture;
+pub mod trace_file;
 pub mod trap;
+pub mod tui;
 mod uart;
 mod virtio;
+mod virtio_snd;
+mod virtio_vsock;
+pub mod virtqueue;
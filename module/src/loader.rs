@@ -0,0 +1,283 @@
+//! The loader module contains readers of kernel images. An image is a list of segments placed at
+//! their own addresses and an entry point. Besides raw binaries, which are placed at the start of
+//! the dram, it reads the loadable segments of ELF files and the text formats which embedded
+//! toolchains often output:
+//! - Intel HEX (`.hex`, `.ihex`)
+//!   See: https://developer.arm.com/documentation/ka003292/latest
+//! - Motorola S-record (`.srec`, `.s19`, `.s28`, `.s37`, `.mot`)
+//!   See: https://manpages.debian.org/testing/srecord/srec_motorola.5.en.html
+
+use std::fs;
+use std::io;
+use std::ops::Range;
+use std::path::Path;
+
+use crate::bus::*;
+use crate::cpu::*;
+use crate::dram::DRAM_SIZE;
+use crate::elf::{ElfFile, PT_LOAD};
+use crate::machine::Machine;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// The format of an image file.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ImageFormat {
+    Raw,
+    Elf,
+    IntelHex,
+    Srec,
+}
+
+/// Contiguous bytes of an image at an address.
+#[derive(Debug, Clone)]
+pub struct Segment {
+    pub addr: u64,
+    pub data: Vec<u8>,
+}
+
+/// A kernel image.
+#[derive(Debug, Clone)]
+pub struct Image {
+    pub format: ImageFormat,
+    pub segments: Vec<Segment>,
+    /// The address to start executing, if the image has one.
+    pub entry: Option<u64>,
+}
+
+impl Image {
+    /// Read an image file. Intel HEX and S-record files are recognized by their extensions, and
+    /// ELF files by their magic number. Other files are raw binaries. ELF segments must be
+    /// inside the dram of `machine`, or of the default layout if it's None.
+    pub fn load(path: &str, machine: Option<&Machine>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        let extension = Path::new(path)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_ascii_lowercase());
+        match extension.as_deref() {
+            Some("hex") | Some("ihex") => Self::parse_ihex(&text(&bytes)?),
+            Some("srec") | Some("s19") | Some("s28") | Some("s37") | Some("mot") => {
+                Self::parse_srec(&text(&bytes)?)
+            }
+            _ if bytes.starts_with(b"\x7fELF") => {
+                let dram = match machine {
+                    Some(machine) => machine.memory.clone(),
+                    None => DRAM_BASE..DRAM_BASE + DRAM_SIZE,
+                };
+                Self::parse_elf(&bytes, &dram)
+            }
+            _ => Ok(Self::raw(bytes)),
+        }
+    }
+
+    /// Create an image of a raw binary placed at the start of the dram.
+    pub fn raw(binary: Vec<u8>) -> Self {
+        Self {
+            format: ImageFormat::Raw,
+            segments: vec![Segment {
+                addr: DRAM_BASE,
+                data: binary,
+            }],
+            entry: Some(DRAM_BASE),
+        }
+    }
+
+    /// Move the image by the distance from the start of the dram to `base`. Raw images have no
+    /// addresses of their own, so this places them at the start of the dram of another machine.
+    pub fn relocate(&mut self, base: u64) {
+        for segment in self.segments.iter_mut() {
+            segment.addr = segment.addr - DRAM_BASE + base;
+        }
+        self.entry = self.entry.map(|entry| entry - DRAM_BASE + base);
+    }
+
+    /// Parse the loadable segments of a 64-bit little-endian ELF file. A segment outside `dram`
+    /// is rejected before its memory is allocated.
+    pub fn parse_elf(bytes: &[u8], dram: &Range<u64>) -> io::Result<Self> {
+        let elf = ElfFile::new(bytes)?;
+        let mut segments = Vec::new();
+        for (i, ph) in elf.program_headers()?.iter().enumerate() {
+            if ph.kind != PT_LOAD {
+                continue;
+            }
+            let size = ph.memsz.max(ph.filesz);
+            let end = ph.paddr.checked_add(size);
+            if ph.paddr < dram.start || end.is_none_or(|end| end > dram.end) {
+                return Err(invalid(format!(
+                    "segment {} at {:#x} of {:#x} bytes is outside the dram",
+                    i, ph.paddr, size
+                )));
+            }
+            let mut data = elf
+                .bytes(ph.offset as usize, ph.filesz as usize)
+                .map_err(|_| invalid(format!("segment {} is outside the ELF file", i)))?
+                .to_vec();
+            // The rest of the segment, e.g. .bss, is zero-filled.
+            data.resize(size as usize, 0);
+            segments.push(Segment {
+                addr: ph.paddr,
+                data,
+            });
+        }
+        Ok(Self {
+            format: ImageFormat::Elf,
+            segments,
+            entry: Some(elf.entry()?),
+        })
+    }
+
+    /// Parse an Intel HEX file.
+    pub fn parse_ihex(text: &str) -> io::Result<Self> {
+        let mut image = Self::empty(ImageFormat::IntelHex);
+        // The base address set by an extended segment or linear address record.
+        let mut base = 0;
+        for (n, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+            let record = line
+                .strip_prefix(':')
+                .ok_or_else(|| invalid(format!("line {}: a record doesn't start with ':'", n)))?;
+            let bytes = decode_hex(record, n)?;
+            if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+                return Err(invalid(format!("line {}: bad record length", n)));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+                return Err(invalid(format!("line {}: bad checksum", n)));
+            }
+            let offset = u16::from_be_bytes([bytes[1], bytes[2]]) as u64;
+            let data = &bytes[4..bytes.len() - 1];
+            let value = data.iter().fold(0u64, |v, b| (v << 8) | *b as u64);
+            match bytes[3] {
+                // Data.
+                0x00 => image.push(base + offset, data),
+                // End of file.
+                0x01 => break,
+                // Extended segment address.
+                0x02 => base = value << 4,
+                // Start segment address, CS:IP.
+                0x03 => image.entry = Some(((value >> 16) << 4) + (value & 0xffff)),
+                // Extended linear address.
+                0x04 => base = value << 16,
+                // Start linear address.
+                0x05 => image.entry = Some(value),
+                kind => return Err(invalid(format!("line {}: unknown record type {}", n, kind))),
+            }
+        }
+        Ok(image)
+    }
+
+    /// Parse a Motorola S-record file.
+    pub fn parse_srec(text: &str) -> io::Result<Self> {
+        let mut image = Self::empty(ImageFormat::Srec);
+        for (n, line) in text.lines().enumerate().map(|(i, l)| (i + 1, l.trim())) {
+            if line.is_empty() {
+                continue;
+            }
+            let kind = line
+                .strip_prefix('S')
+                .and_then(|rest| rest.chars().next())
+                .ok_or_else(|| invalid(format!("line {}: a record doesn't start with 'S'", n)))?;
+            let addr_len = match kind {
+                '0' | '1' | '5' | '9' => 2,
+                '2' | '6' | '8' => 3,
+                '3' | '7' => 4,
+                _ => {
+                    return Err(invalid(format!(
+                        "line {}: unknown record type S{}",
+                        n, kind
+                    )))
+                }
+            };
+            let bytes = decode_hex(&line[2..], n)?;
+            if bytes.is_empty() || bytes.len() != bytes[0] as usize + 1 {
+                return Err(invalid(format!("line {}: bad record length", n)));
+            }
+            if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0xff {
+                return Err(invalid(format!("line {}: bad checksum", n)));
+            }
+            if bytes.len() < addr_len + 2 {
+                return Err(invalid(format!("line {}: bad record length", n)));
+            }
+            let addr = bytes[1..1 + addr_len]
+                .iter()
+                .fold(0u64, |v, b| (v << 8) | *b as u64);
+            let data = &bytes[1 + addr_len..bytes.len() - 1];
+            match kind {
+                '1' | '2' | '3' => image.push(addr, data),
+                '7' | '8' | '9' => image.entry = Some(addr),
+                // A header and record counts.
+                _ => {}
+            }
+        }
+        Ok(image)
+    }
+
+    fn empty(format: ImageFormat) -> Self {
+        Self {
+            format,
+            segments: Vec::new(),
+            entry: None,
+        }
+    }
+
+    /// Append `data` at `addr`, extending the last segment if `data` follows it.
+    fn push(&mut self, addr: u64, data: &[u8]) {
+        match self.segments.last_mut() {
+            Some(last) if last.addr + last.data.len() as u64 == addr => {
+                last.data.extend_from_slice(data)
+            }
+            _ => self.segments.push(Segment {
+                addr,
+                data: data.to_vec(),
+            }),
+        }
+    }
+
+    /// Copy the segments to the dram. They must be inside the dram.
+    pub fn write_segments(&self, cpu: &mut Cpu) -> io::Result<()> {
+        for segment in &self.segments {
+            let end = segment.addr.checked_add(segment.data.len() as u64);
+            let dram = cpu.bus.dram_range();
+            if segment.addr < dram.start || end.is_none_or(|end| end > dram.end) {
+                return Err(invalid(format!(
+                    "a segment at {:#x} of {:#x} bytes is outside the dram",
+                    segment.addr,
+                    segment.data.len()
+                )));
+            }
+            cpu.bus.write_image(segment.addr, &segment.data);
+        }
+        Ok(())
+    }
+
+    /// Copy the segments to the dram and start executing at the entry point.
+    pub fn load_into(&self, cpu: &mut Cpu) -> io::Result<()> {
+        self.write_segments(cpu)?;
+        if let Some(entry) = self.entry {
+            cpu.pc = entry;
+        }
+        Ok(())
+    }
+}
+
+fn text(bytes: &[u8]) -> io::Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| invalid("an image isn't text".into()))
+}
+
+/// Decode pairs of hexadecimal digits.
+fn decode_hex(s: &str, line: usize) -> io::Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return Err(invalid(format!("line {}: bad hexadecimal digits", line)));
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&s[i..i + 2], 16)
+                .map_err(|_| invalid(format!("line {}: bad hexadecimal digits", line)))
+        })
+        .collect()
+}
@@ -0,0 +1,533 @@
+//! The machine module contains descriptions of board layouts read from device trees, either a
+//! flattened device tree blob (DTB) or its source (DTS). The memory node and the devices the
+//! emulator knows are placed at the addresses declared in `reg`, so a guest built for a board
+//! other than QEMU virt runs without changing the emulator.
+//!
+//! A device is recognized by the first string of its `compatible` property:
+//! - `ns16550a`, `ns16550`: the 16550a UART
+//! - `sifive,uart0`: the SiFive UART
+//! - `riscv,clint0`, `sifive,clint0`: CLINT
+//! - `riscv,plic0`, `sifive,plic-1.0.0`: PLIC
+//! - `google,goldfish-rtc`: the Goldfish RTC
+//! - `sifive,pwm0`: PWM0, then PWM1
+//! - `virtio,mmio`: a virtio-mmio slot, numbered in the order of addresses like QEMU virt machine
+//!
+//! Other nodes are ignored. The `ranges` of buses are assumed to be identity mappings and IRQ
+//! numbers are fixed by the emulator regardless of `interrupts`. The virtio devices are placed in
+//! the slots as `--virtio-slots` says and the slot `n` raises IRQ `n + 1`, which matches the DTB
+//! of QEMU virt machine. A minimal description is:
+//!
+//! ```text
+//! /dts-v1/;
+//! / {
+//!     #address-cells = <2>;
+//!     #size-cells = <2>;
+//!     memory@40000000 {
+//!         device_type = "memory";
+//!         reg = <0x0 0x40000000 0x0 0x8000000>;
+//!     };
+//!     serial@9000000 {
+//!         compatible = "ns16550a";
+//!         reg = <0x0 0x9000000 0x0 0x100>;
+//!     };
+//! };
+//! ```
+//!
+//! See: https://devicetree-specification.readthedocs.io/en/stable/flattened-format.html
+
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::ops::Range;
+
+/// The magic number at the start of a DTB.
+const FDT_MAGIC: u32 = 0xd00d_feed;
+/// The tokens of the structure block of a DTB.
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// A device which a machine can place on the bus.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum MachineDevice {
+    Rtc,
+    Clint,
+    Plic,
+    Uart,
+    SifiveUart,
+    SifivePwm0,
+    SifivePwm1,
+}
+
+/// A board layout.
+#[derive(Debug, Clone)]
+pub struct Machine {
+    /// The `model` property of the root node.
+    pub model: Option<String>,
+    /// The physical address range of the dram.
+    pub memory: Range<u64>,
+    /// The devices and their physical address ranges.
+    pub devices: Vec<(MachineDevice, Range<u64>)>,
+    /// The physical address ranges of the virtio-mmio slots in ascending order.
+    pub virtio: Vec<Range<u64>>,
+}
+
+/// A node of a device tree.
+#[derive(Debug, Default)]
+struct Node {
+    name: String,
+    properties: Vec<(String, Vec<u8>)>,
+    children: Vec<Node>,
+}
+
+impl Node {
+    fn property(&self, name: &str) -> Option<&[u8]> {
+        self.properties
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    /// Return the first string of a string list property.
+    fn string(&self, name: &str) -> Option<&str> {
+        let value = self.property(name)?;
+        let end = value.iter().position(|b| *b == 0).unwrap_or(value.len());
+        std::str::from_utf8(&value[..end]).ok()
+    }
+
+    /// Return a property holding a single cell.
+    fn cell(&self, name: &str) -> Option<u32> {
+        self.property(name)
+            .and_then(|value| value.try_into().ok())
+            .map(u32::from_be_bytes)
+    }
+
+    /// Return the address ranges in `reg`, whose entries are `address_cells` cells of an address
+    /// and `size_cells` cells of a size.
+    fn reg(&self, address_cells: u32, size_cells: u32) -> io::Result<Vec<Range<u64>>> {
+        let value = match self.property("reg") {
+            Some(value) => value,
+            None => return Ok(Vec::new()),
+        };
+        let entry = 4 * (address_cells + size_cells) as usize;
+        if address_cells > 2 || size_cells > 2 || entry == 0 || value.len() % entry != 0 {
+            return Err(invalid(format!("{}: unsupported reg", self.name)));
+        }
+        let number = |cells: &[u8]| {
+            cells.chunks(4).fold(0u64, |n, c| {
+                (n << 32) | u32::from_be_bytes(c.try_into().unwrap()) as u64
+            })
+        };
+        Ok(value
+            .chunks(entry)
+            .map(|e| {
+                let (addr, size) = e.split_at(4 * address_cells as usize);
+                let addr = number(addr);
+                addr..addr.saturating_add(number(size))
+            })
+            .collect())
+    }
+}
+
+impl Machine {
+    /// Read a machine description. A file starting with the DTB magic number is a blob and
+    /// others are sources.
+    pub fn load(path: &str) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        if bytes.starts_with(&FDT_MAGIC.to_be_bytes()) {
+            Self::parse_dtb(&bytes)
+        } else {
+            let text = String::from_utf8(bytes)
+                .map_err(|_| invalid(format!("{} is neither a DTB nor a DTS", path)))?;
+            Self::parse_dts(&text)
+        }
+    }
+
+    /// Parse a flattened device tree blob.
+    pub fn parse_dtb(bytes: &[u8]) -> io::Result<Self> {
+        let u32_at = |offset: usize| -> io::Result<u32> {
+            bytes
+                .get(offset..offset.saturating_add(4))
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or_else(|| invalid(format!("DTB is truncated at {:#x}", offset)))
+        };
+        let c_string = |offset: usize| -> io::Result<String> {
+            let rest = bytes
+                .get(offset..)
+                .ok_or_else(|| invalid(format!("DTB is truncated at {:#x}", offset)))?;
+            let end = rest
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or_else(|| invalid(format!("unterminated string at {:#x}", offset)))?;
+            Ok(String::from_utf8_lossy(&rest[..end]).into_owned())
+        };
+
+        if u32_at(0)? != FDT_MAGIC {
+            return Err(invalid("bad DTB magic number".into()));
+        }
+        let struct_offset = u32_at(8)? as usize;
+        let strings_offset = u32_at(12)? as usize;
+
+        // The stack of open nodes. The bottom one is a placeholder holding the root.
+        let mut stack = vec![Node::default()];
+        let mut offset = struct_offset;
+        loop {
+            let token = u32_at(offset)?;
+            offset += 4;
+            match token {
+                FDT_BEGIN_NODE => {
+                    let name = c_string(offset)?;
+                    offset += (name.len() + 4) & !3;
+                    stack.push(Node {
+                        name,
+                        ..Node::default()
+                    });
+                }
+                FDT_END_NODE => {
+                    if stack.len() < 2 {
+                        return Err(invalid("unbalanced DTB nodes".into()));
+                    }
+                    let node = stack.pop().unwrap();
+                    stack.last_mut().unwrap().children.push(node);
+                }
+                FDT_PROP => {
+                    let len = u32_at(offset)? as usize;
+                    let name = c_string(strings_offset + u32_at(offset + 4)? as usize)?;
+                    let value = bytes
+                        .get(offset + 8..offset + 8 + len)
+                        .ok_or_else(|| invalid(format!("DTB is truncated at {:#x}", offset)))?
+                        .to_vec();
+                    offset += 8 + ((len + 3) & !3);
+                    if stack.len() < 2 {
+                        return Err(invalid("a DTB property is outside nodes".into()));
+                    }
+                    stack.last_mut().unwrap().properties.push((name, value));
+                }
+                FDT_NOP => {}
+                FDT_END => break,
+                _ => return Err(invalid(format!("unknown DTB token {:#x}", token))),
+            }
+        }
+        match stack.pop().map(|mut top| top.children.pop()) {
+            Some(Some(root)) if stack.is_empty() => Self::from_tree(&root),
+            _ => Err(invalid("unbalanced DTB nodes".into())),
+        }
+    }
+
+    /// Parse a device tree source. Nodes, labels, properties of cells, strings and bytes are
+    /// supported, but includes, macros and expressions aren't. References to labels in cells
+    /// are read as 0.
+    pub fn parse_dts(text: &str) -> io::Result<Self> {
+        let mut parser = DtsParser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let mut root: Option<Node> = None;
+        loop {
+            parser.skip_space();
+            if parser.pos >= parser.text.len() {
+                break;
+            }
+            if parser.eat("/dts-v1/") {
+                parser.expect(b';')?;
+            } else if parser.eat("/memreserve/") {
+                parser.skip_until(b';');
+                parser.expect(b';')?;
+            } else if parser.eat("/") {
+                let node = parser.node("/".into())?;
+                // Later definitions of the root node extend earlier ones.
+                match root.as_mut() {
+                    Some(root) => {
+                        root.properties.extend(node.properties);
+                        root.children.extend(node.children);
+                    }
+                    None => root = Some(node),
+                }
+            } else {
+                return Err(parser.error("expected the root node"));
+            }
+        }
+        match root {
+            Some(root) => Self::from_tree(&root),
+            None => Err(invalid("DTS has no root node".into())),
+        }
+    }
+
+    fn from_tree(root: &Node) -> io::Result<Self> {
+        let mut machine = Self {
+            model: root.string("model").map(|s| s.to_string()),
+            memory: 0..0,
+            devices: Vec::new(),
+            virtio: Vec::new(),
+        };
+        machine.visit(root, 2, 1)?;
+        machine.virtio.sort_by_key(|range| range.start);
+        if machine.memory.is_empty() {
+            return Err(invalid("the machine has no memory node".into()));
+        }
+        Ok(machine)
+    }
+
+    /// Add the memory and the devices in `node` and its descendants. The cells are
+    /// `#address-cells` and `#size-cells` of the parent.
+    fn visit(&mut self, node: &Node, address_cells: u32, size_cells: u32) -> io::Result<()> {
+        let reg = node.reg(address_cells, size_cells)?;
+        if node.string("device_type") == Some("memory") {
+            match reg.as_slice() {
+                [range] if self.memory.is_empty() && !range.is_empty() => {
+                    self.memory = range.clone()
+                }
+                [] => {}
+                _ => {
+                    return Err(invalid(format!(
+                        "{}: only one contiguous memory range is supported",
+                        node.name
+                    )))
+                }
+            }
+        }
+        let count = |devices: &[(MachineDevice, Range<u64>)], kinds: &[MachineDevice]| {
+            devices.iter().filter(|(d, _)| kinds.contains(d)).count()
+        };
+        let device = match node.string("compatible") {
+            Some("ns16550a") | Some("ns16550") => Some(MachineDevice::Uart),
+            Some("sifive,uart0") => Some(MachineDevice::SifiveUart),
+            Some("google,goldfish-rtc") => Some(MachineDevice::Rtc),
+            Some("riscv,clint0") | Some("sifive,clint0") => Some(MachineDevice::Clint),
+            Some("riscv,plic0") | Some("sifive,plic-1.0.0") => Some(MachineDevice::Plic),
+            Some("sifive,pwm0") => {
+                let pwms = [MachineDevice::SifivePwm0, MachineDevice::SifivePwm1];
+                pwms.get(count(&self.devices, &pwms)).copied()
+            }
+            Some("virtio,mmio") => {
+                self.virtio.extend(reg.first().cloned());
+                None
+            }
+            _ => None,
+        };
+        if let (Some(device), Some(range)) = (device, reg.first()) {
+            if !self.devices.iter().any(|(d, _)| *d == device) {
+                self.devices.push((device, range.clone()));
+            }
+        }
+
+        let address_cells = node.cell("#address-cells").unwrap_or(2);
+        let size_cells = node.cell("#size-cells").unwrap_or(1);
+        for child in &node.children {
+            self.visit(child, address_cells, size_cells)?;
+        }
+        Ok(())
+    }
+
+    /// Return the address range of a device, if the machine has it.
+    pub fn device(&self, device: MachineDevice) -> Option<Range<u64>> {
+        self.devices
+            .iter()
+            .find(|(d, _)| *d == device)
+            .map(|(_, range)| range.clone())
+    }
+}
+
+/// A recursive descent parser of device tree sources.
+struct DtsParser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl DtsParser<'_> {
+    fn error(&self, message: &str) -> io::Error {
+        let line = self.text[..self.pos]
+            .iter()
+            .filter(|b| **b == b'\n')
+            .count()
+            + 1;
+        invalid(format!("DTS line {}: {}", line, message))
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.text.get(self.pos).copied()
+    }
+
+    /// Skip whitespace and comments.
+    fn skip_space(&mut self) {
+        loop {
+            match self.peek() {
+                Some(b) if b.is_ascii_whitespace() => self.pos += 1,
+                _ if self.text[self.pos..].starts_with(b"//") => self.skip_until(b'\n'),
+                _ if self.text[self.pos..].starts_with(b"/*") => {
+                    match self.text[self.pos..].windows(2).position(|w| w == b"*/") {
+                        Some(end) => self.pos += end + 2,
+                        None => self.pos = self.text.len(),
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn skip_until(&mut self, byte: u8) {
+        while self.peek().is_some_and(|b| b != byte) {
+            self.pos += 1;
+        }
+    }
+
+    /// Consume `s` if the text continues with it.
+    fn eat(&mut self, s: &str) -> bool {
+        self.skip_space();
+        if self.text[self.pos..].starts_with(s.as_bytes()) {
+            self.pos += s.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, byte: u8) -> io::Result<()> {
+        self.skip_space();
+        if self.peek() == Some(byte) {
+            self.pos += 1;
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected '{}'", byte as char)))
+        }
+    }
+
+    /// Read a node or property name, or a label.
+    fn name(&mut self) -> io::Result<String> {
+        self.skip_space();
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|b| b.is_ascii_alphanumeric() || b",._+-#@?".contains(&b))
+        {
+            self.pos += 1;
+        }
+        if start == self.pos {
+            return Err(self.error("expected a name"));
+        }
+        Ok(String::from_utf8_lossy(&self.text[start..self.pos]).into_owned())
+    }
+
+    /// Read the body of a node from its `{` to its `};`.
+    fn node(&mut self, name: String) -> io::Result<Node> {
+        let mut node = Node {
+            name,
+            ..Node::default()
+        };
+        self.expect(b'{')?;
+        loop {
+            if self.eat("}") {
+                self.expect(b';')?;
+                return Ok(node);
+            }
+            if self.eat("/delete-node/") || self.eat("/delete-property/") {
+                self.skip_until(b';');
+                self.expect(b';')?;
+                continue;
+            }
+            let mut name = self.name()?;
+            // Labels are only used by references, which aren't resolved.
+            while self.eat(":") {
+                name = self.name()?;
+            }
+            self.skip_space();
+            match self.peek() {
+                Some(b'{') => {
+                    let child = self.node(name)?;
+                    node.children.push(child);
+                }
+                Some(b'=') => {
+                    self.pos += 1;
+                    let value = self.value()?;
+                    node.properties.push((name, value));
+                }
+                Some(b';') => {
+                    self.pos += 1;
+                    node.properties.push((name, Vec::new()));
+                }
+                _ => return Err(self.error("expected a node or a property")),
+            }
+        }
+    }
+
+    /// Read a property value up to its `;`.
+    fn value(&mut self) -> io::Result<Vec<u8>> {
+        let mut value = Vec::new();
+        loop {
+            self.skip_space();
+            match self.peek() {
+                Some(b'<') => {
+                    self.pos += 1;
+                    while !self.eat(">") {
+                        let cell = self.cell()?;
+                        value.extend_from_slice(&cell.to_be_bytes());
+                    }
+                }
+                Some(b'"') => {
+                    self.pos += 1;
+                    while let Some(b) = self.peek() {
+                        self.pos += 1;
+                        match b {
+                            b'"' => break,
+                            b'\\' => {
+                                let escaped = self.peek().unwrap_or(b'\\');
+                                self.pos += 1;
+                                value.push(match escaped {
+                                    b'n' => b'\n',
+                                    b't' => b'\t',
+                                    other => other,
+                                });
+                            }
+                            _ => value.push(b),
+                        }
+                    }
+                    value.push(0);
+                }
+                Some(b'[') => {
+                    self.pos += 1;
+                    while !self.eat("]") {
+                        self.skip_space();
+                        let digits = self
+                            .text
+                            .get(self.pos..self.pos + 2)
+                            .and_then(|d| std::str::from_utf8(d).ok())
+                            .and_then(|d| u8::from_str_radix(d, 16).ok())
+                            .ok_or_else(|| self.error("bad byte string"))?;
+                        value.push(digits);
+                        self.pos += 2;
+                    }
+                }
+                _ => return Err(self.error("expected a property value")),
+            }
+            if self.eat(";") {
+                return Ok(value);
+            }
+            self.expect(b',')?;
+        }
+    }
+
+    /// Read a cell, a number or a reference to a label.
+    fn cell(&mut self) -> io::Result<u32> {
+        self.skip_space();
+        if self.eat("&") {
+            self.name()?;
+            return Ok(0);
+        }
+        let start = self.pos;
+        while self.peek().is_some_and(|b| b.is_ascii_alphanumeric()) {
+            self.pos += 1;
+        }
+        let s = std::str::from_utf8(&self.text[start..self.pos]).unwrap_or("");
+        let parsed = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+            Some(hex) => u32::from_str_radix(hex, 16),
+            None => s.parse::<u32>(),
+        };
+        parsed.map_err(|_| self.error(&format!("bad cell '{}'", s)))
+    }
+}
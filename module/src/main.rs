@@ -2,64 +2,892 @@ use std::env;
 use std::fs::File;
 use std::io;
 use std::io::prelude::*;
+use std::io::IsTerminal;
+use std::thread;
+use std::time::Duration;
 
+use rvemu::bench::{self, DEFAULT_BENCH_STEPS};
+use rvemu::bus::{ConsoleKind, MemFill};
+use rvemu::checkpoint::{Checkpoint, DEFAULT_CHECKPOINT_PREFIX};
+use rvemu::chrome_trace::ChromeTrace;
+use rvemu::compare::{self, DEFAULT_COMPARE_INTERVAL, DEFAULT_COMPARE_STEPS};
+use rvemu::coredump;
 use rvemu::cpu::Cpu;
-use rvemu::trap::Trap;
+use rvemu::elf::SymbolTable;
+use rvemu::emulator::{Emulator, Observer};
+use rvemu::entropy::Entropy;
+use rvemu::explain::Explainer;
+use rvemu::extensions::Extensions;
+use rvemu::filestore::Filestore;
+use rvemu::goldfish_rtc::RtcSetting;
+use rvemu::heap_checker::HeapChecker;
+use rvemu::history::{History, DEFAULT_HISTORY_LENGTH};
+use rvemu::jtag::RemoteBitbang;
+use rvemu::level::{Level, MAX_LEVEL};
+use rvemu::loader::{Image, ImageFormat};
+use rvemu::machine::Machine;
+use rvemu::mux::Mux;
+use rvemu::ntrace::NTrace;
+use rvemu::panic_detector::{PanicAction, PanicDetector};
+use rvemu::pflash::Pflash;
+use rvemu::pipeline::Pipeline;
+use rvemu::plugin::PluginDevice;
+use rvemu::qmp::{Control, Qmp};
+use rvemu::script::Script;
+use rvemu::state_dump::StateDump;
+use rvemu::stats::{IntervalStats, DEFAULT_STATS_INTERVAL};
+use rvemu::strace::{Strace, SyscallAbi};
+use rvemu::timeline::Timeline;
+use rvemu::torture;
+use rvemu::trace_file::TraceFile;
+use rvemu::trap::{Exception, FatalPolicy, Interrupt};
+use rvemu::tui::Tui;
+
+/// The time the host thread sleeps at each step while the hart waits in wfi, with --wfi-sleep.
+const WFI_SLEEP: Duration = Duration::from_millis(1);
+
+const USAGE: &str = "Usage: rvemu-for-book [options] <filename> <(option) image>
+       rvemu-for-book bench [--max-steps <n>] <image>...
+
+The kernel is a raw binary loaded at the start of the dram (0x80000000 by default), an ELF
+file, an Intel HEX file (.hex) or an S-record file (.srec, .s19, .s28, .s37).
+
+Trace files whose names end with .zst are compressed by the zstd command.
+
+bench runs bare-metal Dhrystone and CoreMark images, which exit via the emulator control
+device, checks their reports and prints the host MIPS and the guest-reported scores. An image
+may execute up to --max-steps instructions (default: 20000000000).
+
+Options:
+    --console <ns16550|sifive>  Select a console device (default: ns16550)
+    --machine <file>            Place the memory and devices as declared in a DTB or DTS file
+    --virtio-slots <dev>[,<dev>...]
+                                Place virtio devices in the virtio-mmio slots in order, empty
+                                for a free slot (default: virtio,vsock,snd)
+    --protect <start>-<end>     Make a physical range inaccessible to S-mode and U-mode
+    --rom <start>-<end>         Make a physical range read-only
+    --explain                   Explain the fields, the meaning and the register changes of
+                                each executed instruction
+    --strace <xv6|linux>        Log ecalls as SBI calls from S-mode and system calls of the OS
+                                from U-mode
+    --timeline <n>              Keep the last n traps and xRETs and print them on exit
+    --trace-mmio <dev>[,<dev>...]
+                                Log loads and stores to devices, e.g. uart,virtio,plic,clint
+    --trace-log <file>          Write the MMIO trace and the instruction trace started via the
+                                emulator control device to a file instead of stderr
+    --trace-events <file>       Write guest execution in the Trace Event JSON format
+    --ntrace <file>             Write a branch trace in the N-Trace message format
+    --pipeline <file>           Write a five-stage pipeline diagram and its statistics
+    --stats-csv <file>          Append interval statistics to a CSV file
+    --stats-interval <n>        Write statistics every n instructions (default: 1000000)
+    --qmp <path>                Listen for QMP-like JSON commands on a Unix socket
+    --mux                       Share the terminal between the console and a monitor, switched
+                                by Ctrl-A c (Ctrl-A h for help)
+    --checkpoint <prefix>       Write a snapshot to <prefix>-<n>.snap on SIGUSR1
+                                (default: rvemu-checkpoint)
+    --history <n>               Keep the last n executed instructions, printed on a fatal
+                                exception and in a state dump on SIGQUIT or C-a s (default: 32)
+    --on-panic <warn|stop>      Detect guest kernel panics on the console and by panic symbols,
+                                and report them or stop with status 1
+    --heap-check                Report use after free, overflows and invalid frees of guest heap
+                                blocks by hooking malloc/free or kalloc/kfree (needs symbols)
+    --strict-atomics            Raise misaligned exceptions for AMOs, LRs and SCs, and report
+                                SCs which don't pair with the last LR in address and size
+    --wfi-sleep                 Sleep the host thread while the hart waits for an interrupt in
+                                wfi instead of spinning
+    --fatal <stuck|access|never>
+                                Select exceptions which stop the emulator (default: stuck)
+    --core <file>               Write the registers and the dram to an ELF core file when a
+                                fatal exception stops the emulator
+    --isa <string>              Execute only the extensions in an ISA string, e.g.
+                                rv64ima_zicsr_zifencei, and raise illegal instruction exceptions
+                                for the others (default: all implemented extensions)
+    --level <n>                 Enable only the subsystems of the book's steps 1 to n (n <= 10)
+    --seed <n>                  Make a run reproducible by seeding all nondeterministic inputs
+    --memfill <zero|pattern|random(<seed>)>
+                                Fill the dram except the loaded images with 0xdeadbeef or random
+                                bytes to catch reads of uninitialized memory (default: zero)
+    --rtc <base=<time>|offset=<seconds>>
+                                Start the RTC at a time (YYYY-MM-DDTHH:MM:SS in UTC) or shift
+                                the host time
+    --pflash <file>             Map a persistent parallel flash at 0x20000000 backed by a file
+    --filestore <dir>           Map a device at 0x1002000 which lets a guest write memory to files
+                                in a host directory
+    --device <path>@<base>[,irq=<n>][,args=<s>]
+                                Map a device from a plugin shared library
+    --compare-runs <n>          Run the kernel n times with the same seed and console input from
+                                stdin, and check that the states every 1000000 instructions match
+    --compare-steps <n>         Stop each run of --compare-runs after n instructions
+                                (default: 10000000)
+    --torture <n>               Check n random programs against a reference model and exit
+    --tui                       Start the terminal UI debugger
+    --script <file>             Run a debugger script instead of the interactive loop
+    --symbols <elf>             Resolve symbol names in the debugger with an ELF file of the kernel
+    --jtag <host:port>          Serve the Debug Module to OpenOCD over the remote_bitbang protocol
+    --vsock <path>              Connect host programs to guest vsock ports via Unix sockets
+    --sound-wav <file>          Write audio played on the virtio sound device to a WAV file";
 
 fn read_file(filename: &str) -> io::Result<Vec<u8>> {
     let mut file = File::open(filename)?;
     let mut binary = Vec::new();
     file.read_to_end(&mut binary)?;
-    return Ok(binary);
+    Ok(binary)
+}
+
+/// Parse a number in decimal or in hexadecimal with a `0x` prefix.
+fn parse_u64(s: &str) -> u64 {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse::<u64>(),
+    };
+    parsed.unwrap_or_else(|_| panic!("{}", USAGE))
+}
+
+/// Parse a range written as `<start>-<end>`.
+fn parse_range(s: &str) -> (u64, u64) {
+    match s.split_once('-') {
+        Some((start, end)) => (parse_u64(start), parse_u64(end)),
+        None => panic!("{}", USAGE),
+    }
+}
+
+/// Write a line of the MMIO or instruction trace to the trace log, or to stderr without it.
+fn write_trace_log(trace_log: &mut Option<TraceFile>, line: &str) -> io::Result<()> {
+    match trace_log {
+        Some(out) => writeln!(out, "{}", line),
+        None => {
+            eprintln!("{}", line);
+            Ok(())
+        }
+    }
+}
+
+/// The tools which look at each instruction of the main loop.
+struct Tools {
+    trace_log: Option<TraceFile>,
+    tracer: Option<ChromeTrace>,
+    ntrace: Option<NTrace>,
+    pipeline: Option<Pipeline>,
+    stats: Option<IntervalStats>,
+    explainer: Option<Explainer>,
+    heap_checker: Option<HeapChecker>,
+    strace: Option<Strace>,
+    jtag: Option<RemoteBitbang>,
+    history: History,
+    /// The first error writing an output, returned after the step.
+    error: Option<io::Error>,
+}
+
+impl Tools {
+    fn check(&mut self, result: io::Result<()>) {
+        if let Err(e) = result {
+            self.error.get_or_insert(e);
+        }
+    }
+
+    /// Write the trap of `cause` to the traces.
+    fn trap(&mut self, cpu: &Cpu, cause: String) {
+        if let Some(tracer) = self.tracer.as_mut() {
+            let result = tracer.trap(cpu, &cause);
+            self.check(result);
+        }
+        if let Some(ntrace) = self.ntrace.as_mut() {
+            let result = ntrace.trap(cpu);
+            self.check(result);
+        }
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            pipeline.trap();
+        }
+    }
+}
+
+impl Observer for Tools {
+    fn before_execute(&mut self, cpu: &mut Cpu, pc: u64, inst: Option<u64>) {
+        self.history.record(pc, inst.map(|inst| inst as u32));
+        if let Some(explainer) = self.explainer.as_mut() {
+            explainer.before(cpu, inst.unwrap_or(0));
+        }
+        if let Some(checker) = self.heap_checker.as_mut() {
+            if let Some(report) = checker.check_call(cpu) {
+                eprintln!("{}", report);
+            }
+            if let Some(report) = inst.and_then(|inst| checker.check_access(cpu, pc, inst)) {
+                eprintln!("{}", report);
+            }
+        }
+        if cpu.bus.emuctl.is_tracing() {
+            let line = format!("[trace] pc={:#018x} inst={:#010x}", pc, inst.unwrap_or(0));
+            let result = write_trace_log(&mut self.trace_log, &line);
+            self.check(result);
+        }
+    }
+
+    fn executed(&mut self, cpu: &mut Cpu, pc: u64, inst: u64) {
+        if let Some(explainer) = self.explainer.as_ref() {
+            eprintln!("{}", explainer.explain(cpu, pc, Some(inst), None));
+        }
+        if let Some(line) = self.strace.as_mut().and_then(|strace| strace.returned(cpu)) {
+            eprintln!("{}", line);
+        }
+        if let Some(tracer) = self.tracer.as_mut() {
+            let result = tracer.instruction(cpu, pc, inst);
+            self.check(result);
+        }
+        if let Some(ntrace) = self.ntrace.as_mut() {
+            let result = ntrace.instruction(cpu, pc, inst);
+            self.check(result);
+        }
+        if let Some(pipeline) = self.pipeline.as_mut() {
+            let result = pipeline.instruction(cpu, pc, inst);
+            self.check(result);
+        }
+    }
+
+    fn exception_raised(
+        &mut self,
+        cpu: &mut Cpu,
+        pc: u64,
+        inst: Option<u64>,
+        exception: &Exception,
+    ) -> bool {
+        if let Some(explainer) = self.explainer.as_ref() {
+            eprintln!("{}", explainer.explain(cpu, pc, inst, Some(exception)));
+        }
+        if let Some(line) = self
+            .strace
+            .as_mut()
+            .and_then(|strace| strace.ecall(cpu, pc, exception))
+        {
+            eprintln!("{}", line);
+        }
+        // Enter debug mode instead of the trap if the debugger set a breakpoint.
+        match (exception, self.jtag.as_mut()) {
+            (Exception::Breakpoint, Some(jtag)) => jtag.ebreak(cpu),
+            _ => false,
+        }
+    }
+
+    fn exception_taken(&mut self, cpu: &mut Cpu, exception: &Exception) {
+        self.trap(cpu, format!("{:?}", exception));
+    }
+
+    fn interrupt_taken(&mut self, cpu: &mut Cpu, interrupt: &Interrupt) {
+        self.trap(cpu, format!("{:?}", interrupt));
+        if let Some(stats) = self.stats.as_mut() {
+            stats.interrupt();
+        }
+    }
+}
+
+/// Run the benchmark images in `args` and return the exit status, 1 if any of them failed.
+fn bench(args: &[String]) -> io::Result<i32> {
+    let mut images = Vec::new();
+    let mut max_steps = DEFAULT_BENCH_STEPS;
+    let mut options = args.iter();
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--max-steps" => match options.next() {
+                Some(n) => max_steps = parse_u64(n),
+                None => panic!("{}", USAGE),
+            },
+            _ => images.push(arg),
+        }
+    }
+    if images.is_empty() {
+        panic!("{}", USAGE);
+    }
+
+    let mut failed = false;
+    for path in images {
+        let result = bench::run(&Image::load(path, None)?, max_steps)?;
+        println!("bench: {}: {}", path, result);
+        failed |= !result.passed();
+    }
+    Ok(failed as i32)
 }
 
 fn main() -> io::Result<()> {
     let args: Vec<String> = env::args().collect();
+    if args.get(1).map(|s| s.as_str()) == Some("bench") {
+        std::process::exit(bench(&args[2..])?);
+    }
 
-    if (args.len() != 2) && (args.len() != 3) {
-        panic!("Usage: rvemu-for-book <filename> <(option) image>");
+    let mut files = Vec::new();
+    let mut console = None;
+    let mut machine_path = None;
+    let mut protected = Vec::new();
+    let mut rom = Vec::new();
+    let mut explain = false;
+    let mut strace_abi = None;
+    let mut timeline_len = None;
+    let mut trace_mmio = None;
+    let mut virtio_slots = None;
+    let mut trace_events = None;
+    let mut trace_log_path = None;
+    let mut ntrace_path = None;
+    let mut pipeline_path = None;
+    let mut stats_csv = None;
+    let mut stats_interval = DEFAULT_STATS_INTERVAL;
+    let mut qmp_path = None;
+    let mut mux = false;
+    let mut checkpoint_prefix = DEFAULT_CHECKPOINT_PREFIX;
+    let mut history_length = DEFAULT_HISTORY_LENGTH;
+    let mut tui = false;
+    let mut devices = Vec::new();
+    let mut pflash_path = None;
+    let mut filestore_dir = None;
+    let mut level = None;
+    let mut seed = None;
+    let mut rtc = None;
+    let mut memfill = None;
+    let mut torture_count = None;
+    let mut compare_runs = None;
+    let mut compare_steps = DEFAULT_COMPARE_STEPS;
+    let mut fatal_policy = FatalPolicy::Stuck;
+    let mut core_path = None;
+    let mut heap_check = false;
+    let mut wfi_sleep = false;
+    let mut strict_atomics = false;
+    let mut extensions = None;
+    let mut panic_action = None;
+    let mut symbols_path = None;
+    let mut script_path = None;
+    let mut jtag_addr = None;
+    let mut vsock_path = None;
+    let mut sound_wav = None;
+    let mut options = args.iter().skip(1);
+    while let Some(arg) = options.next() {
+        match arg.as_str() {
+            "--console" => {
+                console = match options.next().map(|s| s.as_str()) {
+                    Some("ns16550") => Some(ConsoleKind::Ns16550),
+                    Some("sifive") => Some(ConsoleKind::Sifive),
+                    _ => panic!("{}", USAGE),
+                }
+            }
+            "--machine" => match options.next() {
+                Some(path) => machine_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--virtio-slots" => match options.next() {
+                Some(devices) => virtio_slots = Some(devices),
+                None => panic!("{}", USAGE),
+            },
+            "--protect" => match options.next() {
+                Some(range) => protected.push(parse_range(range)),
+                None => panic!("{}", USAGE),
+            },
+            "--rom" => match options.next() {
+                Some(range) => rom.push(parse_range(range)),
+                None => panic!("{}", USAGE),
+            },
+            "--explain" => explain = true,
+            "--strace" => match options.next().map(|s| SyscallAbi::parse(s)) {
+                Some(Some(abi)) => strace_abi = Some(abi),
+                _ => panic!("{}", USAGE),
+            },
+            "--timeline" => match options.next() {
+                Some(n) => timeline_len = Some(parse_u64(n) as usize),
+                None => panic!("{}", USAGE),
+            },
+            "--trace-mmio" => match options.next() {
+                Some(devices) => trace_mmio = Some(devices),
+                None => panic!("{}", USAGE),
+            },
+            "--trace-log" => match options.next() {
+                Some(path) => trace_log_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--trace-events" => match options.next() {
+                Some(path) => trace_events = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--ntrace" => match options.next() {
+                Some(path) => ntrace_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--pipeline" => match options.next() {
+                Some(path) => pipeline_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--stats-csv" => match options.next() {
+                Some(path) => stats_csv = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--stats-interval" => match options.next() {
+                Some(n) => stats_interval = parse_u64(n).max(1),
+                None => panic!("{}", USAGE),
+            },
+            "--qmp" => match options.next() {
+                Some(path) => qmp_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--history" => match options.next() {
+                Some(n) => history_length = parse_u64(n) as usize,
+                None => panic!("{}", USAGE),
+            },
+            "--checkpoint" => match options.next() {
+                Some(prefix) => checkpoint_prefix = prefix,
+                None => panic!("{}", USAGE),
+            },
+            "--strict-atomics" => strict_atomics = true,
+            "--heap-check" => heap_check = true,
+            "--wfi-sleep" => wfi_sleep = true,
+            "--fatal" => {
+                fatal_policy = match options.next().map(|s| s.as_str()) {
+                    Some("stuck") => FatalPolicy::Stuck,
+                    Some("access") => FatalPolicy::AccessFault,
+                    Some("never") => FatalPolicy::Never,
+                    _ => panic!("{}", USAGE),
+                }
+            }
+            "--core" => match options.next() {
+                Some(path) => core_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--on-panic" => match options.next().map(|s| PanicAction::parse(s)) {
+                Some(Some(action)) => panic_action = Some(action),
+                _ => panic!("{}", USAGE),
+            },
+            "--isa" => match options.next().map(|isa| Extensions::parse(isa)) {
+                Some(Ok(isa)) => extensions = Some(isa),
+                Some(Err(e)) => panic!("{}\n{}", e, USAGE),
+                None => panic!("{}", USAGE),
+            },
+            "--level" => match options.next().map(|n| parse_u64(n)) {
+                Some(n) if n <= MAX_LEVEL as u64 => match Level::new(n as u8) {
+                    Some(step) => level = Some(step),
+                    None => panic!("{}", USAGE),
+                },
+                _ => panic!("{}", USAGE),
+            },
+            "--seed" => match options.next() {
+                Some(n) => seed = Some(parse_u64(n)),
+                None => panic!("{}", USAGE),
+            },
+            "--memfill" => match options.next().map(|s| MemFill::parse(s)) {
+                Some(Ok(fill)) => memfill = Some(fill),
+                Some(Err(e)) => panic!("{}\n{}", e, USAGE),
+                None => panic!("{}", USAGE),
+            },
+            "--rtc" => match options.next().map(|s| RtcSetting::parse(s)) {
+                Some(Ok(setting)) => rtc = Some(setting),
+                Some(Err(e)) => panic!("{}\n{}", e, USAGE),
+                None => panic!("{}", USAGE),
+            },
+            "--compare-runs" => match options.next() {
+                Some(n) => compare_runs = Some(parse_u64(n)),
+                None => panic!("{}", USAGE),
+            },
+            "--compare-steps" => match options.next() {
+                Some(n) => compare_steps = parse_u64(n),
+                None => panic!("{}", USAGE),
+            },
+            "--torture" => match options.next() {
+                Some(n) => torture_count = Some(parse_u64(n)),
+                None => panic!("{}", USAGE),
+            },
+            "--filestore" => match options.next() {
+                Some(dir) => filestore_dir = Some(dir),
+                None => panic!("{}", USAGE),
+            },
+            "--pflash" => match options.next() {
+                Some(path) => pflash_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--device" => match options.next() {
+                Some(spec) => devices.push(spec),
+                None => panic!("{}", USAGE),
+            },
+            "--mux" => mux = true,
+            "--tui" => tui = true,
+            "--script" => match options.next() {
+                Some(path) => script_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--symbols" => match options.next() {
+                Some(path) => symbols_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--jtag" => match options.next() {
+                Some(addr) => jtag_addr = Some(addr),
+                None => panic!("{}", USAGE),
+            },
+            "--vsock" => match options.next() {
+                Some(path) => vsock_path = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            "--sound-wav" => match options.next() {
+                Some(path) => sound_wav = Some(path),
+                None => panic!("{}", USAGE),
+            },
+            _ => files.push(arg),
+        }
+    }
+
+    if let Some(count) = torture_count {
+        let seed = seed.unwrap_or_else(|| Entropy::from_host().next_u64());
+        println!("torture: seed {}, {} programs", seed, count);
+        let mismatches = torture::run(seed, count, torture::DEFAULT_PROGRAM_LEN);
+        for mismatch in &mismatches {
+            println!("{}", mismatch);
+        }
+        println!("torture: {} of {} programs differ", mismatches.len(), count);
+        std::process::exit((!mismatches.is_empty()) as i32);
+    }
+
+    if (files.len() != 1) && (files.len() != 2) {
+        panic!("{}", USAGE);
     }
-    let kernel = read_file(&args[1])?;
+    let machine = match machine_path {
+        Some(path) => Some(Machine::load(path)?),
+        None => None,
+    };
+    let kernel = Image::load(files[0], machine.as_ref())?;
 
     let mut disk_image = Vec::new();
-    if args.len() == 3 {
-        disk_image = read_file(&args[2])?;
+    if files.len() == 2 {
+        disk_image = read_file(files[1])?;
+    }
+
+    // Runs compared with each other must be seeded the same.
+    let seed = match (compare_runs, seed) {
+        (Some(_), None) => Some(Entropy::from_host().next_u64()),
+        _ => seed,
+    };
+    // A CPU is built for each run of --compare-runs, so building it doesn't consume the options.
+    let build_cpu = || -> io::Result<Cpu> {
+        let mut cpu = Cpu::new(Vec::new(), disk_image.clone());
+        let mut kernel = kernel.clone();
+        if let Some(machine) = &machine {
+            cpu.set_machine(machine);
+            if kernel.format == ImageFormat::Raw {
+                kernel.relocate(machine.memory.start);
+            }
+        }
+        kernel.load_into(&mut cpu)?;
+        if let Some(console) = console {
+            cpu.bus.set_console(console);
+        }
+        if let Some(devices) = virtio_slots {
+            if let Err(e) = cpu.bus.set_virtio_slots(devices) {
+                panic!("{}\n{}", e, USAGE);
+            }
+        }
+        cpu.fatal_policy = fatal_policy;
+        cpu.strict_atomics = strict_atomics;
+        if let Some(extensions) = extensions {
+            cpu.extensions = extensions;
+        }
+        if let Some(n) = timeline_len {
+            cpu.timeline = Timeline::new(n);
+        }
+        if let Some(seed) = seed {
+            cpu.bus.set_seed(seed);
+        }
+        if let Some(setting) = rtc {
+            cpu.bus.rtc.set_clock(setting);
+        }
+        if let Some(fill) = memfill {
+            cpu.bus.set_memfill(fill);
+        }
+        for &(start, end) in protected.iter() {
+            cpu.protect(start, end);
+        }
+        for &(start, end) in rom.iter() {
+            cpu.bus.add_rom(start, end);
+        }
+        if let Some(path) = pflash_path {
+            cpu.bus.set_pflash(Pflash::open(path)?);
+        }
+        if let Some(dir) = filestore_dir {
+            cpu.bus.set_filestore(Filestore::new(dir)?);
+        }
+        for spec in devices.iter() {
+            cpu.bus.add_plugin(PluginDevice::load_spec(spec)?);
+        }
+        if let Some(path) = vsock_path {
+            cpu.bus.vsock.listen(path)?;
+        }
+        if let Some(path) = sound_wav {
+            cpu.bus.snd.set_wav_output(path);
+        }
+        if let Some(level) = level {
+            cpu.set_level(level);
+        }
+        cpu.save_boot_state();
+        Ok(cpu)
+    };
+    if let Some(runs) = compare_runs {
+        // Every run reads the same console input, which is empty on a terminal.
+        let mut input = Vec::new();
+        if !io::stdin().is_terminal() {
+            io::stdin().read_to_end(&mut input)?;
+        }
+        println!(
+            "compare: seed {}, {} runs of up to {} instructions",
+            seed.unwrap_or_default(),
+            runs,
+            compare_steps
+        );
+        let build = || -> io::Result<Cpu> {
+            let mut cpu = build_cpu()?;
+            cpu.bus
+                .detach_console(Box::new(io::Cursor::new(input.clone())));
+            Ok(cpu)
+        };
+        let mismatches = compare::run(build, runs, compare_steps, DEFAULT_COMPARE_INTERVAL)?;
+        for mismatch in &mismatches {
+            println!("{}", mismatch);
+        }
+        println!("compare: {} of {} runs differ", mismatches.len(), runs);
+        std::process::exit((!mismatches.is_empty()) as i32);
     }
+    let mut cpu = build_cpu()?;
+
+    if tui {
+        let mut tui = Tui::attach(&mut cpu);
+        if let Some(path) = symbols_path {
+            tui.set_symbols(SymbolTable::load(path)?);
+        }
+        let mut emu = Emulator::new(cpu);
+        tui.run(&mut emu)?;
+        emu.cpu.dump_registers();
+        return Ok(());
+    }
+
+    if let Some(path) = script_path {
+        let mut script = Script::load(path)?;
+        if let Some(path) = symbols_path {
+            script.set_symbols(SymbolTable::load(path)?);
+        }
+        let mut emu = Emulator::new(cpu);
+        script.run(&mut emu)?;
+        emu.cpu.dump_registers();
+        return Ok(());
+    }
+
+    let trace_log = match trace_log_path {
+        Some(path) => Some(TraceFile::create(path)?),
+        None => None,
+    };
+    let tracer = match trace_events {
+        Some(path) => Some(ChromeTrace::create(path, &cpu)?),
+        None => None,
+    };
+    let ntrace = match ntrace_path {
+        Some(path) => Some(NTrace::create(path, &cpu)?),
+        None => None,
+    };
+    let pipeline = match pipeline_path {
+        Some(path) => Some(Pipeline::create(path)?),
+        None => None,
+    };
+    let stats = match stats_csv {
+        Some(path) => Some(IntervalStats::create(path, stats_interval, &cpu)?),
+        None => None,
+    };
+
+    let mut qmp = match qmp_path {
+        Some(path) => Some(Qmp::bind(path)?),
+        None => None,
+    };
+
+    let mut mux = match mux {
+        true => Some(Mux::attach(
+            &mut cpu,
+            Box::new(io::stdin()),
+            Box::new(io::stdout()),
+        )),
+        false => None,
+    };
+
+    let jtag = match jtag_addr {
+        Some(addr) => Some(RemoteBitbang::bind(addr)?),
+        None => None,
+    };
+
+    let strace = strace_abi.map(Strace::new);
+    // Symbols of an ELF kernel are used unless others are given.
+    let load_symbols = || -> io::Result<Option<SymbolTable>> {
+        Ok(match (symbols_path, kernel.format) {
+            (Some(path), _) => Some(SymbolTable::load(path)?),
+            (None, ImageFormat::Elf) => SymbolTable::load(files[0]).ok(),
+            _ => None,
+        })
+    };
+    let mut panic_detector = match panic_action {
+        Some(action) => {
+            let mut detector = PanicDetector::new(action);
+            cpu.bus.add_serial_backend(detector.console_tap());
+            if let Some(symbols) = load_symbols()? {
+                detector.set_symbols(symbols);
+            }
+            Some(detector)
+        }
+        None => None,
+    };
+    let heap_checker = match heap_check {
+        true => {
+            let checker = HeapChecker::new(load_symbols()?.unwrap_or_default());
+            match checker.hooked().as_slice() {
+                [] => eprintln!("heap: no allocator functions in the symbols"),
+                hooked => eprintln!("heap: hooked {}", hooked.join(", ")),
+            }
+            Some(checker)
+        }
+        false => None,
+    };
+    let mut panicked = false;
+    let explainer = match explain {
+        true => Some(Explainer::new()),
+        false => None,
+    };
+
+    let mut checkpoint = Checkpoint::install(checkpoint_prefix);
+    let mut state_dump = StateDump::install();
+    let mut tools = Tools {
+        trace_log,
+        tracer,
+        ntrace,
+        pipeline,
+        stats,
+        explainer,
+        heap_checker,
+        strace,
+        jtag,
+        history: History::new(history_length),
+        error: None,
+    };
 
-    let mut cpu = Cpu::new(kernel, disk_image);
+    // Plugin devices are traced by the name "plugin", so they must be mapped before.
+    if let Some(devices) = trace_mmio {
+        if let Err(e) = cpu.bus.set_mmio_trace(devices) {
+            panic!("{}\n{}", e, USAGE);
+        }
+    }
 
+    let mut emu = Emulator::new(cpu);
     loop {
-        // 1. Fetch.
-        let inst = match cpu.fetch() {
-            Ok(inst) => inst,
-            Err(_exception) => 0, // Place 0 if fetch() fails. It will break out of the loop.
+        if let Some(qmp) = qmp.as_mut() {
+            if qmp.poll(&mut emu.cpu) == Control::Quit {
+                break;
+            }
+        }
+        if let Some(mux) = mux.as_mut() {
+            if mux.poll(&mut emu.cpu) == Control::Quit {
+                break;
+            }
+        }
+        if let Some(jtag) = tools.jtag.as_mut() {
+            jtag.poll(&mut emu.cpu)?;
+        }
+        // Keep running even if the checkpoint can't be written.
+        match checkpoint.poll(&emu.cpu) {
+            Ok(Some(path)) => eprintln!("checkpoint: wrote {}", path),
+            Ok(None) => {}
+            Err(e) => eprintln!("checkpoint: {}", e),
+        }
+        if let Some(dump) = state_dump.poll(&emu.cpu, &tools.history) {
+            eprint!("{}", dump);
+        }
+
+        let executed = match emu.step_with(&mut tools) {
+            Some(executed) => executed,
+            None => break,
         };
+        if let Some(e) = tools.error.take() {
+            return Err(e);
+        }
+        let stopped = emu.is_stopped();
+        let cpu = &mut emu.cpu;
+        for access in cpu.bus.take_mmio_log() {
+            let line = format!("mmio: [{:#x}] {}", executed.pc, access);
+            write_trace_log(&mut tools.trace_log, &line)?;
+        }
+        // The emulator stops by a fatal exception unless a guest requested exit.
+        let fatal = stopped && cpu.bus.emuctl.exit_code().is_none();
+        if let Some(exception) = executed.exception.as_ref().filter(|_| fatal) {
+            if let Some(trap_loop) = cpu.trap_loop {
+                eprintln!("{}", trap_loop);
+            }
+            eprint!("{}", tools.history);
+            if let Some(path) = core_path {
+                coredump::write_core(path, cpu, executed.pc, exception)?;
+                eprintln!("wrote a core file to {}", path);
+            }
+            break;
+        }
 
-        // 2. Add 4 to the program counter.
-        cpu.pc += 4;
-
-        // 3. Decode.
-        // 4. Execute.
-        match cpu.execute(inst) {
-            Ok(_) => {}
-            Err(exception) => {
-                exception.take_trap(&mut cpu);
-                // Break the loop if a fatal error occurs.
-                if exception.is_fatal() {
+        if wfi_sleep && cpu.is_waiting() {
+            thread::sleep(WFI_SLEEP);
+        }
+
+        if let Some(stats) = tools.stats.as_mut() {
+            stats.instruction(cpu)?;
+        }
+
+        if let Some(unpaired_sc) = cpu.unpaired_sc.take() {
+            eprintln!("{}", unpaired_sc);
+        }
+        if let Some(sc_livelock) = cpu.sc_livelock.take() {
+            eprintln!("{}", sc_livelock);
+        }
+
+        if let Some(detector) = panic_detector.as_mut() {
+            if let Some(report) = detector.check(cpu) {
+                eprintln!("{}", report);
+                if detector.action == PanicAction::Stop {
+                    eprint!("{}", detector.context());
+                    panicked = true;
                     break;
                 }
             }
         }
 
-        match cpu.check_pending_interrupt() {
-            Some(interrupt) => interrupt.take_trap(&mut cpu),
-            None => {}
+        // Stop if a guest requested it via the emulator control device.
+        if stopped {
+            break;
         }
     }
 
+    if let Some(trace_log) = tools.trace_log {
+        trace_log.finish()?;
+    }
+    if let Some(tracer) = tools.tracer {
+        tracer.finish()?;
+    }
+    if let Some(checker) = tools.heap_checker {
+        let (blocks, bytes) = checker.live();
+        eprintln!(
+            "heap: {} blocks of {} bytes are live on exit",
+            blocks, bytes
+        );
+    }
+    if let Some(ntrace) = tools.ntrace {
+        ntrace.finish()?;
+    }
+    if let Some(pipeline) = tools.pipeline {
+        eprintln!("{}", pipeline.finish()?);
+    }
+    let mut cpu = emu.cpu;
+    if cpu.timeline.is_enabled() {
+        eprint!("{}", cpu.timeline.dump());
+    }
+
     cpu.dump_registers();
     println!("-----------------------------------------------------------------------------------------------------------");
     cpu.dump_csrs();
 
+    // Write the flash back before exit(), which doesn't run destructors.
+    if let Some(pflash) = cpu.bus.pflash.as_mut() {
+        pflash.flush()?;
+    }
+    if panicked {
+        std::process::exit(1);
+    }
+    if let Some(code) = cpu.bus.emuctl.exit_code() {
+        std::process::exit(code as i32);
+    }
     Ok(())
 }
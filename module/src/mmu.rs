@@ -1,9 +1,29 @@
-use crate::cpu::Cpu;
+use std::fmt;
+
+use crate::cpu::{Cpu, Mode, MSTATUS, MSTATUS_MPRV, MSTATUS_MXR, MSTATUS_SUM, SATP};
 use crate::trap::Exception;
 
 /// The page size (4 KiB) for the virtual dram system.
 pub const PAGE_SIZE: u64 = 4096;
 
+/// Return the number of levels of the page table of the addressing mode which `satp` selects, or
+/// None if paging is off or the mode isn't supported. SV39 (MODE=8) has 3 levels, SV48 (MODE=9)
+/// has 4 and SV57 (MODE=10) has 5.
+pub fn paging_levels(satp: u64) -> Option<usize> {
+    match satp >> 60 {
+        8 => Some(3),
+        9 => Some(4),
+        10 => Some(5),
+        _ => None,
+    }
+}
+
+/// Return the index of the PTE in the page table of `level` for a virtual address, i.e.
+/// va.vpn[level]. Each VPN field is 9 bits wide in SV39, SV48 and SV57.
+fn vpn(addr: u64, level: usize) -> u64 {
+    (addr >> (12 + 9 * level)) & 0x1ff
+}
+
 /// Access type that is used in the virtual address translation process. It decides which exception
 /// should raises (InstructionPageFault, LoadPageFault or StoreAMOPageFault).
 #[derive(Debug, PartialEq, PartialOrd)]
@@ -16,22 +36,42 @@ pub enum AccessType {
     Store,
 }
 
+/// Return the privilege mode which an access of `access_type` is translated and checked in.
+pub(crate) fn access_mode(cpu: &Cpu, access_type: &AccessType) -> Mode {
+    // "When MPRV=1, load and store memory addresses are translated and protected, and endianness
+    // is applied, as though the current privilege mode were set to MPP. Instruction
+    // address-translation and protection are unaffected by the setting of MPRV."
+    let mstatus = cpu.load_csr(MSTATUS);
+    if cpu.mode != Mode::Machine
+        || mstatus & MSTATUS_MPRV == 0
+        || *access_type == AccessType::Instruction
+    {
+        return cpu.mode;
+    }
+    // MPP is two bits wide at [11..12] of the MSTATUS csr.
+    match (mstatus >> 11) & 0b11 {
+        3 => Mode::Machine,
+        1 => Mode::Supervisor,
+        _ => Mode::User,
+    }
+}
+
 /// Translate a virtual address to a physical address for the paged virtual-dram system.
 pub fn translate(cpu: &mut Cpu, addr: u64, access_type: AccessType) -> Result<u64, Exception> {
-    if !cpu.enable_paging {
+    // "When MODE=Bare, supervisor virtual addresses are equal to supervisor physical addresses".
+    // M-mode accesses aren't translated either.
+    if !cpu.enable_paging || access_mode(cpu, &access_type) == Mode::Machine {
         return Ok(addr);
     }
+    cpu.page_walks += 1;
 
     // The following comments are cited from 4.3.2 Virtual Address Translation Process
     // in "The RISC-V Instruction Set Manual Volume II-Privileged Architecture_20190608".
 
     // "A virtual address va is translated into a physical address pa as follows:"
-    let levels = 3;
-    let vpn = [
-        (addr >> 12) & 0x1ff,
-        (addr >> 21) & 0x1ff,
-        (addr >> 30) & 0x1ff,
-    ];
+    // LEVELS is 3 for SV39, 4 for SV48 and 5 for SV57, which differ only in the number of VPN
+    // fields, so one walk handles all of them.
+    let levels = cpu.page_levels as i64;
 
     // "1. Let a be satp.ppn × PAGESIZE, and let i = LEVELS − 1. (For Sv32, PAGESIZE=212
     //     and LEVELS=2.)"
@@ -42,7 +82,14 @@ pub fn translate(cpu: &mut Cpu, addr: u64, access_type: AccessType) -> Result<u6
         // "2. Let pte be the value of the PTE at address a+va.vpn[i]×PTESIZE. (For Sv32,
         //     PTESIZE=4.) If accessing pte violates a PMA or PMP check, raise an access
         //     exception corresponding to the original access type."
-        pte = cpu.bus.load(a + vpn[i as usize] * 8, 64)?;
+        pte = match cpu.bus.load(a + vpn(addr, i as usize) * 8, 64) {
+            Ok(pte) => pte,
+            Err(_) => match access_type {
+                AccessType::Instruction => return Err(Exception::InstructionAccessFault(addr)),
+                AccessType::Load => return Err(Exception::LoadAccessFault(addr)),
+                AccessType::Store => return Err(Exception::StoreAMOAccessFault(addr)),
+            },
+        };
 
         // "3. If pte.v = 0, or if pte.r = 0 and pte.w = 1, stop and raise a page-fault
         //     exception corresponding to the original access type."
@@ -52,9 +99,9 @@ pub fn translate(cpu: &mut Cpu, addr: u64, access_type: AccessType) -> Result<u6
         let x = (pte >> 3) & 1;
         if v == 0 || (r == 0 && w == 1) {
             match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                AccessType::Load => return Err(Exception::LoadPageFault),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault),
+                AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
+                AccessType::Load => return Err(Exception::LoadPageFault(addr)),
+                AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
             }
         }
 
@@ -71,26 +118,26 @@ pub fn translate(cpu: &mut Cpu, addr: u64, access_type: AccessType) -> Result<u6
         a = ppn * PAGE_SIZE;
         if i < 0 {
             match access_type {
-                AccessType::Instruction => return Err(Exception::InstructionPageFault),
-                AccessType::Load => return Err(Exception::LoadPageFault),
-                AccessType::Store => return Err(Exception::StoreAMOPageFault),
+                AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
+                AccessType::Load => return Err(Exception::LoadPageFault(addr)),
+                AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
             }
         }
     }
 
-    // A leaf PTE has been found.
-    let ppn = [
-        (pte >> 10) & 0x1ff,
-        (pte >> 19) & 0x1ff,
-        (pte >> 28) & 0x03ff_ffff,
-    ];
-
-    // We skip implementing from step 5 to 7.
-
     // "5. A leaf PTE has been found. Determine if the requested dram access is allowed by
     //     the pte.r, pte.w, pte.x, and pte.u bits, given the current privilege mode and the
     //     value of the SUM and MXR fields of the mstatus register. If not, stop and raise a
     //     page-fault exception corresponding to the original access type."
+    if !is_permitted(cpu, pte, &access_type) {
+        match access_type {
+            AccessType::Instruction => return Err(Exception::InstructionPageFault(addr)),
+            AccessType::Load => return Err(Exception::LoadPageFault(addr)),
+            AccessType::Store => return Err(Exception::StoreAMOPageFault(addr)),
+        }
+    }
+
+    // We skip implementing step 6 and 7.
 
     // "6. If i > 0 and pte.ppn[i − 1 : 0] ̸= 0, this is a misaligned superpage; stop and
     //     raise a page-fault exception corresponding to the original access type."
@@ -109,26 +156,215 @@ pub fn translate(cpu: &mut Cpu, addr: u64, access_type: AccessType) -> Result<u6
     //     • If i > 0, then this is a superpage translation and pa.ppn[i−1:0] =
     //     va.vpn[i−1:0].
     //     • pa.ppn[LEVELS−1:i] = pte.ppn[LEVELS−1:i]."
-    let offset = addr & 0xfff;
-    match i {
-        0 => {
-            let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
-            Ok((ppn << 12) | offset)
+    // If i > 0, it's a superpage translation. A superpage is a dram page of larger size than an
+    // ordinary page (4 KiB). It reduces TLB misses and improves performance. The VPN fields
+    // below level i and the page offset come from the virtual address. The PPN of a PTE is 44
+    // bits wide in all the modes.
+    let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+    let mask = (1 << (12 + 9 * i)) - 1;
+    Ok(((ppn << 12) & !mask) | (addr & mask))
+}
+
+/// Return true if the leaf `pte` permits an access of `access_type` in the privilege mode of the
+/// access.
+fn is_permitted(cpu: &Cpu, pte: u64, access_type: &AccessType) -> bool {
+    let r = (pte >> 1) & 1 == 1;
+    let w = (pte >> 2) & 1 == 1;
+    let x = (pte >> 3) & 1 == 1;
+    let u = (pte >> 4) & 1 == 1;
+    let mstatus = cpu.load_csr(MSTATUS);
+
+    // 4.3.1 Addressing and Memory Protection
+    // "U-mode software may only access the page when U=1. If the SUM bit in the sstatus register
+    // is set, supervisor mode software may also access pages with U=1. However, supervisor code
+    // normally operates with the SUM bit clear, in which case, supervisor code will fault on
+    // accesses to user-mode pages. Irrespective of SUM, the supervisor may not execute code on
+    // pages with U=1."
+    let privileged = match access_mode(cpu, access_type) {
+        Mode::User => u,
+        _ => !u || (mstatus & MSTATUS_SUM != 0 && *access_type != AccessType::Instruction),
+    };
+    // "When MXR=1, loads from pages marked either readable or executable (R=1 or X=1) will
+    // succeed."
+    let allowed = match access_type {
+        AccessType::Instruction => x,
+        AccessType::Load => r || (mstatus & MSTATUS_MXR != 0 && x),
+        AccessType::Store => w,
+    };
+    privileged && allowed
+}
+
+/// Read a physical address for a debugger. Only the dram is read, to avoid side effects of
+/// devices.
+fn debug_load_physical(cpu: &mut Cpu, p_addr: u64, size: u64) -> Option<u64> {
+    let dram = cpu.bus.dram_range();
+    if p_addr < dram.start || p_addr.checked_add(size / 8)? > dram.end {
+        return None;
+    }
+    cpu.bus.load(p_addr, size).ok()
+}
+
+/// Walk the page table for a debugger without side effects: no exception is raised and page
+/// walks aren't counted. `satp` selects the page table, or the current satp is used if it's None.
+/// Return the physical address and the leaf PTE, or None if the address isn't mapped or paging
+/// is off.
+pub fn debug_walk(cpu: &mut Cpu, addr: u64, satp: Option<u64>) -> Option<(u64, u64)> {
+    let satp = satp.unwrap_or_else(|| cpu.load_csr(SATP));
+    let levels = paging_levels(satp)?;
+
+    let mut a = (satp & ((1 << 44) - 1)) * PAGE_SIZE;
+    for i in (0..levels).rev() {
+        let pte = debug_load_physical(cpu, a + vpn(addr, i) * 8, 64)?;
+        let v = pte & 1;
+        let r = (pte >> 1) & 1;
+        let w = (pte >> 2) & 1;
+        let x = (pte >> 3) & 1;
+        if v == 0 || (r == 0 && w == 1) {
+            return None;
         }
-        1 => {
-            // Superpage translation. A superpage is a dram page of larger size than an
-            // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-            Ok((ppn[2] << 30) | (ppn[1] << 21) | (vpn[0] << 12) | offset)
+        let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+        if r == 1 || x == 1 {
+            // A leaf PTE. The low bits of a superpage come from the virtual address.
+            let mask = (1 << (12 + 9 * i)) - 1;
+            return Some((((ppn << 12) & !mask) | (addr & mask), pte));
         }
-        2 => {
-            // Superpage translation. A superpage is a dram page of larger size than an
-            // ordinary page (4 KiB). It reduces TLB misses and improves performance.
-            Ok((ppn[2] << 30) | (vpn[1] << 21) | (vpn[0] << 12) | offset)
+        a = ppn * PAGE_SIZE;
+    }
+    None
+}
+
+/// Translate a virtual address for a debugger without side effects. See `debug_walk` for
+/// `satp`. An address is itself if paging is off. Return None if the address isn't mapped.
+pub fn debug_translate(cpu: &mut Cpu, addr: u64, satp: Option<u64>) -> Option<u64> {
+    let satp = satp.unwrap_or_else(|| cpu.load_csr(SATP));
+    if paging_levels(satp).is_none() {
+        return Some(addr);
+    }
+    debug_walk(cpu, addr, Some(satp)).map(|(p_addr, _)| p_addr)
+}
+
+/// Return the permission and status bits of a PTE as letters, e.g. `rwx-gad`.
+pub fn pte_flags(pte: u64) -> String {
+    "rwxugad"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if (pte >> (i + 1)) & 1 == 1 { c } else { '-' })
+        .collect()
+}
+
+/// Virtually and physically contiguous pages with the same permission and status bits.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Mapping {
+    /// The first virtual address.
+    pub va: u64,
+    /// The first physical address.
+    pub pa: u64,
+    /// The size in bytes.
+    pub size: u64,
+    /// The low 8 bits of the leaf PTEs, i.e. V, R, W, X, U, G, A and D.
+    pub flags: u64,
+}
+
+impl fmt::Display for Mapping {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:#018x}-{:#018x} -> {:#x}-{:#x} {}",
+            self.va,
+            self.va.wrapping_add(self.size - 1),
+            self.pa,
+            self.pa + self.size - 1,
+            pte_flags(self.flags)
+        )
+    }
+}
+
+/// Collect the leaf PTEs of the page table at `table` of `level`, for virtual addresses starting
+/// with `va` of `bits` significant bits.
+fn collect_mappings(
+    cpu: &mut Cpu,
+    table: u64,
+    level: usize,
+    va: u64,
+    bits: usize,
+    out: &mut Vec<Mapping>,
+) {
+    for index in 0..512 {
+        let pte = match debug_load_physical(cpu, table + index * 8, 64) {
+            Some(pte) => pte,
+            None => return,
+        };
+        let v = pte & 1;
+        let r = (pte >> 1) & 1;
+        let w = (pte >> 2) & 1;
+        let x = (pte >> 3) & 1;
+        if v == 0 || (r == 0 && w == 1) {
+            continue;
+        }
+        let shift = 12 + 9 * level;
+        let mut addr = va | (index << shift);
+        // The bits of a virtual address above the significant ones are copies of the highest,
+        // e.g. bits 63-39 are copies of bit 38 in SV39.
+        if (addr >> (bits - 1)) & 1 == 1 {
+            addr |= !((1 << bits) - 1);
+        }
+        let ppn = (pte >> 10) & 0x0fff_ffff_ffff;
+        if r == 1 || x == 1 {
+            let size = 1 << shift;
+            let pa = (ppn << 12) & !(size - 1);
+            let flags = pte & 0xff;
+            match out.last_mut() {
+                Some(last)
+                    if last.va.wrapping_add(last.size) == addr
+                        && last.pa + last.size == pa
+                        && last.flags == flags =>
+                {
+                    last.size += size
+                }
+                _ => out.push(Mapping {
+                    va: addr,
+                    pa,
+                    size,
+                    flags,
+                }),
+            }
+        } else if level > 0 {
+            collect_mappings(cpu, ppn * PAGE_SIZE, level - 1, addr, bits, out);
         }
-        _ => match access_type {
-            AccessType::Instruction => return Err(Exception::InstructionPageFault),
-            AccessType::Load => return Err(Exception::LoadPageFault),
-            AccessType::Store => return Err(Exception::StoreAMOPageFault),
-        },
     }
 }
+
+/// Return all valid mappings of the page table selected by `satp`, or the current one if it's
+/// None, merging contiguous pages with the same bits. It's empty if paging is off.
+pub fn debug_mappings(cpu: &mut Cpu, satp: Option<u64>) -> Vec<Mapping> {
+    let satp = satp.unwrap_or_else(|| cpu.load_csr(SATP));
+    let mut mappings = Vec::new();
+    if let Some(levels) = paging_levels(satp) {
+        let root = (satp & ((1 << 44) - 1)) * PAGE_SIZE;
+        collect_mappings(cpu, root, levels - 1, 0, 12 + 9 * levels, &mut mappings);
+    }
+    mappings
+}
+
+/// Read memory at a virtual address for a debugger. See `debug_translate` for `satp`.
+pub fn debug_load(cpu: &mut Cpu, addr: u64, size: u64, satp: Option<u64>) -> Option<u64> {
+    let p_addr = debug_translate(cpu, addr, satp)?;
+    debug_load_physical(cpu, p_addr, size)
+}
+
+/// Write memory at a virtual address for a debugger. Only the dram is written. Return None if
+/// the address isn't mapped to the dram.
+pub fn debug_store(
+    cpu: &mut Cpu,
+    addr: u64,
+    size: u64,
+    value: u64,
+    satp: Option<u64>,
+) -> Option<()> {
+    let p_addr = debug_translate(cpu, addr, satp)?;
+    let dram = cpu.bus.dram_range();
+    if p_addr < dram.start || p_addr.checked_add(size / 8)? > dram.end {
+        return None;
+    }
+    cpu.bus.store(p_addr, size, value).ok()
+}
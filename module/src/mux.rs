@@ -0,0 +1,375 @@
+//! The mux module contains a console multiplexer which shares one terminal between the guest
+//! console and a text monitor, like `-serial mon:stdio` of QEMU. Input goes to the guest until
+//! Ctrl-A c switches to the monitor:
+//!
+//! ```text
+//! Ctrl-A c     Switch between the console and the monitor.
+//! Ctrl-A x     Quit.
+//! Ctrl-A s     Print a state dump to stderr without stopping the guest.
+//! Ctrl-A h     Show the help.
+//! Ctrl-A Ctrl-A
+//!              Send Ctrl-A itself.
+//! ```
+//!
+//! The monitor reads commands line by line:
+//!
+//! ```text
+//! info status          Show whether the execution is running and the program counter.
+//! info registers       Show the integer registers.
+//! info timeline        Show the privilege-mode transitions kept by `--timeline`.
+//! x <addr> [n]         Show n doublewords of memory at a virtual address (default: 1).
+//! stop                 Pause the execution.
+//! cont                 Resume the execution.
+//! snapshot <path>      Write a snapshot.
+//! checkpoint           Write the next numbered checkpoint.
+//! quit                 Quit.
+//! ```
+//!
+//! The terminal isn't put into raw mode, so a line-buffered terminal delivers an escape when Enter
+//! is pressed. A newline right after an escape is dropped, so `Ctrl-A c` and Enter don't send an
+//! empty line to the guest or the monitor. Console output is held while the monitor is shown.
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::checkpoint;
+use crate::cpu::*;
+use crate::disasm::REG_NAMES;
+use crate::mmu::debug_load;
+use crate::qmp::Control;
+use crate::snapshot;
+use crate::state_dump;
+use crate::tui::ChannelReader;
+
+/// The number of instructions between polls of the monitor while running.
+const MUX_POLL_INTERVAL: u64 = 4096;
+/// The escape character, Ctrl-A.
+const ESCAPE: u8 = 0x01;
+/// The prompt of the monitor.
+const PROMPT: &str = "(rvemu) ";
+
+/// The help of the escapes.
+const HELP: &str = "C-a c    switch between console and monitor
+C-a x    exit emulator
+C-a s    dump the state to stderr
+C-a h    print this help
+C-a C-a  sends C-a";
+
+/// The help of the monitor commands.
+const MONITOR_HELP: &str = "info status          show whether the execution is running
+info registers       show the integer registers
+info timeline        show the privilege-mode transitions
+x <addr> [n]         show n doublewords of memory at a virtual address
+stop                 pause the execution
+cont                 resume the execution
+snapshot <path>      write a snapshot
+checkpoint           write the next numbered checkpoint
+quit                 quit the emulator
+";
+
+/// An output shared by the console and the monitor.
+type Output = Arc<Mutex<Box<dyn Write + Send>>>;
+
+/// An event from the thread reading the terminal.
+enum Event {
+    /// The focus has moved to the monitor if true, or to the console.
+    Focus(bool),
+    /// A line typed into the monitor.
+    Command(String),
+    Help,
+    Dump,
+    Quit,
+}
+
+/// A serial backend writing the console output to the terminal, or holding it while the monitor
+/// is shown.
+struct Console {
+    output: Output,
+    monitor: Arc<AtomicBool>,
+    held: Arc<Mutex<Vec<u8>>>,
+}
+
+impl Write for Console {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.monitor.load(Ordering::Acquire) {
+            self.held
+                .lock()
+                .expect("failed to get held output")
+                .extend_from_slice(buf);
+            return Ok(buf.len());
+        }
+        let mut output = self.output.lock().expect("failed to get the output");
+        output.write_all(buf)?;
+        output.flush()?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The console multiplexer.
+pub struct Mux {
+    events: Receiver<Event>,
+    output: Output,
+    /// True if the monitor is shown.
+    monitor: Arc<AtomicBool>,
+    /// Console output held while the monitor is shown.
+    held: Arc<Mutex<Vec<u8>>>,
+    /// True if the execution is paused by the monitor.
+    paused: bool,
+    /// The number of polls since the last check of events.
+    ticks: u64,
+}
+
+/// Read the terminal and send bytes to the console, and events to the emulator thread.
+fn read_terminal(
+    mut input: Box<dyn Read + Send>,
+    console: Sender<u8>,
+    events: Sender<Event>,
+    monitor: Arc<AtomicBool>,
+) {
+    let mut escape = false;
+    let mut after_escape = false;
+    let mut line = Vec::new();
+    let mut byte = [0; 1];
+    while let Ok(1) = input.read(&mut byte) {
+        let byte = byte[0];
+        let newline_after_escape = after_escape && byte == b'\n';
+        after_escape = false;
+        let event = match byte {
+            b'c' if escape => Event::Focus(!monitor.fetch_xor(true, Ordering::AcqRel)),
+            b'x' if escape => Event::Quit,
+            b'h' if escape => Event::Help,
+            b's' if escape => Event::Dump,
+            // Ctrl-A Ctrl-A is sent as the character itself.
+            ESCAPE if escape => {
+                escape = false;
+                if !monitor.load(Ordering::Acquire) {
+                    let _ = console.send(ESCAPE);
+                }
+                continue;
+            }
+            // An unknown escape is ignored like QEMU does.
+            _ if escape => {
+                escape = false;
+                continue;
+            }
+            ESCAPE => {
+                escape = true;
+                continue;
+            }
+            _ if newline_after_escape => continue,
+            _ if !monitor.load(Ordering::Acquire) => {
+                let _ = console.send(byte);
+                continue;
+            }
+            b'\n' => {
+                Event::Command(String::from_utf8_lossy(&std::mem::take(&mut line)).to_string())
+            }
+            _ => {
+                line.push(byte);
+                continue;
+            }
+        };
+        if escape {
+            escape = false;
+            after_escape = true;
+        }
+        if events.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+impl Mux {
+    /// Attach the multiplexer to the console of `cpu`. The console is disconnected from stdin
+    /// and stdout, and `input` and `output` are shared by the console and the monitor instead.
+    pub fn attach(
+        cpu: &mut Cpu,
+        input: Box<dyn Read + Send>,
+        output: Box<dyn Write + Send>,
+    ) -> Self {
+        let output: Output = Arc::new(Mutex::new(output));
+        let monitor = Arc::new(AtomicBool::new(false));
+        let held = Arc::new(Mutex::new(Vec::new()));
+        let (console, receiver) = mpsc::channel();
+        cpu.bus.detach_console(Box::new(ChannelReader(receiver)));
+        cpu.bus.add_serial_backend(Box::new(Console {
+            output: Arc::clone(&output),
+            monitor: Arc::clone(&monitor),
+            held: Arc::clone(&held),
+        }));
+
+        let (sender, events) = mpsc::channel();
+        let shown = Arc::clone(&monitor);
+        thread::spawn(move || read_terminal(input, console, sender, shown));
+        Self {
+            events,
+            output,
+            monitor,
+            held,
+            paused: false,
+            ticks: 0,
+        }
+    }
+
+    /// Handle events from the terminal. It's called once per instruction and checks events every
+    /// `MUX_POLL_INTERVAL` instructions. While the execution is paused, it blocks until the
+    /// monitor resumes it or quits.
+    pub fn poll(&mut self, cpu: &mut Cpu) -> Control {
+        self.ticks += 1;
+        if self.ticks < MUX_POLL_INTERVAL {
+            return Control::Continue;
+        }
+        self.ticks = 0;
+
+        while let Ok(event) = self.events.try_recv() {
+            if self.handle(cpu, event) == Control::Quit {
+                return Control::Quit;
+            }
+        }
+        while self.paused {
+            match self.events.recv() {
+                Ok(event) => {
+                    if self.handle(cpu, event) == Control::Quit {
+                        return Control::Quit;
+                    }
+                }
+                // The terminal has been closed, so no one can resume the execution.
+                Err(_) => return Control::Quit,
+            }
+        }
+        Control::Continue
+    }
+
+    /// Write `text` to the terminal. A closed terminal is ignored.
+    fn print(&self, text: &str) {
+        let mut output = self.output.lock().expect("failed to get the output");
+        let _ = output.write_all(text.as_bytes());
+        let _ = output.flush();
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, event: Event) -> Control {
+        match event {
+            Event::Focus(true) => self.print(&format!(
+                "\nrvemu {} monitor - type 'help' for more information\n{}",
+                env!("CARGO_PKG_VERSION"),
+                PROMPT
+            )),
+            Event::Focus(false) => {
+                let held =
+                    std::mem::take(&mut *self.held.lock().expect("failed to get held output"));
+                self.print("\n");
+                self.print(&String::from_utf8_lossy(&held));
+            }
+            Event::Help => self.print(&format!("\n{}\n", HELP)),
+            // Printed by the main loop, which records the last pcs.
+            Event::Dump => state_dump::request(),
+            Event::Quit => {
+                self.print("rvemu: terminating on signal from the console\n");
+                return Control::Quit;
+            }
+            Event::Command(command) => {
+                let mut words = command.split_whitespace();
+                let control = match words.next() {
+                    Some(name) => self.execute(cpu, name, &words.collect::<Vec<&str>>()),
+                    None => Control::Continue,
+                };
+                if control == Control::Quit {
+                    return Control::Quit;
+                }
+                // The focus may have moved to the console while the command was queued.
+                if self.monitor.load(Ordering::Acquire) {
+                    self.print(PROMPT);
+                }
+            }
+        }
+        Control::Continue
+    }
+
+    /// Execute a monitor command and print its output.
+    fn execute(&mut self, cpu: &mut Cpu, name: &str, args: &[&str]) -> Control {
+        let result = match (name, args) {
+            ("help" | "h" | "?", _) => Ok(MONITOR_HELP.to_string()),
+            ("info", ["status"]) => Ok(format!(
+                "VM status: {}, pc: {:#x}\n",
+                if self.paused { "paused" } else { "running" },
+                cpu.pc
+            )),
+            ("info", ["registers"]) => Ok(registers(cpu)),
+            ("info", ["timeline"]) => match cpu.timeline.is_enabled() {
+                true => Ok(cpu
+                    .timeline
+                    .events()
+                    .map(|(n, event)| format!("#{} {}\n", n, event))
+                    .collect()),
+                false => Err("the timeline is disabled; start with --timeline <n>".to_string()),
+            },
+            ("info", _) => Err("usage: info <status|registers|timeline>".to_string()),
+            ("x", [addr]) => memory(cpu, addr, 1),
+            ("x", [addr, count]) => match count.parse() {
+                Ok(count) => memory(cpu, addr, count),
+                Err(_) => Err(format!("invalid count {}", count)),
+            },
+            ("x", _) => Err("usage: x <addr> [n]".to_string()),
+            ("stop", []) => {
+                self.paused = true;
+                Ok(String::new())
+            }
+            ("cont" | "c", []) => {
+                self.paused = false;
+                Ok(String::new())
+            }
+            ("snapshot", [path]) => snapshot::save(cpu, path)
+                .map(|_| String::new())
+                .map_err(|e| e.to_string()),
+            ("snapshot", _) => Err("usage: snapshot <path>".to_string()),
+            // Written to the next numbered checkpoint file by the main loop.
+            ("checkpoint", []) => {
+                checkpoint::request();
+                Ok(String::new())
+            }
+            ("quit" | "q", []) => return Control::Quit,
+            _ => Err(format!("unknown command: '{}'", name)),
+        };
+        match result {
+            Ok(text) => self.print(&text),
+            Err(e) => self.print(&format!("{}\n", e)),
+        }
+        Control::Continue
+    }
+}
+
+/// Return the program counter and the integer registers, four per line.
+fn registers(cpu: &Cpu) -> String {
+    let mut text = format!("pc   {:#018x}\n", cpu.pc);
+    for i in (0..32).step_by(4) {
+        let line: Vec<String> = (i..i + 4)
+            .map(|r| format!("{:<4} {:#018x}", REG_NAMES[r], cpu.regs[r]))
+            .collect();
+        text.push_str(&line.join(" "));
+        text.push('\n');
+    }
+    text
+}
+
+/// Return `count` doublewords of memory at `addr`, translated by the current page table.
+fn memory(cpu: &mut Cpu, addr: &str, count: u64) -> Result<String, String> {
+    let start = u64::from_str_radix(addr.trim_start_matches("0x"), 16)
+        .map_err(|_| format!("invalid address {}", addr))?;
+    let mut text = String::new();
+    for i in 0..count {
+        let addr = start.wrapping_add(i * 8);
+        match debug_load(cpu, addr, 64, None) {
+            Some(value) => text.push_str(&format!("{:#018x}: {:#018x}\n", addr, value)),
+            None => return Err(format!("cannot access memory at {:#x}", addr)),
+        }
+    }
+    Ok(text)
+}
@@ -0,0 +1,173 @@
+//! The ntrace module contains an encoder of a branch trace in the style of the RISC-V N-Trace
+//! (Nexus-based trace) in the branch history mode. Conditional branches are recorded as history
+//! bits (1 is taken), and only indirect jumps, xRET and traps emit messages with their targets, so
+//! a decoder can reconstruct the execution from the program binary.
+//!
+//! Messages are written in the Nexus byte format: each byte carries 6 data bits (MDO) in bits
+//! [7:2] and 2 bits of MSEO in bits [1:0], which are 0b01 at the end of a variable-length field
+//! and 0b11 at the end of a message. Fields are packed from the least significant bit.
+//!
+//! ```text
+//! ProgTraceSync      TCODE=9  SYNC(4) ICNT F-ADDR
+//! ResourceFull       TCODE=27 RCODE(4)=1 HIST
+//! IndirectBranchHist TCODE=28 B-TYPE(2) ICNT U-ADDR HIST
+//! ```
+//!
+//! ICNT counts 16-bit units, and addresses are shifted right by 1. U-ADDR is the xor of the
+//! target and the last address sent.
+//! See the spec: https://github.com/riscv-non-isa/tg-nexus-trace
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::cpu::*;
+use crate::trace_file::TraceFile;
+
+const TCODE_PROG_TRACE_SYNC: u128 = 9;
+const TCODE_RESOURCE_FULL: u128 = 27;
+const TCODE_INDIRECT_BRANCH_HIST: u128 = 28;
+
+/// The SYNC reason for the start of tracing.
+const SYNC_TRACE_ENABLE: u128 = 1;
+/// The RCODE of ResourceFull for a full history buffer.
+const RCODE_HIST_FULL: u128 = 1;
+
+/// B-TYPE of an indirect jump or xRET.
+const BTYPE_INDIRECT: u128 = 0;
+/// B-TYPE of an exception or an interrupt.
+const BTYPE_TRAP: u128 = 1;
+
+/// The MSEO at the end of a variable-length field.
+const MSEO_END_FIELD: u8 = 0b01;
+/// The MSEO at the end of a message.
+const MSEO_END_MESSAGE: u8 = 0b11;
+
+/// The history buffer holds up to 31 branches after a leading 1.
+const HIST_FULL: u64 = 1 << 31;
+
+/// A message under construction.
+struct Message {
+    bytes: Vec<u8>,
+    bits: u128,
+    len: u32,
+}
+
+impl Message {
+    fn new(tcode: u128) -> Self {
+        let mut message = Self {
+            bytes: Vec::new(),
+            bits: 0,
+            len: 0,
+        };
+        message.fixed(tcode, 6);
+        message
+    }
+
+    /// Add a fixed-length field.
+    fn fixed(&mut self, value: u128, len: u32) {
+        self.bits |= value << self.len;
+        self.len += len;
+    }
+
+    /// Add a variable-length field, terminated by `mseo` in its last byte.
+    fn var(&mut self, value: u128, mseo: u8) {
+        self.bits |= value << self.len;
+        self.len += 128 - value.leading_zeros();
+        loop {
+            let last = self.len <= 6;
+            self.bytes
+                .push(((self.bits & 0x3f) as u8) << 2 | if last { mseo } else { 0 });
+            if last {
+                break;
+            }
+            self.bits >>= 6;
+            self.len -= 6;
+        }
+        self.bits = 0;
+        self.len = 0;
+    }
+}
+
+/// An encoder writing a branch trace to a file.
+pub struct NTrace {
+    out: TraceFile,
+    /// The number of 16-bit units executed since the last message.
+    icnt: u64,
+    /// Branch history with a leading 1.
+    hist: u64,
+    /// The last address sent, shifted right by 1.
+    last_addr: u64,
+}
+
+impl NTrace {
+    /// Create a new trace file at `path`. It starts with a synchronization message at the
+    /// current program counter.
+    pub fn create(path: &str, cpu: &Cpu) -> io::Result<Self> {
+        let mut trace = Self {
+            out: TraceFile::create(path)?,
+            icnt: 0,
+            hist: 1,
+            last_addr: cpu.pc >> 1,
+        };
+        let mut message = Message::new(TCODE_PROG_TRACE_SYNC);
+        message.fixed(SYNC_TRACE_ENABLE, 4);
+        message.var(0, MSEO_END_FIELD);
+        message.var(trace.last_addr as u128, MSEO_END_MESSAGE);
+        trace.out.write_all(&message.bytes)?;
+        Ok(trace)
+    }
+
+    /// Emit a message with the branch history and the target of an indirect control flow change.
+    fn indirect(&mut self, btype: u128, target: u64) -> io::Result<()> {
+        let addr = target >> 1;
+        let mut message = Message::new(TCODE_INDIRECT_BRANCH_HIST);
+        message.fixed(btype, 2);
+        message.var(self.icnt as u128, MSEO_END_FIELD);
+        message.var((addr ^ self.last_addr) as u128, MSEO_END_FIELD);
+        message.var(self.hist as u128, MSEO_END_MESSAGE);
+        self.out.write_all(&message.bytes)?;
+        self.icnt = 0;
+        self.hist = 1;
+        self.last_addr = addr;
+        Ok(())
+    }
+
+    /// Record an instruction `inst` at `pc` which has been executed. `cpu` is the state after the
+    /// execution.
+    pub fn instruction(&mut self, cpu: &Cpu, pc: u64, inst: u64) -> io::Result<()> {
+        // All instructions are 32 bits, i.e. 2 units.
+        self.icnt += 2;
+        match inst & 0x7f {
+            // A conditional branch.
+            0x63 => {
+                let taken = cpu.pc != pc.wrapping_add(4);
+                self.hist = (self.hist << 1) | taken as u64;
+                if self.hist & HIST_FULL != 0 {
+                    let mut message = Message::new(TCODE_RESOURCE_FULL);
+                    message.fixed(RCODE_HIST_FULL, 4);
+                    message.var(self.hist as u128, MSEO_END_MESSAGE);
+                    self.out.write_all(&message.bytes)?;
+                    self.hist = 1;
+                }
+            }
+            // jalr.
+            0x67 => self.indirect(BTYPE_INDIRECT, cpu.pc)?,
+            // sret or mret.
+            0x73 if inst == 0x10200073 || inst == 0x30200073 => {
+                self.indirect(BTYPE_INDIRECT, cpu.pc)?
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Record a trap, an exception or an interrupt. `cpu` is the state after taking the trap.
+    pub fn trap(&mut self, cpu: &Cpu) -> io::Result<()> {
+        self.indirect(BTYPE_TRAP, cpu.pc)
+    }
+
+    /// Flush the rest of the trace.
+    pub fn finish(self) -> io::Result<()> {
+        self.out.finish()
+    }
+}
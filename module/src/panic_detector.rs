@@ -0,0 +1,184 @@
+//! The panic_detector module contains a watcher of guest kernel panics, so a boot in CI fails fast
+//! with context instead of spinning until a timeout. `--on-panic <warn|stop>` detects:
+//! - Console lines with a panic or oops banner, e.g. xv6's `panic: ` or Linux's
+//!   `Kernel panic - not syncing` and `Oops`.
+//! - Calls to panic functions, e.g. `panic`, if symbols are given by `--symbols` or the kernel is
+//!   an ELF file. The first argument is read as the message, which is the format string on Linux.
+//!
+//! `warn` prints the detection and keeps running. `stop` also prints the last console lines and
+//! stops the emulator, which dumps the registers and exits with status 1.
+
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::*;
+use crate::elf::SymbolTable;
+use crate::mmu::debug_load;
+
+/// Console output which starts a panic or an oops report.
+const CONSOLE_PATTERNS: [&str; 6] = [
+    "panic: ",
+    "Kernel panic - not syncing",
+    "Oops",
+    "Unable to handle kernel",
+    "BUG: ",
+    "Internal error: ",
+];
+/// Functions which xv6 and Linux call on a panic or an oops.
+const PANIC_SYMBOLS: [&str; 2] = ["panic", "oops_enter"];
+/// The number of console lines kept as context.
+const CONTEXT_LINES: usize = 16;
+/// The maximum length of a message read from the guest memory.
+const MAX_MESSAGE_LEN: u64 = 128;
+
+/// What to do when a panic is detected.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum PanicAction {
+    /// Report it and keep running.
+    Warn,
+    /// Report it with context and stop the emulator.
+    Stop,
+}
+
+impl PanicAction {
+    /// Parse the name of an action.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "warn" => Some(PanicAction::Warn),
+            "stop" => Some(PanicAction::Stop),
+            _ => None,
+        }
+    }
+}
+
+/// Console output seen so far.
+#[derive(Default)]
+struct Console {
+    /// The last complete lines.
+    lines: VecDeque<String>,
+    /// The line being transmitted.
+    line: Vec<u8>,
+    /// Lines matching a pattern which haven't been reported yet.
+    matches: VecDeque<String>,
+}
+
+impl Console {
+    fn push(&mut self, byte: u8) {
+        if byte != b'\n' {
+            self.line.push(byte);
+            return;
+        }
+        let line = String::from_utf8_lossy(&self.line)
+            .trim_end_matches('\r')
+            .to_string();
+        self.line.clear();
+        if CONSOLE_PATTERNS
+            .iter()
+            .any(|pattern| line.contains(pattern))
+        {
+            self.matches.push_back(line.clone());
+        }
+        if self.lines.len() == CONTEXT_LINES {
+            self.lines.pop_front();
+        }
+        self.lines.push_back(line);
+    }
+}
+
+/// A serial backend which copies transmitted bytes to a `Console`.
+struct ConsoleTap(Arc<Mutex<Console>>);
+
+impl Write for ConsoleTap {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut console = self.0.lock().unwrap();
+        for &byte in buf {
+            console.push(byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A watcher of guest kernel panics.
+pub struct PanicDetector {
+    pub action: PanicAction,
+    console: Arc<Mutex<Console>>,
+    symbols: SymbolTable,
+    /// The addresses of the panic functions.
+    entries: Vec<(u64, &'static str)>,
+}
+
+impl PanicDetector {
+    /// Create a new `PanicDetector` object which takes `action` on a panic.
+    pub fn new(action: PanicAction) -> Self {
+        Self {
+            action,
+            console: Arc::new(Mutex::new(Console::default())),
+            symbols: SymbolTable::default(),
+            entries: Vec::new(),
+        }
+    }
+
+    /// Return a serial backend to watch the console output.
+    pub fn console_tap(&self) -> Box<dyn Write + Send> {
+        Box::new(ConsoleTap(Arc::clone(&self.console)))
+    }
+
+    /// Watch calls to the panic functions in `symbols`.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.entries = PANIC_SYMBOLS
+            .iter()
+            .filter_map(|&name| symbols.lookup(name).map(|addr| (addr, name)))
+            .collect();
+        self.symbols = symbols;
+    }
+
+    /// Return a report if the console printed a panic line or the cpu entered a panic function.
+    pub fn check(&mut self, cpu: &mut Cpu) -> Option<String> {
+        if let Some(line) = self.console.lock().unwrap().matches.pop_front() {
+            return Some(format!("panic: detected on the console: {}", line));
+        }
+        let &(_, name) = self.entries.iter().find(|(addr, _)| *addr == cpu.pc)?;
+        let ra = cpu.regs[1];
+        let caller = match self.symbols.symbolize(ra) {
+            Some((symbol, offset)) => format!("{:#x} <{}+{:#x}>", ra, symbol, offset),
+            None => format!("{:#x}", ra),
+        };
+        Some(format!(
+            "panic: {}() called from {} with \"{}\"",
+            name,
+            caller,
+            read_string(cpu, cpu.regs[10])
+        ))
+    }
+
+    /// Return the last console lines, including the one being transmitted.
+    pub fn context(&self) -> String {
+        let console = self.console.lock().unwrap();
+        let mut context = String::new();
+        for line in console.lines.iter() {
+            context += &format!("console: {}\n", line);
+        }
+        if !console.line.is_empty() {
+            context += &format!("console: {}\n", String::from_utf8_lossy(&console.line));
+        }
+        context
+    }
+}
+
+/// Read a NUL-terminated string at `addr` through the current page table.
+fn read_string(cpu: &mut Cpu, addr: u64) -> String {
+    let mut bytes = Vec::new();
+    for i in 0..MAX_MESSAGE_LEN {
+        match debug_load(cpu, addr.wrapping_add(i), 8, None) {
+            Some(0) | None => break,
+            Some(byte) => bytes.push(byte as u8),
+        }
+    }
+    String::from_utf8_lossy(&bytes).escape_debug().to_string()
+}
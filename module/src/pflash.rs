@@ -0,0 +1,333 @@
+//! The pflash module contains a CFI parallel flash with the Intel command set, like pflash_cfi01
+//! in QEMU. Its contents are read from a host file when the emulator starts and modified sectors
+//! are written back, so guests can keep configuration across runs without a block device.
+//!
+//! The flash is 8 bits wide. Reads return the array unless a command selects the status register,
+//! the device identifier or the CFI query table. Commands are written to any address:
+//! - 0xff: read array
+//! - 0x90: read identifier
+//! - 0x98: CFI query
+//! - 0x70: read status register
+//! - 0x50: clear status register
+//! - 0x40 or 0x10, then data: program
+//! - 0xe8, then a count minus 1, data and 0xd0: write to buffer
+//! - 0x20, then 0xd0: erase the sector
+//!
+//! See: QEMU hw/block/pflash_cfi01.c
+
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The size of an erase sector (256 KiB).
+pub const PFLASH_SECTOR_SIZE: u64 = 0x4_0000;
+
+/// The manufacturer code, Intel.
+const MANUFACTURER_ID: u64 = 0x89;
+/// The device code.
+const DEVICE_ID: u64 = 0x18;
+/// The maximum number of bytes in the write buffer.
+const WRITE_BUFFER_SIZE: u64 = 1 << 11;
+
+/// The status register bit which shows the device is ready.
+const STATUS_READY: u8 = 0x80;
+/// The status register bit of an erase error.
+const STATUS_ERASE_ERROR: u8 = 0x20;
+/// The status register bit of a program error.
+const STATUS_PROGRAM_ERROR: u8 = 0x10;
+
+/// What reads return and what the next write means.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum State {
+    ReadArray,
+    ReadId,
+    Query,
+    ReadStatus,
+    /// The next write is data to program.
+    Program,
+    /// The next write is the erase confirmation.
+    EraseSetup,
+    /// The next write is the number of bytes minus 1 of a buffered write.
+    BufferCount,
+    /// Writes are data of a buffered write until `remaining` bytes are written.
+    BufferData {
+        remaining: u64,
+    },
+    /// The next write is the buffered write confirmation.
+    BufferConfirm,
+}
+
+/// A parallel flash backed by a host file.
+pub struct Pflash {
+    data: Vec<u8>,
+    file: File,
+    state: State,
+    status: u8,
+    /// Sectors modified since the last flush.
+    dirty: Vec<bool>,
+}
+
+impl Device for Pflash {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::QuadWord => Err(Exception::LoadAccessFault(addr)),
+            _ => Ok(self.read(addr - PFLASH_BASE, width.bytes())),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::QuadWord => Err(Exception::StoreAMOAccessFault(addr)),
+            _ => {
+                self.write(addr - PFLASH_BASE, width.bytes(), value);
+                Ok(())
+            }
+        }
+    }
+    /// The contents are kept, and the flash returns to the read array mode.
+    fn reset(&mut self) {
+        self.state = State::ReadArray;
+        self.status = STATUS_READY;
+    }
+}
+
+impl Pflash {
+    /// Open a flash backed by the file at `path`, which is created if it doesn't exist. A file
+    /// smaller than the flash is padded with erased bytes (0xff).
+    pub fn open(path: &str) -> io::Result<Self> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path)?;
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        if data.len() as u64 > PFLASH_SIZE {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} is larger than the flash ({:#x} bytes)",
+                    path, PFLASH_SIZE
+                ),
+            ));
+        }
+        // Pad the file on the first flush if it's short.
+        let padded = (data.len() as u64) < PFLASH_SIZE;
+        data.resize(PFLASH_SIZE as usize, 0xff);
+        Ok(Self {
+            data,
+            file,
+            state: State::ReadArray,
+            status: STATUS_READY,
+            dirty: vec![padded; (PFLASH_SIZE / PFLASH_SECTOR_SIZE) as usize],
+        })
+    }
+
+    /// Write the modified sectors back to the file.
+    pub fn flush(&mut self) -> io::Result<()> {
+        for (i, dirty) in self.dirty.iter_mut().enumerate() {
+            if *dirty {
+                let start = i * PFLASH_SECTOR_SIZE as usize;
+                self.file.seek(SeekFrom::Start(start as u64))?;
+                self.file
+                    .write_all(&self.data[start..start + PFLASH_SECTOR_SIZE as usize])?;
+                *dirty = false;
+            }
+        }
+        self.file.flush()
+    }
+
+    /// Save the contents and the command state for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        let (state, remaining) = match self.state {
+            State::ReadArray => (0, 0),
+            State::ReadId => (1, 0),
+            State::Query => (2, 0),
+            State::ReadStatus => (3, 0),
+            State::Program => (4, 0),
+            State::EraseSetup => (5, 0),
+            State::BufferCount => (6, 0),
+            State::BufferData { remaining } => (7, remaining),
+            State::BufferConfirm => (8, 0),
+        };
+        write_u64(out, state)?;
+        write_u64(out, remaining)?;
+        write_u64(out, self.status as u64)?;
+        write_bytes(out, &self.data)
+    }
+
+    /// Restore the contents and the command state from a snapshot. Every sector is written back
+    /// to the file on the next flush.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        let state = read_u64(input)?;
+        let remaining = read_u64(input)?;
+        self.state = match state {
+            0 => State::ReadArray,
+            1 => State::ReadId,
+            2 => State::Query,
+            3 => State::ReadStatus,
+            4 => State::Program,
+            5 => State::EraseSetup,
+            6 => State::BufferCount,
+            7 => State::BufferData { remaining },
+            8 => State::BufferConfirm,
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid flash state in snapshot",
+                ))
+            }
+        };
+        self.status = read_u64(input)? as u8;
+        let data = read_bytes(input)?;
+        if data.len() != self.data.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "flash size mismatch in snapshot",
+            ));
+        }
+        self.data = data;
+        self.dirty.iter_mut().for_each(|dirty| *dirty = true);
+        Ok(())
+    }
+
+    /// Return a byte of the CFI query table.
+    fn query(offset: u64) -> u8 {
+        let sectors = PFLASH_SIZE / PFLASH_SECTOR_SIZE - 1;
+        let sector_size = PFLASH_SECTOR_SIZE >> 8;
+        match offset {
+            0x10 => b'Q',
+            0x11 => b'R',
+            0x12 => b'Y',
+            // The primary command set (Intel) and the address of its extended table.
+            0x13 => 0x01,
+            0x15 => 0x31,
+            // Vcc min and max.
+            0x1b => 0x45,
+            0x1c => 0x55,
+            // Typical timeouts of a program, a buffered write and an erase.
+            0x1f => 0x07,
+            0x20 => 0x07,
+            0x21 => 0x0a,
+            // Maximum timeouts.
+            0x23..=0x25 => 0x04,
+            // The device size, 2^n bytes.
+            0x27 => PFLASH_SIZE.trailing_zeros() as u8,
+            // The write buffer size, 2^n bytes.
+            0x2a => WRITE_BUFFER_SIZE.trailing_zeros() as u8,
+            // One erase region of `sectors + 1` sectors of `sector_size * 256` bytes.
+            0x2c => 1,
+            0x2d => sectors as u8,
+            0x2e => (sectors >> 8) as u8,
+            0x2f => sector_size as u8,
+            0x30 => (sector_size >> 8) as u8,
+            0x31 => b'P',
+            0x32 => b'R',
+            0x33 => b'I',
+            0x34 => b'1',
+            0x35 => b'0',
+            _ => 0,
+        }
+    }
+
+    fn read(&self, offset: u64, bytes: u64) -> u64 {
+        match self.state {
+            State::ReadArray => {
+                let start = offset as usize;
+                let end = (start + bytes as usize).min(self.data.len());
+                self.data[start..end]
+                    .iter()
+                    .rev()
+                    .fold(0, |value, byte| (value << 8) | *byte as u64)
+            }
+            State::ReadId => match offset {
+                0 => MANUFACTURER_ID,
+                1 => DEVICE_ID,
+                _ => 0,
+            },
+            State::Query => Self::query(offset) as u64,
+            _ => self.status as u64,
+        }
+    }
+
+    /// Program bytes of `value` at `offset`.
+    fn program(&mut self, offset: u64, bytes: u64, value: u64) {
+        for i in 0..bytes.min(PFLASH_SIZE - offset) {
+            let index = (offset + i) as usize;
+            self.data[index] = (value >> (i * 8)) as u8;
+            self.dirty[index / PFLASH_SECTOR_SIZE as usize] = true;
+        }
+    }
+
+    fn write(&mut self, offset: u64, bytes: u64, value: u64) {
+        let command = value as u8;
+        self.state = match self.state {
+            State::Program => {
+                self.program(offset, bytes, value);
+                State::ReadStatus
+            }
+            State::EraseSetup if command == 0xd0 => {
+                let sector = offset / PFLASH_SECTOR_SIZE;
+                let start = (sector * PFLASH_SECTOR_SIZE) as usize;
+                self.data[start..start + PFLASH_SECTOR_SIZE as usize].fill(0xff);
+                self.dirty[sector as usize] = true;
+                State::ReadStatus
+            }
+            State::EraseSetup => {
+                self.status |= STATUS_ERASE_ERROR | STATUS_PROGRAM_ERROR;
+                State::ReadStatus
+            }
+            State::BufferCount => {
+                let count = (value & 0xffff) + 1;
+                if count > WRITE_BUFFER_SIZE {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                    State::ReadStatus
+                } else {
+                    State::BufferData { remaining: count }
+                }
+            }
+            State::BufferData { remaining } => {
+                let bytes = bytes.min(remaining);
+                self.program(offset, bytes, value);
+                match remaining - bytes {
+                    0 => State::BufferConfirm,
+                    remaining => State::BufferData { remaining },
+                }
+            }
+            State::BufferConfirm => {
+                if command != 0xd0 {
+                    self.status |= STATUS_PROGRAM_ERROR;
+                }
+                State::ReadStatus
+            }
+            _ => match command {
+                0xff => State::ReadArray,
+                0x90 => State::ReadId,
+                0x98 => State::Query,
+                0x70 => State::ReadStatus,
+                0x50 => {
+                    self.status = STATUS_READY;
+                    self.state
+                }
+                0x40 | 0x10 => State::Program,
+                0x20 => State::EraseSetup,
+                0xe8 => State::BufferCount,
+                _ => self.state,
+            },
+        };
+    }
+}
+
+impl Drop for Pflash {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            eprintln!("failed to write the flash back: {}", e);
+        }
+    }
+}
@@ -0,0 +1,283 @@
+//! The pipeline module contains a timing model of the classic five-stage pipeline (IF, ID, EX,
+//! MEM and WB) for computer-architecture coursework. It only observes executed instructions, so
+//! it doesn't change the results of the emulator, and it counts the cycles a pipelined core would
+//! take:
+//! - Results are forwarded to EX from MEM and WB, so only an instruction using the result of the
+//!   load right before it stalls for a cycle in ID (a load-use hazard).
+//! - Branches are predicted not taken and resolved in EX. A taken branch or a jump flushes the two
+//!   instructions fetched after it.
+//! - A trap flushes the pipeline behind the last completed instruction, and the trap handler is
+//!   fetched after that instruction leaves MEM.
+//!
+//! `--pipeline <file>` writes a diagram with a row per cycle showing which instruction is in each
+//! stage, and the statistics at the end.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+
+use crate::cpu::*;
+use crate::disasm::*;
+use crate::explain::sources;
+
+/// The names of the stages.
+const STAGES: [&str; 5] = ["IF", "ID", "EX", "MEM", "WB"];
+/// The width of a stage column in the diagram.
+const COLUMN_WIDTH: usize = 24;
+
+/// An instruction in the pipeline and the cycles it enters each stage.
+struct Slot {
+    pc: u64,
+    inst: u64,
+    /// The cycles entering IF, ID, EX, MEM and WB.
+    cycles: [u64; 5],
+    /// The register whose value is ready only after MEM, i.e. the destination of a load.
+    loaded: Option<u64>,
+    /// True if the next instruction isn't at pc + 4.
+    redirects: bool,
+}
+
+/// Why a cycle doesn't complete an instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Hazard {
+    LoadUse,
+    Branch,
+    Trap,
+}
+
+/// Statistics of the pipeline model.
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct PipelineStats {
+    /// The number of completed instructions.
+    pub instructions: u64,
+    /// The number of cycles until the last instruction leaves WB.
+    pub cycles: u64,
+    /// The number of cycles stalled by load-use hazards.
+    pub load_use_stalls: u64,
+    /// The number of bubbles inserted by taken branches and jumps.
+    pub branch_bubbles: u64,
+    /// The number of bubbles inserted by traps.
+    pub trap_bubbles: u64,
+}
+
+impl PipelineStats {
+    /// Return the cycles per instruction.
+    pub fn cpi(&self) -> f64 {
+        match self.instructions {
+            0 => 0.0,
+            n => self.cycles as f64 / n as f64,
+        }
+    }
+}
+
+impl fmt::Display for PipelineStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "pipeline: {} instructions in {} cycles (CPI {:.3}), {} load-use stalls, {} branch \
+             bubbles, {} trap bubbles",
+            self.instructions,
+            self.cycles,
+            self.cpi(),
+            self.load_use_stalls,
+            self.branch_bubbles,
+            self.trap_bubbles
+        )
+    }
+}
+
+/// Return the register which `inst` writes, if any.
+fn destination(inst: u64) -> Option<u64> {
+    let rd = (inst >> 7) & 0x1f;
+    let writes = match inst & 0x7f {
+        // Stores, branches and fences.
+        0x23 | 0x63 | 0x0f => false,
+        // Only CSR instructions write rd among system instructions.
+        0x73 => (inst >> 12) & 0x7 != 0,
+        _ => true,
+    };
+    match writes && rd != 0 {
+        true => Some(rd),
+        false => None,
+    }
+}
+
+/// A timing model of the five-stage pipeline.
+pub struct Pipeline {
+    out: Option<BufWriter<File>>,
+    /// Instructions which may still be drawn in the diagram, from the oldest.
+    slots: VecDeque<Slot>,
+    /// The cycles of hazards which haven't been drawn yet.
+    hazards: VecDeque<(u64, Hazard)>,
+    /// The next cycle to draw.
+    next_cycle: u64,
+    /// True if a trap has been taken since the last instruction.
+    trapped: bool,
+    stats: PipelineStats,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Pipeline {
+    /// Create a new `Pipeline` object which only counts statistics.
+    pub fn new() -> Self {
+        Self {
+            out: None,
+            slots: VecDeque::new(),
+            hazards: VecDeque::new(),
+            next_cycle: 1,
+            trapped: false,
+            stats: PipelineStats::default(),
+        }
+    }
+
+    /// Create a new diagram file at `path` and write a header.
+    pub fn create(path: &str) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        let mut header = format!("{:>8}", "cycle");
+        for stage in STAGES {
+            header += &format!("  {:<width$}", stage, width = COLUMN_WIDTH);
+        }
+        writeln!(out, "{}", header.trim_end())?;
+        Ok(Self {
+            out: Some(out),
+            ..Self::new()
+        })
+    }
+
+    /// Return the statistics so far.
+    pub fn stats(&self) -> PipelineStats {
+        self.stats
+    }
+
+    /// Record an instruction `inst` at `pc` which has been executed. `cpu` is the state after the
+    /// execution.
+    pub fn instruction(&mut self, cpu: &Cpu, pc: u64, inst: u64) -> io::Result<()> {
+        let mut fetch = 1;
+        let mut decode_free = 0;
+        let mut execute_free = 0;
+        if let Some(prev) = self.slots.back() {
+            let [_, id, ex, mem, wb] = prev.cycles;
+            // The next instruction is fetched when the previous one moves to ID.
+            fetch = id;
+            if self.trapped {
+                // The handler is fetched after the last instruction reaching MEM.
+                fetch = mem + 1;
+                self.stats.trap_bubbles += fetch - id;
+                self.hazards.push_back((id, Hazard::Trap));
+            } else if prev.redirects {
+                // The target is fetched after the branch is resolved in EX.
+                fetch = ex + 1;
+                self.stats.branch_bubbles += fetch - id;
+                self.hazards.push_back((id, Hazard::Branch));
+            }
+            decode_free = ex;
+            execute_free = match prev.loaded {
+                // The loaded value is forwarded from WB.
+                Some(rd) if sources(inst).contains(&rd) => {
+                    self.stats.load_use_stalls += 1;
+                    self.hazards.push_back((mem, Hazard::LoadUse));
+                    wb
+                }
+                _ => mem,
+            };
+        }
+        let decode = (fetch + 1).max(decode_free);
+        let execute = (decode + 1).max(execute_free);
+        let cycles = [fetch, decode, execute, execute + 1, execute + 2];
+
+        let loaded = match inst & 0x7f {
+            // Loads and atomic instructions get the value in MEM.
+            0x03 | 0x2f => destination(inst),
+            _ => None,
+        };
+        self.slots.push_back(Slot {
+            pc,
+            inst,
+            cycles,
+            loaded,
+            redirects: cpu.pc != pc.wrapping_add(4),
+        });
+        self.trapped = false;
+        self.stats.instructions += 1;
+        self.stats.cycles = cycles[4];
+
+        // No later instruction can be fetched before this one moves to ID.
+        self.draw(fetch)
+    }
+
+    /// Record a trap such as an exception or an interrupt.
+    pub fn trap(&mut self) {
+        self.trapped = true;
+    }
+
+    /// Write the rows of the cycles up to `until` to the diagram.
+    fn draw(&mut self, until: u64) -> io::Result<()> {
+        let out = match self.out.as_mut() {
+            Some(out) => out,
+            None => {
+                self.slots.drain(..self.slots.len().saturating_sub(1));
+                self.hazards.clear();
+                return Ok(());
+            }
+        };
+        while self.next_cycle <= until {
+            let cycle = self.next_cycle;
+            let mut row = format!("{:>8}", cycle);
+            for stage in 0..STAGES.len() {
+                let slot = self.slots.iter().find(|slot| {
+                    let end = match slot.cycles.get(stage + 1) {
+                        Some(&next) => next,
+                        None => slot.cycles[stage] + 1,
+                    };
+                    (slot.cycles[stage]..end).contains(&cycle)
+                });
+                let text = match slot {
+                    Some(slot) => {
+                        let mut text = format!("{:x} {}", slot.pc, disassemble(slot.pc, slot.inst));
+                        text.truncate(COLUMN_WIDTH);
+                        text
+                    }
+                    None => "-".to_string(),
+                };
+                row += &format!("  {:<width$}", text, width = COLUMN_WIDTH);
+            }
+            while let Some(&(hazard_cycle, hazard)) = self.hazards.front() {
+                if hazard_cycle > cycle {
+                    break;
+                }
+                self.hazards.pop_front();
+                let note = match hazard {
+                    Hazard::LoadUse => "load-use stall",
+                    Hazard::Branch => "branch flush",
+                    Hazard::Trap => "trap flush",
+                };
+                row += &format!("  {}", note);
+            }
+            writeln!(out, "{}", row.trim_end())?;
+            self.next_cycle += 1;
+            // Keep the last instruction, which the next one depends on.
+            while self.slots.len() > 1 && self.slots[0].cycles[4] < self.next_cycle {
+                self.slots.pop_front();
+            }
+        }
+        Ok(())
+    }
+
+    /// Draw the remaining cycles, write the statistics and flush the file.
+    pub fn finish(mut self) -> io::Result<PipelineStats> {
+        self.draw(self.stats.cycles)?;
+        if let Some(mut out) = self.out {
+            writeln!(out, "{}", self.stats)?;
+            out.flush()?;
+        }
+        Ok(self.stats)
+    }
+}
@@ -2,8 +2,18 @@
 //! The plic connects all external interrupts in the system to all hart
 //! contexts in the system, via the external interrupt source in each hart.
 //! It's the global interrupt controller in a RISC-V system.
+//!
+//! It has one context, for S-mode. An IRQ stays pending until a guest claims it by reading the
+//! claim register, and the external interrupt is asserted while an enabled IRQ is pending. A
+//! claimed IRQ is in service until the guest completes it by writing it back to the same register,
+//! and the gateway of its source forwards no request meanwhile: an IRQ raised while in service is
+//! held and becomes pending again at the completion.
+
+use std::io;
+use std::io::prelude::*;
 
 use crate::bus::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
 /// The address of interrupt pending bits.
@@ -12,7 +22,8 @@ pub const PLIC_PENDING: u64 = PLIC_BASE + 0x1000;
 pub const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
 /// The address of the registers to set a priority for S-mode.
 pub const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
-/// The address of the claim/complete registers for S-mode.
+/// The address of the claim/complete registers for S-mode. Reading it claims the pending IRQ and
+/// writing it completes one.
 pub const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
 
 /// The platform-level-interrupt controller (PLIC).
@@ -20,23 +31,44 @@ pub struct Plic {
     pending: u64,
     senable: u64,
     spriority: u64,
-    sclaim: u64,
+    /// The IRQs which have been claimed and not completed yet.
+    claimed: u64,
+    /// The IRQs raised while they are in service.
+    held: u64,
 }
 
 impl Device for Plic {
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match size {
-            32 => Ok(self.load32(addr)),
-            _ => Err(Exception::LoadAccessFault),
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::Word => Ok(self.load32(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
-    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match size {
-            32 => Ok(self.store32(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::Word => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
+
+    fn register_name(&self, addr: u64, _store: bool) -> Option<&'static str> {
+        let name = match addr {
+            PLIC_PENDING => "pending",
+            PLIC_SENABLE => "senable",
+            PLIC_SPRIORITY => "sthreshold",
+            PLIC_SCLAIM => "sclaim",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new();
+    }
 }
 
 impl Plic {
@@ -46,16 +78,101 @@ impl Plic {
             pending: 0,
             senable: 0,
             spriority: 0,
-            sclaim: 0,
+            claimed: 0,
+            held: 0,
+        }
+    }
+
+    /// Make `irq` pending for S-mode, or hold it until the completion if it's in service.
+    pub fn raise(&mut self, irq: u64) {
+        match self.claimed & (1 << irq) {
+            0 => self.pending |= 1 << irq,
+            _ => self.held |= 1 << irq,
+        }
+    }
+
+    /// Return true if the external interrupt of S-mode is asserted, i.e. an enabled IRQ is
+    /// pending. Source priorities aren't modeled and are 1, so the threshold must be 0.
+    pub fn is_interrupting(&self) -> bool {
+        self.deliverable() != 0
+    }
+
+    /// Return the pending IRQs which are delivered to S-mode.
+    fn deliverable(&self) -> u64 {
+        match self.spriority {
+            0 => self.pending & self.senable,
+            _ => 0,
         }
     }
 
-    fn load32(&self, addr: u64) -> u64 {
+    /// Return the state of the S-mode context for the monitor. Source priorities aren't modeled, so
+    /// every source has priority 1 and an IRQ is delivered if the threshold is 0.
+    pub fn info(&self) -> String {
+        let irqs = |bits: u64| -> String {
+            let irqs: Vec<String> = (0..64)
+                .filter(|irq| bits & (1 << irq) != 0)
+                .map(|irq| irq.to_string())
+                .collect();
+            match irqs.is_empty() {
+                true => "none".to_string(),
+                false => irqs.join(" "),
+            }
+        };
+        let interrupt = match self.is_interrupting() {
+            true => "asserted",
+            false => "deasserted",
+        };
+        format!(
+            "pending   {:#010x} irq {}\nenable    {:#010x} irq {}\nthreshold {}\nclaimed   irq {}\n\
+             held      irq {}\nexternal interrupt {}",
+            self.pending,
+            irqs(self.pending),
+            self.senable,
+            irqs(self.senable),
+            self.spriority,
+            irqs(self.claimed),
+            irqs(self.held),
+            interrupt
+        )
+    }
+
+    /// Save the registers for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u64(out, self.pending)?;
+        write_u64(out, self.senable)?;
+        write_u64(out, self.spriority)?;
+        write_u64(out, self.claimed)?;
+        write_u64(out, self.held)
+    }
+
+    /// Restore the registers from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.pending = read_u64(input)?;
+        self.senable = read_u64(input)?;
+        self.spriority = read_u64(input)?;
+        self.claimed = read_u64(input)?;
+        self.held = read_u64(input)?;
+        Ok(())
+    }
+
+    fn load32(&mut self, addr: u64) -> u64 {
         match addr {
             PLIC_PENDING => self.pending,
             PLIC_SENABLE => self.senable,
             PLIC_SPRIORITY => self.spriority,
-            PLIC_SCLAIM => self.sclaim,
+            // "The PLIC can perform an interrupt claim by reading the claim/complete register,
+            // which returns the ID of the highest priority pending interrupt or zero if there is
+            // no pending interrupt." Ties are broken by the lowest ID.
+            PLIC_SCLAIM => {
+                let deliverable = self.deliverable();
+                if deliverable == 0 {
+                    return 0;
+                }
+                let irq = deliverable.trailing_zeros() as u64;
+                self.pending &= !(1 << irq);
+                self.claimed |= 1 << irq;
+                irq
+            }
             _ => 0,
         }
     }
@@ -65,7 +182,22 @@ impl Plic {
             PLIC_PENDING => self.pending = value,
             PLIC_SENABLE => self.senable = value,
             PLIC_SPRIORITY => self.spriority = value,
-            PLIC_SCLAIM => self.sclaim = value,
+            // "The PLIC signals it has completed executing an interrupt handler by writing the
+            // interrupt ID it received from the claim to the claim/complete register." An ID
+            // which isn't in service is ignored.
+            PLIC_SCLAIM => {
+                let bit = match value {
+                    0..=63 => 1 << value,
+                    _ => 0,
+                };
+                if self.claimed & bit != 0 {
+                    self.claimed &= !bit;
+                    if self.held & bit != 0 {
+                        self.held &= !bit;
+                        self.pending |= bit;
+                    }
+                }
+            }
             _ => {}
         }
     }
@@ -0,0 +1,207 @@
+//! The plugin module contains a host of device models in shared libraries, so third parties can
+//! ship devices separately and map them on the bus at startup without forking the emulator:
+//!
+//! ```text
+//! rvemu-for-book --device ./libmydev.so@0x10030000,irq=5,args=foo kernel.bin
+//! ```
+//!
+//! A plugin exports `rvemu_device_create`, which returns a device described by the C ABI below.
+//! The device gets an MMIO window of `size` bytes at the base address, and accesses are passed
+//! as offsets in the window. `tick` is called once per instruction and returns nonzero to raise
+//! the IRQ.
+//!
+//! ```c
+//! #define RVEMU_PLUGIN_ABI_VERSION 1
+//!
+//! struct rvemu_device {
+//!     uint32_t abi_version;
+//!     uint64_t size;
+//!     void *ctx;
+//!     /* Return 0 on success, or nonzero for an access fault. size is in bits. */
+//!     int (*load)(void *ctx, uint64_t offset, uint32_t size, uint64_t *value);
+//!     int (*store)(void *ctx, uint64_t offset, uint32_t size, uint64_t value);
+//!     /* Optional. */
+//!     int (*tick)(void *ctx);
+//!     void (*destroy)(void *ctx);
+//! };
+//!
+//! const struct rvemu_device *rvemu_device_create(const char *args);
+//! ```
+
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::io;
+use std::ops::Range;
+
+use crate::bus::*;
+use crate::trap::*;
+
+/// The version of the plugin ABI. A plugin built for another version is rejected.
+pub const PLUGIN_ABI_VERSION: u32 = 1;
+
+/// The name of the function a plugin exports.
+const CREATE_SYMBOL: &str = "rvemu_device_create";
+
+const RTLD_NOW: c_int = 2;
+
+extern "C" {
+    fn dlopen(filename: *const c_char, flag: c_int) -> *mut c_void;
+    fn dlsym(handle: *mut c_void, symbol: *const c_char) -> *mut c_void;
+    fn dlerror() -> *mut c_char;
+}
+
+/// A device described by a plugin, `struct rvemu_device`.
+#[repr(C)]
+struct RawDevice {
+    abi_version: u32,
+    size: u64,
+    ctx: *mut c_void,
+    load: extern "C" fn(*mut c_void, u64, u32, *mut u64) -> c_int,
+    store: extern "C" fn(*mut c_void, u64, u32, u64) -> c_int,
+    tick: Option<extern "C" fn(*mut c_void) -> c_int>,
+    destroy: Option<extern "C" fn(*mut c_void)>,
+}
+
+type CreateFn = extern "C" fn(*const c_char) -> *const RawDevice;
+
+/// Return the last error of the dynamic linker.
+fn dl_error() -> io::Error {
+    // SAFETY: dlerror returns null or a valid C string owned by the dynamic linker.
+    let message = unsafe {
+        let error = dlerror();
+        if error.is_null() {
+            "unknown error".to_string()
+        } else {
+            CStr::from_ptr(error).to_string_lossy().to_string()
+        }
+    };
+    io::Error::other(message)
+}
+
+/// A device loaded from a plugin and mapped on the bus.
+pub struct PluginDevice {
+    base: u64,
+    irq: Option<u64>,
+    raw: *const RawDevice,
+}
+
+impl Device for PluginDevice {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        let raw = self.raw();
+        let mut value = 0;
+        match (raw.load)(raw.ctx, addr - self.base, width.bits() as u32, &mut value) {
+            0 => Ok(value),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        let raw = self.raw();
+        match (raw.store)(raw.ctx, addr - self.base, width.bits() as u32, value) {
+            0 => Ok(()),
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+}
+
+impl PluginDevice {
+    /// Load a device from a spec `<path>@<base>[,irq=<n>][,args=<string>]`. Everything after
+    /// `args=` is passed to the plugin.
+    pub fn load_spec(spec: &str) -> io::Result<Self> {
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid device spec `{}`", spec),
+            )
+        };
+        let (path, rest) = spec.split_once('@').ok_or_else(invalid)?;
+        let (options, args) = match rest.split_once(",args=") {
+            Some((options, args)) => (options, args),
+            None => (rest, ""),
+        };
+        let mut options = options.split(',');
+        let base = options.next().ok_or_else(invalid)?;
+        let base = u64::from_str_radix(base.trim_start_matches("0x"), 16).map_err(|_| invalid())?;
+        let mut irq = None;
+        for option in options {
+            match option.strip_prefix("irq=").map(|n| n.parse::<u64>()) {
+                Some(Ok(n)) => irq = Some(n),
+                _ => return Err(invalid()),
+            }
+        }
+        Self::open(path, base, irq, args)
+    }
+
+    /// Load a device from a shared library at `path` and map it at `base`.
+    pub fn open(path: &str, base: u64, irq: Option<u64>, args: &str) -> io::Result<Self> {
+        let nul = |_| io::Error::new(io::ErrorKind::InvalidInput, "a nul byte in an argument");
+        let c_path = CString::new(path).map_err(nul)?;
+        let c_symbol = CString::new(CREATE_SYMBOL).map_err(nul)?;
+        let c_args = CString::new(args).map_err(nul)?;
+
+        // SAFETY: the arguments are valid C strings. The library is never closed, so the
+        // functions and the device stay valid for the lifetime of the emulator. A plugin is
+        // trusted to follow the ABI.
+        let raw = unsafe {
+            let handle = dlopen(c_path.as_ptr(), RTLD_NOW);
+            if handle.is_null() {
+                return Err(dl_error());
+            }
+            let create = dlsym(handle, c_symbol.as_ptr());
+            if create.is_null() {
+                return Err(dl_error());
+            }
+            let create: CreateFn = std::mem::transmute(create);
+            create(c_args.as_ptr())
+        };
+        if raw.is_null() {
+            return Err(io::Error::other(format!(
+                "{} failed to create a device",
+                path
+            )));
+        }
+        let device = Self { base, irq, raw };
+        let version = device.raw().abi_version;
+        if version != PLUGIN_ABI_VERSION {
+            return Err(io::Error::other(format!(
+                "{} has the plugin ABI version {}",
+                path, version
+            )));
+        }
+        Ok(device)
+    }
+
+    fn raw(&self) -> &RawDevice {
+        // SAFETY: `raw` was checked to be non-null and a plugin keeps it valid until `destroy`.
+        unsafe { &*self.raw }
+    }
+
+    /// Return true if `addr` is in the MMIO window.
+    pub fn contains(&self, addr: u64) -> bool {
+        self.base <= addr && addr - self.base < self.raw().size
+    }
+
+    /// Return the range of the MMIO window.
+    pub fn range(&self) -> Range<u64> {
+        self.base..self.base.saturating_add(self.raw().size)
+    }
+
+    /// Advance the device by one instruction. Return the IRQ if it raises an interrupt.
+    pub fn tick(&mut self) -> Option<u64> {
+        let raw = self.raw();
+        let interrupting = raw.tick.is_some_and(|tick| tick(raw.ctx) != 0);
+        if interrupting {
+            self.irq
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for PluginDevice {
+    fn drop(&mut self) {
+        let raw = self.raw();
+        if let Some(destroy) = raw.destroy {
+            destroy(raw.ctx);
+        }
+    }
+}
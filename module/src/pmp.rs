@@ -0,0 +1,29 @@
+//! The pmp module contains an emulated physical memory protection (PMP)-like default. Physical
+//! ranges registered as protected (e.g. where the M-mode firmware lives) are inaccessible to S-mode
+//! and U-mode, and accessing them raises an access fault.
+
+use crate::cpu::{Cpu, Mode};
+use crate::mmu::{access_mode, AccessType};
+use crate::trap::Exception;
+
+/// Check if `size` bits at the physical address `addr` can be accessed in the privilege mode of
+/// the access, which MPRV may change for loads and stores. An access fails if any of its bytes is
+/// protected. M-mode can access everywhere.
+pub fn check(cpu: &Cpu, addr: u64, size: u64, access_type: AccessType) -> Result<(), Exception> {
+    if access_mode(cpu, &access_type) == Mode::Machine {
+        return Ok(());
+    }
+    let end = addr.saturating_add(size / 8);
+    if !cpu
+        .protected
+        .iter()
+        .any(|range| range.start < end && addr < range.end)
+    {
+        return Ok(());
+    }
+    match access_type {
+        AccessType::Instruction => Err(Exception::InstructionAccessFault(addr)),
+        AccessType::Load => Err(Exception::LoadAccessFault(addr)),
+        AccessType::Store => Err(Exception::StoreAMOAccessFault(addr)),
+    }
+}
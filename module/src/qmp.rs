@@ -0,0 +1,250 @@
+//! The qmp module contains a control channel modeled after the QEMU Machine Protocol (QMP). External
+//! tools connect to a Unix socket and send JSON commands, one per line:
+//!
+//! ```text
+//! {"execute": "qmp_capabilities"}
+//! {"execute": "query-status"}
+//! {"execute": "stop"}
+//! {"execute": "cont"}
+//! {"execute": "snapshot", "arguments": {"path": "/tmp/rvemu.snap"}}
+//! {"execute": "checkpoint"}
+//! {"execute": "system_reset"}
+//! {"execute": "query-timeline"}
+//! {"execute": "serial-add", "arguments": {"backend": "file", "path": "/tmp/console.log"}}
+//! {"execute": "serial-add", "arguments": {"backend": "unix", "path": "/tmp/console.sock"}}
+//! {"execute": "quit"}
+//! ```
+//!
+//! Each command is answered with `{"return": ...}` or `{"error": {"class": ..., "desc": ...}}`.
+
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+
+use crate::checkpoint;
+use crate::cpu::*;
+use crate::json::Json;
+use crate::snapshot;
+
+/// The number of instructions between polls of the control channel while running.
+const QMP_POLL_INTERVAL: u64 = 4096;
+
+/// What the emulator should do after polling the control channel.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Control {
+    /// Keep executing instructions.
+    Continue,
+    /// Stop the emulator.
+    Quit,
+}
+
+/// A command received from a client and a channel to send a reply to.
+struct Request {
+    command: Json,
+    reply: Sender<Json>,
+}
+
+/// The control channel.
+pub struct Qmp {
+    requests: Receiver<Request>,
+    /// True if the execution is paused by a client.
+    paused: bool,
+    /// The number of polls since the last check of requests.
+    ticks: u64,
+}
+
+impl Qmp {
+    /// Listen on a Unix socket at `path`. A stale socket file is removed.
+    pub fn bind(path: &str) -> io::Result<Self> {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let sender = sender.clone();
+                thread::spawn(move || {
+                    let _ = serve(stream, sender);
+                });
+            }
+        });
+        Ok(Self {
+            requests,
+            paused: false,
+            ticks: 0,
+        })
+    }
+
+    /// Handle commands from clients. It's called once per instruction and checks requests every
+    /// `QMP_POLL_INTERVAL` instructions. While the execution is paused, it blocks until a client
+    /// resumes it or quits.
+    pub fn poll(&mut self, cpu: &mut Cpu) -> Control {
+        self.ticks += 1;
+        if self.ticks < QMP_POLL_INTERVAL {
+            return Control::Continue;
+        }
+        self.ticks = 0;
+
+        while let Ok(request) = self.requests.try_recv() {
+            if self.handle(cpu, request) == Control::Quit {
+                return Control::Quit;
+            }
+        }
+        while self.paused {
+            match self.requests.recv() {
+                Ok(request) => {
+                    if self.handle(cpu, request) == Control::Quit {
+                        return Control::Quit;
+                    }
+                }
+                // No one can resume the execution.
+                Err(_) => return Control::Quit,
+            }
+        }
+        Control::Continue
+    }
+
+    fn handle(&mut self, cpu: &mut Cpu, request: Request) -> Control {
+        let name = request
+            .command
+            .get("execute")
+            .and_then(|e| e.as_str())
+            .unwrap_or("");
+        let arguments = request.command.get("arguments");
+        let argument = |key: &str| arguments.and_then(|a| a.get(key)).and_then(|v| v.as_str());
+
+        let mut control = Control::Continue;
+        let result = match name {
+            "qmp_capabilities" => Ok(Json::object(vec![])),
+            "query-status" => Ok(Json::object(vec![
+                ("running", Json::Bool(!self.paused)),
+                (
+                    "status",
+                    Json::String(if self.paused { "paused" } else { "running" }.to_string()),
+                ),
+                ("pc", Json::Number(cpu.pc as f64)),
+            ])),
+            "stop" | "pause" => {
+                self.paused = true;
+                Ok(Json::object(vec![]))
+            }
+            "cont" | "resume" => {
+                self.paused = false;
+                Ok(Json::object(vec![]))
+            }
+            "snapshot" => match argument("path") {
+                Some(path) => snapshot::save(cpu, path)
+                    .map(|_| Json::object(vec![]))
+                    .map_err(|e| e.to_string()),
+                None => Err("missing argument 'path'".to_string()),
+            },
+            // Written to the next numbered checkpoint file by the main loop.
+            "checkpoint" => {
+                checkpoint::request();
+                Ok(Json::object(vec![]))
+            }
+            // The last privilege-mode transitions kept by `--timeline`, one string per event.
+            "query-timeline" => match cpu.timeline.is_enabled() {
+                true => Ok(Json::Array(
+                    cpu.timeline
+                        .events()
+                        .map(|(n, event)| Json::String(format!("#{} {}", n, event)))
+                        .collect(),
+                )),
+                false => Err("the timeline is disabled; start with --timeline <n>".to_string()),
+            },
+            "serial-add" => match (argument("backend"), argument("path")) {
+                (Some(backend), Some(path)) => add_serial_backend(cpu, backend, path)
+                    .map(|_| Json::object(vec![]))
+                    .map_err(|e| e.to_string()),
+                _ => Err("missing argument 'backend' or 'path'".to_string()),
+            },
+            // Reboot the guest in place.
+            "system_reset" => {
+                cpu.reset();
+                Ok(Json::object(vec![]))
+            }
+            "quit" => {
+                control = Control::Quit;
+                Ok(Json::object(vec![]))
+            }
+            _ => Err(format!("the command {} has not been found", name)),
+        };
+
+        let reply = match result {
+            Ok(value) => Json::object(vec![("return", value)]),
+            Err(desc) => Json::object(vec![(
+                "error",
+                Json::object(vec![
+                    ("class", Json::String("GenericError".to_string())),
+                    ("desc", Json::String(desc)),
+                ]),
+            )]),
+        };
+        let _ = request.reply.send(reply);
+        control
+    }
+}
+
+/// Copy the console output to a file or a Unix socket.
+fn add_serial_backend(cpu: &mut Cpu, backend: &str, path: &str) -> io::Result<()> {
+    let backend: Box<dyn Write + Send> = match backend {
+        "file" => Box::new(OpenOptions::new().create(true).append(true).open(path)?),
+        "unix" => Box::new(UnixStream::connect(path)?),
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unknown serial backend {}", backend),
+            ))
+        }
+    };
+    cpu.bus.add_serial_backend(backend);
+    Ok(())
+}
+
+/// Serve a client. Commands are forwarded to the emulator thread and replies are written back.
+fn serve(stream: UnixStream, sender: Sender<Request>) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let greeting = Json::object(vec![(
+        "QMP",
+        Json::object(vec![
+            (
+                "version",
+                Json::String(env!("CARGO_PKG_VERSION").to_string()),
+            ),
+            ("capabilities", Json::Array(vec![])),
+        ]),
+    )]);
+    writeln!(writer, "{}", greeting)?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match Json::parse(&line) {
+            Ok(command) => {
+                let (reply, receiver) = mpsc::channel();
+                if sender.send(Request { command, reply }).is_err() {
+                    break;
+                }
+                match receiver.recv() {
+                    Ok(reply) => reply,
+                    Err(_) => break,
+                }
+            }
+            Err(e) => Json::object(vec![(
+                "error",
+                Json::object(vec![
+                    ("class", Json::String("GenericError".to_string())),
+                    ("desc", Json::String(e)),
+                ]),
+            )]),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
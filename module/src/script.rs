@@ -0,0 +1,417 @@
+//! The script module contains a small scripting language to automate debugging without
+//! recompiling the emulator. A script sets breakpoints and trap handlers with access to registers
+//! and memory, and runs the emulator:
+//!
+//! ```text
+//! # Dump registers whenever the kernel panics, then stop.
+//! break panic
+//!     print "panic from " ra
+//!     dump
+//!     stop
+//! end
+//! # Count page faults on loads.
+//! on trap 13
+//!     print "load page fault at " csr.sepc " stval=" csr.stval
+//! end
+//! run 100000000
+//! ```
+//!
+//! Statements:
+//!
+//! ```text
+//! break <expr> ... end      Run the block when the program counter reaches <expr>.
+//! on trap [cause] ... end   Run the block after an exception is taken.
+//! delete <expr>             Delete a breakpoint.
+//! run [n]                   Execute n instructions, or until `stop` or a fatal exception.
+//! stop                      Stop `run` after the current block.
+//! print <arg>...            Print strings in double quotes and expressions.
+//! set <reg|pc> <expr>       Write a register or the program counter.
+//! poke <addr> <expr> [size] Write memory of size bits (default: 64).
+//! dump                      Dump the registers.
+//! info mem                  Print all valid mappings of the current page table.
+//! info irq                  Print the pending, enable, threshold and claim state of the PLIC.
+//! info clint                Print mtime and mtimecmp of the CLINT.
+//! translate <expr>          Translate a virtual address by the current page table.
+//! ```
+//!
+//! An expression is terms joined by `+` or `-` without spaces, e.g. `sp+16`. A term is a number
+//! in decimal or hexadecimal with `0x`, a register name, `pc`, `csr.<name>`, a symbol name, or
+//! `[<expr>]` for a 64-bit memory read through the current page table.
+
+use std::fs;
+use std::io;
+use std::rc::Rc;
+
+use crate::cpu::SATP;
+use crate::disasm::{csr_name, REG_NAMES};
+use crate::elf::SymbolTable;
+use crate::emulator::Emulator;
+use crate::mmu::{debug_load, debug_mappings, debug_store, debug_walk, pte_flags};
+use crate::trap::*;
+
+/// An argument of `print`.
+#[derive(Debug)]
+enum Arg {
+    Text(String),
+    Expr(String),
+}
+
+/// A statement. Expressions are kept as strings and evaluated when they run.
+#[derive(Debug)]
+enum Stmt {
+    Break(String, Rc<Vec<Stmt>>),
+    OnTrap(Option<u64>, Rc<Vec<Stmt>>),
+    Delete(String),
+    Run(Option<u64>),
+    Stop,
+    Print(Vec<Arg>),
+    Set(String, String),
+    Poke(String, String, u64),
+    Dump,
+    InfoMem,
+    InfoIrq,
+    InfoClint,
+    Translate(String),
+}
+
+fn error(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, message),
+    )
+}
+
+fn runtime_error(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message)
+}
+
+/// Split a line into words. A string in double quotes is a word starting with `"`.
+fn tokenize(line: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut chars = line.chars().peekable();
+    while let Some(&c) = chars.peek() {
+        if c.is_whitespace() {
+            chars.next();
+        } else if c == '#' {
+            break;
+        } else if c == '"' {
+            chars.next();
+            let mut word = String::from("\"");
+            for c in chars.by_ref() {
+                if c == '"' {
+                    break;
+                }
+                word.push(c);
+            }
+            words.push(word);
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            words.push(word);
+        }
+    }
+    words
+}
+
+/// Parse a number in decimal or in hexadecimal with a `0x` prefix.
+fn parse_number(s: &str) -> Option<u64> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => s.parse::<u64>().ok(),
+    }
+}
+
+/// Parse statements until `end` or the end of the lines.
+fn parse_block(
+    lines: &mut dyn Iterator<Item = (usize, Vec<String>)>,
+    nested: bool,
+) -> io::Result<Vec<Stmt>> {
+    let mut block = Vec::new();
+    while let Some((number, words)) = lines.next() {
+        let arg = |i: usize| {
+            words
+                .get(i)
+                .cloned()
+                .ok_or_else(|| error(number, "missing an argument"))
+        };
+        let stmt = match words[0].as_str() {
+            "end" if nested => return Ok(block),
+            "break" => Stmt::Break(arg(1)?, Rc::new(parse_block(lines, true)?)),
+            "on" if words.get(1).map(|w| w.as_str()) == Some("trap") => {
+                let cause = match words.get(2) {
+                    Some(cause) => {
+                        Some(parse_number(cause).ok_or_else(|| error(number, "invalid cause"))?)
+                    }
+                    None => None,
+                };
+                Stmt::OnTrap(cause, Rc::new(parse_block(lines, true)?))
+            }
+            "delete" => Stmt::Delete(arg(1)?),
+            "run" => Stmt::Run(match words.get(1) {
+                Some(n) => Some(parse_number(n).ok_or_else(|| error(number, "invalid count"))?),
+                None => None,
+            }),
+            "stop" => Stmt::Stop,
+            "print" => Stmt::Print(
+                words[1..]
+                    .iter()
+                    .map(|w| match w.strip_prefix('"') {
+                        Some(text) => Arg::Text(text.to_string()),
+                        None => Arg::Expr(w.clone()),
+                    })
+                    .collect(),
+            ),
+            "set" => Stmt::Set(arg(1)?, arg(2)?),
+            "poke" => {
+                let size = match words.get(3) {
+                    Some(size) => match parse_number(size) {
+                        Some(size @ (8 | 16 | 32 | 64)) => size,
+                        _ => return Err(error(number, "invalid size")),
+                    },
+                    None => 64,
+                };
+                Stmt::Poke(arg(1)?, arg(2)?, size)
+            }
+            "dump" => Stmt::Dump,
+            "info" => match words.get(1).map(|w| w.as_str()) {
+                Some("mem") => Stmt::InfoMem,
+                Some("irq") => Stmt::InfoIrq,
+                Some("clint") => Stmt::InfoClint,
+                _ => {
+                    return Err(error(
+                        number,
+                        "expected `info mem`, `info irq` or `info clint`",
+                    ))
+                }
+            },
+            "translate" => Stmt::Translate(arg(1)?),
+            word => return Err(error(number, &format!("unknown statement `{}`", word))),
+        };
+        block.push(stmt);
+    }
+    if nested {
+        return Err(runtime_error("missing `end` of a block".to_string()));
+    }
+    Ok(block)
+}
+
+/// A handler of breakpoints or traps.
+struct Handler {
+    /// An address, or an exception code which is None for all traps.
+    key: Option<u64>,
+    body: Rc<Vec<Stmt>>,
+}
+
+/// A script running an emulator.
+pub struct Script {
+    stmts: Rc<Vec<Stmt>>,
+    symbols: SymbolTable,
+    breakpoints: Vec<Handler>,
+    trap_handlers: Vec<Handler>,
+    /// True if `stop` has been executed in the current `run`.
+    stopping: bool,
+}
+
+impl Script {
+    /// Read a script from a file at `path`.
+    pub fn load(path: &str) -> io::Result<Self> {
+        Self::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Parse a script.
+    pub fn parse(source: &str) -> io::Result<Self> {
+        let mut lines = source
+            .lines()
+            .enumerate()
+            .map(|(i, line)| (i + 1, tokenize(line)))
+            .filter(|(_, words)| !words.is_empty());
+        Ok(Self {
+            stmts: Rc::new(parse_block(&mut lines, false)?),
+            symbols: SymbolTable::default(),
+            breakpoints: Vec::new(),
+            trap_handlers: Vec::new(),
+            stopping: false,
+        })
+    }
+
+    /// Use `symbols` to resolve names in expressions.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Run the script on `emu`.
+    pub fn run(&mut self, emu: &mut Emulator) -> io::Result<()> {
+        let stmts = self.stmts.clone();
+        self.execute(emu, &stmts)
+    }
+
+    /// Execute up to `count` instructions and run handlers of breakpoints and traps.
+    fn run_for(&mut self, emu: &mut Emulator, count: u64) -> io::Result<()> {
+        self.stopping = false;
+        for _ in 0..count {
+            let executed = match emu.step() {
+                Some(executed) => executed,
+                None => break,
+            };
+            if let Some(exception) = &executed.exception {
+                let code = exception.exception_code();
+                let bodies: Vec<Rc<Vec<Stmt>>> = self
+                    .trap_handlers
+                    .iter()
+                    .filter(|h| h.key.is_none_or(|key| key == code))
+                    .map(|h| h.body.clone())
+                    .collect();
+                for body in bodies {
+                    self.execute(emu, &body)?;
+                }
+            }
+            let pc = emu.cpu.pc;
+            let body = self
+                .breakpoints
+                .iter()
+                .find(|b| b.key == Some(pc))
+                .map(|b| b.body.clone());
+            if let Some(body) = body {
+                self.execute(emu, &body)?;
+            }
+            if self.stopping {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluate an expression.
+    fn eval(&self, emu: &mut Emulator, expr: &str) -> io::Result<u64> {
+        let invalid = || runtime_error(format!("invalid expression `{}`", expr));
+        let mut value: u64 = 0;
+        let mut rest = expr;
+        let mut negative = false;
+        loop {
+            // A memory read may contain `+` and `-` inside brackets.
+            let end = if rest.starts_with('[') {
+                rest.find(']').ok_or_else(invalid)? + 1
+            } else {
+                rest.find(['+', '-']).unwrap_or(rest.len())
+            };
+            let (term, next) = rest.split_at(end);
+            let term = self.eval_term(emu, term).ok_or_else(invalid)?;
+            value = if negative {
+                value.wrapping_sub(term)
+            } else {
+                value.wrapping_add(term)
+            };
+            match next.chars().next() {
+                None => return Ok(value),
+                Some(op) => {
+                    negative = op == '-';
+                    rest = &next[1..];
+                }
+            }
+        }
+    }
+
+    fn eval_term(&self, emu: &mut Emulator, term: &str) -> Option<u64> {
+        if let Some(inner) = term.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+            let addr = self.eval(emu, inner).ok()?;
+            return debug_load(&mut emu.cpu, addr, 64, None);
+        }
+        if let Some(name) = term.strip_prefix("csr.") {
+            let csr = (0..4096).find(|csr| csr_name(*csr) == name)?;
+            return Some(emu.cpu.load_csr(csr as usize));
+        }
+        if term == "pc" {
+            return Some(emu.cpu.pc);
+        }
+        if let Some(i) = REG_NAMES.iter().position(|reg| *reg == term) {
+            return Some(emu.cpu.regs[i]);
+        }
+        parse_number(term).or_else(|| self.symbols.lookup(term))
+    }
+
+    /// Execute statements.
+    fn execute(&mut self, emu: &mut Emulator, stmts: &[Stmt]) -> io::Result<()> {
+        for stmt in stmts {
+            match stmt {
+                Stmt::Break(expr, body) => {
+                    let addr = self.eval(emu, expr)?;
+                    self.breakpoints.retain(|b| b.key != Some(addr));
+                    self.breakpoints.push(Handler {
+                        key: Some(addr),
+                        body: body.clone(),
+                    });
+                }
+                Stmt::OnTrap(cause, body) => self.trap_handlers.push(Handler {
+                    key: *cause,
+                    body: body.clone(),
+                }),
+                Stmt::Delete(expr) => {
+                    let addr = self.eval(emu, expr)?;
+                    self.breakpoints.retain(|b| b.key != Some(addr));
+                }
+                Stmt::Run(count) => self.run_for(emu, count.unwrap_or(u64::MAX))?,
+                Stmt::Stop => self.stopping = true,
+                Stmt::Print(args) => {
+                    let mut line = String::new();
+                    for arg in args {
+                        match arg {
+                            Arg::Text(text) => line.push_str(text),
+                            Arg::Expr(expr) => {
+                                line.push_str(&format!("{:#x}", self.eval(emu, expr)?))
+                            }
+                        }
+                    }
+                    println!("{}", line);
+                }
+                Stmt::Set(target, expr) => {
+                    let value = self.eval(emu, expr)?;
+                    match REG_NAMES.iter().position(|reg| reg == target) {
+                        _ if target == "pc" => emu.cpu.pc = value,
+                        Some(0) => {}
+                        Some(i) => emu.cpu.regs[i] = value,
+                        None => {
+                            return Err(runtime_error(format!("unknown register `{}`", target)))
+                        }
+                    }
+                }
+                Stmt::Poke(addr, expr, size) => {
+                    let addr = self.eval(emu, addr)?;
+                    let value = self.eval(emu, expr)?;
+                    if debug_store(&mut emu.cpu, addr, *size, value, None).is_none() {
+                        println!("failed to write to {:#x}", addr);
+                    }
+                }
+                Stmt::Dump => emu.cpu.dump_registers(),
+                Stmt::InfoMem => {
+                    let mappings = debug_mappings(&mut emu.cpu, None);
+                    if mappings.is_empty() {
+                        println!("paging is off");
+                    }
+                    for mapping in mappings {
+                        println!("{}", mapping);
+                    }
+                }
+                Stmt::InfoIrq => println!("{}", emu.cpu.bus.plic_info()),
+                Stmt::InfoClint => println!("{}", emu.cpu.bus.clint_info()),
+                Stmt::Translate(expr) => {
+                    let addr = self.eval(emu, expr)?;
+                    let paging = emu.cpu.load_csr(SATP) >> 60 == 8;
+                    match debug_walk(&mut emu.cpu, addr, None) {
+                        Some((p_addr, pte)) => {
+                            println!("{:#x} -> {:#x} {}", addr, p_addr, pte_flags(pte))
+                        }
+                        None if paging => println!("{:#x} is not mapped", addr),
+                        None => println!("{:#x} -> {:#x} (paging is off)", addr, addr),
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
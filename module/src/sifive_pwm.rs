@@ -0,0 +1,213 @@
+//! The sifive_pwm module contains the PWM block found on SiFive boards. Each block has a counter
+//! and four comparators, and firmware uses it both for PWM outputs and as an extra timer through
+//! the comparator interrupts. The counter advances one cycle per instruction.
+//! See the spec: https://sifive.cdn.prismic.io/sifive/d3ed5cd0-6e74-46b2-a12d-72b06706513e_fu540-c000-manual-v1p4.pdf
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The interrupt request of the comparator 0 of PWM0, same as FU540. Comparators of a block use
+/// consecutive IRQs.
+pub const SIFIVE_PWM0_IRQ: u64 = 42;
+/// The interrupt request of the comparator 0 of PWM1, same as FU540.
+pub const SIFIVE_PWM1_IRQ: u64 = 46;
+
+/// Configuration register, an offset from the base of a block.
+pub const SIFIVE_PWM_CFG: u64 = 0x00;
+/// Counter register.
+pub const SIFIVE_PWM_COUNT: u64 = 0x08;
+/// Scaled counter register, read-only.
+pub const SIFIVE_PWM_S: u64 = 0x10;
+/// Compare registers 0-3.
+pub const SIFIVE_PWM_CMP0: u64 = 0x20;
+pub const SIFIVE_PWM_CMP3: u64 = 0x2c;
+
+// pwmcfg fields.
+const CFG_SCALE: u32 = 0xf;
+const CFG_STICKY: u32 = 1 << 8;
+const CFG_ZEROCMP: u32 = 1 << 9;
+const CFG_DEGLITCH: u32 = 1 << 10;
+const CFG_ENALWAYS: u32 = 1 << 12;
+const CFG_ENONESHOT: u32 = 1 << 13;
+const CFG_CMP_CENTER_SHIFT: u32 = 16;
+const CFG_CMP_IP_SHIFT: u32 = 28;
+/// The writable bits of pwmcfg.
+const CFG_MASK: u32 = 0xffff_370f;
+
+/// The width of the comparators in FU540.
+const CMP_WIDTH: u32 = 16;
+const CMP_MASK: u32 = (1 << CMP_WIDTH) - 1;
+/// The counter is 15 bits wider than the comparators.
+const COUNT_MASK: u32 = (1 << (CMP_WIDTH + 15)) - 1;
+
+/// A SiFive PWM block.
+pub struct SifivePwm {
+    /// The IRQ of the comparator 0.
+    irq: u64,
+    cfg: u32,
+    count: u32,
+    cmp: [u32; 4],
+    /// Comparator interrupt bits which have been set but not raised yet.
+    rising: u32,
+}
+
+impl Device for SifivePwm {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::Word => Ok(self.load32(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::Word => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Self::new(self.irq);
+    }
+}
+
+impl SifivePwm {
+    /// Create a new `SifivePwm` object whose comparator 0 raises `irq`.
+    pub fn new(irq: u64) -> Self {
+        Self {
+            irq,
+            cfg: 0,
+            count: 0,
+            cmp: [0; 4],
+            rising: 0,
+        }
+    }
+
+    /// Save the registers for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        for reg in [self.cfg, self.count, self.rising]
+            .iter()
+            .chain(self.cmp.iter())
+        {
+            write_u64(out, *reg as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the registers from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.cfg = read_u64(input)? as u32;
+        self.count = read_u64(input)? as u32;
+        self.rising = read_u64(input)? as u32;
+        for cmp in self.cmp.iter_mut() {
+            *cmp = read_u64(input)? as u32;
+        }
+        Ok(())
+    }
+
+    /// Return the scaled counter, pwms.
+    fn scaled(&self) -> u32 {
+        (self.count >> (self.cfg & CFG_SCALE)) & CMP_MASK
+    }
+
+    /// Return the interrupt pending bits of the comparators.
+    fn ip(&self) -> u32 {
+        (self.cfg >> CFG_CMP_IP_SHIFT) & 0xf
+    }
+
+    fn set_ip(&mut self, ip: u32) {
+        let old = self.ip();
+        self.rising |= ip & !old;
+        self.cfg = (self.cfg & !(0xf << CFG_CMP_IP_SHIFT)) | (ip << CFG_CMP_IP_SHIFT);
+    }
+
+    /// Advance the counter by one cycle and update the comparator outputs.
+    pub fn tick(&mut self) {
+        if self.cfg & (CFG_ENALWAYS | CFG_ENONESHOT) == 0 {
+            return;
+        }
+
+        let mut ip = self.ip();
+        let mut reset = false;
+        self.count = (self.count + 1) & COUNT_MASK;
+        if self.count == 0 {
+            reset = true;
+        } else if self.cfg & CFG_ZEROCMP != 0 && self.scaled() >= self.cmp[0] {
+            // The counter resets to zero when the scaled counter matches the comparator 0.
+            self.count = 0;
+            reset = true;
+        }
+        if reset {
+            // A one-shot cycle ends at a reset, and non-sticky outputs start over.
+            self.cfg &= !CFG_ENONESHOT;
+            if self.cfg & CFG_STICKY == 0 {
+                ip = 0;
+            }
+        }
+
+        let s = self.scaled();
+        for (i, cmp) in self.cmp.iter().enumerate() {
+            // In the center mode, the comparator sees the counter going up and down.
+            let center = (self.cfg >> (CFG_CMP_CENTER_SHIFT + i as u32)) & 1 != 0;
+            let value = if center && s & (1 << (CMP_WIDTH - 1)) != 0 {
+                !s & CMP_MASK
+            } else {
+                s
+            };
+            let out = value >= *cmp;
+            if out {
+                ip |= 1 << i;
+            } else if self.cfg & (CFG_STICKY | CFG_DEGLITCH) == 0 {
+                // A deglitched output stays high for the rest of the cycle.
+                ip &= !(1 << i);
+            }
+        }
+        self.set_ip(ip);
+    }
+
+    /// Return the IRQ if a comparator interrupt bit has been set since the last call.
+    pub fn is_interrupting(&mut self) -> Option<u64> {
+        if self.rising == 0 {
+            return None;
+        }
+        let i = self.rising.trailing_zeros();
+        self.rising &= !(1 << i);
+        Some(self.irq + i as u64)
+    }
+
+    fn load32(&self, addr: u64) -> u64 {
+        let value = match addr & 0xfff {
+            SIFIVE_PWM_CFG => self.cfg,
+            SIFIVE_PWM_COUNT => self.count,
+            SIFIVE_PWM_S => self.scaled(),
+            offset @ SIFIVE_PWM_CMP0..=SIFIVE_PWM_CMP3 if offset % 4 == 0 => {
+                self.cmp[((offset - SIFIVE_PWM_CMP0) / 4) as usize]
+            }
+            _ => 0,
+        };
+        value as u64
+    }
+
+    fn store32(&mut self, addr: u64, value: u64) {
+        let val = value as u32;
+        match addr & 0xfff {
+            SIFIVE_PWM_CFG => {
+                self.cfg = val & CFG_MASK;
+                // Interrupt bits written by software don't raise an interrupt.
+                self.rising &= self.ip();
+            }
+            SIFIVE_PWM_COUNT => self.count = val & COUNT_MASK,
+            offset @ SIFIVE_PWM_CMP0..=SIFIVE_PWM_CMP3 if offset % 4 == 0 => {
+                self.cmp[((offset - SIFIVE_PWM_CMP0) / 4) as usize] = val & CMP_MASK;
+            }
+            _ => {}
+        }
+    }
+}
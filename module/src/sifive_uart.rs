@@ -0,0 +1,306 @@
+//! The sifive_uart module contains the UART found on SiFive boards such as HiFive Unleashed. It's
+//! an alternative console device to the 16550a UART, so firmware written for SiFive hardware can
+//! run unmodified.
+//! See the spec: https://sifive.cdn.prismic.io/sifive/d3ed5cd0-6e74-46b2-a12d-72b06706513e_fu540-c000-manual-v1p4.pdf
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc, Condvar, Mutex,
+};
+use std::thread;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The interrupt request of the SiFive UART, same as UART0 in FU540.
+pub const SIFIVE_UART_IRQ: u64 = 4;
+
+/// Transmit data register. Writing the low byte transmits it. Reading returns the full bit (31).
+pub const SIFIVE_UART_TXDATA: u64 = SIFIVE_UART_BASE;
+/// Receive data register. Reading returns a received byte, or the empty bit (31) if there is no
+/// data.
+pub const SIFIVE_UART_RXDATA: u64 = SIFIVE_UART_BASE + 0x04;
+/// Transmit control register.
+pub const SIFIVE_UART_TXCTRL: u64 = SIFIVE_UART_BASE + 0x08;
+/// Receive control register.
+pub const SIFIVE_UART_RXCTRL: u64 = SIFIVE_UART_BASE + 0x0c;
+/// UART interrupt enable.
+pub const SIFIVE_UART_IE: u64 = SIFIVE_UART_BASE + 0x10;
+/// UART interrupt pending, read-only.
+pub const SIFIVE_UART_IP: u64 = SIFIVE_UART_BASE + 0x14;
+/// Baud rate divisor.
+pub const SIFIVE_UART_DIV: u64 = SIFIVE_UART_BASE + 0x18;
+
+/// The empty bit in the rxdata register.
+pub const SIFIVE_UART_RXDATA_EMPTY: u64 = 1 << 31;
+/// The transmit watermark bit in the ie and ip registers.
+pub const SIFIVE_UART_IP_TXWM: u64 = 1 << 0;
+/// The receive watermark bit in the ie and ip registers.
+pub const SIFIVE_UART_IP_RXWM: u64 = 1 << 1;
+/// The reset value of the divisor in FU540.
+const SIFIVE_UART_DIV_RESET: u32 = 0x21e;
+
+/// The SiFive UART.
+pub struct SifiveUart {
+    /// Pair of a received byte and a conditional variable.
+    rx: Arc<(Mutex<Option<u8>>, Condvar)>,
+    /// Bit if an interrupt happens.
+    interrupting: Arc<AtomicBool>,
+    /// True if a thread reading stdin has been started.
+    reading: bool,
+    /// The number of bytes transmitted and received.
+    io_bytes: u64,
+    /// Additional outputs which transmitted bytes are copied to, e.g. a log file.
+    backends: Vec<Box<dyn Write + Send>>,
+    /// An input source replacing stdin.
+    input: Option<Box<dyn Read + Send>>,
+    /// True if transmitted bytes are printed to stdout.
+    stdout: bool,
+    /// True if input is read by `poll_input` instead of a thread.
+    deterministic: bool,
+    txctrl: u32,
+    rxctrl: u32,
+    ie: u32,
+    div: u32,
+}
+
+impl Device for SifiveUart {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        self.start_reader();
+        match width {
+            Width::Word => Ok(self.load32(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        self.start_reader();
+        match width {
+            Width::Word => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    fn register_name(&self, addr: u64, _store: bool) -> Option<&'static str> {
+        let name = match addr {
+            SIFIVE_UART_TXDATA => "txdata",
+            SIFIVE_UART_RXDATA => "rxdata",
+            SIFIVE_UART_TXCTRL => "txctrl",
+            SIFIVE_UART_RXCTRL => "rxctrl",
+            SIFIVE_UART_IE => "ie",
+            SIFIVE_UART_IP => "ip",
+            SIFIVE_UART_DIV => "div",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    /// A received byte which a guest hasn't read is kept, so no input is lost.
+    fn reset(&mut self) {
+        self.txctrl = 0;
+        self.rxctrl = 0;
+        self.ie = 0;
+        self.div = SIFIVE_UART_DIV_RESET;
+    }
+}
+
+impl SifiveUart {
+    /// Create a new `SifiveUart` object.
+    pub fn new() -> Self {
+        Self {
+            rx: Arc::new((Mutex::new(None), Condvar::new())),
+            interrupting: Arc::new(AtomicBool::new(false)),
+            reading: false,
+            io_bytes: 0,
+            backends: Vec::new(),
+            input: None,
+            stdout: true,
+            deterministic: false,
+            txctrl: 0,
+            rxctrl: 0,
+            ie: 0,
+            div: SIFIVE_UART_DIV_RESET,
+        }
+    }
+
+    /// Start a thread reading stdin when a guest accesses the UART for the first time.
+    fn start_reader(&mut self) {
+        if self.reading {
+            return;
+        }
+        self.reading = true;
+        if self.deterministic {
+            return;
+        }
+
+        let mut input = self.input.take().unwrap_or_else(|| Box::new(io::stdin()));
+        let mut byte = [0; 1];
+        let cloned_rx = self.rx.clone();
+        let cloned_interrupting = self.interrupting.clone();
+        let _uart_thread_for_read = thread::spawn(move || loop {
+            match input.read(&mut byte) {
+                // The end of the input.
+                Ok(0) => break,
+                Ok(_) => {
+                    let (rx, cvar) = &*cloned_rx;
+                    let mut rx = rx.lock().expect("failed to get an UART object");
+                    // Wait for a guest to read the previous byte.
+                    while rx.is_some() {
+                        rx = cvar.wait(rx).expect("the mutex is poisoned");
+                    }
+                    *rx = Some(byte[0]);
+                    cloned_interrupting.store(true, Ordering::Release);
+                }
+                Err(e) => {
+                    println!("{}", e);
+                }
+            }
+        });
+    }
+
+    /// Read input by `poll_input` instead of a thread, so the time input arrives doesn't depend
+    /// on the host. It must be called before a guest accesses the UART.
+    pub fn set_deterministic(&mut self) {
+        self.deterministic = true;
+    }
+
+    /// Receive a byte if there is no received byte, blocking until the input has one. It does
+    /// nothing until a guest accesses the UART.
+    pub fn poll_input(&mut self) {
+        if !self.deterministic || !self.reading {
+            return;
+        }
+        let (rx, _cvar) = &*self.rx;
+        let mut rx = rx.lock().expect("failed to get an UART object");
+        if rx.is_some() {
+            return;
+        }
+        let input = self.input.get_or_insert_with(|| Box::new(io::stdin()));
+        let mut byte = [0; 1];
+        match input.read(&mut byte) {
+            Ok(1) => {
+                *rx = Some(byte[0]);
+                self.interrupting.store(true, Ordering::Release);
+            }
+            // The end of the input.
+            _ => self.input = Some(Box::new(io::empty())),
+        }
+    }
+
+    /// Return true if an interrupt is pending and the receive watermark interrupt is enabled.
+    pub fn is_interrupting(&self) -> bool {
+        if self.ie as u64 & SIFIVE_UART_IP_RXWM == 0 {
+            return false;
+        }
+        self.interrupting.swap(false, Ordering::Acquire)
+    }
+
+    /// Disconnect the UART from stdin and stdout. Input is read from `input` instead, and
+    /// transmitted bytes are only copied to backends. It must be called before a guest accesses
+    /// the UART.
+    pub fn detach(&mut self, input: Box<dyn Read + Send>) {
+        self.input = Some(input);
+        self.stdout = false;
+    }
+
+    /// Add an output which transmitted bytes are copied to.
+    pub fn add_backend(&mut self, backend: Box<dyn Write + Send>) {
+        self.backends.push(backend);
+    }
+
+    /// Save the registers and the received byte for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        for reg in [self.txctrl, self.rxctrl, self.ie, self.div] {
+            write_u64(out, reg as u64)?;
+        }
+        let (rx, _cvar) = &*self.rx;
+        let rx = *rx.lock().expect("failed to get an UART object");
+        write_bytes(out, rx.as_slice())?;
+        write_u64(out, self.interrupting.load(Ordering::Acquire) as u64)
+    }
+
+    /// Restore the registers and the received byte from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.txctrl = read_u64(input)? as u32;
+        self.rxctrl = read_u64(input)? as u32;
+        self.ie = read_u64(input)? as u32;
+        self.div = read_u64(input)? as u32;
+        let byte = read_bytes(input)?.first().copied();
+        let (rx, cvar) = &*self.rx;
+        *rx.lock().expect("failed to get an UART object") = byte;
+        cvar.notify_one();
+        self.interrupting
+            .store(read_u64(input)? != 0, Ordering::Release);
+        Ok(())
+    }
+
+    /// Return the number of bytes transmitted and received.
+    pub fn io_bytes(&self) -> u64 {
+        self.io_bytes
+    }
+
+    /// Return the value of the ip register. The transmitter is always ready, so the transmit
+    /// watermark is pending whenever the watermark level is above 0.
+    fn ip(&self) -> u64 {
+        let mut ip = 0;
+        if (self.txctrl >> 16) & 0x7 > 0 {
+            ip |= SIFIVE_UART_IP_TXWM;
+        }
+        let (rx, _cvar) = &*self.rx;
+        if rx.lock().expect("failed to get an UART object").is_some() {
+            ip |= SIFIVE_UART_IP_RXWM;
+        }
+        ip
+    }
+
+    fn load32(&mut self, addr: u64) -> u64 {
+        match addr {
+            SIFIVE_UART_TXDATA => 0,
+            SIFIVE_UART_RXDATA => {
+                let (rx, cvar) = &*self.rx;
+                let mut rx = rx.lock().expect("failed to get an UART object");
+                match rx.take() {
+                    Some(byte) => {
+                        self.io_bytes += 1;
+                        cvar.notify_one();
+                        byte as u64
+                    }
+                    None => SIFIVE_UART_RXDATA_EMPTY,
+                }
+            }
+            SIFIVE_UART_TXCTRL => self.txctrl as u64,
+            SIFIVE_UART_RXCTRL => self.rxctrl as u64,
+            SIFIVE_UART_IE => self.ie as u64,
+            SIFIVE_UART_IP => self.ip(),
+            SIFIVE_UART_DIV => self.div as u64,
+            _ => 0,
+        }
+    }
+
+    fn store32(&mut self, addr: u64, value: u64) {
+        let val = value as u32;
+        match addr {
+            SIFIVE_UART_TXDATA => {
+                self.io_bytes += 1;
+                if self.stdout {
+                    print!("{}", value as u8 as char);
+                    io::stdout().flush().expect("failed to flush stdout");
+                }
+                // A backend which fails to write, e.g. a closed socket, is removed.
+                self.backends
+                    .retain_mut(|backend| backend.write_all(&[value as u8]).is_ok());
+            }
+            SIFIVE_UART_TXCTRL => self.txctrl = val,
+            SIFIVE_UART_RXCTRL => self.rxctrl = val,
+            SIFIVE_UART_IE => self.ie = val,
+            SIFIVE_UART_DIV => self.div = val,
+            _ => {}
+        }
+    }
+}
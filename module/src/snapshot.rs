@@ -0,0 +1,96 @@
+//! The snapshot module contains saving and restoring the machine state to and from a file. A
+//! snapshot consists of the CPU state (registers, pc, mode and CSRs) followed by the state of each
+//! device on the bus, including the whole dram, the disk and the flash.
+//!
+//! State outside the emulator isn't saved. Saving fails while a plugin device is mapped or a
+//! vsock connection is open, and the WAV output of the sound device isn't reopened on restore.
+//! The flash and the file export device must be configured the same way at save and restore.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::{BufReader, BufWriter};
+
+use crate::cpu::*;
+
+/// The magic number at the start of a snapshot file.
+const SNAPSHOT_MAGIC: &[u8; 8] = b"RVEMUSNP";
+/// The version of the snapshot format.
+const SNAPSHOT_VERSION: u64 = 3;
+
+/// Write a 64-bit value in little endian.
+pub(crate) fn write_u64(out: &mut dyn Write, value: u64) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+/// Read a 64-bit value in little endian.
+pub(crate) fn read_u64(input: &mut dyn Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    input.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// Write bytes prefixed with the length.
+pub(crate) fn write_bytes(out: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    write_u64(out, bytes.len() as u64)?;
+    out.write_all(bytes)
+}
+
+/// Read bytes prefixed with the length.
+pub(crate) fn read_bytes(input: &mut dyn Read) -> io::Result<Vec<u8>> {
+    let len = read_u64(input)? as usize;
+    let mut bytes = vec![0; len];
+    input.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Save the machine state to a file at `path`.
+pub fn save(cpu: &Cpu, path: &str) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(SNAPSHOT_MAGIC)?;
+    write_u64(&mut out, SNAPSHOT_VERSION)?;
+
+    write_u64(&mut out, cpu.pc)?;
+    write_u64(&mut out, cpu.mode as u64)?;
+    for reg in cpu.regs.iter() {
+        write_u64(&mut out, *reg)?;
+    }
+    for csr in cpu.csrs.iter() {
+        write_u64(&mut out, *csr)?;
+    }
+    cpu.bus.save(&mut out)?;
+    out.flush()
+}
+
+/// Restore the machine state from a file at `path`.
+pub fn restore(cpu: &mut Cpu, path: &str) -> io::Result<()> {
+    let mut input = BufReader::new(File::open(path)?);
+    let mut magic = [0; 8];
+    input.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(invalid("not a snapshot file"));
+    }
+    if read_u64(&mut input)? != SNAPSHOT_VERSION {
+        return Err(invalid("unsupported snapshot version"));
+    }
+
+    cpu.pc = read_u64(&mut input)?;
+    cpu.mode = match read_u64(&mut input)? {
+        0b00 => Mode::User,
+        0b01 => Mode::Supervisor,
+        0b11 => Mode::Machine,
+        _ => return Err(invalid("invalid privilege mode")),
+    };
+    for i in 0..cpu.regs.len() {
+        cpu.regs[i] = read_u64(&mut input)?;
+    }
+    for i in 0..cpu.csrs.len() {
+        cpu.csrs[i] = read_u64(&mut input)?;
+    }
+    cpu.update_paging(SATP);
+    cpu.bus.restore(&mut input)
+}
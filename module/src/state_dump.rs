@@ -0,0 +1,103 @@
+//! The state_dump module contains dumps of the CPU state taken while a guest keeps running, to
+//! find where a guest which seems to hang is spinning without attaching a debugger. Sending
+//! SIGQUIT to the emulator, e.g. by Ctrl-\ in the terminal, or `Ctrl-A s` with `--mux` requests
+//! one, and the emulator prints the privilege mode, the registers, the main CSRs and the last
+//! executed instructions to stderr at the next instruction boundary:
+//!
+//! ```text
+//! kill -QUIT $(pidof rvemu-for-book)
+//! ```
+
+use std::os::raw::c_int;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::cpu::*;
+use crate::disasm::REG_NAMES;
+use crate::history::History;
+
+const SIGQUIT: c_int = 3;
+
+extern "C" {
+    fn signal(signum: c_int, handler: extern "C" fn(c_int)) -> usize;
+}
+
+/// True if a dump has been requested and not printed yet.
+static REQUESTED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn on_signal(_signum: c_int) {
+    // Only async-signal-safe operations are allowed here.
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// Request a dump. It's taken when the emulator polls next.
+pub fn request() {
+    REQUESTED.store(true, Ordering::Relaxed);
+}
+
+/// The CSRs in a dump.
+const CSRS: [(&str, usize); 15] = [
+    ("mstatus", MSTATUS),
+    ("mtvec", MTVEC),
+    ("mepc", MEPC),
+    ("mcause", MCAUSE),
+    ("mtval", MTVAL),
+    ("mie", MIE),
+    ("mip", MIP),
+    ("medeleg", MEDELEG),
+    ("mideleg", MIDELEG),
+    ("sstatus", SSTATUS),
+    ("stvec", STVEC),
+    ("sepc", SEPC),
+    ("scause", SCAUSE),
+    ("stval", STVAL),
+    ("satp", SATP),
+];
+
+/// Taker of dumps on request.
+pub struct StateDump;
+
+impl StateDump {
+    /// Handle SIGQUIT by requesting a dump.
+    pub fn install() -> Self {
+        // SAFETY: the handler only stores to an atomic variable.
+        unsafe {
+            signal(SIGQUIT, on_signal);
+        }
+        Self
+    }
+
+    /// Return a dump if one has been requested.
+    pub fn poll(&mut self, cpu: &Cpu, history: &History) -> Option<String> {
+        if !REQUESTED.swap(false, Ordering::Relaxed) {
+            return None;
+        }
+        Some(dump(cpu, history))
+    }
+}
+
+/// Return the state of `cpu` and the last executed instructions.
+pub fn dump(cpu: &Cpu, history: &History) -> String {
+    let mut text = format!(
+        "state dump: pc {:#x} mode {:?} after {} instructions\n",
+        cpu.pc,
+        cpu.mode,
+        history.recorded()
+    );
+    for i in (0..32).step_by(4) {
+        let line: Vec<String> = (i..i + 4)
+            .map(|r| format!("{:<4} {:#018x}", REG_NAMES[r], cpu.regs[r]))
+            .collect();
+        text.push_str(&line.join(" "));
+        text.push('\n');
+    }
+    for csrs in CSRS.chunks(4) {
+        let line: Vec<String> = csrs
+            .iter()
+            .map(|(name, addr)| format!("{:<8}{:#018x}", name, cpu.load_csr(*addr)))
+            .collect();
+        text.push_str(&line.join(" "));
+        text.push('\n');
+    }
+    text.push_str(&history.to_string());
+    text
+}
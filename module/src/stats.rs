@@ -0,0 +1,96 @@
+//! The stats module contains an exporter of interval statistics. Every N instructions, a row is
+//! appended to a CSV file, so long-running experiments can be plotted without custom
+//! instrumentation.
+//!
+//! The emulator doesn't have a TLB, so the number of page-table walks is reported instead of TLB
+//! hit rates. Every translation is a walk while paging is enabled. The number of failed SCs shows
+//! contention on guest locks built on LR/SC. The drift is how far the guest clock (mtime) lags
+//! the host clock in milliseconds, so it shows whether the emulator keeps up with real time.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::time::Instant;
+
+use crate::cpu::*;
+
+/// The default number of instructions between rows.
+pub const DEFAULT_STATS_INTERVAL: u64 = 1_000_000;
+
+/// An exporter writing interval statistics to a CSV file.
+pub struct IntervalStats {
+    out: BufWriter<File>,
+    /// The number of instructions between rows.
+    interval: u64,
+    /// The number of executed instructions.
+    instructions: u64,
+    /// The number of delivered interrupts.
+    interrupts: u64,
+    /// The values when the last row was written.
+    last_time: Instant,
+    last_interrupts: u64,
+    last_io_bytes: u64,
+    last_page_walks: u64,
+    last_sc_failures: u64,
+}
+
+impl IntervalStats {
+    /// Create a new CSV file at `path` and write a header.
+    pub fn create(path: &str, interval: u64, cpu: &Cpu) -> io::Result<Self> {
+        let mut out = BufWriter::new(File::create(path)?);
+        writeln!(
+            out,
+            "instructions,seconds,mips,interrupts,io_bytes,page_walks,sc_failures,drift_ms"
+        )?;
+        Ok(Self {
+            out,
+            interval,
+            instructions: 0,
+            interrupts: 0,
+            last_time: Instant::now(),
+            last_interrupts: 0,
+            last_io_bytes: cpu.bus.io_bytes(),
+            last_page_walks: cpu.page_walks,
+            last_sc_failures: cpu.sc_failures,
+        })
+    }
+
+    /// Count an executed instruction and append a row every `interval` instructions.
+    pub fn instruction(&mut self, cpu: &Cpu) -> io::Result<()> {
+        self.instructions += 1;
+        if !self.instructions.is_multiple_of(self.interval) {
+            return Ok(());
+        }
+
+        let now = Instant::now();
+        let seconds = now.duration_since(self.last_time).as_secs_f64();
+        let mips = self.interval as f64 / seconds / 1_000_000.0;
+        let io_bytes = cpu.bus.io_bytes();
+        writeln!(
+            self.out,
+            "{},{:.6},{:.3},{},{},{},{},{:.3}",
+            self.instructions,
+            seconds,
+            mips,
+            self.interrupts - self.last_interrupts,
+            io_bytes - self.last_io_bytes,
+            cpu.page_walks - self.last_page_walks,
+            cpu.sc_failures - self.last_sc_failures,
+            cpu.bus.clock_drift() as f64 / 1_000_000.0,
+        )?;
+        self.out.flush()?;
+
+        self.last_time = now;
+        self.last_interrupts = self.interrupts;
+        self.last_io_bytes = io_bytes;
+        self.last_page_walks = cpu.page_walks;
+        self.last_sc_failures = cpu.sc_failures;
+        Ok(())
+    }
+
+    /// Count a delivered interrupt.
+    pub fn interrupt(&mut self) {
+        self.interrupts += 1;
+    }
+}
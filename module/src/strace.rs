@@ -0,0 +1,302 @@
+//! The strace module contains a tracer of environment calls. `--strace <abi>` logs every ecall with
+//! its name, its arguments and, once the caller resumes, its return value:
+//! - An ecall from U-mode is a system call. The number in a7 is decoded by the system call table of
+//!   the guest OS, xv6 or Linux, and the arguments are in a0 to a5.
+//! - An ecall from S-mode is an SBI call. The extension ID in a7 and the function ID in a6 are
+//!   decoded, and the error and the value are returned in a0 and a1.
+//!
+//! A call returns when the caller's mode resumes at the instruction after the ecall in the same
+//! address space. Calls which never return, e.g. exit, are dropped after `MAX_PENDING_CALLS`
+//! newer calls.
+//!
+//! See: https://github.com/riscv-non-isa/riscv-sbi-doc
+
+use std::collections::VecDeque;
+
+use crate::cpu::*;
+use crate::trap::*;
+
+/// The number of calls waiting for their return values.
+const MAX_PENDING_CALLS: usize = 64;
+
+/// The system call table used to decode ecalls from U-mode.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum SyscallAbi {
+    Xv6,
+    Linux,
+}
+
+impl SyscallAbi {
+    /// Parse the name of a system call table.
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "xv6" => Some(SyscallAbi::Xv6),
+            "linux" => Some(SyscallAbi::Linux),
+            _ => None,
+        }
+    }
+}
+
+/// Return the name and the number of arguments of an xv6 system call.
+fn xv6_syscall(number: u64) -> Option<(&'static str, usize)> {
+    let syscall = match number {
+        1 => ("fork", 0),
+        2 => ("exit", 1),
+        3 => ("wait", 1),
+        4 => ("pipe", 1),
+        5 => ("read", 3),
+        6 => ("kill", 1),
+        7 => ("exec", 2),
+        8 => ("fstat", 2),
+        9 => ("chdir", 1),
+        10 => ("dup", 1),
+        11 => ("getpid", 0),
+        12 => ("sbrk", 1),
+        13 => ("sleep", 1),
+        14 => ("uptime", 0),
+        15 => ("open", 2),
+        16 => ("write", 3),
+        17 => ("mknod", 3),
+        18 => ("unlink", 1),
+        19 => ("link", 2),
+        20 => ("mkdir", 1),
+        21 => ("close", 1),
+        _ => return None,
+    };
+    Some(syscall)
+}
+
+/// Return the name and the number of arguments of a Linux system call on RISC-V.
+fn linux_syscall(number: u64) -> Option<(&'static str, usize)> {
+    let syscall = match number {
+        17 => ("getcwd", 2),
+        23 => ("dup", 1),
+        24 => ("dup3", 3),
+        25 => ("fcntl", 3),
+        29 => ("ioctl", 3),
+        34 => ("mkdirat", 3),
+        35 => ("unlinkat", 3),
+        48 => ("faccessat", 3),
+        49 => ("chdir", 1),
+        56 => ("openat", 4),
+        57 => ("close", 1),
+        59 => ("pipe2", 2),
+        61 => ("getdents64", 3),
+        62 => ("lseek", 3),
+        63 => ("read", 3),
+        64 => ("write", 3),
+        65 => ("readv", 3),
+        66 => ("writev", 3),
+        78 => ("readlinkat", 4),
+        79 => ("newfstatat", 4),
+        80 => ("fstat", 2),
+        93 => ("exit", 1),
+        94 => ("exit_group", 1),
+        96 => ("set_tid_address", 1),
+        98 => ("futex", 6),
+        99 => ("set_robust_list", 2),
+        101 => ("nanosleep", 2),
+        113 => ("clock_gettime", 2),
+        124 => ("sched_yield", 0),
+        129 => ("kill", 2),
+        134 => ("rt_sigaction", 4),
+        135 => ("rt_sigprocmask", 4),
+        160 => ("uname", 1),
+        172 => ("getpid", 0),
+        174 => ("getuid", 0),
+        175 => ("geteuid", 0),
+        176 => ("getgid", 0),
+        177 => ("getegid", 0),
+        178 => ("gettid", 0),
+        214 => ("brk", 1),
+        215 => ("munmap", 2),
+        220 => ("clone", 5),
+        221 => ("execve", 3),
+        222 => ("mmap", 6),
+        226 => ("mprotect", 3),
+        260 => ("wait4", 4),
+        261 => ("prlimit64", 4),
+        278 => ("getrandom", 3),
+        _ => return None,
+    };
+    Some(syscall)
+}
+
+/// Return the name and the number of arguments of an SBI function.
+fn sbi_function(eid: u64, fid: u64) -> Option<(&'static str, usize)> {
+    let function = match (eid, fid) {
+        // Legacy extensions, whose extension ID is the function.
+        (0x00, _) => ("sbi_set_timer", 1),
+        (0x01, _) => ("sbi_console_putchar", 1),
+        (0x02, _) => ("sbi_console_getchar", 0),
+        (0x03, _) => ("sbi_clear_ipi", 0),
+        (0x04, _) => ("sbi_send_ipi", 1),
+        (0x05, _) => ("sbi_remote_fence_i", 1),
+        (0x06, _) => ("sbi_remote_sfence_vma", 3),
+        (0x07, _) => ("sbi_remote_sfence_vma_asid", 4),
+        (0x08, _) => ("sbi_shutdown", 0),
+        // Base extension.
+        (0x10, 0) => ("sbi_get_spec_version", 0),
+        (0x10, 1) => ("sbi_get_impl_id", 0),
+        (0x10, 2) => ("sbi_get_impl_version", 0),
+        (0x10, 3) => ("sbi_probe_extension", 1),
+        (0x10, 4) => ("sbi_get_mvendorid", 0),
+        (0x10, 5) => ("sbi_get_marchid", 0),
+        (0x10, 6) => ("sbi_get_mimpid", 0),
+        // "TIME"
+        (0x5449_4d45, 0) => ("sbi_set_timer", 1),
+        // "sPI"
+        (0x0073_5049, 0) => ("sbi_send_ipi", 2),
+        // "RFNC"
+        (0x5246_4e43, 0) => ("sbi_remote_fence_i", 2),
+        (0x5246_4e43, 1) => ("sbi_remote_sfence_vma", 4),
+        (0x5246_4e43, 2) => ("sbi_remote_sfence_vma_asid", 5),
+        // "HSM"
+        (0x0048_534d, 0) => ("sbi_hart_start", 3),
+        (0x0048_534d, 1) => ("sbi_hart_stop", 0),
+        (0x0048_534d, 2) => ("sbi_hart_get_status", 1),
+        (0x0048_534d, 3) => ("sbi_hart_suspend", 3),
+        // "SRST"
+        (0x5352_5354, 0) => ("sbi_system_reset", 2),
+        // "DBCN"
+        (0x4442_434e, 0) => ("sbi_debug_console_write", 3),
+        (0x4442_434e, 1) => ("sbi_debug_console_read", 3),
+        (0x4442_434e, 2) => ("sbi_debug_console_write_byte", 1),
+        _ => return None,
+    };
+    Some(function)
+}
+
+/// Return the name of an SBI error code.
+fn sbi_error(error: u64) -> String {
+    let name = match error as i64 {
+        0 => "SBI_SUCCESS",
+        -1 => "SBI_ERR_FAILED",
+        -2 => "SBI_ERR_NOT_SUPPORTED",
+        -3 => "SBI_ERR_INVALID_PARAM",
+        -4 => "SBI_ERR_DENIED",
+        -5 => "SBI_ERR_INVALID_ADDRESS",
+        -6 => "SBI_ERR_ALREADY_AVAILABLE",
+        -7 => "SBI_ERR_ALREADY_STARTED",
+        -8 => "SBI_ERR_ALREADY_STOPPED",
+        _ => return format!("{}", error as i64),
+    };
+    name.to_string()
+}
+
+/// Format a return value of a system call. Small negative values are errors.
+fn syscall_value(value: u64) -> String {
+    match value as i64 {
+        -4095..=-1 => format!("{}", value as i64),
+        v @ 0..=0xffff => format!("{}", v),
+        _ => format!("{:#x}", value),
+    }
+}
+
+/// A call which hasn't returned yet.
+struct Call {
+    /// The mode, the pc and the address space where the caller resumes.
+    mode: Mode,
+    pc: u64,
+    satp: u64,
+    name: String,
+    /// True if it's an SBI call other than a legacy one, which returns an error and a value.
+    sbi: bool,
+}
+
+/// A tracer of environment calls.
+pub struct Strace {
+    abi: SyscallAbi,
+    pending: VecDeque<Call>,
+}
+
+impl Strace {
+    /// Create a new `Strace` object decoding system calls by `abi`.
+    pub fn new(abi: SyscallAbi) -> Self {
+        Self {
+            abi,
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Record an ecall at `pc` which raised `exception`, before the trap is taken. Return the log
+    /// line, or None if the exception isn't an environment call.
+    pub fn ecall(&mut self, cpu: &Cpu, pc: u64, exception: &Exception) -> Option<String> {
+        let (mode, letter) = match exception {
+            Exception::EnvironmentCallFromUMode => (Mode::User, 'U'),
+            Exception::EnvironmentCallFromSMode => (Mode::Supervisor, 'S'),
+            Exception::EnvironmentCallFromMMode => (Mode::Machine, 'M'),
+            _ => return None,
+        };
+        let a7 = cpu.regs[17];
+        let a6 = cpu.regs[16];
+        let (name, argc, sbi) = match mode {
+            Mode::User => {
+                let syscall = match self.abi {
+                    SyscallAbi::Xv6 => xv6_syscall(a7),
+                    SyscallAbi::Linux => linux_syscall(a7),
+                };
+                match syscall {
+                    Some((name, argc)) => (name.to_string(), argc, false),
+                    None => (format!("syscall_{}", a7), 6, false),
+                }
+            }
+            _ => match sbi_function(a7, a6) {
+                Some((name, argc)) => (name.to_string(), argc, a7 > 0x0f),
+                None => (format!("sbi_{:#x}_{}", a7, a6), 6, true),
+            },
+        };
+        let args: Vec<String> = cpu.regs[10..10 + argc]
+            .iter()
+            .map(|arg| format!("{:#x}", arg))
+            .collect();
+
+        if self.pending.len() == MAX_PENDING_CALLS {
+            self.pending.pop_front();
+        }
+        self.pending.push_back(Call {
+            mode: cpu.mode,
+            pc: pc.wrapping_add(4),
+            satp: cpu.load_csr(SATP),
+            name: name.clone(),
+            sbi,
+        });
+        Some(format!(
+            "strace: [{} {:#x}] {}({})",
+            letter,
+            pc,
+            name,
+            args.join(", ")
+        ))
+    }
+
+    /// Check if a pending call has returned, i.e. its caller resumes at `cpu.pc`, and return the
+    /// log line of the return value.
+    pub fn returned(&mut self, cpu: &Cpu) -> Option<String> {
+        if self.pending.is_empty() {
+            return None;
+        }
+        let satp = cpu.load_csr(SATP);
+        let index = self
+            .pending
+            .iter()
+            .rposition(|call| call.pc == cpu.pc && call.mode == cpu.mode && call.satp == satp)?;
+        let call = self.pending.remove(index)?;
+        let letter = match call.mode {
+            Mode::User => 'U',
+            Mode::Supervisor => 'S',
+            Mode::Machine => 'M',
+        };
+        let result = match call.sbi {
+            true => format!("{}, {:#x}", sbi_error(cpu.regs[10]), cpu.regs[11]),
+            false => syscall_value(cpu.regs[10]),
+        };
+        Some(format!(
+            "strace: [{} {:#x}] {} = {}",
+            letter,
+            call.pc.wrapping_sub(4),
+            call.name,
+            result
+        ))
+    }
+}
@@ -0,0 +1,172 @@
+//! The timeline module contains a ring buffer of the last privilege-mode transitions: traps with
+//! their causes and the pc saved to sepc or mepc, and returns by sret and mret. `--timeline <n>`
+//! keeps the last n transitions and prints them on exit, and the QMP command `query-timeline`
+//! returns them while the guest is running. A guest returning to the same ecall forever shows up
+//! as the same trap and return repeating at the same pc:
+//!
+//! ```text
+//! #41 trap U->S cause=8 (environment call from U-mode) epc=0x4c8 handler=0x80005000
+//! #42 sret S->U pc=0x4c8
+//! ```
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use crate::cpu::*;
+
+/// A privilege-mode transition.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TimelineEvent {
+    /// A trap with `cause`, the value written to mcause or scause, from the instruction at `epc`
+    /// to the trap handler at `handler`.
+    Trap {
+        cause: u64,
+        from: Mode,
+        to: Mode,
+        epc: u64,
+        handler: u64,
+    },
+    /// A return from a trap handler by sret or mret to `pc`, the value of sepc or mepc.
+    Return { from: Mode, to: Mode, pc: u64 },
+}
+
+/// Return the letter of a privilege mode.
+fn mode_letter(mode: Mode) -> char {
+    match mode {
+        Mode::User => 'U',
+        Mode::Supervisor => 'S',
+        Mode::Machine => 'M',
+    }
+}
+
+/// Return the description of a trap cause.
+fn cause_name(cause: u64) -> &'static str {
+    let interrupt = cause >> 63 == 1;
+    match (interrupt, cause & !(1 << 63)) {
+        (true, 0) => "user software interrupt",
+        (true, 1) => "supervisor software interrupt",
+        (true, 3) => "machine software interrupt",
+        (true, 4) => "user timer interrupt",
+        (true, 5) => "supervisor timer interrupt",
+        (true, 7) => "machine timer interrupt",
+        (true, 8) => "user external interrupt",
+        (true, 9) => "supervisor external interrupt",
+        (true, 11) => "machine external interrupt",
+        (false, 0) => "instruction address misaligned",
+        (false, 1) => "instruction access fault",
+        (false, 2) => "illegal instruction",
+        (false, 3) => "breakpoint",
+        (false, 4) => "load address misaligned",
+        (false, 5) => "load access fault",
+        (false, 6) => "store/AMO address misaligned",
+        (false, 7) => "store/AMO access fault",
+        (false, 8) => "environment call from U-mode",
+        (false, 9) => "environment call from S-mode",
+        (false, 11) => "environment call from M-mode",
+        (false, 12) => "instruction page fault",
+        (false, 13) => "load page fault",
+        (false, 15) => "store/AMO page fault",
+        _ => "unknown",
+    }
+}
+
+impl fmt::Display for TimelineEvent {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TimelineEvent::Trap {
+                cause,
+                from,
+                to,
+                epc,
+                handler,
+            } => {
+                let code = match cause >> 63 {
+                    1 => format!("interrupt {}", cause & !(1 << 63)),
+                    _ => format!("{}", cause),
+                };
+                write!(
+                    f,
+                    "trap {}->{} cause={} ({}) epc={:#x} handler={:#x}",
+                    mode_letter(from),
+                    mode_letter(to),
+                    code,
+                    cause_name(cause),
+                    epc,
+                    handler
+                )
+            }
+            TimelineEvent::Return { from, to, pc } => {
+                let name = match from {
+                    Mode::Machine => "mret",
+                    _ => "sret",
+                };
+                write!(
+                    f,
+                    "{} {}->{} pc={:#x}",
+                    name,
+                    mode_letter(from),
+                    mode_letter(to),
+                    pc
+                )
+            }
+        }
+    }
+}
+
+/// A ring buffer of the last privilege-mode transitions.
+#[derive(Debug, Default)]
+pub struct Timeline {
+    /// The events with their sequence numbers, from the oldest.
+    events: VecDeque<(u64, TimelineEvent)>,
+    /// The number of events kept, or 0 if the timeline is disabled.
+    capacity: usize,
+    /// The number of events recorded so far.
+    count: u64,
+}
+
+impl Timeline {
+    /// Create a new `Timeline` object keeping the last `capacity` events.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            count: 0,
+        }
+    }
+
+    /// Return true if events are recorded.
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    /// Record an event, dropping the oldest one if the buffer is full.
+    pub fn record(&mut self, event: TimelineEvent) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.events.len() == self.capacity {
+            self.events.pop_front();
+        }
+        self.count += 1;
+        self.events.push_back((self.count, event));
+    }
+
+    /// Forget all events.
+    pub fn clear(&mut self) {
+        self.events.clear();
+        self.count = 0;
+    }
+
+    /// Return the kept events with their sequence numbers, from the oldest.
+    pub fn events(&self) -> impl Iterator<Item = &(u64, TimelineEvent)> {
+        self.events.iter()
+    }
+
+    /// Return the kept events, one per line.
+    pub fn dump(&self) -> String {
+        self.events
+            .iter()
+            .map(|(n, event)| format!("#{} {}\n", n, event))
+            .collect()
+    }
+}
@@ -0,0 +1,702 @@
+//! The torture module contains a generator of random programs to check the emulator against a
+//! simple reference model, like riscv-torture. A program is a straight-line mix of integer,
+//! memory and atomic instructions with forward branches and jumps, so it always terminates. Loads
+//! and stores stay in a sandbox addressed by registers which the program never writes:
+//!
+//! ```text
+//! rvemu-for-book --seed 1 --torture 1000
+//! ```
+//!
+//! Each program runs in the emulator and in the reference model from the same random registers
+//! and sandbox, and their final registers and sandboxes are compared. The reference model
+//! interprets the generated operations directly instead of decoding instructions, so it shares no
+//! code with the emulator.
+
+use std::fmt;
+
+use crate::bus::DRAM_BASE;
+use crate::cpu::Cpu;
+use crate::disasm::disassemble;
+use crate::emulator::Emulator;
+use crate::entropy::Entropy;
+
+/// The address of the memory sandbox which loads and stores access.
+pub const SANDBOX_BASE: u64 = DRAM_BASE + 0x10_0000;
+/// The size of the memory sandbox.
+pub const SANDBOX_SIZE: u64 = 0x1000;
+/// The default number of operations in a program.
+pub const DEFAULT_PROGRAM_LEN: usize = 200;
+
+/// The register holding `BASE_ADDR` for loads and stores.
+const BASE_REG: usize = 31;
+/// The register holding `AMO_ADDR` for atomic instructions.
+const AMO_REG: usize = 30;
+/// The address which loads and stores are relative to. It's in the middle of the sandbox so that
+/// 12-bit signed offsets cover all of it.
+const BASE_ADDR: u64 = SANDBOX_BASE + 0x800;
+/// The address which atomic instructions access, aligned to 8 bytes.
+const AMO_ADDR: u64 = SANDBOX_BASE + 0x100;
+/// Interesting register values which random ones are mixed with.
+const SPECIAL_VALUES: [u64; 8] = [
+    0,
+    1,
+    u64::MAX,
+    0x7fff_ffff_ffff_ffff,
+    0x8000_0000_0000_0000,
+    0x7fff_ffff,
+    0x8000_0000,
+    0xffff_ffff,
+];
+
+/// An operation of a register-register instruction.
+#[derive(Debug, Clone, Copy)]
+enum AluOp {
+    Add,
+    Sub,
+    Sll,
+    Slt,
+    Sltu,
+    Xor,
+    Srl,
+    Sra,
+    Or,
+    And,
+    Mul,
+    Rol,
+    Ror,
+    Xnor,
+    Orn,
+    Andn,
+    Pack,
+    Packh,
+    Addw,
+    Subw,
+    Sllw,
+    Srlw,
+    Sraw,
+    Rolw,
+    Rorw,
+    Packw,
+}
+
+/// An operation of a register-immediate instruction.
+#[derive(Debug, Clone, Copy)]
+enum ImmOp {
+    Addi,
+    Slti,
+    Sltiu,
+    Xori,
+    Ori,
+    Andi,
+    Slli,
+    Srli,
+    Srai,
+    Rori,
+    Rev8,
+    Addiw,
+    Slliw,
+    Srliw,
+    Sraiw,
+    Roriw,
+}
+
+/// A branch condition.
+#[derive(Debug, Clone, Copy)]
+enum Cond {
+    Eq,
+    Ne,
+    Lt,
+    Ge,
+    Ltu,
+    Geu,
+}
+
+/// An operation of an atomic memory instruction.
+#[derive(Debug, Clone, Copy)]
+enum AmoOp {
+    Add,
+    Swap,
+    Xor,
+    And,
+    Or,
+    Min,
+    Max,
+    Minu,
+    Maxu,
+}
+
+/// An operation in a program. Branch and jump targets are indexes of operations.
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Alu(AluOp, usize, usize, usize),
+    Imm(ImmOp, usize, usize, i64),
+    Lui(usize, i64),
+    Auipc(usize, i64),
+    /// A load of `size` bytes at `BASE_ADDR + offset`, sign-extended if `signed` is true.
+    Load(u64, bool, usize, i64),
+    /// A store of `size` bytes at `BASE_ADDR + offset`.
+    Store(u64, usize, i64),
+    Branch(Cond, usize, usize, usize),
+    Jal(usize, usize),
+    /// An atomic operation at `AMO_ADDR` on a word if `word` is true, or a doubleword.
+    Amo(AmoOp, bool, usize, usize),
+}
+
+fn r_type(funct7: u32, rs2: usize, rs1: usize, funct3: u32, rd: usize, opcode: u32) -> u32 {
+    (funct7 << 25)
+        | ((rs2 as u32) << 20)
+        | ((rs1 as u32) << 15)
+        | (funct3 << 12)
+        | ((rd as u32) << 7)
+        | opcode
+}
+
+fn i_type(imm: i64, rs1: usize, funct3: u32, rd: usize, opcode: u32) -> u32 {
+    ((imm as u32 & 0xfff) << 20)
+        | ((rs1 as u32) << 15)
+        | (funct3 << 12)
+        | ((rd as u32) << 7)
+        | opcode
+}
+
+fn s_type(imm: i64, rs2: usize, rs1: usize, funct3: u32) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5 & 0x7f) << 25)
+        | ((rs2 as u32) << 20)
+        | ((rs1 as u32) << 15)
+        | (funct3 << 12)
+        | ((imm & 0x1f) << 7)
+        | 0x23
+}
+
+fn b_type(offset: i64, rs2: usize, rs1: usize, funct3: u32) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 12 & 1) << 31)
+        | ((imm >> 5 & 0x3f) << 25)
+        | ((rs2 as u32) << 20)
+        | ((rs1 as u32) << 15)
+        | (funct3 << 12)
+        | ((imm >> 1 & 0xf) << 8)
+        | ((imm >> 11 & 1) << 7)
+        | 0x63
+}
+
+fn j_type(offset: i64, rd: usize) -> u32 {
+    let imm = offset as u32;
+    ((imm >> 20 & 1) << 31)
+        | ((imm >> 1 & 0x3ff) << 21)
+        | ((imm >> 11 & 1) << 20)
+        | ((imm >> 12 & 0xff) << 12)
+        | ((rd as u32) << 7)
+        | 0x6f
+}
+
+impl Op {
+    /// Encode the operation at index `i` of a program.
+    fn encode(&self, i: usize) -> u32 {
+        let offset = |target: usize| (target as i64 - i as i64) * 4;
+        match *self {
+            Op::Alu(op, rd, rs1, rs2) => {
+                let (funct7, funct3, opcode) = match op {
+                    AluOp::Add => (0x00, 0, 0x33),
+                    AluOp::Sub => (0x20, 0, 0x33),
+                    AluOp::Sll => (0x00, 1, 0x33),
+                    AluOp::Slt => (0x00, 2, 0x33),
+                    AluOp::Sltu => (0x00, 3, 0x33),
+                    AluOp::Xor => (0x00, 4, 0x33),
+                    AluOp::Srl => (0x00, 5, 0x33),
+                    AluOp::Sra => (0x20, 5, 0x33),
+                    AluOp::Or => (0x00, 6, 0x33),
+                    AluOp::And => (0x00, 7, 0x33),
+                    AluOp::Mul => (0x01, 0, 0x33),
+                    AluOp::Rol => (0x30, 1, 0x33),
+                    AluOp::Ror => (0x30, 5, 0x33),
+                    AluOp::Xnor => (0x20, 4, 0x33),
+                    AluOp::Orn => (0x20, 6, 0x33),
+                    AluOp::Andn => (0x20, 7, 0x33),
+                    AluOp::Pack => (0x04, 4, 0x33),
+                    AluOp::Packh => (0x04, 7, 0x33),
+                    AluOp::Addw => (0x00, 0, 0x3b),
+                    AluOp::Subw => (0x20, 0, 0x3b),
+                    AluOp::Sllw => (0x00, 1, 0x3b),
+                    AluOp::Srlw => (0x00, 5, 0x3b),
+                    AluOp::Sraw => (0x20, 5, 0x3b),
+                    AluOp::Rolw => (0x30, 1, 0x3b),
+                    AluOp::Rorw => (0x30, 5, 0x3b),
+                    AluOp::Packw => (0x04, 4, 0x3b),
+                };
+                r_type(funct7, rs2, rs1, funct3, rd, opcode)
+            }
+            Op::Imm(op, rd, rs1, imm) => {
+                let (imm, funct3, opcode) = match op {
+                    ImmOp::Addi => (imm, 0, 0x13),
+                    ImmOp::Slti => (imm, 2, 0x13),
+                    ImmOp::Sltiu => (imm, 3, 0x13),
+                    ImmOp::Xori => (imm, 4, 0x13),
+                    ImmOp::Ori => (imm, 6, 0x13),
+                    ImmOp::Andi => (imm, 7, 0x13),
+                    ImmOp::Slli => (imm, 1, 0x13),
+                    ImmOp::Srli => (imm, 5, 0x13),
+                    ImmOp::Srai => (0x400 | imm, 5, 0x13),
+                    ImmOp::Rori => (0x600 | imm, 5, 0x13),
+                    ImmOp::Rev8 => (0x6b8, 5, 0x13),
+                    ImmOp::Addiw => (imm, 0, 0x1b),
+                    ImmOp::Slliw => (imm, 1, 0x1b),
+                    ImmOp::Srliw => (imm, 5, 0x1b),
+                    ImmOp::Sraiw => (0x400 | imm, 5, 0x1b),
+                    ImmOp::Roriw => (0x600 | imm, 5, 0x1b),
+                };
+                i_type(imm, rs1, funct3, rd, opcode)
+            }
+            Op::Lui(rd, imm) => ((imm as u32) << 12) | ((rd as u32) << 7) | 0x37,
+            Op::Auipc(rd, imm) => ((imm as u32) << 12) | ((rd as u32) << 7) | 0x17,
+            Op::Load(size, signed, rd, offset) => {
+                let funct3 = match (size, signed) {
+                    (1, true) => 0,
+                    (2, true) => 1,
+                    (4, true) => 2,
+                    (8, _) => 3,
+                    (1, false) => 4,
+                    (2, false) => 5,
+                    _ => 6,
+                };
+                i_type(offset, BASE_REG, funct3, rd, 0x03)
+            }
+            Op::Store(size, rs2, offset) => s_type(offset, rs2, BASE_REG, size.trailing_zeros()),
+            Op::Branch(cond, rs1, rs2, target) => {
+                let funct3 = match cond {
+                    Cond::Eq => 0,
+                    Cond::Ne => 1,
+                    Cond::Lt => 4,
+                    Cond::Ge => 5,
+                    Cond::Ltu => 6,
+                    Cond::Geu => 7,
+                };
+                b_type(offset(target), rs2, rs1, funct3)
+            }
+            Op::Jal(rd, target) => j_type(offset(target), rd),
+            Op::Amo(op, word, rd, rs2) => {
+                let funct5 = match op {
+                    AmoOp::Add => 0x00,
+                    AmoOp::Swap => 0x01,
+                    AmoOp::Xor => 0x04,
+                    AmoOp::Or => 0x08,
+                    AmoOp::And => 0x0c,
+                    AmoOp::Min => 0x10,
+                    AmoOp::Max => 0x14,
+                    AmoOp::Minu => 0x18,
+                    AmoOp::Maxu => 0x1c,
+                };
+                let funct3 = if word { 2 } else { 3 };
+                r_type(funct5 << 2, rs2, AMO_REG, funct3, rd, 0x2f)
+            }
+        }
+    }
+}
+
+/// A random program with its initial state.
+#[derive(Debug, Clone)]
+pub struct Program {
+    ops: Vec<Op>,
+    /// The initial registers.
+    regs: [u64; 32],
+    /// The initial sandbox.
+    sandbox: Vec<u8>,
+}
+
+/// The final state of a program.
+#[derive(Debug, PartialEq, Eq)]
+struct State {
+    regs: [u64; 32],
+    sandbox: Vec<u8>,
+    /// The first exception raised, which the reference model never raises.
+    exception: Option<String>,
+}
+
+impl Program {
+    /// Generate a program of `len` operations with `entropy`.
+    pub fn generate(entropy: &mut Entropy, len: usize) -> Self {
+        let mut regs = [0; 32];
+        for reg in regs.iter_mut().skip(1) {
+            *reg = match entropy.below(3) {
+                0 => SPECIAL_VALUES[entropy.below(SPECIAL_VALUES.len() as u64) as usize],
+                _ => entropy.next_u64(),
+            };
+        }
+        regs[BASE_REG] = BASE_ADDR;
+        regs[AMO_REG] = AMO_ADDR;
+        let mut sandbox = vec![0; SANDBOX_SIZE as usize];
+        entropy.fill_bytes(&mut sandbox);
+
+        let ops = (0..len)
+            .map(|i| Self::generate_op(entropy, i, len))
+            .collect();
+        Self { ops, regs, sandbox }
+    }
+
+    fn generate_op(entropy: &mut Entropy, i: usize, len: usize) -> Op {
+        use AluOp::*;
+        use ImmOp::*;
+        const ALU_OPS: [AluOp; 26] = [
+            Add, Sub, Sll, Slt, Sltu, Xor, Srl, Sra, Or, And, Mul, Rol, Ror, Xnor, Orn, Andn, Pack,
+            Packh, Addw, Subw, Sllw, Srlw, Sraw, Rolw, Rorw, Packw,
+        ];
+        const IMM_OPS: [ImmOp; 16] = [
+            Addi, Slti, Sltiu, Xori, Ori, Andi, Slli, Srli, Srai, Rori, Rev8, Addiw, Slliw, Srliw,
+            Sraiw, Roriw,
+        ];
+        const CONDS: [Cond; 6] = [Cond::Eq, Cond::Ne, Cond::Lt, Cond::Ge, Cond::Ltu, Cond::Geu];
+        const AMO_OPS: [AmoOp; 9] = [
+            AmoOp::Add,
+            AmoOp::Swap,
+            AmoOp::Xor,
+            AmoOp::And,
+            AmoOp::Or,
+            AmoOp::Min,
+            AmoOp::Max,
+            AmoOp::Minu,
+            AmoOp::Maxu,
+        ];
+        const SIZES: [u64; 4] = [1, 2, 4, 8];
+
+        // The program must not write the sandbox registers.
+        let rd = entropy.below(AMO_REG as u64) as usize;
+        let mut rs = || entropy.below(32) as usize;
+        let (rs1, rs2) = (rs(), rs());
+        // A target from the next operation to the end of the program.
+        let target = i + 1 + entropy.below((len - i) as u64) as usize;
+        match entropy.below(16) {
+            0..=5 => Op::Alu(ALU_OPS[entropy.below(26) as usize], rd, rs1, rs2),
+            6..=9 => {
+                let op = IMM_OPS[entropy.below(16) as usize];
+                let imm = match op {
+                    Slli | Srli | Srai | Rori => entropy.below(64) as i64,
+                    Slliw | Srliw | Sraiw | Roriw => entropy.below(32) as i64,
+                    _ => entropy.below(4096) as i64 - 2048,
+                };
+                Op::Imm(op, rd, rs1, imm)
+            }
+            10 => match entropy.below(2) {
+                0 => Op::Lui(rd, entropy.below(1 << 20) as i64),
+                _ => Op::Auipc(rd, entropy.below(1 << 20) as i64),
+            },
+            11 | 12 => {
+                let size = SIZES[entropy.below(4) as usize];
+                let offset = SANDBOX_BASE + entropy.below(SANDBOX_SIZE / size) * size;
+                let offset = offset.wrapping_sub(BASE_ADDR) as i64;
+                match entropy.below(2) {
+                    0 => Op::Load(size, size == 8 || entropy.below(2) == 0, rd, offset),
+                    _ => Op::Store(size, rs2, offset),
+                }
+            }
+            13 => Op::Branch(CONDS[entropy.below(6) as usize], rs1, rs2, target),
+            14 => Op::Jal(rd, target),
+            _ => {
+                let op = AMO_OPS[entropy.below(AMO_OPS.len() as u64) as usize];
+                Op::Amo(op, entropy.below(2) == 0, rd, rs2)
+            }
+        }
+    }
+
+    /// Return the instructions of the program.
+    pub fn instructions(&self) -> Vec<u32> {
+        self.ops
+            .iter()
+            .enumerate()
+            .map(|(i, op)| op.encode(i))
+            .collect()
+    }
+
+    /// Run the program in `emu`, which is reset first.
+    fn run_emulator(&self, emu: &mut Emulator) -> State {
+        let binary: Vec<u8> = self
+            .instructions()
+            .iter()
+            .flat_map(|inst| inst.to_le_bytes())
+            .collect();
+        emu.reset_with(&binary);
+        emu.cpu.regs = self.regs;
+        emu.cpu.bus.write_image(SANDBOX_BASE, &self.sandbox);
+        let end = DRAM_BASE + self.ops.len() as u64 * 4;
+        let mut exception = None;
+        // Branches only go forward, so a program takes at most one step per operation.
+        for _ in 0..self.ops.len() {
+            if emu.cpu.pc == end {
+                break;
+            }
+            match emu.step() {
+                Some(executed) => {
+                    if let Some(e) = executed.exception {
+                        exception = Some(format!("{:?} at {:#x}", e, executed.pc));
+                        break;
+                    }
+                }
+                None => break,
+            }
+        }
+        let mut sandbox = Vec::with_capacity(SANDBOX_SIZE as usize);
+        for addr in SANDBOX_BASE..SANDBOX_BASE + SANDBOX_SIZE {
+            sandbox.push(emu.cpu.bus.load(addr, 8).unwrap_or(0) as u8);
+        }
+        State {
+            regs: emu.cpu.regs,
+            sandbox,
+            exception,
+        }
+    }
+
+    /// Run the program in the reference model.
+    fn run_reference(&self) -> State {
+        let mut regs = self.regs;
+        let mut mem = self.sandbox.clone();
+        let mut i = 0;
+        while i < self.ops.len() {
+            let pc = DRAM_BASE + i as u64 * 4;
+            let mut next = i + 1;
+            let mut result = None;
+            match self.ops[i] {
+                Op::Alu(op, rd, rs1, rs2) => {
+                    result = Some((rd, alu(op, regs[rs1], regs[rs2])));
+                }
+                Op::Imm(op, rd, rs1, imm) => result = Some((rd, alu_imm(op, regs[rs1], imm))),
+                Op::Lui(rd, imm) => result = Some((rd, sext32((imm as u64) << 12))),
+                Op::Auipc(rd, imm) => {
+                    result = Some((rd, pc.wrapping_add(sext32((imm as u64) << 12))))
+                }
+                Op::Load(size, signed, rd, offset) => {
+                    let value = read(&mem, BASE_ADDR.wrapping_add(offset as u64), size);
+                    let value = match signed {
+                        true => sign_extend(value, size * 8),
+                        false => value,
+                    };
+                    result = Some((rd, value));
+                }
+                Op::Store(size, rs2, offset) => write(
+                    &mut mem,
+                    BASE_ADDR.wrapping_add(offset as u64),
+                    size,
+                    regs[rs2],
+                ),
+                Op::Branch(cond, rs1, rs2, target) => {
+                    let (a, b) = (regs[rs1], regs[rs2]);
+                    let taken = match cond {
+                        Cond::Eq => a == b,
+                        Cond::Ne => a != b,
+                        Cond::Lt => (a as i64) < (b as i64),
+                        Cond::Ge => (a as i64) >= (b as i64),
+                        Cond::Ltu => a < b,
+                        Cond::Geu => a >= b,
+                    };
+                    if taken {
+                        next = target;
+                    }
+                }
+                Op::Jal(rd, target) => {
+                    result = Some((rd, pc + 4));
+                    next = target;
+                }
+                Op::Amo(op, word, rd, rs2) => {
+                    let size = if word { 4 } else { 8 };
+                    let old = sign_extend(read(&mem, AMO_ADDR, size), size * 8);
+                    let src = sign_extend(regs[rs2], size * 8);
+                    let unsigned = |v: u64| v & (u64::MAX >> (64 - size * 8));
+                    let new = match op {
+                        AmoOp::Add => old.wrapping_add(src),
+                        AmoOp::Swap => src,
+                        AmoOp::Xor => old ^ src,
+                        AmoOp::And => old & src,
+                        AmoOp::Or => old | src,
+                        AmoOp::Min => (old as i64).min(src as i64) as u64,
+                        AmoOp::Max => (old as i64).max(src as i64) as u64,
+                        AmoOp::Minu => unsigned(old).min(unsigned(src)),
+                        AmoOp::Maxu => unsigned(old).max(unsigned(src)),
+                    };
+                    write(&mut mem, AMO_ADDR, size, new);
+                    result = Some((rd, old));
+                }
+            }
+            if let Some((rd, value)) = result {
+                if rd != 0 {
+                    regs[rd] = value;
+                }
+            }
+            i = next;
+        }
+        State {
+            regs,
+            sandbox: mem,
+            exception: None,
+        }
+    }
+
+    /// Run the program in the emulator and the reference model, and return the differences of
+    /// their final states.
+    pub fn check(&self) -> Vec<String> {
+        self.check_with(&mut Emulator::new(Cpu::new(Vec::new(), Vec::new())))
+    }
+
+    /// Same as `check`, but run the program in `emu` to reuse its dram across programs.
+    pub fn check_with(&self, emu: &mut Emulator) -> Vec<String> {
+        let actual = self.run_emulator(emu);
+        let expected = self.run_reference();
+        let mut differences = Vec::new();
+        if let Some(exception) = actual.exception {
+            differences.push(format!("emulator raised {}", exception));
+        }
+        for i in 0..32 {
+            if actual.regs[i] != expected.regs[i] {
+                differences.push(format!(
+                    "x{}: emulator {:#x}, reference {:#x}",
+                    i, actual.regs[i], expected.regs[i]
+                ));
+            }
+        }
+        for (i, (a, e)) in actual.sandbox.iter().zip(&expected.sandbox).enumerate() {
+            if a != e {
+                differences.push(format!(
+                    "memory {:#x}: emulator {:#04x}, reference {:#04x}",
+                    SANDBOX_BASE + i as u64,
+                    a,
+                    e
+                ));
+            }
+        }
+        differences
+    }
+}
+
+impl fmt::Display for Program {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, reg) in self.regs.iter().enumerate().skip(1) {
+            writeln!(f, "  x{} = {:#x}", i, reg)?;
+        }
+        for (i, inst) in self.instructions().iter().enumerate() {
+            let pc = DRAM_BASE + i as u64 * 4;
+            writeln!(
+                f,
+                "  {:#x}: {:08x}  {}",
+                pc,
+                inst,
+                disassemble(pc, *inst as u64)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+fn sext32(value: u64) -> u64 {
+    value as u32 as i32 as i64 as u64
+}
+
+fn sign_extend(value: u64, bits: u64) -> u64 {
+    let shift = 64 - bits;
+    ((value << shift) as i64 >> shift) as u64
+}
+
+/// Read `size` bytes of the sandbox at `addr`.
+fn read(mem: &[u8], addr: u64, size: u64) -> u64 {
+    let start = (addr - SANDBOX_BASE) as usize;
+    let mut bytes = [0; 8];
+    bytes[..size as usize].copy_from_slice(&mem[start..start + size as usize]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Write `size` bytes to the sandbox at `addr`.
+fn write(mem: &mut [u8], addr: u64, size: u64, value: u64) {
+    let start = (addr - SANDBOX_BASE) as usize;
+    mem[start..start + size as usize].copy_from_slice(&value.to_le_bytes()[..size as usize]);
+}
+
+fn alu(op: AluOp, a: u64, b: u64) -> u64 {
+    let shamt = (b & 0x3f) as u32;
+    let shamtw = (b & 0x1f) as u32;
+    match op {
+        AluOp::Add => a.wrapping_add(b),
+        AluOp::Sub => a.wrapping_sub(b),
+        AluOp::Sll => a << shamt,
+        AluOp::Slt => ((a as i64) < (b as i64)) as u64,
+        AluOp::Sltu => (a < b) as u64,
+        AluOp::Xor => a ^ b,
+        AluOp::Srl => a >> shamt,
+        AluOp::Sra => ((a as i64) >> shamt) as u64,
+        AluOp::Or => a | b,
+        AluOp::And => a & b,
+        AluOp::Mul => a.wrapping_mul(b),
+        AluOp::Rol => a.rotate_left(shamt),
+        AluOp::Ror => a.rotate_right(shamt),
+        AluOp::Xnor => !(a ^ b),
+        AluOp::Orn => a | !b,
+        AluOp::Andn => a & !b,
+        AluOp::Pack => (a & 0xffff_ffff) | (b << 32),
+        AluOp::Packh => (a & 0xff) | ((b & 0xff) << 8),
+        AluOp::Addw => sext32(a.wrapping_add(b)),
+        AluOp::Subw => sext32(a.wrapping_sub(b)),
+        AluOp::Sllw => sext32(((a as u32) << shamtw) as u64),
+        AluOp::Srlw => sext32(((a as u32) >> shamtw) as u64),
+        AluOp::Sraw => ((a as i32) >> shamtw) as i64 as u64,
+        AluOp::Rolw => sext32((a as u32).rotate_left(shamtw) as u64),
+        AluOp::Rorw => sext32((a as u32).rotate_right(shamtw) as u64),
+        AluOp::Packw => sext32((a & 0xffff) | ((b & 0xffff) << 16)),
+    }
+}
+
+fn alu_imm(op: ImmOp, a: u64, imm: i64) -> u64 {
+    let b = imm as u64;
+    match op {
+        ImmOp::Addi => alu(AluOp::Add, a, b),
+        ImmOp::Slti => alu(AluOp::Slt, a, b),
+        ImmOp::Sltiu => alu(AluOp::Sltu, a, b),
+        ImmOp::Xori => a ^ b,
+        ImmOp::Ori => a | b,
+        ImmOp::Andi => a & b,
+        ImmOp::Slli => alu(AluOp::Sll, a, b),
+        ImmOp::Srli => alu(AluOp::Srl, a, b),
+        ImmOp::Srai => alu(AluOp::Sra, a, b),
+        ImmOp::Rori => alu(AluOp::Ror, a, b),
+        ImmOp::Rev8 => a.swap_bytes(),
+        ImmOp::Addiw => alu(AluOp::Addw, a, b),
+        ImmOp::Slliw => alu(AluOp::Sllw, a, b),
+        ImmOp::Srliw => alu(AluOp::Srlw, a, b),
+        ImmOp::Sraiw => alu(AluOp::Sraw, a, b),
+        ImmOp::Roriw => alu(AluOp::Rorw, a, b),
+    }
+}
+
+/// A program for which the emulator and the reference model disagree.
+#[derive(Debug)]
+pub struct Mismatch {
+    /// The index of the program in a run.
+    pub index: u64,
+    pub program: Program,
+    pub differences: Vec<String>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "program {} differs:", self.index)?;
+        for difference in &self.differences {
+            writeln!(f, "  {}", difference)?;
+        }
+        write!(f, "{}", self.program)
+    }
+}
+
+/// Generate and check `count` programs of `len` operations seeded by `seed`. Return the
+/// programs which fail.
+pub fn run(seed: u64, count: u64, len: usize) -> Vec<Mismatch> {
+    let mut entropy = Entropy::new(seed);
+    let mut mismatches = Vec::new();
+    let mut emu = Emulator::new(Cpu::new(Vec::new(), Vec::new()));
+    for index in 0..count {
+        let program = Program::generate(&mut entropy, len);
+        let differences = program.check_with(&mut emu);
+        if !differences.is_empty() {
+            mismatches.push(Mismatch {
+                index,
+                program,
+                differences,
+            });
+        }
+    }
+    mismatches
+}
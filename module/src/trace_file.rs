@@ -0,0 +1,128 @@
+//! The trace_file module contains files which traces are written to and read from. A path ending
+//! with `.zst` is compressed by streaming through the `zstd` command, since full traces of an xv6
+//! boot are tens of gigabytes otherwise:
+//!
+//! ```text
+//! rvemu-for-book --trace-events boot.json.zst --trace-mmio virtio --trace-log mmio.log.zst ...
+//! ```
+//!
+//! The `zstd` command must be in PATH only if a compressed path is given. If the emulator is
+//! killed, e.g. by Ctrl-C, the buffered end of a trace is lost as with an uncompressed file, but
+//! zstd still completes the file with what it has received.
+
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::BufWriter;
+use std::os::unix::process::CommandExt;
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+
+/// Return true if `path` is compressed by zstd.
+fn is_compressed(path: &str) -> bool {
+    path.ends_with(".zst")
+}
+
+/// Start the `zstd` command with `args`. It's in its own process group, so Ctrl-C stopping the
+/// emulator doesn't kill it, and it completes the file at the end of its input.
+fn zstd(args: &[&str], stdin: Stdio, stdout: Stdio) -> io::Result<Child> {
+    Command::new("zstd")
+        .args(args)
+        .stdin(stdin)
+        .stdout(stdout)
+        .process_group(0)
+        .spawn()
+        .map_err(|e| io::Error::new(e.kind(), format!("failed to run zstd: {}", e)))
+}
+
+/// Wait for `child` and return an error if it failed.
+fn wait(child: &mut Child) -> io::Result<()> {
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!("zstd failed: {}", status)));
+    }
+    Ok(())
+}
+
+/// A buffered file which a trace is written to.
+pub enum TraceFile {
+    Plain(BufWriter<File>),
+    /// The input of a `zstd` process writing the file.
+    Zstd(BufWriter<ChildStdin>, Child),
+}
+
+impl TraceFile {
+    /// Create a new file at `path`, which is compressed if it ends with `.zst`.
+    pub fn create(path: &str) -> io::Result<Self> {
+        if !is_compressed(path) {
+            return Ok(TraceFile::Plain(BufWriter::new(File::create(path)?)));
+        }
+        let mut child = zstd(&["-q", "-f", "-o", path], Stdio::piped(), Stdio::null())?;
+        let stdin = child.stdin.take().expect("stdin of zstd is piped");
+        Ok(TraceFile::Zstd(BufWriter::new(stdin), child))
+    }
+
+    /// Flush the rest of the trace and wait until it's compressed.
+    pub fn finish(self) -> io::Result<()> {
+        match self {
+            TraceFile::Plain(mut out) => out.flush(),
+            TraceFile::Zstd(out, mut child) => {
+                // Close the input so that zstd finishes the frame.
+                drop(out.into_inner().map_err(|e| e.into_error())?);
+                wait(&mut child)
+            }
+        }
+    }
+}
+
+impl Write for TraceFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            TraceFile::Plain(out) => out.write(buf),
+            TraceFile::Zstd(out, _) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            TraceFile::Plain(out) => out.flush(),
+            TraceFile::Zstd(out, _) => out.flush(),
+        }
+    }
+}
+
+/// A file which a trace is read from. It isn't buffered, so a `BufReader` reads it by lines.
+pub enum TraceReader {
+    Plain(File),
+    /// The output of a `zstd` process reading the file.
+    Zstd(ChildStdout, Child),
+}
+
+impl TraceReader {
+    /// Open the file at `path`, which is decompressed if it ends with `.zst`.
+    pub fn open(path: &str) -> io::Result<Self> {
+        if !is_compressed(path) {
+            return Ok(TraceReader::Plain(File::open(path)?));
+        }
+        // Fail here instead of at the first read if the file doesn't exist.
+        File::open(path)?;
+        let mut child = zstd(&["-q", "-d", "-c", path], Stdio::null(), Stdio::piped())?;
+        let stdout = child.stdout.take().expect("stdout of zstd is piped");
+        Ok(TraceReader::Zstd(stdout, child))
+    }
+}
+
+impl Read for TraceReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            TraceReader::Plain(input) => input.read(buf),
+            TraceReader::Zstd(input, child) => {
+                let n = input.read(buf)?;
+                // A truncated or corrupted file ends the output early.
+                if n == 0 && !buf.is_empty() {
+                    wait(child)?;
+                }
+                Ok(n)
+            }
+        }
+    }
+}
@@ -2,26 +2,35 @@
 
 #![allow(dead_code)]
 
+use std::fmt;
+
 use crate::cpu::*;
+use crate::level::Feature;
+use crate::timeline::TimelineEvent;
+
+/// The number of times the same exception at the same pc repeats before it's a trap loop.
+pub const TRAP_LOOP_REPEATS: u64 = 1000;
 
 /// All kinds of exceptions, an unusual condition occurring at run
-/// time associated with an instruction in the current hardware thread.
+/// time associated with an instruction in the current hardware thread. An address-misaligned,
+/// access or page-fault exception holds the faulting virtual address, which the trap handler
+/// finds in mtval or stval.
 #[derive(Debug)]
 pub enum Exception {
-    InstructionAddressMisaligned,
-    InstructionAccessFault,
+    InstructionAddressMisaligned(u64),
+    InstructionAccessFault(u64),
     IllegalInstruction,
     Breakpoint,
-    LoadAddressMisaligned,
-    LoadAccessFault,
-    StoreAMOAddressMisaligned,
-    StoreAMOAccessFault,
+    LoadAddressMisaligned(u64),
+    LoadAccessFault(u64),
+    StoreAMOAddressMisaligned(u64),
+    StoreAMOAccessFault(u64),
     EnvironmentCallFromUMode,
     EnvironmentCallFromSMode,
     EnvironmentCallFromMMode,
-    InstructionPageFault,
-    LoadPageFault,
-    StoreAMOPageFault,
+    InstructionPageFault(u64),
+    LoadPageFault(u64),
+    StoreAMOPageFault(u64),
 }
 
 /// All kinds of interrupts, an external asynchronous event that may
@@ -40,39 +49,128 @@ pub enum Interrupt {
     MachineExternalInterrupt,
 }
 
+/// A policy deciding which exceptions stop the emulator.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum FatalPolicy {
+    /// Stop only if a guest can't make progress because of a trap loop.
+    Stuck,
+    /// Stop at a trap loop and any access fault or misaligned fetch and store, even if a guest
+    /// can handle it.
+    AccessFault,
+    /// Never stop.
+    Never,
+}
+
+/// A trap loop, where a guest can't make progress because its trap handler is wrong.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum TrapLoop {
+    /// The first instruction of the trap handler at `vector` raised an exception, e.g. because
+    /// the trap vector points at unmapped memory.
+    DoubleFault { vector: u64, cause: u64 },
+    /// The instruction at `pc` raised the same exception `count` times without completing.
+    Repeated { pc: u64, cause: u64, count: u64 },
+}
+
+impl fmt::Display for TrapLoop {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TrapLoop::DoubleFault { vector, cause } => write!(
+                f,
+                "double fault: the trap handler at {:#x} raised exception {} at its first \
+                 instruction",
+                vector, cause
+            ),
+            TrapLoop::Repeated { pc, cause, count } => write!(
+                f,
+                "trap loop: the instruction at {:#x} raised exception {} {} times in a row",
+                pc, cause, count
+            ),
+        }
+    }
+}
+
+/// Return the address of the trap handler which `tvec`, i.e. mtvec or stvec, selects for a trap
+/// with `cause`, whose highest bit is set for an interrupt.
+///
+/// 3.1.7 Machine Trap-Vector Base-Address Register (mtvec)
+/// "When MODE=Direct, all traps into machine mode cause the pc to be set to the address in the
+/// BASE field. When MODE=Vectored, all synchronous exceptions into machine mode cause the pc to be
+/// set to the address in the BASE field, whereas interrupts cause the pc to be set to the address
+/// in the BASE field plus four times the interrupt cause number." MODE is the low 2 bits and BASE
+/// is the rest. The reserved MODE values 2 and 3 are treated as Direct.
+pub fn trap_vector(tvec: u64, cause: u64) -> u64 {
+    let base = tvec & !0b11;
+    let is_interrupt = (cause >> 63) == 1;
+    match tvec & 0b11 {
+        1 if is_interrupt => base.wrapping_add(4 * (cause & !(1 << 63))),
+        _ => base,
+    }
+}
+
+/// Record an exception with `cause` raised at `pc` and detect a trap loop.
+fn detect_trap_loop(cpu: &mut Cpu, pc: u64, cause: u64) {
+    if cpu.trap_entry == Some(pc) {
+        cpu.trap_loop = Some(TrapLoop::DoubleFault { vector: pc, cause });
+        return;
+    }
+    // Breakpoints and environment calls in a loop are intended.
+    if matches!(cause, 3 | 8 | 9 | 11) {
+        return;
+    }
+    if cpu.last_exception == Some((pc, cause)) {
+        cpu.exception_repeats += 1;
+        if cpu.exception_repeats >= TRAP_LOOP_REPEATS {
+            cpu.trap_loop = Some(TrapLoop::Repeated {
+                pc,
+                cause,
+                count: cpu.exception_repeats,
+            });
+        }
+    } else {
+        cpu.last_exception = Some((pc, cause));
+        cpu.exception_repeats = 1;
+    }
+}
+
 /// The transfer of control to a trap handler caused by either an
 /// exception or an interrupt.
 pub trait Trap {
     /// Returns an exception code that identifys the last exception.
     fn exception_code(&self) -> u64;
+    /// Return the value written to mtval or stval.
+    fn trap_value(&self) -> u64 {
+        0
+    }
     /// Trap handler.
     fn take_trap(&self, cpu: &mut Cpu);
     /// Helper method for a trap handler.
     fn take_trap_helper(&self, cpu: &mut Cpu, is_interrupt: bool) {
-        let exception_pc = cpu.pc.wrapping_sub(4);
+        // An exception is taken at the instruction which raised it, and the pc has already been
+        // advanced past it. An interrupt is taken between instructions, so the pc is the
+        // instruction to resume, which isn't always the next one after a jump or an xRET.
+        let exception_pc = match is_interrupt {
+            true => cpu.pc,
+            false => cpu.pc.wrapping_sub(4),
+        };
         let previous_mode = cpu.mode;
 
         let mut cause = self.exception_code();
         // Set an interrupt bit if a trap is an interrupt.
         if is_interrupt {
-            cause = (1 << 63) | cause;
+            cause |= 1 << 63;
+        } else {
+            detect_trap_loop(cpu, exception_pc, cause);
         }
+        // Interrupts are delegated by mideleg and exceptions by medeleg.
+        let deleg = if is_interrupt { MIDELEG } else { MEDELEG };
         if (previous_mode <= Mode::Supervisor)
-            && ((cpu.load_csr(MEDELEG).wrapping_shr(cause as u32)) & 1 != 0)
+            && ((cpu.load_csr(deleg).wrapping_shr(cause as u32)) & 1 != 0)
         {
             // Handle the trap in S-mode.
             cpu.mode = Mode::Supervisor;
 
-            // Set the program counter to the supervisor trap-handler base address (stvec).
-            if is_interrupt {
-                let vector = match cpu.load_csr(STVEC) & 1 {
-                    1 => 4 * cause, // vectored mode
-                    _ => 0,         // direct mode
-                };
-                cpu.pc = (cpu.load_csr(STVEC) & !1) + vector;
-            } else {
-                cpu.pc = cpu.load_csr(STVEC) & !1;
-            }
+            // Set the program counter to the supervisor trap-handler address (stvec).
+            cpu.pc = trap_vector(cpu.load_csr(STVEC), cause);
 
             // 4.1.9 Supervisor Exception Program Counter (sepc)
             // "The low bit of sepc (sepc[0]) is always zero."
@@ -97,7 +195,7 @@ pub trait Trap {
             // written with the faulting virtual address. On an illegal instruction trap,
             // stval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other exceptions, stval is set to zero."
-            cpu.store_csr(STVAL, 0);
+            cpu.store_csr(STVAL, self.trap_value());
 
             // Set a previous interrupt-enable bit for supervisor mode (SPIE, 5) to the value
             // of a global interrupt-enable bit for supervisor mode (SIE, 1).
@@ -122,16 +220,8 @@ pub trait Trap {
             // Handle the trap in M-mode.
             cpu.mode = Mode::Machine;
 
-            // Set the program counter to the machine trap-handler base address (mtvec).
-            if is_interrupt {
-                let vector = match cpu.load_csr(MTVEC) & 1 {
-                    1 => 4 * cause, // vectored mode
-                    _ => 0,         // direct mode
-                };
-                cpu.pc = (cpu.load_csr(MTVEC) & !1) + vector;
-            } else {
-                cpu.pc = cpu.load_csr(MTVEC) & !1;
-            }
+            // Set the program counter to the machine trap-handler address (mtvec).
+            cpu.pc = trap_vector(cpu.load_csr(MTVEC), cause);
 
             // 3.1.15 Machine Exception Program Counter (mepc)
             // "The low bit of mepc (mepc[0]) is always zero."
@@ -157,7 +247,7 @@ pub trait Trap {
             // written with the faulting virtual address. On an illegal instruction trap,
             // mtval may be written with the first XLEN or ILEN bits of the faulting
             // instruction as described below. For other traps, mtval is set to zero."
-            cpu.store_csr(MTVAL, 0);
+            cpu.store_csr(MTVAL, self.trap_value());
 
             // Set a previous interrupt-enable bit for supervisor mode (MPIE, 7) to the value
             // of a global interrupt-enable bit for supervisor mode (MIE, 3).
@@ -171,46 +261,111 @@ pub trait Trap {
             );
             // Set a global interrupt-enable bit for supervisor mode (MIE, 3) to 0.
             cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) & !(1 << 3));
-            // Set a previous privilege mode for supervisor mode (MPP, 11..13) to 0.
-            cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) & !(0b11 << 11));
+            // Set a previous privilege mode for machine mode (MPP, 11..13) to the mode the
+            // trap was taken from.
+            cpu.store_csr(
+                MSTATUS,
+                (cpu.load_csr(MSTATUS) & !(0b11 << 11)) | ((previous_mode as u64) << 11),
+            );
         }
+        cpu.trap_entry = Some(cpu.pc);
+        cpu.timeline.record(TimelineEvent::Trap {
+            cause,
+            from: previous_mode,
+            to: cpu.mode,
+            epc: exception_pc,
+            handler: cpu.pc,
+        });
     }
 }
 
 impl Trap for Exception {
     fn exception_code(&self) -> u64 {
         match self {
-            Exception::InstructionAddressMisaligned => 0,
-            Exception::InstructionAccessFault => 1,
+            Exception::InstructionAddressMisaligned(_) => 0,
+            Exception::InstructionAccessFault(_) => 1,
             Exception::IllegalInstruction => 2,
             Exception::Breakpoint => 3,
-            Exception::LoadAddressMisaligned => 4,
-            Exception::LoadAccessFault => 5,
-            Exception::StoreAMOAddressMisaligned => 6,
-            Exception::StoreAMOAccessFault => 7,
+            Exception::LoadAddressMisaligned(_) => 4,
+            Exception::LoadAccessFault(_) => 5,
+            Exception::StoreAMOAddressMisaligned(_) => 6,
+            Exception::StoreAMOAccessFault(_) => 7,
             Exception::EnvironmentCallFromUMode => 8,
             Exception::EnvironmentCallFromSMode => 9,
             Exception::EnvironmentCallFromMMode => 11,
-            Exception::InstructionPageFault => 12,
-            Exception::LoadPageFault => 13,
-            Exception::StoreAMOPageFault => 15,
+            Exception::InstructionPageFault(_) => 12,
+            Exception::LoadPageFault(_) => 13,
+            Exception::StoreAMOPageFault(_) => 15,
         }
     }
 
+    /// Return the faulting virtual address, or 0 for the other exceptions. An illegal instruction
+    /// doesn't write its encoding.
+    fn trap_value(&self) -> u64 {
+        self.address().unwrap_or(0)
+    }
+
     fn take_trap(&self, cpu: &mut Cpu) {
         self.take_trap_helper(cpu, false);
     }
 }
 
 impl Exception {
-    pub fn is_fatal(&self) -> bool {
+    /// Return the faulting address of an address-misaligned, access or page-fault exception.
+    pub fn address(&self) -> Option<u64> {
         match self {
-            Exception::InstructionAddressMisaligned
-            | Exception::InstructionAccessFault
-            | Exception::LoadAccessFault
-            | Exception::StoreAMOAddressMisaligned
-            | Exception::StoreAMOAccessFault => true,
-            _ => false,
+            Exception::InstructionAddressMisaligned(addr)
+            | Exception::InstructionAccessFault(addr)
+            | Exception::LoadAddressMisaligned(addr)
+            | Exception::LoadAccessFault(addr)
+            | Exception::StoreAMOAddressMisaligned(addr)
+            | Exception::StoreAMOAccessFault(addr)
+            | Exception::InstructionPageFault(addr)
+            | Exception::LoadPageFault(addr)
+            | Exception::StoreAMOPageFault(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+
+    /// Return the exception with the faulting address replaced by `addr`. The bus and the
+    /// devices raise faults at physical addresses, and the cpu replaces them with the virtual
+    /// address of the access.
+    pub fn at(self, addr: u64) -> Self {
+        match self {
+            Exception::InstructionAddressMisaligned(_) => {
+                Exception::InstructionAddressMisaligned(addr)
+            }
+            Exception::InstructionAccessFault(_) => Exception::InstructionAccessFault(addr),
+            Exception::LoadAddressMisaligned(_) => Exception::LoadAddressMisaligned(addr),
+            Exception::LoadAccessFault(_) => Exception::LoadAccessFault(addr),
+            Exception::StoreAMOAddressMisaligned(_) => Exception::StoreAMOAddressMisaligned(addr),
+            Exception::StoreAMOAccessFault(_) => Exception::StoreAMOAccessFault(addr),
+            Exception::InstructionPageFault(_) => Exception::InstructionPageFault(addr),
+            Exception::LoadPageFault(_) => Exception::LoadPageFault(addr),
+            Exception::StoreAMOPageFault(_) => Exception::StoreAMOPageFault(addr),
+            exception => exception,
+        }
+    }
+
+    /// Return true if the exception stops the emulator under `cpu.fatal_policy`. It must be
+    /// called after the trap is taken. Every exception is fatal before the step which introduces
+    /// exceptions.
+    pub fn is_fatal(&self, cpu: &Cpu) -> bool {
+        if !cpu.level().enables(Feature::Exceptions) {
+            return true;
+        }
+        match cpu.fatal_policy {
+            FatalPolicy::Stuck => cpu.trap_loop.is_some(),
+            FatalPolicy::AccessFault if cpu.trap_loop.is_some() => true,
+            FatalPolicy::AccessFault => matches!(
+                self,
+                Exception::InstructionAddressMisaligned(_)
+                    | Exception::InstructionAccessFault(_)
+                    | Exception::LoadAccessFault(_)
+                    | Exception::StoreAMOAddressMisaligned(_)
+                    | Exception::StoreAMOAccessFault(_)
+            ),
+            FatalPolicy::Never => false,
         }
     }
 }
@@ -0,0 +1,309 @@
+//! The tui module contains a terminal UI debugger. It shows panes for the disassembly around the
+//! program counter, the registers (highlighting the ones changed by the last command), the stack
+//! memory, and the console, and updates them as you single-step. Memory is read through the page
+//! table without side effects on the guest. It draws with ANSI escape sequences and reads commands
+//! line by line:
+//!
+//! ```text
+//! s [n]        Step n instructions (default: 1). An empty line steps once.
+//! c [n]        Continue until a breakpoint or n instructions.
+//! b <addr>     Set a breakpoint.
+//! tr <addr>    Set a tracepoint, which counts hits without stopping.
+//! d <addr>     Delete a breakpoint or a tracepoint.
+//! i <text>     Send text and a newline to the console.
+//! t [satp]     Show memory through the page table of satp, or the current one if omitted.
+//! q            Quit.
+//! ```
+//!
+//! An address can also be a symbol name, e.g. `b kernelvec`, if symbols are loaded by
+//! `set_symbols`.
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+use crate::cpu::*;
+use crate::disasm::{disassemble, REG_NAMES};
+use crate::elf::SymbolTable;
+use crate::emulator::Emulator;
+use crate::mmu::debug_load;
+
+/// The width of the disassembly pane.
+const DISASM_WIDTH: usize = 56;
+/// The number of instructions shown before the program counter.
+const DISASM_BEFORE: u64 = 6;
+/// The number of lines in the disassembly and register panes.
+const PANE_HEIGHT: u64 = 16;
+/// The number of lines in the console pane.
+const CONSOLE_HEIGHT: usize = 8;
+
+/// A reader receiving console input from the debugger.
+pub(crate) struct ChannelReader(pub(crate) Receiver<u8>);
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.0.recv() {
+            Ok(byte) => {
+                buf[0] = byte;
+                Ok(1)
+            }
+            // The debugger has gone.
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+/// A writer collecting console output for the console pane.
+struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .lock()
+            .expect("failed to get a console buffer")
+            .extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The terminal UI debugger.
+pub struct Tui {
+    breakpoints: Vec<u64>,
+    /// Tracepoints and their hit counts.
+    tracepoints: Vec<(u64, u64)>,
+    symbols: SymbolTable,
+    /// Registers before the last command, to highlight changes.
+    prev_regs: [u64; 32],
+    /// Output of the console.
+    console: Arc<Mutex<Vec<u8>>>,
+    /// Input to the console.
+    input: Sender<u8>,
+    /// A message shown in the status line.
+    message: String,
+    /// The satp value to translate addresses with, or None to use the current one.
+    satp: Option<u64>,
+}
+
+/// Parse an address in hexadecimal with or without a `0x` prefix.
+fn parse_addr(s: &str) -> Option<u64> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16).ok()
+}
+
+impl Tui {
+    /// Attach the debugger to `cpu`. The console is disconnected from stdin and stdout and shown
+    /// in the console pane instead.
+    pub fn attach(cpu: &mut Cpu) -> Self {
+        let console = Arc::new(Mutex::new(Vec::new()));
+        let (input, receiver) = mpsc::channel();
+        cpu.bus.detach_console(Box::new(ChannelReader(receiver)));
+        cpu.bus
+            .add_serial_backend(Box::new(SharedBuffer(console.clone())));
+        Self {
+            breakpoints: Vec::new(),
+            tracepoints: Vec::new(),
+            symbols: SymbolTable::default(),
+            prev_regs: cpu.regs,
+            console,
+            input,
+            message: "type 'h' for help".to_string(),
+            satp: None,
+        }
+    }
+
+    /// Use `symbols` to resolve names in commands and to show addresses.
+    pub fn set_symbols(&mut self, symbols: SymbolTable) {
+        self.symbols = symbols;
+    }
+
+    /// Resolve a symbol name or an address in hexadecimal. A symbol wins over an address, e.g.
+    /// `add` is a symbol if there is one.
+    fn resolve(&self, s: &str) -> Option<u64> {
+        self.symbols.lookup(s).or_else(|| parse_addr(s))
+    }
+
+    /// Show an address with the symbol containing it.
+    fn describe(&self, addr: u64) -> String {
+        match self.symbols.symbolize(addr) {
+            Some((name, 0)) => format!("{:#x} <{}>", addr, name),
+            Some((name, offset)) => format!("{:#x} <{}+{:#x}>", addr, name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+
+    /// Run the debugger until a user quits.
+    pub fn run(&mut self, emu: &mut Emulator) -> io::Result<()> {
+        loop {
+            self.render(&mut emu.cpu)?;
+            let mut line = String::new();
+            if io::stdin().read_line(&mut line)? == 0 {
+                return Ok(());
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let count = words.get(1).and_then(|n| n.parse::<u64>().ok());
+            let addr = words.get(1).and_then(|a| self.resolve(a));
+            self.prev_regs = emu.cpu.regs;
+            match words.first().copied() {
+                None | Some("s") => self.run_for(emu, count.unwrap_or(1), false),
+                Some("c") => self.run_for(emu, count.unwrap_or(u64::MAX), true),
+                Some("b") => match addr {
+                    Some(addr) => {
+                        self.breakpoints.push(addr);
+                        self.message = format!("breakpoint at {}", self.describe(addr));
+                    }
+                    None => self.message = "usage: b <addr>".to_string(),
+                },
+                Some("tr") => match addr {
+                    Some(addr) => {
+                        self.tracepoints.push((addr, 0));
+                        self.message = format!("tracepoint at {}", self.describe(addr));
+                    }
+                    None => self.message = "usage: tr <addr>".to_string(),
+                },
+                Some("d") => match addr {
+                    Some(addr) => {
+                        self.breakpoints.retain(|b| *b != addr);
+                        self.tracepoints.retain(|(t, _)| *t != addr);
+                        self.message = format!("deleted {}", self.describe(addr));
+                    }
+                    None => self.message = "usage: d <addr>".to_string(),
+                },
+                Some("i") => {
+                    let text = line.trim_start()[1..].trim();
+                    for byte in text.bytes().chain(Some(b'\n')) {
+                        let _ = self.input.send(byte);
+                    }
+                    self.message = format!("sent {:?}", text);
+                }
+                Some("t") => {
+                    self.satp = words.get(1).and_then(|a| parse_addr(a));
+                    self.message = match self.satp {
+                        Some(satp) => format!("page table of satp={:#x}", satp),
+                        None => "page table of the current satp".to_string(),
+                    };
+                }
+                Some("q") => return Ok(()),
+                Some(_) => {
+                    self.message =
+                        "s [n]: step, c [n]: continue, b/tr/d <addr>: set a breakpoint/set a \
+                         tracepoint/delete, i <text>: input, t [satp]: page table, q: quit"
+                            .to_string()
+                }
+            }
+        }
+    }
+
+    /// Execute up to `count` instructions. If `stop_at_breakpoint` is true, stop when the program
+    /// counter reaches a breakpoint.
+    fn run_for(&mut self, emu: &mut Emulator, count: u64, stop_at_breakpoint: bool) {
+        for (_, hits) in self.tracepoints.iter_mut() {
+            *hits = 0;
+        }
+        self.message = format!("executed {} instructions", count);
+        for i in 0..count {
+            emu.step();
+            let cpu = &emu.cpu;
+            if emu.is_stopped() {
+                self.message = match (cpu.bus.emuctl.exit_code(), cpu.trap_loop) {
+                    (Some(code), _) => format!("stopped: exited with code {}", code),
+                    (None, Some(trap_loop)) => format!("stopped: {}", trap_loop),
+                    (None, None) => format!("stopped by a fatal exception at {:#x}", cpu.pc),
+                };
+                break;
+            }
+            for (addr, hits) in self.tracepoints.iter_mut() {
+                if *addr == cpu.pc {
+                    *hits += 1;
+                }
+            }
+            if stop_at_breakpoint && self.breakpoints.contains(&cpu.pc) {
+                self.message = format!(
+                    "hit a breakpoint at {} after {} steps",
+                    self.describe(cpu.pc),
+                    i + 1
+                );
+                break;
+            }
+        }
+        for (addr, hits) in &self.tracepoints {
+            let hit = format!(", {} hit {} times", self.describe(*addr), hits);
+            self.message.push_str(&hit);
+        }
+    }
+
+    fn render(&self, cpu: &mut Cpu) -> io::Result<()> {
+        let mut screen = String::from("\x1b[2J\x1b[H");
+        screen.push_str(&format!(
+            "{:<width$}| Registers (mode: {:?})\n",
+            " Disassembly",
+            cpu.mode,
+            width = DISASM_WIDTH
+        ));
+
+        let start = cpu.pc.wrapping_sub(DISASM_BEFORE * 4);
+        for row in 0..PANE_HEIGHT {
+            let addr = start.wrapping_add(row * 4);
+            let marker = match (addr == cpu.pc, self.breakpoints.contains(&addr)) {
+                (true, _) => '>',
+                (false, true) => '*',
+                _ => ' ',
+            };
+            let text = match debug_load(cpu, addr, 32, self.satp) {
+                Some(inst) => format!("{:#010x}  {}", inst, disassemble(addr, inst)),
+                None => "<unmapped>".to_string(),
+            };
+            let mut left = format!("{} {:#x}: {}", marker, addr, text);
+            left.truncate(DISASM_WIDTH - 1);
+            screen.push_str(&format!("{:<width$}|", left, width = DISASM_WIDTH));
+
+            for i in [row as usize * 2, row as usize * 2 + 1] {
+                let cell = format!(" {:>4} {:#018x}", REG_NAMES[i], cpu.regs[i]);
+                if cpu.regs[i] != self.prev_regs[i] {
+                    screen.push_str(&format!("\x1b[7m{}\x1b[0m", cell));
+                } else {
+                    screen.push_str(&cell);
+                }
+            }
+            screen.push('\n');
+        }
+
+        let sp = cpu.regs[2];
+        screen.push_str(&format!("\n Stack (sp={:#x})\n", sp));
+        for row in 0..4 {
+            screen.push(' ');
+            for col in 0..2 {
+                let addr = sp.wrapping_add((row * 2 + col) * 8);
+                match debug_load(cpu, addr, 64, self.satp) {
+                    Some(value) => screen.push_str(&format!("{:#x}: {:#018x}  ", addr, value)),
+                    None => screen.push_str(&format!("{:#x}: <unmapped>          ", addr)),
+                }
+            }
+            screen.push('\n');
+        }
+
+        screen.push_str("\n Console\n");
+        let console = self.console.lock().expect("failed to get a console buffer");
+        let text = String::from_utf8_lossy(&console);
+        let lines: Vec<&str> = text.split('\n').collect();
+        let first = lines.len().saturating_sub(CONSOLE_HEIGHT);
+        for line in &lines[first..] {
+            screen.push_str(&format!(" {}\n", line));
+        }
+
+        screen.push_str(&format!(
+            "\n pc={} | {}\n(rvemu) ",
+            self.describe(cpu.pc),
+            self.message
+        ));
+        let mut stdout = io::stdout();
+        stdout.write_all(screen.as_bytes())?;
+        stdout.flush()
+    }
+}
@@ -7,21 +7,22 @@
 use std::io;
 use std::io::prelude::*;
 use std::sync::{
-    atomic::{AtomicBool, Ordering},
+    atomic::{AtomicBool, AtomicU8, Ordering},
     Arc, Condvar, Mutex,
 };
 use std::thread;
 
 use crate::bus::*;
+use crate::snapshot::*;
 use crate::trap::*;
 
 /// The interrupt request of UART.
 pub const UART_IRQ: u64 = 10;
 
 /// Receive holding register (for input bytes).
-pub const UART_RHR: u64 = UART_BASE + 0;
+pub const UART_RHR: u64 = UART_BASE;
 /// Transmit holding register (for output bytes).
-pub const UART_THR: u64 = UART_BASE + 0;
+pub const UART_THR: u64 = UART_BASE;
 /// Line control register.
 pub const UART_LCR: u64 = UART_BASE + 3;
 /// Line status register.
@@ -39,26 +40,73 @@ pub const UART_LSR_RX: u8 = 1;
 pub const UART_LSR_TX: u8 = 1 << 5;
 
 pub struct Uart {
-    /// Pair of an array for UART buffer and a conditional variable.
+    /// Pair of an array for UART buffer and a conditional variable. The line status register in
+    /// the array is unused.
     uart: Arc<(Mutex<[u8; UART_SIZE as usize]>, Condvar)>,
+    /// The line status register. It's out of the mutex so that polling it doesn't take the lock.
+    /// The RX bit is changed while holding the lock.
+    lsr: Arc<AtomicU8>,
     /// Bit if an interrupt happens.
     interrupting: Arc<AtomicBool>,
+    /// True if a thread reading stdin has been started.
+    reading: bool,
+    /// The number of bytes transmitted and received.
+    io_bytes: u64,
+    /// Additional outputs which transmitted bytes are copied to, e.g. a log file.
+    backends: Vec<Box<dyn Write + Send>>,
+    /// An input source replacing stdin.
+    input: Option<Box<dyn Read + Send>>,
+    /// True if transmitted bytes are printed to stdout.
+    stdout: bool,
+    /// True if input is read by `poll_input` instead of a thread.
+    deterministic: bool,
 }
 
 impl Device for Uart {
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match size {
-            8 => Ok(self.load8(addr)),
-            _ => Err(Exception::LoadAccessFault),
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        self.start_reader();
+        match width {
+            Width::Byte => Ok(self.load8(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
-    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match size {
-            8 => Ok(self.store8(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        self.start_reader();
+        match width {
+            Width::Byte => {
+                self.store8(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
+
+    fn register_name(&self, addr: u64, store: bool) -> Option<&'static str> {
+        let name = match (addr - UART_BASE, store) {
+            (0, false) => "RHR",
+            (0, true) => "THR",
+            (1, _) => "IER",
+            (2, false) => "ISR",
+            (2, true) => "FCR",
+            (3, _) => "LCR",
+            (4, _) => "MCR",
+            (5, _) => "LSR",
+            (6, _) => "MSR",
+            (7, _) => "SPR",
+            _ => return None,
+        };
+        Some(name)
+    }
+
+    /// A received byte which a guest hasn't read is kept, so no input is lost.
+    fn reset(&mut self) {
+        let (uart, _cvar) = &*self.uart;
+        let mut uart = uart.lock().expect("failed to get an UART object");
+        uart[1..].fill(0);
+        let rx = self.lsr.load(Ordering::Acquire) & UART_LSR_RX;
+        self.lsr.store(UART_LSR_TX | rx, Ordering::Release);
+    }
 }
 
 impl Uart {
@@ -66,36 +114,87 @@ impl Uart {
     pub fn new() -> Self {
         let uart = Arc::new((Mutex::new([0; UART_SIZE as usize]), Condvar::new()));
         let interrupting = Arc::new(AtomicBool::new(false));
-        {
-            let (uart, _cvar) = &*uart;
-            let mut uart = uart.lock().expect("failed to get an UART object");
+        Self {
+            uart,
             // Transmitter hold register is empty.
-            uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_TX;
+            lsr: Arc::new(AtomicU8::new(UART_LSR_TX)),
+            interrupting,
+            reading: false,
+            io_bytes: 0,
+            backends: Vec::new(),
+            input: None,
+            stdout: true,
+            deterministic: false,
+        }
+    }
+
+    /// Start a thread reading stdin. It's started when a guest accesses the UART for the first
+    /// time, so that a console device which isn't used doesn't steal input from the other.
+    fn start_reader(&mut self) {
+        if self.reading {
+            return;
+        }
+        self.reading = true;
+        if self.deterministic {
+            return;
         }
 
+        let mut input = self.input.take().unwrap_or_else(|| Box::new(io::stdin()));
         let mut byte = [0; 1];
-        let cloned_uart = uart.clone();
-        let cloned_interrupting = interrupting.clone();
+        let cloned_uart = self.uart.clone();
+        let cloned_lsr = self.lsr.clone();
+        let cloned_interrupting = self.interrupting.clone();
         let _uart_thread_for_read = thread::spawn(move || loop {
-            match io::stdin().read(&mut byte) {
+            match input.read(&mut byte) {
+                // The end of the input.
+                Ok(0) => break,
                 Ok(_) => {
                     let (uart, cvar) = &*cloned_uart;
                     let mut uart = uart.lock().expect("failed to get an UART object");
                     // Wait for the thread to start up.
-                    while (uart[(UART_LSR - UART_BASE) as usize] & UART_LSR_RX) == 1 {
+                    while (cloned_lsr.load(Ordering::Acquire) & UART_LSR_RX) == 1 {
                         uart = cvar.wait(uart).expect("the mutex is poisoned");
                     }
                     uart[0] = byte[0];
                     cloned_interrupting.store(true, Ordering::Release);
                     // Data has been receive.
-                    uart[(UART_LSR - UART_BASE) as usize] |= UART_LSR_RX;
+                    cloned_lsr.fetch_or(UART_LSR_RX, Ordering::Release);
                 }
                 Err(e) => {
                     println!("{}", e);
                 }
             }
         });
-        Self { uart, interrupting }
+    }
+
+    /// Read input by `poll_input` instead of a thread, so the time input arrives doesn't depend
+    /// on the host. It must be called before a guest accesses the UART.
+    pub fn set_deterministic(&mut self) {
+        self.deterministic = true;
+    }
+
+    /// Receive a byte if the receive holding register is empty, blocking until the input has one.
+    /// It does nothing until a guest accesses the UART.
+    pub fn poll_input(&mut self) {
+        if !self.deterministic || !self.reading {
+            return;
+        }
+        let (uart, _cvar) = &*self.uart;
+        let mut uart = uart.lock().expect("failed to get an UART object");
+        if (self.lsr.load(Ordering::Acquire) & UART_LSR_RX) == 1 {
+            return;
+        }
+        let input = self.input.get_or_insert_with(|| Box::new(io::stdin()));
+        let mut byte = [0; 1];
+        match input.read(&mut byte) {
+            Ok(1) => {
+                uart[0] = byte[0];
+                self.interrupting.store(true, Ordering::Release);
+                self.lsr.fetch_or(UART_LSR_RX, Ordering::Release);
+            }
+            // The end of the input.
+            _ => self.input = Some(Box::new(io::empty())),
+        }
     }
 
     /// Return true if an interrupt is pending. Clear the interrupting flag by swapping a value.
@@ -103,13 +202,59 @@ impl Uart {
         self.interrupting.swap(false, Ordering::Acquire)
     }
 
-    fn load8(&mut self, addr: u64) -> u64 {
+    /// Save the registers for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        let (uart, _cvar) = &*self.uart;
+        let mut regs = *uart.lock().expect("failed to get an UART object");
+        regs[(UART_LSR - UART_BASE) as usize] = self.lsr.load(Ordering::Acquire);
+        write_bytes(out, &regs[..])
+    }
+
+    /// Restore the registers from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        let regs = read_bytes(input)?;
         let (uart, cvar) = &*self.uart;
         let mut uart = uart.lock().expect("failed to get an UART object");
+        let len = regs.len().min(uart.len());
+        uart[..len].copy_from_slice(&regs[..len]);
+        if let Some(lsr) = regs.get((UART_LSR - UART_BASE) as usize) {
+            self.lsr.store(*lsr, Ordering::Release);
+        }
+        cvar.notify_one();
+        Ok(())
+    }
+
+    /// Disconnect the UART from stdin and stdout. Input is read from `input` instead, and
+    /// transmitted bytes are only copied to backends. It must be called before a guest accesses
+    /// the UART.
+    pub fn detach(&mut self, input: Box<dyn Read + Send>) {
+        self.input = Some(input);
+        self.stdout = false;
+    }
+
+    /// Add an output which transmitted bytes are copied to.
+    pub fn add_backend(&mut self, backend: Box<dyn Write + Send>) {
+        self.backends.push(backend);
+    }
+
+    /// Return the number of bytes transmitted and received.
+    pub fn io_bytes(&self) -> u64 {
+        self.io_bytes
+    }
+
+    fn load8(&mut self, addr: u64) -> u64 {
+        // Guests poll the line status register in a loop, so read it without the lock.
+        if addr == UART_LSR {
+            return self.lsr.load(Ordering::Acquire) as u64;
+        }
+        let (uart, cvar) = &*self.uart;
+        let uart = uart.lock().expect("failed to get an UART object");
         match addr {
             UART_RHR => {
+                if (self.lsr.fetch_and(!UART_LSR_RX, Ordering::AcqRel) & UART_LSR_RX) == 1 {
+                    self.io_bytes += 1;
+                }
                 cvar.notify_one();
-                uart[(UART_LSR - UART_BASE) as usize] &= !UART_LSR_RX;
                 uart[(UART_RHR - UART_BASE) as usize] as u64
             }
             _ => uart[(addr - UART_BASE) as usize] as u64,
@@ -117,14 +262,21 @@ impl Uart {
     }
 
     fn store8(&mut self, addr: u64, value: u64) {
-        let (uart, _cvar) = &*self.uart;
-        let mut uart = uart.lock().expect("failed to get an UART object");
         match addr {
             UART_THR => {
-                print!("{}", value as u8 as char);
-                io::stdout().flush().expect("failed to flush stdout");
+                self.io_bytes += 1;
+                if self.stdout {
+                    print!("{}", value as u8 as char);
+                    io::stdout().flush().expect("failed to flush stdout");
+                }
+                // A backend which fails to write, e.g. a closed socket, is removed.
+                self.backends
+                    .retain_mut(|backend| backend.write_all(&[value as u8]).is_ok());
             }
+            UART_LSR => self.lsr.store(value as u8, Ordering::Release),
             _ => {
+                let (uart, _cvar) = &*self.uart;
+                let mut uart = uart.lock().expect("failed to get an UART object");
                 uart[(addr - UART_BASE) as usize] = value as u8;
             }
         }
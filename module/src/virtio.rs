@@ -4,19 +4,45 @@
 //! The virtio spec:
 //! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
 
+use std::collections::VecDeque;
+use std::io;
+use std::io::prelude::*;
+
 use crate::bus::*;
-use crate::cpu::*;
+use crate::snapshot::*;
 use crate::trap::*;
+use crate::virtqueue::*;
 
-/// The interrupt request of virtio.
+/// The interrupt request of the first virtio-mmio slot. The slot `n` raises `VIRTIO_IRQ + n`.
 pub const VIRTIO_IRQ: u64 = 1;
 
-const VRING_DESC_SIZE: u64 = 16;
-/// The number of virtio descriptors. It must be a power of two.
-const DESC_NUM: u64 = 8;
+/// The maximum size of the queue.
+const QUEUE_NUM_MAX: u32 = 64;
+
+/// The feature bit of the flush command.
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+/// The request types in `virtio_blk_outhdr`.
+const VIRTIO_BLK_T_IN: u64 = 0;
+const VIRTIO_BLK_T_OUT: u64 = 1;
+const VIRTIO_BLK_T_FLUSH: u64 = 4;
+const VIRTIO_BLK_T_GET_ID: u64 = 8;
+
+/// The status of a request written to its last descriptor.
+const VIRTIO_BLK_S_OK: u64 = 0;
+const VIRTIO_BLK_S_IOERR: u64 = 1;
+const VIRTIO_BLK_S_UNSUPP: u64 = 2;
+
+/// The number of bytes transferred between the disk and the dram per instruction. A request
+/// completes after its data is transferred, so the guest keeps running during a large transfer.
+const DISK_BYTES_PER_TICK: u64 = 64;
+
+/// The serial number returned by the GET_ID command, padded with zeros to 20 bytes.
+const VIRTIO_BLK_ID: &[u8] = b"rvemu-virtio-blk";
+const VIRTIO_BLK_ID_BYTES: u64 = 20;
 
 /// Always return 0x74726976.
-pub const VIRTIO_MAGIC: u64 = VIRTIO_BASE + 0x000;
+pub const VIRTIO_MAGIC: u64 = VIRTIO_BASE;
 /// The version. 1 is legacy.
 pub const VIRTIO_VERSION: u64 = VIRTIO_BASE + 0x004;
 /// device type; 1 is net, 2 is disk.
@@ -31,10 +57,12 @@ pub const VIRTIO_DRIVER_FEATURES: u64 = VIRTIO_BASE + 0x020;
 pub const VIRTIO_GUEST_PAGE_SIZE: u64 = VIRTIO_BASE + 0x028;
 /// Select queue, write-only.
 pub const VIRTIO_QUEUE_SEL: u64 = VIRTIO_BASE + 0x030;
-/// Max size of current queue, read-only. In QEMU, `VIRTIO_COUNT = 8`.
+/// Max size of current queue, read-only. It's 0 if the queue doesn't exist.
 pub const VIRTIO_QUEUE_NUM_MAX: u64 = VIRTIO_BASE + 0x034;
 /// Size of current queue, write-only.
 pub const VIRTIO_QUEUE_NUM: u64 = VIRTIO_BASE + 0x038;
+/// Used ring alignment for current queue, write-only.
+pub const VIRTIO_QUEUE_ALIGN: u64 = VIRTIO_BASE + 0x03c;
 /// Physical page number for queue, read and write.
 pub const VIRTIO_QUEUE_PFN: u64 = VIRTIO_BASE + 0x040;
 /// Notify the queue number, write-only.
@@ -44,56 +72,235 @@ pub const VIRTIO_QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x050;
 /// progress. Writing zero (0x0) to this register triggers a device reset.
 pub const VIRTIO_STATUS: u64 = VIRTIO_BASE + 0x070;
 
-/// Paravirtualized drivers for IO virtualization.
+/// A request taken from the available ring.
+#[derive(Debug, Clone)]
+struct Request {
+    /// The head of the descriptor chain.
+    head: u64,
+    blk_type: u64,
+    sector: u64,
+    /// The data buffers (address, length, flags).
+    data: Vec<(u64, u64, u64)>,
+    /// The buffer of the status result.
+    status: Option<(u64, u64, u64)>,
+    result: u64,
+    /// The number of data bytes transferred.
+    done: u64,
+}
+
+impl Request {
+    /// Return the number of data bytes to transfer, which is 0 unless it's a valid read or
+    /// write.
+    fn data_len(&self) -> u64 {
+        match (self.blk_type, self.result) {
+            (VIRTIO_BLK_T_IN, VIRTIO_BLK_S_OK) | (VIRTIO_BLK_T_OUT, VIRTIO_BLK_S_OK) => {
+                self.data.iter().map(|(_, len, _)| len).sum()
+            }
+            _ => 0,
+        }
+    }
+
+    fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        let status = self.status.unwrap_or((0, 0, 0));
+        for value in [
+            self.head,
+            self.blk_type,
+            self.sector,
+            self.status.is_some() as u64,
+            status.0,
+            status.1,
+            status.2,
+            self.result,
+            self.done,
+            self.data.len() as u64,
+        ] {
+            write_u64(out, value)?;
+        }
+        for &(addr, len, flags) in &self.data {
+            write_u64(out, addr)?;
+            write_u64(out, len)?;
+            write_u64(out, flags)?;
+        }
+        Ok(())
+    }
+
+    fn restore(input: &mut dyn Read) -> io::Result<Self> {
+        let head = read_u64(input)?;
+        let blk_type = read_u64(input)?;
+        let sector = read_u64(input)?;
+        let has_status = read_u64(input)? != 0;
+        let status = (read_u64(input)?, read_u64(input)?, read_u64(input)?);
+        let result = read_u64(input)?;
+        let done = read_u64(input)?;
+        let count = read_u64(input)?;
+        let mut data = Vec::new();
+        for _ in 0..count {
+            data.push((read_u64(input)?, read_u64(input)?, read_u64(input)?));
+        }
+        Ok(Self {
+            head,
+            blk_type,
+            sector,
+            data,
+            status: if has_status { Some(status) } else { None },
+            result,
+            done,
+        })
+    }
+}
+
+/// Paravirtualized drivers for IO virtualization. The block device has one queue, the queue 0.
 pub struct Virtio {
-    id: u64,
     driver_features: u32,
     page_size: u32,
     queue_sel: u32,
-    queue_num: u32,
-    queue_pfn: u32,
+    queue: Virtqueue,
     queue_notify: u32,
     status: u32,
     disk: Vec<u8>,
+    /// The requests taken from the available ring and not completed yet, in order.
+    requests: VecDeque<Request>,
+    /// True if completed requests raise an interrupt which hasn't been taken.
+    interrupting: bool,
+    /// The number of bytes transferred between the disk and the dram.
+    io_bytes: u64,
+}
+
+/// Return the name of the register at `offset` of the legacy virtio MMIO interface.
+pub fn mmio_register_name(offset: u64) -> Option<&'static str> {
+    let name = match offset {
+        0x000 => "MagicValue",
+        0x004 => "Version",
+        0x008 => "DeviceID",
+        0x00c => "VendorID",
+        0x010 => "DeviceFeatures",
+        0x014 => "DeviceFeaturesSel",
+        0x020 => "DriverFeatures",
+        0x024 => "DriverFeaturesSel",
+        0x028 => "GuestPageSize",
+        0x030 => "QueueSel",
+        0x034 => "QueueNumMax",
+        0x038 => "QueueNum",
+        0x03c => "QueueAlign",
+        0x040 => "QueuePFN",
+        0x050 => "QueueNotify",
+        0x060 => "InterruptStatus",
+        0x064 => "InterruptACK",
+        0x070 => "Status",
+        _ => return None,
+    };
+    Some(name)
 }
 
 impl Device for Virtio {
-    fn load(&mut self, addr: u64, size: u64) -> Result<u64, Exception> {
-        match size {
-            32 => Ok(self.load32(addr)),
-            _ => Err(Exception::LoadAccessFault),
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::Word => Ok(self.load32(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
         }
     }
 
-    fn store(&mut self, addr: u64, size: u64, value: u64) -> Result<(), Exception> {
-        match size {
-            32 => Ok(self.store32(addr, value)),
-            _ => Err(Exception::StoreAMOAccessFault),
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::Word => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
         }
     }
+
+    fn register_name(&self, addr: u64, _store: bool) -> Option<&'static str> {
+        mmio_register_name(addr - VIRTIO_BASE)
+    }
+
+    /// The disk keeps its contents, and requests in flight are dropped.
+    fn reset(&mut self) {
+        self.driver_features = 0;
+        self.page_size = 0;
+        self.queue_sel = 0;
+        self.queue = Virtqueue::new();
+        self.queue_notify = 9999;
+        self.status = 0;
+        self.requests.clear();
+        self.interrupting = false;
+    }
+}
+
+impl DmaDevice for Virtio {
+    /// Access the disk via virtio. It transfers up to `DISK_BYTES_PER_TICK` bytes of the
+    /// requests in order, so a large transfer doesn't stall the CPU. An interrupt is raised when
+    /// requests complete unless the driver suppresses it by the flag of the available ring or,
+    /// with the event index feature, by `used_event`.
+    ///
+    /// If the queue or a buffer is outside the dram, the requests in flight are dropped and the
+    /// device sets DEVICE_NEEDS_RESET and does nothing until the driver resets it.
+    fn tick(&mut self, dma: &mut Dma) {
+        if self.status & VIRTIO_STATUS_DEVICE_NEEDS_RESET != 0 {
+            return;
+        }
+        if self.process_requests(dma).is_err() {
+            self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+            self.requests.clear();
+        }
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        std::mem::replace(&mut self.interrupting, false)
+    }
 }
 
 impl Virtio {
+    /// Take the requests after a notification and transfer them.
+    fn process_requests(&mut self, dma: &mut Dma) -> Result<(), Exception> {
+        if self.take_notify() {
+            self.take_requests(dma)?;
+        }
+        if self.requests.is_empty() {
+            return Ok(());
+        }
+
+        let old_used_idx = self.queue.used_idx;
+        let mut budget = DISK_BYTES_PER_TICK;
+        while let Some(mut request) = self.requests.pop_front() {
+            budget -= self.transfer(dma, &mut request, budget)?;
+            if request.done < request.data_len() {
+                self.requests.push_front(request);
+                break;
+            }
+            self.complete(dma, &request)?;
+        }
+        let event_idx = self.driver_features as u64 & VIRTIO_RING_F_EVENT_IDX != 0;
+        if self
+            .queue
+            .needs_interrupt(dma, self.page_size, old_used_idx, event_idx)?
+        {
+            self.interrupting = true;
+        }
+        Ok(())
+    }
+
     /// Create a new virtio object.
     pub fn new(disk_image: Vec<u8>) -> Self {
         let mut disk = Vec::new();
         disk.extend(disk_image.iter().cloned());
 
         Self {
-            id: 0,
             driver_features: 0,
             page_size: 0,
             queue_sel: 0,
-            queue_num: 0,
-            queue_pfn: 0,
+            queue: Virtqueue::new(),
             queue_notify: 9999, // TODO: what is the correct initial value?
             status: 0,
             disk,
+            requests: VecDeque::new(),
+            interrupting: false,
+            io_bytes: 0,
         }
     }
 
-    /// Return true if an interrupt is pending.
-    pub fn is_interrupting(&mut self) -> bool {
+    /// Return true if the guest has notified the queue since the last call.
+    fn take_notify(&mut self) -> bool {
         if self.queue_notify != 9999 {
             self.queue_notify = 9999;
             return true;
@@ -108,10 +315,12 @@ impl Virtio {
             VIRTIO_VERSION => 0x1,
             VIRTIO_DEVICE_ID => 0x2,
             VIRTIO_VENDOR_ID => 0x554d4551,
-            VIRTIO_DEVICE_FEATURES => 0, // TODO: what should it return?
+            VIRTIO_DEVICE_FEATURES => {
+                VIRTIO_BLK_F_FLUSH | VIRTIO_RING_F_INDIRECT_DESC | VIRTIO_RING_F_EVENT_IDX
+            }
             VIRTIO_DRIVER_FEATURES => self.driver_features as u64,
-            VIRTIO_QUEUE_NUM_MAX => 8,
-            VIRTIO_QUEUE_PFN => self.queue_pfn as u64,
+            VIRTIO_QUEUE_NUM_MAX if self.queue_sel == 0 => QUEUE_NUM_MAX as u64,
+            VIRTIO_QUEUE_PFN if self.queue_sel == 0 => self.queue.pfn as u64,
             VIRTIO_STATUS => self.status as u64,
             _ => 0,
         }
@@ -121,24 +330,68 @@ impl Virtio {
     pub fn store32(&mut self, addr: u64, value: u64) {
         let val = value as u32;
         match addr {
-            VIRTIO_DEVICE_FEATURES => self.driver_features = val,
+            VIRTIO_DEVICE_FEATURES | VIRTIO_DRIVER_FEATURES => self.driver_features = val,
             VIRTIO_GUEST_PAGE_SIZE => self.page_size = val,
             VIRTIO_QUEUE_SEL => self.queue_sel = val,
-            VIRTIO_QUEUE_NUM => self.queue_num = val,
-            VIRTIO_QUEUE_PFN => self.queue_pfn = val,
+            // Other queues than the queue 0 don't exist.
+            VIRTIO_QUEUE_NUM if self.queue_sel == 0 => self.queue.num = val.min(QUEUE_NUM_MAX),
+            VIRTIO_QUEUE_ALIGN if self.queue_sel == 0 => self.queue.align = val,
+            VIRTIO_QUEUE_PFN if self.queue_sel == 0 => self.queue.pfn = val,
             VIRTIO_QUEUE_NOTIFY => self.queue_notify = val,
             VIRTIO_STATUS => self.status = val,
             _ => {}
         }
     }
 
-    fn get_new_id(&mut self) -> u64 {
-        self.id = self.id.wrapping_add(1);
-        self.id
+    /// Return the number of bytes transferred between the disk and the dram.
+    pub fn io_bytes(&self) -> u64 {
+        self.io_bytes
     }
 
-    fn desc_addr(&self) -> u64 {
-        self.queue_pfn as u64 * self.page_size as u64
+    /// Save the registers and the disk for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        write_u64(out, self.queue.used_idx as u64)?;
+        write_u64(out, self.queue.last_avail as u64)?;
+        for reg in [
+            self.driver_features,
+            self.page_size,
+            self.queue_sel,
+            self.queue.num,
+            self.queue.align,
+            self.queue.pfn,
+            self.queue_notify,
+            self.status,
+        ] {
+            write_u64(out, reg as u64)?;
+        }
+        write_bytes(out, &self.disk)?;
+        write_u64(out, self.interrupting as u64)?;
+        write_u64(out, self.requests.len() as u64)?;
+        for request in &self.requests {
+            request.save(out)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the registers and the disk from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.queue.used_idx = read_u64(input)? as u16;
+        self.queue.last_avail = read_u64(input)? as u16;
+        self.driver_features = read_u64(input)? as u32;
+        self.page_size = read_u64(input)? as u32;
+        self.queue_sel = read_u64(input)? as u32;
+        self.queue.num = read_u64(input)? as u32;
+        self.queue.align = read_u64(input)? as u32;
+        self.queue.pfn = read_u64(input)? as u32;
+        self.queue_notify = read_u64(input)? as u32;
+        self.status = read_u64(input)? as u32;
+        self.disk = read_bytes(input)?;
+        self.interrupting = read_u64(input)? != 0;
+        self.requests.clear();
+        for _ in 0..read_u64(input)? {
+            self.requests.push_back(Request::restore(input)?);
+        }
+        Ok(())
     }
 
     fn read_disk(&self, addr: u64) -> u64 {
@@ -149,116 +402,135 @@ impl Virtio {
         self.disk[addr as usize] = value as u8
     }
 
-    /// Access the disk via virtio. This is an associated function which takes a `cpu` object to
-    /// read and write with a dram directly (DMA).
-    pub fn disk_access(cpu: &mut Cpu) {
+    /// Return true if `len` bytes from the sector are inside the disk.
+    fn is_in_disk(&self, sector: u64, len: u64) -> bool {
+        sector
+            .checked_mul(512)
+            .and_then(|start| start.checked_add(len))
+            .is_some_and(|end| end <= self.disk.len() as u64)
+    }
+
+    /// Take the requests which the guest has put in the available ring after a notification.
+    /// They are processed by `tick` later.
+    fn take_requests(&mut self, dma: &mut Dma) -> Result<(), Exception> {
         // See more information in
         // https://github.com/mit-pdos/xv6-riscv/blob/riscv/kernel/virtio_disk.c
+        while let Some(chain) = self.queue.pop_chain(dma, self.page_size)? {
+            let request = self.parse_request(dma, chain)?;
+            self.requests.push_back(request);
+        }
+        Ok(())
+    }
+
+    /// Read a request in a descriptor chain.
+    fn parse_request(&self, dma: &mut Dma, chain: Chain) -> Result<Request, Exception> {
+        let Chain { head, descs } = chain;
+        // A request is a header, data buffers and a 1-byte status result. Reads and writes have
+        // one data buffer in xv6, the flush command has none.
+        let (header, status, data) = match descs.as_slice() {
+            [header, data @ .., status] => (*header, Some(*status), data.to_vec()),
+            _ => ((0, 0, 0), None, Vec::new()),
+        };
+        let mut request = Request {
+            head,
+            blk_type: VIRTIO_BLK_T_IN,
+            sector: 0,
+            data,
+            status,
+            result: VIRTIO_BLK_S_OK,
+            done: 0,
+        };
+        if status.is_none() {
+            request.result = VIRTIO_BLK_S_IOERR;
+            return Ok(request);
+        }
 
-        // the spec says that legacy block operations use three
-        // descriptors: one for type/reserved/sector, one for
-        // the data, one for a 1-byte status result.
-
-        // desc = pages -- num * VRingDesc
-        // avail = pages + 0x40 -- 2 * uint16, then num * uint16
-        // used = pages + 4096 -- 2 * uint16, then num * vRingUsedElem
-        let desc_addr = cpu.bus.virtio.desc_addr();
-        let avail_addr = cpu.bus.virtio.desc_addr() + 0x40;
-        let used_addr = cpu.bus.virtio.desc_addr() + 4096;
-
-        // avail[0] is flags
-        // avail[1] tells the device how far to look in avail[2...].
-        let offset = cpu
-            .bus
-            .load(avail_addr.wrapping_add(1), 16)
-            .expect("failed to read offset");
-        // avail[2...] are desc[] indices the device should process.
-        // we only tell device the first index in our chain of descriptors.
-        let index = cpu
-            .bus
-            .load(
-                avail_addr.wrapping_add(offset % DESC_NUM).wrapping_add(2),
-                16,
-            )
-            .expect("failed to read index");
-
-        // Read `VRingDesc`, virtio descriptors.
-        let desc_addr0 = desc_addr + VRING_DESC_SIZE * index;
-        let addr0 = cpu
-            .bus
-            .load(desc_addr0, 64)
-            .expect("failed to read an address field in a descriptor");
-        // Add 14 because of `VRingDesc` structure.
-        // struct VRingDesc {
-        //   uint64 addr;
-        //   uint32 len;
-        //   uint16 flags;
-        //   uint16 next
-        // };
-        // The `next` field can be accessed by offset 14 (8 + 4 + 2) bytes.
-        let next0 = cpu
-            .bus
-            .load(desc_addr0.wrapping_add(14), 16)
-            .expect("failed to read a next field in a descripor");
-
-        // Read `VRingDesc` again, virtio descriptors.
-        let desc_addr1 = desc_addr + VRING_DESC_SIZE * next0;
-        let addr1 = cpu
-            .bus
-            .load(desc_addr1, 64)
-            .expect("failed to read an address field in a descriptor");
-        let len1 = cpu
-            .bus
-            .load(desc_addr1.wrapping_add(8), 32)
-            .expect("failed to read a length field in a descriptor");
-        let flags1 = cpu
-            .bus
-            .load(desc_addr1.wrapping_add(12), 16)
-            .expect("failed to read a flags field in a descriptor");
-
-        // Read `virtio_blk_outhdr`. Add 8 because of its structure.
+        // Read `virtio_blk_outhdr`.
         // struct virtio_blk_outhdr {
         //   uint32 type;
         //   uint32 reserved;
         //   uint64 sector;
         // } buf0;
-        let blk_sector = cpu
-            .bus
-            .load(addr0.wrapping_add(8), 64)
-            .expect("failed to read a sector field in a virtio_blk_outhdr");
-
-        // Write to a device if the second bit `flag1` is set.
-        match (flags1 & 2) == 0 {
-            true => {
-                // Read dram data and write it to a disk directly (DMA).
-                for i in 0..len1 as u64 {
-                    let data = cpu
-                        .bus
-                        .load(addr1 + i, 8)
-                        .expect("failed to read from dram");
-                    cpu.bus.virtio.write_disk(blk_sector * 512 + i, data);
-                }
+        request.blk_type = dma.load(header.0, 32)?;
+        request.sector = dma.load(header.0.wrapping_add(8), 64)?;
+        request.result = match request.blk_type {
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT
+                if !self.is_in_disk(request.sector, request.data.iter().map(|d| d.1).sum()) =>
+            {
+                VIRTIO_BLK_S_IOERR
             }
-            false => {
-                // Read disk data and write it to dram directly (DMA).
-                for i in 0..len1 as u64 {
-                    let data = cpu.bus.virtio.read_disk(blk_sector * 512 + i);
-                    cpu.bus
-                        .store(addr1 + i, 8, data)
-                        .expect("failed to write to dram");
+            VIRTIO_BLK_T_IN | VIRTIO_BLK_T_OUT | VIRTIO_BLK_T_FLUSH => VIRTIO_BLK_S_OK,
+            VIRTIO_BLK_T_GET_ID => match request.data.first() {
+                Some(&(_, _, flags)) if flags & VRING_DESC_F_WRITE != 0 => VIRTIO_BLK_S_OK,
+                _ => VIRTIO_BLK_S_IOERR,
+            },
+            _ => VIRTIO_BLK_S_UNSUPP,
+        };
+        Ok(request)
+    }
+
+    /// Transfer up to `budget` bytes of a read or a write request between the disk and the
+    /// dram directly (DMA). Return the number of bytes transferred.
+    fn transfer(
+        &mut self,
+        dma: &mut Dma,
+        request: &mut Request,
+        budget: u64,
+    ) -> Result<u64, Exception> {
+        let end = request.data_len().min(request.done + budget);
+        let start = request.done;
+        // Walk the data buffers to the bytes from `start` to `end`.
+        let mut offset = 0;
+        for &(addr, len, _) in &request.data {
+            let from = start.max(offset);
+            let to = end.min(offset + len);
+            for i in from..to {
+                let disk_addr = request.sector * 512 + i;
+                let dram_addr = addr.wrapping_add(i - offset);
+                match request.blk_type {
+                    VIRTIO_BLK_T_IN => {
+                        // Read disk data and write it to dram directly (DMA).
+                        let data = self.read_disk(disk_addr);
+                        dma.store(dram_addr, 8, data)?;
+                    }
+                    _ => {
+                        // Read dram data and write it to a disk directly (DMA).
+                        let data = dma.load(dram_addr, 8)?;
+                        self.write_disk(disk_addr, data);
+                    }
                 }
             }
+            offset += len;
+        }
+        request.done = end;
+        self.io_bytes += end - start;
+        Ok(end - start)
+    }
+
+    /// Finish a request: write its status, and put it to the used ring.
+    fn complete(&mut self, dma: &mut Dma, request: &Request) -> Result<(), Exception> {
+        // The number of bytes written to the guest.
+        let mut written = match request.blk_type {
+            VIRTIO_BLK_T_IN if request.result == VIRTIO_BLK_S_OK => request.data_len(),
+            _ => 0,
         };
+        if let (VIRTIO_BLK_T_GET_ID, VIRTIO_BLK_S_OK, Some(&(addr, len, _))) =
+            (request.blk_type, request.result, request.data.first())
+        {
+            for i in 0..len.min(VIRTIO_BLK_ID_BYTES) {
+                let byte = VIRTIO_BLK_ID.get(i as usize).copied().unwrap_or(0);
+                dma.store(addr.wrapping_add(i), 8, byte as u64)?;
+                written += 1;
+            }
+        }
+        if let Some((addr, len, flags)) = request.status {
+            if flags & VRING_DESC_F_WRITE != 0 && len >= 1 {
+                dma.store(addr, 8, request.result)?;
+                written += 1;
+            }
+        }
 
-        // Write id to `UsedArea`. Add 2 because of its structure.
-        // struct UsedArea {
-        //   uint16 flags;
-        //   uint16 id;
-        //   struct VRingUsedElem elems[NUM];
-        // };
-        let new_id = cpu.bus.virtio.get_new_id();
-        cpu.bus
-            .store(used_addr.wrapping_add(2), 16, new_id % 8)
-            .expect("failed to write to dram");
+        self.queue
+            .push_used(dma, self.page_size, request.head, written)
     }
 }
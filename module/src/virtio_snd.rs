@@ -0,0 +1,521 @@
+//! The virtio_snd module contains a virtio sound device with a single PCM output stream. Frames
+//! the guest plays are written to a WAV file, so drivers can be developed and checked without a
+//! host audio device.
+//!
+//! Buffers are returned as soon as they are written, so playback runs faster than real time. The
+//! WAV header records the parameters of the first start; later samples are written as they are.
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.2/virtio-v1.2.pdf
+
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::prelude::*;
+use std::io::SeekFrom;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtqueue::*;
+
+/// Always return 0x74726976.
+pub const VIRTIO_SND_MAGIC: u64 = VIRTIO_SND_BASE;
+/// The version. 1 is legacy.
+pub const VIRTIO_SND_VERSION: u64 = VIRTIO_SND_BASE + 0x004;
+/// Device type; 25 is a sound device.
+pub const VIRTIO_SND_DEVICE_ID: u64 = VIRTIO_SND_BASE + 0x008;
+/// Always return 0x554d4551.
+pub const VIRTIO_SND_VENDOR_ID: u64 = VIRTIO_SND_BASE + 0x00c;
+/// Device features of the selected 32 bits.
+pub const VIRTIO_SND_DEVICE_FEATURES: u64 = VIRTIO_SND_BASE + 0x010;
+/// Select 32 bits of device features, write-only.
+pub const VIRTIO_SND_DEVICE_FEATURES_SEL: u64 = VIRTIO_SND_BASE + 0x014;
+/// Driver features of the selected 32 bits.
+pub const VIRTIO_SND_DRIVER_FEATURES: u64 = VIRTIO_SND_BASE + 0x020;
+/// Select 32 bits of driver features, write-only.
+pub const VIRTIO_SND_DRIVER_FEATURES_SEL: u64 = VIRTIO_SND_BASE + 0x024;
+/// Page size for PFN, write-only.
+pub const VIRTIO_SND_GUEST_PAGE_SIZE: u64 = VIRTIO_SND_BASE + 0x028;
+/// Select queue, write-only.
+pub const VIRTIO_SND_QUEUE_SEL: u64 = VIRTIO_SND_BASE + 0x030;
+/// Max size of current queue, read-only.
+pub const VIRTIO_SND_QUEUE_NUM_MAX: u64 = VIRTIO_SND_BASE + 0x034;
+/// Size of current queue, write-only.
+pub const VIRTIO_SND_QUEUE_NUM: u64 = VIRTIO_SND_BASE + 0x038;
+/// Used ring alignment of current queue, write-only.
+pub const VIRTIO_SND_QUEUE_ALIGN: u64 = VIRTIO_SND_BASE + 0x03c;
+/// Physical page number for current queue, read and write.
+pub const VIRTIO_SND_QUEUE_PFN: u64 = VIRTIO_SND_BASE + 0x040;
+/// Notify the queue number, write-only.
+pub const VIRTIO_SND_QUEUE_NOTIFY: u64 = VIRTIO_SND_BASE + 0x050;
+/// Interrupt status, read-only.
+pub const VIRTIO_SND_INTERRUPT_STATUS: u64 = VIRTIO_SND_BASE + 0x060;
+/// Interrupt acknowledge, write-only.
+pub const VIRTIO_SND_INTERRUPT_ACK: u64 = VIRTIO_SND_BASE + 0x064;
+/// Device status, read and write. Writing zero resets the device.
+pub const VIRTIO_SND_STATUS: u64 = VIRTIO_SND_BASE + 0x070;
+/// The number of jacks in the configuration space.
+pub const VIRTIO_SND_CONFIG_JACKS: u64 = VIRTIO_SND_BASE + 0x100;
+/// The number of PCM streams in the configuration space.
+pub const VIRTIO_SND_CONFIG_STREAMS: u64 = VIRTIO_SND_BASE + 0x104;
+/// The number of channel maps in the configuration space.
+pub const VIRTIO_SND_CONFIG_CHMAPS: u64 = VIRTIO_SND_BASE + 0x108;
+
+/// The maximum size of a queue.
+const QUEUE_NUM_MAX: u32 = 64;
+/// The queue for control requests.
+const CONTROL_QUEUE: usize = 0;
+/// The queue for PCM output frames.
+const TX_QUEUE: usize = 2;
+/// The number of queues: control, event, tx and rx.
+const QUEUE_COUNT: usize = 4;
+/// The DRIVER_OK bit in the status register.
+const STATUS_DRIVER_OK: u32 = 4;
+/// VIRTIO_F_VERSION_1, which the Linux driver requires.
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+// Control request codes.
+const R_PCM_INFO: u32 = 0x0100;
+const R_PCM_SET_PARAMS: u32 = 0x0101;
+const R_PCM_PREPARE: u32 = 0x0102;
+const R_PCM_RELEASE: u32 = 0x0103;
+const R_PCM_START: u32 = 0x0104;
+const R_PCM_STOP: u32 = 0x0105;
+
+// Status codes.
+const S_OK: u32 = 0x8000;
+const S_BAD_MSG: u32 = 0x8001;
+const S_NOT_SUPP: u32 = 0x8002;
+const S_IO_ERR: u32 = 0x8003;
+
+// PCM sample formats.
+const PCM_FMT_U8: u8 = 4;
+const PCM_FMT_S16: u8 = 5;
+const PCM_FMT_S32: u8 = 17;
+
+/// PCM frame rates indexed by `VIRTIO_SND_PCM_RATE_*`.
+const PCM_RATES: [u32; 14] = [
+    5512, 8000, 11025, 16000, 22050, 32000, 44100, 48000, 64000, 88200, 96000, 176400, 192000,
+    384000,
+];
+
+/// The direction of the output stream.
+const DIRECTION_OUTPUT: u8 = 0;
+/// The maximum number of channels of the output stream.
+const CHANNELS_MAX: u8 = 2;
+/// The size of `struct virtio_snd_pcm_info`.
+const PCM_INFO_SIZE: usize = 32;
+/// The size of the WAV header.
+const WAV_HEADER_SIZE: u32 = 44;
+
+/// The parameters of the PCM stream.
+#[derive(Clone, Copy)]
+struct PcmParams {
+    channels: u8,
+    format: u8,
+    rate: u8,
+}
+
+impl PcmParams {
+    fn bits_per_sample(&self) -> u16 {
+        match self.format {
+            PCM_FMT_U8 => 8,
+            PCM_FMT_S16 => 16,
+            _ => 32,
+        }
+    }
+}
+
+/// A WAV file receiving PCM frames.
+struct WavWriter {
+    out: File,
+    /// The number of bytes in the data chunk.
+    data_len: u32,
+}
+
+impl WavWriter {
+    fn create(path: &str, params: PcmParams) -> io::Result<Self> {
+        let mut out = File::create(path)?;
+        let channels = params.channels as u16;
+        let bits = params.bits_per_sample();
+        let rate = PCM_RATES[params.rate as usize];
+        let block_align = channels * bits / 8;
+        out.write_all(b"RIFF")?;
+        out.write_all(&(WAV_HEADER_SIZE - 8).to_le_bytes())?;
+        out.write_all(b"WAVEfmt ")?;
+        out.write_all(&16u32.to_le_bytes())?;
+        // PCM.
+        out.write_all(&1u16.to_le_bytes())?;
+        out.write_all(&channels.to_le_bytes())?;
+        out.write_all(&rate.to_le_bytes())?;
+        out.write_all(&(rate * block_align as u32).to_le_bytes())?;
+        out.write_all(&block_align.to_le_bytes())?;
+        out.write_all(&bits.to_le_bytes())?;
+        out.write_all(b"data")?;
+        out.write_all(&0u32.to_le_bytes())?;
+        Ok(Self { out, data_len: 0 })
+    }
+
+    /// Append frames and update the sizes in the header, so the file is valid at any time.
+    fn write(&mut self, frames: &[u8]) -> io::Result<()> {
+        self.out.write_all(frames)?;
+        self.data_len = self.data_len.wrapping_add(frames.len() as u32);
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out
+            .write_all(&(WAV_HEADER_SIZE - 8 + self.data_len).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&self.data_len.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        Ok(())
+    }
+}
+
+/// The virtio sound device.
+pub struct VirtioSnd {
+    device_features_sel: u32,
+    driver_features: u64,
+    driver_features_sel: u32,
+    page_size: u32,
+    queue_sel: u32,
+    queues: [Virtqueue; QUEUE_COUNT],
+    interrupt_status: u32,
+    status: u32,
+    /// Queues which the guest notified, one bit per queue.
+    notified: u32,
+    /// True if used queues raise an interrupt which hasn't been taken.
+    interrupting: bool,
+    params: Option<PcmParams>,
+    started: bool,
+    /// The path of the WAV file. Frames are discarded if it's None.
+    wav_path: Option<String>,
+    wav: Option<WavWriter>,
+}
+
+impl Device for VirtioSnd {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::Word => Ok(self.load32(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::Word => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    /// The WAV output is kept.
+    fn reset(&mut self) {
+        self.status = 0;
+        self.queues = [Virtqueue::new(); QUEUE_COUNT];
+        self.interrupt_status = 0;
+        self.notified = 0;
+        self.interrupting = false;
+        self.params = None;
+        self.started = false;
+    }
+}
+
+impl DmaDevice for VirtioSnd {
+    /// Process requests in the control queue and frames in the tx queue by direct dram access
+    /// (DMA). An interrupt is raised if any queue is used and the driver doesn't suppress it.
+    ///
+    /// If a queue or a buffer is outside the dram, the device sets DEVICE_NEEDS_RESET, notifies
+    /// the driver of the configuration change and does nothing until the driver resets it.
+    fn tick(&mut self, dma: &mut Dma) {
+        if self.notified == 0
+            || self.status & STATUS_DRIVER_OK == 0
+            || self.status & VIRTIO_STATUS_DEVICE_NEEDS_RESET != 0
+        {
+            return;
+        }
+        let notified = std::mem::replace(&mut self.notified, 0);
+        match self.process_queues(dma, notified) {
+            Ok(true) => self.interrupt_status |= 1,
+            Ok(false) => return,
+            Err(_) => {
+                self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+                self.interrupt_status |= 2;
+            }
+        }
+        self.interrupting = true;
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        std::mem::replace(&mut self.interrupting, false)
+    }
+}
+
+impl VirtioSnd {
+    /// Create a new `VirtioSnd` object without an output file.
+    pub fn new() -> Self {
+        Self {
+            device_features_sel: 0,
+            driver_features: 0,
+            driver_features_sel: 0,
+            page_size: 0,
+            queue_sel: 0,
+            queues: [Virtqueue::new(); QUEUE_COUNT],
+            interrupt_status: 0,
+            status: 0,
+            notified: 0,
+            interrupting: false,
+            params: None,
+            started: false,
+            wav_path: None,
+            wav: None,
+        }
+    }
+
+    /// Write played frames to a WAV file at `path`. The file is created at the first start.
+    pub fn set_wav_output(&mut self, path: &str) {
+        self.wav_path = Some(path.to_string());
+    }
+
+    /// Save the registers, the queues and the stream for a snapshot. The WAV file isn't part of
+    /// the snapshot, so frames played after a restore go to the file already open, if any.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        for reg in [
+            self.device_features_sel,
+            self.driver_features_sel,
+            self.page_size,
+            self.queue_sel,
+            self.interrupt_status,
+            self.status,
+            self.notified,
+        ] {
+            write_u64(out, reg as u64)?;
+        }
+        write_u64(out, self.driver_features)?;
+        for queue in self.queues.iter() {
+            queue.save(out)?;
+        }
+        let params = self
+            .params
+            .map(|p| vec![p.channels, p.format, p.rate])
+            .unwrap_or_default();
+        write_bytes(out, &params)?;
+        write_u64(out, self.started as u64)?;
+        write_u64(out, self.interrupting as u64)
+    }
+
+    /// Restore the registers, the queues and the stream from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.device_features_sel = read_u64(input)? as u32;
+        self.driver_features_sel = read_u64(input)? as u32;
+        self.page_size = read_u64(input)? as u32;
+        self.queue_sel = read_u64(input)? as u32;
+        self.interrupt_status = read_u64(input)? as u32;
+        self.status = read_u64(input)? as u32;
+        self.notified = read_u64(input)? as u32;
+        self.driver_features = read_u64(input)?;
+        for queue in self.queues.iter_mut() {
+            queue.restore(input)?;
+        }
+        self.params = match read_bytes(input)?.as_slice() {
+            [channels, format, rate] => Some(PcmParams {
+                channels: *channels,
+                format: *format,
+                rate: *rate,
+            }),
+            _ => None,
+        };
+        self.started = read_u64(input)? != 0;
+        self.interrupting = read_u64(input)? != 0;
+        Ok(())
+    }
+
+    fn load32(&self, addr: u64) -> u64 {
+        let queue = &self.queues[self.queue_sel as usize % QUEUE_COUNT];
+        match addr {
+            VIRTIO_SND_MAGIC => 0x74726976,
+            VIRTIO_SND_VERSION => 0x1,
+            VIRTIO_SND_DEVICE_ID => 25,
+            VIRTIO_SND_VENDOR_ID => 0x554d4551,
+            VIRTIO_SND_DEVICE_FEATURES => match self.device_features_sel {
+                1 => VIRTIO_F_VERSION_1 >> 32,
+                _ => VIRTIO_RING_F_INDIRECT_DESC,
+            },
+            VIRTIO_SND_DRIVER_FEATURES => {
+                (self.driver_features >> (32 * (self.driver_features_sel & 1))) & 0xffffffff
+            }
+            VIRTIO_SND_QUEUE_NUM_MAX => QUEUE_NUM_MAX as u64,
+            VIRTIO_SND_QUEUE_PFN => queue.pfn as u64,
+            VIRTIO_SND_INTERRUPT_STATUS => self.interrupt_status as u64,
+            VIRTIO_SND_STATUS => self.status as u64,
+            VIRTIO_SND_CONFIG_JACKS => 0,
+            VIRTIO_SND_CONFIG_STREAMS => 1,
+            VIRTIO_SND_CONFIG_CHMAPS => 0,
+            _ => 0,
+        }
+    }
+
+    fn store32(&mut self, addr: u64, value: u64) {
+        let val = value as u32;
+        let queue = &mut self.queues[self.queue_sel as usize % QUEUE_COUNT];
+        match addr {
+            VIRTIO_SND_DEVICE_FEATURES_SEL => self.device_features_sel = val,
+            VIRTIO_SND_DRIVER_FEATURES => {
+                let shift = 32 * (self.driver_features_sel & 1);
+                self.driver_features =
+                    (self.driver_features & !(0xffffffff << shift)) | ((val as u64) << shift);
+            }
+            VIRTIO_SND_DRIVER_FEATURES_SEL => self.driver_features_sel = val,
+            VIRTIO_SND_GUEST_PAGE_SIZE => self.page_size = val,
+            VIRTIO_SND_QUEUE_SEL => self.queue_sel = val,
+            VIRTIO_SND_QUEUE_NUM => queue.num = val.min(QUEUE_NUM_MAX),
+            VIRTIO_SND_QUEUE_ALIGN => queue.align = val,
+            VIRTIO_SND_QUEUE_PFN => queue.pfn = val,
+            VIRTIO_SND_QUEUE_NOTIFY if (val as usize) < QUEUE_COUNT => self.notified |= 1 << val,
+            VIRTIO_SND_INTERRUPT_ACK => self.interrupt_status &= !val,
+            VIRTIO_SND_STATUS => {
+                self.status = val;
+                if val == 0 {
+                    self.reset();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a control request and return the response.
+    fn control(&mut self, request: &[u8]) -> Vec<u8> {
+        let u32_at = |i: usize| {
+            request
+                .get(i..i + 4)
+                .map(|b| u32::from_le_bytes(b.try_into().unwrap()))
+        };
+        let (code, stream_id) = match (u32_at(0), u32_at(4)) {
+            (Some(code), Some(stream_id)) => (code, stream_id),
+            _ => return S_BAD_MSG.to_le_bytes().to_vec(),
+        };
+
+        let status = match code {
+            R_PCM_INFO => {
+                // The query: start_id, count and size.
+                let (count, size) = match (u32_at(8), u32_at(12)) {
+                    (Some(count), Some(size)) => (count, size as usize),
+                    _ => return S_BAD_MSG.to_le_bytes().to_vec(),
+                };
+                if stream_id != 0 || count != 1 || size < PCM_INFO_SIZE {
+                    return S_BAD_MSG.to_le_bytes().to_vec();
+                }
+                let mut response = S_OK.to_le_bytes().to_vec();
+                // hda_fn_nid and features.
+                response.extend_from_slice(&[0; 8]);
+                let formats: u64 = (1 << PCM_FMT_U8) | (1 << PCM_FMT_S16) | (1 << PCM_FMT_S32);
+                response.extend_from_slice(&formats.to_le_bytes());
+                let rates: u64 = (1 << PCM_RATES.len()) - 1;
+                response.extend_from_slice(&rates.to_le_bytes());
+                response.extend_from_slice(&[DIRECTION_OUTPUT, 1, CHANNELS_MAX]);
+                response.resize(4 + size, 0);
+                return response;
+            }
+            _ if stream_id != 0 => S_BAD_MSG,
+            R_PCM_SET_PARAMS => {
+                let params = PcmParams {
+                    channels: request.get(20).copied().unwrap_or(0),
+                    format: request.get(21).copied().unwrap_or(0),
+                    rate: request.get(22).copied().unwrap_or(u8::MAX),
+                };
+                let supported = matches!(params.format, PCM_FMT_U8 | PCM_FMT_S16 | PCM_FMT_S32)
+                    && (1..=CHANNELS_MAX).contains(&params.channels)
+                    && (params.rate as usize) < PCM_RATES.len();
+                if supported {
+                    self.params = Some(params);
+                    S_OK
+                } else {
+                    S_NOT_SUPP
+                }
+            }
+            R_PCM_PREPARE | R_PCM_RELEASE | R_PCM_STOP => {
+                self.started = false;
+                S_OK
+            }
+            R_PCM_START => match (self.params, self.wav_path.as_ref()) {
+                (None, _) => S_BAD_MSG,
+                (Some(params), Some(path)) if self.wav.is_none() => {
+                    match WavWriter::create(path, params) {
+                        Ok(wav) => {
+                            self.wav = Some(wav);
+                            self.started = true;
+                            S_OK
+                        }
+                        Err(e) => {
+                            eprintln!("virtio-snd: failed to create {}: {}", path, e);
+                            S_IO_ERR
+                        }
+                    }
+                }
+                _ => {
+                    self.started = true;
+                    S_OK
+                }
+            },
+            _ => S_NOT_SUPP,
+        };
+        status.to_le_bytes().to_vec()
+    }
+
+    /// Return true if the driver suppresses interrupts for a queue by the flag of its available
+    /// ring. The event index feature isn't offered, so `used_event` is ignored.
+    fn is_interrupt_suppressed(&self, dma: &mut Dma, q: usize) -> Result<bool, Exception> {
+        let avail = self.queues[q].avail_addr(self.page_size);
+        let flags = dma.load(avail, 16)?;
+        Ok(flags & VRING_AVAIL_F_NO_INTERRUPT != 0)
+    }
+
+    /// Process the queues in the bitmap `notified`. Return true if an interrupt should be raised.
+    fn process_queues(&mut self, dma: &mut Dma, notified: u32) -> Result<bool, Exception> {
+        let mut interrupt = false;
+        if notified & (1 << CONTROL_QUEUE) != 0 && self.process_control(dma)? {
+            interrupt |= !self.is_interrupt_suppressed(dma, CONTROL_QUEUE)?;
+        }
+        if notified & (1 << TX_QUEUE) != 0 && self.process_tx(dma)? {
+            interrupt |= !self.is_interrupt_suppressed(dma, TX_QUEUE)?;
+        }
+        Ok(interrupt)
+    }
+
+    /// Answer requests in the control queue. Return true if any is used.
+    fn process_control(&mut self, dma: &mut Dma) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = self.queues[CONTROL_QUEUE].pop_chain(dma, self.page_size)? {
+            let request = chain.read(dma)?;
+            let response = self.control(&request);
+            let len = chain.write(dma, &response)?;
+            self.queues[CONTROL_QUEUE].push_used(dma, self.page_size, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Play frames in the tx queue. Return true if any is used.
+    fn process_tx(&mut self, dma: &mut Dma) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = self.queues[TX_QUEUE].pop_chain(dma, self.page_size)? {
+            let xfer = chain.read(dma)?;
+            // Skip the header with the stream ID.
+            let frames = xfer.get(4..).unwrap_or(&[]);
+            let mut status = S_OK;
+            if let (true, Some(wav)) = (self.started, self.wav.as_mut()) {
+                if let Err(e) = wav.write(frames) {
+                    eprintln!("virtio-snd: failed to write frames: {}", e);
+                    status = S_IO_ERR;
+                }
+            }
+            // struct virtio_snd_pcm_status: status and latency_bytes.
+            let mut response = status.to_le_bytes().to_vec();
+            response.extend_from_slice(&0u32.to_le_bytes());
+            let len = chain.write(dma, &response)?;
+            self.queues[TX_QUEUE].push_used(dma, self.page_size, chain.head, len)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
@@ -0,0 +1,640 @@
+//! The virtio_vsock module contains a virtio socket device, a channel of stream connections
+//! between host programs and guest services without networking. The host side follows the hybrid
+//! vsock of Firecracker, mapping vsock ports to Unix sockets:
+//!
+//! - A host program connects to the Unix socket at `path` and writes `CONNECT <port>\n` to reach
+//!   the guest port. It receives `OK <host port>\n` once the guest accepts it.
+//! - A guest connection to the host (CID 2) port `P` is forwarded to the Unix socket at
+//!   `path_P`, where a host program listens.
+//!
+//! Connections aren't included in snapshots because they live in the host.
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
+use std::fs;
+use std::io;
+use std::io::prelude::*;
+use std::net::Shutdown;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+use crate::virtio::mmio_register_name;
+use crate::virtqueue::*;
+
+/// Always return 0x74726976.
+pub const VIRTIO_VSOCK_MAGIC: u64 = VIRTIO_VSOCK_BASE;
+/// The version. 1 is legacy.
+pub const VIRTIO_VSOCK_VERSION: u64 = VIRTIO_VSOCK_BASE + 0x004;
+/// Device type; 19 is a socket device.
+pub const VIRTIO_VSOCK_DEVICE_ID: u64 = VIRTIO_VSOCK_BASE + 0x008;
+/// Always return 0x554d4551.
+pub const VIRTIO_VSOCK_VENDOR_ID: u64 = VIRTIO_VSOCK_BASE + 0x00c;
+/// Device features.
+pub const VIRTIO_VSOCK_DEVICE_FEATURES: u64 = VIRTIO_VSOCK_BASE + 0x010;
+/// Driver features.
+pub const VIRTIO_VSOCK_DRIVER_FEATURES: u64 = VIRTIO_VSOCK_BASE + 0x020;
+/// Page size for PFN, write-only.
+pub const VIRTIO_VSOCK_GUEST_PAGE_SIZE: u64 = VIRTIO_VSOCK_BASE + 0x028;
+/// Select queue, write-only.
+pub const VIRTIO_VSOCK_QUEUE_SEL: u64 = VIRTIO_VSOCK_BASE + 0x030;
+/// Max size of current queue, read-only.
+pub const VIRTIO_VSOCK_QUEUE_NUM_MAX: u64 = VIRTIO_VSOCK_BASE + 0x034;
+/// Size of current queue, write-only.
+pub const VIRTIO_VSOCK_QUEUE_NUM: u64 = VIRTIO_VSOCK_BASE + 0x038;
+/// Used ring alignment of current queue, write-only.
+pub const VIRTIO_VSOCK_QUEUE_ALIGN: u64 = VIRTIO_VSOCK_BASE + 0x03c;
+/// Physical page number for current queue, read and write.
+pub const VIRTIO_VSOCK_QUEUE_PFN: u64 = VIRTIO_VSOCK_BASE + 0x040;
+/// Notify the queue number, write-only.
+pub const VIRTIO_VSOCK_QUEUE_NOTIFY: u64 = VIRTIO_VSOCK_BASE + 0x050;
+/// Interrupt status, read-only.
+pub const VIRTIO_VSOCK_INTERRUPT_STATUS: u64 = VIRTIO_VSOCK_BASE + 0x060;
+/// Interrupt acknowledge, write-only.
+pub const VIRTIO_VSOCK_INTERRUPT_ACK: u64 = VIRTIO_VSOCK_BASE + 0x064;
+/// Device status, read and write. Writing zero resets the device.
+pub const VIRTIO_VSOCK_STATUS: u64 = VIRTIO_VSOCK_BASE + 0x070;
+/// The guest CID in the configuration space, 64 bits.
+pub const VIRTIO_VSOCK_CONFIG_GUEST_CID: u64 = VIRTIO_VSOCK_BASE + 0x100;
+
+/// The maximum size of a queue.
+const QUEUE_NUM_MAX: u32 = 64;
+/// The queue for packets to the guest.
+const RX_QUEUE: usize = 0;
+/// The queue for packets from the guest.
+const TX_QUEUE: usize = 1;
+/// The DRIVER_OK bit in the status register.
+const STATUS_DRIVER_OK: u32 = 4;
+
+/// The well-known CID of the host.
+const HOST_CID: u64 = 2;
+/// The default CID of the guest.
+const DEFAULT_GUEST_CID: u64 = 3;
+
+/// The size of `struct virtio_vsock_hdr`.
+const HDR_SIZE: usize = 44;
+/// The stream socket type.
+const TYPE_STREAM: u16 = 1;
+// Packet operations.
+const OP_REQUEST: u16 = 1;
+const OP_RESPONSE: u16 = 2;
+const OP_RST: u16 = 3;
+const OP_SHUTDOWN: u16 = 4;
+const OP_RW: u16 = 5;
+const OP_CREDIT_UPDATE: u16 = 6;
+const OP_CREDIT_REQUEST: u16 = 7;
+/// Shutdown flags for both directions.
+const SHUTDOWN_BOTH: u32 = 3;
+
+/// The receive buffer space the host side advertises per connection.
+const BUF_ALLOC: u32 = 256 * 1024;
+/// The maximum payload of a packet to the guest.
+const MAX_PAYLOAD: usize = 4096;
+/// The first port assigned to connections from host programs.
+const FIRST_HOST_PORT: u32 = 1024;
+
+/// An event from threads serving host programs.
+enum Event {
+    /// A host program connected and asked for a guest port.
+    Connect(UnixStream, u32),
+    /// Bytes from a host program for a connection (host port, guest port).
+    Data((u32, u32), Vec<u8>),
+    /// A host program closed a connection (host port, guest port).
+    Closed((u32, u32)),
+}
+
+/// A stream connection between a host program and a guest service.
+struct Connection {
+    stream: UnixStream,
+    /// True after the handshake is done.
+    established: bool,
+    /// Bytes from the host program waiting for credit of the guest.
+    pending: VecDeque<u8>,
+    /// True if the host program has closed the connection.
+    closing: bool,
+    /// The number of bytes received from the guest and forwarded.
+    fwd_cnt: u32,
+    /// The number of bytes sent to the guest.
+    tx_cnt: u32,
+    /// The buffer space and the forwarded count of the guest.
+    peer_buf_alloc: u32,
+    peer_fwd_cnt: u32,
+}
+
+impl Connection {
+    fn new(stream: UnixStream, established: bool) -> Self {
+        Self {
+            stream,
+            established,
+            pending: VecDeque::new(),
+            closing: false,
+            fwd_cnt: 0,
+            tx_cnt: 0,
+            peer_buf_alloc: 0,
+            peer_fwd_cnt: 0,
+        }
+    }
+
+    /// Return the number of bytes the guest can receive.
+    fn credit(&self) -> u32 {
+        self.peer_buf_alloc
+            .saturating_sub(self.tx_cnt.wrapping_sub(self.peer_fwd_cnt))
+    }
+}
+
+/// The header of a vsock packet.
+struct Header {
+    src_cid: u64,
+    dst_cid: u64,
+    src_port: u32,
+    dst_port: u32,
+    len: u32,
+    op: u16,
+    buf_alloc: u32,
+    fwd_cnt: u32,
+}
+
+impl Header {
+    fn parse(bytes: &[u8]) -> Option<Header> {
+        if bytes.len() < HDR_SIZE {
+            return None;
+        }
+        let u16_at = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+        let u64_at = |i: usize| u64::from_le_bytes(bytes[i..i + 8].try_into().unwrap());
+        Some(Header {
+            src_cid: u64_at(0),
+            dst_cid: u64_at(8),
+            src_port: u32_at(16),
+            dst_port: u32_at(20),
+            len: u32_at(24),
+            op: u16_at(30),
+            buf_alloc: u32_at(36),
+            fwd_cnt: u32_at(40),
+        })
+    }
+}
+
+/// The virtio socket device.
+pub struct VirtioVsock {
+    guest_cid: u64,
+    driver_features: u32,
+    page_size: u32,
+    queue_sel: u32,
+    queues: [Virtqueue; 3],
+    interrupt_status: u32,
+    status: u32,
+    /// True if the guest notified the transmit queue.
+    notified: bool,
+    /// True if used queues raise an interrupt which hasn't been taken.
+    interrupting: bool,
+    /// The path of the Unix socket for host programs. The host side is disabled if it's None.
+    uds_path: Option<String>,
+    sender: Sender<Event>,
+    events: Receiver<Event>,
+    /// True if threads sent events which haven't been handled yet.
+    has_events: Arc<AtomicBool>,
+    /// Connections keyed by (host port, guest port).
+    connections: HashMap<(u32, u32), Connection>,
+    next_host_port: u32,
+    /// Packets waiting for buffers in the receive queue.
+    rx_packets: VecDeque<Vec<u8>>,
+}
+
+impl Device for VirtioVsock {
+    fn load(&mut self, addr: u64, width: Width) -> Result<u64, Exception> {
+        match width {
+            Width::Word => Ok(self.load32(addr)),
+            _ => Err(Exception::LoadAccessFault(addr)),
+        }
+    }
+
+    fn store(&mut self, addr: u64, width: Width, value: u64) -> Result<(), Exception> {
+        match width {
+            Width::Word => {
+                self.store32(addr, value);
+                Ok(())
+            }
+            _ => Err(Exception::StoreAMOAccessFault(addr)),
+        }
+    }
+
+    fn register_name(&self, addr: u64, _store: bool) -> Option<&'static str> {
+        match addr {
+            VIRTIO_VSOCK_CONFIG_GUEST_CID => Some("guest_cid"),
+            _ => mmio_register_name(addr - VIRTIO_VSOCK_BASE),
+        }
+    }
+
+    /// The listening socket is kept, and the connections are closed.
+    fn reset(&mut self) {
+        self.status = 0;
+        self.queues = [Virtqueue::new(); 3];
+        self.interrupt_status = 0;
+        self.notified = false;
+        self.interrupting = false;
+        self.connections.clear();
+        self.rx_packets.clear();
+    }
+}
+
+impl DmaDevice for VirtioVsock {
+    /// Exchange packets with the guest by direct dram access (DMA). An interrupt is raised if
+    /// any queue is used and the driver doesn't suppress it.
+    ///
+    /// If a queue or a buffer is outside the dram, the device sets DEVICE_NEEDS_RESET, notifies
+    /// the driver of the configuration change and does nothing until the driver resets it.
+    fn tick(&mut self, dma: &mut Dma) {
+        if self.status & STATUS_DRIVER_OK == 0
+            || self.status & VIRTIO_STATUS_DEVICE_NEEDS_RESET != 0
+        {
+            return;
+        }
+        match self.process_queues(dma) {
+            Ok(true) => self.interrupt_status |= 1,
+            Ok(false) => return,
+            Err(_) => {
+                self.status |= VIRTIO_STATUS_DEVICE_NEEDS_RESET;
+                self.interrupt_status |= 2;
+            }
+        }
+        self.interrupting = true;
+    }
+
+    fn is_interrupting(&mut self) -> bool {
+        std::mem::replace(&mut self.interrupting, false)
+    }
+}
+
+impl VirtioVsock {
+    /// Create a new `VirtioVsock` object without the host side.
+    pub fn new() -> Self {
+        let (sender, events) = mpsc::channel();
+        Self {
+            guest_cid: DEFAULT_GUEST_CID,
+            driver_features: 0,
+            page_size: 0,
+            queue_sel: 0,
+            queues: [Virtqueue::new(); 3],
+            interrupt_status: 0,
+            status: 0,
+            notified: false,
+            interrupting: false,
+            uds_path: None,
+            sender,
+            events,
+            has_events: Arc::new(AtomicBool::new(false)),
+            connections: HashMap::new(),
+            next_host_port: FIRST_HOST_PORT,
+            rx_packets: VecDeque::new(),
+        }
+    }
+
+    /// Save the registers and the queues for a snapshot. Host connections can't be saved, so
+    /// this fails while any connection is open or any packet is waiting for the guest.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        if !self.connections.is_empty() || !self.rx_packets.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't snapshot open vsock connections",
+            ));
+        }
+        write_u64(out, self.guest_cid)?;
+        for reg in [
+            self.driver_features,
+            self.page_size,
+            self.queue_sel,
+            self.interrupt_status,
+            self.status,
+        ] {
+            write_u64(out, reg as u64)?;
+        }
+        for queue in self.queues.iter() {
+            queue.save(out)?;
+        }
+        write_u64(out, self.notified as u64)?;
+        write_u64(out, self.interrupting as u64)
+    }
+
+    /// Restore the registers and the queues from a snapshot. Open connections are closed.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.guest_cid = read_u64(input)?;
+        self.driver_features = read_u64(input)? as u32;
+        self.page_size = read_u64(input)? as u32;
+        self.queue_sel = read_u64(input)? as u32;
+        self.interrupt_status = read_u64(input)? as u32;
+        self.status = read_u64(input)? as u32;
+        for queue in self.queues.iter_mut() {
+            queue.restore(input)?;
+        }
+        self.notified = read_u64(input)? != 0;
+        self.interrupting = read_u64(input)? != 0;
+        self.connections.clear();
+        self.rx_packets.clear();
+        Ok(())
+    }
+
+    /// Listen for host programs on a Unix socket at `path`. A stale socket file is removed.
+    pub fn listen(&mut self, path: &str) -> io::Result<()> {
+        let _ = fs::remove_file(path);
+        let listener = UnixListener::bind(path)?;
+        self.uds_path = Some(path.to_string());
+        let sender = self.sender.clone();
+        let has_events = self.has_events.clone();
+        thread::spawn(move || {
+            for mut stream in listener.incoming().flatten() {
+                // Read the `CONNECT <port>` line byte by byte not to consume data after it.
+                let mut line = Vec::new();
+                let mut byte = [0; 1];
+                while matches!(stream.read(&mut byte), Ok(1)) && byte[0] != b'\n' {
+                    line.push(byte[0]);
+                }
+                let line = String::from_utf8_lossy(&line);
+                match line
+                    .trim()
+                    .strip_prefix("CONNECT ")
+                    .map(|p| p.parse::<u32>())
+                {
+                    Some(Ok(port)) => {
+                        let _ = sender.send(Event::Connect(stream, port));
+                        has_events.store(true, Ordering::Release);
+                    }
+                    _ => {
+                        let _ = stream.write_all(b"ERR invalid command\n");
+                    }
+                }
+            }
+        });
+        Ok(())
+    }
+
+    fn load32(&self, addr: u64) -> u64 {
+        let queue = &self.queues[self.queue_sel as usize % 3];
+        match addr {
+            VIRTIO_VSOCK_MAGIC => 0x74726976,
+            VIRTIO_VSOCK_VERSION => 0x1,
+            VIRTIO_VSOCK_DEVICE_ID => 19,
+            VIRTIO_VSOCK_VENDOR_ID => 0x554d4551,
+            VIRTIO_VSOCK_DEVICE_FEATURES => VIRTIO_RING_F_INDIRECT_DESC,
+            VIRTIO_VSOCK_DRIVER_FEATURES => self.driver_features as u64,
+            VIRTIO_VSOCK_QUEUE_NUM_MAX => QUEUE_NUM_MAX as u64,
+            VIRTIO_VSOCK_QUEUE_PFN => queue.pfn as u64,
+            VIRTIO_VSOCK_INTERRUPT_STATUS => self.interrupt_status as u64,
+            VIRTIO_VSOCK_STATUS => self.status as u64,
+            VIRTIO_VSOCK_CONFIG_GUEST_CID => self.guest_cid & 0xffffffff,
+            a if a == VIRTIO_VSOCK_CONFIG_GUEST_CID + 4 => self.guest_cid >> 32,
+            _ => 0,
+        }
+    }
+
+    fn store32(&mut self, addr: u64, value: u64) {
+        let val = value as u32;
+        let queue = &mut self.queues[self.queue_sel as usize % 3];
+        match addr {
+            VIRTIO_VSOCK_DRIVER_FEATURES => self.driver_features = val,
+            VIRTIO_VSOCK_GUEST_PAGE_SIZE => self.page_size = val,
+            VIRTIO_VSOCK_QUEUE_SEL => self.queue_sel = val,
+            VIRTIO_VSOCK_QUEUE_NUM => queue.num = val.min(QUEUE_NUM_MAX),
+            VIRTIO_VSOCK_QUEUE_ALIGN => queue.align = val,
+            VIRTIO_VSOCK_QUEUE_PFN => queue.pfn = val,
+            VIRTIO_VSOCK_QUEUE_NOTIFY if val as usize == TX_QUEUE => self.notified = true,
+            VIRTIO_VSOCK_INTERRUPT_ACK => self.interrupt_status &= !val,
+            VIRTIO_VSOCK_STATUS => {
+                self.status = val;
+                if val == 0 {
+                    self.reset();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Build a packet from the host to the guest.
+    fn packet(&self, key: (u32, u32), op: u16, flags: u32, payload: &[u8]) -> Vec<u8> {
+        let fwd_cnt = self.connections.get(&key).map_or(0, |c| c.fwd_cnt);
+        let mut packet = Vec::with_capacity(HDR_SIZE + payload.len());
+        packet.extend_from_slice(&HOST_CID.to_le_bytes());
+        packet.extend_from_slice(&self.guest_cid.to_le_bytes());
+        packet.extend_from_slice(&key.0.to_le_bytes());
+        packet.extend_from_slice(&key.1.to_le_bytes());
+        packet.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        packet.extend_from_slice(&TYPE_STREAM.to_le_bytes());
+        packet.extend_from_slice(&op.to_le_bytes());
+        packet.extend_from_slice(&flags.to_le_bytes());
+        packet.extend_from_slice(&BUF_ALLOC.to_le_bytes());
+        packet.extend_from_slice(&fwd_cnt.to_le_bytes());
+        packet.extend_from_slice(payload);
+        packet
+    }
+
+    fn send(&mut self, key: (u32, u32), op: u16, flags: u32, payload: &[u8]) {
+        let packet = self.packet(key, op, flags, payload);
+        self.rx_packets.push_back(packet);
+    }
+
+    /// Start a thread forwarding bytes from a host program to the guest.
+    fn spawn_reader(&self, key: (u32, u32), stream: &UnixStream) {
+        let mut stream = match stream.try_clone() {
+            Ok(stream) => stream,
+            Err(_) => return,
+        };
+        let sender = self.sender.clone();
+        let has_events = self.has_events.clone();
+        thread::spawn(move || {
+            let mut buf = [0; MAX_PAYLOAD];
+            loop {
+                let event = match stream.read(&mut buf) {
+                    Ok(0) | Err(_) => Event::Closed(key),
+                    Ok(n) => Event::Data(key, buf[..n].to_vec()),
+                };
+                let closed = matches!(event, Event::Closed(_));
+                if sender.send(event).is_err() || closed {
+                    has_events.store(true, Ordering::Release);
+                    break;
+                }
+                has_events.store(true, Ordering::Release);
+            }
+        });
+    }
+
+    /// Send bytes waiting for credit of the guest, and the shutdown after all of them.
+    fn flush(&mut self, key: (u32, u32)) {
+        loop {
+            let conn = match self.connections.get_mut(&key) {
+                Some(conn) if conn.established => conn,
+                _ => return,
+            };
+            let len = conn
+                .pending
+                .len()
+                .min(conn.credit() as usize)
+                .min(MAX_PAYLOAD);
+            if len == 0 {
+                if conn.pending.is_empty() && conn.closing {
+                    conn.closing = false;
+                    self.send(key, OP_SHUTDOWN, SHUTDOWN_BOTH, &[]);
+                }
+                return;
+            }
+            let payload: Vec<u8> = conn.pending.drain(..len).collect();
+            conn.tx_cnt = conn.tx_cnt.wrapping_add(len as u32);
+            self.send(key, OP_RW, 0, &payload);
+        }
+    }
+
+    /// Handle events from threads serving host programs.
+    fn handle_events(&mut self) {
+        if !self.has_events.swap(false, Ordering::Acquire) {
+            return;
+        }
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::Connect(stream, guest_port) => {
+                    let key = (self.next_host_port, guest_port);
+                    self.next_host_port = self.next_host_port.wrapping_add(1).max(FIRST_HOST_PORT);
+                    self.connections.insert(key, Connection::new(stream, false));
+                    self.send(key, OP_REQUEST, 0, &[]);
+                }
+                Event::Data(key, bytes) => {
+                    if let Some(conn) = self.connections.get_mut(&key) {
+                        conn.pending.extend(bytes);
+                        self.flush(key);
+                    }
+                }
+                Event::Closed(key) => {
+                    if let Some(conn) = self.connections.get_mut(&key) {
+                        conn.closing = true;
+                        self.flush(key);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handle a packet from the guest.
+    fn handle_packet(&mut self, header: Header, payload: &[u8]) {
+        if header.dst_cid != HOST_CID || header.src_cid != self.guest_cid {
+            return;
+        }
+        let key = (header.dst_port, header.src_port);
+        if let Some(conn) = self.connections.get_mut(&key) {
+            conn.peer_buf_alloc = header.buf_alloc;
+            conn.peer_fwd_cnt = header.fwd_cnt;
+        }
+
+        match header.op {
+            OP_REQUEST => {
+                let stream = self
+                    .uds_path
+                    .as_ref()
+                    .and_then(|path| UnixStream::connect(format!("{}_{}", path, key.0)).ok());
+                match stream {
+                    Some(stream) => {
+                        self.spawn_reader(key, &stream);
+                        let mut conn = Connection::new(stream, true);
+                        conn.peer_buf_alloc = header.buf_alloc;
+                        conn.peer_fwd_cnt = header.fwd_cnt;
+                        self.connections.insert(key, conn);
+                        self.send(key, OP_RESPONSE, 0, &[]);
+                    }
+                    None => self.send(key, OP_RST, 0, &[]),
+                }
+            }
+            OP_RESPONSE => match self.connections.get_mut(&key) {
+                Some(conn) if !conn.established => {
+                    conn.established = true;
+                    let _ = writeln!(conn.stream, "OK {}", key.0);
+                    let stream = conn.stream.try_clone();
+                    if let Ok(stream) = stream {
+                        self.spawn_reader(key, &stream);
+                    }
+                    self.flush(key);
+                }
+                _ => self.send(key, OP_RST, 0, &[]),
+            },
+            OP_RW => match self.connections.get_mut(&key) {
+                Some(conn) => {
+                    let payload = &payload[..payload.len().min(header.len as usize)];
+                    let _ = conn.stream.write_all(payload);
+                    conn.fwd_cnt = conn.fwd_cnt.wrapping_add(payload.len() as u32);
+                }
+                None => self.send(key, OP_RST, 0, &[]),
+            },
+            OP_CREDIT_UPDATE => self.flush(key),
+            OP_CREDIT_REQUEST => self.send(key, OP_CREDIT_UPDATE, 0, &[]),
+            OP_SHUTDOWN => {
+                if let Some(conn) = self.connections.remove(&key) {
+                    let _ = conn.stream.shutdown(Shutdown::Both);
+                }
+                self.send(key, OP_RST, 0, &[]);
+            }
+            OP_RST => {
+                if let Some(conn) = self.connections.remove(&key) {
+                    let _ = conn.stream.shutdown(Shutdown::Both);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Return true if the driver suppresses interrupts for a queue by the flag of its available
+    /// ring. The event index feature isn't offered, so `used_event` is ignored.
+    fn is_interrupt_suppressed(&self, dma: &mut Dma, q: usize) -> Result<bool, Exception> {
+        let avail = self.queues[q].avail_addr(self.page_size);
+        let flags = dma.load(avail, 16)?;
+        Ok(flags & VRING_AVAIL_F_NO_INTERRUPT != 0)
+    }
+
+    /// Exchange packets in both queues. Return true if an interrupt should be raised.
+    fn process_queues(&mut self, dma: &mut Dma) -> Result<bool, Exception> {
+        let mut interrupt = false;
+        if self.notified {
+            self.notified = false;
+            if self.process_tx(dma)? {
+                interrupt |= !self.is_interrupt_suppressed(dma, TX_QUEUE)?;
+            }
+        }
+        self.handle_events();
+        if self.process_rx(dma)? {
+            interrupt |= !self.is_interrupt_suppressed(dma, RX_QUEUE)?;
+        }
+        Ok(interrupt)
+    }
+
+    /// Take packets from the guest in the transmit queue. Return true if any is used.
+    fn process_tx(&mut self, dma: &mut Dma) -> Result<bool, Exception> {
+        let mut used = false;
+        while let Some(chain) = self.queues[TX_QUEUE].pop_chain(dma, self.page_size)? {
+            let bytes = chain.read(dma)?;
+            if let Some(header) = Header::parse(&bytes) {
+                self.handle_packet(header, &bytes[HDR_SIZE..]);
+            }
+            self.queues[TX_QUEUE].push_used(dma, self.page_size, chain.head, 0)?;
+            used = true;
+        }
+        Ok(used)
+    }
+
+    /// Put packets to the guest in the receive queue. Return true if any is used.
+    fn process_rx(&mut self, dma: &mut Dma) -> Result<bool, Exception> {
+        let mut used = false;
+        while !self.rx_packets.is_empty() {
+            let chain = match self.queues[RX_QUEUE].pop_chain(dma, self.page_size)? {
+                Some(chain) => chain,
+                None => break,
+            };
+            let packet = self.rx_packets.pop_front().expect("failed to get a packet");
+            let written = chain.write(dma, &packet)?;
+            self.queues[RX_QUEUE].push_used(dma, self.page_size, chain.head, written)?;
+            used = true;
+        }
+        Ok(used)
+    }
+}
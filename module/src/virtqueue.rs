@@ -0,0 +1,314 @@
+//! The virtqueue module contains the split virtqueue in the legacy layout, which the virtio
+//! devices share to take descriptor chains from the available ring and return them to the used
+//! ring. A device has any number of queues, selected by `QueueSel`.
+//!
+//! The legacy layout of a queue is the descriptor table, the available ring and the used ring,
+//! which starts at the next multiple of the alignment:
+//!
+//! ```text
+//! desc = pfn * page size -- num * struct VRingDesc
+//! avail = desc + num * 16 -- flags, idx, num * ring[], used_event
+//! used = align(avail + 6 + num * 2) -- flags, idx, num * struct VRingUsedElem, avail_event
+//! ```
+//!
+//! The virtio spec:
+//! https://docs.oasis-open.org/virtio/virtio/v1.1/virtio-v1.1.pdf
+
+use std::io;
+use std::io::prelude::*;
+
+use crate::bus::*;
+use crate::snapshot::*;
+use crate::trap::*;
+
+/// The size of a descriptor.
+const VRING_DESC_SIZE: u64 = 16;
+/// The flag of a descriptor which continues via the `next` field.
+pub const VRING_DESC_F_NEXT: u64 = 1;
+/// The flag of a descriptor which the device writes.
+pub const VRING_DESC_F_WRITE: u64 = 2;
+/// The flag of a descriptor whose buffer is a table of descriptors.
+pub const VRING_DESC_F_INDIRECT: u64 = 4;
+/// The flag of the available ring which suppresses interrupts.
+pub const VRING_AVAIL_F_NO_INTERRUPT: u64 = 1;
+
+/// The DEVICE_NEEDS_RESET bit in the status register, which a device sets when a queue points
+/// outside the dram. The device stops processing its queues until the driver resets it.
+pub const VIRTIO_STATUS_DEVICE_NEEDS_RESET: u32 = 64;
+
+/// The feature bit of indirect descriptors.
+pub const VIRTIO_RING_F_INDIRECT_DESC: u64 = 1 << 28;
+/// The feature bit of the event indexes, `used_event` after the available ring and
+/// `avail_event` after the used ring.
+pub const VIRTIO_RING_F_EVENT_IDX: u64 = 1 << 29;
+
+/// A descriptor chain taken from the available ring.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain {
+    /// The index of the head descriptor, which is returned to the used ring.
+    pub head: u64,
+    /// The descriptors (address, length, flags) with indirect tables expanded.
+    pub descs: Vec<(u64, u64, u64)>,
+}
+
+impl Chain {
+    /// Return the bytes in the device-readable descriptors.
+    pub fn read(&self, dma: &mut Dma) -> Result<Vec<u8>, Exception> {
+        let mut bytes = Vec::new();
+        for &(addr, len, flags) in self.descs.iter() {
+            if flags & VRING_DESC_F_WRITE != 0 {
+                continue;
+            }
+            for i in 0..len {
+                let byte = dma.load(addr.wrapping_add(i), 8)?;
+                bytes.push(byte as u8);
+            }
+        }
+        Ok(bytes)
+    }
+
+    /// Write `data` to the device-writable descriptors and return the number of bytes written.
+    pub fn write(&self, dma: &mut Dma, data: &[u8]) -> Result<u64, Exception> {
+        let mut written = 0;
+        for &(addr, len, flags) in self.descs.iter() {
+            if flags & VRING_DESC_F_WRITE == 0 {
+                continue;
+            }
+            let n = (len as usize).min(data.len() - written);
+            for (i, byte) in data[written..written + n].iter().enumerate() {
+                dma.store(addr.wrapping_add(i as u64), 8, *byte as u64)?;
+            }
+            written += n;
+        }
+        Ok(written as u64)
+    }
+}
+
+/// A virtqueue in the legacy layout. The device keeps the guest page size, which is common to
+/// all queues, and passes it to the methods.
+#[derive(Debug, Clone, Copy)]
+pub struct Virtqueue {
+    /// The size of the queue.
+    pub num: u32,
+    pub align: u32,
+    /// The guest page number of the descriptor table.
+    pub pfn: u32,
+    /// The next index of the available ring to process.
+    pub last_avail: u16,
+    /// The next index of the used ring to write.
+    pub used_idx: u16,
+}
+
+impl Default for Virtqueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Virtqueue {
+    /// Create a new `Virtqueue` object which the driver hasn't set up.
+    pub fn new() -> Self {
+        Self {
+            num: 0,
+            align: 4096,
+            pfn: 0,
+            last_avail: 0,
+            used_idx: 0,
+        }
+    }
+
+    /// Save the queue for a snapshot.
+    pub fn save(&self, out: &mut dyn Write) -> io::Result<()> {
+        for value in [
+            self.num,
+            self.align,
+            self.pfn,
+            self.last_avail as u32,
+            self.used_idx as u32,
+        ] {
+            write_u64(out, value as u64)?;
+        }
+        Ok(())
+    }
+
+    /// Restore the queue from a snapshot.
+    pub fn restore(&mut self, input: &mut dyn Read) -> io::Result<()> {
+        self.num = read_u64(input)? as u32;
+        self.align = read_u64(input)? as u32;
+        self.pfn = read_u64(input)? as u32;
+        self.last_avail = read_u64(input)? as u16;
+        self.used_idx = read_u64(input)? as u16;
+        Ok(())
+    }
+
+    /// Return true if the driver has set up the queue.
+    pub fn is_ready(&self) -> bool {
+        self.num != 0 && self.pfn != 0
+    }
+
+    pub fn desc_addr(&self, page_size: u32) -> u64 {
+        self.pfn as u64 * page_size as u64
+    }
+
+    pub fn avail_addr(&self, page_size: u32) -> u64 {
+        self.desc_addr(page_size) + VRING_DESC_SIZE * self.num as u64
+    }
+
+    pub fn used_addr(&self, page_size: u32) -> u64 {
+        let align = self.align.max(1) as u64;
+        let end = self.avail_addr(page_size) + 6 + 2 * self.num as u64;
+        end.div_ceil(align) * align
+    }
+
+    /// Take the next available descriptor chain. An error is returned if the rings or the
+    /// descriptors are outside the dram.
+    pub fn pop_chain(&mut self, dma: &mut Dma, page_size: u32) -> Result<Option<Chain>, Exception> {
+        let head = match self.pop_avail(dma, page_size)? {
+            Some(head) => head,
+            None => return Ok(None),
+        };
+        let descs = self.chain(dma, page_size, head)?;
+        Ok(Some(Chain { head, descs }))
+    }
+
+    /// Return the head of the next available descriptor chain.
+    fn pop_avail(&mut self, dma: &mut Dma, page_size: u32) -> Result<Option<u64>, Exception> {
+        if !self.is_ready() {
+            return Ok(None);
+        }
+        // avail[0] is flags.
+        // avail[1] tells the device how far to look in avail[2...].
+        let avail = self.avail_addr(page_size);
+        let idx = dma.load(avail.wrapping_add(2), 16)?;
+        if idx as u16 == self.last_avail {
+            return Ok(None);
+        }
+        // avail[2...] are desc[] indices the device should process.
+        let slot = self.last_avail as u64 % self.num as u64;
+        let head = dma.load(avail.wrapping_add(4 + 2 * slot), 16)?;
+        self.last_avail = self.last_avail.wrapping_add(1);
+        Ok(Some(head))
+    }
+
+    /// Return the descriptors (address, length, flags) of the chain starting at `head`. An
+    /// indirect descriptor is replaced with the chain in its table.
+    fn chain(
+        &self,
+        dma: &mut Dma,
+        page_size: u32,
+        head: u64,
+    ) -> Result<Vec<(u64, u64, u64)>, Exception> {
+        let mut descs = Vec::new();
+        if !self.is_ready() {
+            return Ok(descs);
+        }
+        let num = self.num as u64;
+        let table = self.desc_addr(page_size);
+        // Bound the walk by the queue size in case of a loop.
+        let mut index = head;
+        for _ in 0..num {
+            let (addr, len, flags, next) = read_desc(dma, table, index % num)?;
+            if flags & VRING_DESC_F_INDIRECT != 0 {
+                // "The device MUST ignore the write-only flag in the descriptor that refers to
+                // an indirect table", and the indirect table can't be chained further.
+                descs.extend(indirect_chain(dma, addr, len / VRING_DESC_SIZE)?);
+            } else {
+                descs.push((addr, len, flags));
+            }
+            if flags & VRING_DESC_F_NEXT == 0 {
+                break;
+            }
+            index = next;
+        }
+        Ok(descs)
+    }
+
+    /// Put the chain starting at `head` to the used ring with the number of bytes written to it.
+    pub fn push_used(
+        &mut self,
+        dma: &mut Dma,
+        page_size: u32,
+        head: u64,
+        len: u64,
+    ) -> Result<(), Exception> {
+        // struct UsedArea {
+        //   uint16 flags;
+        //   uint16 id;
+        //   struct VRingUsedElem elems[NUM];
+        // };
+        // struct VRingUsedElem {
+        //   uint32 id;
+        //   uint32 len;
+        // };
+        let used = self.used_addr(page_size);
+        let slot = self.used_idx as u64 % self.num as u64;
+        let elem = used.wrapping_add(4 + 8 * slot);
+        dma.store(elem, 32, head)?;
+        dma.store(elem.wrapping_add(4), 32, len)?;
+        self.used_idx = self.used_idx.wrapping_add(1);
+        dma.store(used.wrapping_add(2), 16, self.used_idx as u64)
+    }
+
+    /// Return true if the driver wants an interrupt for the used ring entries after
+    /// `old_used_idx`. Without the event index feature, the driver suppresses interrupts by the
+    /// flag of the available ring.
+    pub fn needs_interrupt(
+        &self,
+        dma: &mut Dma,
+        page_size: u32,
+        old_used_idx: u16,
+        event_idx: bool,
+    ) -> Result<bool, Exception> {
+        if self.used_idx == old_used_idx {
+            return Ok(false);
+        }
+        let num = self.num as u64;
+        let avail = self.avail_addr(page_size);
+        if !event_idx {
+            let flags = dma.load(avail, 16)?;
+            return Ok(flags & VRING_AVAIL_F_NO_INTERRUPT == 0);
+        }
+        // Tell the driver the next avail index to notify at, and interrupt only if the used index
+        // has passed `used_event` which the driver asked for.
+        let used = self.used_addr(page_size);
+        dma.store(used.wrapping_add(4 + 8 * num), 16, self.last_avail as u64)?;
+        let used_event = dma.load(avail.wrapping_add(4 + 2 * num), 16)? as u16;
+        // vring_need_event() in the virtio spec.
+        Ok(self.used_idx.wrapping_sub(used_event).wrapping_sub(1)
+            < self.used_idx.wrapping_sub(old_used_idx))
+    }
+}
+
+/// Read the descriptor at `index` of a table: address, length, flags and next.
+fn read_desc(dma: &mut Dma, table: u64, index: u64) -> Result<(u64, u64, u64, u64), Exception> {
+    // struct VRingDesc {
+    //   uint64 addr;
+    //   uint32 len;
+    //   uint16 flags;
+    //   uint16 next
+    // };
+    let addr = table.wrapping_add(VRING_DESC_SIZE * index);
+    let mut load = |offset: u64, size: u64| dma.load(addr.wrapping_add(offset), size);
+    Ok((load(0, 64)?, load(8, 32)?, load(12, 16)?, load(14, 16)?))
+}
+
+/// Return the descriptors of the chain in an indirect table of `count` descriptors, which starts
+/// at its first descriptor.
+fn indirect_chain(
+    dma: &mut Dma,
+    table: u64,
+    count: u64,
+) -> Result<Vec<(u64, u64, u64)>, Exception> {
+    let mut descs = Vec::new();
+    let mut index = 0;
+    for _ in 0..count {
+        let (addr, len, flags, next) = read_desc(dma, table, index)?;
+        // Nested indirect tables aren't allowed.
+        descs.push((addr, len, flags & !VRING_DESC_F_INDIRECT));
+        if flags & VRING_DESC_F_NEXT == 0 || next >= count {
+            break;
+        }
+        index = next;
+    }
+    Ok(descs)
+}
@@ -0,0 +1,119 @@
+//! Tests of the atomic memory operations on words and doublewords, and of the read-modify-write
+//! path of the bus.
+
+mod common;
+
+use common::run;
+use rvemu::bus::{Amo, Bus, Width, DRAM_BASE, PLIC_BASE};
+use rvemu::trap::Exception;
+
+/// auipc a0,1: the operand is in the page after the program.
+const AUIPC_A0: u32 = 0x00001517;
+/// addi a1,zero,-1
+const LI_A1_M1: u32 = 0xfff00593;
+/// addi a2,zero,1
+const LI_A2_1: u32 = 0x00100613;
+/// sw a1,0(a0)
+const SW_A1: u32 = 0x00b52023;
+/// sd a1,0(a0)
+const SD_A1: u32 = 0x00b53023;
+
+/// Return an AMO with `funct5` and `funct3` (2 for words, 3 for doublewords) at the address in
+/// a0.
+fn amo(funct5: u32, funct3: u32, rd: u32, rs2: u32) -> u32 {
+    (funct5 << 27) | (rs2 << 20) | (10 << 15) | (funct3 << 12) | (rd << 7) | 0x2f
+}
+
+#[test]
+fn word_results_are_sign_extended() {
+    // The word is 0xffffffff. amominu.w t0,a2,(a0); amomax.w t1,a1,(a0)
+    let mut cpu = run(&[
+        AUIPC_A0,
+        LI_A1_M1,
+        LI_A2_1,
+        SW_A1,
+        amo(0x18, 2, 5, 12),
+        amo(0x14, 2, 6, 11),
+    ]);
+    assert_eq!(cpu.regs[5], u64::MAX);
+    assert_eq!(cpu.regs[6], 1);
+    assert_eq!(cpu.load(cpu.regs[10], 64).unwrap(), 1);
+}
+
+#[test]
+fn doubleword_logic_and_signed_compare() {
+    // The doubleword is -1. amoand.d t0,a2,(a0); amoxor.d t1,a1,(a0); amomin.d t2,a1,(a0)
+    let mut cpu = run(&[
+        AUIPC_A0,
+        LI_A1_M1,
+        LI_A2_1,
+        SD_A1,
+        amo(0x0c, 3, 5, 12),
+        amo(0x04, 3, 6, 11),
+        amo(0x10, 3, 7, 11),
+    ]);
+    assert_eq!(cpu.regs[5], u64::MAX);
+    assert_eq!(cpu.regs[6], 1);
+    assert_eq!(cpu.regs[7], !1);
+    assert_eq!(cpu.load(cpu.regs[10], 64).unwrap(), !1);
+}
+
+#[test]
+fn bus_amo_is_one_load_and_store() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.set_mmio_trace("plic").unwrap();
+    // amoor.w on the enable bits of the PLIC.
+    bus.store(PLIC_BASE + 0x2080, 32, 0x2).unwrap();
+    assert_eq!(
+        bus.amo(PLIC_BASE + 0x2080, Width::Word, Amo::Or(0x400))
+            .unwrap(),
+        0x2
+    );
+    let lines: Vec<String> = bus
+        .take_mmio_log()
+        .iter()
+        .map(|access| access.to_string())
+        .collect();
+    assert_eq!(
+        lines,
+        [
+            "plic.senable store32 0x2",
+            "plic.senable load32 0x2",
+            "plic.senable store32 0x402",
+        ]
+    );
+
+    bus.store(DRAM_BASE, 64, u64::MAX).unwrap();
+    assert_eq!(bus.amo(DRAM_BASE, Width::Byte, Amo::Minu(1)).unwrap(), 0xff);
+    assert_eq!(bus.load(DRAM_BASE, 64).unwrap(), 0xffff_ffff_ffff_ff01);
+}
+
+#[test]
+fn bus_amo_to_rom_faults() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.store(DRAM_BASE, 32, 7).unwrap();
+    bus.add_rom(DRAM_BASE, DRAM_BASE + 0x1000);
+    assert!(matches!(
+        bus.amo(DRAM_BASE, Width::Word, Amo::Add(1)),
+        Err(Exception::StoreAMOAccessFault(_))
+    ));
+    assert_eq!(bus.load(DRAM_BASE, 32).unwrap(), 7);
+}
+
+#[test]
+fn bus_stores_straddling_rom_fault() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    let rom = DRAM_BASE + 0x1000;
+    bus.add_rom(rom, rom + 0x1000);
+    // The last bytes of a doubleword before the region are read-only.
+    assert!(matches!(
+        bus.store(rom - 4, 64, u64::MAX),
+        Err(Exception::StoreAMOAccessFault(_))
+    ));
+    assert!(matches!(
+        bus.amo(rom - 2, Width::Word, Amo::Swap(u64::MAX)),
+        Err(Exception::StoreAMOAccessFault(_))
+    ));
+    assert_eq!(bus.load(rom - 8, 64).unwrap(), 0);
+    assert!(bus.store(rom - 8, 64, 1).is_ok());
+}
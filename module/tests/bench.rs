@@ -0,0 +1,70 @@
+//! Tests of the checks of benchmark reports and of a benchmark run.
+
+use rvemu::bench::{check_report, run, Benchmark};
+use rvemu::loader::Image;
+
+/// The end of a Dhrystone 2.1 report, with a wrong `Int_Glob` if `int_glob` isn't 5.
+fn dhrystone_report(int_glob: u32) -> String {
+    format!(
+        "Dhrystone Benchmark, Version 2.1 (Language: C)
+
+Execution starts, 500000 runs through Dhrystone
+Execution ends
+
+Final values of the variables used in the benchmark:
+
+Int_Glob:            {}
+        should be:   5
+Arr_2_Glob[8][7]:    500010
+        should be:   Number_Of_Runs + 10
+Ptr_Glob->
+  Ptr_Comp:          -2147467200
+        should be:   (implementation-dependent)
+Str_2_Loc:           DHRYSTONE PROGRAM, 2'ND STRING
+        should be:   DHRYSTONE PROGRAM, 2'ND STRING
+
+Microseconds for one run through Dhrystone:        2.0
+Dhrystones per Second:                        500000.0
+",
+        int_glob
+    )
+}
+
+#[test]
+fn dhrystone_values_are_checked() {
+    let (benchmark, errors) = check_report(&dhrystone_report(5));
+    assert_eq!(benchmark, Benchmark::Dhrystone(Some(500000.0)));
+    assert!(errors.is_empty(), "{:?}", errors);
+
+    let (_, errors) = check_report(&dhrystone_report(4));
+    assert_eq!(errors, vec!["Int_Glob is \"4\", should be \"5\""]);
+}
+
+#[test]
+fn coremark_validation_is_checked() {
+    let report = "2K performance run parameters for coremark.
+CoreMark Size    : 666
+Iterations/Sec   : 1234.5
+Correct operation validated. See README.md for run and reporting rules.
+CoreMark 1.0 : 1234.5 / GCC10 -O2 / STATIC
+";
+    let (benchmark, errors) = check_report(report);
+    assert_eq!(benchmark, Benchmark::CoreMark(Some(1234.5)));
+    assert!(errors.is_empty());
+
+    let (_, errors) = check_report("CoreMark Size    : 666\nErrors detected\n");
+    assert_eq!(errors.len(), 1);
+}
+
+#[test]
+fn image_must_exit_with_zero() {
+    let image = Image::load("tests/fixtures/arith.bin", None).unwrap();
+    let result = run(&image, 100_000).unwrap();
+    assert_eq!(result.benchmark, Benchmark::Unknown);
+    assert_eq!(result.exit_code, Some(0));
+    assert!(result.passed());
+
+    let result = run(&image, 10).unwrap();
+    assert_eq!(result.instructions, 10);
+    assert!(!result.passed());
+}
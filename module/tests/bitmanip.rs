@@ -0,0 +1,92 @@
+//! Tests of the bit-manipulation instructions of Zba, Zbb and Zbs.
+
+mod common;
+
+use common::{new_cpu, step};
+use rvemu::trap::Exception;
+
+/// Return an instruction writing a2 from a0 and a1, or from a0 and the immediate `rs2` field.
+fn inst(funct7: u32, rs2: u32, funct3: u32, opcode: u32) -> u32 {
+    (funct7 << 25) | (rs2 << 20) | (10 << 15) | (funct3 << 12) | (12 << 7) | opcode
+}
+
+/// Return an R-type instruction `op a2,a0,a1`.
+fn r(funct7: u32, funct3: u32, opcode: u32) -> u32 {
+    inst(funct7, 11, funct3, opcode)
+}
+
+/// Execute `inst` with a0 and a1 and return a2.
+fn exec(inst: u32, a0: u64, a1: u64) -> u64 {
+    let mut cpu = new_cpu(&[inst]);
+    cpu.regs[10] = a0;
+    cpu.regs[11] = a1;
+    step(&mut cpu).unwrap();
+    cpu.regs[12]
+}
+
+#[test]
+fn zba_shifted_adds() {
+    assert_eq!(exec(r(0x10, 2, 0x33), 3, 100), 106);
+    assert_eq!(exec(r(0x10, 6, 0x33), u64::MAX, 100), 92);
+    // add.uw and sh2add.uw take the low word of rs1 unsigned.
+    assert_eq!(exec(r(0x04, 0, 0x3b), 0xffff_ffff_0000_0001, 1), 2);
+    assert_eq!(exec(r(0x10, 4, 0x3b), u64::MAX, 0), 0x3_ffff_fffc);
+    // slli.uw a2,a0,32
+    assert_eq!(
+        exec(inst(0x04, 0, 1, 0x1b) | 32 << 20, 0xdead_beef_8000_0001, 0),
+        0x8000_0001_0000_0000
+    );
+}
+
+#[test]
+fn zbb_counts_and_extensions() {
+    // clz, ctz and cpop
+    assert_eq!(exec(inst(0x30, 0, 1, 0x13), 0x0000_ff00, 0), 48);
+    assert_eq!(exec(inst(0x30, 1, 1, 0x13), 0x0000_ff00, 0), 8);
+    assert_eq!(exec(inst(0x30, 2, 1, 0x13), 0x0000_ff00, 0), 8);
+    assert_eq!(exec(inst(0x30, 0, 1, 0x13), 0, 0), 64);
+    // clzw, ctzw and cpopw ignore the upper word.
+    assert_eq!(exec(inst(0x30, 0, 1, 0x1b), 0xffff_0000_0001_0000, 0), 15);
+    assert_eq!(exec(inst(0x30, 1, 1, 0x1b), 0xffff_ffff_0000_0000, 0), 32);
+    assert_eq!(exec(inst(0x30, 2, 1, 0x1b), 0xffff_ffff_0000_0003, 0), 2);
+    // sext.b, sext.h and zext.h
+    assert_eq!(exec(inst(0x30, 4, 1, 0x13), 0x80, 0), 0xffff_ffff_ffff_ff80);
+    assert_eq!(exec(inst(0x30, 5, 1, 0x13), 0x1_7fff, 0), 0x7fff);
+    assert_eq!(exec(inst(0x04, 0, 4, 0x3b), 0xffff_ffff, 0), 0xffff);
+    // orc.b
+    assert_eq!(
+        exec(inst(0x14, 7, 5, 0x13), 0x0001_0000_8000_0100, 0),
+        0x00ff_0000_ff00_ff00
+    );
+}
+
+#[test]
+fn zbb_min_max() {
+    assert_eq!(exec(r(0x05, 4, 0x33), u64::MAX, 1), u64::MAX);
+    assert_eq!(exec(r(0x05, 5, 0x33), u64::MAX, 1), 1);
+    assert_eq!(exec(r(0x05, 6, 0x33), u64::MAX, 1), 1);
+    assert_eq!(exec(r(0x05, 7, 0x33), u64::MAX, 1), u64::MAX);
+}
+
+#[test]
+fn zbs_single_bits() {
+    // The bit index is taken modulo 64.
+    assert_eq!(exec(r(0x14, 1, 0x33), 0, 65), 2);
+    assert_eq!(exec(r(0x24, 1, 0x33), u64::MAX, 63), u64::MAX >> 1);
+    assert_eq!(exec(r(0x34, 1, 0x33), 1, 0), 0);
+    assert_eq!(exec(r(0x24, 5, 0x33), 1 << 40, 40), 1);
+    // bseti a2,a0,63; bclri a2,a0,0; binvi a2,a0,32; bexti a2,a0,63
+    assert_eq!(exec(inst(0x14, 0, 1, 0x13) | 63 << 20, 0, 0), 1 << 63);
+    assert_eq!(exec(inst(0x24, 0, 1, 0x13), 3, 0), 2);
+    assert_eq!(exec(inst(0x34, 0, 1, 0x13) | 32 << 20, 0, 0), 1 << 32);
+    assert_eq!(exec(inst(0x24, 0, 5, 0x13) | 63 << 20, 1 << 63, 0), 1);
+}
+
+#[test]
+fn reserved_immediate_shifts_are_illegal() {
+    // funct7 0x0c isn't a shift or an operation of Zbb, Zbs or Zbkb for either funct3.
+    for funct3 in [1, 5] {
+        let mut cpu = new_cpu(&[inst(0x0c, 0, funct3, 0x13)]);
+        assert!(matches!(step(&mut cpu), Err(Exception::IllegalInstruction)));
+    }
+}
@@ -0,0 +1,34 @@
+//! Helpers shared by the tests which execute short programs.
+
+#![allow(dead_code)]
+
+use rvemu::cpu::Cpu;
+use rvemu::trap::Exception;
+
+/// Return a cpu with `program` at the start of the dram.
+pub fn new_cpu(program: &[u32]) -> Cpu {
+    let binary = program.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+    Cpu::new(binary, Vec::new())
+}
+
+/// Execute the instruction at the pc.
+pub fn step(cpu: &mut Cpu) -> Result<(), Exception> {
+    let inst = cpu.fetch().unwrap();
+    cpu.pc += 4;
+    cpu.execute(inst)
+}
+
+/// Execute `program` and return the cpu.
+pub fn run(program: &[u32]) -> Cpu {
+    run_with_a0(program, 0)
+}
+
+/// Execute `program` with a0 = `a0` and return the cpu.
+pub fn run_with_a0(program: &[u32], a0: u64) -> Cpu {
+    let mut cpu = new_cpu(program);
+    cpu.regs[10] = a0;
+    for _ in program {
+        step(&mut cpu).unwrap();
+    }
+    cpu
+}
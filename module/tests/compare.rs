@@ -0,0 +1,47 @@
+//! Tests of the comparison of runs at checkpoints.
+
+use std::io;
+
+use rvemu::compare;
+use rvemu::cpu::Cpu;
+
+/// addi a0,a0,1
+const ADDI_A0: u32 = 0x00150513;
+/// jal zero,-4
+const LOOP: u32 = 0xffdff06f;
+
+/// Return a cpu looping forever with a0 = `a0`.
+fn build(a0: u64) -> io::Result<Cpu> {
+    let binary = [ADDI_A0, LOOP]
+        .iter()
+        .flat_map(|inst| inst.to_le_bytes())
+        .collect();
+    let mut cpu = Cpu::new(binary, Vec::new());
+    cpu.regs[10] = a0;
+    Ok(cpu)
+}
+
+#[test]
+fn same_runs_match() {
+    let mismatches = compare::run(|| build(0), 2, 20, 10).unwrap();
+    assert!(mismatches.is_empty());
+}
+
+#[test]
+fn first_differing_checkpoint_is_reported() {
+    let mut builds = 0;
+    let mismatches = compare::run(
+        || {
+            builds += 1;
+            build(if builds == 2 { 1 } else { 0 })
+        },
+        2,
+        20,
+        10,
+    )
+    .unwrap();
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].run, 1);
+    assert_eq!(mismatches[0].step, 10);
+    assert_eq!(mismatches[0].differences, vec!["x10 0x6, expected 0x5"]);
+}
@@ -0,0 +1,72 @@
+//! Tests of ELF core files written on fatal exceptions.
+
+mod common;
+
+use std::convert::TryInto;
+
+use common::{new_cpu, step};
+use rvemu::bus::DRAM_BASE;
+use rvemu::coredump::core_image;
+use rvemu::trap::Exception;
+
+/// addi a1,zero,42
+const LI_A1: u32 = 0x02a00593;
+/// ld a2,0(zero)
+const LD_ZERO: u32 = 0x00003603;
+
+fn u16_at(image: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(image[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(image: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(image[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(image: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(image[offset..offset + 8].try_into().unwrap())
+}
+
+#[test]
+fn core_holds_registers_and_dram() {
+    let program = [LI_A1, LD_ZERO];
+    let mut cpu = new_cpu(&program);
+    let mut fault = None;
+    for _ in program.iter() {
+        let pc = cpu.pc;
+        if let Err(exception) = step(&mut cpu) {
+            fault = Some((pc, exception));
+        }
+    }
+    let (pc, exception) = fault.expect("the load faults");
+    assert!(matches!(exception, Exception::LoadAccessFault(_)));
+    let image = core_image(&cpu, pc, &exception);
+
+    // A 64-bit little-endian core file for RISC-V with two program headers.
+    assert_eq!(&image[..6], b"\x7fELF\x02\x01");
+    assert_eq!(u16_at(&image, 0x10), 4);
+    assert_eq!(u16_at(&image, 0x12), 243);
+    assert_eq!(u16_at(&image, 0x38), 2);
+    let phoff = u64_at(&image, 0x20) as usize;
+
+    // The NT_PRSTATUS note with SIGSEGV, the faulting pc and a1.
+    let note_phdr = phoff;
+    assert_eq!(u32_at(&image, note_phdr), 4);
+    let note = u64_at(&image, note_phdr + 8) as usize;
+    assert_eq!(u32_at(&image, note + 8), 1);
+    assert_eq!(&image[note + 12..note + 17], b"CORE\0");
+    let prstatus = note + 20;
+    assert_eq!(u32_at(&image, prstatus), 11);
+    assert_eq!(u64_at(&image, prstatus + 112), DRAM_BASE + 4);
+    assert_eq!(u64_at(&image, prstatus + 112 + 11 * 8), 42);
+
+    // The dram at its physical address.
+    let load_phdr = phoff + 56;
+    assert_eq!(u32_at(&image, load_phdr), 1);
+    let offset = u64_at(&image, load_phdr + 8) as usize;
+    assert_eq!(u64_at(&image, load_phdr + 16), DRAM_BASE);
+    assert_eq!(
+        u64_at(&image, load_phdr + 32) as usize,
+        cpu.bus.dram_bytes().len()
+    );
+    assert_eq!(u32_at(&image, offset + 4), LD_ZERO);
+}
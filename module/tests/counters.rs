@@ -0,0 +1,108 @@
+//! Tests of the cycle, time and instructions-retired counters.
+
+mod common;
+
+use common::{new_cpu, run_with_a0, step};
+use rvemu::bus::CLINT_BASE;
+use rvemu::cpu::*;
+use rvemu::trap::Exception;
+
+/// addi zero,zero,0
+const NOP: u32 = 0x00000013;
+
+/// csrrs <rd>,<csr>,zero
+fn csrr(rd: u32, csr: usize) -> u32 {
+    ((csr as u32) << 20) | (0x2 << 12) | (rd << 7) | 0x73
+}
+
+/// csrrw zero,<csr>,a0
+fn csrw(csr: usize) -> u32 {
+    ((csr as u32) << 20) | (10 << 15) | (0x1 << 12) | 0x73
+}
+
+#[test]
+fn counters_increment_per_instruction() {
+    // A read returns the count of the instructions before it.
+    let cpu = run_with_a0(&[NOP, NOP, NOP, csrr(11, INSTRET), csrr(12, CYCLE)], 0);
+    assert_eq!(cpu.regs[11], 3);
+    assert_eq!(cpu.regs[12], 4);
+    assert_eq!(cpu.load_csr(MINSTRET), 5);
+    assert_eq!(cpu.load_csr(MCYCLE), 5);
+}
+
+#[test]
+fn written_value_is_read_by_the_next_instruction() {
+    let cpu = run_with_a0(&[csrw(MINSTRET), csrr(11, MINSTRET), csrr(12, MCYCLE)], 100);
+    assert_eq!(cpu.regs[11], 100);
+    // mcycle counts the write of minstret.
+    assert_eq!(cpu.regs[12], 2);
+}
+
+#[test]
+fn mcountinhibit_stops_counters() {
+    let cpu = run_with_a0(&[csrw(MCOUNTINHIBIT), NOP, NOP], MCOUNTINHIBIT_IR);
+    assert_eq!(cpu.load_csr(MINSTRET), 0);
+    assert_eq!(cpu.load_csr(MCYCLE), 3);
+}
+
+#[test]
+fn time_reads_mtime() {
+    let mut cpu = new_cpu(&[csrr(11, TIME)]);
+    // mtime of the CLINT.
+    cpu.bus.store(CLINT_BASE + 0xbff8, 64, 12345).unwrap();
+    // rdtime works in U-mode if both counter-enable registers allow it.
+    cpu.mode = Mode::User;
+    cpu.store_csr(MCOUNTEREN, COUNTEREN_TM);
+    cpu.store_csr(SCOUNTEREN, COUNTEREN_TM);
+    step(&mut cpu).unwrap();
+    assert_eq!(cpu.regs[11], 12345);
+}
+
+#[test]
+fn mtime_ticks_and_raises_the_timer_interrupt() {
+    let mut cpu = new_cpu(&[]);
+    // The clock advances by 1 tick (100 ns) every 10 instructions if it's deterministic.
+    cpu.bus.set_seed(1);
+    cpu.bus.store(CLINT_BASE + 0x4000, 64, 10).unwrap();
+    for _ in 0..99 {
+        cpu.check_pending_interrupt();
+    }
+    assert_eq!(cpu.load_csr(TIME), 9);
+    assert_eq!(cpu.load_csr(MIP) & MIP_MTIP, 0);
+    cpu.check_pending_interrupt();
+    assert_eq!(cpu.load_csr(TIME), 10);
+    assert_eq!(cpu.load_csr(MIP) & MIP_MTIP, MIP_MTIP);
+
+    // MTIP is read-only in mip and is cleared by moving mtimecmp.
+    cpu.store_csr(MIP, 0);
+    assert_eq!(cpu.load_csr(MIP) & MIP_MTIP, MIP_MTIP);
+    cpu.bus.store(CLINT_BASE + 0x4000, 64, u64::MAX).unwrap();
+    assert_eq!(cpu.load_csr(MIP) & MIP_MTIP, 0);
+}
+
+/// Read `csr` in `mode` with mcounteren and scounteren and return the result.
+fn read_counter(csr: usize, mode: Mode, mcounteren: u64, scounteren: u64) -> Result<(), Exception> {
+    let mut cpu = new_cpu(&[csrr(11, csr)]);
+    cpu.mode = mode;
+    cpu.store_csr(MCOUNTEREN, mcounteren);
+    cpu.store_csr(SCOUNTEREN, scounteren);
+    step(&mut cpu)
+}
+
+#[test]
+fn counteren_controls_reads() {
+    let illegal = |result| matches!(result, Err(Exception::IllegalInstruction));
+    assert!(read_counter(CYCLE, Mode::Machine, 0, 0).is_ok());
+    assert!(illegal(read_counter(CYCLE, Mode::Supervisor, 0, 0)));
+    assert!(read_counter(CYCLE, Mode::Supervisor, COUNTEREN_CY, 0).is_ok());
+    assert!(illegal(read_counter(INSTRET, Mode::User, COUNTEREN_IR, 0)));
+    assert!(illegal(read_counter(INSTRET, Mode::User, 0, COUNTEREN_IR)));
+    assert!(read_counter(INSTRET, Mode::User, COUNTEREN_IR, COUNTEREN_IR).is_ok());
+    // The bit of another counter doesn't enable it.
+    assert!(illegal(read_counter(
+        TIME,
+        Mode::Supervisor,
+        COUNTEREN_CY | COUNTEREN_IR,
+        0
+    )));
+}
@@ -0,0 +1,57 @@
+//! Tests of the privilege and read-only checks of CSR instructions.
+
+mod common;
+
+use common::{new_cpu, step};
+use rvemu::cpu::*;
+use rvemu::trap::Exception;
+
+/// Return `op a1,<csr>,<rs1>` of a CSR instruction with `funct3`.
+fn csr_inst(csr: usize, funct3: u32, rs1: u32) -> u32 {
+    ((csr as u32) << 20) | (rs1 << 15) | (funct3 << 12) | (11 << 7) | 0x73
+}
+
+/// Execute `inst` in `mode` and return the result.
+fn exec(inst: u32, mode: Mode) -> Result<(), Exception> {
+    let mut cpu = new_cpu(&[inst]);
+    cpu.mode = mode;
+    step(&mut cpu)
+}
+
+fn is_illegal(result: Result<(), Exception>) -> bool {
+    matches!(result, Err(Exception::IllegalInstruction))
+}
+
+#[test]
+fn lower_modes_cannot_access_higher_csrs() {
+    // csrrs a1,mstatus,zero
+    assert!(exec(csr_inst(MSTATUS, 2, 0), Mode::Machine).is_ok());
+    assert!(is_illegal(exec(csr_inst(MSTATUS, 2, 0), Mode::Supervisor)));
+    // csrrw a1,sstatus,a0
+    assert!(exec(csr_inst(SSTATUS, 1, 10), Mode::Supervisor).is_ok());
+    assert!(is_illegal(exec(csr_inst(SSTATUS, 1, 10), Mode::User)));
+    // A hypervisor CSR, hstatus, isn't accessible from S-mode.
+    assert!(is_illegal(exec(csr_inst(0x600, 2, 0), Mode::Supervisor)));
+    // fcsr is accessible from U-mode if F is implemented and the floating-point state isn't Off.
+    let mut cpu = new_cpu(&[csr_inst(FCSR, 2, 0)]);
+    cpu.extensions.f = true;
+    cpu.store_csr(MSTATUS, MSTATUS_FS_INITIAL);
+    cpu.mode = Mode::User;
+    assert!(step(&mut cpu).is_ok());
+}
+
+#[test]
+fn read_only_csrs_reject_writes() {
+    // csrrs and csrrc with rs1 = zero and csrrsi and csrrci with zero only read.
+    for funct3 in [2, 3, 6, 7] {
+        assert!(exec(csr_inst(MHARTID, funct3, 0), Mode::Machine).is_ok());
+    }
+    for funct3 in [1, 2, 3, 5, 6, 7] {
+        assert!(is_illegal(exec(
+            csr_inst(MHARTID, funct3, 10),
+            Mode::Machine
+        )));
+    }
+    // cycle is read-only even in M-mode.
+    assert!(is_illegal(exec(csr_inst(CYCLE, 1, 0), Mode::Machine)));
+}
@@ -0,0 +1,469 @@
+//! Round-trip tests of the disassembler. Random instructions of every format are encoded from a
+//! table, disassembled, assembled back from the text and compared with the original encoding, so
+//! a field which the disassembler extracts wrongly, e.g. a bit of a B-type or J-type immediate,
+//! shows up as a different instruction.
+//!
+//! Cases are drawn from a seeded generator instead of proptest, which isn't available offline. A
+//! failure reports the seed and the case, and `ROUNDTRIP_SEED` reruns a seed.
+
+use std::env;
+
+use rvemu::disasm::{csr_name, disassemble, REG_NAMES};
+use rvemu::entropy::Entropy;
+
+/// The number of random cases per table entry.
+const CASES: usize = 2000;
+
+/// An instruction format, which decides the fields filled randomly and the operand syntax.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    /// `rd,rs1,rs2`
+    R,
+    /// `rd,rs1,imm`
+    I,
+    /// `rd,rs1,shamt` with a 6-bit shift amount.
+    Shift64,
+    /// `rd,rs1,shamt` with a 5-bit shift amount.
+    Shift32,
+    /// `rd,rs1` with the rest of the instruction fixed.
+    Unary,
+    /// `rd,imm(rs1)`
+    Load,
+    /// `rs2,imm(rs1)`
+    Store,
+    /// `rs1,rs2,target`
+    Branch,
+    /// `rd,imm20`
+    Upper,
+    /// `rd,target`
+    Jal,
+    /// `rd,imm(rs1)`
+    Jalr,
+    /// `rd,rs2,(rs1)` with an ordering suffix.
+    Amo,
+    /// `rd,(rs1)` with an ordering suffix.
+    Lr,
+    /// `rd,csr,rs1`
+    Csr,
+    /// `rd,csr,zimm`
+    CsrImm,
+    /// `rd,rs1,rs2,bs` with a 2-bit byte select.
+    ByteSelect,
+    /// `rd,rs1,rnum` with a 4-bit round number.
+    RoundNumber,
+    /// `rs1,rs2`
+    Fence,
+    /// No operands.
+    Fixed,
+}
+
+use Format::*;
+
+/// Mnemonics with their formats and the fixed bits of their encodings. AMOs leave the width
+/// (funct3) and the ordering bits to be filled in.
+const TABLE: &[(&str, Format, u32)] = &[
+    ("lb", Load, 0x0000_0003),
+    ("lh", Load, 0x0000_1003),
+    ("lw", Load, 0x0000_2003),
+    ("ld", Load, 0x0000_3003),
+    ("lbu", Load, 0x0000_4003),
+    ("lhu", Load, 0x0000_5003),
+    ("lwu", Load, 0x0000_6003),
+    ("fence.i", Fixed, 0x0000_100f),
+    ("addi", I, 0x0000_0013),
+    ("slti", I, 0x0000_2013),
+    ("sltiu", I, 0x0000_3013),
+    ("xori", I, 0x0000_4013),
+    ("ori", I, 0x0000_6013),
+    ("andi", I, 0x0000_7013),
+    ("slli", Shift64, 0x0000_1013),
+    ("srli", Shift64, 0x0000_5013),
+    ("srai", Shift64, 0x4000_5013),
+    ("rori", Shift64, 0x6000_5013),
+    ("rev8", Unary, 0x6b80_5013),
+    ("brev8", Unary, 0x6870_5013),
+    ("sha256sum0", Unary, 0x1000_1013),
+    ("sha256sum1", Unary, 0x1010_1013),
+    ("sha256sig0", Unary, 0x1020_1013),
+    ("sha256sig1", Unary, 0x1030_1013),
+    ("sha512sum0", Unary, 0x1040_1013),
+    ("sha512sum1", Unary, 0x1050_1013),
+    ("sha512sig0", Unary, 0x1060_1013),
+    ("sha512sig1", Unary, 0x1070_1013),
+    ("sm3p0", Unary, 0x1080_1013),
+    ("sm3p1", Unary, 0x1090_1013),
+    ("aes64im", Unary, 0x3000_1013),
+    ("clz", Unary, 0x6000_1013),
+    ("ctz", Unary, 0x6010_1013),
+    ("cpop", Unary, 0x6020_1013),
+    ("sext.b", Unary, 0x6040_1013),
+    ("sext.h", Unary, 0x6050_1013),
+    ("orc.b", Unary, 0x2870_5013),
+    ("bseti", Shift64, 0x2800_1013),
+    ("bclri", Shift64, 0x4800_1013),
+    ("binvi", Shift64, 0x6800_1013),
+    ("bexti", Shift64, 0x4800_5013),
+    ("aes64ks1i", RoundNumber, 0x3100_1013),
+    ("auipc", Upper, 0x0000_0017),
+    ("addiw", I, 0x0000_001b),
+    ("slliw", Shift32, 0x0000_101b),
+    ("srliw", Shift32, 0x0000_501b),
+    ("sraiw", Shift32, 0x4000_501b),
+    ("roriw", Shift32, 0x6000_501b),
+    ("slli.uw", Shift64, 0x0800_101b),
+    ("clzw", Unary, 0x6000_101b),
+    ("ctzw", Unary, 0x6010_101b),
+    ("cpopw", Unary, 0x6020_101b),
+    ("sb", Store, 0x0000_0023),
+    ("sh", Store, 0x0000_1023),
+    ("sw", Store, 0x0000_2023),
+    ("sd", Store, 0x0000_3023),
+    ("amoadd", Amo, 0x0000_002f),
+    ("amoswap", Amo, 0x0800_002f),
+    ("lr", Lr, 0x1000_002f),
+    ("sc", Amo, 0x1800_002f),
+    ("amoxor", Amo, 0x2000_002f),
+    ("amoor", Amo, 0x4000_002f),
+    ("amoand", Amo, 0x6000_002f),
+    ("amomin", Amo, 0x8000_002f),
+    ("amomax", Amo, 0xa000_002f),
+    ("amominu", Amo, 0xc000_002f),
+    ("amomaxu", Amo, 0xe000_002f),
+    ("add", R, 0x0000_0033),
+    ("sub", R, 0x4000_0033),
+    ("sll", R, 0x0000_1033),
+    ("slt", R, 0x0000_2033),
+    ("sltu", R, 0x0000_3033),
+    ("xor", R, 0x0000_4033),
+    ("srl", R, 0x0000_5033),
+    ("sra", R, 0x4000_5033),
+    ("or", R, 0x0000_6033),
+    ("and", R, 0x0000_7033),
+    ("mul", R, 0x0200_0033),
+    ("mulh", R, 0x0200_1033),
+    ("mulhsu", R, 0x0200_2033),
+    ("mulhu", R, 0x0200_3033),
+    ("div", R, 0x0200_4033),
+    ("divu", R, 0x0200_5033),
+    ("rem", R, 0x0200_6033),
+    ("remu", R, 0x0200_7033),
+    ("aes64es", R, 0x3200_0033),
+    ("aes64esm", R, 0x3600_0033),
+    ("aes64ds", R, 0x3a00_0033),
+    ("aes64dsm", R, 0x3e00_0033),
+    ("aes64ks2", R, 0x7e00_0033),
+    ("sm4ed", ByteSelect, 0x3000_0033),
+    ("sm4ks", ByteSelect, 0x3400_0033),
+    ("clmul", R, 0x0a00_1033),
+    ("clmulh", R, 0x0a00_3033),
+    ("xperm4", R, 0x2800_2033),
+    ("xperm8", R, 0x2800_4033),
+    ("rol", R, 0x6000_1033),
+    ("ror", R, 0x6000_5033),
+    ("xnor", R, 0x4000_4033),
+    ("orn", R, 0x4000_6033),
+    ("andn", R, 0x4000_7033),
+    ("pack", R, 0x0800_4033),
+    ("packh", R, 0x0800_7033),
+    ("sh1add", R, 0x2000_2033),
+    ("sh2add", R, 0x2000_4033),
+    ("sh3add", R, 0x2000_6033),
+    ("min", R, 0x0a00_4033),
+    ("minu", R, 0x0a00_5033),
+    ("max", R, 0x0a00_6033),
+    ("maxu", R, 0x0a00_7033),
+    ("bset", R, 0x2800_1033),
+    ("bclr", R, 0x4800_1033),
+    ("binv", R, 0x6800_1033),
+    ("bext", R, 0x4800_5033),
+    ("lui", Upper, 0x0000_0037),
+    ("addw", R, 0x0000_003b),
+    ("subw", R, 0x4000_003b),
+    ("sllw", R, 0x0000_103b),
+    ("srlw", R, 0x0000_503b),
+    ("sraw", R, 0x4000_503b),
+    ("mulw", R, 0x0200_003b),
+    ("divw", R, 0x0200_403b),
+    ("divuw", R, 0x0200_503b),
+    ("remw", R, 0x0200_603b),
+    ("remuw", R, 0x0200_703b),
+    ("rolw", R, 0x6000_103b),
+    ("rorw", R, 0x6000_503b),
+    ("packw", R, 0x0800_403b),
+    ("zext.h", Unary, 0x0800_403b),
+    ("add.uw", R, 0x0800_003b),
+    ("sh1add.uw", R, 0x2000_203b),
+    ("sh2add.uw", R, 0x2000_403b),
+    ("sh3add.uw", R, 0x2000_603b),
+    ("beq", Branch, 0x0000_0063),
+    ("bne", Branch, 0x0000_1063),
+    ("blt", Branch, 0x0000_4063),
+    ("bge", Branch, 0x0000_5063),
+    ("bltu", Branch, 0x0000_6063),
+    ("bgeu", Branch, 0x0000_7063),
+    ("jalr", Jalr, 0x0000_0067),
+    ("jal", Jal, 0x0000_006f),
+    ("ecall", Fixed, 0x0000_0073),
+    ("ebreak", Fixed, 0x0010_0073),
+    ("sret", Fixed, 0x1020_0073),
+    ("mret", Fixed, 0x3020_0073),
+    ("wfi", Fixed, 0x1050_0073),
+    ("sfence.vma", Fence, 0x1200_0073),
+    ("csrrw", Csr, 0x0000_1073),
+    ("csrrs", Csr, 0x0000_2073),
+    ("csrrc", Csr, 0x0000_3073),
+    ("csrrwi", CsrImm, 0x0000_5073),
+    ("csrrsi", CsrImm, 0x0000_6073),
+    ("csrrci", CsrImm, 0x0000_7073),
+];
+
+/// The widths of AMOs by funct3.
+const WIDTHS: [&str; 4] = ["b", "h", "w", "d"];
+/// The ordering suffixes of AMOs by the aq and rl bits.
+const ORDERINGS: [&str; 4] = ["", ".rl", ".aq", ".aqrl"];
+
+fn rd(reg: u32) -> u32 {
+    reg << 7
+}
+
+fn rs1(reg: u32) -> u32 {
+    reg << 15
+}
+
+fn rs2(reg: u32) -> u32 {
+    reg << 20
+}
+
+fn i_imm(imm: i64) -> u32 {
+    (imm as u32 & 0xfff) << 20
+}
+
+fn s_imm(imm: i64) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 5 & 0x7f) << 25) | ((imm & 0x1f) << 7)
+}
+
+fn b_imm(imm: i64) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 12 & 1) << 31)
+        | ((imm >> 5 & 0x3f) << 25)
+        | ((imm >> 1 & 0xf) << 8)
+        | ((imm >> 11 & 1) << 7)
+}
+
+fn j_imm(imm: i64) -> u32 {
+    let imm = imm as u32;
+    ((imm >> 20 & 1) << 31) | ((imm >> 1 & 0x3ff) << 21) | ((imm >> 11 & 1) << 20) | (imm & 0xff000)
+}
+
+/// Return a random signed immediate of `bits` bits, biased towards the boundaries of its range.
+fn random_imm(entropy: &mut Entropy, bits: u32) -> i64 {
+    let min = -(1i64 << (bits - 1));
+    let max = (1i64 << (bits - 1)) - 1;
+    match entropy.below(8) {
+        0 => min,
+        1 => max,
+        2 => 0,
+        3 => -1,
+        _ => min + entropy.below(1 << bits) as i64,
+    }
+}
+
+/// Encode a random instruction of a table entry.
+fn encode_random(entropy: &mut Entropy, format: Format, base: u32) -> u32 {
+    let mut reg = || entropy.below(32) as u32;
+    let (d, s1, s2) = (reg(), reg(), reg());
+    match format {
+        R => base | rd(d) | rs1(s1) | rs2(s2),
+        I | Load | Jalr => base | rd(d) | rs1(s1) | i_imm(random_imm(entropy, 12)),
+        Shift64 => base | rd(d) | rs1(s1) | (entropy.below(64) as u32) << 20,
+        Shift32 => base | rd(d) | rs1(s1) | (entropy.below(32) as u32) << 20,
+        Unary => base | rd(d) | rs1(s1),
+        Store => base | rs1(s1) | rs2(s2) | s_imm(random_imm(entropy, 12)),
+        Branch => base | rs1(s1) | rs2(s2) | b_imm(random_imm(entropy, 12) * 2),
+        Upper => base | rd(d) | (entropy.below(1 << 20) as u32) << 12,
+        Jal => base | rd(d) | j_imm(random_imm(entropy, 20) * 2),
+        Amo | Lr => {
+            let width = entropy.below(4) as u32;
+            let ordering = entropy.below(4) as u32;
+            let s2 = if format == Lr { 0 } else { s2 };
+            base | rd(d) | rs1(s1) | rs2(s2) | width << 12 | ordering << 25
+        }
+        Csr => base | rd(d) | rs1(s1) | (entropy.below(4096) as u32) << 20,
+        CsrImm => base | rd(d) | rs1(s1) | (entropy.below(4096) as u32) << 20,
+        ByteSelect => base | rd(d) | rs1(s1) | rs2(s2) | (entropy.below(4) as u32) << 30,
+        RoundNumber => base | rd(d) | rs1(s1) | (entropy.below(16) as u32) << 20,
+        Fence => base | rs1(s1) | rs2(s2),
+        Fixed => base,
+    }
+}
+
+fn parse_reg(s: &str) -> Result<u32, String> {
+    REG_NAMES
+        .iter()
+        .position(|name| *name == s)
+        .map(|reg| reg as u32)
+        .ok_or_else(|| format!("unknown register {:?}", s))
+}
+
+fn parse_num(s: &str) -> Result<i64, String> {
+    let parsed = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(hex, 16).map(|n| n as i64),
+        None => s.parse::<i64>(),
+    };
+    parsed.map_err(|_| format!("bad number {:?}", s))
+}
+
+/// Parse `imm(reg)`.
+fn parse_mem(s: &str) -> Result<(i64, u32), String> {
+    let (imm, reg) = s
+        .strip_suffix(')')
+        .and_then(|s| s.split_once('('))
+        .ok_or_else(|| format!("bad memory operand {:?}", s))?;
+    let imm = if imm.is_empty() { 0 } else { parse_num(imm)? };
+    Ok((imm, parse_reg(reg)?))
+}
+
+fn parse_csr(s: &str) -> Result<u32, String> {
+    if s.starts_with("0x") {
+        return parse_num(s).map(|csr| csr as u32);
+    }
+    (0..4096)
+        .find(|csr| csr_name(*csr) == s)
+        .map(|csr| csr as u32)
+        .ok_or_else(|| format!("unknown CSR {:?}", s))
+}
+
+/// Assemble the text of an instruction at `pc` in the syntax of the disassembler.
+fn assemble(pc: u64, text: &str) -> Result<u32, String> {
+    let (name, operands) = text.split_once(' ').unwrap_or((text, ""));
+    let ops: Vec<&str> = operands.split(',').filter(|op| !op.is_empty()).collect();
+    let op = |i: usize| {
+        ops.get(i)
+            .copied()
+            .ok_or_else(|| format!("missing operand {} in {:?}", i, text))
+    };
+    let offset = |target: &str| parse_num(target).map(|t| (t as u64).wrapping_sub(pc) as i64);
+
+    // Pseudo-instructions.
+    match name {
+        "nop" => return Ok(0x0000_0013),
+        "ret" => return Ok(0x0000_8067),
+        "mv" => return Ok(0x0000_0013 | rd(parse_reg(op(0)?)?) | rs1(parse_reg(op(1)?)?)),
+        "j" => return Ok(0x0000_006f | j_imm(offset(op(0)?)?)),
+        _ => {}
+    }
+
+    // Split an AMO into its operation, width and ordering, e.g. `amoadd.w.aqrl`.
+    let mut parts = name.splitn(2, '.');
+    let base_name = parts.next().unwrap_or("");
+    let suffix = parts.next().unwrap_or("");
+    let entry = TABLE
+        .iter()
+        .find(|(n, f, _)| match f {
+            Amo | Lr => *n == base_name,
+            _ => *n == name,
+        })
+        .ok_or_else(|| format!("unknown mnemonic {:?}", name))?;
+    let (_, format, base) = *entry;
+    let inst = match format {
+        R => base | rd(parse_reg(op(0)?)?) | rs1(parse_reg(op(1)?)?) | rs2(parse_reg(op(2)?)?),
+        I => base | rd(parse_reg(op(0)?)?) | rs1(parse_reg(op(1)?)?) | i_imm(parse_num(op(2)?)?),
+        Shift64 | Shift32 | RoundNumber => {
+            base | rd(parse_reg(op(0)?)?)
+                | rs1(parse_reg(op(1)?)?)
+                | (parse_num(op(2)?)? as u32) << 20
+        }
+        Unary => base | rd(parse_reg(op(0)?)?) | rs1(parse_reg(op(1)?)?),
+        Load | Jalr => {
+            let (imm, reg) = parse_mem(op(1)?)?;
+            base | rd(parse_reg(op(0)?)?) | rs1(reg) | i_imm(imm)
+        }
+        Store => {
+            let (imm, reg) = parse_mem(op(1)?)?;
+            base | rs2(parse_reg(op(0)?)?) | rs1(reg) | s_imm(imm)
+        }
+        Branch => base | rs1(parse_reg(op(0)?)?) | rs2(parse_reg(op(1)?)?) | b_imm(offset(op(2)?)?),
+        Upper => base | rd(parse_reg(op(0)?)?) | (parse_num(op(1)?)? as u32) << 12,
+        Jal => base | rd(parse_reg(op(0)?)?) | j_imm(offset(op(1)?)?),
+        Amo | Lr => {
+            let (width, ordering) = suffix.split_once('.').unwrap_or((suffix, ""));
+            let width = WIDTHS
+                .iter()
+                .position(|w| *w == width)
+                .ok_or_else(|| format!("bad width in {:?}", name))? as u32;
+            let ordering = ORDERINGS
+                .iter()
+                .position(|o| o.trim_start_matches('.') == ordering)
+                .ok_or_else(|| format!("bad ordering in {:?}", name))?
+                as u32;
+            let (s2, addr) = match format {
+                Lr => (0, op(1)?),
+                _ => (parse_reg(op(1)?)?, op(2)?),
+            };
+            let (_, s1) = parse_mem(addr)?;
+            base | rd(parse_reg(op(0)?)?) | rs1(s1) | rs2(s2) | width << 12 | ordering << 25
+        }
+        Csr => base | rd(parse_reg(op(0)?)?) | parse_csr(op(1)?)? << 20 | rs1(parse_reg(op(2)?)?),
+        CsrImm => {
+            base | rd(parse_reg(op(0)?)?)
+                | parse_csr(op(1)?)? << 20
+                | rs1(parse_num(op(2)?)? as u32)
+        }
+        ByteSelect => {
+            base | rd(parse_reg(op(0)?)?)
+                | rs1(parse_reg(op(1)?)?)
+                | rs2(parse_reg(op(2)?)?)
+                | (parse_num(op(3)?)? as u32) << 30
+        }
+        Fence => base | rs1(parse_reg(op(0)?)?) | rs2(parse_reg(op(1)?)?),
+        Fixed => base,
+    };
+    Ok(inst)
+}
+
+/// Return the seed of the cases, from `ROUNDTRIP_SEED` if it's set.
+fn seed() -> u64 {
+    env::var("ROUNDTRIP_SEED")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| Entropy::from_host().next_u64())
+}
+
+#[test]
+fn disassembly_assembles_to_the_same_instruction() {
+    let seed = seed();
+    let mut entropy = Entropy::new(seed);
+    for &(name, format, base) in TABLE {
+        for _ in 0..CASES {
+            let inst = encode_random(&mut entropy, format, base);
+            // Branch and jump targets wrap around the address space.
+            let pc = entropy.next_u64() & !0x3;
+            let text = disassemble(pc, inst as u64);
+            let reassembled = assemble(pc, &text);
+            assert_eq!(
+                reassembled,
+                Ok(inst),
+                "seed {}: {} {:#010x} at {:#x} disassembles to {:?}",
+                seed,
+                name,
+                inst,
+                pc,
+                text
+            );
+        }
+    }
+}
+
+#[test]
+fn branch_and_jump_immediates_at_boundaries() {
+    let pc = 0x8000_0000;
+    for imm in [-4096, -4094, -2, 0, 2, 2046, 2048, 4094] {
+        let inst = 0x0000_0063 | rs1(10) | rs2(11) | b_imm(imm);
+        assert_eq!(assemble(pc, &disassemble(pc, inst as u64)), Ok(inst));
+    }
+    for imm in [-(1 << 20), -2, 0, 2, 2048, 1 << 12, (1 << 20) - 2] {
+        let inst = 0x0000_006f | rd(1) | j_imm(imm);
+        assert_eq!(assemble(pc, &disassemble(pc, inst as u64)), Ok(inst));
+    }
+}
@@ -0,0 +1,83 @@
+//! Tests of the explanations printed by `--explain`. A few instructions of each format are run on
+//! a CPU and their explanations are compared with the expected text.
+
+mod common;
+
+use common::new_cpu;
+use rvemu::explain::{describe, fields, Explainer};
+
+/// Execute the instructions of `program` one by one and return their explanations.
+fn explain(program: &[u32]) -> Vec<String> {
+    let mut cpu = new_cpu(program);
+    let mut explainer = Explainer::new();
+    let mut explanations = Vec::new();
+    for _ in program {
+        let pc = cpu.pc;
+        let inst = cpu.fetch().unwrap();
+        explainer.before(&cpu, inst);
+        cpu.pc += 4;
+        let result = cpu.execute(inst);
+        explanations.push(explainer.explain(&cpu, pc, Some(inst), result.as_ref().err()));
+    }
+    explanations
+}
+
+#[test]
+fn fields_of_each_format() {
+    // addi a0,zero,42
+    assert_eq!(
+        fields(0x02a00513),
+        "opcode=0x13 (OP-IMM, I-type) funct3=0x0 rd=a0 (x10) rs1=zero (x0) imm=42"
+    );
+    // add a2,a0,a1
+    assert_eq!(
+        fields(0x00b50633),
+        "opcode=0x33 (OP, R-type) funct3=0x0 funct7=0x00 rd=a2 (x12) rs1=a0 (x10) rs2=a1 (x11)"
+    );
+    // sd a1,-8(sp)
+    assert_eq!(
+        fields(0xfeb13c23),
+        "opcode=0x23 (STORE, S-type) funct3=0x3 rs1=sp (x2) rs2=a1 (x11) imm=-8"
+    );
+    // csrrwi zero,mscratch,5
+    assert_eq!(
+        fields(0x3402d073),
+        "opcode=0x73 (SYSTEM, I-type) funct3=0x5 rd=zero (x0) uimm=5 csr=mscratch"
+    );
+}
+
+#[test]
+fn meaning_with_operands() {
+    assert_eq!(describe(0x80000000, 0x00b50633), "a2 = a0 + a1");
+    // lw a0,4(sp)
+    assert_eq!(
+        describe(0x80000000, 0x00412503),
+        "a0 = sign-extended word at address sp + 4"
+    );
+    // bne a0,a1,.+16
+    assert_eq!(
+        describe(0x80000000, 0x00b51863),
+        "jump to 0x80000010 if a0 != a1"
+    );
+    // amoadd.d.aqrl a0,a1,(a2)
+    assert_eq!(
+        describe(0x80000000, 0x06b6352f),
+        "atomically a0 = doubleword at address a2, and store a0 + a1 there"
+    );
+}
+
+#[test]
+fn registers_before_and_after() {
+    // addi a0,zero,42; add a1,a0,a0; jal zero,.+8
+    let explanations = explain(&[0x02a00513, 0x00a505b3, 0x0080006f]);
+    assert_eq!(
+        explanations[0],
+        "0x80000000: addi a0,zero,42\n    \
+         fields:  opcode=0x13 (OP-IMM, I-type) funct3=0x0 rd=a0 (x10) rs1=zero (x0) imm=42\n    \
+         meaning: a0 = zero + 42\n    \
+         before:  zero=0x0 a0=0x0\n    \
+         after:   a0=0x2a"
+    );
+    assert!(explanations[1].ends_with("before:  a0=0x2a a1=0x0\n    after:   a1=0x54"));
+    assert!(explanations[2].ends_with("before:  (nothing)\n    after:   pc=0x80000010"));
+}
@@ -0,0 +1,90 @@
+//! Tests of fcsr and its fields fflags and frm.
+
+mod common;
+
+use common::{new_cpu, run_with_a0, step};
+use rvemu::cpu::*;
+use rvemu::trap::Exception;
+
+/// csrrw a1,<csr>,a0
+fn csrrw(csr: u32) -> u32 {
+    (csr << 20) | (10 << 15) | (0x1 << 12) | (11 << 7) | 0x73
+}
+
+/// Execute `inst` with a0 = `a0` on a cpu implementing F, with the floating-point state `fs`.
+fn exec_fp(inst: u32, a0: u64, fs: u64) -> (Cpu, Result<(), Exception>) {
+    let mut cpu = new_cpu(&[inst]);
+    cpu.extensions.f = true;
+    cpu.store_csr(MSTATUS, fs);
+    cpu.regs[10] = a0;
+    let result = step(&mut cpu);
+    (cpu, result)
+}
+
+#[test]
+fn fields_alias_fcsr() {
+    let (cpu, _) = exec_fp(csrrw(FCSR as u32), 0x1_ff, MSTATUS_FS_INITIAL);
+    assert_eq!(cpu.load_csr(FCSR), 0xff);
+    assert_eq!(cpu.load_csr(FFLAGS), 0x1f);
+    assert_eq!(cpu.load_csr(FRM), 0b111);
+
+    let (mut cpu, _) = exec_fp(csrrw(FRM as u32), RM_RUP, MSTATUS_FS_INITIAL);
+    assert_eq!(cpu.load_csr(FCSR), RM_RUP << 5);
+    cpu.store_csr(FFLAGS, FFLAGS_DZ | 0x20);
+    assert_eq!(cpu.load_csr(FCSR), (RM_RUP << 5) | FFLAGS_DZ);
+}
+
+#[test]
+fn dynamic_rounding_mode_reads_frm() {
+    let mut cpu = run_with_a0(&[], 0);
+    cpu.store_csr(FRM, RM_RTZ);
+    assert_eq!(cpu.rounding_mode(RM_DYN).unwrap(), RM_RTZ);
+    assert_eq!(cpu.rounding_mode(RM_RMM).unwrap(), RM_RMM);
+    assert!(cpu.rounding_mode(0b101).is_err());
+    cpu.store_csr(FRM, RM_DYN);
+    assert!(cpu.rounding_mode(RM_DYN).is_err());
+}
+
+#[test]
+fn flags_accrue_until_cleared() {
+    let mut cpu = run_with_a0(&[], 0);
+    cpu.accrue_fflags(FFLAGS_NX);
+    cpu.accrue_fflags(FFLAGS_OF | FFLAGS_NV);
+    assert_eq!(cpu.load_csr(FFLAGS), FFLAGS_NX | FFLAGS_OF | FFLAGS_NV);
+    cpu.store_csr(FFLAGS, 0);
+    assert_eq!(cpu.load_csr(FCSR), 0);
+}
+
+#[test]
+fn integer_division_by_zero_sets_no_flag() {
+    // divuw a1,a0,zero
+    let cpu = run_with_a0(&[0x020555bb], 7);
+    assert_eq!(cpu.regs[11], u64::MAX);
+    assert_eq!(cpu.load_csr(FFLAGS), 0);
+}
+
+#[test]
+fn fp_csrs_need_f_and_fs() {
+    let illegal = |result| matches!(result, Err(Exception::IllegalInstruction));
+    for csr in [FFLAGS, FRM, FCSR] {
+        // F isn't implemented by default.
+        let mut cpu = new_cpu(&[csrrw(csr as u32)]);
+        cpu.store_csr(MSTATUS, MSTATUS_FS_INITIAL);
+        assert!(illegal(step(&mut cpu)));
+        assert_eq!(cpu.extensions.misa() & (1 << 5), 0);
+        // The floating-point state is Off.
+        let (_, result) = exec_fp(csrrw(csr as u32), 0, 0);
+        assert!(illegal(result));
+    }
+}
+
+#[test]
+fn writes_make_fs_dirty() {
+    let (cpu, result) = exec_fp(csrrw(FFLAGS as u32), FFLAGS_NX, MSTATUS_FS_INITIAL);
+    assert!(result.is_ok());
+    assert_eq!(cpu.load_csr(MSTATUS) & MSTATUS_FS, MSTATUS_FS);
+    // SD summarizes the Dirty state in mstatus and sstatus.
+    assert_eq!(cpu.load_csr(MSTATUS) & MSTATUS_SD, MSTATUS_SD);
+    assert_eq!(cpu.load_csr(SSTATUS) & MSTATUS_SD, MSTATUS_SD);
+    assert_eq!(cpu.extensions.misa() & (1 << 5), 1 << 5);
+}
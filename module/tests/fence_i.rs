@@ -0,0 +1,21 @@
+//! Tests of fence.i with self-modifying code.
+
+mod common;
+
+use common::run;
+
+/// fence.i
+const FENCE_I: u32 = 0x0000100f;
+
+#[test]
+fn fetch_after_fence_i_sees_stores() {
+    // Overwrite the nop at the end with addi a2,zero,42 (0x02a00613).
+    let cpu = run(&[
+        0x00000517, // auipc a0,0
+        0x02a005b7, // lui a1,0x2a00
+        0x61358593, // addi a1,a1,0x613
+        0x00b52a23, // sw a1,20(a0)
+        FENCE_I, 0x00000013, // nop
+    ]);
+    assert_eq!(cpu.regs[12], 42);
+}
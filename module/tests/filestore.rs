@@ -0,0 +1,97 @@
+//! Tests of the file export device writing guest memory to host files.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rvemu::bus::{DRAM_BASE, FILESTORE_BASE};
+use rvemu::cpu::Cpu;
+use rvemu::filestore::*;
+
+/// Create a directory in the temporary directory which is unique to this test.
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("rvemu-{}-{}", std::process::id(), name));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Return a cpu whose dram holds `data` at DRAM_BASE and `name` at DRAM_BASE + 0x100, with the
+/// device writing to `dir`.
+fn setup(dir: &Path, data: &[u8], name: &str) -> Cpu {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.bus
+        .set_filestore(Filestore::new(dir.to_str().unwrap()).unwrap());
+    for (i, byte) in data.iter().enumerate() {
+        cpu.bus
+            .store(DRAM_BASE + i as u64, 8, *byte as u64)
+            .unwrap();
+    }
+    for (i, byte) in name.bytes().chain([0]).enumerate() {
+        cpu.bus
+            .store(DRAM_BASE + 0x100 + i as u64, 8, byte as u64)
+            .unwrap();
+    }
+    cpu.bus.store(FILESTORE_ADDR, 64, DRAM_BASE).unwrap();
+    cpu.bus.store(FILESTORE_LEN, 64, data.len() as u64).unwrap();
+    cpu.bus
+        .store(FILESTORE_NAME, 64, DRAM_BASE + 0x100)
+        .unwrap();
+    cpu
+}
+
+#[test]
+fn memory_is_written_and_appended() {
+    let dir = temp_dir("filestore-write");
+    let mut cpu = setup(&dir, b"result: ok\n", "result.txt");
+    cpu.bus
+        .store(FILESTORE_CMD, 64, FILESTORE_CMD_WRITE)
+        .unwrap();
+    assert_eq!(
+        cpu.bus.load(FILESTORE_STATUS, 64).unwrap(),
+        FILESTORE_STATUS_OK
+    );
+    cpu.bus
+        .store(FILESTORE_CMD, 64, FILESTORE_CMD_APPEND)
+        .unwrap();
+    assert_eq!(
+        fs::read(dir.join("result.txt")).unwrap(),
+        b"result: ok\nresult: ok\n"
+    );
+
+    // Writing replaces the contents.
+    cpu.bus
+        .store(FILESTORE_CMD, 64, FILESTORE_CMD_WRITE)
+        .unwrap();
+    assert_eq!(fs::read(dir.join("result.txt")).unwrap(), b"result: ok\n");
+    fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn bad_requests_fail() {
+    let dir = temp_dir("filestore-errors");
+    let mut cpu = setup(&dir, b"data", "../escape");
+    cpu.bus
+        .store(FILESTORE_CMD, 64, FILESTORE_CMD_WRITE)
+        .unwrap();
+    assert_eq!(
+        cpu.bus.load(FILESTORE_STATUS, 64).unwrap(),
+        FILESTORE_STATUS_BAD_NAME
+    );
+
+    let mut cpu = setup(&dir, b"data", "data.bin");
+    cpu.bus.store(FILESTORE_CMD, 64, 7).unwrap();
+    assert_eq!(
+        cpu.bus.load(FILESTORE_STATUS, 64).unwrap(),
+        FILESTORE_STATUS_BAD_COMMAND
+    );
+    cpu.bus.store(FILESTORE_ADDR, 64, FILESTORE_BASE).unwrap();
+    cpu.bus
+        .store(FILESTORE_CMD, 64, FILESTORE_CMD_WRITE)
+        .unwrap();
+    assert_eq!(
+        cpu.bus.load(FILESTORE_STATUS, 64).unwrap(),
+        FILESTORE_STATUS_BAD_ADDRESS
+    );
+    assert!(!dir.join("data.bin").exists());
+    fs::remove_dir_all(dir).unwrap();
+}
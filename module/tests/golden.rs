@@ -0,0 +1,136 @@
+//! Golden-state regression tests. Each `tests/fixtures/<name>.bin` is a flat binary loaded at
+//! `DRAM_BASE` and run until it exits via the emulator control device. Its final registers, CSRs
+//! and hashes of the low dram are compared with `tests/fixtures/<name>.golden`, so any change of
+//! behavior in execute() or trap.rs shows up as a difference. The source of each fixture is next
+//! to it in `<name>.S`, and `make -C tests/fixtures` rebuilds the binaries from the sources with
+//! llvm-mc.
+//!
+//! A golden file is written if it doesn't exist. After an intended change of behavior, rewrite
+//! all of them and review the differences:
+//!
+//! ```text
+//! GOLDEN_BLESS=1 cargo test --test golden
+//! ```
+
+use std::env;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::*;
+use rvemu::disasm::csr_name;
+use rvemu::emulator::Emulator;
+
+/// The maximum number of instructions a fixture may execute.
+const MAX_STEPS: u64 = 100_000;
+/// The size of the dram from `DRAM_BASE` which is hashed.
+const MEMORY_SIZE: u64 = 0x10000;
+/// The size of a hashed block of the dram.
+const BLOCK_SIZE: u64 = 0x1000;
+/// The CSRs in the final state.
+const CSRS: [usize; 15] = [
+    MSTATUS, MEDELEG, MIDELEG, MIE, MIP, MTVEC, MEPC, MCAUSE, MTVAL, SSTATUS, STVEC, SEPC, SCAUSE,
+    STVAL, SATP,
+];
+
+/// Return the 64-bit FNV-1a hash of `bytes`.
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Run a fixture and return its final state in the format of golden files. `emu` is reset
+/// first, so fixtures also check that a reset CPU behaves the same as a new one.
+fn run(emu: &mut Emulator, binary: &[u8]) -> String {
+    emu.reset_with(binary);
+    let mut steps = 0;
+    while steps < MAX_STEPS && emu.step().is_some() {
+        steps += 1;
+    }
+
+    let cpu = &mut emu.cpu;
+    let mut state = String::new();
+    writeln!(state, "steps {}", steps).unwrap();
+    writeln!(state, "exit {:?}", cpu.bus.emuctl.exit_code()).unwrap();
+    writeln!(state, "pc {:#x}", cpu.pc).unwrap();
+    writeln!(state, "mode {:?}", cpu.mode).unwrap();
+    for (i, value) in cpu.regs.iter().enumerate() {
+        writeln!(state, "x{} {:#x}", i, value).unwrap();
+    }
+    for csr in CSRS.iter() {
+        writeln!(state, "{} {:#x}", csr_name(*csr as u64), cpu.load_csr(*csr)).unwrap();
+    }
+    for block in (DRAM_BASE..DRAM_BASE + MEMORY_SIZE).step_by(BLOCK_SIZE as usize) {
+        let bytes: Vec<u8> = (block..block + BLOCK_SIZE)
+            .map(|addr| cpu.bus.load(addr, 8).expect("the dram is readable") as u8)
+            .collect();
+        // Skip blocks which the fixture never touched.
+        if bytes.iter().any(|byte| *byte != 0) {
+            writeln!(state, "memory {:#x} {:016x}", block, fnv1a(&bytes)).unwrap();
+        }
+    }
+    state
+}
+
+/// Return the lines which differ between two states.
+fn diff(expected: &str, actual: &str) -> String {
+    let mut out = String::new();
+    for line in expected
+        .lines()
+        .filter(|l| !actual.lines().any(|a| a == *l))
+    {
+        writeln!(out, "- {}", line).unwrap();
+    }
+    for line in actual
+        .lines()
+        .filter(|l| !expected.lines().any(|e| e == *l))
+    {
+        writeln!(out, "+ {}", line).unwrap();
+    }
+    out
+}
+
+fn fixtures() -> Vec<PathBuf> {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir)
+        .expect("tests/fixtures exists")
+        .map(|entry| entry.expect("a directory entry").path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "bin"))
+        .collect();
+    paths.sort();
+    paths
+}
+
+#[test]
+fn fixtures_match_golden_states() {
+    let bless = env::var_os("GOLDEN_BLESS").is_some();
+    let mut failures = String::new();
+    let paths = fixtures();
+    assert!(!paths.is_empty(), "no fixtures in tests/fixtures");
+    let mut emu = Emulator::new(Cpu::new(Vec::new(), Vec::new()));
+    for path in paths {
+        let actual = run(&mut emu, &fs::read(&path).expect("a readable fixture"));
+        let golden = path.with_extension("golden");
+        match fs::read_to_string(&golden) {
+            Ok(expected) if !bless => {
+                if expected != actual {
+                    writeln!(
+                        failures,
+                        "{} differs from its golden state:\n{}",
+                        path.display(),
+                        diff(&expected, &actual)
+                    )
+                    .unwrap();
+                }
+            }
+            _ => fs::write(&golden, &actual).expect("a writable golden file"),
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "{}rerun with GOLDEN_BLESS=1 if the changes are intended",
+        failures
+    );
+}
@@ -0,0 +1,111 @@
+//! Tests of the guest heap checker hooking allocator functions.
+
+use rvemu::cpu::Cpu;
+use rvemu::elf::{Symbol, SymbolTable};
+use rvemu::heap_checker::*;
+
+const MALLOC: u64 = 0x8000_1000;
+const FREE: u64 = 0x8000_1100;
+const KFREE: u64 = 0x8000_1200;
+/// The return address of allocator calls.
+const CALLER: u64 = 0x8000_0080;
+const SP: u64 = 0x8800_0000;
+
+fn checker() -> HeapChecker {
+    let symbol = |name: &str, addr| Symbol {
+        name: name.to_string(),
+        addr,
+        size: 0x100,
+    };
+    HeapChecker::new(SymbolTable::new(vec![
+        symbol("main", 0x8000_0000),
+        symbol("malloc", MALLOC),
+        symbol("free", FREE),
+        symbol("kfree", KFREE),
+    ]))
+}
+
+/// Call `function` with a0 and return with a0 = `ret`, and return the reports.
+fn call(checker: &mut HeapChecker, cpu: &mut Cpu, function: u64, a0: u64, ret: u64) -> String {
+    let mut reports = String::new();
+    cpu.pc = function;
+    cpu.regs[1] = CALLER;
+    cpu.regs[2] = SP;
+    cpu.regs[10] = a0;
+    reports.extend(checker.check_call(cpu));
+    // A store by the allocator itself isn't checked.
+    reports.extend(checker.check_access(cpu, function + 4, sd(10, 0)));
+    cpu.pc = CALLER;
+    cpu.regs[10] = ret;
+    reports.extend(checker.check_call(cpu));
+    reports
+}
+
+/// sd zero,<offset>(<rs1>)
+fn sd(rs1: u64, offset: u64) -> u64 {
+    ((offset >> 5) << 25) | (rs1 << 15) | (0x3 << 12) | ((offset & 0x1f) << 7) | 0x23
+}
+
+/// ld a1,<offset>(<rs1>)
+fn ld(rs1: u64, offset: u64) -> u64 {
+    (offset << 20) | (rs1 << 15) | (0x3 << 12) | (11 << 7) | 0x03
+}
+
+#[test]
+fn hooked_functions() {
+    assert_eq!(checker().hooked(), vec!["malloc", "free", "kfree"]);
+}
+
+#[test]
+fn overflow_and_use_after_free_are_reported() {
+    let mut checker = checker();
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    assert_eq!(call(&mut checker, &mut cpu, MALLOC, 32, 0x8010_0000), "");
+    assert_eq!(call(&mut checker, &mut cpu, MALLOC, 32, 0x8010_0040), "");
+    assert_eq!(checker.live(), (2, 64));
+
+    cpu.regs[10] = 0x8010_0000;
+    assert_eq!(checker.check_access(&cpu, 0x8000_0010, sd(10, 24)), None);
+    let report = checker.check_access(&cpu, 0x8000_0014, sd(10, 32)).unwrap();
+    assert!(report.starts_with("heap: buffer overflow: store of 8 bytes at 0x80100020"));
+    assert!(report.contains("0 bytes after a block of 0x20 bytes at 0x80100000"));
+    assert!(report.contains("<main+0x80>"));
+    // The same instruction is reported once.
+    assert_eq!(checker.check_access(&cpu, 0x8000_0014, sd(10, 32)), None);
+
+    assert_eq!(call(&mut checker, &mut cpu, FREE, 0x8010_0000, 0), "");
+    cpu.regs[10] = 0x8010_0000;
+    let report = checker.check_access(&cpu, 0x8000_0018, ld(10, 8)).unwrap();
+    assert!(report.starts_with("heap: use after free: load of 8 bytes at 0x80100008"));
+    assert!(report.contains("0x8 bytes into a block of 0x20 bytes"));
+
+    // A new allocation over the freed block makes it live again.
+    assert_eq!(call(&mut checker, &mut cpu, MALLOC, 16, 0x8010_0000), "");
+    cpu.regs[10] = 0x8010_0000;
+    assert_eq!(checker.check_access(&cpu, 0x8000_001c, ld(10, 8)), None);
+    assert_eq!(checker.live(), (2, 48));
+}
+
+#[test]
+fn invalid_frees_are_reported() {
+    let mut checker = checker();
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    assert_eq!(call(&mut checker, &mut cpu, MALLOC, 8, 0x8010_0000), "");
+    assert_eq!(call(&mut checker, &mut cpu, FREE, 0x8010_0000, 0), "");
+    assert!(
+        call(&mut checker, &mut cpu, FREE, 0x8010_0000, 0).starts_with(
+            "heap: double free: free(0x80100000) by 0x80000080 <main+0x80>, freed by 0x80000080"
+        )
+    );
+    assert!(call(&mut checker, &mut cpu, FREE, 0x8020_0000, 0)
+        .starts_with("heap: invalid free: free(0x80200000)"));
+    assert_eq!(call(&mut checker, &mut cpu, FREE, 0, 0), "");
+
+    // A page given to kfree without kalloc fills the free list.
+    assert_eq!(call(&mut checker, &mut cpu, KFREE, 0x8030_0000, 0), "");
+    cpu.regs[10] = 0x8030_0000;
+    assert!(checker
+        .check_access(&cpu, 0x8000_0010, sd(10, 0x100))
+        .unwrap()
+        .starts_with("heap: use after free"));
+}
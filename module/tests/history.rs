@@ -0,0 +1,47 @@
+//! Tests of the circular buffer of the last executed instructions.
+
+use rvemu::history::History;
+
+/// addi a0,a0,1
+const ADDI_A0: u32 = 0x00150513;
+
+#[test]
+fn last_instructions_are_kept_in_a_circular_buffer() {
+    let mut history = History::new(3);
+    assert!(history.entries().is_empty());
+    history.record(0x8000_0000, Some(ADDI_A0));
+    history.record(0x8000_0004, None);
+    assert_eq!(
+        history.entries(),
+        vec![(0x8000_0000, Some(ADDI_A0)), (0x8000_0004, None)]
+    );
+    for pc in &[0x8000_0008, 0x8000_000c, 0x8000_0010] {
+        history.record(*pc, Some(ADDI_A0));
+    }
+    assert_eq!(history.recorded(), 5);
+    assert_eq!(
+        history
+            .entries()
+            .iter()
+            .map(|(pc, _)| *pc)
+            .collect::<Vec<_>>(),
+        vec![0x8000_0008, 0x8000_000c, 0x8000_0010]
+    );
+
+    let mut empty = History::new(0);
+    empty.record(0x8000_0000, Some(ADDI_A0));
+    assert!(empty.entries().is_empty());
+    assert_eq!(empty.recorded(), 1);
+}
+
+#[test]
+fn history_is_printed_with_disassembly() {
+    let mut history = History::new(4);
+    history.record(0x8000_0000, Some(ADDI_A0));
+    history.record(0x8000_0004, None);
+    assert_eq!(
+        history.to_string(),
+        "last 2 instructions:\n  0x0000000080000000: 00150513  addi a0,a0,1\n  \
+         0x0000000080000004: <fetch failed>\n"
+    );
+}
@@ -0,0 +1,78 @@
+//! Tests of ISA strings selecting the executed extensions.
+
+mod common;
+
+use common::{new_cpu, step};
+use rvemu::extensions::Extensions;
+use rvemu::trap::Exception;
+
+/// mul a2,a0,a1
+const MUL: u32 = 0x02b50633;
+/// rori a2,a0,8 (Zbb, Zbkb)
+const RORI: u32 = 0x60855613;
+/// csrrs a2,mscratch,zero
+const CSRR_MSCRATCH: u32 = 0x34002673;
+/// fence.i
+const FENCE_I: u32 = 0x0000100f;
+
+/// Execute `inst` with the extensions in `isa` and return the result.
+fn exec(isa: &str, inst: u32) -> Result<(), Exception> {
+    let mut cpu = new_cpu(&[inst]);
+    cpu.extensions = Extensions::parse(isa).unwrap();
+    step(&mut cpu)
+}
+
+#[test]
+fn isa_strings_are_parsed() {
+    let isa = Extensions::parse("RV64IMA_Zicsr_Zifencei").unwrap();
+    assert_eq!(isa.to_string(), "rv64ima_zicsr_zifencei");
+    // misa reports I, M, A, S and U.
+    assert_eq!(isa.misa(), 2 << 62 | 0x14_1101);
+
+    let isa = Extensions::parse("rv64ib_zks").unwrap();
+    assert_eq!(
+        isa.to_string(),
+        "rv64i_zba_zbb_zbs_zbkb_zbkc_zbkx_zksed_zksh"
+    );
+    assert_eq!(isa.misa() & (1 << 1), 1 << 1);
+    assert_eq!(
+        Extensions::parse(&Extensions::new().to_string()),
+        Ok(Extensions::new())
+    );
+
+    // F only brings its CSRs, so it's named explicitly.
+    let isa = Extensions::parse("rv64imaf").unwrap();
+    assert_eq!(isa.to_string(), "rv64imaf");
+    assert_eq!(isa.misa() & (1 << 5), 1 << 5);
+    assert_eq!(Extensions::new().misa() & (1 << 5), 0);
+
+    assert!(Extensions::parse("rv32imac").is_err());
+    assert!(Extensions::parse("rv64e").is_err());
+    assert!(Extensions::parse("rv64imac_zicsr").is_err());
+    assert!(Extensions::parse("rv64im_zfoo").is_err());
+}
+
+#[test]
+fn disabled_extensions_are_illegal() {
+    assert!(exec("rv64im", MUL).is_ok());
+    assert!(matches!(
+        exec("rv64i", MUL),
+        Err(Exception::IllegalInstruction)
+    ));
+    // rori belongs to both Zbb and Zbkb.
+    assert!(exec("rv64i_zbkb", RORI).is_ok());
+    assert!(exec("rv64i_zbb", RORI).is_ok());
+    assert!(matches!(
+        exec("rv64i_zba_zbs", RORI),
+        Err(Exception::IllegalInstruction)
+    ));
+    assert!(exec("rv64i_zicsr", CSRR_MSCRATCH).is_ok());
+    assert!(matches!(
+        exec("rv64i", CSRR_MSCRATCH),
+        Err(Exception::IllegalInstruction)
+    ));
+    assert!(matches!(
+        exec("rv64i_zicsr", FENCE_I),
+        Err(Exception::IllegalInstruction)
+    ));
+}
@@ -0,0 +1,58 @@
+//! Tests of reading the loadable segments of ELF files.
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::loader::{Image, ImageFormat};
+
+/// The size of the dram which segments must fit in.
+const DRAM_SIZE: u64 = 0x10_0000;
+
+/// Return a 64-bit little-endian ELF file with a loadable segment of `data` at `paddr`, which is
+/// `memsz` bytes in memory.
+fn elf(paddr: u64, data: &[u8], memsz: u64) -> Vec<u8> {
+    let mut file = vec![0; 64 + 56];
+    file[..6].copy_from_slice(b"\x7fELF\x02\x01");
+    file[0x18..0x20].copy_from_slice(&paddr.to_le_bytes());
+    // e_phoff, e_phentsize and e_phnum.
+    file[0x20..0x28].copy_from_slice(&64u64.to_le_bytes());
+    file[0x36..0x38].copy_from_slice(&56u16.to_le_bytes());
+    file[0x38..0x3a].copy_from_slice(&1u16.to_le_bytes());
+    // PT_LOAD with p_offset, p_paddr, p_filesz and p_memsz.
+    file[64..68].copy_from_slice(&1u32.to_le_bytes());
+    file[64 + 0x08..64 + 0x10].copy_from_slice(&120u64.to_le_bytes());
+    file[64 + 0x18..64 + 0x20].copy_from_slice(&paddr.to_le_bytes());
+    file[64 + 0x20..64 + 0x28].copy_from_slice(&(data.len() as u64).to_le_bytes());
+    file[64 + 0x28..64 + 0x30].copy_from_slice(&memsz.to_le_bytes());
+    file.extend_from_slice(data);
+    file
+}
+
+fn parse(file: &[u8]) -> Result<Image, std::io::Error> {
+    Image::parse_elf(file, &(DRAM_BASE..DRAM_BASE + DRAM_SIZE))
+}
+
+#[test]
+fn segments_are_zero_filled_up_to_memsz() {
+    let image = parse(&elf(DRAM_BASE + 0x100, &[1, 2, 3], 8)).unwrap();
+    assert_eq!(image.format, ImageFormat::Elf);
+    assert_eq!(image.entry, Some(DRAM_BASE + 0x100));
+    assert_eq!(image.segments.len(), 1);
+    assert_eq!(image.segments[0].addr, DRAM_BASE + 0x100);
+    assert_eq!(image.segments[0].data, [1, 2, 3, 0, 0, 0, 0, 0]);
+}
+
+#[test]
+fn segments_outside_the_dram_are_rejected() {
+    // A huge p_memsz fails without allocating the segment.
+    assert!(parse(&elf(DRAM_BASE, &[1], u64::MAX - DRAM_BASE)).is_err());
+    assert!(parse(&elf(DRAM_BASE, &[1], DRAM_SIZE + 1)).is_err());
+    assert!(parse(&elf(DRAM_BASE - 4, &[1], 4)).is_err());
+    assert!(parse(&elf(DRAM_BASE + DRAM_SIZE - 4, &[1], 4)).is_ok());
+}
+
+#[test]
+fn truncated_files_are_rejected() {
+    let mut file = elf(DRAM_BASE, &[1, 2, 3, 4], 4);
+    file.truncate(file.len() - 1);
+    assert!(parse(&file).is_err());
+    assert!(parse(&file[..60]).is_err());
+}
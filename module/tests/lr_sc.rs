@@ -0,0 +1,152 @@
+//! Tests of the reservation tracking of LR and SC.
+
+mod common;
+
+use common::{new_cpu, run, step};
+use rvemu::cpu::{Cpu, SC_LIVELOCK_FAILURES};
+use rvemu::trap::Exception;
+
+/// auipc a0,1: the reserved address is the page after the program.
+const AUIPC_A0: u32 = 0x00001517;
+/// addi a1,zero,5
+const LI_A1_5: u32 = 0x00500593;
+/// sd a1,0(a0)
+const SD_A1: u32 = 0x00b53023;
+
+/// Return an LR or an SC of a doubleword, `funct5` 0x02 or 0x03, from and to the address in a0.
+fn lr_sc(funct5: u32, rd: u32, rs2: u32) -> u32 {
+    (funct5 << 27) | (rs2 << 20) | (10 << 15) | (0x3 << 12) | (rd << 7) | 0x2f
+}
+
+#[test]
+fn sc_succeeds_after_lr() {
+    // lr.d t0,(a0); sc.d t1,a1,(a0)
+    let mut cpu = run(&[AUIPC_A0, LI_A1_5, lr_sc(0x02, 5, 0), lr_sc(0x03, 6, 11)]);
+    assert_eq!(cpu.regs[6], 0);
+    assert_eq!(cpu.load(cpu.regs[10], 64).unwrap(), 5);
+}
+
+#[test]
+fn store_invalidates_reservation() {
+    // lr.d t0,(a0); sd a1,0(a0); sc.d t1,zero,(a0)
+    let mut cpu = run(&[
+        AUIPC_A0,
+        LI_A1_5,
+        lr_sc(0x02, 5, 0),
+        SD_A1,
+        lr_sc(0x03, 6, 0),
+    ]);
+    assert_eq!(cpu.regs[6], 1);
+    assert_eq!(cpu.load(cpu.regs[10], 64).unwrap(), 5);
+}
+
+#[test]
+fn sc_consumes_reservation() {
+    // sc.d t1,a1,(a0) without lr, then lr.d t0,(a0); sc.d t2,a1,(a0); sc.d t3,a1,(a0)
+    let cpu = run(&[
+        AUIPC_A0,
+        LI_A1_5,
+        lr_sc(0x03, 6, 11),
+        lr_sc(0x02, 5, 0),
+        lr_sc(0x03, 7, 11),
+        lr_sc(0x03, 28, 11),
+    ]);
+    assert_eq!(cpu.regs[6], 1);
+    assert_eq!(cpu.regs[7], 0);
+    assert_eq!(cpu.regs[28], 1);
+}
+
+/// Execute `program` with the atomic checks and return the cpu or the first exception.
+fn run_strict(program: &[u32]) -> Result<Cpu, Exception> {
+    let mut cpu = new_cpu(program);
+    cpu.strict_atomics = true;
+    for _ in program {
+        step(&mut cpu)?;
+    }
+    Ok(cpu)
+}
+
+#[test]
+fn strict_sc_must_pair_with_lr() {
+    // lr.d t0,(a0); sc.w t1,a1,(a0): the reservation contains the word.
+    let program = [
+        AUIPC_A0,
+        LI_A1_5,
+        lr_sc(0x02, 5, 0),
+        lr_sc(0x03, 6, 11) & !(1 << 12),
+    ];
+    assert_eq!(run(&program).regs[6], 0);
+    let cpu = run_strict(&program).unwrap();
+    assert_eq!(cpu.regs[6], 1);
+    assert_eq!(
+        cpu.unpaired_sc.as_deref(),
+        Some("atomics: sc.w to 0x80001000 at 0x8000000c doesn't pair with lr.d to 0x80001000")
+    );
+
+    // sc.d t1,a1,(a0) without lr
+    let cpu = run_strict(&[AUIPC_A0, lr_sc(0x03, 6, 11)]).unwrap();
+    assert_eq!(cpu.regs[6], 1);
+    assert!(cpu.unpaired_sc.is_some());
+}
+
+#[test]
+fn strict_atomics_must_be_aligned() {
+    // addi a0,a0,4; amoadd.d zero,a1,(a0)
+    let amo = [AUIPC_A0, 0x00450513, 0x00b5302f];
+    assert_eq!(run(&amo).regs[10], 0x8000_1004);
+    assert!(matches!(
+        run_strict(&amo),
+        Err(Exception::StoreAMOAddressMisaligned(_))
+    ));
+    // The word AMO at the same address is aligned.
+    assert!(run_strict(&[AUIPC_A0, 0x00450513, 0x00b5202f]).is_ok());
+    // addi a0,a0,4; lr.d t0,(a0) and sc.d t1,a1,(a0)
+    assert!(matches!(
+        run_strict(&[AUIPC_A0, 0x00450513, lr_sc(0x02, 5, 0)]),
+        Err(Exception::LoadAddressMisaligned(_))
+    ));
+    assert!(matches!(
+        run_strict(&[AUIPC_A0, 0x00450513, lr_sc(0x03, 6, 11)]),
+        Err(Exception::StoreAMOAddressMisaligned(_))
+    ));
+}
+
+#[test]
+fn failing_sc_loop_is_reported_as_livelock() {
+    // 1: lr.d t0,(a0); sd a1,0(a0); sc.d t1,a1,(a0); bnez t1,1b
+    let program = [
+        AUIPC_A0,
+        LI_A1_5,
+        lr_sc(0x02, 5, 0),
+        SD_A1,
+        lr_sc(0x03, 6, 11),
+        0xfe031ae3,
+    ];
+    let mut cpu = new_cpu(&program);
+    let mut steps = 0;
+    while cpu.sc_livelock.is_none() {
+        step(&mut cpu).unwrap();
+        steps += 1;
+        assert!(steps < 2 + 4 * SC_LIVELOCK_FAILURES);
+    }
+    assert_eq!(cpu.sc_failures, SC_LIVELOCK_FAILURES);
+    assert_eq!(
+        cpu.sc_livelock.as_deref(),
+        Some("atomics: sc at 0x80000010 failed 1000 times in a row (1000 failed scs in total)")
+    );
+}
+
+#[test]
+fn successful_sc_resets_the_streak() {
+    // lr.d t0,(a0); sc.d t1,a1,(a0) after a failed sc.d t1,a1,(a0)
+    let cpu = run(&[
+        AUIPC_A0,
+        LI_A1_5,
+        lr_sc(0x03, 6, 11),
+        lr_sc(0x02, 5, 0),
+        lr_sc(0x03, 6, 11),
+    ]);
+    assert_eq!(cpu.regs[6], 0);
+    assert_eq!(cpu.sc_failures, 1);
+    assert!(cpu.sc_livelock.is_none());
+}
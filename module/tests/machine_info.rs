@@ -0,0 +1,50 @@
+//! Tests of the read-only machine information CSRs.
+
+mod common;
+
+use common::{new_cpu, step};
+use rvemu::cpu::*;
+use rvemu::trap::Exception;
+
+/// Return `op a1,<csr>,a0` of a CSR instruction with `funct3`.
+fn csr_inst(csr: usize, funct3: u32) -> u32 {
+    ((csr as u32) << 20) | (10 << 15) | (funct3 << 12) | (11 << 7) | 0x73
+}
+
+/// Execute `inst` with a0 = `a0` and return a1, or the exception.
+fn exec(inst: u32, a0: u64) -> Result<u64, Exception> {
+    let mut cpu = new_cpu(&[inst]);
+    cpu.regs[10] = a0;
+    cpu.regs[11] = 0xdead;
+    step(&mut cpu).map(|_| cpu.regs[11])
+}
+
+#[test]
+fn information_registers_are_readable() {
+    // csrrs a1,<csr>,zero
+    assert_eq!(exec(csr_inst(MHARTID, 2) & !(0x1f << 15), 0).unwrap(), 0);
+    assert_eq!(exec(csr_inst(MVENDORID, 2) & !(0x1f << 15), 0).unwrap(), 0);
+    assert_eq!(exec(csr_inst(MARCHID, 2) & !(0x1f << 15), 0).unwrap(), 0);
+    // Version 0.1.0.
+    assert_eq!(
+        exec(csr_inst(MIMPID, 2) & !(0x1f << 15), 0).unwrap(),
+        0x00_01_00
+    );
+    // csrrs with rs1 other than zero is a write even if the value is zero.
+    assert!(matches!(
+        exec(csr_inst(MHARTID, 2), 0),
+        Err(Exception::IllegalInstruction)
+    ));
+}
+
+#[test]
+fn writes_are_rejected() {
+    for funct3 in [1, 2, 3, 5, 6, 7] {
+        let result = exec(csr_inst(MHARTID, funct3), 1);
+        assert!(
+            matches!(result, Err(Exception::IllegalInstruction)),
+            "funct3 {}",
+            funct3
+        );
+    }
+}
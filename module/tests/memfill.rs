@@ -0,0 +1,47 @@
+//! Tests of the initial contents of the dram.
+
+use rvemu::bus::{Bus, MemFill, DRAM_BASE};
+
+#[test]
+fn parse_memfill() {
+    assert_eq!(MemFill::parse("zero"), Ok(MemFill::Zero));
+    assert_eq!(MemFill::parse("pattern"), Ok(MemFill::Pattern));
+    assert_eq!(MemFill::parse("random(42)"), Ok(MemFill::Random(42)));
+    assert_eq!(MemFill::parse("random(0x2a)"), Ok(MemFill::Random(42)));
+    assert!(MemFill::parse("random").is_err());
+    assert!(MemFill::parse("random(x)").is_err());
+}
+
+#[test]
+fn pattern_keeps_images() {
+    let mut bus = Bus::new(vec![1, 2, 3], Vec::new());
+    bus.write_image(DRAM_BASE + 0x2000, &[4]);
+    bus.set_memfill(MemFill::Pattern);
+    assert_eq!(bus.load(DRAM_BASE, 32).unwrap(), 0xde03_0201);
+    assert_eq!(bus.load(DRAM_BASE + 0x2000, 32).unwrap(), 0xdead_be04);
+    assert_eq!(
+        bus.load(DRAM_BASE + 0x1000, 64).unwrap(),
+        0xdeadbeef_deadbeef
+    );
+    let end = bus.dram_range().end;
+    assert_eq!(bus.load(end - 4, 32).unwrap(), 0xdeadbeef);
+}
+
+#[test]
+fn random_fill_is_reproducible() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.set_memfill(MemFill::Random(7));
+    let first = bus.load(DRAM_BASE + 0x3008, 64).unwrap();
+    assert_ne!(first, 0);
+    assert_ne!(first, bus.load(DRAM_BASE + 0x4008, 64).unwrap());
+
+    // A reset fills the written pages the same way.
+    bus.store(DRAM_BASE + 0x3008, 64, 0).unwrap();
+    bus.reset(&[0x13, 0, 0, 0]);
+    assert_eq!(bus.load(DRAM_BASE + 0x3008, 64).unwrap(), first);
+    assert_eq!(bus.load(DRAM_BASE, 32).unwrap(), 0x13);
+
+    let mut other = Bus::new(Vec::new(), Vec::new());
+    other.set_memfill(MemFill::Random(7));
+    assert_eq!(other.load(DRAM_BASE + 0x3008, 64).unwrap(), first);
+}
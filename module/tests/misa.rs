@@ -0,0 +1,30 @@
+//! Tests of misa built from the implemented extensions.
+
+mod common;
+
+use common::run_with_a0;
+use rvemu::cpu::*;
+
+/// csrrw a1,misa,a0
+const CSRRW_MISA: u32 = 0x301515f3;
+
+#[test]
+fn misa_reports_rv64imabsu() {
+    // MXL = 2 and the letters A, B, I, M, S and U.
+    let misa = 2 << 62 | 1 << 0 | 1 << 1 | 1 << 8 | 1 << 12 | 1 << 18 | 1 << 20;
+    let cpu = run_with_a0(&[CSRRW_MISA], 0);
+    assert_eq!(cpu.regs[11], misa);
+    // The write is ignored.
+    assert_eq!(cpu.load_csr(MISA), misa);
+}
+
+#[test]
+fn misa_follows_extensions() {
+    let mut cpu = run_with_a0(&[], 0);
+    cpu.extensions.m = false;
+    cpu.extensions.zbs = false;
+    assert_eq!(
+        cpu.load_csr(MISA),
+        2 << 62 | 1 << 0 | 1 << 8 | 1 << 18 | 1 << 20
+    );
+}
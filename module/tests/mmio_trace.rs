@@ -0,0 +1,55 @@
+//! Tests of the MMIO access logger of the bus.
+
+use rvemu::bus::{Bus, MmioAccess, DRAM_BASE, PLIC_BASE, VIRTIO_BASE};
+
+#[test]
+fn selected_devices_are_logged_by_register_name() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.set_mmio_trace("plic,virtio").unwrap();
+    bus.store(PLIC_BASE + 0x2080, 32, 0x402).unwrap();
+    bus.store(DRAM_BASE, 64, 1).unwrap();
+    bus.load(PLIC_BASE + 0x201004, 32).unwrap();
+    assert!(bus.load(VIRTIO_BASE + 0x50, 64).is_err());
+
+    let log = bus.take_mmio_log();
+    assert_eq!(
+        log[0],
+        MmioAccess {
+            device: "plic",
+            offset: 0x2080,
+            register: Some("senable"),
+            store: true,
+            size: 32,
+            value: Some(0x402),
+        }
+    );
+    let lines: Vec<String> = log.iter().map(|access| access.to_string()).collect();
+    assert_eq!(
+        lines,
+        [
+            "plic.senable store32 0x402",
+            "plic.sclaim load32 0x0",
+            "virtio.QueueNotify load64 fault",
+        ]
+    );
+    assert!(bus.take_mmio_log().is_empty());
+}
+
+#[test]
+fn unknown_device_is_rejected() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    assert_eq!(
+        bus.set_mmio_trace("uart,disk"),
+        Err("unknown device `disk`".to_string())
+    );
+}
+
+#[test]
+fn quad_word_accesses_are_logged_as_faults() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.set_mmio_trace("plic").unwrap();
+    assert!(bus.store(PLIC_BASE, 128, u64::MAX).is_err());
+    let lines: Vec<String> = bus.take_mmio_log().iter().map(|a| a.to_string()).collect();
+    assert_eq!(lines.len(), 1);
+    assert!(lines[0].ends_with("store128 fault"), "{}", lines[0]);
+}
@@ -0,0 +1,81 @@
+//! Tests of the console multiplexer switching between the console and the monitor.
+
+mod common;
+
+use std::io;
+use std::io::prelude::*;
+use std::sync::{Arc, Mutex};
+
+use common::{new_cpu, step};
+use rvemu::mux::Mux;
+use rvemu::qmp::Control;
+
+/// Echo every byte received by the UART:
+///     lui t0,0x10000
+/// 1:  lbu a0,5(t0); andi a0,a0,1; beqz a0,1b
+///     lbu a0,0(t0); sb a0,0(t0); j 1b
+const ECHO: [u32; 7] = [
+    0x100002b7, 0x0052c503, 0x00157513, 0xfe050ce3, 0x0002c503, 0x00a28023, 0xfedff06f,
+];
+
+/// A writer whose bytes are kept for the test.
+struct Capture(Arc<Mutex<Vec<u8>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Run the echo program with the multiplexer reading `input` until the monitor quits or the
+/// output contains `until` unless it's empty. Return the output and true if the monitor quit.
+fn run(input: &'static [u8], until: &str) -> (String, bool) {
+    let mut cpu = new_cpu(&ECHO);
+    let output = Arc::new(Mutex::new(Vec::new()));
+    let mut mux = Mux::attach(
+        &mut cpu,
+        Box::new(input),
+        Box::new(Capture(Arc::clone(&output))),
+    );
+    let text = || String::from_utf8_lossy(&output.lock().unwrap()).to_string();
+    for _ in 0..50_000_000 {
+        if mux.poll(&mut cpu) == Control::Quit {
+            return (text(), true);
+        }
+        if !until.is_empty() && text().contains(until) {
+            break;
+        }
+        step(&mut cpu).unwrap();
+    }
+    (text(), false)
+}
+
+#[test]
+fn console_input_reaches_the_guest() {
+    // Ctrl-A Ctrl-A sends Ctrl-A itself.
+    let (output, quit) = run(b"ab\x01\x01c", "ab\x01c");
+    assert!(!quit);
+    assert!(output.ends_with("ab\x01c"), "{:?}", output);
+}
+
+#[test]
+fn monitor_runs_commands() {
+    let (output, quit) = run(b"\x01c\ninfo status\nx 80000000\nbogus\nquit\n", "");
+    assert!(quit);
+    assert!(output.contains("monitor - type 'help'"), "{:?}", output);
+    assert!(output.contains("VM status: running"), "{:?}", output);
+    assert!(output.contains("0x0000000080000000: 0x0052c503100002b7"));
+    assert!(output.contains("unknown command: 'bogus'"));
+}
+
+#[test]
+fn escape_quits() {
+    let (output, quit) = run(b"\x01x", "");
+    assert!(quit);
+    assert!(output.contains("terminating"));
+}
@@ -0,0 +1,227 @@
+//! Tests of the virtual address translation with the SV39, SV48 and SV57 paging, its permission
+//! checks and MPRV.
+
+mod common;
+
+use common::{new_cpu, step};
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::*;
+use rvemu::trap::{Exception, Trap};
+
+/// csrrw zero,satp,a0
+const CSRW_SATP: u32 = 0x18051073;
+
+const PAGE_SIZE: u64 = 4096;
+const ROOT: u64 = DRAM_BASE + 0x10_0000;
+const PAGE: u64 = DRAM_BASE + 0x20_0000;
+
+const V: u64 = 1 << 0;
+const R: u64 = 1 << 1;
+const W: u64 = 1 << 2;
+const X: u64 = 1 << 3;
+const U: u64 = 1 << 4;
+
+/// Return the virtual address of the VPN fields from the highest, and `offset`.
+fn va(vpns: &[u64], offset: u64) -> u64 {
+    vpns.iter().fold(0, |va, vpn| (va << 9) | vpn) << 12 | offset
+}
+
+/// Write a PTE pointing to `pa` at `index` of the page table at `table`.
+fn map(cpu: &mut Cpu, table: u64, index: u64, pa: u64, flags: u64) {
+    cpu.bus
+        .store(table + index * 8, 64, ((pa / PAGE_SIZE) << 10) | flags)
+        .unwrap();
+}
+
+/// Return a cpu in S-mode with page tables of 5 levels from `ROOT`, and paging of satp MODE
+/// `mode` enabled.
+/// SV39 and SV48 share the root table of the fourth level.
+fn cpu(mode: u64) -> Cpu {
+    let mut cpu = new_cpu(&[CSRW_SATP]);
+    let table = |level: u64| ROOT + level * PAGE_SIZE;
+    // 5-6-7-8 is a 4 KiB page and 5-9 is a 1 GiB superpage in SV48.
+    map(&mut cpu, table(3), 5, table(2), V);
+    map(&mut cpu, table(2), 6, table(1), V);
+    map(&mut cpu, table(1), 7, table(0), V);
+    map(&mut cpu, table(0), 8, PAGE, V | R | W);
+    map(&mut cpu, table(2), 9, DRAM_BASE, V | R);
+    // 6-7-8 is the same 4 KiB page in SV39 from the second table.
+    map(&mut cpu, table(3), 6, table(1), V);
+    // 4-5-6-7-8 is the same 4 KiB page in SV57.
+    map(&mut cpu, table(4), 4, table(3), V);
+
+    let root = match mode {
+        10 => table(4),
+        _ => table(3),
+    };
+    cpu.regs[10] = (mode << 60) | (root / PAGE_SIZE);
+    step(&mut cpu).unwrap();
+    // M-mode accesses aren't translated.
+    cpu.mode = Mode::Supervisor;
+    cpu
+}
+
+/// Return the value of 64 bits loaded from a virtual address, after writing `value` to the
+/// physical address `pa`.
+fn load(cpu: &mut Cpu, addr: u64, pa: u64, value: u64) -> Option<u64> {
+    cpu.bus.store(pa, 64, value).unwrap();
+    cpu.load(addr, 64).ok()
+}
+
+#[test]
+fn sv48_walks_four_levels() {
+    let mut cpu = cpu(9);
+    assert_eq!(cpu.page_levels, 4);
+    let addr = va(&[5, 6, 7, 8], 0x120);
+    assert_eq!(load(&mut cpu, addr, PAGE + 0x120, 1), Some(1));
+
+    // A leaf at the second level is a 1 GiB superpage.
+    let addr = va(&[5, 9, 0x12, 0x34], 0x568);
+    let pa = DRAM_BASE + (0x12 << 21) + (0x34 << 12) + 0x568;
+    assert_eq!(load(&mut cpu, addr, pa, 2), Some(2));
+
+    assert_eq!(load(&mut cpu, va(&[4, 6, 7, 8], 0), PAGE, 3), None);
+    // The entry for SV39 makes the page a 2 MiB superpage in SV48.
+    let addr = va(&[6, 7, 8, 0x10], 0x8);
+    assert_eq!(load(&mut cpu, addr, PAGE + 0x10_008, 4), Some(4));
+}
+
+#[test]
+fn sv39_walks_three_levels() {
+    let mut cpu = cpu(8);
+    assert_eq!(cpu.page_levels, 3);
+    // The root table of SV48 is the root of SV39, where 6 points to the table of 7.
+    let addr = va(&[6, 7, 8], 0x120);
+    assert_eq!(load(&mut cpu, addr, PAGE + 0x120, 5), Some(5));
+    assert_eq!(load(&mut cpu, va(&[5, 6, 7], 0), PAGE, 6), None);
+}
+
+#[test]
+fn sv57_walks_five_levels() {
+    let mut cpu = cpu(10);
+    assert_eq!(cpu.page_levels, 5);
+    let addr = va(&[4, 5, 6, 7, 8], 0x120);
+    assert_eq!(load(&mut cpu, addr, PAGE + 0x120, 7), Some(7));
+    let addr = va(&[4, 5, 9, 0x12, 0x34], 0x568);
+    let pa = DRAM_BASE + (0x12 << 21) + (0x34 << 12) + 0x568;
+    assert_eq!(load(&mut cpu, addr, pa, 8), Some(8));
+    // The same addresses without the fifth level aren't mapped.
+    assert_eq!(
+        load(&mut cpu, va(&[5, 6, 7, 8], 0x120), PAGE + 0x120, 9),
+        None
+    );
+}
+
+#[test]
+fn machine_mode_accesses_are_not_translated() {
+    let mut cpu = cpu(9);
+    cpu.mode = Mode::Machine;
+    assert_eq!(load(&mut cpu, PAGE + 0x120, PAGE + 0x120, 10), Some(10));
+}
+
+/// Return true if an access of `kind` in `mode` with `mstatus` to a 4 KiB page of `flags` succeeds.
+fn permits(flags: u64, mode: Mode, mstatus: u64, kind: char) -> bool {
+    let mut cpu = cpu(9);
+    let table0 = ROOT;
+    map(&mut cpu, table0, 9, PAGE, flags);
+    cpu.mode = mode;
+    cpu.store_csr(MSTATUS, mstatus);
+    let addr = va(&[5, 6, 7, 9], 0);
+    match kind {
+        'r' => cpu.load(addr, 64).is_ok(),
+        'w' => cpu.store(addr, 64, 0).is_ok(),
+        _ => {
+            cpu.pc = addr;
+            cpu.fetch().is_ok()
+        }
+    }
+}
+
+#[test]
+fn leaf_permissions_are_checked() {
+    let s = Mode::Supervisor;
+    assert!(permits(V | R, s, 0, 'r'));
+    assert!(!permits(V | R, s, 0, 'w'));
+    assert!(!permits(V | R, s, 0, 'x'));
+    assert!(permits(V | R | W, s, 0, 'w'));
+    assert!(permits(V | X, s, 0, 'x'));
+    // MXR makes executable pages readable.
+    assert!(!permits(V | X, s, 0, 'r'));
+    assert!(permits(V | X, s, MSTATUS_MXR, 'r'));
+}
+
+#[test]
+fn user_pages_are_checked_against_the_privilege_mode() {
+    let (s, u) = (Mode::Supervisor, Mode::User);
+    let rwx = V | R | W | X;
+    assert!(!permits(rwx, u, 0, 'r'));
+    assert!(permits(rwx | U, u, 0, 'r'));
+    assert!(permits(rwx | U, u, 0, 'x'));
+    // S-mode accesses user pages only with SUM, and never executes them.
+    assert!(!permits(rwx | U, s, 0, 'r'));
+    assert!(permits(rwx | U, s, MSTATUS_SUM, 'r'));
+    assert!(permits(rwx | U, s, MSTATUS_SUM, 'w'));
+    assert!(!permits(rwx | U, s, MSTATUS_SUM, 'x'));
+}
+
+#[test]
+fn mprv_translates_data_accesses_as_mpp() {
+    let (m, rwx) = (Mode::Machine, V | R | W | X);
+    let mpp_s = MSTATUS_MPRV | (1 << 11);
+    let mpp_u = MSTATUS_MPRV;
+    assert!(permits(rwx, m, mpp_s, 'r'));
+    assert!(permits(rwx, m, mpp_s, 'w'));
+    assert!(!permits(rwx, m, mpp_u, 'r'));
+    assert!(permits(rwx | U, m, mpp_u, 'w'));
+    // SUM applies to the mode of MPP.
+    assert!(!permits(rwx | U, m, mpp_s, 'r'));
+    assert!(permits(rwx | U, m, mpp_s | MSTATUS_SUM, 'r'));
+
+    // Instruction fetches aren't affected: the pc is a physical address.
+    let mut cpu = cpu(9);
+    cpu.mode = Mode::Machine;
+    cpu.store_csr(MSTATUS, mpp_u);
+    cpu.pc = PAGE;
+    assert!(cpu.fetch().is_ok());
+}
+
+#[test]
+fn mprv_translates_as_the_mode_a_trap_came_from() {
+    let mut cpu = cpu(9);
+    Exception::EnvironmentCallFromSMode.take_trap(&mut cpu);
+    assert_eq!(cpu.mode, Mode::Machine);
+    assert_eq!((cpu.load_csr(MSTATUS) >> 11) & 0b11, 0b01);
+
+    // The handler reads the caller's memory through its virtual address.
+    cpu.store_csr(MSTATUS, cpu.load_csr(MSTATUS) | MSTATUS_MPRV);
+    let addr = va(&[5, 6, 7, 8], 0x18);
+    assert_eq!(load(&mut cpu, addr, PAGE + 0x18, 0x1234), Some(0x1234));
+}
+
+#[test]
+fn returning_below_machine_mode_clears_mprv() {
+    // mret
+    const MRET: u64 = 0x30200073;
+    for (mpp, mprv) in [(3, MSTATUS_MPRV), (1, 0), (0, 0)].iter() {
+        let mut cpu = new_cpu(&[]);
+        cpu.store_csr(MSTATUS, MSTATUS_MPRV | (mpp << 11));
+        cpu.execute(MRET).unwrap();
+        assert_eq!(cpu.load_csr(MSTATUS) & MSTATUS_MPRV, *mprv);
+    }
+}
+
+#[test]
+fn page_faults_hold_the_virtual_address() {
+    let mut cpu = cpu(9);
+    let addr = va(&[4, 6, 7, 8], 0x10);
+    assert!(matches!(
+        cpu.load(addr, 64),
+        Err(Exception::LoadPageFault(a)) if a == addr
+    ));
+    // The store to the read-only superpage.
+    let addr = va(&[5, 9, 0, 0], 0x20);
+    assert!(matches!(
+        cpu.store(addr, 64, 0),
+        Err(Exception::StoreAMOPageFault(a)) if a == addr
+    ));
+}
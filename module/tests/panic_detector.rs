@@ -0,0 +1,38 @@
+//! Tests of the detection of guest kernel panics.
+
+use std::io::Write;
+
+use rvemu::cpu::Cpu;
+use rvemu::panic_detector::{PanicAction, PanicDetector};
+
+#[test]
+fn console_panic_line() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut detector = PanicDetector::new(PanicAction::Stop);
+    let mut tap = detector.console_tap();
+    tap.write_all(b"init: starting sh\r\n$ panic: ").unwrap();
+    // The report waits for the whole line.
+    assert_eq!(detector.check(&mut cpu), None);
+    tap.write_all(b"acquire\n").unwrap();
+    assert_eq!(
+        detector.check(&mut cpu).as_deref(),
+        Some("panic: detected on the console: $ panic: acquire")
+    );
+    assert_eq!(detector.check(&mut cpu), None);
+    assert_eq!(
+        detector.context(),
+        "console: init: starting sh\nconsole: $ panic: acquire\n"
+    );
+}
+
+#[test]
+fn linux_oops_banner() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut detector = PanicDetector::new(PanicAction::Warn);
+    let mut tap = detector.console_tap();
+    tap.write_all(b"[    1.234] Unable to handle kernel NULL pointer dereference\n")
+        .unwrap();
+    tap.write_all(b"[    1.235] Oops [#1]\n").unwrap();
+    assert!(detector.check(&mut cpu).unwrap().ends_with("dereference"));
+    assert!(detector.check(&mut cpu).unwrap().ends_with("Oops [#1]"));
+}
@@ -0,0 +1,45 @@
+//! Tests of the hazard accounting of the five-stage pipeline model.
+
+mod common;
+
+use common::new_cpu;
+use rvemu::pipeline::{Pipeline, PipelineStats};
+
+/// Execute `count` instructions of `program` and return the statistics of the pipeline model.
+fn run(program: &[u32], count: usize) -> PipelineStats {
+    let mut cpu = new_cpu(program);
+    let mut pipeline = Pipeline::new();
+    for _ in 0..count {
+        let pc = cpu.pc;
+        let inst = cpu.fetch().unwrap();
+        cpu.pc += 4;
+        cpu.execute(inst).unwrap();
+        pipeline.instruction(&cpu, pc, inst).unwrap();
+    }
+    pipeline.finish().unwrap()
+}
+
+#[test]
+fn no_hazards() {
+    // addi a0,zero,1; addi a1,a0,1; addi a2,a1,1
+    let stats = run(&[0x00100513, 0x00150593, 0x00158613], 3);
+    // Forwarding avoids stalls, so the pipeline is filled in 4 cycles.
+    assert_eq!(stats.cycles, 3 + 4);
+    assert_eq!(stats.load_use_stalls, 0);
+}
+
+#[test]
+fn load_use_stall() {
+    // auipc a0,0; ld a1,0(a0); addi a2,a1,1
+    let stats = run(&[0x00000517, 0x00053583, 0x00158613], 3);
+    assert_eq!(stats.load_use_stalls, 1);
+    assert_eq!(stats.cycles, 3 + 4 + 1);
+}
+
+#[test]
+fn taken_branch_flush() {
+    // jal zero,.+8; addi a0,zero,1; addi a1,zero,1
+    let stats = run(&[0x0080006f, 0x00100513, 0x00100593], 2);
+    assert_eq!(stats.branch_bubbles, 2);
+    assert_eq!(stats.cycles, 2 + 4 + 2);
+}
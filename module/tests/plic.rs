@@ -0,0 +1,93 @@
+//! Tests of the PLIC claim/complete and the external interrupt pending bits of mip.
+
+use rvemu::bus::PLIC_BASE;
+use rvemu::cpu::*;
+
+mod common;
+use common::{new_cpu, step};
+
+const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
+const PLIC_SPRIORITY: u64 = PLIC_BASE + 0x201000;
+const PLIC_SCLAIM: u64 = PLIC_BASE + 0x201004;
+
+fn claim(cpu: &mut Cpu) -> u64 {
+    cpu.bus.load(PLIC_SCLAIM, 32).unwrap()
+}
+
+fn complete(cpu: &mut Cpu, irq: u64) {
+    cpu.bus.store(PLIC_SCLAIM, 32, irq).unwrap();
+}
+
+#[test]
+fn seip_follows_enabled_pending_irqs() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.bus.raise_irq(10);
+    assert_eq!(cpu.load_csr(MIP) & MIP_SEIP, 0);
+    assert_eq!(claim(&mut cpu), 0);
+
+    cpu.bus.store(PLIC_SENABLE, 32, 1 << 10).unwrap();
+    assert_eq!(cpu.load_csr(MIP) & MIP_SEIP, MIP_SEIP);
+    // A non-zero threshold masks every source, whose priority is 1.
+    cpu.bus.store(PLIC_SPRIORITY, 32, 1).unwrap();
+    assert_eq!(cpu.load_csr(MIP) & MIP_SEIP, 0);
+    cpu.bus.store(PLIC_SPRIORITY, 32, 0).unwrap();
+
+    // Software can't set MEIP or clear the SEIP driven by the PLIC.
+    cpu.store_csr(MIP, 0);
+    assert_eq!(cpu.load_csr(MIP), MIP_SEIP);
+    cpu.store_csr(MIP, MIP_MEIP | MIP_SSIP);
+    assert_eq!(cpu.load_csr(MIP), MIP_SEIP | MIP_SSIP);
+
+    assert_eq!(claim(&mut cpu), 10);
+    assert_eq!(cpu.load_csr(MIP), MIP_SSIP);
+}
+
+#[test]
+fn seip_is_the_software_bit_ored_with_the_plic() {
+    let mut cpu = new_cpu(&[
+        0x3442_a373, // csrrs t1, mip, t0
+    ]);
+    // M-mode software can set SEIP, which stays pending without the PLIC.
+    cpu.store_csr(MIP, MIP_SEIP);
+    assert_eq!(cpu.load_csr(MIP), MIP_SEIP);
+    cpu.store_csr(MIP, 0);
+    assert_eq!(cpu.load_csr(MIP), 0);
+
+    // csrrs doesn't latch the PLIC output into the software bit.
+    cpu.bus.store(PLIC_SENABLE, 32, 1 << 10).unwrap();
+    cpu.bus.raise_irq(10);
+    cpu.regs[5] = MIP_SSIP;
+    step(&mut cpu).unwrap();
+    assert_eq!(cpu.regs[6], MIP_SEIP);
+    assert_eq!(claim(&mut cpu), 10);
+    assert_eq!(cpu.load_csr(MIP), MIP_SSIP);
+}
+
+#[test]
+fn irq_raised_in_service_is_redelivered_after_completion() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.bus
+        .store(PLIC_SENABLE, 32, (1 << 1) | (1 << 10))
+        .unwrap();
+    cpu.bus.raise_irq(10);
+    cpu.bus.raise_irq(1);
+    // The lowest ID is claimed first.
+    assert_eq!(claim(&mut cpu), 1);
+    assert_eq!(claim(&mut cpu), 10);
+    assert_eq!(claim(&mut cpu), 0);
+
+    // The gateway holds a request from a source in service.
+    cpu.bus.raise_irq(10);
+    assert!(!cpu.bus.is_external_interrupting());
+    complete(&mut cpu, 1);
+    assert!(!cpu.bus.is_external_interrupting());
+    complete(&mut cpu, 10);
+    assert!(cpu.bus.is_external_interrupting());
+    assert_eq!(claim(&mut cpu), 10);
+
+    // A completion of an IRQ which isn't in service is ignored.
+    complete(&mut cpu, 3);
+    complete(&mut cpu, 10);
+    complete(&mut cpu, 10);
+    assert_eq!(claim(&mut cpu), 0);
+}
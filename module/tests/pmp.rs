@@ -0,0 +1,43 @@
+//! Tests of the physical ranges protected against S-mode and U-mode.
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::*;
+use rvemu::trap::Exception;
+
+const START: u64 = DRAM_BASE + 0x1000;
+const END: u64 = DRAM_BASE + 0x2000;
+
+/// Return a cpu in S-mode with the range from `START` to `END` protected.
+fn cpu() -> Cpu {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.protect(START, END);
+    cpu.mode = Mode::Supervisor;
+    cpu
+}
+
+#[test]
+fn accesses_overlapping_a_protected_range_fault() {
+    let mut cpu = cpu();
+    // The last bytes of a doubleword before the range are protected.
+    assert!(matches!(
+        cpu.load(START - 4, 64),
+        Err(Exception::LoadAccessFault(a)) if a == START - 4
+    ));
+    assert!(matches!(
+        cpu.store(START - 1, 16, 0),
+        Err(Exception::StoreAMOAccessFault(a)) if a == START - 1
+    ));
+    assert!(cpu.load(END - 1, 8).is_err());
+
+    // Accesses which end before the range or start at its end don't overlap it.
+    assert!(cpu.load(START - 8, 64).is_ok());
+    assert!(cpu.store(END, 64, 0).is_ok());
+}
+
+#[test]
+fn machine_mode_accesses_protected_ranges() {
+    let mut cpu = cpu();
+    cpu.mode = Mode::Machine;
+    assert!(cpu.load(START - 4, 64).is_ok());
+    assert!(cpu.store(START, 64, 0).is_ok());
+}
@@ -0,0 +1,65 @@
+//! Tests of the machine-wide reset which reboots a guest in place.
+
+use rvemu::bus::{DRAM_BASE, PLIC_BASE, UART_BASE};
+use rvemu::cpu::*;
+use rvemu::emulator::Emulator;
+
+/// addi a0,zero,1
+const LI_A0_1: u32 = 0x00100513;
+/// lui t0,0x1000: t0 is the base of the emulator control device.
+const LUI_T0_EMUCTL: u32 = 0x010002b7;
+/// sd zero,40(t0): request a reset at 0x28 of the emulator control device.
+const SD_RESET: u32 = 0x0202b423;
+
+/// The enable bits of the PLIC for S-mode.
+const PLIC_SENABLE: u64 = PLIC_BASE + 0x2080;
+/// The interrupt enable register of the uart.
+const UART_IER: u64 = UART_BASE + 1;
+
+fn binary(program: &[u32]) -> Vec<u8> {
+    program.iter().flat_map(|inst| inst.to_le_bytes()).collect()
+}
+
+#[test]
+fn reset_restores_devices_dram_and_hart() {
+    let mut cpu = Cpu::new(binary(&[LI_A0_1]), Vec::new());
+    cpu.regs[11] = 0x1234;
+    cpu.save_boot_state();
+
+    cpu.bus.store(PLIC_SENABLE, 32, 0x402).unwrap();
+    cpu.bus.store(UART_IER, 8, 1).unwrap();
+    // Overwrite the image and memory after it.
+    cpu.bus.store(DRAM_BASE, 32, 0).unwrap();
+    cpu.bus.store(DRAM_BASE + 0x1000, 64, 7).unwrap();
+    cpu.store_csr(MSTATUS, MSTATUS_MPRV);
+    cpu.mode = Mode::Supervisor;
+    cpu.pc = DRAM_BASE + 0x100;
+    cpu.regs[11] = 0;
+
+    cpu.reset();
+    assert_eq!(cpu.bus.load(PLIC_SENABLE, 32).unwrap(), 0);
+    assert_eq!(cpu.bus.load(UART_IER, 8).unwrap(), 0);
+    assert_eq!(cpu.bus.load(DRAM_BASE, 32).unwrap(), LI_A0_1 as u64);
+    assert_eq!(cpu.bus.load(DRAM_BASE + 0x1000, 64).unwrap(), 0);
+    assert_eq!(cpu.load_csr(MSTATUS), 0);
+    assert_eq!(cpu.mode, Mode::Machine);
+    assert_eq!(cpu.pc, DRAM_BASE);
+    assert_eq!(cpu.regs[11], 0x1234);
+}
+
+#[test]
+fn guest_requests_a_reset() {
+    let cpu = Cpu::new(binary(&[LI_A0_1, LUI_T0_EMUCTL, SD_RESET]), Vec::new());
+    let mut emu = Emulator::new(cpu);
+    for _ in 0..3 {
+        emu.step().unwrap();
+    }
+    assert_eq!(emu.cpu.pc, DRAM_BASE);
+    assert_eq!(emu.cpu.regs[10], 0);
+    assert!(!emu.cpu.bus.emuctl.is_reset_requested());
+    assert!(!emu.is_stopped());
+
+    // The guest runs again from the start.
+    emu.step().unwrap();
+    assert_eq!(emu.cpu.regs[10], 1);
+}
@@ -0,0 +1,67 @@
+//! Tests of saving and restoring the machine state.
+
+use std::env;
+use std::process;
+
+use rvemu::bus::{
+    ConsoleKind, SIFIVE_PWM0_BASE, SIFIVE_UART_BASE, VIRTIO_SND_BASE, VIRTIO_VSOCK_BASE,
+};
+use rvemu::cpu::*;
+use rvemu::pflash::Pflash;
+use rvemu::snapshot;
+
+/// The txctrl register of the SiFive uart.
+const SIFIVE_UART_TXCTRL: u64 = SIFIVE_UART_BASE + 0x08;
+/// The cmp0 register of the first PWM.
+const SIFIVE_PWM0_CMP0: u64 = SIFIVE_PWM0_BASE + 0x20;
+/// The QueueSel, QueuePFN and Status registers of the sound device.
+const SND_QUEUE_SEL: u64 = VIRTIO_SND_BASE + 0x030;
+const SND_QUEUE_PFN: u64 = VIRTIO_SND_BASE + 0x040;
+const SND_STATUS: u64 = VIRTIO_SND_BASE + 0x070;
+/// The Status register of the socket device.
+const VSOCK_STATUS: u64 = VIRTIO_VSOCK_BASE + 0x070;
+
+fn snapshot_path(name: &str) -> String {
+    env::temp_dir()
+        .join(format!("rvemu-{}-{}.snap", name, process::id()))
+        .to_string_lossy()
+        .into_owned()
+}
+
+#[test]
+fn snapshot_restores_every_device() {
+    let path = snapshot_path("devices");
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.bus.set_console(ConsoleKind::Sifive);
+    cpu.bus.store(SIFIVE_UART_TXCTRL, 32, 1).unwrap();
+    cpu.bus.store(SIFIVE_PWM0_CMP0, 32, 0x1234).unwrap();
+    cpu.bus.store(SND_QUEUE_SEL, 32, 1).unwrap();
+    cpu.bus.store(SND_QUEUE_PFN, 32, 0x80001).unwrap();
+    cpu.bus.store(SND_STATUS, 32, 0x3).unwrap();
+    cpu.bus.store(VSOCK_STATUS, 32, 0x1).unwrap();
+    snapshot::save(&cpu, &path).unwrap();
+
+    let mut restored = Cpu::new(Vec::new(), Vec::new());
+    restored.bus.set_console(ConsoleKind::Sifive);
+    snapshot::restore(&mut restored, &path).unwrap();
+    let _ = std::fs::remove_file(&path);
+    assert_eq!(restored.bus.load(SIFIVE_UART_TXCTRL, 32).unwrap(), 1);
+    assert_eq!(restored.bus.load(SIFIVE_PWM0_CMP0, 32).unwrap(), 0x1234);
+    assert_eq!(restored.bus.load(SND_QUEUE_PFN, 32).unwrap(), 0x80001);
+    assert_eq!(restored.bus.load(SND_STATUS, 32).unwrap(), 0x3);
+    assert_eq!(restored.bus.load(VSOCK_STATUS, 32).unwrap(), 0x1);
+}
+
+#[test]
+fn snapshot_rejects_a_machine_with_a_different_flash() {
+    let path = snapshot_path("flash");
+    let flash = snapshot_path("flash-image");
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.bus.set_pflash(Pflash::open(&flash).unwrap());
+    snapshot::save(&cpu, &path).unwrap();
+
+    let mut restored = Cpu::new(Vec::new(), Vec::new());
+    assert!(snapshot::restore(&mut restored, &path).is_err());
+    let _ = std::fs::remove_file(&path);
+    let _ = std::fs::remove_file(&flash);
+}
@@ -0,0 +1,25 @@
+//! Tests of the state dumps taken while a guest keeps running.
+
+use rvemu::cpu::*;
+use rvemu::history::History;
+use rvemu::state_dump::{self, StateDump};
+
+#[test]
+fn requested_dump_is_taken_once() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.regs[10] = 0x1234;
+    cpu.store_csr(MCAUSE, 2);
+    let mut history = History::new(2);
+    // addi a0,a0,1
+    history.record(0x8000_0000, Some(0x00150513));
+    let mut dump = StateDump::install();
+    assert_eq!(dump.poll(&cpu, &history), None);
+
+    state_dump::request();
+    let text = dump.poll(&cpu, &history).unwrap();
+    assert!(text.starts_with("state dump: pc 0x80000000 mode Machine after 1 instructions\n"));
+    assert!(text.contains("a0   0x0000000000001234"));
+    assert!(text.contains("mcause  0x0000000000000002"));
+    assert!(text.ends_with("last 1 instructions:\n  0x0000000080000000: 00150513  addi a0,a0,1\n"));
+    assert_eq!(dump.poll(&cpu, &history), None);
+}
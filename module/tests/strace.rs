@@ -0,0 +1,64 @@
+//! Tests of the decoding of environment calls by `--strace`.
+
+use rvemu::cpu::{Cpu, Mode};
+use rvemu::strace::{Strace, SyscallAbi};
+use rvemu::trap::Exception;
+
+#[test]
+fn xv6_syscall_and_return() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut strace = Strace::new(SyscallAbi::Xv6);
+    cpu.mode = Mode::User;
+    cpu.regs[17] = 16;
+    cpu.regs[10] = 1;
+    cpu.regs[11] = 0x2fe0;
+    cpu.regs[12] = 5;
+    let line = strace.ecall(&cpu, 0x4c8, &Exception::EnvironmentCallFromUMode);
+    assert_eq!(
+        line.as_deref(),
+        Some("strace: [U 0x4c8] write(0x1, 0x2fe0, 0x5)")
+    );
+
+    // The kernel handles the call in S-mode and returns to the next instruction.
+    cpu.mode = Mode::Supervisor;
+    cpu.pc = 0x4cc;
+    assert_eq!(strace.returned(&cpu), None);
+    cpu.mode = Mode::User;
+    cpu.regs[10] = 5;
+    assert_eq!(
+        strace.returned(&cpu).as_deref(),
+        Some("strace: [U 0x4c8] write = 5")
+    );
+    assert_eq!(strace.returned(&cpu), None);
+}
+
+#[test]
+fn sbi_call_and_error() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut strace = Strace::new(SyscallAbi::Linux);
+    cpu.mode = Mode::Supervisor;
+    // sbi_probe_extension("HSM")
+    cpu.regs[17] = 0x10;
+    cpu.regs[16] = 3;
+    cpu.regs[10] = 0x48534d;
+    let line = strace.ecall(&cpu, 0x80200000, &Exception::EnvironmentCallFromSMode);
+    assert_eq!(
+        line.as_deref(),
+        Some("strace: [S 0x80200000] sbi_probe_extension(0x48534d)")
+    );
+
+    cpu.pc = 0x80200004;
+    cpu.regs[10] = -2i64 as u64;
+    cpu.regs[11] = 0;
+    assert_eq!(
+        strace.returned(&cpu).as_deref(),
+        Some("strace: [S 0x80200000] sbi_probe_extension = SBI_ERR_NOT_SUPPORTED, 0x0")
+    );
+}
+
+#[test]
+fn other_exceptions_are_ignored() {
+    let cpu = Cpu::new(Vec::new(), Vec::new());
+    let mut strace = Strace::new(SyscallAbi::Linux);
+    assert_eq!(strace.ecall(&cpu, 0, &Exception::Breakpoint), None);
+}
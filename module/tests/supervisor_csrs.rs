@@ -0,0 +1,46 @@
+//! Tests of sstatus, sip and sie as views of mstatus, mip and mie.
+
+use rvemu::cpu::*;
+
+#[test]
+fn sstatus_is_a_view_of_mstatus() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    // MPP and MIE aren't visible in sstatus.
+    cpu.store_csr(MSTATUS, (0b11 << 11) | (1 << 3));
+    assert_eq!(cpu.load_csr(SSTATUS), 0);
+
+    // SIE, SPIE and SPP written via sstatus are seen in mstatus, which keeps MPP and MIE.
+    cpu.store_csr(SSTATUS, u64::MAX);
+    assert_eq!(cpu.load_csr(MSTATUS) & 0x1922, 0x1922);
+    assert_eq!(cpu.load_csr(SSTATUS) & (1 << 3 | 0b11 << 11), 0);
+
+    cpu.store_csr(MSTATUS, 0);
+    assert_eq!(cpu.load_csr(SSTATUS), 0);
+}
+
+#[test]
+fn sip_and_sie_show_delegated_supervisor_interrupts() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.store_csr(MIP, MIP_SSIP | MIP_STIP | MIP_MSIP);
+    cpu.store_csr(MIE, MIP_STIP | MIP_MTIP);
+    assert_eq!(cpu.load_csr(SIP), 0);
+    assert_eq!(cpu.load_csr(SIE), 0);
+
+    // M-mode interrupts aren't visible even if mideleg has their bits.
+    cpu.store_csr(MIDELEG, 0xffff);
+    assert_eq!(cpu.load_csr(SIP), MIP_SSIP | MIP_STIP);
+    assert_eq!(cpu.load_csr(SIE), MIP_STIP);
+
+    // Only SSIP is writable in sip, and sie can't change M-mode interrupts.
+    cpu.store_csr(SIP, 0);
+    assert_eq!(cpu.load_csr(MIP), MIP_STIP | MIP_MSIP);
+    cpu.store_csr(SIE, MIP_SSIP);
+    assert_eq!(cpu.load_csr(MIE), MIP_SSIP | MIP_MTIP);
+
+    // Without delegation, writes don't reach mip and mie.
+    cpu.store_csr(MIDELEG, 0);
+    cpu.store_csr(SIP, MIP_SSIP);
+    cpu.store_csr(SIE, 0);
+    assert_eq!(cpu.load_csr(MIP), MIP_STIP | MIP_MSIP);
+    assert_eq!(cpu.load_csr(MIE), MIP_SSIP | MIP_MTIP);
+}
@@ -0,0 +1,39 @@
+//! Tests of the ring buffer of privilege-mode transitions.
+
+use rvemu::cpu::Mode;
+use rvemu::timeline::{Timeline, TimelineEvent};
+
+fn ecall(epc: u64) -> TimelineEvent {
+    TimelineEvent::Trap {
+        cause: 8,
+        from: Mode::User,
+        to: Mode::Supervisor,
+        epc,
+        handler: 0x80001000,
+    }
+}
+
+#[test]
+fn keeps_the_last_events() {
+    let mut timeline = Timeline::new(2);
+    timeline.record(ecall(0x100));
+    timeline.record(TimelineEvent::Return {
+        from: Mode::Supervisor,
+        to: Mode::User,
+        pc: 0x104,
+    });
+    timeline.record(ecall(0x200));
+    assert_eq!(
+        timeline.dump(),
+        "#2 sret S->U pc=0x104\n\
+         #3 trap U->S cause=8 (environment call from U-mode) epc=0x200 handler=0x80001000\n"
+    );
+}
+
+#[test]
+fn disabled_by_default() {
+    let mut timeline = Timeline::default();
+    timeline.record(ecall(0x100));
+    assert!(!timeline.is_enabled());
+    assert_eq!(timeline.dump(), "");
+}
@@ -0,0 +1,65 @@
+//! Tests of writing and reading trace files, compressed or not.
+
+use std::env;
+use std::fs;
+use std::io::prelude::*;
+use std::io::BufReader;
+use std::process::Command;
+
+use rvemu::trace_file::{TraceFile, TraceReader};
+
+/// Return a path in the temporary directory which is unique to this test process.
+fn temp_path(name: &str) -> String {
+    let dir = env::temp_dir();
+    let path = dir.join(format!("rvemu-{}-{}", std::process::id(), name));
+    path.to_str().unwrap().to_string()
+}
+
+/// Write lines to `path`, read them back and return them.
+fn roundtrip(path: &str, lines: usize) -> Vec<String> {
+    let mut out = TraceFile::create(path).unwrap();
+    for i in 0..lines {
+        writeln!(
+            out,
+            "mmio: [{:#x}] uart store8 {:#x}",
+            0x8000_0000 + i * 4,
+            i
+        )
+        .unwrap();
+    }
+    out.finish().unwrap();
+    let input = BufReader::new(TraceReader::open(path).unwrap());
+    let read = input.lines().map(|line| line.unwrap()).collect();
+    fs::remove_file(path).unwrap();
+    read
+}
+
+#[test]
+fn plain_trace_roundtrip() {
+    let lines = roundtrip(&temp_path("trace.log"), 3);
+    assert_eq!(
+        lines,
+        vec![
+            "mmio: [0x80000000] uart store8 0x0",
+            "mmio: [0x80000004] uart store8 0x1",
+            "mmio: [0x80000008] uart store8 0x2",
+        ]
+    );
+}
+
+#[test]
+fn zstd_trace_roundtrip() {
+    if Command::new("zstd").arg("--version").output().is_err() {
+        eprintln!("skipped: zstd isn't installed");
+        return;
+    }
+    let path = temp_path("trace.log.zst");
+    let lines = roundtrip(&path, 100_000);
+    assert_eq!(lines.len(), 100_000);
+    assert_eq!(lines[99_999], "mmio: [0x80061a7c] uart store8 0x1869f");
+}
+
+#[test]
+fn missing_compressed_trace_fails_to_open() {
+    assert!(TraceReader::open(&temp_path("missing.log.zst")).is_err());
+}
@@ -0,0 +1,79 @@
+//! Tests of the TVM, TW and TSR fields of mstatus, which trap instructions of S-mode.
+
+mod common;
+
+use common::{new_cpu, step};
+use rvemu::cpu::*;
+use rvemu::trap::Exception;
+
+/// csrrs a0,satp,zero
+const CSRR_SATP: u32 = 0x18002573;
+/// sfence.vma zero,zero
+const SFENCE_VMA: u32 = 0x12000073;
+/// sret
+const SRET: u32 = 0x10200073;
+/// wfi
+const WFI: u32 = 0x10500073;
+
+/// Return a cpu in `mode` with `mstatus` about to execute `inst`.
+fn cpu_at(inst: u32, mode: Mode, mstatus: u64) -> Cpu {
+    let mut cpu = new_cpu(&[inst]);
+    cpu.store_csr(MSTATUS, mstatus);
+    cpu.mode = mode;
+    cpu
+}
+
+fn is_illegal(result: Result<(), Exception>) -> bool {
+    matches!(result, Err(Exception::IllegalInstruction))
+}
+
+#[test]
+fn tvm_traps_satp_and_sfence_vma_in_supervisor_mode() {
+    for &inst in &[CSRR_SATP, SFENCE_VMA] {
+        assert!(step(&mut cpu_at(inst, Mode::Supervisor, 0)).is_ok());
+        assert!(is_illegal(step(&mut cpu_at(
+            inst,
+            Mode::Supervisor,
+            MSTATUS_TVM
+        ))));
+        assert!(step(&mut cpu_at(inst, Mode::Machine, MSTATUS_TVM)).is_ok());
+    }
+}
+
+#[test]
+fn tsr_traps_sret_in_supervisor_mode() {
+    assert!(step(&mut cpu_at(SRET, Mode::Supervisor, 0)).is_ok());
+    assert!(is_illegal(step(&mut cpu_at(
+        SRET,
+        Mode::Supervisor,
+        MSTATUS_TSR
+    ))));
+    assert!(step(&mut cpu_at(SRET, Mode::Machine, MSTATUS_TSR)).is_ok());
+    assert!(is_illegal(step(&mut cpu_at(SRET, Mode::User, 0))));
+}
+
+#[test]
+fn tw_traps_wfi_after_the_timeout() {
+    let mut cpu = cpu_at(WFI, Mode::Supervisor, MSTATUS_TW);
+    let pc = cpu.pc;
+    for _ in 0..WFI_TIMEOUT {
+        step(&mut cpu).unwrap();
+        assert!(cpu.is_waiting());
+    }
+    assert!(is_illegal(step(&mut cpu)));
+    // The exception is raised at the wfi.
+    assert_eq!(cpu.pc, pc + 4);
+
+    // A wfi which completes in time doesn't trap, and M-mode waits without a limit.
+    let mut cpu = cpu_at(WFI, Mode::Supervisor, MSTATUS_TW);
+    step(&mut cpu).unwrap();
+    cpu.store_csr(MIE, MIP_SSIP);
+    cpu.store_csr(MIP, MIP_SSIP);
+    assert!(step(&mut cpu).is_ok());
+    assert!(!cpu.is_waiting());
+    let mut cpu = cpu_at(WFI, Mode::Machine, MSTATUS_TW);
+    for _ in 0..=WFI_TIMEOUT {
+        step(&mut cpu).unwrap();
+    }
+    assert!(cpu.is_waiting());
+}
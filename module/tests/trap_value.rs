@@ -0,0 +1,61 @@
+//! Tests of the faulting addresses written to mtval and stval.
+
+use rvemu::bus::DRAM_BASE;
+use rvemu::cpu::*;
+use rvemu::trap::*;
+
+#[test]
+fn faults_write_the_address_to_xtval() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.pc = DRAM_BASE + 4;
+    Exception::LoadPageFault(0x1234).take_trap(&mut cpu);
+    assert_eq!(cpu.load_csr(MTVAL), 0x1234);
+
+    // A delegated fault from S-mode writes stval.
+    cpu.mode = Mode::Supervisor;
+    cpu.store_csr(MEDELEG, 1 << 15);
+    Exception::StoreAMOPageFault(0x5678).take_trap(&mut cpu);
+    assert_eq!(cpu.load_csr(STVAL), 0x5678);
+    assert_eq!(cpu.load_csr(MTVAL), 0x1234);
+
+    // Other exceptions write 0.
+    cpu.mode = Mode::Machine;
+    Exception::IllegalInstruction.take_trap(&mut cpu);
+    assert_eq!(cpu.load_csr(MTVAL), 0);
+}
+
+#[test]
+fn bus_faults_hold_the_virtual_address() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    // Nothing is mapped below the dram at 0x100.
+    assert!(matches!(
+        cpu.load(0x100, 64),
+        Err(Exception::LoadAccessFault(0x100))
+    ));
+    assert!(matches!(
+        cpu.store(0x100, 64, 0),
+        Err(Exception::StoreAMOAccessFault(0x100))
+    ));
+    assert_eq!(Exception::LoadAccessFault(1).at(2).address(), Some(2));
+    assert_eq!(Exception::Breakpoint.at(2).address(), None);
+}
+
+#[test]
+fn accesses_past_the_dram_fault() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    let end = cpu.bus.dram_range().end;
+    assert!(matches!(
+        cpu.load(end, 64),
+        Err(Exception::LoadAccessFault(a)) if a == end
+    ));
+    assert!(matches!(
+        cpu.store(u64::MAX - 3, 32, 0),
+        Err(Exception::StoreAMOAccessFault(_))
+    ));
+    // An access straddling the end of the dram.
+    assert!(matches!(
+        cpu.store(end - 4, 64, 0),
+        Err(Exception::StoreAMOAccessFault(a)) if a == end - 4
+    ));
+    assert!(cpu.load(end - 8, 64).is_ok());
+}
@@ -0,0 +1,55 @@
+//! Tests of the trap handler addresses selected by mtvec and stvec.
+
+use rvemu::cpu::*;
+use rvemu::trap::*;
+
+const BASE: u64 = 0x8000_1000;
+/// The interrupt bit of mcause and scause.
+const INTERRUPT: u64 = 1 << 63;
+
+#[test]
+fn direct_mode_uses_the_base() {
+    assert_eq!(trap_vector(BASE, 2), BASE);
+    assert_eq!(trap_vector(BASE, INTERRUPT | 7), BASE);
+}
+
+#[test]
+fn vectored_mode_offsets_only_interrupts() {
+    assert_eq!(trap_vector(BASE | 1, INTERRUPT | 1), BASE + 4);
+    assert_eq!(trap_vector(BASE | 1, INTERRUPT | 7), BASE + 28);
+    assert_eq!(trap_vector(BASE | 1, INTERRUPT | 11), BASE + 44);
+    // Exceptions always use the base, whatever their cause is.
+    assert_eq!(trap_vector(BASE | 1, 2), BASE);
+    assert_eq!(trap_vector(BASE | 1, 15), BASE);
+}
+
+#[test]
+fn reserved_modes_are_direct() {
+    for mode in 2..4 {
+        assert_eq!(trap_vector(BASE | mode, INTERRUPT | 7), BASE);
+        assert_eq!(trap_vector(BASE | mode, 2), BASE);
+    }
+}
+
+#[test]
+fn traps_use_the_vector_of_the_mode_taking_them() {
+    let mut cpu = Cpu::new(Vec::new(), Vec::new());
+    cpu.store_csr(MTVEC, BASE | 1);
+    cpu.store_csr(STVEC, (BASE + 0x100) | 1);
+    cpu.store_csr(MIDELEG, MIP_STIP);
+    cpu.store_csr(MEDELEG, 1 << 2);
+
+    cpu.mode = Mode::Supervisor;
+    Interrupt::MachineTimerInterrupt.take_trap(&mut cpu);
+    assert_eq!(cpu.pc, BASE + 28);
+
+    cpu.mode = Mode::User;
+    Interrupt::SupervisorTimerInterrupt.take_trap(&mut cpu);
+    assert_eq!(cpu.pc, BASE + 0x100 + 20);
+
+    cpu.mode = Mode::User;
+    cpu.pc = 0x8000_0004;
+    Exception::IllegalInstruction.take_trap(&mut cpu);
+    assert_eq!(cpu.mode, Mode::Supervisor);
+    assert_eq!(cpu.pc, BASE + 0x100);
+}
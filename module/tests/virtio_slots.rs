@@ -0,0 +1,80 @@
+//! Tests of the placement of virtio devices in the virtio-mmio slots.
+
+use rvemu::bus::{Bus, VirtioDevice, VIRTIO_BASE, VIRTIO_SIZE};
+use rvemu::machine::Machine;
+
+/// Return the device ID in a slot, 0 for a free slot.
+fn device_id(bus: &mut Bus, slot: u64) -> u64 {
+    bus.load(VIRTIO_BASE + slot * VIRTIO_SIZE + 0x008, 32)
+        .unwrap_or(0)
+}
+
+#[test]
+fn default_slots_follow_qemu_virt() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    assert_eq!(device_id(&mut bus, 0), 2);
+    assert_eq!(device_id(&mut bus, 1), 19);
+    assert_eq!(device_id(&mut bus, 2), 25);
+    assert_eq!(device_id(&mut bus, 3), 0);
+    assert_eq!(bus.virtio_irq(VirtioDevice::Blk), Some(1));
+    assert_eq!(bus.virtio_irq(VirtioDevice::Snd), Some(3));
+}
+
+#[test]
+fn devices_move_with_their_irqs() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.set_virtio_slots("snd,,,,,,,virtio").unwrap();
+    assert_eq!(device_id(&mut bus, 0), 25);
+    assert_eq!(device_id(&mut bus, 1), 0);
+    assert_eq!(device_id(&mut bus, 7), 2);
+    assert_eq!(bus.virtio_irq(VirtioDevice::Snd), Some(1));
+    assert_eq!(bus.virtio_irq(VirtioDevice::Blk), Some(8));
+    assert_eq!(bus.virtio_irq(VirtioDevice::Vsock), None);
+}
+
+#[test]
+fn invalid_slots_are_rejected() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    assert!(bus.set_virtio_slots("virtio,net").is_err());
+    assert!(bus.set_virtio_slots("virtio,virtio").is_err());
+    assert!(bus.set_virtio_slots(",,,,,,,,snd").is_err());
+    // The default layout is kept.
+    assert_eq!(device_id(&mut bus, 1), 19);
+}
+
+#[test]
+fn machine_slots_are_in_address_order() {
+    let machine = Machine::parse_dts(
+        r#"
+        /dts-v1/;
+        / {
+            #address-cells = <2>;
+            #size-cells = <2>;
+            memory@80000000 {
+                device_type = "memory";
+                reg = <0x0 0x80000000 0x0 0x8000000>;
+            };
+            virtio_mmio@40002000 {
+                compatible = "virtio,mmio";
+                reg = <0x0 0x40002000 0x0 0x1000>;
+            };
+            virtio_mmio@40001000 {
+                compatible = "virtio,mmio";
+                reg = <0x0 0x40001000 0x0 0x1000>;
+            };
+        };
+        "#,
+    )
+    .unwrap();
+    assert_eq!(
+        machine.virtio,
+        vec![0x4000_1000..0x4000_2000, 0x4000_2000..0x4000_3000]
+    );
+
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.set_machine(&machine);
+    bus.set_virtio_slots("vsock,virtio").unwrap();
+    assert_eq!(bus.load(0x4000_1008, 32).unwrap(), 19);
+    assert_eq!(bus.load(0x4000_2008, 32).unwrap(), 2);
+    assert!(bus.load(VIRTIO_BASE + 0x008, 32).is_err());
+}
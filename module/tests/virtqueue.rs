@@ -0,0 +1,240 @@
+//! Tests of the virtqueue against rings laid out by hand in the guest memory.
+
+use rvemu::bus::{Bus, DRAM_BASE, VIRTIO_BASE};
+use rvemu::virtqueue::*;
+
+const PAGE_SIZE: u32 = 4096;
+/// The descriptor table at the second page of the dram.
+const DESC: u64 = DRAM_BASE + 0x1000;
+/// The available ring after 8 descriptors.
+const AVAIL: u64 = DESC + 8 * 16;
+/// The used ring at the next page.
+const USED: u64 = DESC + 0x1000;
+/// An indirect table at the fourth page of the dram.
+const INDIRECT: u64 = DRAM_BASE + 0x3000;
+
+/// Return a bus and a queue of 8 descriptors at `DESC`.
+fn setup() -> (Bus, Virtqueue) {
+    let bus = Bus::new(Vec::new(), Vec::new());
+    let mut queue = Virtqueue::new();
+    queue.num = 8;
+    queue.pfn = (DESC / PAGE_SIZE as u64) as u32;
+    (bus, queue)
+}
+
+/// Write a descriptor at `index` of the table at `table`.
+fn write_desc(bus: &mut Bus, table: u64, index: u64, desc: (u64, u64, u64, u64)) {
+    let (addr, len, flags, next) = desc;
+    let base = table + 16 * index;
+    bus.store(base, 64, addr).unwrap();
+    bus.store(base + 8, 32, len).unwrap();
+    bus.store(base + 12, 16, flags).unwrap();
+    bus.store(base + 14, 16, next).unwrap();
+}
+
+/// Make the chains starting at `heads` available.
+fn make_available(bus: &mut Bus, heads: &[u64]) {
+    for (i, &head) in heads.iter().enumerate() {
+        bus.store(AVAIL + 4 + 2 * i as u64, 16, head).unwrap();
+    }
+    bus.store(AVAIL + 2, 16, heads.len() as u64).unwrap();
+}
+
+#[test]
+fn ring_addresses_follow_legacy_layout() {
+    let (_, queue) = setup();
+    assert_eq!(queue.desc_addr(PAGE_SIZE), DESC);
+    assert_eq!(queue.avail_addr(PAGE_SIZE), AVAIL);
+    assert_eq!(queue.used_addr(PAGE_SIZE), USED);
+}
+
+#[test]
+fn pop_chain_follows_next() {
+    let (mut bus, mut queue) = setup();
+    assert_eq!(queue.pop_chain(&mut bus.dma(), PAGE_SIZE).unwrap(), None);
+
+    write_desc(&mut bus, DESC, 3, (0x8000_4000, 16, VRING_DESC_F_NEXT, 5));
+    write_desc(
+        &mut bus,
+        DESC,
+        5,
+        (0x8000_5000, 512, VRING_DESC_F_NEXT | VRING_DESC_F_WRITE, 1),
+    );
+    write_desc(&mut bus, DESC, 1, (0x8000_6000, 1, VRING_DESC_F_WRITE, 0));
+    make_available(&mut bus, &[3]);
+
+    let chain = queue.pop_chain(&mut bus.dma(), PAGE_SIZE).unwrap().unwrap();
+    assert_eq!(chain.head, 3);
+    assert_eq!(
+        chain.descs,
+        vec![
+            (0x8000_4000, 16, VRING_DESC_F_NEXT),
+            (0x8000_5000, 512, VRING_DESC_F_NEXT | VRING_DESC_F_WRITE),
+            (0x8000_6000, 1, VRING_DESC_F_WRITE),
+        ]
+    );
+    assert_eq!(queue.last_avail, 1);
+    assert_eq!(queue.pop_chain(&mut bus.dma(), PAGE_SIZE).unwrap(), None);
+}
+
+#[test]
+fn pop_chain_expands_indirect_table() {
+    let (mut bus, mut queue) = setup();
+    write_desc(
+        &mut bus,
+        DESC,
+        0,
+        (INDIRECT, 2 * 16, VRING_DESC_F_INDIRECT, 0),
+    );
+    write_desc(
+        &mut bus,
+        INDIRECT,
+        0,
+        (0x8000_4000, 8, VRING_DESC_F_NEXT, 1),
+    );
+    write_desc(
+        &mut bus,
+        INDIRECT,
+        1,
+        (0x8000_5000, 4, VRING_DESC_F_WRITE, 0),
+    );
+    make_available(&mut bus, &[0]);
+
+    let chain = queue.pop_chain(&mut bus.dma(), PAGE_SIZE).unwrap().unwrap();
+    assert_eq!(chain.head, 0);
+    assert_eq!(
+        chain.descs,
+        vec![
+            (0x8000_4000, 8, VRING_DESC_F_NEXT),
+            (0x8000_5000, 4, VRING_DESC_F_WRITE),
+        ]
+    );
+}
+
+#[test]
+fn chain_reads_and_writes_buffers() {
+    let (mut bus, mut queue) = setup();
+    write_desc(&mut bus, DESC, 0, (0x8000_4000, 2, VRING_DESC_F_NEXT, 1));
+    write_desc(
+        &mut bus,
+        DESC,
+        1,
+        (0x8000_5000, 2, VRING_DESC_F_NEXT | VRING_DESC_F_WRITE, 2),
+    );
+    write_desc(&mut bus, DESC, 2, (0x8000_6000, 4, VRING_DESC_F_WRITE, 0));
+    bus.store(0x8000_4000, 16, 0xbbaa).unwrap();
+    make_available(&mut bus, &[0]);
+
+    let chain = queue.pop_chain(&mut bus.dma(), PAGE_SIZE).unwrap().unwrap();
+    assert_eq!(chain.read(&mut bus.dma()).unwrap(), vec![0xaa, 0xbb]);
+    assert_eq!(chain.write(&mut bus.dma(), &[1, 2, 3]).unwrap(), 3);
+    assert_eq!(bus.load(0x8000_5000, 16).unwrap(), 0x0201);
+    assert_eq!(bus.load(0x8000_6000, 8).unwrap(), 3);
+}
+
+#[test]
+fn push_used_writes_element_and_index() {
+    let (mut bus, mut queue) = setup();
+    queue.push_used(&mut bus.dma(), PAGE_SIZE, 3, 513).unwrap();
+    queue.push_used(&mut bus.dma(), PAGE_SIZE, 6, 0).unwrap();
+    assert_eq!(bus.load(USED + 2, 16).unwrap(), 2);
+    assert_eq!(bus.load(USED + 4, 32).unwrap(), 3);
+    assert_eq!(bus.load(USED + 8, 32).unwrap(), 513);
+    assert_eq!(bus.load(USED + 12, 32).unwrap(), 6);
+    assert_eq!(bus.load(USED + 16, 32).unwrap(), 0);
+}
+
+#[test]
+fn avail_flag_suppresses_interrupt() {
+    let (mut bus, mut queue) = setup();
+    queue.push_used(&mut bus.dma(), PAGE_SIZE, 0, 0).unwrap();
+    assert!(queue
+        .needs_interrupt(&mut bus.dma(), PAGE_SIZE, 0, false)
+        .unwrap());
+    assert!(!queue
+        .needs_interrupt(&mut bus.dma(), PAGE_SIZE, 1, false)
+        .unwrap());
+    bus.store(AVAIL, 16, VRING_AVAIL_F_NO_INTERRUPT).unwrap();
+    assert!(!queue
+        .needs_interrupt(&mut bus.dma(), PAGE_SIZE, 0, false)
+        .unwrap());
+}
+
+#[test]
+fn buffers_outside_dram_are_errors() {
+    let (mut bus, mut queue) = setup();
+    // A readable buffer which wraps around the end of the address space.
+    write_desc(&mut bus, DESC, 0, (u64::MAX - 1, 4, VRING_DESC_F_NEXT, 1));
+    write_desc(&mut bus, DESC, 1, (0x1000, 4, VRING_DESC_F_WRITE, 0));
+    // An indirect table outside the dram.
+    write_desc(&mut bus, DESC, 2, (0x2000, 16, VRING_DESC_F_INDIRECT, 0));
+    make_available(&mut bus, &[0, 2]);
+
+    let chain = queue.pop_chain(&mut bus.dma(), PAGE_SIZE).unwrap().unwrap();
+    assert!(chain.read(&mut bus.dma()).is_err());
+    assert!(chain.write(&mut bus.dma(), &[1]).is_err());
+    assert!(queue.pop_chain(&mut bus.dma(), PAGE_SIZE).is_err());
+
+    // A queue whose rings are outside the dram.
+    queue.pfn = 1;
+    assert!(queue.pop_chain(&mut bus.dma(), PAGE_SIZE).is_err());
+    assert!(queue.push_used(&mut bus.dma(), PAGE_SIZE, 0, 0).is_err());
+}
+
+#[test]
+fn device_needs_reset_after_a_queue_outside_dram() {
+    const QUEUE_NUM: u64 = VIRTIO_BASE + 0x038;
+    const QUEUE_PFN: u64 = VIRTIO_BASE + 0x040;
+    const QUEUE_NOTIFY: u64 = VIRTIO_BASE + 0x050;
+    const STATUS: u64 = VIRTIO_BASE + 0x070;
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.store(VIRTIO_BASE + 0x028, 32, PAGE_SIZE as u64)
+        .unwrap();
+    bus.store(QUEUE_NUM, 32, 8).unwrap();
+    bus.store(QUEUE_PFN, 32, 1).unwrap();
+    bus.store(QUEUE_NOTIFY, 32, 0).unwrap();
+    bus.tick_virtio();
+    assert_eq!(
+        bus.load(STATUS, 32).unwrap(),
+        VIRTIO_STATUS_DEVICE_NEEDS_RESET as u64
+    );
+}
+
+#[test]
+fn device_needs_reset_after_a_buffer_outside_dram() {
+    let mut bus = Bus::new(Vec::new(), vec![0; 1024]);
+    bus.store(VIRTIO_BASE + 0x028, 32, PAGE_SIZE as u64)
+        .unwrap();
+    bus.store(VIRTIO_BASE + 0x038, 32, 8).unwrap();
+    bus.store(VIRTIO_BASE + 0x040, 32, DESC / PAGE_SIZE as u64)
+        .unwrap();
+    // A read of a sector into a buffer outside the dram.
+    write_desc(&mut bus, DESC, 0, (0x8000_4000, 16, VRING_DESC_F_NEXT, 1));
+    write_desc(
+        &mut bus,
+        DESC,
+        1,
+        (0x1000, 512, VRING_DESC_F_NEXT | VRING_DESC_F_WRITE, 2),
+    );
+    write_desc(&mut bus, DESC, 2, (0x8000_6000, 1, VRING_DESC_F_WRITE, 0));
+    make_available(&mut bus, &[0]);
+    bus.store(VIRTIO_BASE + 0x050, 32, 0).unwrap();
+    bus.tick_virtio();
+    assert_eq!(
+        bus.load(VIRTIO_BASE + 0x070, 32).unwrap(),
+        VIRTIO_STATUS_DEVICE_NEEDS_RESET as u64
+    );
+    // The request isn't completed.
+    assert_eq!(bus.load(USED + 2, 16).unwrap(), 0);
+}
+
+#[test]
+fn dma_rejects_addresses_outside_dram() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    let end = bus.dram_range().end;
+    let mut dma = bus.dma();
+    assert!(dma.load(DRAM_BASE - 8, 64).is_err());
+    assert!(dma.store(end - 4, 64, 0).is_err());
+    assert!(dma.store(end - 8, 64, 1).is_ok());
+    assert_eq!(dma.load(end - 8, 64).unwrap(), 1);
+}
@@ -0,0 +1,51 @@
+//! Tests of wfi stalling the hart until an interrupt is pending.
+
+mod common;
+
+use common::{new_cpu, step};
+use rvemu::bus::CLINT_BASE;
+use rvemu::cpu::*;
+use rvemu::trap::Exception;
+
+/// wfi
+const WFI: u32 = 0x10500073;
+/// The mtimecmp register of the CLINT.
+const CLINT_MTIMECMP: u64 = CLINT_BASE + 0x4000;
+
+fn cpu() -> Cpu {
+    new_cpu(&[WFI])
+}
+
+#[test]
+fn wfi_stalls_until_an_interrupt_is_pending() {
+    let mut cpu = cpu();
+    let pc = cpu.pc;
+    for _ in 0..3 {
+        step(&mut cpu).unwrap();
+        assert_eq!(cpu.pc, pc);
+        assert!(cpu.is_waiting());
+    }
+    assert_eq!(cpu.load_csr(MINSTRET), 0);
+
+    // A pending interrupt which isn't enabled in mie doesn't wake the hart. The timer interrupt
+    // is pending once mtime reaches mtimecmp.
+    cpu.bus.store(CLINT_MTIMECMP, 64, 0).unwrap();
+    assert_eq!(cpu.load_csr(MIP), MIP_MTIP);
+    step(&mut cpu).unwrap();
+    assert!(cpu.is_waiting());
+
+    // The hart resumes even if interrupts are globally disabled in mstatus.
+    cpu.store_csr(MIE, MIP_MTIP);
+    step(&mut cpu).unwrap();
+    assert_eq!(cpu.pc, pc + 4);
+    assert!(!cpu.is_waiting());
+    assert_eq!(cpu.load_csr(MINSTRET), 1);
+}
+
+#[test]
+fn wfi_is_illegal_in_user_mode() {
+    let mut cpu = cpu();
+    cpu.mode = Mode::User;
+    assert!(matches!(step(&mut cpu), Err(Exception::IllegalInstruction)));
+    assert!(!cpu.is_waiting());
+}
@@ -0,0 +1,68 @@
+//! Tests of the widths of accesses to devices.
+
+use rvemu::bus::{Bus, Width, DRAM_BASE, EMUCTL_BASE, UART_BASE};
+use rvemu::cpu::implementation_id;
+use rvemu::trap::Exception;
+
+#[test]
+fn widths_round_trip_through_bits() {
+    for width in [
+        Width::Byte,
+        Width::HalfWord,
+        Width::Word,
+        Width::DoubleWord,
+        Width::QuadWord,
+    ]
+    .iter()
+    {
+        assert_eq!(Width::from_bits(width.bits()), Some(*width));
+        assert_eq!(width.bytes() * 8, width.bits());
+    }
+    assert_eq!(Width::from_bits(24), None);
+    assert_eq!(Width::HalfWord.mask(), 0xffff);
+    assert_eq!(Width::DoubleWord.mask(), u64::MAX);
+    assert_eq!(Width::QuadWord.mask(), u64::MAX);
+}
+
+#[test]
+fn unsupported_widths_fault() {
+    let mut bus = Bus::new(Vec::new(), Vec::new());
+    bus.store(DRAM_BASE, 64, 0x1122_3344_5566_7788).unwrap();
+    assert_eq!(bus.load(DRAM_BASE, 16).unwrap(), 0x7788);
+    // No device supports 128-bit accesses yet.
+    assert!(matches!(
+        bus.load(DRAM_BASE, 128),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    assert!(matches!(
+        bus.store(DRAM_BASE, 128, 0),
+        Err(Exception::StoreAMOAccessFault(_))
+    ));
+    assert!(matches!(
+        bus.load(DRAM_BASE, 24),
+        Err(Exception::LoadAccessFault(_))
+    ));
+    // The uart only has byte registers.
+    assert!(matches!(
+        bus.load(UART_BASE, 32),
+        Err(Exception::LoadAccessFault(_))
+    ));
+}
+
+#[test]
+fn emuctl_loads_and_stores_the_same_widths() {
+    // The exit register.
+    const EXIT: u64 = EMUCTL_BASE + 0x20;
+    for size in [8, 16, 32, 64].iter() {
+        let mut bus = Bus::new(Vec::new(), Vec::new());
+        // The version and the features.
+        assert_eq!(
+            bus.load(EMUCTL_BASE, *size).unwrap(),
+            implementation_id() & (u64::MAX >> (64 - size))
+        );
+        assert_ne!(bus.load(EMUCTL_BASE + 0x8, *size).unwrap(), 0);
+        // A narrower store writes the low bits.
+        bus.store(EXIT, *size, u64::MAX).unwrap();
+        assert_eq!(bus.emuctl.exit_code(), Some(u64::MAX >> (64 - size)));
+    }
+}